@@ -0,0 +1,13 @@
+fn main() {
+    // The gRPC service is generated from proto/lsm.proto with a pure-Rust
+    // compiler, so building it needs no protoc
+    #[cfg(feature = "grpc")]
+    {
+        let descriptors = protox::compile(["proto/lsm.proto"], ["proto"])
+            .expect("proto/lsm.proto should compile");
+        tonic_build::configure()
+            .compile_fds(descriptors)
+            .expect("gRPC code generation should succeed");
+        println!("cargo:rerun-if-changed=proto/lsm.proto");
+    }
+}
@@ -2,24 +2,85 @@ use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
 use std::io;
 
+use crate::error::StorageError;
+
+/// Settings for the filters written into new SSTables
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BloomConfig {
+    pub false_positive_rate: f64,
+}
+
+impl Default for BloomConfig {
+    fn default() -> Self {
+        BloomConfig {
+            false_positive_rate: 0.01,
+        }
+    }
+}
+
+/// How a filter picks the bits it sets for a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BloomHashing {
+    /// Every probe rehashes the key and the probe's number with std's
+    /// `DefaultHasher`, as filters were built in tables before format
+    /// version 2. That hasher may change between Rust releases, so these
+    /// filters are only trustworthy in the build that wrote them.
+    Seeded,
+    /// Probe `i` is `h1 + i * h2` for two 64-bit hashes of the key from a
+    /// single pass of a hash that doesn't change between builds
+    Double,
+}
+
+impl BloomHashing {
+    /// Recorded in the serialized filter, so a build that doesn't know how
+    /// a filter was hashed can tell rather than misread it
+    fn id(self) -> u8 {
+        match self {
+            BloomHashing::Seeded => 1,
+            BloomHashing::Double => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(BloomHashing::Seeded),
+            2 => Some(BloomHashing::Double),
+            _ => None,
+        }
+    }
+}
+
 /// A simple Bloom filter implementation
 pub struct BloomFilter {
-    bits: Vec<bool>,
+    // Bit `i` is bit `i % 64` of word `i / 64`, so the words' little-endian
+    // bytes are the serialized bit array
+    bits: Vec<u64>,
     num_hash_functions: usize,
     size: usize,
+    hashing: BloomHashing,
 }
 
 impl BloomFilter {
     /// Create a new Bloom filter with the given size and desired false positive rate
     pub fn new(expected_elements: usize, false_positive_rate: f64) -> Self {
+        Self::with_hashing(expected_elements, false_positive_rate, BloomHashing::Double)
+    }
+
+    /// [`new`](BloomFilter::new), picking bits as `hashing` does
+    pub fn with_hashing(
+        expected_elements: usize,
+        false_positive_rate: f64,
+        hashing: BloomHashing,
+    ) -> Self {
         // Calculate optimal size and number of hash functions
         let size = Self::optimal_size(expected_elements, false_positive_rate);
         let num_hash_functions = Self::optimal_hash_count(size, expected_elements);
 
         BloomFilter {
-            bits: vec![false; size],
+            bits: vec![0; size.div_ceil(64)],
             num_hash_functions,
             size,
+            hashing,
         }
     }
 
@@ -36,110 +97,156 @@ impl BloomFilter {
     }
 
     /// Insert an element into the Bloom filter
-    pub fn insert<T: Hash + ?Sized>(&mut self, element: &T) {
-        for i in 0..self.num_hash_functions {
-            let position = self.hash_position(element, i);
-            self.bits[position] = true;
+    pub fn insert<T: AsRef<[u8]> + ?Sized>(&mut self, element: &T) {
+        for position in self.positions(element.as_ref()) {
+            self.bits[position / 64] |= 1 << (position % 64);
         }
     }
 
     /// Check if an element might exist in the set
-    pub fn might_contain<T: Hash + ?Sized>(&self, element: &T) -> bool {
-        for i in 0..self.num_hash_functions {
-            let position = self.hash_position(element, i);
-            if !self.bits[position] {
-                return false; // Definitely not in set
-            }
-        }
-        true // Might be in set
+    pub fn might_contain<T: AsRef<[u8]> + ?Sized>(&self, element: &T) -> bool {
+        // Any bit unset and it's definitely not in the set
+        self.positions(element.as_ref())
+            .all(|position| self.bits[position / 64] & (1 << (position % 64)) != 0)
     }
 
-    /// Calculate hash position for an element with a seed
-    fn hash_position<T: Hash + ?Sized>(&self, element: &T, seed: usize) -> usize {
-        let mut hasher = DefaultHasher::new();
-        element.hash(&mut hasher);
-        seed.hash(&mut hasher);
-        (hasher.finish() as usize) % self.size
+    /// Number of bits in the filter
+    pub fn bit_count(&self) -> usize {
+        self.size
     }
 
-    /// Serialize the Bloom filter to a byte vector
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
+    /// Bytes of memory the bit array takes
+    pub fn memory_bytes(&self) -> usize {
+        self.bits.len() * 8
+    }
 
-        // Write size and hash function count
-        bytes.extend_from_slice(&(self.size as u32).to_le_bytes());
-        bytes.extend_from_slice(&(self.num_hash_functions as u32).to_le_bytes());
+    /// Number of hash functions each element sets a bit for
+    pub fn hash_count(&self) -> usize {
+        self.num_hash_functions
+    }
 
-        // Convert bits to bytes
-        let mut current_byte = 0u8;
-        let mut bit_count = 0;
+    /// How the filter picks the bits it sets
+    pub fn hashing(&self) -> BloomHashing {
+        self.hashing
+    }
 
-        for &bit in &self.bits {
-            if bit {
-                current_byte |= 1 << bit_count;
+    /// The bit positions `key` sets, one per hash function
+    fn positions<'a>(&self, key: &'a [u8]) -> impl Iterator<Item = usize> + 'a {
+        let hashing = self.hashing;
+        let (h1, h2) = match hashing {
+            BloomHashing::Double => hash_pair(key),
+            BloomHashing::Seeded => (0, 0),
+        };
+        let size = self.size as u64;
+        (0..self.num_hash_functions).map(move |i| match hashing {
+            BloomHashing::Seeded => {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                i.hash(&mut hasher);
+                (hasher.finish() % size) as usize
             }
+            BloomHashing::Double => (h1.wrapping_add((i as u64).wrapping_mul(h2)) % size) as usize,
+        })
+    }
 
-            bit_count += 1;
-            if bit_count == 8 {
-                bytes.push(current_byte);
-                current_byte = 0;
-                bit_count = 0;
-            }
+    /// Serialize the Bloom filter to a byte vector: the size and hash
+    /// function count, the hashing's id, then the bits packed eight to a
+    /// byte, lowest first
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(9 + self.bits.len() * 8);
+        bytes.extend_from_slice(&(self.size as u32).to_le_bytes());
+        bytes.extend_from_slice(&(self.num_hash_functions as u32).to_le_bytes());
+        bytes.push(self.hashing.id());
+        for word in &self.bits {
+            bytes.extend_from_slice(&word.to_le_bytes());
         }
+        // The last word's unused bytes aren't written
+        bytes.truncate(9 + self.size.div_ceil(8));
+        bytes
+    }
 
-        // Push the last byte if there are remaining bits
-        if bit_count > 0 {
-            bytes.push(current_byte);
+    /// Deserialize a Bloom filter from bytes. One hashed in a way this
+    /// build doesn't know is refused with an `Unsupported` error.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, StorageError> {
+        if bytes.len() < 9 {
+            return Err(invalid().into());
         }
-
-        bytes
+        let hashing = BloomHashing::from_id(bytes[8]).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Bloom filter uses unknown hashing {}", bytes[8]),
+            )
+        })?;
+        Ok(Self::decode(&bytes[..8], &bytes[9..], hashing)?)
     }
 
-    /// Deserialize a Bloom filter from bytes
-    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+    /// [`from_bytes`](BloomFilter::from_bytes) for a filter serialized
+    /// before the hashing was recorded, built with `hashing`
+    pub fn from_legacy_bytes(bytes: &[u8], hashing: BloomHashing) -> io::Result<Self> {
         if bytes.len() < 8 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Invalid Bloom filter data",
-            ));
+            return Err(invalid());
         }
+        Self::decode(&bytes[..8], &bytes[8..], hashing)
+    }
 
-        // Read size and hash function count
-        let size = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
-        let num_hash_functions =
-            u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as usize;
-
-        // Read bit array
-        let mut bits = vec![false; size];
-        let mut byte_index = 8; // Start after the header
-        let mut bit_index = 0;
-
-        while bit_index < size && byte_index < bytes.len() {
-            let byte = bytes[byte_index];
-
-            for i in 0..8 {
-                if bit_index >= size {
-                    break;
-                }
-
-                bits[bit_index] = (byte & (1 << i)) != 0;
-                bit_index += 1;
-            }
-
-            byte_index += 1;
+    /// A filter from its size and hash function count in `header`, and its
+    /// bit array
+    fn decode(header: &[u8], bytes: &[u8], hashing: BloomHashing) -> io::Result<Self> {
+        let size = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let num_hash_functions = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+        // The bit array must fill exactly the bytes that follow
+        if size == 0 || num_hash_functions == 0 || bytes.len() != size.div_ceil(8) {
+            return Err(invalid());
         }
 
+        let bits = bytes
+            .chunks(8)
+            .map(|chunk| {
+                let mut word = [0u8; 8];
+                word[..chunk.len()].copy_from_slice(chunk);
+                u64::from_le_bytes(word)
+            })
+            .collect();
+
         Ok(BloomFilter {
             bits,
             num_hash_functions,
             size,
+            hashing,
         })
     }
 }
 
+fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Invalid Bloom filter data")
+}
+
+/// Two hashes of `key` for double hashing: 64-bit FNV-1a, then two
+/// different MurmurHash3 finalizers of it to spread its bits. Both are fixed
+/// here, so filters read the same whichever build wrote them. The second is
+/// odd, so probes never repeat when the filter's size is a power of two.
+fn hash_pair(key: &[u8]) -> (u64, u64) {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for &byte in key {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    (fmix64(hash), fmix64(hash ^ 0x9e37_79b9_7f4a_7c15) | 1)
+}
+
+/// MurmurHash3's 64-bit finalizer
+fn fmix64(mut h: u64) -> u64 {
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::time::Instant;
 
     #[test]
     fn test_bloom_filter_basic() {
@@ -183,4 +290,221 @@ mod tests {
         assert!(restored_filter.might_contain("banana"));
         assert!(restored_filter.might_contain("cherry"));
     }
+
+    /// Keys from a fixed-seed generator, as random as the tests need
+    fn random_keys(count: usize) -> Vec<Vec<u8>> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        (0..count)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                let len = 8 + (state % 17) as usize;
+                state
+                    .to_le_bytes()
+                    .iter()
+                    .cycle()
+                    .take(len)
+                    .copied()
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// The filter as it was kept before packing, one `bool` per bit
+    struct Unpacked {
+        bits: Vec<bool>,
+    }
+
+    impl Unpacked {
+        fn build(filter: &BloomFilter, keys: &[Vec<u8>]) -> Self {
+            let mut bits = vec![false; filter.size];
+            for key in keys {
+                for position in filter.positions(key) {
+                    bits[position] = true;
+                }
+            }
+            Unpacked { bits }
+        }
+
+        fn might_contain(&self, filter: &BloomFilter, key: &[u8]) -> bool {
+            filter.positions(key).all(|position| self.bits[position])
+        }
+
+        /// `to_bytes` as it was written bit by bit, before the hashing was
+        /// recorded
+        fn to_bytes(&self, filter: &BloomFilter) -> Vec<u8> {
+            let mut bytes = Vec::new();
+            bytes.extend_from_slice(&(filter.size as u32).to_le_bytes());
+            bytes.extend_from_slice(&(filter.num_hash_functions as u32).to_le_bytes());
+            for chunk in self.bits.chunks(8) {
+                let byte = chunk
+                    .iter()
+                    .enumerate()
+                    .fold(0u8, |byte, (i, &bit)| byte | ((bit as u8) << i));
+                bytes.push(byte);
+            }
+            bytes
+        }
+    }
+
+    #[test]
+    fn test_packed_bits_match_unpacked() {
+        let keys = random_keys(20_000);
+        let (inserted, absent) = keys.split_at(10_000);
+        let mut filter = BloomFilter::new(inserted.len(), 0.01);
+        for key in inserted {
+            filter.insert(key);
+        }
+        let unpacked = Unpacked::build(&filter, inserted);
+
+        for key in &keys {
+            assert_eq!(
+                filter.might_contain(key),
+                unpacked.might_contain(&filter, key)
+            );
+        }
+        assert!(inserted.iter().all(|key| filter.might_contain(key)));
+        let false_positives = absent
+            .iter()
+            .filter(|key| filter.might_contain(key))
+            .count();
+        assert!(false_positives < absent.len() / 50, "{}", false_positives);
+
+        // Byte for byte the layout older tables hold, bar the hashing's id
+        let bytes = filter.to_bytes();
+        let legacy = unpacked.to_bytes(&filter);
+        assert_eq!(bytes[..8], legacy[..8]);
+        assert_eq!(bytes[8], BloomHashing::Double.id());
+        assert_eq!(bytes[9..], legacy[8..]);
+        for restored in [
+            BloomFilter::from_bytes(&bytes).unwrap(),
+            BloomFilter::from_legacy_bytes(&legacy, BloomHashing::Double).unwrap(),
+        ] {
+            assert_eq!(restored.to_bytes(), bytes);
+            for key in &keys {
+                assert_eq!(restored.might_contain(key), filter.might_contain(key));
+            }
+        }
+    }
+
+    #[test]
+    fn test_false_positive_rate() {
+        // Measured at 0.00986 for 100k keys at 1%, and 0.00103 at 0.1%
+        let keys = random_keys(200_000);
+        let (inserted, absent) = keys.split_at(100_000);
+        for rate in [0.01, 0.001] {
+            let mut filter = BloomFilter::new(inserted.len(), rate);
+            for key in inserted {
+                filter.insert(key);
+            }
+            assert!(inserted.iter().all(|key| filter.might_contain(key)));
+            let false_positives = absent
+                .iter()
+                .filter(|key| filter.might_contain(key))
+                .count();
+            let observed = false_positives as f64 / absent.len() as f64;
+            assert!(observed < rate * 2.0, "{} observed for {}", observed, rate);
+        }
+    }
+
+    #[test]
+    fn test_hashing_is_stable() {
+        // Fixed here, unlike std's hasher, so tables read the same in any build
+        assert_eq!(
+            hash_pair(b""),
+            (0xefd0_1f60_ba99_2926, 0x6bcb_9d63_eb8e_ab8b)
+        );
+        assert_eq!(
+            hash_pair(b"key"),
+            (0xcf8c_7983_8f3b_3030, 0x1630_5747_4a2d_fb91)
+        );
+    }
+
+    #[test]
+    fn test_golden_filter() {
+        // 1000 keys at 1%, serialized when double hashing was introduced.
+        // Tables hold filters like it, so neither the bytes written for
+        // these keys nor the answers read from it may ever change.
+        let golden = include_bytes!("golden.bloom");
+        fn keys(prefix: &'static str) -> impl Iterator<Item = String> {
+            (0..1000).map(move |i| format!("{}{:04}", prefix, i))
+        }
+
+        let mut filter = BloomFilter::new(1000, 0.01);
+        for key in keys("key") {
+            filter.insert(&key);
+        }
+        assert_eq!(filter.to_bytes(), golden);
+
+        let filter = BloomFilter::from_bytes(golden).unwrap();
+        assert_eq!(filter.hashing(), BloomHashing::Double);
+        assert_eq!((filter.bit_count(), filter.hash_count()), (9586, 7));
+        assert!(keys("key").all(|key| filter.might_contain(&key)));
+        let false_positives: Vec<_> = keys("absent")
+            .enumerate()
+            .filter(|(_, key)| filter.might_contain(key))
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(
+            false_positives,
+            [201, 213, 236, 308, 355, 417, 542, 713, 926, 985]
+        );
+    }
+
+    /// Insert and lookup throughput of each hashing. Run with
+    /// `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_hashing() {
+        let keys = random_keys(1_000_000);
+        for hashing in [BloomHashing::Seeded, BloomHashing::Double] {
+            let mut filter = BloomFilter::with_hashing(keys.len(), 0.01, hashing);
+            let start = Instant::now();
+            for key in &keys {
+                filter.insert(key);
+            }
+            let inserted = start.elapsed();
+            let start = Instant::now();
+            let found = keys.iter().filter(|key| filter.might_contain(key)).count();
+            let looked_up = start.elapsed();
+            assert_eq!(found, keys.len());
+            println!(
+                "{:?}: {:.0} inserts/s, {:.0} lookups/s",
+                hashing,
+                keys.len() as f64 / inserted.as_secs_f64(),
+                keys.len() as f64 / looked_up.as_secs_f64()
+            );
+        }
+    }
+
+    #[test]
+    fn test_from_bytes_rejects_damaged_filters() {
+        let bytes = BloomFilter::new(100, 0.01).to_bytes();
+        let with_header = |size: u32, hashes: u32| {
+            let mut damaged = bytes.clone();
+            damaged[..4].copy_from_slice(&size.to_le_bytes());
+            damaged[4..8].copy_from_slice(&hashes.to_le_bytes());
+            damaged
+        };
+        let hashes = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+
+        for damaged in [
+            bytes[..bytes.len() - 1].to_vec(),
+            bytes[..4].to_vec(),
+            with_header(u32::MAX, hashes),
+            with_header(0, hashes),
+            with_header((bytes.len() as u32 - 9) * 8, 0),
+        ] {
+            let err = BloomFilter::from_bytes(&damaged).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+
+        // Hashing from a newer build is refused rather than misread
+        let mut unknown = bytes.clone();
+        unknown[8] = 0xff;
+        let err = BloomFilter::from_bytes(&unknown).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert!(err.to_string().contains("unknown hashing 255"), "{}", err);
+    }
 }
@@ -0,0 +1,785 @@
+//! The `lsm-rust` admin commands, run by the binary with the arguments it
+//! was given
+
+mod repl;
+
+use std::fs::File;
+use std::io::{self, IsTerminal, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+
+use crate::sstable::{DescribeOptions, SSTable};
+use crate::stats::LevelStats;
+use crate::storage::{json_string, Storage, VerifyReport};
+
+const USAGE: &str = "\
+usage: lsm-rust <command> <data_dir> [options] [--json]
+       lsm-rust repl --path <data_dir>
+       lsm-rust demo [-v]
+
+commands:
+  flush <data_dir>                          write the memtable out and clear the WAL
+  compact <data_dir> [--level N | --all]    merge one level into the next, or every level (default)
+  verify <data_dir>                         read every SSTable back; exits 1 on corruption
+  stats <data_dir>                          per-level file counts, sizes and entries
+  backup <data_dir> <dest>                  write a checkpoint of the database to dest
+  verify-backup <backup_dir>                check a backup is complete and readable; exits 1 if not
+  dump <data_dir> <file>                    write every live pair to file, for loading elsewhere
+  load <data_dir> <file>                    load a dump into the database; creates the directory
+  sst-dump <file> [--keys | --values] [--verify]
+                                            describe one SSTable, optionally listing every entry
+                                            and checking it; exits 1 on corruption
+  serve <data_dir> --grpc <addr>            serve the database over gRPC (needs the grpc feature)
+  serve <data_dir> --resp <addr>            serve the database to Redis clients until SHUTDOWN
+  repl --path <data_dir>                    read put/get/del/scan/flush/compact/stats from stdin; creates the directory
+  demo [-v]                                 run the demo against ./data, wiping it first";
+
+/// Whether `arg` names a subcommand `run` handles
+pub fn is_command(arg: &str) -> bool {
+    matches!(
+        arg,
+        "flush"
+            | "compact"
+            | "verify"
+            | "stats"
+            | "backup"
+            | "verify-backup"
+            | "dump"
+            | "load"
+            | "sst-dump"
+            | "serve"
+            | "repl"
+    )
+}
+
+/// Parsed command line: positional arguments plus the flags any command takes
+struct Args<'a> {
+    command: &'a str,
+    positional: Vec<&'a str>,
+    json: bool,
+    level: Option<usize>,
+    all: bool,
+    grpc: Option<&'a str>,
+    resp: Option<&'a str>,
+    path: Option<&'a str>,
+    keys: bool,
+    values: bool,
+    verify: bool,
+}
+
+impl<'a> Args<'a> {
+    fn parse(args: &'a [String]) -> io::Result<Self> {
+        let usage = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let (command, rest) = args
+            .split_first()
+            .ok_or_else(|| usage("missing command".to_string()))?;
+        let mut parsed = Args {
+            command,
+            positional: Vec::new(),
+            json: false,
+            level: None,
+            all: false,
+            grpc: None,
+            resp: None,
+            path: None,
+            keys: false,
+            values: false,
+            verify: false,
+        };
+
+        let mut rest = rest.iter();
+        while let Some(arg) = rest.next() {
+            match arg.as_str() {
+                "--json" => parsed.json = true,
+                "--all" => parsed.all = true,
+                "--keys" => parsed.keys = true,
+                "--values" => parsed.values = true,
+                "--verify" => parsed.verify = true,
+                "--level" => {
+                    let level = rest
+                        .next()
+                        .ok_or_else(|| usage("--level needs a number".to_string()))?;
+                    parsed.level = Some(
+                        level
+                            .parse()
+                            .map_err(|_| usage(format!("invalid level {:?}", level)))?,
+                    );
+                }
+                "--grpc" => {
+                    parsed.grpc = Some(
+                        rest.next()
+                            .ok_or_else(|| usage("--grpc needs an address".to_string()))?,
+                    );
+                }
+                "--resp" => {
+                    parsed.resp = Some(
+                        rest.next()
+                            .ok_or_else(|| usage("--resp needs an address".to_string()))?,
+                    );
+                }
+                "--path" => {
+                    parsed.path = Some(
+                        rest.next()
+                            .ok_or_else(|| usage("--path needs a directory".to_string()))?,
+                    );
+                }
+                flag if flag.starts_with("--") => {
+                    return Err(usage(format!("unknown option {}", flag)))
+                }
+                positional => parsed.positional.push(positional),
+            }
+        }
+
+        let expected = match parsed.command {
+            "backup" | "dump" | "load" => 2,
+            "repl" => 0,
+            _ => 1,
+        };
+        if parsed.positional.len() != expected {
+            return Err(usage(format!(
+                "{} takes {} path argument(s)",
+                parsed.command, expected
+            )));
+        }
+        if (parsed.level.is_some() || parsed.all) && parsed.command != "compact" {
+            return Err(usage("--level and --all only apply to compact".to_string()));
+        }
+        if parsed.level.is_some() && parsed.all {
+            return Err(usage("--level and --all are exclusive".to_string()));
+        }
+        let servers = usize::from(parsed.grpc.is_some()) + usize::from(parsed.resp.is_some());
+        if servers != usize::from(parsed.command == "serve") {
+            return Err(usage(
+                "serve takes one of --grpc <addr> or --resp <addr>, and only serve".to_string(),
+            ));
+        }
+        if (parsed.keys || parsed.values || parsed.verify) && parsed.command != "sst-dump" {
+            return Err(usage(
+                "--keys, --values and --verify only apply to sst-dump".to_string(),
+            ));
+        }
+        if parsed.json && parsed.command == "sst-dump" {
+            return Err(usage("sst-dump has no --json output".to_string()));
+        }
+        if parsed.path.is_some() != (parsed.command == "repl") {
+            return Err(usage(
+                "repl takes --path <data_dir>, and only repl".to_string(),
+            ));
+        }
+        Ok(parsed)
+    }
+}
+
+/// Run the admin subcommand in `args` (without the program name), writing
+/// its report to `out` and any error to `err`. Returns the exit code: 0 on
+/// success, 1 on failure or corruption, 2 for a malformed command line.
+pub fn run(args: &[String], out: &mut impl Write, err: &mut impl Write) -> i32 {
+    let result = Args::parse(args).and_then(|args| match args.command {
+        "flush" => flush(&args, out),
+        "compact" => compact(&args, out),
+        "verify" => verify(&args, out),
+        "stats" => stats(&args, out),
+        "backup" => backup(&args, out),
+        "verify-backup" => verify_backup(&args, out),
+        "dump" => dump(&args, out),
+        "load" => load(&args, out),
+        "sst-dump" => sst_dump(&args, out),
+        "serve" => serve(&args, out),
+        "repl" => repl(&args, out),
+        command => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown command {:?}", command),
+        )),
+    });
+    match result {
+        Ok(code) => code,
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+            let _ = writeln!(err, "error: {}\n\n{}", e, USAGE);
+            2
+        }
+        Err(e) => {
+            let _ = writeln!(err, "error: {}", e);
+            1
+        }
+    }
+}
+
+/// Open an existing data directory; unlike `Storage::new`, never create one
+fn open(dir: &str) -> io::Result<Storage> {
+    if !Path::new(dir).is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no database at {}", dir),
+        ));
+    }
+    Ok(Storage::new(dir, false)?)
+}
+
+fn flush(args: &Args, out: &mut impl Write) -> io::Result<i32> {
+    let db = open(args.positional[0])?;
+    let flushed = db.flush_pending();
+    db.flush()?;
+    if args.json {
+        writeln!(out, "{{\"command\":\"flush\",\"flushed\":{}}}", flushed)?;
+    } else if flushed {
+        writeln!(out, "flushed memtable to level 0")?;
+    } else {
+        writeln!(out, "nothing to flush")?;
+    }
+    Ok(0)
+}
+
+fn compact(args: &Args, out: &mut impl Write) -> io::Result<i32> {
+    let mut db = open(args.positional[0])?;
+    let mut compacted = Vec::new();
+    if let Some(level) = args.level {
+        if db.compact_level(level)? {
+            compacted.push(level);
+        }
+    } else {
+        // Push every level down in turn until one table holds everything
+        let mut level = 0;
+        loop {
+            let levels = db.stats().levels;
+            let Some(deepest) = levels
+                .iter()
+                .filter(|l| l.file_count > 0)
+                .map(|l| l.level)
+                .max()
+            else {
+                break;
+            };
+            if level > deepest {
+                break;
+            }
+            let files = levels
+                .iter()
+                .find(|l| l.level == level)
+                .map_or(0, |l| l.file_count);
+            if (files > 0 && level < deepest) || files > 1 {
+                db.compact_level(level)?;
+                compacted.push(level);
+            }
+            level += 1;
+        }
+    }
+
+    let levels = db.stats().levels;
+    if args.json {
+        let compacted: Vec<String> = compacted.iter().map(usize::to_string).collect();
+        writeln!(
+            out,
+            "{{\"command\":\"compact\",\"compacted_levels\":[{}],\"levels\":{}}}",
+            compacted.join(","),
+            levels_json(&levels)
+        )?;
+    } else {
+        if compacted.is_empty() {
+            writeln!(out, "nothing to compact")?;
+        }
+        for level in &compacted {
+            writeln!(out, "compacted L{} into L{}", level, level + 1)?;
+        }
+        write_levels(&levels, out)?;
+    }
+    Ok(0)
+}
+
+fn verify(args: &Args, out: &mut impl Write) -> io::Result<i32> {
+    let db = open(args.positional[0])?;
+    write_report("verify", &db.verify(), args.json, out)
+}
+
+fn verify_backup(args: &Args, out: &mut impl Write) -> io::Result<i32> {
+    let dir = args.positional[0];
+    if !Path::new(dir).is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no backup at {}", dir),
+        ));
+    }
+    write_report(
+        "verify-backup",
+        &Storage::verify_checkpoint(dir),
+        args.json,
+        out,
+    )
+}
+
+fn socket_addr(addr: &str) -> io::Result<SocketAddr> {
+    addr.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid address {:?}", addr),
+        )
+    })
+}
+
+fn serve(args: &Args, out: &mut impl Write) -> io::Result<i32> {
+    let Some(addr) = args.resp else {
+        return serve_grpc(args, out);
+    };
+    let addr = socket_addr(addr)?;
+    let db = open(args.positional[0])?;
+    writeln!(out, "serving RESP on {}", addr)?;
+    out.flush()?;
+    crate::resp::serve(db, addr)?;
+    Ok(0)
+}
+
+#[cfg(feature = "grpc")]
+fn serve_grpc(args: &Args, out: &mut impl Write) -> io::Result<i32> {
+    let addr = socket_addr(args.grpc.unwrap_or_default())?;
+    let db = open(args.positional[0])?;
+    writeln!(out, "serving gRPC on {}", addr)?;
+    out.flush()?;
+    crate::grpc::serve(db, addr)?;
+    Ok(0)
+}
+
+#[cfg(not(feature = "grpc"))]
+fn serve_grpc(_args: &Args, _out: &mut impl Write) -> io::Result<i32> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "this build has no gRPC support; rebuild with --features grpc",
+    ))
+}
+
+/// Read commands from stdin against the database at `--path`, creating it
+/// if need be. The prompt only shows when a terminal is typing them.
+fn repl(args: &Args, out: &mut impl Write) -> io::Result<i32> {
+    let mut db = Storage::new(args.path.unwrap_or_default(), false)?;
+    let stdin = io::stdin();
+    let prompt = stdin.is_terminal();
+    repl::run(&mut db, stdin.lock(), out, prompt)?;
+    Ok(0)
+}
+
+/// Print a verification report, returning 1 if anything failed
+fn write_report(
+    command: &str,
+    report: &VerifyReport,
+    json: bool,
+    out: &mut impl Write,
+) -> io::Result<i32> {
+    if json {
+        let corrupt: Vec<String> = report
+            .corrupt
+            .iter()
+            .map(|(path, error)| {
+                format!(
+                    "{{\"path\":{},\"error\":{}}}",
+                    json_string(&path.to_string_lossy()),
+                    json_string(error)
+                )
+            })
+            .collect();
+        writeln!(
+            out,
+            "{{\"command\":\"{}\",\"ok\":{},\"tables\":{},\"entries\":{},\"corrupt\":[{}]}}",
+            command,
+            report.is_ok(),
+            report.tables,
+            report.entries,
+            corrupt.join(",")
+        )?;
+    } else {
+        for (path, error) in &report.corrupt {
+            writeln!(out, "corrupt {}: {}", path.display(), error)?;
+        }
+        writeln!(
+            out,
+            "checked {} tables, {} entries: {}",
+            report.tables,
+            report.entries,
+            if report.is_ok() {
+                "ok".to_string()
+            } else {
+                format!("{} corrupt", report.corrupt.len())
+            }
+        )?;
+    }
+    Ok(if report.is_ok() { 0 } else { 1 })
+}
+
+fn stats(args: &Args, out: &mut impl Write) -> io::Result<i32> {
+    let db = open(args.positional[0])?;
+    if args.json {
+        let stats = db.stats();
+        writeln!(
+            out,
+            "{{\"command\":\"stats\",\"entries\":{},\"tombstones\":{},\"bytes\":{},\
+             \"levels\":{},\"event_log_errors\":{}}}",
+            stats.stored.entry_count,
+            stats.stored.tombstone_count,
+            stats.levels.iter().map(|l| l.total_bytes).sum::<usize>(),
+            levels_json(&stats.levels),
+            stats.event_log_errors
+        )?;
+    } else {
+        write!(out, "{}", db.describe())?;
+    }
+    Ok(0)
+}
+
+fn backup(args: &Args, out: &mut impl Write) -> io::Result<i32> {
+    let db = open(args.positional[0])?;
+    let dest = args.positional[1];
+    let tables = db.checkpoint(dest)?;
+    if args.json {
+        writeln!(
+            out,
+            "{{\"command\":\"backup\",\"dest\":{},\"tables\":{}}}",
+            json_string(dest),
+            tables
+        )?;
+    } else {
+        writeln!(out, "backed up {} tables to {}", tables, dest)?;
+    }
+    Ok(0)
+}
+
+fn dump(args: &Args, out: &mut impl Write) -> io::Result<i32> {
+    let db = open(args.positional[0])?;
+    let file = args.positional[1];
+    let pairs = db.export_to(File::create(file)?)?;
+    if args.json {
+        writeln!(
+            out,
+            "{{\"command\":\"dump\",\"file\":{},\"pairs\":{}}}",
+            json_string(file),
+            pairs
+        )?;
+    } else {
+        writeln!(out, "dumped {} pairs to {}", pairs, file)?;
+    }
+    Ok(0)
+}
+
+fn load(args: &Args, out: &mut impl Write) -> io::Result<i32> {
+    let file = File::open(args.positional[1])?;
+    let mut db = Storage::new(args.positional[0], false)?;
+    let pairs = db.import_from(file)?;
+    if args.json {
+        writeln!(out, "{{\"command\":\"load\",\"pairs\":{}}}", pairs)?;
+    } else {
+        writeln!(out, "loaded {} pairs", pairs)?;
+    }
+    Ok(0)
+}
+
+/// Describe one table file, which needn't belong to a database, in any
+/// format this build reads. Damage is reported in the description and exits
+/// 1.
+fn sst_dump(args: &Args, out: &mut impl Write) -> io::Result<i32> {
+    let path = Path::new(args.positional[0]);
+    if !path.is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no SSTable at {}", path.display()),
+        ));
+    }
+    let table = SSTable::open_legacy(path.to_path_buf())?;
+    let options = DescribeOptions {
+        keys: args.keys,
+        values: args.values,
+        verify: args.verify,
+    };
+    let mut description = String::new();
+    let healthy = table
+        .describe_to(options, &mut description)
+        .expect("writing to a String cannot fail");
+    out.write_all(description.as_bytes())?;
+    Ok(if healthy { 0 } else { 1 })
+}
+
+fn levels_json(levels: &[LevelStats]) -> String {
+    let levels: Vec<String> = levels
+        .iter()
+        .filter(|l| l.file_count > 0)
+        .map(|l| {
+            format!(
+                "{{\"level\":{},\"files\":{},\"bytes\":{},\"filter_bytes\":{},\"entries\":{}}}",
+                l.level, l.file_count, l.total_bytes, l.filter_bytes, l.properties.entry_count
+            )
+        })
+        .collect();
+    format!("[{}]", levels.join(","))
+}
+
+fn write_levels(levels: &[LevelStats], out: &mut impl Write) -> io::Result<()> {
+    for l in levels.iter().filter(|l| l.file_count > 0) {
+        writeln!(
+            out,
+            "L{}: {} files, {} bytes, {} filter bytes, {} entries",
+            l.level, l.file_count, l.total_bytes, l.filter_bytes, l.properties.entry_count
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn populated() -> TempDir {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        for i in 0..300 {
+            let key = format!("key{:03}", i).into_bytes();
+            storage.put(key, vec![b'v'; 100]).unwrap();
+            if i % 100 == 99 {
+                storage.flush().unwrap();
+            }
+        }
+        storage.put(b"pending", b"v").unwrap();
+        temp_dir
+    }
+
+    /// Run a command line, returning the exit code, stdout and stderr
+    fn lsm(args: &[&str]) -> (i32, String, String) {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let code = run(&args, &mut out, &mut err);
+        (
+            code,
+            String::from_utf8(out).unwrap(),
+            String::from_utf8(err).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_flush_and_compact() {
+        let temp_dir = populated();
+        let dir = temp_dir.path().to_str().unwrap();
+
+        let (code, out, _) = lsm(&["compact", dir, "--level", "3", "--json"]);
+        assert_eq!(code, 0);
+        assert!(out.contains("\"compacted_levels\":[]"), "{}", out);
+
+        let (code, out, _) = lsm(&["compact", dir, "--level", "0", "--json"]);
+        assert_eq!(code, 0);
+        assert!(
+            out.starts_with(
+                "{\"command\":\"compact\",\"compacted_levels\":[0],\
+                 \"levels\":[{\"level\":1,\"files\":1,"
+            ),
+            "{}",
+            out
+        );
+        assert!(out.contains("\"entries\":300}"), "{}", out);
+
+        let (code, out, _) = lsm(&["flush", dir, "--json"]);
+        assert_eq!(code, 0);
+        assert_eq!(out.trim(), "{\"command\":\"flush\",\"flushed\":true}");
+        let (_, out, _) = lsm(&["flush", dir]);
+        assert_eq!(out.trim(), "nothing to flush");
+
+        // Everything ends up in a single table one level down
+        let (code, out, _) = lsm(&["compact", dir, "--all"]);
+        assert_eq!(code, 0);
+        assert!(
+            out.starts_with("compacted L0 into L1\ncompacted L1 into L2\nL2: 1 files"),
+            "{}",
+            out
+        );
+        assert!(out.ends_with("301 entries\n"), "{}", out);
+        let (_, out, _) = lsm(&["compact", dir]);
+        assert!(
+            out.starts_with("nothing to compact\nL2: 1 files"),
+            "{}",
+            out
+        );
+    }
+
+    #[test]
+    fn test_verify_reports_corruption() {
+        let temp_dir = populated();
+        let dir = temp_dir.path().to_str().unwrap();
+
+        let (code, out, _) = lsm(&["verify", dir, "--json"]);
+        assert_eq!(code, 0);
+        assert_eq!(
+            out.trim(),
+            "{\"command\":\"verify\",\"ok\":true,\"tables\":3,\"entries\":300,\"corrupt\":[]}"
+        );
+
+        let table = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().is_some_and(|ext| ext == "sst"))
+            .unwrap();
+        let len = fs::metadata(&table).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&table)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+
+        let (code, out, _) = lsm(&["verify", dir]);
+        assert_eq!(code, 1);
+        assert!(out.contains(&format!("corrupt {}", table.display())));
+        assert!(out.ends_with("checked 3 tables, 200 entries: 1 corrupt\n"));
+    }
+
+    #[test]
+    fn test_stats_and_backup() {
+        let temp_dir = populated();
+        let dir = temp_dir.path().to_str().unwrap();
+
+        let (code, out, _) = lsm(&["stats", dir, "--json"]);
+        assert_eq!(code, 0);
+        assert!(out.starts_with("{\"command\":\"stats\",\"entries\":300,\"tombstones\":0,"));
+        assert!(out.contains("{\"level\":0,\"files\":3,"));
+        let (_, out, _) = lsm(&["stats", dir]);
+        assert!(out.contains("memtable: 1 entries"));
+
+        let dest_dir = TempDir::new().unwrap();
+        let dest = dest_dir.path().join("backup");
+        let dest = dest.to_str().unwrap();
+        // Flushing the pending write first fills L0 and compacts it to one table
+        let (code, out, _) = lsm(&["backup", dir, dest, "--json"]);
+        assert_eq!(code, 0);
+        assert_eq!(
+            out.trim(),
+            format!(
+                "{{\"command\":\"backup\",\"dest\":{},\"tables\":1}}",
+                json_string(dest)
+            )
+        );
+        let copy = Storage::new(dest, false).unwrap();
+        assert_eq!(copy.get(b"pending").unwrap(), Some(b"v".to_vec()));
+
+        // Backing up over an existing backup fails without touching it
+        let (code, _, err) = lsm(&["backup", dir, dest]);
+        assert_eq!(code, 1);
+        assert!(err.contains("not empty"));
+
+        let (code, out, _) = lsm(&["verify-backup", dest]);
+        assert_eq!(code, 0);
+        assert_eq!(out, "checked 1 tables, 301 entries: ok\n");
+        for entry in fs::read_dir(dest).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|ext| ext == "sst") {
+                fs::remove_file(path).unwrap();
+            }
+        }
+        let (code, out, _) = lsm(&["verify-backup", dest, "--json"]);
+        assert_eq!(code, 1);
+        assert!(out.starts_with("{\"command\":\"verify-backup\",\"ok\":false,"));
+    }
+
+    #[test]
+    fn test_dump_and_load() {
+        let temp_dir = populated();
+        let dir = temp_dir.path().to_str().unwrap();
+        let dest_dir = TempDir::new().unwrap();
+        let file = dest_dir.path().join("db.dump");
+        let file = file.to_str().unwrap();
+
+        let (code, out, _) = lsm(&["dump", dir, file]);
+        assert_eq!(code, 0);
+        assert_eq!(out, format!("dumped 301 pairs to {}\n", file));
+
+        let copy = dest_dir.path().join("copy");
+        let copy = copy.to_str().unwrap();
+        let (code, out, _) = lsm(&["load", copy, file, "--json"]);
+        assert_eq!(code, 0);
+        assert_eq!(out, "{\"command\":\"load\",\"pairs\":301}\n");
+        let original = Storage::new(dir, false).unwrap();
+        let loaded = Storage::new(copy, false).unwrap();
+        let scan = |db: &Storage| {
+            db.scan(b"", b"")
+                .unwrap()
+                .map(Result::unwrap)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(scan(&loaded), scan(&original));
+
+        // Anything else is refused
+        let (code, _, err) = lsm(&["load", copy, dir]);
+        assert_eq!(code, 1);
+        assert!(err.starts_with("error: "));
+    }
+
+    #[test]
+    fn test_sst_dump() {
+        let temp_dir = populated();
+        let table = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().is_some_and(|ext| ext == "sst"))
+            .unwrap();
+        let file = table.to_str().unwrap();
+
+        let (code, out, _) = lsm(&["sst-dump", file]);
+        assert_eq!(code, 0);
+        let format = format!("\nformat: version {}, ", crate::TABLE_FORMAT_VERSION);
+        assert!(out.contains(&format), "{}", out);
+        assert!(out.contains("\nentries: 100 (0 tombstones), "), "{}", out);
+        assert!(!out.contains("\n\"key"), "{}", out);
+        let (code, out, _) = lsm(&["sst-dump", file, "--values", "--verify"]);
+        assert_eq!(code, 0);
+        assert_eq!(out.matches(" put \"vvvv").count(), 100);
+        assert!(out.ends_with("\nverify: ok, 100 entries\n"), "{}", out);
+
+        let len = fs::metadata(&table).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&table)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+        let (code, out, _) = lsm(&["sst-dump", file, "--verify"]);
+        assert_eq!(code, 1);
+        assert!(out.contains("\nverify: corrupt at offset "), "{}", out);
+    }
+
+    #[test]
+    fn test_bad_command_lines() {
+        let temp_dir = TempDir::new().unwrap();
+        let missing = temp_dir.path().join("missing");
+        let missing = missing.to_str().unwrap();
+
+        let (code, _, err) = lsm(&["stats", missing]);
+        assert_eq!(code, 1);
+        assert!(err.contains("no database"));
+        assert!(!Path::new(missing).exists());
+        let (code, _, err) = lsm(&["sst-dump", missing]);
+        assert_eq!(code, 1);
+        assert!(err.contains("no SSTable"));
+
+        for args in [
+            &["stats"][..],
+            &["backup", missing],
+            &["stats", missing, "--all"],
+            &["compact", missing, "--level", "x"],
+            &["compact", missing, "--level", "1", "--all"],
+            &["verify", missing, "--bogus"],
+            &["serve", missing],
+            &["stats", missing, "--grpc", "127.0.0.1:0"],
+            &[
+                "serve",
+                missing,
+                "--grpc",
+                "127.0.0.1:0",
+                "--resp",
+                "127.0.0.1:0",
+            ],
+            &["serve", missing, "--resp", "localhost"],
+            &["repl"],
+            &["repl", missing],
+            &["repl", "--path"],
+            &["stats", missing, "--path", missing],
+            &["stats", missing, "--keys"],
+            &["sst-dump", missing, "--json"],
+        ] {
+            let (code, _, err) = lsm(args);
+            assert_eq!(code, 2, "{:?}", args);
+            assert!(err.contains("usage:"));
+        }
+    }
+}
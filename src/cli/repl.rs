@@ -0,0 +1,438 @@
+//! The interactive shell behind `lsm-rust repl`: one command per line,
+//! run against an open database.
+//!
+//! Keys and values are single words, or double-quoted to hold spaces
+//! (with `\"` and `\\` escapes). Anything starting with `hex:` outside
+//! quotes is decoded as hex, for binary data.
+
+use std::io::{self, BufRead, Write};
+
+use crate::storage::Storage;
+
+const HELP: &str = "\
+commands:
+  put <key> <value>       write a value
+  get <key>               print the value, or (nil)
+  del <key>               delete a key
+  scan [start] [end]      print every live pair in [start, end); an empty end runs to the last key
+  flush                   write the memtable out to level 0
+  compact                 merge every level down into one
+  stats                   describe the tree
+  exit                    leave (as does end of input)
+keys and values: word, \"quoted words\", or hex:00ff";
+
+/// One line of input, parsed
+#[derive(Debug, PartialEq)]
+pub(super) enum Command {
+    Put(Vec<u8>, Vec<u8>),
+    Get(Vec<u8>),
+    Del(Vec<u8>),
+    Scan(Vec<u8>, Vec<u8>),
+    Flush,
+    Compact,
+    Stats,
+    Help,
+    Exit,
+}
+
+impl Command {
+    /// Parse a line, or `None` if it's blank
+    pub(super) fn parse(line: &str) -> io::Result<Option<Command>> {
+        let mut words = tokenize(line)?.into_iter();
+        let Some(name) = words.next() else {
+            return Ok(None);
+        };
+        let args: Vec<Vec<u8>> = words.collect();
+        let arity = |range: std::ops::RangeInclusive<usize>, usage: &str| {
+            if range.contains(&args.len()) {
+                Ok(())
+            } else {
+                Err(invalid(format!("usage: {}", usage)))
+            }
+        };
+
+        let mut args_iter = args.iter().cloned();
+        let mut next = || args_iter.next().unwrap_or_default();
+        let command = match name.as_slice() {
+            b"put" => {
+                arity(2..=2, "put <key> <value>")?;
+                Command::Put(next(), next())
+            }
+            b"get" => {
+                arity(1..=1, "get <key>")?;
+                Command::Get(next())
+            }
+            b"del" => {
+                arity(1..=1, "del <key>")?;
+                Command::Del(next())
+            }
+            b"scan" => {
+                arity(0..=2, "scan [start] [end]")?;
+                Command::Scan(next(), next())
+            }
+            b"flush" => {
+                arity(0..=0, "flush")?;
+                Command::Flush
+            }
+            b"compact" => {
+                arity(0..=0, "compact")?;
+                Command::Compact
+            }
+            b"stats" => {
+                arity(0..=0, "stats")?;
+                Command::Stats
+            }
+            b"help" => Command::Help,
+            b"exit" | b"quit" => Command::Exit,
+            other => {
+                return Err(invalid(format!(
+                    "unknown command {:?}; try help",
+                    String::from_utf8_lossy(other)
+                )))
+            }
+        };
+        Ok(Some(command))
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// Split `line` into words, unquoting quoted ones and decoding `hex:` ones
+fn tokenize(line: &str) -> io::Result<Vec<Vec<u8>>> {
+    let mut words = Vec::new();
+    let mut chars = line.trim().chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        if c == '"' {
+            chars.next();
+            let mut word = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(escaped @ ('"' | '\\')) => word.push(escaped),
+                        _ => return Err(invalid("only \\\" and \\\\ can be escaped".into())),
+                    },
+                    Some(c) => word.push(c),
+                    None => return Err(invalid("unterminated quote".into())),
+                }
+            }
+            if chars.peek().is_some_and(|c| !c.is_whitespace()) {
+                return Err(invalid("closing quote must end the word".into()));
+            }
+            words.push(word.into_bytes());
+        } else {
+            let mut word = String::new();
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                word.push(c);
+            }
+            match word.strip_prefix("hex:") {
+                Some(hex) => words.push(decode_hex(hex)?),
+                None => words.push(word.into_bytes()),
+            }
+        }
+    }
+    Ok(words)
+}
+
+fn decode_hex(hex: &str) -> io::Result<Vec<u8>> {
+    let bad = || invalid(format!("invalid hex {:?}", hex));
+    if !hex.len().is_multiple_of(2) {
+        return Err(bad());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(bad)
+        })
+        .collect()
+}
+
+/// `bytes` the way they'd be typed back in: bare when that reads the same,
+/// quoted when they hold spaces or quotes, and as hex when not text
+fn display(bytes: &[u8]) -> String {
+    let Ok(text) = std::str::from_utf8(bytes) else {
+        return hex(bytes);
+    };
+    if text.chars().any(|c| c.is_control()) {
+        return hex(bytes);
+    }
+    let bare = !text.is_empty()
+        && !text.starts_with("hex:")
+        && !text
+            .chars()
+            .any(|c| c.is_whitespace() || c == '"' || c == '\\');
+    if bare {
+        return text.to_string();
+    }
+    let escaped = text.replace('\\', "\\\\").replace('"', "\\\"");
+    format!("\"{}\"", escaped)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let digits: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("hex:{}", digits)
+}
+
+/// Run one command, printing its result. Returns false once it's time to
+/// leave.
+pub(super) fn execute(
+    db: &mut Storage,
+    command: Command,
+    out: &mut impl Write,
+) -> io::Result<bool> {
+    match command {
+        Command::Put(key, value) => {
+            db.put(key, value)?;
+            writeln!(out, "OK")?;
+        }
+        Command::Get(key) => match db.get(&key)? {
+            Some(value) => writeln!(out, "{}", display(&value))?,
+            None => writeln!(out, "(nil)")?,
+        },
+        Command::Del(key) => {
+            db.delete(&key)?;
+            writeln!(out, "OK")?;
+        }
+        Command::Scan(start, end) => {
+            let mut count = 0;
+            for pair in db.scan(&start, &end)? {
+                let (key, value) = pair?;
+                writeln!(out, "{} {}", display(&key), display(&value))?;
+                count += 1;
+            }
+            writeln!(out, "({} keys)", count)?;
+        }
+        Command::Flush => {
+            let pending = db.flush_pending();
+            db.flush()?;
+            if pending {
+                writeln!(out, "flushed memtable to level 0")?;
+            } else {
+                writeln!(out, "nothing to flush")?;
+            }
+        }
+        Command::Compact => {
+            db.compact_all()?;
+            writeln!(out, "OK")?;
+        }
+        Command::Stats => write!(out, "{}", db.describe())?,
+        Command::Help => writeln!(out, "{}", HELP)?,
+        Command::Exit => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Read commands from `input` until `exit` or its end, showing a prompt
+/// before each when `prompt` is set. A bad command or failed operation is
+/// reported and the loop carries on; only failing to read or write stops it.
+pub(super) fn run(
+    db: &mut Storage,
+    input: impl BufRead,
+    out: &mut impl Write,
+    prompt: bool,
+) -> io::Result<()> {
+    let mut lines = input.lines();
+    loop {
+        if prompt {
+            write!(out, "lsm> ")?;
+            out.flush()?;
+        }
+        let Some(line) = lines.next().transpose()? else {
+            return Ok(());
+        };
+        let result = Command::parse(&line).and_then(|command| match command {
+            Some(command) => execute(db, command, out),
+            None => Ok(true),
+        });
+        match result {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(e) => writeln!(out, "error: {}", e)?,
+        }
+        out.flush()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn words(line: &str) -> Vec<Vec<u8>> {
+        tokenize(line).unwrap()
+    }
+
+    /// Feed `input` to a fresh shell, returning what it printed
+    fn session(db: &mut Storage, input: &str) -> String {
+        let mut out = Vec::new();
+        run(db, input.as_bytes(), &mut out, false).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_tokenize_quotes_and_hex() {
+        assert_eq!(
+            words("  put  a   b "),
+            vec![b"put".to_vec(), b"a".to_vec(), b"b".to_vec()]
+        );
+        assert_eq!(
+            words(r#"put "new york" "say \"hi\" \\o/""#),
+            vec![
+                b"put".to_vec(),
+                b"new york".to_vec(),
+                br#"say "hi" \o/"#.to_vec()
+            ]
+        );
+        assert_eq!(words(r#"get """#), vec![b"get".to_vec(), Vec::new()]);
+        assert_eq!(
+            words("get hex:00ff7A"),
+            vec![b"get".to_vec(), vec![0, 0xff, 0x7a]]
+        );
+        // Quoting keeps the prefix literal
+        assert_eq!(
+            words(r#"get "hex:00""#),
+            vec![b"get".to_vec(), b"hex:00".to_vec()]
+        );
+        assert!(words("").is_empty());
+
+        for bad in [
+            r#"get "open"#,
+            r#"get "a"b"#,
+            r#"get "\n""#,
+            "get hex:0",
+            "get hex:zz",
+        ] {
+            let err = tokenize(bad).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_parse_each_command() {
+        let parse = |line: &str| Command::parse(line).unwrap();
+        assert_eq!(parse("   "), None);
+        assert_eq!(
+            parse("put k v"),
+            Some(Command::Put(b"k".to_vec(), b"v".to_vec()))
+        );
+        assert_eq!(parse("get k"), Some(Command::Get(b"k".to_vec())));
+        assert_eq!(parse("del k"), Some(Command::Del(b"k".to_vec())));
+        assert_eq!(parse("scan"), Some(Command::Scan(Vec::new(), Vec::new())));
+        assert_eq!(
+            parse("scan a"),
+            Some(Command::Scan(b"a".to_vec(), Vec::new()))
+        );
+        assert_eq!(
+            parse("scan a z"),
+            Some(Command::Scan(b"a".to_vec(), b"z".to_vec()))
+        );
+        assert_eq!(parse("flush"), Some(Command::Flush));
+        assert_eq!(parse("compact"), Some(Command::Compact));
+        assert_eq!(parse("stats"), Some(Command::Stats));
+        assert_eq!(parse("help"), Some(Command::Help));
+        assert_eq!(parse("exit"), Some(Command::Exit));
+        assert_eq!(parse("quit"), Some(Command::Exit));
+
+        for bad in ["put k", "get", "del a b", "scan a b c", "flush now", "frob"] {
+            let err = Command::parse(bad).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_put_get_del_and_scan() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Storage::new(temp_dir.path(), false).unwrap();
+        let out = session(
+            &mut db,
+            r#"put name "John Doe"
+put city "New York"
+put bin hex:00ff
+get name
+get missing
+scan
+del name
+get name
+scan c
+scan "" c
+"#,
+        );
+        assert_eq!(
+            out,
+            "OK\nOK\nOK\n\
+             \"John Doe\"\n\
+             (nil)\n\
+             bin hex:00ff\ncity \"New York\"\nname \"John Doe\"\n(3 keys)\n\
+             OK\n\
+             (nil)\n\
+             city \"New York\"\n(1 keys)\n\
+             bin hex:00ff\n(1 keys)\n"
+        );
+        assert_eq!(db.get(b"bin").unwrap(), Some(vec![0, 0xff]));
+    }
+
+    #[test]
+    fn test_flush_compact_and_stats() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Storage::new(temp_dir.path(), false).unwrap();
+        let out = session(
+            &mut db,
+            "put a 1\nflush\nflush\nput b 2\nflush\ncompact\nstats\n",
+        );
+        assert!(
+            out.starts_with(
+                "OK\nflushed memtable to level 0\nnothing to flush\n\
+                 OK\nflushed memtable to level 0\nOK\n"
+            ),
+            "{}",
+            out
+        );
+        assert!(out.contains("memtable: 0 entries"), "{}", out);
+        assert_eq!(db.scan(b"", b"").unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_errors_are_reported_and_exit_stops() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Storage::new(temp_dir.path(), false).unwrap();
+        let out = session(&mut db, "bogus\nput k\n\nput k v\nexit\nput late v\n");
+        assert_eq!(
+            out,
+            "error: unknown command \"bogus\"; try help\n\
+             error: usage: put <key> <value>\n\
+             OK\n"
+        );
+        assert_eq!(db.get(b"late").unwrap(), None);
+
+        // The prompt comes before every line read, including the last
+        let mut out = Vec::new();
+        run(&mut db, "get k\n".as_bytes(), &mut out, true).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "lsm> v\nlsm> ");
+    }
+
+    #[test]
+    fn test_display_round_trips() {
+        for bytes in [
+            &b"plain"[..],
+            b"two words",
+            br#"quote" and \"#,
+            b"",
+            b"hex:literal",
+            &[0, 1, 0xff],
+            b"tab\there",
+        ] {
+            let shown = display(bytes);
+            assert_eq!(words(&shown), vec![bytes.to_vec()], "{}", shown);
+        }
+        assert_eq!(display(b"plain"), "plain");
+        assert_eq!(display(&[0xff, 0xfe]), "hex:fffe");
+    }
+}
@@ -0,0 +1,160 @@
+use std::io;
+use std::sync::Arc;
+
+use crate::Value;
+
+/// Combines merge operands with the value beneath them. It's called with
+/// the key, the existing value if there is one, and the operands oldest
+/// first, and returns the new value. Applying operands a few at a time
+/// must give the same result as applying them all at once.
+pub type MergeOperator = Arc<dyn Fn(&[u8], Option<&[u8]>, &[&[u8]]) -> Vec<u8> + Send + Sync>;
+
+/// A single version of a key, as held by the memtable and SSTables
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    Value(Value),
+    /// A value that reads as deleted from `expires_at` (milliseconds since
+    /// the epoch) on
+    Expiring {
+        value: Value,
+        expires_at: u64,
+    },
+    /// The key was deleted at `deleted_at` (milliseconds since the epoch)
+    Tombstone {
+        deleted_at: u64,
+    },
+    /// An operand for the merge operator, applied on top of the versions
+    /// beneath it when read
+    Merge(Value),
+}
+
+/// An [`Entry`] stamped with the sequence number of the write that produced it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    pub seq: u64,
+    pub entry: Entry,
+}
+
+/// Borrowed counterpart of [`Entry`], used when decoding into scratch buffers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryRef<'a> {
+    Value(&'a [u8]),
+    Expiring { value: &'a [u8], expires_at: u64 },
+    Tombstone { deleted_at: u64 },
+    Merge(&'a [u8]),
+}
+
+impl Entry {
+    pub fn as_entry_ref(&self) -> EntryRef<'_> {
+        match self {
+            Entry::Value(value) => EntryRef::Value(value),
+            Entry::Expiring { value, expires_at } => EntryRef::Expiring {
+                value,
+                expires_at: *expires_at,
+            },
+            Entry::Tombstone { deleted_at } => EntryRef::Tombstone {
+                deleted_at: *deleted_at,
+            },
+            Entry::Merge(operand) => EntryRef::Merge(operand),
+        }
+    }
+
+    /// The stored value or merge operand, expired or not
+    pub fn value(&self) -> Option<&Value> {
+        match self {
+            Entry::Value(value) | Entry::Expiring { value, .. } | Entry::Merge(value) => {
+                Some(value)
+            }
+            Entry::Tombstone { .. } => None,
+        }
+    }
+
+    /// The value as read at time `now`: none for a tombstone, an expired
+    /// entry or a merge operand, which needs the versions beneath it
+    pub fn live_value(&self, now: u64) -> Option<&Value> {
+        match self {
+            Entry::Value(value) => Some(value),
+            Entry::Expiring { value, expires_at } if *expires_at > now => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn into_live_value(self, now: u64) -> Option<Value> {
+        match self {
+            Entry::Value(value) => Some(value),
+            Entry::Expiring { value, expires_at } if expires_at > now => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Whether the key reads as holding a value at time `now`, as it does
+    /// with a merge operand on top
+    pub fn is_live(&self, now: u64) -> bool {
+        self.is_merge() || self.live_value(now).is_some()
+    }
+
+    pub fn is_tombstone(&self) -> bool {
+        matches!(self, Entry::Tombstone { .. })
+    }
+
+    pub fn is_merge(&self) -> bool {
+        matches!(self, Entry::Merge(_))
+    }
+}
+
+/// The value `key` reads as at time `now`, given its entries newest first:
+/// merge operands on top are applied with `merge` to the value beneath
+/// them, or to none below a tombstone or the oldest entry. Entries past
+/// the first that isn't an operand don't matter.
+pub(crate) fn resolve<'a>(
+    key: &[u8],
+    entries: impl IntoIterator<Item = &'a Entry>,
+    now: u64,
+    merge: Option<&MergeOperator>,
+) -> io::Result<Option<Value>> {
+    let mut operands: Vec<&[u8]> = Vec::new();
+    let mut base = None;
+    for entry in entries {
+        match entry {
+            Entry::Merge(operand) => operands.push(operand),
+            entry => {
+                base = entry.live_value(now);
+                break;
+            }
+        }
+    }
+    if operands.is_empty() {
+        return Ok(base.cloned());
+    }
+    let merge = merge.ok_or_else(no_merge_operator)?;
+    operands.reverse();
+    Ok(Some(merge(key, base.map(Vec::as_slice), &operands)))
+}
+
+/// Merge operands were found without a merge operator to apply them
+pub(crate) fn no_merge_operator() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Merge operands can't be read without a merge operator",
+    )
+}
+
+impl Version {
+    pub fn new(seq: u64, entry: Entry) -> Self {
+        Version { seq, entry }
+    }
+}
+
+impl EntryRef<'_> {
+    pub fn to_entry(self) -> Entry {
+        match self {
+            EntryRef::Value(value) => Entry::Value(value.to_vec()),
+            EntryRef::Expiring { value, expires_at } => Entry::Expiring {
+                value: value.to_vec(),
+                expires_at,
+            },
+            EntryRef::Tombstone { deleted_at } => Entry::Tombstone { deleted_at },
+            EntryRef::Merge(operand) => Entry::Merge(operand.to_vec()),
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// What went wrong, in more detail than an `io::ErrorKind`.
+///
+/// `Storage`, `SSTable`, `WAL` and `BloomFilter::from_bytes` return it,
+/// so callers can match on the variant:
+///
+/// ```
+/// # use lsm_rust::{Storage, StorageError, StorageOptions};
+/// # let dir = tempfile::tempdir().unwrap();
+/// let options = StorageOptions::default().memtable_size(0);
+/// let err = Storage::open_with_options(dir.path(), options).err().unwrap();
+/// assert!(matches!(err, StorageError::InvalidArgument(_)));
+/// ```
+///
+/// Internally it travels inside an `io::Error`, and `StorageError::from`
+/// recovers the variant, so `?` converts either way.
+#[derive(Debug)]
+pub enum StorageError {
+    /// Any other failure, such as a full disk or a missing file
+    Io(io::Error),
+    /// File contents that can't be decoded, at `offset` within `file` (or
+    /// within the restored data of a compressed table)
+    Corruption {
+        file: PathBuf,
+        offset: u64,
+        detail: String,
+    },
+    /// An argument or option the operation can't accept
+    InvalidArgument(String),
+    /// `file` was written in format `version`, newer than the `supported`
+    /// one this build reads
+    UnsupportedVersion {
+        file: PathBuf,
+        version: u32,
+        supported: u32,
+    },
+    /// A WAL record that passed its checksum but can't be decoded, starting
+    /// at `offset` within log `segment`
+    WalReplay {
+        segment: u64,
+        offset: u64,
+        detail: String,
+    },
+}
+
+impl StorageError {
+    /// The kind the error carries as an `io::Error`
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            StorageError::Io(e) => e.kind(),
+            StorageError::Corruption { .. } | StorageError::WalReplay { .. } => {
+                io::ErrorKind::InvalidData
+            }
+            StorageError::InvalidArgument(_) => io::ErrorKind::InvalidInput,
+            StorageError::UnsupportedVersion { .. } => io::ErrorKind::Unsupported,
+        }
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Io(e) => e.fmt(f),
+            StorageError::Corruption {
+                file,
+                offset,
+                detail,
+            } => write!(f, "Corrupt {:?} at offset {}: {}", file, offset, detail),
+            StorageError::InvalidArgument(detail) => f.write_str(detail),
+            StorageError::UnsupportedVersion {
+                file,
+                version,
+                supported,
+            } => write!(
+                f,
+                "{:?} uses format version {}, but this build only supports up to {}",
+                file, version, supported
+            ),
+            StorageError::WalReplay {
+                segment,
+                offset,
+                detail,
+            } => write!(
+                f,
+                "WAL segment {} can't be replayed at offset {}: {}",
+                segment, offset, detail
+            ),
+        }
+    }
+}
+
+impl Error for StorageError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            StorageError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for StorageError {
+    /// Unwrap the variant an `io::Error` carries. Plain `InvalidInput`
+    /// errors, such as rejected options, are invalid arguments; anything
+    /// else unclassified stays `Io`.
+    fn from(e: io::Error) -> Self {
+        if e.get_ref().is_some_and(|inner| inner.is::<StorageError>()) {
+            let inner = e.into_inner().unwrap();
+            return *inner.downcast::<StorageError>().unwrap();
+        }
+        if e.kind() == io::ErrorKind::InvalidInput {
+            return StorageError::InvalidArgument(e.to_string());
+        }
+        StorageError::Io(e)
+    }
+}
+
+impl From<StorageError> for io::Error {
+    fn from(e: StorageError) -> Self {
+        match e {
+            StorageError::Io(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
+
+/// Damage to `file` at `offset`, as an `io::Error`
+pub(crate) fn corruption(file: &Path, offset: u64, detail: impl Into<String>) -> io::Error {
+    StorageError::Corruption {
+        file: file.to_path_buf(),
+        offset,
+        detail: detail.into(),
+    }
+    .into()
+}
+
+/// `e` as damage to `file` at `offset` if it reports data that can't be
+/// decoded or ends early, and isn't classified already. Other errors pass
+/// through.
+pub(crate) fn locate(e: io::Error, file: &Path, offset: u64) -> io::Error {
+    let classified = e.get_ref().is_some_and(|inner| inner.is::<StorageError>());
+    match e.kind() {
+        _ if classified => e,
+        io::ErrorKind::InvalidData => corruption(file, offset, e.to_string()),
+        io::ErrorKind::UnexpectedEof => corruption(file, offset, "cut short"),
+        _ => e,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_variants_survive_io_error() {
+        let err = corruption(Path::new("L0_1.sst"), 42, "bad entry");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            "Corrupt \"L0_1.sst\" at offset 42: bad entry"
+        );
+        match StorageError::from(err) {
+            StorageError::Corruption { file, offset, .. } => {
+                assert_eq!((file.as_path(), offset), (Path::new("L0_1.sst"), 42))
+            }
+            other => panic!("expected corruption, got {:?}", other),
+        }
+
+        let full = io::Error::new(io::ErrorKind::StorageFull, "disk full");
+        let err = StorageError::from(full);
+        assert!(matches!(err, StorageError::Io(_)));
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::StorageFull);
+
+        let invalid = io::Error::new(io::ErrorKind::InvalidInput, "no");
+        assert!(matches!(
+            StorageError::from(invalid),
+            StorageError::InvalidArgument(detail) if detail == "no"
+        ));
+    }
+
+    #[test]
+    fn test_locate_only_classifies_damage() {
+        let file = Path::new("L1_2.sst");
+        let eof = io::Error::from(io::ErrorKind::UnexpectedEof);
+        assert!(matches!(
+            StorageError::from(locate(eof, file, 7)),
+            StorageError::Corruption { offset: 7, .. }
+        ));
+
+        // Already located errors keep their offset
+        let located = locate(corruption(file, 3, "bad"), file, 9);
+        assert!(matches!(
+            StorageError::from(located),
+            StorageError::Corruption { offset: 3, .. }
+        ));
+
+        let missing = io::Error::from(io::ErrorKind::NotFound);
+        assert_eq!(locate(missing, file, 0).kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,288 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+
+use crate::error::StorageError;
+use crate::storage::{SharedStorage, Storage};
+
+#[allow(clippy::all)]
+pub mod proto {
+    tonic::include_proto!("lsm");
+}
+
+use proto::lsm_server::{Lsm, LsmServer};
+use proto::{
+    DeleteRequest, DeleteResponse, FlushRequest, FlushResponse, GetRequest, GetResponse, KeyValue,
+    MaybeValue, MultiGetRequest, MultiGetResponse, PutRequest, PutResponse, ScanRequest,
+    StatsRequest, StatsResponse,
+};
+
+// Pairs a scan stream buffers ahead of a slow client
+const SCAN_BUFFER: usize = 64;
+
+/// Storage shared by every request: reads run in parallel, and never wait
+/// for a flush or compaction to write its table
+type Shared = Arc<SharedStorage>;
+
+/// The `lsm.Lsm` service from `proto/lsm.proto`
+pub struct LsmService {
+    storage: Shared,
+}
+
+impl LsmService {
+    pub fn new(storage: Shared) -> Self {
+        LsmService { storage }
+    }
+
+    /// Run `f` against the storage on a blocking thread, since every
+    /// storage call may do disk I/O
+    async fn with_storage<T, F>(&self, f: F) -> Result<T, Status>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Shared) -> Result<T, StorageError> + Send + 'static,
+    {
+        let storage = self.storage.clone();
+        tokio::task::spawn_blocking(move || f(&storage))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(status)
+    }
+}
+
+/// Map a storage error onto the closest gRPC status
+fn status(e: StorageError) -> Status {
+    match e.kind() {
+        io::ErrorKind::NotFound => Status::not_found(e.to_string()),
+        io::ErrorKind::InvalidInput => Status::invalid_argument(e.to_string()),
+        io::ErrorKind::StorageFull => Status::resource_exhausted(e.to_string()),
+        io::ErrorKind::InvalidData => Status::data_loss(e.to_string()),
+        _ => Status::internal(e.to_string()),
+    }
+}
+
+#[tonic::async_trait]
+impl Lsm for LsmService {
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        let key = request.into_inner().key;
+        let value = self
+            .with_storage(move |storage| storage.get(&key))
+            .await?
+            .ok_or_else(|| Status::not_found("key not found"))?;
+        Ok(Response::new(GetResponse { value }))
+    }
+
+    async fn put(&self, request: Request<PutRequest>) -> Result<Response<PutResponse>, Status> {
+        let PutRequest { key, value } = request.into_inner();
+        self.with_storage(move |storage| storage.put(key, value))
+            .await?;
+        Ok(Response::new(PutResponse {}))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<DeleteRequest>,
+    ) -> Result<Response<DeleteResponse>, Status> {
+        let key = request.into_inner().key;
+        self.with_storage(move |storage| storage.delete(&key))
+            .await?;
+        Ok(Response::new(DeleteResponse {}))
+    }
+
+    async fn multi_get(
+        &self,
+        request: Request<MultiGetRequest>,
+    ) -> Result<Response<MultiGetResponse>, Status> {
+        let keys = request.into_inner().keys;
+        let values = self
+            .with_storage(move |storage| storage.multi_get(&keys))
+            .await?;
+        let values = values
+            .into_iter()
+            .map(|value| MaybeValue {
+                found: value.is_some(),
+                value: value.unwrap_or_default(),
+            })
+            .collect();
+        Ok(Response::new(MultiGetResponse { values }))
+    }
+
+    type ScanStream = ReceiverStream<Result<KeyValue, Status>>;
+
+    async fn scan(
+        &self,
+        request: Request<ScanRequest>,
+    ) -> Result<Response<Self::ScanStream>, Status> {
+        let ScanRequest { start, end } = request.into_inner();
+        // The scan holds its own references to the tables, so the lock is
+        // only needed to open it
+        let scan = self
+            .with_storage(move |storage| storage.scan(&start, &end))
+            .await?;
+
+        let (sender, receiver) = mpsc::channel(SCAN_BUFFER);
+        tokio::task::spawn_blocking(move || {
+            for pair in scan {
+                let item = pair
+                    .map(|(key, value)| KeyValue { key, value })
+                    .map_err(|e| status(e.into()));
+                let failed = item.is_err();
+                // Stop reading once the client goes away
+                if sender.blocking_send(item).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn stats(
+        &self,
+        _request: Request<StatsRequest>,
+    ) -> Result<Response<StatsResponse>, Status> {
+        let stats = self.with_storage(|storage| storage.stats()).await?;
+        let levels = stats
+            .levels
+            .iter()
+            .filter(|l| l.file_count > 0)
+            .map(|l| proto::LevelStats {
+                level: l.level as u32,
+                files: l.file_count as u64,
+                bytes: l.total_bytes as u64,
+                entries: l.properties.entry_count,
+                filter_bytes: l.filter_bytes as u64,
+            })
+            .collect();
+        Ok(Response::new(StatsResponse {
+            entries: stats.stored.entry_count,
+            tombstones: stats.stored.tombstone_count,
+            bytes: stats.levels.iter().map(|l| l.total_bytes as u64).sum(),
+            levels,
+        }))
+    }
+
+    async fn flush(
+        &self,
+        _request: Request<FlushRequest>,
+    ) -> Result<Response<FlushResponse>, Status> {
+        let flushed = self
+            .with_storage(|storage| {
+                let storage = storage.read()?;
+                let pending = storage.flush_pending();
+                storage.flush().map(|()| pending)
+            })
+            .await?;
+        Ok(Response::new(FlushResponse { flushed }))
+    }
+}
+
+/// Serve `storage` on `addr` until the process is stopped
+pub fn serve(storage: Storage, addr: SocketAddr) -> io::Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        serve_on(Arc::new(SharedStorage::new(storage)), listener).await
+    })
+}
+
+/// Serve `storage` on an already bound listener
+pub async fn serve_on(storage: Shared, listener: tokio::net::TcpListener) -> io::Result<()> {
+    Server::builder()
+        .add_service(LsmServer::new(LsmService::new(storage)))
+        .serve_with_incoming(TcpListenerStream::new(listener))
+        .await
+        .map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::proto::lsm_client::LsmClient;
+    use super::*;
+    use tempfile::TempDir;
+    use tonic::Code;
+
+    #[tokio::test]
+    async fn test_every_rpc() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Arc::new(SharedStorage::open(temp_dir.path()).unwrap());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_on(storage.clone(), listener));
+
+        let mut client = LsmClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+        for i in 0..10u8 {
+            let request = PutRequest {
+                key: vec![b'k', i],
+                value: vec![i; 3],
+            };
+            client.put(request).await.unwrap();
+        }
+        client
+            .delete(DeleteRequest { key: vec![b'k', 4] })
+            .await
+            .unwrap();
+
+        let value = client
+            .get(GetRequest { key: vec![b'k', 2] })
+            .await
+            .unwrap()
+            .into_inner()
+            .value;
+        assert_eq!(value, vec![2; 3]);
+        let missing = client.get(GetRequest { key: vec![b'k', 4] }).await;
+        assert_eq!(missing.unwrap_err().code(), Code::NotFound);
+
+        let values = client
+            .multi_get(MultiGetRequest {
+                keys: vec![vec![b'k', 9], vec![b'k', 4], b"other".to_vec()],
+            })
+            .await
+            .unwrap()
+            .into_inner()
+            .values;
+        let found: Vec<_> = values.iter().map(|v| v.found).collect();
+        assert_eq!(found, vec![true, false, false]);
+        assert_eq!(values[0].value, vec![9; 3]);
+
+        let flushed = client.flush(FlushRequest {}).await.unwrap().into_inner();
+        assert!(flushed.flushed);
+        assert!(
+            !client
+                .flush(FlushRequest {})
+                .await
+                .unwrap()
+                .into_inner()
+                .flushed
+        );
+
+        let mut stream = client
+            .scan(ScanRequest {
+                start: vec![b'k', 3],
+                end: vec![b'k', 7],
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        let mut keys = Vec::new();
+        while let Some(pair) = stream.message().await.unwrap() {
+            assert_eq!(pair.value, vec![pair.key[1]; 3]);
+            keys.push(pair.key[1]);
+        }
+        assert_eq!(keys, vec![3, 5, 6]);
+
+        let stats = client.stats(StatsRequest {}).await.unwrap().into_inner();
+        assert_eq!(stats.entries, 9);
+        assert_eq!(stats.levels.len(), 1);
+        assert_eq!(stats.levels[0].files, 1);
+
+        // Writes through the server land in the shared instance
+        let value = storage.get([b'k', 0]).unwrap();
+        assert_eq!(value, Some(vec![0; 3]));
+    }
+}
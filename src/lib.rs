@@ -0,0 +1,50 @@
+//! A Log-Structured Merge Tree with leveled SSTables, a write-ahead log and
+//! bloom filters.
+//!
+//! [`Storage`] is the entry point: it buffers writes in a [`MemTable`],
+//! logs them to the [`WAL`], flushes to [`SSTable`]s and compacts them down
+//! the levels as they fill up.
+//!
+//! ```
+//! use lsm_rust::Storage;
+//!
+//! # fn main() -> std::io::Result<()> {
+//! # let dir = tempfile::tempdir()?;
+//! let db = Storage::new(dir.path(), false)?;
+//! db.put("name", "Ada")?;
+//! assert_eq!(db.get("name")?, Some(b"Ada".to_vec()));
+//! # Ok(())
+//! # }
+//! ```
+
+mod bloom;
+pub mod cli;
+mod entry;
+pub mod error;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod memtable;
+#[cfg(feature = "object-store")]
+mod objectfs;
+mod resp;
+mod sstable;
+pub mod stats;
+pub mod storage;
+mod wal;
+
+pub use bloom::{BloomConfig, BloomFilter};
+pub use entry::{Entry, MergeOperator, Version};
+pub use error::StorageError;
+#[cfg(feature = "skiplist")]
+pub use memtable::ConcurrentMemTable;
+pub use memtable::MemTable;
+#[cfg(feature = "object-store")]
+pub use objectfs::ObjectFs;
+pub use sstable::{
+    Compression, DescribeOptions, SSTable, SSTableIterator, TableProperties, TABLE_FORMAT_VERSION,
+};
+pub use storage::{Storage, StorageOptions, WriteBatch};
+pub use wal::{Operation, SyncPolicy, WalRecord, WAL};
+
+pub type Key = Vec<u8>;
+pub type Value = Vec<u8>;
@@ -1,20 +1,28 @@
 use std::env;
 use std::fs;
 use std::io;
+use std::process;
 
-mod bloom;
-mod memtable;
-mod sstable;
-mod storage;
-mod wal;
-
-pub type Key = Vec<u8>;
-pub type Value = Vec<u8>;
-
-use storage::Storage;
+use lsm_rust::{cli, Storage};
 
 fn main() -> io::Result<()> {
-    let verbose = env::args().any(|arg| arg == "-v" || arg == "--verbose");
+    let args: Vec<String> = env::args().skip(1).collect();
+    let demo = args.first().map(String::as_str) == Some("demo");
+    let verbose = demo && args.iter().any(|arg| arg == "-v" || arg == "--verbose");
+
+    // RUST_LOG overrides these: the demo shows flushes and compactions, -v
+    // every operation, and admin commands only warnings
+    let level = match (demo, verbose) {
+        (_, true) => "debug",
+        (true, false) => "info",
+        (false, _) => "warn",
+    };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level)).init();
+
+    if !demo {
+        let code = cli::run(&args, &mut io::stdout(), &mut io::stderr());
+        process::exit(code);
+    }
 
     println!("LSM Tree Database Example");
     if verbose {
@@ -38,26 +46,26 @@ fn main() -> io::Result<()> {
 
 fn basic_operations_test(db: &mut Storage) -> io::Result<()> {
     println!("Inserting initial data...");
-    db.put(b"name".to_vec(), b"John Doe".to_vec())?;
-    db.put(b"age".to_vec(), b"30".to_vec())?;
-    db.put(b"city".to_vec(), b"New York".to_vec())?;
+    db.put("name", "John Doe")?;
+    db.put("age", "30")?;
+    db.put("city", "New York")?;
 
     println!("\nRetrieving data:");
-    if let Ok(Some(name)) = db.get(&b"name".to_vec()) {
+    if let Ok(Some(name)) = db.get("name") {
         println!("name: {}", String::from_utf8_lossy(&name));
     }
-    if let Ok(Some(age)) = db.get(&b"age".to_vec()) {
+    if let Ok(Some(age)) = db.get("age") {
         println!("age: {}", String::from_utf8_lossy(&age));
     }
-    if let Ok(Some(city)) = db.get(&b"city".to_vec()) {
+    if let Ok(Some(city)) = db.get("city") {
         println!("city: {}", String::from_utf8_lossy(&city));
     }
 
     println!("\nDeleting 'age' entry...");
-    db.delete(&b"age".to_vec())?;
+    db.delete("age")?;
 
     println!("\nTrying to retrieve deleted data:");
-    match db.get(&b"age".to_vec()) {
+    match db.get("age") {
         Ok(Some(_)) => println!("age: still exists"),
         Ok(None) => println!("age: was deleted"),
         Err(e) => println!("Error: {}", e),
@@ -89,8 +97,8 @@ fn compaction_test(db: &mut Storage) -> io::Result<()> {
     // Write enough data to trigger multiple flushes and compactions
     println!("\nWriting large dataset to trigger compaction...");
     for i in 0..5000 {
-        let key = format!("key{:05}", i).into_bytes();
-        let value = format!("value{}", i).repeat(100).into_bytes(); // Large values
+        let key = format!("key{:05}", i);
+        let value = format!("value{}", i).repeat(100); // Large values
         db.put(key, value)?;
 
         if i > 0 && i % 1000 == 0 {
@@ -109,7 +117,7 @@ fn compaction_test(db: &mut Storage) -> io::Result<()> {
     println!("\nVerifying data integrity...");
     let test_keys = [0, 1000, 2000, 3000, 4000, 4999];
     for i in test_keys {
-        let key = format!("key{:05}", i).into_bytes();
+        let key = format!("key{:05}", i);
         let expected_value = format!("value{}", i).repeat(100);
         match db.get(&key) {
             Ok(Some(value)) => {
@@ -125,5 +133,10 @@ fn compaction_test(db: &mut Storage) -> io::Result<()> {
         }
     }
 
+    println!("\nRecent flushes and compactions:");
+    for event in db.recent_events(10)? {
+        println!("  {}", event);
+    }
+
     Ok(())
 }
@@ -1,11 +1,32 @@
+use crate::entry::{Entry, Version};
 use crate::{Key, Value};
 use std::collections::BTreeMap;
-
+use std::ops::Bound;
+
+#[cfg(feature = "skiplist")]
+mod skiplist;
+#[cfg(feature = "skiplist")]
+pub use skiplist::ConcurrentMemTable;
+
+/// In-memory table holding the versions of each key, newest first.
+///
+/// A put supersedes every older version. A delete keeps the most recent
+/// value beneath its tombstone so it can be recovered while the tombstone
+/// is within its retention window; flush and compaction decide when that
+/// value is finally discarded. A merge operand keeps every version beneath
+/// it down to the first that isn't one. Either way, older versions that a
+/// live snapshot would read are kept as well.
 pub struct MemTable {
-    data: BTreeMap<Key, Value>,
+    data: BTreeMap<Key, Vec<Version>>,
     size: usize,
 }
 
+impl Default for MemTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl MemTable {
     pub fn new() -> Self {
         MemTable {
@@ -14,30 +35,82 @@ impl MemTable {
         }
     }
 
-    pub fn insert(&mut self, key: Key, value: Value) -> Option<Value> {
-        let key_len = key.len();
-        let value_len = value.len();
+    /// Write `value` at sequence number `seq`, returning the value it shadows
+    pub fn insert(&mut self, key: Key, seq: u64, value: Value, snapshots: &[u64]) -> Option<Value> {
+        let previous = self
+            .get(&key)
+            .and_then(|entry| entry.live_value(0).cloned());
+        self.push(key, Version::new(seq, Entry::Value(value)), snapshots);
+        previous
+    }
 
-        // If key exists, subtract its size before adding new one
-        if let Some(old_value) = self.data.get(&key) {
-            self.size = self.size.saturating_sub(key_len + old_value.len());
-        }
+    /// Insert a value that reads as deleted from `expires_at` on
+    pub fn insert_expiring(
+        &mut self,
+        key: Key,
+        seq: u64,
+        value: Value,
+        expires_at: u64,
+        snapshots: &[u64],
+    ) {
+        let entry = Entry::Expiring { value, expires_at };
+        self.push(key, Version::new(seq, entry), snapshots);
+    }
 
-        self.size += key_len + value_len;
-        self.data.insert(key, value)
+    /// Add a merge operand for `key`, keeping the versions beneath it that
+    /// it will be applied to
+    pub fn merge(&mut self, key: Key, seq: u64, operand: Value, snapshots: &[u64]) {
+        self.push(key, Version::new(seq, Entry::Merge(operand)), snapshots);
     }
 
-    pub fn get(&self, key: &[u8]) -> Option<&Value> {
-        self.data.get(key)
+    /// Record a tombstone for `key`, retaining the most recent live value
+    /// beneath it
+    pub fn delete(&mut self, key: Key, seq: u64, deleted_at: u64, snapshots: &[u64]) {
+        self.push(
+            key,
+            Version::new(seq, Entry::Tombstone { deleted_at }),
+            snapshots,
+        );
     }
 
-    pub fn remove(&mut self, key: &[u8]) -> Option<Value> {
-        if let Some(value) = self.data.remove(key) {
-            self.size -= key.len() + value.len();
-            Some(value)
-        } else {
-            None
+    /// Add `version` of `key`, dropping the versions beneath it that no
+    /// snapshot in `snapshots` reads
+    pub(crate) fn push(&mut self, key: Key, version: Version, snapshots: &[u64]) {
+        let key_len = key.len();
+        let versions = self.data.entry(key).or_default();
+        let old_size = Self::versions_size(key_len, versions);
+
+        // Newest first by sequence number, whatever order writes arrive in;
+        // a version already held at the same number is replaced
+        let at = versions.partition_point(|held| held.seq > version.seq);
+        match versions.get_mut(at) {
+            Some(held) if held.seq == version.seq => *held = version,
+            _ => versions.insert(at, version),
         }
+        let mut keep = retained(versions.iter(), snapshots).into_iter();
+        versions.retain(|_| keep.next().unwrap_or(false));
+
+        self.size = self.size.saturating_sub(old_size) + Self::versions_size(key_len, versions);
+    }
+
+    /// Newest version of `key`, which may be a tombstone
+    pub fn get(&self, key: &[u8]) -> Option<&Entry> {
+        self.get_at(key, u64::MAX)
+    }
+
+    /// Newest version of `key` written at or before sequence number `max_seq`
+    pub fn get_at(&self, key: &[u8], max_seq: u64) -> Option<&Entry> {
+        self.versions(key)
+            .iter()
+            .find(|version| version.seq <= max_seq)
+            .map(|version| &version.entry)
+    }
+
+    /// Every version held for `key`, newest first
+    pub fn versions(&self, key: &[u8]) -> &[Version] {
+        self.data
+            .get(key)
+            .map_or(&[], |versions| versions.as_slice())
     }
 
     pub fn size(&self) -> usize {
@@ -48,13 +121,116 @@ impl MemTable {
         self.data.is_empty()
     }
 
+    /// Number of distinct keys, including deleted ones
     pub fn len(&self) -> usize {
         self.data.len()
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = (&Key, &Value)> {
-        self.data.iter()
+    /// All versions in key order, newest first within a key
+    pub fn iter(&self) -> impl Iterator<Item = (&Key, &Version)> {
+        self.data
+            .iter()
+            .flat_map(|(key, versions)| versions.iter().map(move |version| (key, version)))
+    }
+
+    /// Keys in `[start, end)` with all of their versions, in key order; a
+    /// missing `end` runs to the last key
+    pub fn range_versions<'a>(
+        &'a self,
+        start: &[u8],
+        end: Option<&[u8]>,
+    ) -> impl Iterator<Item = (&'a Key, &'a [Version])> {
+        let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+        self.data
+            .range::<[u8], _>((Bound::Included(start), end))
+            .map(|(key, versions)| (key, versions.as_slice()))
+    }
+
+    /// Keys between `start` and `end` with the value of their newest
+    /// version, in key order. Keys whose newest version is a tombstone or a
+    /// merge operand are left out; expiring values are included whatever
+    /// the time. Bounds that leave nothing between them yield nothing.
+    pub fn range<'a>(
+        &'a self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+    ) -> impl Iterator<Item = (&'a Key, &'a Value)> {
+        self.live(start, end)
+    }
+
+    /// [`range`](MemTable::range) in descending key order
+    pub fn range_rev<'a>(
+        &'a self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+    ) -> impl Iterator<Item = (&'a Key, &'a Value)> {
+        self.live(start, end).rev()
+    }
+
+    fn live<'a>(
+        &'a self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+    ) -> impl DoubleEndedIterator<Item = (&'a Key, &'a Value)> {
+        // BTreeMap::range panics on bounds that cross
+        let empty = match (start, end) {
+            (Bound::Included(s), Bound::Included(e)) => s > e,
+            (Bound::Included(s) | Bound::Excluded(s), Bound::Included(e) | Bound::Excluded(e)) => {
+                s >= e
+            }
+            _ => false,
+        };
+        (!empty)
+            .then(|| self.data.range::<[u8], _>((start, end)))
+            .into_iter()
+            .flatten()
+            .filter_map(|(key, versions)| match &versions.first()?.entry {
+                Entry::Value(value) | Entry::Expiring { value, .. } => Some((key, value)),
+                _ => None,
+            })
     }
+
+    /// Each key with all of its versions, in key order
+    pub fn iter_versions(&self) -> impl Iterator<Item = (&Key, &[Version])> {
+        self.data
+            .iter()
+            .map(|(key, versions)| (key, versions.as_slice()))
+    }
+
+    fn versions_size(key_len: usize, versions: &[Version]) -> usize {
+        versions
+            .iter()
+            .map(|version| key_len + version.entry.value().map_or(0, |v| v.len()))
+            .sum()
+    }
+}
+
+/// Which of a key's `versions`, newest first, are kept beneath the newest:
+/// those a live snapshot reads, the value a tombstone keeps recoverable, and
+/// whatever merge operands still need
+fn retained<'a>(versions: impl IntoIterator<Item = &'a Version>, snapshots: &[u64]) -> Vec<bool> {
+    let mut versions = versions.into_iter();
+    let Some(newest) = versions.next() else {
+        return Vec::new();
+    };
+    let mut newer_seq = newest.seq;
+    let mut recoverable = newest.entry.is_tombstone();
+    // Whether every newer version is a merge operand
+    let mut merging = newest.entry.is_merge();
+    let mut keep = vec![true];
+    for version in versions {
+        // The newest version at or below a snapshot is what it reads
+        let visible = snapshots
+            .iter()
+            .any(|&snapshot| version.seq <= snapshot && snapshot < newer_seq);
+        let undeletable = recoverable && !version.entry.is_tombstone();
+        recoverable &= !undeletable;
+        newer_seq = version.seq;
+        let beneath_merge = merging;
+        merging &= version.entry.is_merge();
+        keep.push(visible || undeletable || beneath_merge);
+    }
+    keep
 }
 
 #[cfg(test)]
@@ -78,12 +254,12 @@ mod tests {
         let value_len = value.len();
 
         // Test insert
-        assert!(table.insert(key.clone(), value.clone()).is_none());
+        assert!(table.insert(key.clone(), 1, value.clone(), &[]).is_none());
         assert_eq!(table.len(), 1);
         assert_eq!(table.size(), key_len + value_len);
 
         // Test get
-        assert_eq!(table.get(&key), Some(&value));
+        assert_eq!(table.get(&key), Some(&Entry::Value(value)));
     }
 
     #[test]
@@ -93,36 +269,61 @@ mod tests {
         let value1 = b"value1".to_vec();
         let value2 = b"value2".to_vec();
 
-        table.insert(key.clone(), value1.clone());
-        let old_value = table.insert(key.clone(), value2.clone());
+        table.insert(key.clone(), 1, value1.clone(), &[]);
+        let old_value = table.insert(key.clone(), 2, value2.clone(), &[]);
 
         assert_eq!(old_value, Some(value1));
-        assert_eq!(table.get(&key), Some(&value2));
+        assert_eq!(table.get(&key), Some(&Entry::Value(value2.clone())));
         assert_eq!(table.len(), 1);
         assert_eq!(table.size(), key.len() + value2.len());
     }
 
     #[test]
-    fn test_remove() {
+    fn test_delete() {
         let mut table = MemTable::new();
         let key = b"test_key".to_vec();
         let value = b"test_value".to_vec();
         let total_size = key.len() + value.len();
 
-        table.insert(key.clone(), value.clone());
+        table.insert(key.clone(), 1, value.clone(), &[]);
         assert_eq!(table.size(), total_size);
 
-        let removed = table.remove(&key);
-        assert_eq!(removed, Some(value));
-        assert!(table.is_empty());
-        assert_eq!(table.size(), 0);
-        assert_eq!(table.get(&key), None);
+        table.delete(key.clone(), 2, 42, &[]);
+        assert_eq!(table.get(&key), Some(&Entry::Tombstone { deleted_at: 42 }));
+
+        // The shadowed value is retained beneath the tombstone
+        assert_eq!(
+            table.versions(&key),
+            &[
+                Version::new(2, Entry::Tombstone { deleted_at: 42 }),
+                Version::new(1, Entry::Value(value))
+            ]
+        );
+        assert_eq!(table.size(), total_size + key.len());
+
+        // Deleting again only refreshes the tombstone
+        table.delete(key.clone(), 3, 43, &[]);
+        assert_eq!(table.versions(&key).len(), 2);
+        assert_eq!(table.size(), total_size + key.len());
+
+        // A new put discards both the tombstone and the retained value
+        assert_eq!(table.insert(key.clone(), 4, b"v2".to_vec(), &[]), None);
+        assert_eq!(
+            table.versions(&key),
+            &[Version::new(4, Entry::Value(b"v2".to_vec()))]
+        );
+        assert_eq!(table.size(), key.len() + 2);
     }
 
     #[test]
-    fn test_remove_nonexistent() {
+    fn test_delete_nonexistent() {
         let mut table = MemTable::new();
-        assert!(table.remove(b"nonexistent").is_none());
+        table.delete(b"nonexistent".to_vec(), 1, 1, &[]);
+        assert_eq!(
+            table.versions(b"nonexistent"),
+            &[Version::new(1, Entry::Tombstone { deleted_at: 1 })]
+        );
+        assert_eq!(table.size(), b"nonexistent".len());
     }
 
     #[test]
@@ -134,11 +335,14 @@ mod tests {
             (b"key3".to_vec(), b"value3".to_vec()),
         ];
 
-        for (key, value) in entries.iter() {
-            table.insert(key.clone(), value.clone());
+        for (seq, (key, value)) in entries.iter().enumerate() {
+            table.insert(key.clone(), seq as u64, value.clone(), &[]);
         }
 
-        let mut iter_entries: Vec<_> = table.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        let mut iter_entries: Vec<_> = table
+            .iter()
+            .map(|(k, v)| (k.clone(), v.entry.value().unwrap().clone()))
+            .collect();
         iter_entries.sort();
 
         let mut expected = entries.clone();
@@ -157,16 +361,122 @@ mod tests {
             let key = format!("key{}", i).into_bytes();
             let value = format!("value{}", i).into_bytes();
             expected_size += key.len() + value.len();
-            table.insert(key, value);
+            table.insert(key, i, value, &[]);
         }
 
         assert_eq!(table.size(), expected_size);
 
-        // Remove some entries
+        // Deleting adds a tombstone on top of the retained value
         let key = b"key0".to_vec();
-        let removed_value = table.remove(&key).unwrap();
-        expected_size -= key.len() + removed_value.len();
+        table.delete(key.clone(), 5, 0, &[]);
+        expected_size += key.len();
+
+        assert_eq!(table.size(), expected_size);
+
+        // Overwriting drops both
+        table.insert(key.clone(), 6, b"v".to_vec(), &[]);
+        expected_size -= key.len() + b"value0".len();
+        expected_size += 1;
 
         assert_eq!(table.size(), expected_size);
     }
+
+    #[test]
+    fn test_snapshot_versions_retained() {
+        let mut table = MemTable::new();
+        let key = b"key".to_vec();
+
+        table.insert(key.clone(), 1, b"v1".to_vec(), &[]);
+        table.insert(key.clone(), 2, b"v2".to_vec(), &[]);
+        // A snapshot at seq 2 keeps v2, but nothing can still see v1
+        table.insert(key.clone(), 3, b"v3".to_vec(), &[2]);
+        table.delete(key.clone(), 4, 0, &[2]);
+        assert_eq!(
+            table
+                .versions(&key)
+                .iter()
+                .map(|v| v.seq)
+                .collect::<Vec<_>>(),
+            vec![4, 3, 2]
+        );
+
+        assert_eq!(table.get(&key), Some(&Entry::Tombstone { deleted_at: 0 }));
+        assert_eq!(table.get_at(&key, 3), Some(&Entry::Value(b"v3".to_vec())));
+        assert_eq!(table.get_at(&key, 2), Some(&Entry::Value(b"v2".to_vec())));
+        assert_eq!(table.get_at(&key, 0), None);
+
+        // Once the snapshot is gone the next write drops what it pinned
+        table.insert(key.clone(), 5, b"v5".to_vec(), &[]);
+        assert_eq!(table.versions(&key).len(), 1);
+        assert_eq!(table.size(), key.len() + 2);
+    }
+
+    #[test]
+    fn test_newest_by_seq() {
+        let mut table = MemTable::new();
+        let key = b"key".to_vec();
+
+        // An older write arriving late stays beneath the newer one
+        table.insert(key.clone(), 5, b"v5".to_vec(), &[]);
+        table.insert(key.clone(), 3, b"v3".to_vec(), &[4]);
+        assert_eq!(table.get(&key), Some(&Entry::Value(b"v5".to_vec())));
+        assert_eq!(table.get_at(&key, 4), Some(&Entry::Value(b"v3".to_vec())));
+
+        // The same write again changes nothing
+        let size = table.size();
+        table.insert(key.clone(), 5, b"v5".to_vec(), &[4]);
+        assert_eq!(
+            table
+                .versions(&key)
+                .iter()
+                .map(|v| v.seq)
+                .collect::<Vec<_>>(),
+            vec![5, 3]
+        );
+        assert_eq!(table.size(), size);
+    }
+
+    #[test]
+    fn test_range() {
+        let mut table = MemTable::new();
+        for (seq, key) in [b"b", b"d", b"f", b"h"].into_iter().enumerate() {
+            table.insert(key.to_vec(), seq as u64, key.to_vec(), &[]);
+        }
+        table.delete(b"f".to_vec(), 4, 0, &[]);
+        table.merge(b"h".to_vec(), 5, b"+".to_vec(), &[]);
+        table.insert_expiring(b"j".to_vec(), 6, b"j".to_vec(), 1, &[]);
+
+        let keys = |start: Bound<&[u8]>, end: Bound<&[u8]>| {
+            table
+                .range(start, end)
+                .map(|(key, value)| {
+                    assert_eq!(key, value);
+                    key.clone()
+                })
+                .collect::<Vec<_>>()
+        };
+        use Bound::{Excluded, Included, Unbounded};
+        let all = vec![b"b".to_vec(), b"d".to_vec(), b"j".to_vec()];
+        assert_eq!(keys(Unbounded, Unbounded), all);
+
+        // Inclusive and exclusive ends on keys that exist
+        assert_eq!(keys(Included(b"b"), Excluded(b"d")), vec![b"b".to_vec()]);
+        assert_eq!(keys(Excluded(b"b"), Included(b"d")), vec![b"d".to_vec()]);
+        assert_eq!(keys(Included(b"d"), Included(b"d")), vec![b"d".to_vec()]);
+        assert_eq!(keys(Included(b"c"), Unbounded), all[1..].to_vec());
+
+        // Empty, crossed, and entirely before or after the data
+        assert!(keys(Included(b"d"), Excluded(b"d")).is_empty());
+        assert!(keys(Excluded(b"d"), Excluded(b"d")).is_empty());
+        assert!(keys(Included(b"e"), Included(b"c")).is_empty());
+        assert!(keys(Unbounded, Excluded(b"a")).is_empty());
+        assert!(keys(Excluded(b"j"), Unbounded).is_empty());
+        assert!(MemTable::new().range(Unbounded, Unbounded).next().is_none());
+
+        let reversed: Vec<_> = table
+            .range_rev(Unbounded, Included(b"d"))
+            .map(|(key, _)| key.clone())
+            .collect();
+        assert_eq!(reversed, vec![b"d".to_vec(), b"b".to_vec()]);
+    }
 }
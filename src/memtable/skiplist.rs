@@ -0,0 +1,346 @@
+use super::{retained, MemTable};
+use crate::entry::{Entry, Version};
+use crate::{Key, Value};
+use crossbeam_skiplist::SkipMap;
+use std::cmp::Reverse;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A [`MemTable`] that many threads write and read at once through `&self`.
+///
+/// Keys sit in a lock-free skip list, each with a skip list of its versions
+/// newest first, so reads never wait and writers only share the size
+/// counter. Every version written is kept while the table takes writes, as
+/// a reader may be walking them; [`freeze`](ConcurrentMemTable::freeze)
+/// then drops the ones `MemTable` would have and hands the entries over as
+/// a `MemTable`, which is what flushes read.
+pub struct ConcurrentMemTable {
+    data: SkipMap<Key, SkipMap<Reverse<u64>, Version>>,
+    size: AtomicUsize,
+}
+
+impl Default for ConcurrentMemTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<MemTable> for ConcurrentMemTable {
+    fn from(memtable: MemTable) -> Self {
+        let table = ConcurrentMemTable::new();
+        for (key, versions) in memtable.data {
+            for version in versions {
+                table.push(key.clone(), version);
+            }
+        }
+        table
+    }
+}
+
+impl ConcurrentMemTable {
+    pub fn new() -> Self {
+        ConcurrentMemTable {
+            data: SkipMap::new(),
+            size: AtomicUsize::new(0),
+        }
+    }
+
+    /// Write `value` at sequence number `seq`, returning the value it shadows
+    pub fn insert(&self, key: Key, seq: u64, value: Value) -> Option<Value> {
+        let previous = self
+            .get(&key)
+            .and_then(|entry| entry.live_value(0).cloned());
+        self.push(key, Version::new(seq, Entry::Value(value)));
+        previous
+    }
+
+    /// Insert a value that reads as deleted from `expires_at` on
+    pub fn insert_expiring(&self, key: Key, seq: u64, value: Value, expires_at: u64) {
+        let entry = Entry::Expiring { value, expires_at };
+        self.push(key, Version::new(seq, entry));
+    }
+
+    /// Add a merge operand for `key`
+    pub fn merge(&self, key: Key, seq: u64, operand: Value) {
+        self.push(key, Version::new(seq, Entry::Merge(operand)));
+    }
+
+    /// Record a tombstone for `key`
+    pub fn delete(&self, key: Key, seq: u64, deleted_at: u64) {
+        let entry = Entry::Tombstone { deleted_at };
+        self.push(key, Version::new(seq, entry));
+    }
+
+    /// Add `version` of `key`; a version already held at the same
+    /// sequence number is replaced
+    pub(crate) fn push(&self, key: Key, version: Version) {
+        let size =
+            |version: &Version| MemTable::versions_size(key.len(), std::slice::from_ref(version));
+        let versions = self.data.get_or_insert_with(key.clone(), SkipMap::new);
+        let versions = versions.value();
+        if let Some(held) = versions.get(&Reverse(version.seq)) {
+            self.size.fetch_sub(size(held.value()), Ordering::Relaxed);
+        }
+        self.size.fetch_add(size(&version), Ordering::Relaxed);
+        versions.insert(Reverse(version.seq), version);
+    }
+
+    /// Newest version of `key`, which may be a tombstone
+    pub fn get(&self, key: &[u8]) -> Option<Entry> {
+        self.get_at(key, u64::MAX)
+    }
+
+    /// Newest version of `key` written at or before sequence number `max_seq`
+    pub fn get_at(&self, key: &[u8], max_seq: u64) -> Option<Entry> {
+        let versions = self.data.get(key)?;
+        let newest = versions.value().range(Reverse(max_seq)..).next()?;
+        Some(newest.value().entry.clone())
+    }
+
+    /// Every version held for `key`, newest first
+    pub fn versions(&self, key: &[u8]) -> Vec<Version> {
+        self.data.get(key).map_or_else(Vec::new, |versions| {
+            versions
+                .value()
+                .iter()
+                .map(|version| version.value().clone())
+                .collect()
+        })
+    }
+
+    /// Keys in `[start, end)` with all of their versions, in key order; a
+    /// missing `end` runs to the last key
+    pub fn range_versions<'a>(
+        &'a self,
+        start: &'a [u8],
+        end: Option<&'a [u8]>,
+    ) -> impl Iterator<Item = (Key, Vec<Version>)> + 'a {
+        let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+        self.data
+            .range::<[u8], _>((Bound::Included(start), end))
+            .map(|versions| {
+                let held = versions.value().iter();
+                let held = held.map(|version| version.value().clone()).collect();
+                (versions.key().clone(), held)
+            })
+    }
+
+    /// Keys between `start` and `end` with the value of their newest
+    /// version, as [`MemTable::range`] yields them
+    pub fn range<'a>(
+        &'a self,
+        start: Bound<&'a [u8]>,
+        end: Bound<&'a [u8]>,
+    ) -> impl Iterator<Item = (Key, Value)> + 'a {
+        self.data
+            .range::<[u8], _>((start, end))
+            .filter_map(|versions| match &versions.value().front()?.value().entry {
+                Entry::Value(value) | Entry::Expiring { value, .. } => {
+                    Some((versions.key().clone(), value.clone()))
+                }
+                _ => None,
+            })
+    }
+
+    pub fn size(&self) -> usize {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Number of distinct keys, including deleted ones
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// The entries as a `MemTable`, keeping only the versions it would
+    /// have kept with `snapshots` live. Taking the table by value means no
+    /// writer or reader is left, so a flush reading the result sees one
+    /// consistent set of versions.
+    pub fn freeze(self, snapshots: &[u64]) -> MemTable {
+        let mut memtable = MemTable::new();
+        for (key, versions) in self.data {
+            let versions: Vec<Version> = versions.into_iter().map(|(_, v)| v).collect();
+            let mut keep = retained(&versions, snapshots).into_iter();
+            let versions: Vec<Version> = versions
+                .into_iter()
+                .filter(|_| keep.next().unwrap_or(false))
+                .collect();
+            memtable.size += MemTable::versions_size(key.len(), &versions);
+            memtable.data.insert(key, versions);
+        }
+        memtable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SSTable;
+    use std::fs;
+    use std::sync::atomic::AtomicU64;
+    use std::sync::Arc;
+    use std::thread;
+    use tempfile::TempDir;
+
+    fn key(i: usize) -> Key {
+        format!("key{:05}", i).into_bytes()
+    }
+
+    #[test]
+    fn test_single_threaded_matches_memtable() {
+        let concurrent = ConcurrentMemTable::new();
+        let mut memtable = MemTable::new();
+        let mut snapshots = Vec::new();
+        for seq in 1..=3000u64 {
+            let i = (seq * 7919 % 300) as usize;
+            let value = seq.to_le_bytes().to_vec();
+            if seq % 1000 == 0 {
+                snapshots.push(seq);
+            }
+            match seq % 5 {
+                0 => {
+                    concurrent.delete(key(i), seq, seq);
+                    memtable.delete(key(i), seq, seq, &snapshots);
+                }
+                1 => {
+                    concurrent.merge(key(i), seq, value.clone());
+                    memtable.merge(key(i), seq, value, &snapshots);
+                }
+                2 => {
+                    concurrent.insert_expiring(key(i), seq, value.clone(), seq);
+                    memtable.insert_expiring(key(i), seq, value, seq, &snapshots);
+                }
+                _ => {
+                    let shadowed = concurrent.insert(key(i), seq, value.clone());
+                    assert_eq!(shadowed, memtable.insert(key(i), seq, value, &snapshots));
+                }
+            }
+        }
+
+        // Unpruned, each snapshot still reads what it would have
+        assert_eq!(concurrent.len(), memtable.len());
+        for i in 0..300 {
+            for &snapshot in &snapshots {
+                assert_eq!(
+                    concurrent.get_at(&key(i), snapshot),
+                    memtable.get_at(&key(i), snapshot).cloned()
+                );
+            }
+        }
+        let bounds = (
+            Bound::Included(&b"key00100"[..]),
+            Bound::Excluded(&b"key00200"[..]),
+        );
+        let ranged: Vec<_> = memtable
+            .range(bounds.0, bounds.1)
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        assert_eq!(
+            concurrent.range(bounds.0, bounds.1).collect::<Vec<_>>(),
+            ranged
+        );
+
+        // Flushed, the two write the same table byte for byte
+        let temp_dir = TempDir::new().unwrap();
+        let flush = |memtable: &MemTable, name: &str| {
+            let path = temp_dir.path().join(name);
+            let entries: Vec<_> = memtable
+                .iter()
+                .map(|(key, version)| (key.clone(), version.clone()))
+                .collect();
+            SSTable::new(path.clone())
+                .unwrap()
+                .write_entries(&entries)
+                .unwrap();
+            fs::read(path).unwrap()
+        };
+        let held = concurrent.size();
+        let frozen = concurrent.freeze(&snapshots);
+        assert!(frozen.size() < held);
+        assert_eq!(frozen.size(), memtable.size());
+        for i in 0..300 {
+            assert_eq!(frozen.versions(&key(i)), memtable.versions(&key(i)));
+        }
+        assert_eq!(
+            flush(&frozen, "frozen.sst"),
+            flush(&memtable, "memtable.sst")
+        );
+    }
+
+    #[test]
+    fn test_concurrent_writers_and_readers() {
+        const WRITERS: usize = 4;
+        const KEYS: usize = 2000;
+        let table = Arc::new(ConcurrentMemTable::new());
+        let seq = Arc::new(AtomicU64::new(0));
+        // Keys below each writer's mark have been written
+        let written: Arc<Vec<AtomicUsize>> =
+            Arc::new((0..WRITERS).map(|_| AtomicUsize::new(0)).collect());
+
+        let writers: Vec<_> = (0..WRITERS)
+            .map(|w| {
+                let (table, seq, written) = (table.clone(), seq.clone(), written.clone());
+                thread::spawn(move || {
+                    for i in 0..KEYS {
+                        let next = seq.fetch_add(1, Ordering::Relaxed) + 1;
+                        table.insert(key(w * KEYS + i), next, vec![w as u8; 32]);
+                        // Every writer also overwrites one shared key
+                        let next = seq.fetch_add(1, Ordering::Relaxed) + 1;
+                        table.insert(b"shared".to_vec(), next, next.to_le_bytes().to_vec());
+                        written[w].store(i + 1, Ordering::Release);
+                    }
+                })
+            })
+            .collect();
+        let readers: Vec<_> = (0..4)
+            .map(|r| {
+                let (table, written) = (table.clone(), written.clone());
+                thread::spawn(move || {
+                    let mut reads = 0;
+                    while written
+                        .iter()
+                        .any(|mark| mark.load(Ordering::Acquire) < KEYS)
+                    {
+                        let w = (reads + r) % WRITERS;
+                        let mark = written[w].load(Ordering::Acquire);
+                        if mark > 0 {
+                            let i = w * KEYS + (reads * 7919) % mark;
+                            assert_eq!(table.get(&key(i)), Some(Entry::Value(vec![w as u8; 32])));
+                        }
+                        let start = key(w * KEYS);
+                        let seen = table
+                            .range(Bound::Included(&start), Bound::Unbounded)
+                            .count();
+                        assert!(seen >= mark);
+                        reads += 1;
+                    }
+                    reads
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        for reader in readers {
+            assert!(reader.join().unwrap() > 0);
+        }
+
+        // No write was lost, and freezing leaves only the newest shared value
+        let last = seq.load(Ordering::Relaxed);
+        let table = Arc::into_inner(table).unwrap();
+        assert_eq!(table.len(), WRITERS * KEYS + 1);
+        assert_eq!(table.versions(b"shared").len(), WRITERS * KEYS);
+        assert_eq!(
+            table.get(b"shared"),
+            Some(Entry::Value(last.to_le_bytes().to_vec()))
+        );
+        let frozen = table.freeze(&[]);
+        let expected = WRITERS * KEYS * (key(0).len() + 32) + b"shared".len() + 8;
+        assert_eq!(frozen.size(), expected);
+        assert_eq!(frozen.versions(b"shared").len(), 1);
+        assert_eq!(frozen.iter().count(), WRITERS * KEYS + 1);
+    }
+}
@@ -0,0 +1,131 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+// Extension of the files chunks are kept in; nothing else in the cache
+// directory is touched
+const CHUNK_EXTENSION: &str = "chunk";
+
+/// Chunks of objects kept as files in a local directory, so only the first
+/// read of a chunk goes to the store. The least recently read chunks are
+/// removed once the total passes the capacity.
+pub(super) struct ChunkCache {
+    dir: PathBuf,
+    capacity: u64,
+    state: Mutex<State>,
+    // Tells apart the scratch files of chunks written at the same time
+    writes: AtomicU64,
+}
+
+#[derive(Default)]
+struct State {
+    // (object, chunk index) to its size and when it was last read
+    chunks: HashMap<(String, u64), (u64, u64)>,
+    // When each chunk was last read, oldest first
+    by_use: BTreeMap<u64, (String, u64)>,
+    tick: u64,
+    size: u64,
+}
+
+impl State {
+    /// Mark a chunk as just read; false if it isn't cached
+    fn touch(&mut self, key: &(String, u64)) -> bool {
+        self.tick += 1;
+        let Some((_, used)) = self.chunks.get_mut(key) else {
+            return false;
+        };
+        let old = std::mem::replace(used, self.tick);
+        self.by_use.remove(&old);
+        self.by_use.insert(self.tick, key.clone());
+        true
+    }
+
+    fn remove(&mut self, key: &(String, u64)) {
+        if let Some((size, used)) = self.chunks.remove(key) {
+            self.by_use.remove(&used);
+            self.size -= size;
+        }
+    }
+}
+
+impl ChunkCache {
+    /// A cache in `dir`, dropping any chunks an earlier run left there
+    pub(super) fn open(dir: &Path, capacity: u64) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let ours = path.extension().and_then(|s| s.to_str()) == Some(CHUNK_EXTENSION)
+                || path.to_string_lossy().contains(".chunk.tmp");
+            if ours && path.is_file() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(ChunkCache {
+            dir: dir.to_path_buf(),
+            capacity,
+            state: Mutex::new(State::default()),
+            writes: AtomicU64::new(0),
+        })
+    }
+
+    fn path(&self, name: &str, index: u64) -> PathBuf {
+        self.dir
+            .join(format!("{}.{}.{}", name, index, CHUNK_EXTENSION))
+    }
+
+    /// Chunk `index` of object `name`, if cached
+    pub(super) fn get(&self, name: &str, index: u64) -> Option<Vec<u8>> {
+        let key = (name.to_string(), index);
+        if !self.state.lock().unwrap().touch(&key) {
+            return None;
+        }
+        // Evicted since, or lost; either way the store still has it
+        fs::read(self.path(name, index)).ok()
+    }
+
+    /// Keep `bytes` as chunk `index` of object `name`, evicting the least
+    /// recently read chunks to make room
+    pub(super) fn insert(&self, name: &str, index: u64, bytes: &[u8]) -> io::Result<()> {
+        // Written aside and renamed, so readers never see a partial chunk
+        let path = self.path(name, index);
+        let write = self.writes.fetch_add(1, Ordering::Relaxed);
+        let tmp_path = path.with_extension(format!("{}.tmp{}", CHUNK_EXTENSION, write));
+        fs::write(&tmp_path, bytes)?;
+        fs::rename(&tmp_path, &path)?;
+
+        let mut state = self.state.lock().unwrap();
+        let key = (name.to_string(), index);
+        state.remove(&key);
+        state.tick += 1;
+        let tick = state.tick;
+        state.chunks.insert(key.clone(), (bytes.len() as u64, tick));
+        state.by_use.insert(tick, key);
+        state.size += bytes.len() as u64;
+        while state.size > self.capacity {
+            let Some(oldest) = state.by_use.values().next().cloned() else {
+                break;
+            };
+            state.remove(&oldest);
+            let _ = fs::remove_file(self.path(&oldest.0, oldest.1));
+        }
+        Ok(())
+    }
+
+    /// Drop every cached chunk of object `name`
+    pub(super) fn remove(&self, name: &str) {
+        let mut state = self.state.lock().unwrap();
+        let keys: Vec<_> = state
+            .chunks
+            .keys()
+            .filter(|(object, _)| object == name)
+            .cloned()
+            .collect();
+        for key in keys {
+            state.remove(&key);
+            let _ = fs::remove_file(self.path(&key.0, key.1));
+        }
+    }
+}
@@ -0,0 +1,334 @@
+//! SSTables kept in an object store instead of the table directories.
+//!
+//! Tables never change once written, so a flush or compaction uploads the
+//! finished file and deletes the local copy, reads fetch byte ranges of the
+//! object, and dropping a replaced table deletes its object. The WAL and
+//! memtable stay on local disk. Every read goes through a cache of object
+//! chunks on local disk, as an uncached read is a network round trip.
+
+mod cache;
+
+use std::fs::{self, File};
+use std::future::Future;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use object_store::memory::InMemory;
+use object_store::path::Path as ObjectPath;
+use object_store::{ObjectStore, PutPayload, WriteMultipart};
+use tokio::runtime::Runtime;
+
+use cache::ChunkCache;
+
+/// Bytes fetched from the store, and cached, at a time
+pub(crate) const CHUNK_SIZE: u64 = 256 * 1024;
+// Chunk cache capacity of `ObjectFs::in_memory`
+const DEFAULT_CACHE_SIZE: u64 = 64 * 1024 * 1024;
+// Parts of one upload in flight at once
+const MAX_UPLOAD_PARTS: usize = 4;
+
+/// An object store holding a database's tables, with the local chunk cache
+/// its reads go through. Hand it to
+/// [`StorageOptions::object_fs`](crate::StorageOptions::object_fs).
+///
+/// Objects are named after their tables and sit at the root of `store`;
+/// wrap it in [`object_store::prefix::PrefixStore`] to share a bucket
+/// between databases. Calls block on a runtime of their own, so the
+/// database must not be used from inside another async runtime's tasks.
+pub struct ObjectFs {
+    store: Arc<dyn ObjectStore>,
+    runtime: Runtime,
+    cache: ChunkCache,
+    range_reads: AtomicU64,
+}
+
+impl ObjectFs {
+    /// Keep tables in `store`, caching up to `cache_size` bytes of them in
+    /// `cache_dir`. The cache is emptied on creation, and must hold at
+    /// least one 256KB chunk.
+    pub fn new(
+        store: Arc<dyn ObjectStore>,
+        cache_dir: impl AsRef<Path>,
+        cache_size: u64,
+    ) -> io::Result<Self> {
+        if cache_size < CHUNK_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "object cache size {} must be at least the {} byte chunk size",
+                    cache_size, CHUNK_SIZE
+                ),
+            ));
+        }
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()?;
+        Ok(ObjectFs {
+            store,
+            runtime,
+            cache: ChunkCache::open(cache_dir.as_ref(), cache_size)?,
+            range_reads: AtomicU64::new(0),
+        })
+    }
+
+    /// A store held in memory, standing in for a real one in tests, with a
+    /// 64MB cache in `cache_dir`
+    pub fn in_memory(cache_dir: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(Arc::new(InMemory::new()), cache_dir, DEFAULT_CACHE_SIZE)
+    }
+
+    /// Ranged reads sent to the store so far; reads the cache served don't
+    /// count
+    pub fn range_reads(&self) -> u64 {
+        self.range_reads.load(Ordering::Relaxed)
+    }
+
+    fn block_on<T>(&self, future: impl Future<Output = object_store::Result<T>>) -> io::Result<T> {
+        self.runtime.block_on(future).map_err(io::Error::from)
+    }
+
+    /// Upload the finished table at `path` as the object of the same name,
+    /// then remove the local file. Its chunks are cached on the way, so the
+    /// new table reads warm.
+    pub(crate) fn upload(&self, path: &Path) -> io::Result<()> {
+        let name = object_name(path)?;
+        // A name can come back after a clear; its old chunks are stale
+        self.cache.remove(name);
+        let mut file = File::open(path)?;
+        let location = ObjectPath::from(name);
+        let mut upload = WriteMultipart::new(self.block_on(self.store.put_multipart(&location))?);
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE as usize);
+        let mut send = || -> io::Result<()> {
+            for index in 0.. {
+                chunk.clear();
+                Read::by_ref(&mut file)
+                    .take(CHUNK_SIZE)
+                    .read_to_end(&mut chunk)?;
+                if chunk.is_empty() {
+                    break;
+                }
+                upload.write(&chunk);
+                self.cache.insert(name, index, &chunk)?;
+                self.block_on(upload.wait_for_capacity(MAX_UPLOAD_PARTS))?;
+            }
+            Ok(())
+        };
+        if let Err(e) = send() {
+            let _ = self.block_on(upload.abort());
+            self.cache.remove(name);
+            return Err(e);
+        }
+        self.block_on(upload.finish())?;
+        fs::remove_file(path)
+    }
+
+    /// Open object `name` for reading through the cache
+    pub(crate) fn open(self: &Arc<Self>, name: &str) -> io::Result<RemoteFile> {
+        let len = self
+            .block_on(self.store.head(&ObjectPath::from(name)))?
+            .size;
+        Ok(RemoteFile {
+            fs: self.clone(),
+            name: name.to_string(),
+            len,
+            pos: 0,
+            chunk: None,
+        })
+    }
+
+    /// Chunk `index` of object `name`, `len` bytes long, from the cache or
+    /// else from the store
+    fn chunk(&self, name: &str, index: u64, len: u64) -> io::Result<Vec<u8>> {
+        if let Some(chunk) = self.cache.get(name, index) {
+            return Ok(chunk);
+        }
+        let start = index * CHUNK_SIZE;
+        let end = (start + CHUNK_SIZE).min(len);
+        self.range_reads.fetch_add(1, Ordering::Relaxed);
+        let bytes = self.block_on(self.store.get_range(&ObjectPath::from(name), start..end))?;
+        self.cache.insert(name, index, &bytes)?;
+        Ok(bytes.to_vec())
+    }
+
+    /// Delete object `name` and its cached chunks; a missing object is
+    /// already deleted
+    pub(crate) fn delete(&self, name: &str) -> io::Result<()> {
+        self.cache.remove(name);
+        match self.block_on(self.store.delete(&ObjectPath::from(name))) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Names of every object in the store
+    pub(crate) fn names(&self) -> io::Result<Vec<String>> {
+        let listing = self.block_on(self.store.list_with_delimiter(None))?;
+        Ok(listing
+            .objects
+            .iter()
+            .filter_map(|object| object.location.filename().map(str::to_string))
+            .collect())
+    }
+
+    /// Replace object `name` with `bytes`, bypassing the cache, for small
+    /// objects that change such as the manifest
+    pub(crate) fn put(&self, name: &str, bytes: Vec<u8>) -> io::Result<()> {
+        self.block_on(
+            self.store
+                .put(&ObjectPath::from(name), PutPayload::from(bytes)),
+        )?;
+        Ok(())
+    }
+
+    /// The whole of object `name`, bypassing the cache, or `None` if there
+    /// is no such object
+    pub(crate) fn fetch(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        let location = ObjectPath::from(name);
+        match self.block_on(async { self.store.get(&location).await?.bytes().await }) {
+            Ok(bytes) => Ok(Some(bytes.to_vec())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// The object name of the table at `path`: its file name
+pub(crate) fn object_name(path: &Path) -> io::Result<&str> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Table path {:?} has no usable file name", path),
+            )
+        })
+}
+
+/// An object read like a file, a chunk at a time through the cache
+pub(crate) struct RemoteFile {
+    fs: Arc<ObjectFs>,
+    name: String,
+    len: u64,
+    pos: u64,
+    // The chunk reads are currently served from, by index
+    chunk: Option<(u64, Vec<u8>)>,
+}
+
+impl Read for RemoteFile {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || out.is_empty() {
+            return Ok(0);
+        }
+        let index = self.pos / CHUNK_SIZE;
+        if !matches!(&self.chunk, Some((cached, _)) if *cached == index) {
+            self.chunk = Some((index, self.fs.chunk(&self.name, index, self.len)?));
+        }
+        let Some((_, chunk)) = &self.chunk else {
+            unreachable!("the chunk was just loaded");
+        };
+        let offset = (self.pos - index * CHUNK_SIZE) as usize;
+        let available = chunk.get(offset..).unwrap_or_default();
+        let n = available.len().min(out.len());
+        out[..n].copy_from_slice(&available[..n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for RemoteFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        self.pos = target.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the object",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn upload(fs: &ObjectFs, dir: &Path, name: &str, bytes: &[u8]) {
+        let path = dir.join(name);
+        fs::write(&path, bytes).unwrap();
+        fs.upload(&path).unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_reads_span_chunks() {
+        let temp_dir = TempDir::new().unwrap();
+        let fs = Arc::new(ObjectFs::in_memory(temp_dir.path().join("cache")).unwrap());
+        let bytes: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| i as u8).collect();
+        upload(&fs, temp_dir.path(), "L0_1.sst", &bytes);
+
+        let mut file = fs.open("L0_1.sst").unwrap();
+        let mut read = Vec::new();
+        file.read_to_end(&mut read).unwrap();
+        assert_eq!(read, bytes);
+
+        let mut tail = [0u8; 200];
+        file.seek(SeekFrom::End(-200)).unwrap();
+        file.read_exact(&mut tail).unwrap();
+        assert_eq!(tail[..], bytes[bytes.len() - 200..]);
+        assert!(file
+            .seek(SeekFrom::Current(-(bytes.len() as i64) - 1))
+            .is_err());
+        // The upload left every chunk cached
+        assert_eq!(fs.range_reads(), 0);
+    }
+
+    #[test]
+    fn test_evicted_chunks_are_fetched_again() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = Arc::new(InMemory::new());
+        let fs = ObjectFs::new(store.clone(), temp_dir.path().join("cache"), CHUNK_SIZE).unwrap();
+        let fs = Arc::new(fs);
+        upload(&fs, temp_dir.path(), "a.sst", &[1; CHUNK_SIZE as usize]);
+        upload(&fs, temp_dir.path(), "b.sst", &[2; 10]);
+
+        // Only the newest chunk fits, so reading `a` goes to the store
+        let mut read = Vec::new();
+        fs.open("a.sst").unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, vec![1; CHUNK_SIZE as usize]);
+        assert_eq!(fs.range_reads(), 1);
+        fs.open("a.sst").unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(fs.range_reads(), 1);
+
+        // A fresh cache over the same store starts empty
+        let fs = ObjectFs::new(store, temp_dir.path().join("cache"), CHUNK_SIZE).unwrap();
+        let mut names = fs.names().unwrap();
+        names.sort();
+        assert_eq!(names, ["a.sst", "b.sst"]);
+        fs.delete("a.sst").unwrap();
+        fs.delete("a.sst").unwrap();
+        assert_eq!(fs.names().unwrap(), ["b.sst"]);
+        assert!(ObjectFs::new(Arc::new(InMemory::new()), temp_dir.path(), 1).is_err());
+    }
+
+    #[test]
+    fn test_put_and_fetch() {
+        let temp_dir = TempDir::new().unwrap();
+        let fs = Arc::new(ObjectFs::in_memory(temp_dir.path()).unwrap());
+        assert_eq!(fs.fetch("MANIFEST").unwrap(), None);
+        fs.put("MANIFEST", b"one".to_vec()).unwrap();
+        fs.put("MANIFEST", b"two".to_vec()).unwrap();
+        assert_eq!(fs.fetch("MANIFEST").unwrap(), Some(b"two".to_vec()));
+        assert_eq!(
+            fs.open("missing").err().map(|e| e.kind()),
+            Some(io::ErrorKind::NotFound)
+        );
+    }
+}
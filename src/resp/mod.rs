@@ -0,0 +1,629 @@
+//! A minimal Redis (RESP2) front end, so `redis-cli` and Redis client
+//! libraries can talk to the store: `GET`, `SET`, `DEL`, `EXISTS`, `SCAN`,
+//! `FLUSHALL` and `PING`, plus `QUIT` and `SHUTDOWN`. Each connection gets
+//! its own thread.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use crate::error::StorageError;
+use crate::storage::{SharedStorage, Storage, WriteBatch};
+use crate::Key;
+
+// Largest bulk string accepted, as in Redis
+const MAX_BULK: usize = 512 * 1024 * 1024;
+// Most arguments one command may carry
+const MAX_ARGS: usize = 1024 * 1024;
+// Longest inline command, which has no length up front
+const MAX_INLINE: usize = 64 * 1024;
+// Keys a SCAN returns when no COUNT is given
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// A reply as it goes on the wire
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Simple(s) => out.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+            Reply::Error(e) => {
+                // Line breaks would end the frame early
+                let e = e.replace(['\r', '\n'], " ");
+                out.extend_from_slice(format!("-{}\r\n", e).as_bytes());
+            }
+            Reply::Integer(n) => out.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+            Reply::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Bulk(Some(bytes)) => {
+                out.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+                out.extend_from_slice(bytes);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(out);
+                }
+            }
+        }
+    }
+}
+
+fn protocol_error(detail: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, detail.into())
+}
+
+/// The line starting at `from` in `buf`, without its line ending, and the
+/// offset just past it, or `None` if it hasn't all arrived
+fn line(buf: &[u8], from: usize) -> Option<(&[u8], usize)> {
+    let end = from + buf.get(from..)?.iter().position(|&b| b == b'\n')?;
+    let line = &buf[from..end];
+    Some((line.strip_suffix(b"\r").unwrap_or(line), end + 1))
+}
+
+fn length(line: &[u8], max: usize) -> io::Result<usize> {
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .filter(|&n| n <= max)
+        .ok_or_else(|| protocol_error("invalid length"))
+}
+
+/// Parse the command at the front of `buf`: its arguments and the bytes
+/// it took, or `None` if it hasn't all arrived. Clients send arrays of
+/// bulk strings; a line of plain words, as typed into telnet, also works.
+fn parse_command(buf: &[u8]) -> io::Result<Option<(Vec<Vec<u8>>, usize)>> {
+    if buf.first() != Some(&b'*') {
+        let Some((line, used)) = line(buf, 0) else {
+            if buf.len() > MAX_INLINE {
+                return Err(protocol_error("too big inline request"));
+            }
+            return Ok(None);
+        };
+        let args = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|word| !word.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some((args, used)));
+    }
+
+    let Some((header, mut pos)) = line(buf, 1) else {
+        return Ok(None);
+    };
+    let count = length(header, MAX_ARGS)?;
+    let mut args = Vec::with_capacity(count.min(64));
+    for _ in 0..count {
+        let Some((header, start)) = line(buf, pos) else {
+            return Ok(None);
+        };
+        let Some(header) = header.strip_prefix(b"$") else {
+            return Err(protocol_error("expected '$'"));
+        };
+        let len = length(header, MAX_BULK)?;
+        let end = start + len;
+        if buf.len() < end + 2 {
+            return Ok(None);
+        }
+        if &buf[end..end + 2] != b"\r\n" {
+            return Err(protocol_error("bulk string not terminated"));
+        }
+        args.push(buf[start..end].to_vec());
+        pos = end + 2;
+    }
+    Ok(Some((args, pos)))
+}
+
+/// Whether `key` matches a Redis glob: `*` for any run, `?` for any byte,
+/// and `\` to take the next byte literally
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => key.is_empty(),
+        Some((b'*', rest)) => (0..=key.len()).any(|skip| glob_match(rest, &key[skip..])),
+        Some((b'?', rest)) => !key.is_empty() && glob_match(rest, &key[1..]),
+        Some((b'\\', rest)) if !rest.is_empty() => {
+            key.first() == Some(&rest[0]) && glob_match(&rest[1..], &key[1..])
+        }
+        Some((&b, rest)) => key.first() == Some(&b) && glob_match(rest, &key[1..]),
+    }
+}
+
+fn wrong_arity(command: &str) -> Reply {
+    Reply::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        command
+    ))
+}
+
+fn syntax_error() -> Reply {
+    Reply::Error("ERR syntax error".to_string())
+}
+
+/// What the connection does once a command's reply is sent
+#[derive(Debug, PartialEq)]
+enum Next {
+    Continue,
+    Close,
+    Shutdown,
+}
+
+/// One client's session. SCAN cursors are numbers, as clients expect, each
+/// standing for the last key a page returned on this connection.
+struct Session {
+    storage: Arc<SharedStorage>,
+    cursors: HashMap<u64, Key>,
+    next_cursor: u64,
+}
+
+impl Session {
+    fn new(storage: Arc<SharedStorage>) -> Self {
+        Session {
+            storage,
+            cursors: HashMap::new(),
+            next_cursor: 1,
+        }
+    }
+
+    fn execute(&mut self, args: &[Vec<u8>]) -> (Reply, Next) {
+        let Some((name, args)) = args.split_first() else {
+            return (
+                Reply::Error("ERR empty command".to_string()),
+                Next::Continue,
+            );
+        };
+        let name = String::from_utf8_lossy(name).to_lowercase();
+        let result = match name.as_str() {
+            "ping" => match args {
+                [] => Ok(Reply::Simple("PONG")),
+                [message] => Ok(Reply::Bulk(Some(message.clone()))),
+                _ => Ok(wrong_arity("ping")),
+            },
+            "get" => match args {
+                [key] => self.storage.get(key).map(Reply::Bulk),
+                _ => Ok(wrong_arity("get")),
+            },
+            "set" => match args {
+                [key, value] => self
+                    .storage
+                    .put(key.clone(), value.clone())
+                    .map(|()| Reply::Simple("OK")),
+                [_, _, ..] => Ok(syntax_error()),
+                _ => Ok(wrong_arity("set")),
+            },
+            "del" if !args.is_empty() => self.del(args),
+            "exists" if !args.is_empty() => self.storage.multi_get(args).map(|values| {
+                Reply::Integer(values.iter().filter(|value| value.is_some()).count() as i64)
+            }),
+            "scan" if !args.is_empty() => self.scan(args),
+            "flushall" => match args {
+                [] => self.flushall(),
+                [mode]
+                    if mode.eq_ignore_ascii_case(b"sync")
+                        || mode.eq_ignore_ascii_case(b"async") =>
+                {
+                    self.flushall()
+                }
+                _ => return (syntax_error(), Next::Continue),
+            }
+            .map(|()| Reply::Simple("OK")),
+            "quit" => return (Reply::Simple("OK"), Next::Close),
+            "shutdown" => {
+                // Like Redis saving first: leave nothing to replay on restart
+                return match self.storage.flush() {
+                    Ok(_) => (Reply::Simple("OK"), Next::Shutdown),
+                    Err(e) => (Reply::Error(format!("ERR {}", e)), Next::Continue),
+                };
+            }
+            "del" | "exists" | "scan" => Ok(wrong_arity(&name)),
+            _ => Ok(Reply::Error(format!("ERR unknown command '{}'", name))),
+        };
+        let reply = result.unwrap_or_else(|e| Reply::Error(format!("ERR {}", e)));
+        (reply, Next::Continue)
+    }
+
+    /// Clear the database, holding off every other session meanwhile
+    fn flushall(&self) -> Result<(), StorageError> {
+        self.storage.lock()?.clear()
+    }
+
+    /// Delete the keys that exist, all in one batch, and count them
+    fn del(&self, keys: &[Vec<u8>]) -> Result<Reply, StorageError> {
+        let values = self.storage.multi_get(keys)?;
+        let mut batch = WriteBatch::new();
+        let mut deleted: Vec<&Key> = Vec::new();
+        for (key, value) in keys.iter().zip(values) {
+            if value.is_some() && !deleted.contains(&key) {
+                batch.delete(key.clone());
+                deleted.push(key);
+            }
+        }
+        if !batch.is_empty() {
+            self.storage.write(batch)?;
+        }
+        Ok(Reply::Integer(deleted.len() as i64))
+    }
+
+    /// `SCAN cursor [MATCH pattern] [COUNT count]`. Like Redis, COUNT
+    /// bounds the keys looked at, so a page with a pattern may come back
+    /// short or empty before the cursor reaches 0.
+    fn scan(&mut self, args: &[Vec<u8>]) -> Result<Reply, StorageError> {
+        let Some(cursor) = std::str::from_utf8(&args[0])
+            .ok()
+            .and_then(|cursor| cursor.parse::<u64>().ok())
+        else {
+            return Ok(Reply::Error("ERR invalid cursor".to_string()));
+        };
+        let mut pattern = None;
+        let mut count = DEFAULT_SCAN_COUNT;
+        let mut options = args[1..].chunks(2);
+        for option in &mut options {
+            match option {
+                [name, value] if name.eq_ignore_ascii_case(b"match") => {
+                    pattern = Some(value.as_slice())
+                }
+                [name, value] if name.eq_ignore_ascii_case(b"count") => {
+                    match std::str::from_utf8(value).ok().and_then(|n| n.parse().ok()) {
+                        Some(n) if n > 0 => count = n,
+                        _ => return Ok(syntax_error()),
+                    }
+                }
+                _ => return Ok(syntax_error()),
+            }
+        }
+
+        let start_after = match cursor {
+            0 => None,
+            cursor => match self.cursors.remove(&cursor) {
+                Some(key) => Some(key),
+                None => return Ok(Reply::Error("ERR invalid cursor".to_string())),
+            },
+        };
+        let (page, token) = self
+            .storage
+            .read()?
+            .scan_page(b"", b"", count, start_after)?;
+        let next = match token {
+            Some(key) => {
+                let cursor = self.next_cursor;
+                self.next_cursor += 1;
+                self.cursors.insert(cursor, key);
+                cursor
+            }
+            None => 0,
+        };
+        let keys = page
+            .into_iter()
+            .map(|(key, _)| key)
+            .filter(|key| pattern.is_none_or(|pattern| glob_match(pattern, key)))
+            .map(|key| Reply::Bulk(Some(key)))
+            .collect();
+        Ok(Reply::Array(vec![
+            Reply::Bulk(Some(next.to_string().into_bytes())),
+            Reply::Array(keys),
+        ]))
+    }
+}
+
+/// Serve one client until it disconnects, quits or shuts the server down.
+/// Every command that has fully arrived is answered, in order, before
+/// reading more, so pipelined commands go back in a single write.
+fn handle(stream: &mut TcpStream, storage: Arc<SharedStorage>) -> io::Result<Next> {
+    let mut session = Session::new(storage);
+    let mut buf = Vec::new();
+    let mut chunk = [0; 16 * 1024];
+    loop {
+        let read = stream.read(&mut chunk)?;
+        if read == 0 {
+            return Ok(Next::Close);
+        }
+        buf.extend_from_slice(&chunk[..read]);
+
+        let mut out = Vec::new();
+        let mut used = 0;
+        let mut next = Next::Continue;
+        while next == Next::Continue {
+            match parse_command(&buf[used..]) {
+                Ok(Some((args, len))) => {
+                    used += len;
+                    // Blank inline lines are ignored, as Redis does
+                    if !args.is_empty() {
+                        let (reply, after) = session.execute(&args);
+                        reply.encode(&mut out);
+                        next = after;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    Reply::Error(format!("ERR Protocol error: {}", e)).encode(&mut out);
+                    next = Next::Close;
+                }
+            }
+        }
+        buf.drain(..used);
+        stream.write_all(&out)?;
+        if next != Next::Continue {
+            return Ok(next);
+        }
+    }
+}
+
+/// Serve `storage` on `addr` until a client sends `SHUTDOWN`, then close it
+pub fn serve(storage: Storage, addr: SocketAddr) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    let storage = Arc::new(SharedStorage::new(storage));
+    serve_on(storage.clone(), listener)?;
+    match Arc::into_inner(storage) {
+        Some(storage) => Ok(storage.into_inner()?.close()?),
+        None => Ok(()),
+    }
+}
+
+/// Serve `storage` on an already bound listener until a client sends
+/// `SHUTDOWN`. Returns once every connection has been closed and its
+/// thread has finished.
+pub fn serve_on(storage: Arc<SharedStorage>, listener: TcpListener) -> io::Result<()> {
+    let addr = listener.local_addr()?;
+    let stopping = Arc::new(AtomicBool::new(false));
+    let mut connections: Vec<(JoinHandle<()>, TcpStream)> = Vec::new();
+    for stream in listener.incoming() {
+        if stopping.load(Ordering::Acquire) {
+            break;
+        }
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            // The client may have gone before it was accepted
+            Err(_) => continue,
+        };
+        connections.retain(|(handle, _)| !handle.is_finished());
+        let peer = stream.try_clone()?;
+        let (storage, stopping) = (storage.clone(), stopping.clone());
+        let handle = thread::spawn(move || {
+            let next = handle(&mut stream, storage);
+            // The accept loop's clone would otherwise keep it open
+            let _ = stream.shutdown(Shutdown::Both);
+            if let Ok(Next::Shutdown) = next {
+                stopping.store(true, Ordering::Release);
+                // Wake the accept loop so it sees the flag
+                let _ = TcpStream::connect(addr);
+            }
+        });
+        connections.push((handle, peer));
+    }
+
+    for (handle, stream) in connections {
+        let _ = stream.shutdown(Shutdown::Both);
+        let _ = handle.join();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    /// A command as a client sends it: an array of bulk strings
+    fn frame(args: &[&str]) -> Vec<u8> {
+        let mut out = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            out.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+        }
+        out
+    }
+
+    /// Send `request` and read until `expected` bytes of replies arrive
+    fn exchange(stream: &mut TcpStream, request: &[u8], expected: &str) {
+        stream.write_all(request).unwrap();
+        let mut got = vec![0; expected.len()];
+        stream.read_exact(&mut got).unwrap();
+        assert_eq!(String::from_utf8_lossy(&got), expected);
+    }
+
+    fn start(dir: &TempDir) -> (SocketAddr, JoinHandle<Storage>) {
+        let storage = Arc::new(SharedStorage::open(dir.path()).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            serve_on(storage.clone(), listener).unwrap();
+            Arc::into_inner(storage).unwrap().into_inner().unwrap()
+        });
+        (addr, server)
+    }
+
+    fn connect(addr: SocketAddr) -> TcpStream {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        stream
+    }
+
+    #[test]
+    fn test_parse_partial_pipelined_and_inline() {
+        let set = frame(&["SET", "key", "a value"]);
+        for cut in 0..set.len() {
+            assert_eq!(parse_command(&set[..cut]).unwrap(), None, "cut at {}", cut);
+        }
+        let mut pipelined = set.clone();
+        pipelined.extend_from_slice(b"GET key\r\n");
+        let (args, used) = parse_command(&pipelined).unwrap().unwrap();
+        assert_eq!(
+            args,
+            vec![b"SET".to_vec(), b"key".to_vec(), b"a value".to_vec()]
+        );
+        assert_eq!(used, set.len());
+        let (args, used) = parse_command(&pipelined[used..]).unwrap().unwrap();
+        assert_eq!(args, vec![b"GET".to_vec(), b"key".to_vec()]);
+        assert_eq!(used, 9);
+
+        // Bulk strings carry any bytes, line breaks included
+        let (args, _) = parse_command(b"*1\r\n$4\r\na\r\nb\r\n").unwrap().unwrap();
+        assert_eq!(args, vec![b"a\r\nb".to_vec()]);
+
+        for bad in [&b"*x\r\n"[..], b"*1\r\n:1\r\n", b"*1\r\n$1\r\nab\r\n"] {
+            assert!(parse_command(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"user:*", b"user:42"));
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"a?c", b"abc"));
+        assert!(!glob_match(b"a?c", b"ac"));
+        assert!(glob_match(b"a\\*", b"a*"));
+        assert!(!glob_match(b"a\\*", b"ab"));
+        assert!(glob_match(b"*:*:end", b"x:y:z:end"));
+    }
+
+    #[test]
+    fn test_commands_over_tcp_and_restart() {
+        let temp_dir = TempDir::new().unwrap();
+        let (addr, server) = start(&temp_dir);
+        let mut client = connect(addr);
+
+        exchange(&mut client, &frame(&["PING"]), "+PONG\r\n");
+        exchange(&mut client, &frame(&["ping", "hi"]), "$2\r\nhi\r\n");
+        exchange(&mut client, &frame(&["SET", "a", "1"]), "+OK\r\n");
+        exchange(&mut client, &frame(&["GET", "a"]), "$1\r\n1\r\n");
+        exchange(&mut client, &frame(&["GET", "missing"]), "$-1\r\n");
+        exchange(&mut client, b"EXISTS a missing a\r\n", ":2\r\n");
+        exchange(
+            &mut client,
+            &frame(&["BOGUS", "x"]),
+            "-ERR unknown command 'bogus'\r\n",
+        );
+        exchange(
+            &mut client,
+            &frame(&["GET"]),
+            "-ERR wrong number of arguments for 'get' command\r\n",
+        );
+        exchange(
+            &mut client,
+            &frame(&["SET", "a", "1", "NX"]),
+            "-ERR syntax error\r\n",
+        );
+
+        // Pipelined and split across writes at awkward places
+        let mut pipeline = Vec::new();
+        for i in 0..25 {
+            pipeline.extend(frame(&["SET", &format!("key{:02}", i), &format!("v{}", i)]));
+        }
+        pipeline.extend(frame(&["DEL", "key03", "key03", "key04", "nope"]));
+        pipeline.extend(frame(&["GET", "key24"]));
+        let (first, rest) = pipeline.split_at(7);
+        client.write_all(first).unwrap();
+        client.flush().unwrap();
+        thread::sleep(Duration::from_millis(20));
+        exchange(
+            &mut client,
+            rest,
+            &format!("{}:2\r\n$3\r\nv24\r\n", "+OK\r\n".repeat(25)),
+        );
+
+        // A full pass of SCAN sees every key once
+        let mut seen = Vec::new();
+        let mut cursor = "0".to_string();
+        loop {
+            client
+                .write_all(&frame(&["SCAN", &cursor, "MATCH", "key*", "COUNT", "4"]))
+                .unwrap();
+            let reply = read_reply(&mut client);
+            let Reply::Array(mut parts) = reply else {
+                panic!("{:?}", reply)
+            };
+            let Reply::Array(keys) = parts.pop().unwrap() else {
+                panic!()
+            };
+            let Reply::Bulk(Some(next)) = parts.pop().unwrap() else {
+                panic!()
+            };
+            for key in keys {
+                let Reply::Bulk(Some(key)) = key else {
+                    panic!()
+                };
+                seen.push(String::from_utf8(key).unwrap());
+            }
+            cursor = String::from_utf8(next).unwrap();
+            if cursor == "0" {
+                break;
+            }
+        }
+        let expected: Vec<String> = (0..25)
+            .filter(|i| ![3, 4].contains(i))
+            .map(|i| format!("key{:02}", i))
+            .collect();
+        assert_eq!(seen, expected);
+        exchange(
+            &mut client,
+            &frame(&["SCAN", "99"]),
+            "-ERR invalid cursor\r\n",
+        );
+
+        // A second client, left connected, is closed by SHUTDOWN
+        let mut other = connect(addr);
+        exchange(&mut other, &frame(&["SET", "b", "2"]), "+OK\r\n");
+        exchange(&mut client, &frame(&["SHUTDOWN"]), "+OK\r\n");
+        let storage = server.join().unwrap();
+        assert_eq!(other.read(&mut [0; 1]).unwrap_or(0), 0);
+        storage.close().unwrap();
+
+        // Everything written survives the restart
+        let (addr, server) = start(&temp_dir);
+        let mut client = connect(addr);
+        exchange(&mut client, &frame(&["GET", "key10"]), "$3\r\nv10\r\n");
+        exchange(
+            &mut client,
+            &frame(&["EXISTS", "a", "b", "key03"]),
+            ":2\r\n",
+        );
+        exchange(&mut client, &frame(&["FLUSHALL"]), "+OK\r\n");
+        exchange(
+            &mut client,
+            &frame(&["SCAN", "0"]),
+            "*2\r\n$1\r\n0\r\n*0\r\n",
+        );
+        exchange(&mut client, &frame(&["QUIT"]), "+OK\r\n");
+        assert_eq!(client.read(&mut [0; 1]).unwrap(), 0);
+        let mut client = connect(addr);
+        exchange(&mut client, b"SHUTDOWN\r\n", "+OK\r\n");
+        let storage = server.join().unwrap();
+        assert_eq!(storage.scan(b"", b"").unwrap().count(), 0);
+    }
+
+    /// Read one reply, enough of RESP to check SCAN pages
+    fn read_reply(stream: &mut TcpStream) -> Reply {
+        let mut header = Vec::new();
+        let mut byte = [0];
+        while !header.ends_with(b"\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            header.push(byte[0]);
+        }
+        let body = std::str::from_utf8(&header[1..header.len() - 2]).unwrap();
+        match header[0] {
+            b'*' => Reply::Array(
+                (0..body.parse().unwrap())
+                    .map(|_| read_reply(stream))
+                    .collect(),
+            ),
+            b'$' => {
+                let mut value = vec![0; body.parse::<usize>().unwrap() + 2];
+                stream.read_exact(&mut value).unwrap();
+                value.truncate(value.len() - 2);
+                Reply::Bulk(Some(value))
+            }
+            other => panic!("unexpected reply type {}", other as char),
+        }
+    }
+}
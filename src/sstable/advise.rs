@@ -0,0 +1,49 @@
+use std::fs::File;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Hints issued by this process, for tests and stats
+static ADVISE_CALLS: AtomicU64 = AtomicU64::new(0);
+
+/// Access pattern hints passed to `posix_fadvise`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Advice {
+    /// The range will be read once, front to back
+    Sequential,
+    /// The range won't be needed again soon; its cached pages can go
+    DontNeed,
+    /// The range will be read soon; start reading it in
+    WillNeed,
+}
+
+/// Tell the kernel how `len` bytes at `offset` will be used (zero `len`
+/// means to the end of the file). Hints are best effort, so failures are
+/// ignored, and this does nothing on platforms without `posix_fadvise`.
+pub fn advise(file: &File, offset: u64, len: u64, advice: Advice) {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        let advice = match advice {
+            Advice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+            Advice::DontNeed => libc::POSIX_FADV_DONTNEED,
+            Advice::WillNeed => libc::POSIX_FADV_WILLNEED,
+        };
+        // SAFETY: the descriptor is owned by `file`, which outlives the call
+        unsafe {
+            libc::posix_fadvise(
+                file.as_raw_fd(),
+                offset as libc::off_t,
+                len as libc::off_t,
+                advice,
+            );
+        }
+        ADVISE_CALLS.fetch_add(1, Ordering::Relaxed);
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (file, offset, len, advice);
+}
+
+/// Number of hints issued so far by this process
+pub fn advise_calls() -> u64 {
+    ADVISE_CALLS.load(Ordering::Relaxed)
+}
@@ -0,0 +1,215 @@
+use super::compression::{Compression, FRAME_HEADER_SIZE};
+use super::index::IndexBuilder;
+use super::write_record;
+use crate::entry::EntryRef;
+use std::io::{self, BufRead, Read, Write};
+
+/// Writes a table's data section and collects its index. Without
+/// compression entries go straight to the output; with it they gather into
+/// blocks that end where the next index point begins, each written as one
+/// compressed frame the index points at.
+pub struct BlockWriter<W: Write> {
+    out: W,
+    compression: Compression,
+    index: IndexBuilder,
+    block: Vec<u8>,
+}
+
+impl<W: Write> BlockWriter<W> {
+    pub fn new(out: W, compression: Compression) -> Self {
+        BlockWriter {
+            out,
+            compression,
+            index: IndexBuilder::new(),
+            block: Vec::new(),
+        }
+    }
+
+    pub fn write(&mut self, key: &[u8], seq: u64, entry: EntryRef) -> io::Result<()> {
+        if self.compression == Compression::None {
+            let size = write_record(&mut self.out, key, seq, entry)?;
+            self.index.record(key, size);
+        } else {
+            if self.index.starts_point(key) {
+                self.flush_block()?;
+            }
+            self.index.record(key, 0);
+            write_record(&mut self.block, key, seq, entry)?;
+        }
+        Ok(())
+    }
+
+    fn flush_block(&mut self) -> io::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let frame = self.compression.compress_block(&self.block)?;
+        self.out.write_all(&frame)?;
+        self.index.advance(frame.len());
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Write out the last block, returning the output and the index of
+    /// what was written
+    pub fn finish(mut self) -> io::Result<(W, IndexBuilder)> {
+        self.flush_block()?;
+        Ok((self.out, self.index))
+    }
+}
+
+/// Reads a compressed data section frame by frame, handing out the
+/// restored entries
+pub struct BlockDecoder<R: Read> {
+    inner: R,
+    compression: Compression,
+    // Stored bytes left before the end of the data section
+    remaining: u64,
+    // Largest block that can be genuine: the whole data section
+    max_raw: u64,
+    block: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> BlockDecoder<R> {
+    /// Decode the `stored` bytes of frames that `inner` is positioned at
+    pub fn new(inner: R, compression: Compression, stored: u64, max_raw: u64) -> Self {
+        BlockDecoder {
+            inner,
+            compression,
+            remaining: stored,
+            max_raw,
+            block: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Skip `n` restored bytes
+    pub fn skip(&mut self, mut n: usize) -> io::Result<()> {
+        while n > 0 {
+            let available = self.fill_buf()?.len();
+            if available == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let step = available.min(n);
+            self.consume(step);
+            n -= step;
+        }
+        Ok(())
+    }
+
+    fn next_block(&mut self) -> io::Result<()> {
+        let damaged = |e: io::Error| match e.kind() {
+            io::ErrorKind::UnexpectedEof => {
+                io::Error::new(io::ErrorKind::InvalidData, "Compressed block cut short")
+            }
+            _ => e,
+        };
+        let mut header = [0u8; FRAME_HEADER_SIZE];
+        self.inner.read_exact(&mut header).map_err(damaged)?;
+        let (stored, raw) = Compression::frame_sizes(&header, self.max_raw)?;
+        let frame_size = (FRAME_HEADER_SIZE + stored) as u64;
+        if frame_size > self.remaining {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Compressed block of {} bytes exceeds data section", stored),
+            ));
+        }
+        let mut bytes = vec![0u8; stored];
+        self.inner.read_exact(&mut bytes).map_err(damaged)?;
+        self.remaining -= frame_size;
+        self.block = self.compression.decompress(&bytes, raw)?;
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for BlockDecoder<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(out.len());
+        out[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: Read> BufRead for BlockDecoder<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.pos == self.block.len() && self.remaining > 0 {
+            self.next_block()?;
+        }
+        Ok(&self.block[self.pos..])
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.pos = (self.pos + n).min(self.block.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_entries(compression: Compression, count: usize) -> (Vec<u8>, IndexBuilder) {
+        let mut writer = BlockWriter::new(Vec::new(), compression);
+        for i in 0..count {
+            let key = format!("key{:04}", i).into_bytes();
+            writer
+                .write(&key, i as u64, EntryRef::Value(&[b'v'; 100]))
+                .unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn test_uncompressed_blocks_are_plain_entries() {
+        let (bytes, index) = write_entries(Compression::None, 40);
+        // key, seq, kind, value and the two length prefixes
+        assert_eq!(bytes.len(), 40 * (7 + 8 + 1 + 100 + 8));
+        assert_eq!(index.finish(0).data_end(), bytes.len() as u64);
+    }
+
+    #[test]
+    fn test_decoder_reads_across_frames() {
+        let frames: Vec<u8> = [&b"first "[..], b"second ", b"third"]
+            .iter()
+            .flat_map(|block| Compression::None.compress_block(block).unwrap())
+            .collect();
+        let mut decoder =
+            BlockDecoder::new(&frames[..], Compression::None, frames.len() as u64, 100);
+        decoder.skip(3).unwrap();
+        let mut restored = String::new();
+        decoder.read_to_string(&mut restored).unwrap();
+        assert_eq!(restored, "st second third");
+
+        // A frame claiming more than is left is damage, not a short read
+        let mut decoder = BlockDecoder::new(&frames[..], Compression::None, 10, 100);
+        let err = decoder.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_compressed_blocks_decode_back_to_entries() {
+        let (plain, _) = write_entries(Compression::None, 40);
+        let raw_size = plain.len() as u64;
+        let codecs = [Compression::Snappy, Compression::Lz4];
+        for codec in codecs.into_iter().filter(|codec| codec.is_supported()) {
+            let (stored, index) = write_entries(codec, 40);
+            assert!((stored.len() as u64) < raw_size / 4);
+            let index = index.finish(0);
+            assert_eq!(index.data_end(), stored.len() as u64);
+
+            let mut decoder = BlockDecoder::new(&stored[..], codec, stored.len() as u64, raw_size);
+            let mut restored = Vec::new();
+            decoder.read_to_end(&mut restored).unwrap();
+            assert_eq!(restored, plain);
+
+            // Every index point starts a frame
+            let (start, end) = index.seek_block(b"key0020").unwrap();
+            let frame = &stored[start as usize..end as usize];
+            let block = codec.decompress_frame(frame, raw_size).unwrap();
+            assert!(block.windows(7).any(|window| window == b"key0020"));
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::stats::CacheStats;
+
+/// Recently read data blocks, keyed by table path and block offset and
+/// shared by every table of a `Storage`. Once the blocks held exceed the
+/// capacity in bytes, the least recently used are dropped.
+pub struct BlockCache {
+    capacity: usize,
+    blocks: Mutex<Blocks>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+// Block offset -> block and when it was last used
+type TableBlocks = HashMap<u64, (Arc<[u8]>, u64)>;
+
+#[derive(Default)]
+struct Blocks {
+    tables: HashMap<PathBuf, TableBlocks>,
+    // Use tick -> block, least recently used first
+    recency: BTreeMap<u64, (PathBuf, u64)>,
+    tick: u64,
+    bytes: usize,
+}
+
+impl Blocks {
+    fn touch(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> Self {
+        BlockCache {
+            capacity,
+            blocks: Mutex::new(Blocks::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// The block of `path` starting at `offset`, counting a hit or a miss
+    pub fn get(&self, path: &Path, offset: u64) -> Option<Arc<[u8]>> {
+        let mut blocks = self.blocks.lock().unwrap();
+        let tick = blocks.touch();
+        let Some((block, used)) = blocks
+            .tables
+            .get_mut(path)
+            .and_then(|table| table.get_mut(&offset))
+        else {
+            drop(blocks);
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        let (block, last) = (block.clone(), std::mem::replace(used, tick));
+        let key = blocks
+            .recency
+            .remove(&last)
+            .expect("cached block is in recency");
+        blocks.recency.insert(tick, key);
+        drop(blocks);
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(block)
+    }
+
+    /// Keep `block` as the one of `path` at `offset`, evicting the least
+    /// recently used blocks to make room. Blocks larger than the whole
+    /// cache aren't kept.
+    pub fn insert(&self, path: &Path, offset: u64, block: Arc<[u8]>) {
+        if block.len() > self.capacity {
+            return;
+        }
+        let mut blocks = self.blocks.lock().unwrap();
+        let tick = blocks.touch();
+        blocks.bytes += block.len();
+        let table = blocks.tables.entry(path.to_path_buf()).or_default();
+        if let Some((old, last)) = table.insert(offset, (block, tick)) {
+            blocks.bytes -= old.len();
+            blocks.recency.remove(&last);
+        }
+        blocks.recency.insert(tick, (path.to_path_buf(), offset));
+
+        while blocks.bytes > self.capacity {
+            let (_, (path, offset)) = blocks.recency.pop_first().expect("cache is over capacity");
+            let table = blocks.tables.get_mut(&path).unwrap();
+            let (evicted, _) = table.remove(&offset).unwrap();
+            if table.is_empty() {
+                blocks.tables.remove(&path);
+            }
+            blocks.bytes -= evicted.len();
+        }
+    }
+
+    /// Drop every block of `path`, whose file was rewritten or removed
+    pub fn invalidate(&self, path: &Path) {
+        let mut blocks = self.blocks.lock().unwrap();
+        if let Some(table) = blocks.tables.remove(path) {
+            for (block, used) in table.into_values() {
+                blocks.bytes -= block.len();
+                blocks.recency.remove(&used);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            bytes: self.blocks.lock().unwrap().bytes as u64,
+            capacity: self.capacity as u64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(len: usize) -> Arc<[u8]> {
+        vec![0u8; len].into()
+    }
+
+    #[test]
+    fn test_least_recently_used_evicted_first() {
+        let cache = BlockCache::new(300);
+        let (a, b) = (Path::new("a.sst"), Path::new("b.sst"));
+        cache.insert(a, 0, block(100));
+        cache.insert(a, 100, block(100));
+        cache.insert(b, 0, block(100));
+
+        // Using the first block makes the second the oldest
+        assert!(cache.get(a, 0).is_some());
+        cache.insert(b, 100, block(100));
+        assert!(cache.get(a, 100).is_none());
+        assert!(cache.get(a, 0).is_some());
+        assert!(cache.get(b, 0).is_some());
+        assert!(cache.get(b, 100).is_some());
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (4, 1));
+        assert_eq!(stats.bytes, 300);
+
+        // Too big to keep at all
+        cache.insert(b, 200, block(301));
+        assert!(cache.get(b, 200).is_none());
+        assert_eq!(cache.stats().bytes, 300);
+    }
+
+    #[test]
+    fn test_invalidate_drops_one_table() {
+        let cache = BlockCache::new(1000);
+        let (a, b) = (Path::new("a.sst"), Path::new("b.sst"));
+        cache.insert(a, 0, block(100));
+        cache.insert(a, 100, block(100));
+        cache.insert(b, 0, block(100));
+
+        cache.invalidate(a);
+        assert!(cache.get(a, 0).is_none());
+        assert!(cache.get(a, 100).is_none());
+        assert!(cache.get(b, 0).is_some());
+        assert_eq!(cache.stats().bytes, 100);
+
+        // Replacing a block accounts for the old one
+        cache.insert(b, 0, block(50));
+        assert_eq!(cache.stats().bytes, 50);
+    }
+}
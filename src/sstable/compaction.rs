@@ -1,10 +1,146 @@
-use super::SSTable;
-use std::collections::BTreeMap;
+use super::{EntryReader, IoMode, SSTable};
+use crate::entry::{Entry, MergeOperator, Version};
+use crate::Key;
+use log::debug;
+use std::cmp::{self, Reverse};
+use std::collections::BinaryHeap;
 use std::io;
+use std::sync::Arc;
 
-pub struct CompactionManager {
+/// Rules for discarding versions when rewriting a key
+#[derive(Clone)]
+pub(crate) struct GcPolicy {
+    /// Current time in milliseconds since the epoch
+    pub now: u64,
+    /// How long the value beneath a tombstone stays recoverable, in milliseconds
+    pub tombstone_retention: u64,
+    /// Whether the output holds the oldest data for its key range, so that
+    /// nothing beneath it could be resurrected by dropping a tombstone
+    pub bottommost: bool,
+    /// Sequence numbers of live snapshots, each of which must keep reading
+    /// the version it saw when it was taken
+    pub snapshots: Vec<u64>,
+    /// Applies merge operands to the versions beneath them
+    pub merge: Option<MergeOperator>,
+}
+
+impl GcPolicy {
+    /// Given every version of a key, newest first, return those that must survive.
+    ///
+    /// The newest version always wins, along with the newest version at or
+    /// below each live snapshot. A tombstone still inside the retention window
+    /// keeps the most recent value beneath it for `undelete`; an expired
+    /// tombstone is kept until it reaches the bottommost level with nothing
+    /// left beneath it. A value past its expiry is treated as a tombstone.
+    /// Merge operands become values where they can be applied, and are
+    /// otherwise kept along with everything beneath them.
+    pub fn retain(&self, key: &[u8], versions: Vec<Version>) -> Vec<Version> {
+        let versions = versions.into_iter().map(|v| self.expire(v)).collect();
+        let versions = self.apply_merges(key, versions);
+        let mut kept: Vec<Version> = Vec::new();
+        let mut newer_seq = None;
+        let mut recoverable = false;
+        // Whether every newer version is a merge operand
+        let mut merging = true;
+
+        for version in versions {
+            let keep = match newer_seq {
+                None => {
+                    if let Entry::Tombstone { deleted_at } = version.entry {
+                        recoverable = !self.expired(deleted_at);
+                    }
+                    true
+                }
+                Some(newer_seq) => {
+                    let undeletable = recoverable && !version.entry.is_tombstone();
+                    recoverable &= !undeletable;
+                    undeletable || merging || self.visible_to_snapshot(version.seq, newer_seq)
+                }
+            };
+            newer_seq = Some(version.seq);
+            merging &= version.entry.is_merge();
+            if keep {
+                kept.push(version);
+            }
+        }
+
+        // With nothing beneath, dropping an expired tombstone changes no read
+        if self.bottommost {
+            while kept.last().is_some_and(|version| match version.entry {
+                Entry::Tombstone { deleted_at } => self.expired(deleted_at),
+                Entry::Value(_) | Entry::Expiring { .. } | Entry::Merge(_) => false,
+            }) {
+                kept.pop();
+            }
+        }
+        kept
+    }
+
+    /// A value past its expiry reads as deleted, so it's kept as a
+    /// tombstone from when it expired, dropped like any other
+    fn expire(&self, version: Version) -> Version {
+        match version.entry {
+            Entry::Expiring { expires_at, .. } if expires_at <= self.now => Version::new(
+                version.seq,
+                Entry::Tombstone {
+                    deleted_at: expires_at,
+                },
+            ),
+            _ => version,
+        }
+    }
+
+    /// Turn merge operands with a version beneath them, or with nothing
+    /// beneath at the bottommost level, into the values they read as. Each
+    /// becomes the value as of its own sequence number, so snapshots keep
+    /// reading what they did.
+    fn apply_merges(&self, key: &[u8], mut versions: Vec<Version>) -> Vec<Version> {
+        let Some(merge) = &self.merge else {
+            return versions;
+        };
+        let base = versions
+            .iter()
+            .position(|version| !version.entry.is_merge())
+            .or(self.bottommost.then_some(versions.len()));
+        let Some(base) = base else {
+            return versions;
+        };
+        let mut value = versions
+            .get(base)
+            .and_then(|version| version.entry.live_value(self.now))
+            .cloned();
+        for version in versions[..base].iter_mut().rev() {
+            if let Entry::Merge(operand) = &version.entry {
+                let merged = merge(key, value.as_deref(), &[operand.as_slice()]);
+                value = Some(merged.clone());
+                version.entry = Entry::Value(merged);
+            }
+        }
+        versions
+    }
+
+    fn expired(&self, deleted_at: u64) -> bool {
+        self.now.saturating_sub(deleted_at) >= self.tombstone_retention
+    }
+
+    /// Whether a version is the one some snapshot reads, given the sequence
+    /// number of the version just above it
+    fn visible_to_snapshot(&self, seq: u64, newer_seq: u64) -> bool {
+        self.snapshots
+            .iter()
+            .any(|&snapshot| seq <= snapshot && snapshot < newer_seq)
+    }
+}
+
+/// Level 0 compacts once it holds this many files, unless configured otherwise
+pub const L0_COMPACTION_FILES: usize = 4;
+
+#[derive(Clone)]
+pub(crate) struct CompactionManager {
     level_multiplier: u32,
     size_threshold: usize,
+    l0_files: usize,
+    fadvise: bool,
 }
 
 impl CompactionManager {
@@ -12,58 +148,479 @@ impl CompactionManager {
         CompactionManager {
             level_multiplier,
             size_threshold,
+            l0_files: L0_COMPACTION_FILES,
+            fadvise: false,
+        }
+    }
+
+    /// Hint to the kernel that compaction inputs are read once, so they
+    /// don't push hot data out of the page cache
+    pub fn fadvise(mut self, enabled: bool) -> Self {
+        self.fadvise = enabled;
+        self
+    }
+
+    /// Number of files that triggers a level 0 compaction
+    pub fn l0_files(mut self, files: usize) -> Self {
+        self.l0_files = files.max(1);
+        self
+    }
+
+    /// How full `level` is relative to its compaction trigger; compaction
+    /// is due at 1.0 or more
+    pub fn score(&self, level: usize, tables: &[Arc<SSTable>]) -> f64 {
+        if level == 0 {
+            return tables.len() as f64 / self.l0_files as f64;
         }
+        let level_size: usize = tables.iter().map(|t| t.size()).sum();
+        level_size as f64 / self.level_threshold(level) as f64
+    }
+
+    fn level_threshold(&self, level: usize) -> usize {
+        self.size_threshold * (self.level_multiplier as usize).pow(level as u32)
     }
 
-    pub fn should_compact(&self, level: usize, tables: &[SSTable]) -> bool {
+    pub fn should_compact(&self, level: usize, tables: &[Arc<SSTable>]) -> bool {
         // Get total size of all SSTables at this level
         let level_size: usize = tables.iter().map(|t| t.size()).sum();
 
-        // Level 0 is special - compact when we have more than 4 files
+        // Level 0 is special - compact once it holds enough files
         if level == 0 {
-            return tables.len() >= 4;
+            return tables.len() >= self.l0_files;
         }
 
         // For other levels, use size-based threshold with multiplier
-        let level_threshold =
-            self.size_threshold * (self.level_multiplier as usize).pow(level as u32);
-        println!(
-            "Level {} size: {} bytes, threshold: {} bytes",
-            level, level_size, level_threshold
+        let level_threshold = self.level_threshold(level);
+        debug!(
+            level,
+            bytes = level_size,
+            threshold = level_threshold;
+            "Checked level {} for compaction",
+            level
         );
         level_size >= level_threshold
     }
 
-    pub fn compact(&self, tables: &[SSTable]) -> io::Result<SSTable> {
-        println!("Compacting {} tables", tables.len());
-        // Merge all SSTables into a single sorted map
-        let mut merged_data = BTreeMap::new();
+    /// Merge `tables`, ordered oldest to newest, into `output`, a table yet
+    /// to be written, applying `policy` to the versions of each key. `mode` applies to
+    /// reading the inputs and writing the output.
+    ///
+    /// The inputs are streamed through a k-way merge and the output written
+    /// as it's produced, so memory holds one entry per input plus the
+    /// versions of the key being merged, however large the tables are.
+    pub fn compact(
+        &self,
+        tables: &[Arc<SSTable>],
+        policy: &GcPolicy,
+        mode: IoMode,
+        mut output: SSTable,
+    ) -> io::Result<SSTable> {
+        debug!("Compacting {} tables", tables.len());
+        let mut merge = Merge::new(tables, mode, self.fadvise)?;
+        let expected: u64 = tables.iter().map(|t| t.properties().entry_count).sum();
+
+        // Surviving versions of the current key, last first
+        let mut pending: Vec<(Key, Version)> = Vec::new();
+        let records = std::iter::from_fn(|| loop {
+            if let Some(record) = pending.pop() {
+                return Some(Ok(record));
+            }
+            match merge.next_key() {
+                Ok(Some((key, versions))) => {
+                    pending = policy
+                        .retain(&key, versions)
+                        .into_iter()
+                        .rev()
+                        .map(|version| (key.clone(), version))
+                        .collect();
+                }
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        });
+
+        output.write_stream_with(records, expected as usize, mode)?;
+
+        debug!(
+            "Merged {} entries into a new SSTable of size {} bytes",
+            output.properties().entry_count,
+            output.size()
+        );
+        Ok(output)
+    }
+}
+
+/// The entry at the front of one merge input
+struct Head {
+    key: Key,
+    seq: u64,
+    entry: Entry,
+    // Index of the input; higher is newer
+    source: usize,
+}
+
+impl Head {
+    /// Heap order: smallest key first, then newest version first
+    fn rank(&self) -> (Reverse<&Key>, u64, usize) {
+        (Reverse(&self.key), self.seq, self.source)
+    }
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Self) -> bool {
+        self.rank() == other.rank()
+    }
+}
+
+impl Eq for Head {}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Head {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        self.rank().cmp(&other.rank())
+    }
+}
+
+/// A k-way merge of tables' entries in key order, newest version first
+/// within a key
+struct Merge {
+    readers: Vec<EntryReader>,
+    heap: BinaryHeap<Head>,
+}
+
+impl Merge {
+    fn new(tables: &[Arc<SSTable>], mode: IoMode, fadvise: bool) -> io::Result<Self> {
+        let mut merge = Merge {
+            readers: Vec::with_capacity(tables.len()),
+            heap: BinaryHeap::with_capacity(tables.len()),
+        };
+        for (source, table) in tables.iter().enumerate() {
+            let mut reader = table.entries_with(mode)?;
+            if fadvise {
+                reader.advise_sequential();
+            }
+            merge.readers.push(reader);
+            merge.advance(source)?;
+        }
+        Ok(merge)
+    }
+
+    /// Queue the next entry of input `source`, if it has one
+    fn advance(&mut self, source: usize) -> io::Result<()> {
+        if let Some((key, seq, entry)) = self.readers[source].next_entry()? {
+            self.heap.push(Head {
+                key: key.to_vec(),
+                seq,
+                entry: entry.to_entry(),
+                source,
+            });
+        }
+        Ok(())
+    }
+
+    /// The next key with all of its versions across the inputs, newest first
+    fn next_key(&mut self) -> io::Result<Option<(Key, Vec<Version>)>> {
+        let Some(first) = self.heap.pop() else {
+            return Ok(None);
+        };
+        self.advance(first.source)?;
+        let key = first.key;
+        let mut versions = vec![Version::new(first.seq, first.entry)];
+        while self.heap.peek().is_some_and(|head| head.key == key) {
+            let head = self.heap.pop().unwrap();
+            self.advance(head.source)?;
+            versions.push(Version::new(head.seq, head.entry));
+        }
+        Ok(Some((key, versions)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tombstone(seq: u64, deleted_at: u64) -> Version {
+        Version::new(seq, Entry::Tombstone { deleted_at })
+    }
+
+    fn value(seq: u64, v: &[u8]) -> Version {
+        Version::new(seq, Entry::Value(v.to_vec()))
+    }
+
+    fn policy(now: u64, tombstone_retention: u64, bottommost: bool) -> GcPolicy {
+        GcPolicy {
+            now,
+            tombstone_retention,
+            bottommost,
+            snapshots: Vec::new(),
+            merge: None,
+        }
+    }
+
+    #[test]
+    fn test_retain_newest_value() {
+        let policy = policy(100, 0, false);
+        assert_eq!(
+            policy.retain(
+                b"key",
+                vec![value(3, b"new"), tombstone(2, 50), value(1, b"old")]
+            ),
+            vec![value(3, b"new")]
+        );
+    }
+
+    #[test]
+    fn test_retain_tombstone_within_window() {
+        let policy = policy(100, 60, true);
+        assert_eq!(
+            policy.retain(
+                b"key",
+                vec![
+                    tombstone(4, 50),
+                    tombstone(3, 40),
+                    value(2, b"old"),
+                    value(1, b"older")
+                ]
+            ),
+            vec![tombstone(4, 50), value(2, b"old")]
+        );
+    }
+
+    #[test]
+    fn test_expired_tombstone() {
+        let mut policy = policy(100, 10, false);
+        let versions = vec![tombstone(2, 50), value(1, b"old")];
+        assert_eq!(
+            policy.retain(b"key", versions.clone()),
+            vec![tombstone(2, 50)]
+        );
+
+        policy.bottommost = true;
+        assert!(policy.retain(b"key", versions).is_empty());
+    }
+
+    #[test]
+    fn test_retain_versions_visible_to_snapshots() {
+        let mut policy = policy(100, 0, true);
+        policy.snapshots = vec![1, 3, 5];
+        let versions = vec![
+            value(6, b"v6"),
+            tombstone(4, 50),
+            value(3, b"v3"),
+            value(2, b"v2"),
+            value(1, b"v1"),
+        ];
+
+        // Snapshot 5 reads the tombstone, 3 reads v3 and 1 reads v1
+        assert_eq!(
+            policy.retain(b"key", versions),
+            vec![
+                value(6, b"v6"),
+                tombstone(4, 50),
+                value(3, b"v3"),
+                value(1, b"v1")
+            ]
+        );
+
+        // A tombstone a snapshot reads hides older data from it...
+        let versions = vec![value(6, b"v6"), tombstone(4, 50), value(3, b"v3")];
+        policy.snapshots = vec![3, 4];
+        assert_eq!(policy.retain(b"key", versions.clone()), versions);
+
+        // ...but may go once nothing it hides is kept beneath it
+        policy.snapshots = vec![4];
+        assert_eq!(policy.retain(b"key", versions), vec![value(6, b"v6")]);
+    }
+
+    #[test]
+    fn test_retain_applies_merge_operands() {
+        let operand = |seq: u64, v: &[u8]| Version::new(seq, Entry::Merge(v.to_vec()));
+        let mut policy = policy(100, 0, false);
+        policy.merge = Some(Arc::new(
+            |_: &[u8], base: Option<&[u8]>, operands: &[&[u8]]| {
+                let mut value = base.unwrap_or(b"_").to_vec();
+                operands.iter().for_each(|o| value.extend_from_slice(o));
+                value
+            },
+        ));
+
+        // Nothing to apply them to yet: every operand stays
+        let versions = vec![operand(3, b"c"), operand(2, b"b")];
+        assert_eq!(policy.retain(b"key", versions.clone()), versions);
+
+        // With a value beneath, only the result is left
+        let versions = vec![operand(3, b"c"), operand(2, b"b"), value(1, b"a")];
+        assert_eq!(
+            policy.retain(b"key", versions.clone()),
+            vec![value(3, b"abc")]
+        );
+
+        // A snapshot between operands keeps reading its own result
+        policy.snapshots = vec![2];
+        assert_eq!(
+            policy.retain(b"key", versions),
+            vec![value(3, b"abc"), value(2, b"ab")]
+        );
+
+        // At the bottom, or past a tombstone, they start from nothing
+        policy.snapshots.clear();
+        policy.bottommost = true;
+        let versions = vec![operand(2, b"b"), operand(1, b"a")];
+        assert_eq!(policy.retain(b"key", versions), vec![value(2, b"_ab")]);
+        let versions = vec![operand(3, b"b"), tombstone(2, 50), value(1, b"a")];
+        assert_eq!(policy.retain(b"key", versions), vec![value(3, b"_b")]);
+    }
+
+    /// Counts the bytes each thread has allocated and not yet freed, and the
+    /// most it has held at once, so a test can bound one call's memory
+    /// without interference from tests running on other threads
+    mod counting {
+        use std::alloc::{GlobalAlloc, Layout, System};
+        use std::cell::Cell;
+
+        thread_local! {
+            static CURRENT: Cell<isize> = const { Cell::new(0) };
+            static PEAK: Cell<isize> = const { Cell::new(0) };
+        }
+
+        pub struct Counting;
+
+        fn track(delta: isize) {
+            let _ = CURRENT.try_with(|current| {
+                let now = current.get() + delta;
+                current.set(now);
+                let _ = PEAK.try_with(|peak| peak.set(peak.get().max(now)));
+            });
+        }
+
+        unsafe impl GlobalAlloc for Counting {
+            unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+                let ptr = System.alloc(layout);
+                if !ptr.is_null() {
+                    track(layout.size() as isize);
+                }
+                ptr
+            }
+
+            unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+                System.dealloc(ptr, layout);
+                track(-(layout.size() as isize));
+            }
 
-        // Read and merge data from all tables
-        for table in tables {
-            if let Ok(entries) = table.read() {
-                for (key, value) in entries {
-                    merged_data.entry(key).or_insert(value);
+            unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+                let new_ptr = System.realloc(ptr, layout, new_size);
+                if !new_ptr.is_null() {
+                    track(new_size as isize - layout.size() as isize);
                 }
+                new_ptr
             }
         }
 
-        println!("Merged {} unique keys", merged_data.len());
+        /// Start measuring from what the thread holds now
+        pub fn reset() -> isize {
+            let now = CURRENT.with(Cell::get);
+            PEAK.with(|peak| peak.set(now));
+            now
+        }
+
+        pub fn peak() -> isize {
+            PEAK.with(Cell::get)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: counting::Counting = counting::Counting;
+
+    /// Four tables of 2MB each, every one rewriting the same 500 keys with
+    /// 4KB values, plus 100 keys of its own
+    fn large_inputs(dir: &tempfile::TempDir) -> Vec<Arc<SSTable>> {
+        (0..4u64)
+            .map(|n| {
+                let mut entries: Vec<(Key, Version)> = (0..500)
+                    .map(|i| format!("shared{:04}", i))
+                    .chain((0..100).map(|i| format!("own{}_{:03}", n, i)))
+                    .map(|key| {
+                        let seq = n * 1000 + 1;
+                        (key.into_bytes(), value(seq, &[b'a' + n as u8; 4096]))
+                    })
+                    .collect();
+                entries.sort_by(|a, b| a.0.cmp(&b.0));
+                let path = dir.path().join(format!("L0_{}.sst", n));
+                let mut table = SSTable::new(path).unwrap();
+                table.write_entries(&entries).unwrap();
+                Arc::new(table)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_compaction_memory_bounded() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let tables = large_inputs(&temp_dir);
+        let input_bytes: usize = tables.iter().map(|t| t.size()).sum();
+        let manager = CompactionManager::new(10, 1024 * 1024);
+        let output = SSTable::new(temp_dir.path().join("L1_4.sst")).unwrap();
 
-        // Create a new SSTable with merged data
-        let mut new_table = SSTable::new(tables[0].get_path().with_file_name(format!(
-            "compact_{}.sst",
-            std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs()
-        )))?;
+        let baseline = counting::reset();
+        let merged = manager
+            .compact(&tables, &policy(0, 0, true), IoMode::Buffered, output)
+            .unwrap();
+        let used = (counting::peak() - baseline) as usize;
 
-        // Write merged data to new SSTable
-        let entries: Vec<_> = merged_data.into_iter().collect();
-        new_table.write(&entries)?;
+        // Far below what holding the inputs would take
+        let budget = 512 * 1024;
+        assert!(input_bytes > 8 * 1024 * 1024);
+        assert!(used < budget, "compaction peaked at {} bytes", used);
 
-        println!("Created new SSTable of size {} bytes", new_table.size());
-        Ok(new_table)
+        // Newest table wins each shared key; every table's own keys survive
+        let entries = merged.read_entries().unwrap();
+        assert_eq!(entries.len(), 900);
+        assert!(entries.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        let shared = entries
+            .iter()
+            .find(|(key, _)| key == b"shared0042")
+            .unwrap();
+        assert_eq!(shared.1, value(3001, &[b'd'; 4096]));
+        assert!(merged.might_contain_key(b"own0_000"));
+        assert_eq!(merged.properties().entry_count, 900);
+        assert!(!temp_dir.path().join("L1_4.data").exists());
+    }
+
+    #[test]
+    fn test_merge_keeps_versions_newest_first() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let write = |n: u64, entries: &[(&[u8], Version)]| {
+            let path = temp_dir.path().join(format!("L0_{}.sst", n));
+            let mut table = SSTable::new(path).unwrap();
+            let entries: Vec<_> = entries
+                .iter()
+                .map(|(key, version)| (key.to_vec(), version.clone()))
+                .collect();
+            table.write_entries(&entries).unwrap();
+            Arc::new(table)
+        };
+        let tables = vec![
+            write(0, &[(b"a", value(1, b"a1")), (b"b", value(2, b"b2"))]),
+            write(1, &[(b"b", tombstone(5, 50)), (b"b", value(4, b"b4"))]),
+            write(2, &[(b"a", value(3, b"a3")), (b"c", value(6, b"c6"))]),
+        ];
+        let mut merge = Merge::new(&tables, IoMode::Buffered, false).unwrap();
+        let mut keys = Vec::new();
+        while let Some((key, versions)) = merge.next_key().unwrap() {
+            keys.push((key, versions.iter().map(|v| v.seq).collect::<Vec<_>>()));
+        }
+        assert_eq!(
+            keys,
+            vec![
+                (b"a".to_vec(), vec![3, 1]),
+                (b"b".to_vec(), vec![5, 4, 2]),
+                (b"c".to_vec(), vec![6]),
+            ]
+        );
     }
 }
@@ -0,0 +1,199 @@
+use std::fmt;
+use std::io;
+
+// [stored_size][raw_size] ahead of each compressed block
+pub const FRAME_HEADER_SIZE: usize = 8;
+
+/// How a table's data blocks are compressed. Each codec other than `None`
+/// needs its cargo feature (`snappy`, `lz4`) to write or read tables.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// Entries are stored as written
+    #[default]
+    None,
+    Snappy,
+    Lz4,
+}
+
+impl Compression {
+    /// The codec byte recorded in a table's properties
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Snappy => 1,
+            Compression::Lz4 => 2,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> io::Result<Self> {
+        match byte {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Snappy),
+            2 => Ok(Compression::Lz4),
+            other => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown compression codec {}", other),
+            )),
+        }
+    }
+
+    /// Whether this build can write and read the codec
+    pub fn is_supported(self) -> bool {
+        match self {
+            Compression::None => true,
+            Compression::Snappy => cfg!(feature = "snappy"),
+            Compression::Lz4 => cfg!(feature = "lz4"),
+        }
+    }
+
+    fn unsupported(self) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "{} compression is not compiled in (enable the `{}` feature)",
+                self, self
+            ),
+        )
+    }
+
+    /// `raw` as one framed block: `[stored_size][raw_size][stored bytes]`
+    pub fn compress_block(self, raw: &[u8]) -> io::Result<Vec<u8>> {
+        let stored = match self {
+            Compression::None => raw.to_vec(),
+            #[cfg(feature = "snappy")]
+            Compression::Snappy => snap::raw::Encoder::new()
+                .compress_vec(raw)
+                .map_err(io::Error::other)?,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => lz4_flex::block::compress(raw),
+            #[allow(unreachable_patterns)]
+            codec => return Err(codec.unsupported()),
+        };
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + stored.len());
+        frame.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&(raw.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&stored);
+        Ok(frame)
+    }
+
+    /// Sizes of the stored and raw block a frame header announces,
+    /// rejecting raw sizes over `max_raw`, the table's whole data section
+    pub fn frame_sizes(
+        header: &[u8; FRAME_HEADER_SIZE],
+        max_raw: u64,
+    ) -> io::Result<(usize, usize)> {
+        let stored = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let raw = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+        if raw as u64 > max_raw {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Block of {} bytes exceeds data section", raw),
+            ));
+        }
+        Ok((stored, raw))
+    }
+
+    /// Restore a block of `raw_size` bytes from its stored bytes
+    pub fn decompress(self, stored: &[u8], raw_size: usize) -> io::Result<Vec<u8>> {
+        let corrupt = |e: &dyn fmt::Display| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Corrupt {} block: {}", self, e),
+            )
+        };
+        let raw = match self {
+            Compression::None => stored.to_vec(),
+            #[cfg(feature = "snappy")]
+            Compression::Snappy => snap::raw::Decoder::new()
+                .decompress_vec(stored)
+                .map_err(|e| corrupt(&e))?,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => {
+                lz4_flex::block::decompress(stored, raw_size).map_err(|e| corrupt(&e))?
+            }
+            #[allow(unreachable_patterns)]
+            codec => return Err(codec.unsupported()),
+        };
+        if raw.len() != raw_size {
+            return Err(corrupt(&format!(
+                "{} bytes, expected {}",
+                raw.len(),
+                raw_size
+            )));
+        }
+        Ok(raw)
+    }
+
+    /// Restore the block held by a whole frame
+    pub fn decompress_frame(self, frame: &[u8], max_raw: u64) -> io::Result<Vec<u8>> {
+        let truncated = || io::Error::new(io::ErrorKind::InvalidData, "Compressed block cut short");
+        let header = frame.get(..FRAME_HEADER_SIZE).ok_or_else(truncated)?;
+        let (stored, raw) = Self::frame_sizes(header.try_into().unwrap(), max_raw)?;
+        let stored = frame
+            .get(FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + stored)
+            .ok_or_else(truncated)?;
+        self.decompress(stored, raw)
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compression::None => "none",
+            Compression::Snappy => "snappy",
+            Compression::Lz4 => "lz4",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Vec<u8> {
+        b"value value value value value value value ".repeat(50)
+    }
+
+    #[test]
+    fn test_supported_codecs_round_trip() {
+        for codec in [Compression::None, Compression::Snappy, Compression::Lz4] {
+            assert_eq!(Compression::from_byte(codec.to_byte()).unwrap(), codec);
+            if !codec.is_supported() {
+                continue;
+            }
+            let frame = codec.compress_block(&sample()).unwrap();
+            if codec != Compression::None {
+                assert!(frame.len() < sample().len() / 4, "{}", codec);
+            }
+            let raw = codec.decompress_frame(&frame, u64::MAX).unwrap();
+            assert_eq!(raw, sample());
+        }
+    }
+
+    #[test]
+    fn test_damaged_frames_rejected() {
+        let codec = Compression::None;
+        let frame = codec.compress_block(&sample()).unwrap();
+        let err = codec
+            .decompress_frame(&frame[..frame.len() - 1], u64::MAX)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = codec.decompress_frame(&frame, 10).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(Compression::from_byte(9).is_err());
+    }
+
+    #[test]
+    #[cfg(not(all(feature = "snappy", feature = "lz4")))]
+    fn test_missing_codec_is_an_error() {
+        let codec = if cfg!(feature = "snappy") {
+            Compression::Lz4
+        } else {
+            Compression::Snappy
+        };
+        let err = codec.compress_block(&sample()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        let err = codec.decompress(b"stored", 6).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+}
@@ -0,0 +1,327 @@
+use super::index::SparseIndex;
+use super::SSTable;
+use crate::entry::EntryRef;
+use crate::error::{self, StorageError};
+use std::fmt::{self, Write};
+use std::io::{self, Seek, SeekFrom};
+
+/// What [`SSTable::describe`] lists after the table's metadata
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DescribeOptions {
+    /// Every stored version's key, sequence number and kind
+    pub keys: bool,
+    /// As `keys`, with each value as well
+    pub values: bool,
+    /// Re-read the whole table as [`verify`](SSTable::verify) does
+    pub verify: bool,
+}
+
+impl SSTable {
+    /// A human-readable account of the table file for debugging; see
+    /// [`describe_to`](SSTable::describe_to)
+    pub fn describe(&self, options: DescribeOptions) -> String {
+        let mut out = String::new();
+        self.describe_to(options, &mut out)
+            .expect("writing to a String cannot fail");
+        out
+    }
+
+    /// Write the file's layout, bloom filter parameters, entry counts and
+    /// key range as read from the file itself, then its entries and a
+    /// verification if `options` ask for them. Keys and values are written
+    /// with non-printable bytes hex-escaped. Tables written before indexes
+    /// or compression existed are described by the sections they lack;
+    /// open them with [`open_legacy`](SSTable::open_legacy).
+    ///
+    /// Damage is reported with its offset in place of the rest of the
+    /// output rather than returned as an error. Returns whether none was
+    /// found.
+    pub fn describe_to(
+        &self,
+        options: DescribeOptions,
+        out: &mut impl Write,
+    ) -> Result<bool, fmt::Error> {
+        writeln!(out, "file: {}", self.path.display())?;
+        let len = match self
+            .open_file()
+            .and_then(|mut file| file.seek(SeekFrom::End(0)))
+        {
+            Ok(len) => len,
+            Err(e) => return damage(out, e),
+        };
+        writeln!(out, "size: {} bytes", len)?;
+
+        let metadata = self
+            .open_file()
+            .and_then(|mut file| Self::read_metadata(&mut file, &self.path));
+        let (bloom, properties) = match metadata {
+            Ok(metadata) => metadata,
+            Err(e) => return damage(out, e),
+        };
+        let sections = self.open_file().and_then(|mut file| {
+            Self::skip_metadata(&mut file, &self.path)?;
+            let data_start = file.stream_position()?;
+            Ok((data_start, SparseIndex::read(&mut file)?))
+        });
+        let (data_start, index) = match sections {
+            Ok(sections) => sections,
+            Err(e) => return damage(out, error::locate(e, &self.path, 0)),
+        };
+
+        let legacy_properties = properties.entry_count > 0 && properties.data_size == 0;
+        match self.format_version {
+            Some(version) => write!(out, "format: version {}, ", version)?,
+            None => write!(out, "format: no header (legacy), ")?,
+        }
+        writeln!(
+            out,
+            "{}, {}",
+            match (&index, self.format_version) {
+                (Some(_), _) => "sparse index",
+                (None, Some(_)) => "no sparse index (footer missing)",
+                (None, None) => "no sparse index (legacy)",
+            },
+            if legacy_properties {
+                "properties without compression (legacy)"
+            } else {
+                "properties with compression"
+            }
+        )?;
+        match &bloom {
+            Some(bloom) => writeln!(
+                out,
+                "bloom filter: {} bits, {} hash functions",
+                bloom.bit_count(),
+                bloom.hash_count()
+            )?,
+            None => writeln!(out, "bloom filter: none")?,
+        }
+        writeln!(out, "compression: {}", properties.compression)?;
+        writeln!(
+            out,
+            "entries: {} ({} tombstones), max seq {}",
+            properties.entry_count, properties.tombstone_count, properties.max_seq
+        )?;
+        match properties.key_range() {
+            Some((smallest, largest)) => writeln!(
+                out,
+                "keys: [\"{}\", \"{}\"]",
+                smallest.escape_ascii(),
+                largest.escape_ascii()
+            )?,
+            None => writeln!(out, "keys: none")?,
+        }
+        let data_end = index.as_ref().map_or(len, SparseIndex::data_end);
+        write!(out, "data: offsets {}..{}", data_start, data_end)?;
+        match &index {
+            Some(index) => writeln!(out, ", {} indexed blocks", index.blocks().count())?,
+            None => writeln!(out)?,
+        }
+
+        if options.keys || options.values {
+            let mut reader = match self.entries() {
+                Ok(reader) => reader,
+                Err(e) => return damage(out, e.into()),
+            };
+            loop {
+                match reader.next_entry() {
+                    Ok(Some((key, seq, entry))) => {
+                        write_entry(out, key, seq, entry, options.values)?
+                    }
+                    Ok(None) => break,
+                    Err(e) => return damage(out, e),
+                }
+            }
+        }
+
+        if options.verify {
+            match self.verify() {
+                Ok(entries) => writeln!(out, "verify: ok, {} entries", entries)?,
+                Err(e) => {
+                    write!(out, "verify: ")?;
+                    return damage(out, e.into());
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Report `e`, with its offset if it's damage to the file
+fn damage(out: &mut impl Write, e: io::Error) -> Result<bool, fmt::Error> {
+    match StorageError::from(e) {
+        StorageError::Corruption { offset, detail, .. } => {
+            writeln!(out, "corrupt at offset {}: {}", offset, detail)?
+        }
+        e => writeln!(out, "error: {}", e)?,
+    }
+    Ok(false)
+}
+
+/// One stored version as `"key" @seq kind`, followed by its value if asked
+fn write_entry(
+    out: &mut impl Write,
+    key: &[u8],
+    seq: u64,
+    entry: EntryRef,
+    values: bool,
+) -> fmt::Result {
+    write!(out, "\"{}\" @{} ", key.escape_ascii(), seq)?;
+    let (kind, value, expires_at) = match entry {
+        EntryRef::Value(value) => ("put", Some(value), None),
+        EntryRef::Expiring { value, expires_at } => ("put", Some(value), Some(expires_at)),
+        EntryRef::Tombstone { .. } => ("delete", None, None),
+        EntryRef::Merge(operand) => ("merge", Some(operand), None),
+    };
+    out.write_str(kind)?;
+    if let Some(value) = value.filter(|_| values) {
+        write!(out, " \"{}\"", value.escape_ascii())?;
+    }
+    if let Some(expires_at) = expires_at {
+        write!(out, ", expires at {}", expires_at)?;
+    }
+    writeln!(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::{Entry, Version};
+    use crate::sstable::{
+        write_record, TableProperties, HEADER_SIZE, NO_FILTER, TABLE_FORMAT_VERSION,
+    };
+    use std::fs::{self, File};
+    use std::io::Write as _;
+    use tempfile::TempDir;
+
+    fn versions() -> Vec<(Vec<u8>, Version)> {
+        let mut data: Vec<_> = (0..100)
+            .map(|i| {
+                let key = format!("key{:03}", i).into_bytes();
+                (key, Version::new(i + 10, Entry::Value(vec![b'v', i as u8])))
+            })
+            .collect();
+        data.insert(
+            1,
+            (
+                b"key000".to_vec(),
+                Version::new(1, Entry::Tombstone { deleted_at: 0 }),
+            ),
+        );
+        data
+    }
+
+    fn options(keys: bool, values: bool, verify: bool) -> DescribeOptions {
+        DescribeOptions {
+            keys,
+            values,
+            verify,
+        }
+    }
+
+    #[test]
+    fn test_describe_healthy_table() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut table = SSTable::new(temp_dir.path().join("healthy.sst")).unwrap();
+        table.write_entries(&versions()).unwrap();
+
+        let mut out = String::new();
+        assert!(table
+            .describe_to(options(false, false, true), &mut out)
+            .unwrap());
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(
+            lines[2],
+            format!(
+                "format: version {}, sparse index, properties with compression",
+                TABLE_FORMAT_VERSION
+            )
+        );
+        assert!(lines[3].starts_with("bloom filter: ") && lines[3].ends_with(" hash functions"));
+        assert_eq!(lines[4], "compression: none");
+        assert_eq!(lines[5], "entries: 101 (1 tombstones), max seq 109");
+        assert_eq!(lines[6], "keys: [\"key000\", \"key099\"]");
+        assert!(lines[7].ends_with(", 7 indexed blocks"));
+        assert_eq!(lines[8], "verify: ok, 101 entries");
+        assert_eq!(lines.len(), 9);
+
+        let keys = table.describe(options(true, false, false));
+        assert!(keys.contains("\n\"key000\" @10 put\n\"key000\" @1 delete\n\"key001\" @11 put\n"));
+        let values = table.describe(options(false, true, false));
+        assert!(values.contains("\n\"key000\" @10 put \"v\\x00\"\n"));
+        assert!(values.contains("\n\"key099\" @109 put \"vc\"\n"));
+    }
+
+    #[test]
+    fn test_describe_legacy_table() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("legacy.sst");
+        // No filter, properties without the codec, and no index
+        let mut properties = TableProperties::new();
+        let entry = EntryRef::Value(b"value");
+        properties.record(b"key", 3, entry);
+        let properties_bytes = properties.to_bytes();
+        let legacy = &properties_bytes[..properties_bytes.len() - 9];
+        let mut file = File::create(&path).unwrap();
+        file.write_all(&NO_FILTER.to_le_bytes()).unwrap();
+        file.write_all(&(legacy.len() as u32).to_le_bytes())
+            .unwrap();
+        file.write_all(legacy).unwrap();
+        write_record(&mut file, b"key", 3, entry).unwrap();
+        drop(file);
+
+        let table = SSTable::open_legacy(path).unwrap();
+        let out = table.describe(options(false, true, true));
+        assert!(out.contains(
+            "\nformat: no header (legacy), no sparse index (legacy), \
+             properties without compression (legacy)\n"
+        ));
+        assert!(out.contains("\nbloom filter: none\n"));
+        assert!(out.contains("\n\"key\" @3 put \"value\"\nverify: ok, 1 entries\n"));
+    }
+
+    #[test]
+    fn test_describe_reports_where_a_truncated_table_breaks() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("whole.sst");
+        let mut table = SSTable::new(path.clone()).unwrap();
+        table.write_entries(&versions()).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        let data_end = table.index.as_ref().unwrap().data_end() as usize;
+
+        // Cut partway through the data section: the footer goes with it, and
+        // the last entry runs off the end
+        let cut = temp_dir.path().join("cut.sst");
+        fs::write(&cut, &bytes[..data_end - 5]).unwrap();
+        let table = SSTable::new(cut.clone()).unwrap();
+        let mut out = String::new();
+        assert!(!table
+            .describe_to(options(true, false, true), &mut out)
+            .unwrap());
+        assert!(out.contains(&format!(
+            "\nformat: version {}, no sparse index (footer missing), ",
+            TABLE_FORMAT_VERSION
+        )));
+        assert!(out.contains("\n\"key098\" @108 put\n"));
+        let last = out.lines().last().unwrap();
+        assert!(last.starts_with("corrupt at offset "), "{}", out);
+        assert!(last.ends_with(": entry cut short"), "{}", out);
+        assert!(!out.contains("verify:"));
+
+        // Whatever survives a cut, describing reports rather than panics
+        for len in 0..bytes.len() {
+            fs::write(&cut, &bytes[..len]).unwrap();
+            // A cut into the header doesn't open at all
+            let Ok(table) = SSTable::new(cut.clone()) else {
+                assert!(len < HEADER_SIZE);
+                continue;
+            };
+            let mut out = String::new();
+            let healthy = table
+                .describe_to(options(true, true, true), &mut out)
+                .unwrap();
+            assert!(!healthy, "cut at {}: {}", len, out);
+            assert!(out.lines().last().unwrap().contains("corrupt at offset "));
+        }
+    }
+}
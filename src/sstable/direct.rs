@@ -0,0 +1,228 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Offset, length and buffer alignment required by O_DIRECT
+pub const ALIGNMENT: usize = 4096;
+// Bytes moved per direct read or write
+const BUFFER_SIZE: usize = 256 * ALIGNMENT;
+
+/// A fixed-size heap buffer whose start is aligned to [`ALIGNMENT`]
+struct AlignedBuf {
+    data: Vec<u8>,
+    offset: usize,
+}
+
+impl AlignedBuf {
+    fn new(size: usize) -> Self {
+        let data = vec![0u8; size + ALIGNMENT];
+        let offset = data.as_ptr().align_offset(ALIGNMENT);
+        AlignedBuf { data, offset }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.data[self.offset..self.offset + BUFFER_SIZE]
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.data[self.offset..self.offset + BUFFER_SIZE]
+    }
+}
+
+/// Open `options` with O_DIRECT where the platform and filesystem support it,
+/// falling back to a normal open otherwise
+fn open(path: &Path, options: &mut OpenOptions) -> io::Result<File> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let mut direct = options.clone();
+        direct.custom_flags(libc::O_DIRECT);
+        match direct.open(path) {
+            // Filesystems such as tmpfs reject the flag
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput => {}
+            result => return result,
+        }
+    }
+    options.open(path)
+}
+
+/// Writes a file in aligned blocks, bypassing the page cache.
+///
+/// The last block is padded up to the alignment and the padding cut off
+/// again in [`finish`](DirectWriter::finish), which must be called.
+pub struct DirectWriter {
+    file: File,
+    buf: AlignedBuf,
+    filled: usize,
+    written: u64,
+}
+
+impl DirectWriter {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = open(
+            path,
+            OpenOptions::new().write(true).create(true).truncate(true),
+        )?;
+        Ok(DirectWriter {
+            file,
+            buf: AlignedBuf::new(BUFFER_SIZE),
+            filled: 0,
+            written: 0,
+        })
+    }
+
+    /// Write out the buffered tail and trim the file to the bytes written,
+    /// handing back the file
+    pub fn finish(mut self) -> io::Result<File> {
+        let len = self.written + self.filled as u64;
+        let padded = self.filled.next_multiple_of(ALIGNMENT);
+        self.buf.as_mut_slice()[self.filled..padded].fill(0);
+        self.file.write_all(&self.buf.as_slice()[..padded])?;
+        self.file.set_len(len)?;
+        Ok(self.file)
+    }
+}
+
+impl Write for DirectWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = data.len().min(BUFFER_SIZE - self.filled);
+        self.buf.as_mut_slice()[self.filled..self.filled + n].copy_from_slice(&data[..n]);
+        self.filled += n;
+        if self.filled == BUFFER_SIZE {
+            self.file.write_all(self.buf.as_slice())?;
+            self.written += BUFFER_SIZE as u64;
+            self.filled = 0;
+        }
+        Ok(n)
+    }
+
+    /// Only whole buffers can be written before `finish`, so this is a no-op
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads a file front to back in aligned blocks, bypassing the page cache
+pub struct DirectReader {
+    file: File,
+    buf: AlignedBuf,
+    pos: usize,
+    filled: usize,
+}
+
+impl DirectReader {
+    /// Open `path` positioned at byte `start`
+    pub fn open(path: &Path, start: u64) -> io::Result<Self> {
+        let mut file = open(path, OpenOptions::new().read(true))?;
+        let aligned = start - start % ALIGNMENT as u64;
+        file.seek(SeekFrom::Start(aligned))?;
+        let mut reader = DirectReader {
+            file,
+            buf: AlignedBuf::new(BUFFER_SIZE),
+            pos: 0,
+            filled: 0,
+        };
+        reader.skip((start - aligned) as usize)?;
+        Ok(reader)
+    }
+
+    /// Advance past `n` bytes without copying them out
+    pub fn skip(&mut self, mut n: usize) -> io::Result<()> {
+        while n > 0 {
+            let available = self.fill_buf()?.len();
+            if available == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let step = n.min(available);
+            self.consume(step);
+            n -= step;
+        }
+        Ok(())
+    }
+}
+
+impl Read for DirectReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(out.len());
+        out[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for DirectReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.filled {
+            // Reads past the end come back short, so only the final read of
+            // the file returns less than a whole buffer
+            self.filled = 0;
+            loop {
+                match self.file.read(&mut self.buf.as_mut_slice()[self.filled..]) {
+                    Ok(0) => break,
+                    Ok(n) => self.filled += n,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                }
+                if self.filled == BUFFER_SIZE || !self.filled.is_multiple_of(ALIGNMENT) {
+                    break;
+                }
+            }
+            self.pos = 0;
+        }
+        Ok(&self.buf.as_slice()[self.pos..self.filled])
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.pos = (self.pos + n).min(self.filled);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_round_trip_unaligned_lengths() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("direct");
+
+        for len in [0, 1, ALIGNMENT - 1, ALIGNMENT, BUFFER_SIZE + 17] {
+            let data = pattern(len);
+            let mut writer = DirectWriter::create(&path).unwrap();
+            for chunk in data.chunks(1000) {
+                writer.write_all(chunk).unwrap();
+            }
+            writer.finish().unwrap();
+            assert_eq!(std::fs::read(&path).unwrap(), data);
+
+            let mut read = Vec::new();
+            DirectReader::open(&path, 0)
+                .unwrap()
+                .read_to_end(&mut read)
+                .unwrap();
+            assert_eq!(read, data);
+        }
+    }
+
+    #[test]
+    fn test_reader_starts_mid_block() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("direct");
+        let data = pattern(3 * ALIGNMENT + 5);
+        std::fs::write(&path, &data).unwrap();
+
+        let start = ALIGNMENT + 123;
+        let mut reader = DirectReader::open(&path, start as u64).unwrap();
+        reader.skip(10).unwrap();
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, &data[start + 10..]);
+    }
+}
@@ -0,0 +1,218 @@
+use crate::Key;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Entries between consecutive index points
+pub const INDEX_INTERVAL: usize = 16;
+// Closes the footer of tables that carry an index; older tables end in data
+const FOOTER_MAGIC: u64 = u64::from_le_bytes(*b"LSMINDEX");
+// [index_offset][magic]
+const FOOTER_SIZE: u64 = 16;
+
+/// Every [`INDEX_INTERVAL`]th key of a table with the file offset of its
+/// newest version, written after the data section so point lookups can
+/// seek close to a key instead of scanning from the front
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SparseIndex {
+    points: Vec<(Key, u64)>,
+    // Where the data section stops and the index block begins
+    data_end: u64,
+}
+
+impl SparseIndex {
+    /// Offset of the data section's end, which is where the index starts
+    pub fn data_end(&self) -> u64 {
+        self.data_end
+    }
+
+    /// Offset of the last index point at or before `key`, or `None` if
+    /// `key` sorts before the first key in the table
+    pub fn seek(&self, key: &[u8]) -> Option<u64> {
+        self.seek_block(key).map(|(start, _)| start)
+    }
+
+    /// Offsets `[start, end)` of the block that would hold `key`: from the
+    /// last index point at or before it to the next point or the end of
+    /// the data section
+    pub fn seek_block(&self, key: &[u8]) -> Option<(u64, u64)> {
+        let after = self
+            .points
+            .partition_point(|(point, _)| point.as_slice() <= key);
+        let start = self.points[after.checked_sub(1)?].1;
+        let end = self
+            .points
+            .get(after)
+            .map_or(self.data_end, |(_, offset)| *offset);
+        Some((start, end))
+    }
+
+    /// Write format: [count] then [key_size][key][offset] per point, followed
+    /// by the footer [index_offset][magic]
+    /// Each block's first key and its `[start, end)` offsets, in key order
+    pub fn blocks(&self) -> impl Iterator<Item = (&[u8], u64, u64)> {
+        self.points.iter().enumerate().map(|(i, (key, start))| {
+            let end = self
+                .points
+                .get(i + 1)
+                .map_or(self.data_end, |(_, offset)| *offset);
+            (key.as_slice(), *start, end)
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(self.points.len() as u32).to_le_bytes());
+        for (key, offset) in &self.points {
+            bytes.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(key);
+            bytes.extend_from_slice(&offset.to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.data_end.to_le_bytes());
+        bytes.extend_from_slice(&FOOTER_MAGIC.to_le_bytes());
+        bytes
+    }
+
+    /// Load the index from the end of `file`, or `None` for a table written
+    /// before indexes existed
+    pub fn read<R: Read + Seek>(file: &mut R) -> io::Result<Option<Self>> {
+        let len = file.seek(SeekFrom::End(0))?;
+        if len < FOOTER_SIZE {
+            return Ok(None);
+        }
+        let mut footer = [0u8; FOOTER_SIZE as usize];
+        file.seek(SeekFrom::Start(len - FOOTER_SIZE))?;
+        file.read_exact(&mut footer)?;
+        let (offset, magic) = footer.split_at(8);
+        if u64::from_le_bytes(magic.try_into().unwrap()) != FOOTER_MAGIC {
+            return Ok(None);
+        }
+
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid sparse index");
+        let data_end = u64::from_le_bytes(offset.try_into().unwrap());
+        let mut bytes = Vec::new();
+        let index_size = (len - FOOTER_SIZE)
+            .checked_sub(data_end)
+            .ok_or_else(invalid)?;
+        file.seek(SeekFrom::Start(data_end))?;
+        file.take(index_size).read_to_end(&mut bytes)?;
+
+        let mut pos = 0;
+        let mut take = |n: usize| {
+            let slice = bytes.get(pos..pos + n).ok_or_else(invalid)?;
+            pos += n;
+            Ok::<_, io::Error>(slice)
+        };
+        let count = u32::from_le_bytes(take(4)?.try_into().unwrap());
+        let mut points = Vec::with_capacity((count as usize).min(index_size as usize / 12));
+        for _ in 0..count {
+            let key_size = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+            let key = take(key_size)?.to_vec();
+            let offset = u64::from_le_bytes(take(8)?.try_into().unwrap());
+            if offset >= data_end || points.last().is_some_and(|(last, _)| *last >= key) {
+                return Err(invalid());
+            }
+            points.push((key, offset));
+        }
+        Ok(Some(SparseIndex { points, data_end }))
+    }
+}
+
+/// Collects index points while a table's data section is written
+#[derive(Default)]
+pub struct IndexBuilder {
+    index: SparseIndex,
+    // Entries written since the last index point
+    since_point: usize,
+    last_key: Option<Key>,
+}
+
+impl IndexBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Account for an entry of `size` bytes written next. Points only
+    /// fall on a key's first, newest version so a lookup never starts
+    /// partway through its versions.
+    pub fn record(&mut self, key: &[u8], size: usize) {
+        let new_key = self.last_key.as_deref() != Some(key);
+        if self.starts_point(key) {
+            self.index.points.push((key.to_vec(), self.index.data_end));
+            self.since_point = 0;
+        }
+        if new_key {
+            self.last_key = Some(key.to_vec());
+        }
+        self.since_point += 1;
+        self.index.data_end += size as u64;
+    }
+
+    /// Whether recording `key` next would place an index point
+    pub fn starts_point(&self, key: &[u8]) -> bool {
+        self.last_key.as_deref() != Some(key)
+            && (self.index.points.is_empty() || self.since_point >= INDEX_INTERVAL)
+    }
+
+    /// Account for `size` bytes written that aren't entries of their own,
+    /// such as a compressed block holding the entries recorded before it
+    pub fn advance(&mut self, size: usize) {
+        self.index.data_end += size as u64;
+    }
+
+    /// The finished index, for a data section that begins at file offset
+    /// `start`
+    pub fn finish(mut self, start: u64) -> SparseIndex {
+        for (_, offset) in &mut self.index.points {
+            *offset += start;
+        }
+        self.index.data_end += start;
+        self.index
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_round_trip_and_seek() {
+        let mut builder = IndexBuilder::new();
+        for i in 0..40u32 {
+            // Two versions per key; points land on the first of each pair
+            let key = format!("key{:03}", i / 2).into_bytes();
+            builder.record(&key, 10);
+        }
+        let index = builder.finish(100);
+        assert_eq!(index.points.len(), 3);
+        assert_eq!(index.data_end(), 500);
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("index");
+        let mut file = File::create(&path).unwrap();
+        file.write_all(&[0u8; 500]).unwrap();
+        file.write_all(&index.to_bytes()).unwrap();
+        let read = SparseIndex::read(&mut File::open(&path).unwrap()).unwrap();
+        assert_eq!(read.as_ref(), Some(&index));
+
+        assert_eq!(index.seek(b"a"), None);
+        assert_eq!(index.seek(b"key000"), Some(100));
+        assert_eq!(index.seek(b"key007"), Some(100));
+        assert_eq!(index.seek(b"key008"), Some(260));
+        assert_eq!(index.seek(b"zzz"), Some(420));
+    }
+
+    #[test]
+    fn test_no_footer_means_no_index() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("plain");
+        for bytes in [&[7u8; 64][..], &[]] {
+            std::fs::write(&path, bytes).unwrap();
+            assert_eq!(
+                SparseIndex::read(&mut File::open(&path).unwrap()).unwrap(),
+                None
+            );
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use super::{EntryReader, SSTable};
+use crate::entry::EntryRef;
+use crate::{Key, Value};
+use std::io;
+
+/// Live key/value pairs of an [`SSTable`], read one entry at a time so
+/// only the current entry is held in memory, whatever the table's size.
+/// Yields what [`SSTable::read`] collects; a damaged entry comes out as an
+/// error, after which the iterator ends.
+pub struct SSTableIterator<'a> {
+    table: &'a SSTable,
+    reader: EntryReader,
+    // Key of the last entry decoded, whose older versions are skipped
+    last_key: Option<Key>,
+    // Keys below this are skipped after a seek that landed on a block start
+    lower: Option<Key>,
+    done: bool,
+}
+
+impl<'a> SSTableIterator<'a> {
+    pub(super) fn new(table: &'a SSTable) -> io::Result<Self> {
+        Ok(SSTableIterator {
+            table,
+            reader: table.entries()?,
+            last_key: None,
+            lower: None,
+            done: false,
+        })
+    }
+
+    /// Reposition so the next pair is the first with a key at or after
+    /// `key`. Tables with an index start reading from the block holding it
+    /// rather than the front.
+    pub fn seek(&mut self, key: &[u8]) -> io::Result<()> {
+        self.reader = self.table.entries_from(key)?;
+        self.last_key = None;
+        self.lower = Some(key.to_vec());
+        self.done = false;
+        Ok(())
+    }
+
+    fn next_pair(&mut self) -> io::Result<Option<(Key, Value)>> {
+        while let Some((key, _, entry)) = self.reader.next_entry()? {
+            if self.last_key.as_deref() == Some(key) {
+                continue; // older version of a key already resolved
+            }
+            if self.lower.as_deref().is_some_and(|lower| key < lower) {
+                continue;
+            }
+            self.lower = None;
+            self.last_key = Some(key.to_vec());
+            if let EntryRef::Value(value) | EntryRef::Expiring { value, .. } = entry {
+                return Ok(Some((key.to_vec(), value.to_vec())));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Iterator for SSTableIterator<'_> {
+    type Item = io::Result<(Key, Value)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let pair = self.next_pair().transpose();
+        self.done = !matches!(pair, Some(Ok(_)));
+        pair
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::{Entry, Version};
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn key(i: usize) -> Key {
+        format!("key{:06}", i).into_bytes()
+    }
+
+    #[test]
+    fn test_streams_a_large_table_from_a_small_buffer() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut table = SSTable::new(temp_dir.path().join("large.sst")).unwrap();
+        let data: Vec<_> = (0..8000).map(|i| (key(i), vec![i as u8; 512])).collect();
+        table.write(&data).unwrap();
+        assert!(table.size() > 4 * 1024 * 1024);
+
+        // A few pairs in, only their bytes have been decoded
+        let mut iter = table.iter().unwrap();
+        for expected in &data[..10] {
+            assert_eq!(&iter.next().unwrap().unwrap(), expected);
+        }
+        assert!(iter.reader.bytes_read() < 16 * 1024);
+        assert_eq!(iter.count(), data.len() - 10);
+    }
+
+    #[test]
+    fn test_skips_shadowed_versions_and_tombstones() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut table = SSTable::new(temp_dir.path().join("versions.sst")).unwrap();
+        let version = |seq, entry| Version::new(seq, entry);
+        table
+            .write_entries(&[
+                (b"a".to_vec(), version(3, Entry::Value(b"new".to_vec()))),
+                (b"a".to_vec(), version(1, Entry::Value(b"old".to_vec()))),
+                (
+                    b"b".to_vec(),
+                    version(4, Entry::Tombstone { deleted_at: 0 }),
+                ),
+                (b"b".to_vec(), version(2, Entry::Value(b"gone".to_vec()))),
+                (b"c".to_vec(), version(5, Entry::Merge(b"+1".to_vec()))),
+                (b"d".to_vec(), version(6, Entry::Value(b"d".to_vec()))),
+            ])
+            .unwrap();
+
+        let pairs: Vec<_> = table.iter().unwrap().map(Result::unwrap).collect();
+        assert_eq!(
+            pairs,
+            vec![
+                (b"a".to_vec(), b"new".to_vec()),
+                (b"d".to_vec(), b"d".to_vec()),
+            ]
+        );
+        assert_eq!(pairs, table.read().unwrap());
+    }
+
+    #[test]
+    fn test_seek() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut table = SSTable::new(temp_dir.path().join("seek.sst")).unwrap();
+        let data: Vec<_> = (0..2000)
+            .step_by(2)
+            .map(|i| (key(i), vec![1; 64]))
+            .collect();
+        table.write(&data).unwrap();
+
+        let mut iter = table.iter().unwrap();
+        iter.seek(&key(1500)).unwrap();
+        assert_eq!(iter.next().unwrap().unwrap().0, key(1500));
+        // Between keys, and back towards the front
+        iter.seek(&key(1001)).unwrap();
+        assert_eq!(iter.next().unwrap().unwrap().0, key(1002));
+        // The index starts reading near the key rather than at the front
+        assert!(iter.reader.bytes_read() < table.size() as u64 / 4);
+        iter.seek(b"a").unwrap();
+        assert_eq!(iter.next().unwrap().unwrap().0, key(0));
+        iter.seek(b"z").unwrap();
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_damaged_entries_end_the_iterator_with_an_error() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("damaged.sst");
+        let mut table = SSTable::new(path.clone()).unwrap();
+        let data: Vec<_> = (0..100).map(|i| (key(i), vec![1; 16])).collect();
+        table.write(&data).unwrap();
+
+        // Overwrite an entry kind in the middle of the data section
+        let mut bytes = fs::read(&path).unwrap();
+        let data_end = table.index.as_ref().unwrap().data_end() as usize;
+        let entry = bytes[..data_end]
+            .windows(9)
+            .position(|window| window == key(50).as_slice())
+            .unwrap();
+        // Key length, key, sequence number, then the kind
+        bytes[entry + 9 + 8] = 0xee;
+        fs::write(&path, &bytes).unwrap();
+
+        let table = SSTable::new(path).unwrap();
+        let results: Vec<_> = table.iter().unwrap().collect();
+        assert_eq!(results.len(), 51);
+        assert!(results[..50].iter().all(Result::is_ok));
+        let err = results[50].as_ref().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
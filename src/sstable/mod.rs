@@ -1,131 +1,836 @@
-use crate::bloom::BloomFilter;
+use crate::bloom::{BloomConfig, BloomFilter, BloomHashing};
+use crate::entry::{Entry, EntryRef, Version};
+use crate::error::{self, StorageError};
+#[cfg(feature = "object-store")]
+use crate::objectfs::{self, ObjectFs, RemoteFile};
+use crate::storage::sync_dir;
 use crate::{Key, Value};
+use std::cmp::Ordering;
+#[cfg(all(feature = "iouring", target_os = "linux"))]
+use std::collections::HashMap;
 use std::fs::{self, File};
-use std::io::{self, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
-
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering as AtomicOrdering};
+use std::sync::Arc;
+
+mod advise;
+mod blocks;
+mod cache;
 mod compaction;
-pub use compaction::CompactionManager;
+mod compression;
+mod describe;
+mod direct;
+mod index;
+mod iter;
+mod properties;
+mod reader;
+mod reverse;
+#[cfg(all(feature = "iouring", target_os = "linux"))]
+mod uring;
+pub use advise::advise_calls;
+pub use cache::BlockCache;
+pub(crate) use compaction::{CompactionManager, GcPolicy, L0_COMPACTION_FILES};
+pub use compression::Compression;
+pub use describe::DescribeOptions;
+pub use iter::SSTableIterator;
+pub use properties::TableProperties;
+pub use reader::EntryReader;
+pub use reverse::ReverseReader;
+#[cfg(all(feature = "iouring", target_os = "linux"))]
+pub use uring::Ring;
+
+use advise::{advise, Advice};
+use blocks::BlockWriter;
+use direct::{DirectReader, DirectWriter};
+use index::SparseIndex;
+
+/// Opens every table written since tables carried a header
+pub const TABLE_MAGIC: &[u8; 8] = b"LSMTABLE";
+/// Table layout this build writes, and the newest it reads
+pub const TABLE_FORMAT_VERSION: u16 = 3;
+// Version 1 added the header; version 2 switched bloom filters to double
+// hashing, so older filters are read with the hashing they were built with;
+// version 3 records the hashing in the filter itself
+// [magic][version u16]
+pub(crate) const HEADER_SIZE: usize = TABLE_MAGIC.len() + 2;
+// Written in place of the filter block's length by tables without a filter
+const NO_FILTER: u32 = u32::MAX;
+
+// Entry kind markers in the data section
+const ENTRY_VALUE: u8 = 0;
+const ENTRY_TOMBSTONE: u8 = 1;
+const ENTRY_EXPIRING: u8 = 2;
+const ENTRY_MERGE: u8 = 3;
+
+/// Outcome of resolving a key against a single table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lookup {
+    /// A live value was found
+    Found,
+    /// A value was found that reads as deleted from the given time on
+    Expiring(u64),
+    /// A merge operand was found, which needs older versions to resolve
+    Merge,
+    /// The newest version of the key is a tombstone
+    Deleted,
+    /// The table holds no version of the key
+    Missing,
+}
 
-const BLOOM_FALSE_POSITIVE_RATE: f64 = 0.01;
-const EXPECTED_ENTRIES_PER_SSTABLE: usize = 1000;
+/// How whole-table reads and writes reach the disk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IoMode {
+    /// Through the page cache
+    #[default]
+    Buffered,
+    /// With O_DIRECT where supported, so one-off bulk I/O such as flushes
+    /// and compactions doesn't evict cached data. Point reads always stay
+    /// buffered.
+    Direct,
+}
+
+/// Where point lookups read table data from
+#[derive(Clone, Default)]
+pub enum ReadPath {
+    /// Buffered reads through the standard library
+    #[default]
+    Std,
+    /// Positional reads submitted through a shared io_uring
+    #[cfg(all(feature = "iouring", target_os = "linux"))]
+    Uring(Arc<Ring>),
+}
 
 pub struct SSTable {
     path: PathBuf,
     size: usize,
     bloom_filter: Option<BloomFilter>,
+    // Filter settings for the next write; `None` writes no filter
+    bloom_config: Option<BloomConfig>,
+    properties: TableProperties,
+    // Sparse index over the data section; tables written before it
+    // existed have none and are scanned from the front
+    index: Option<SparseIndex>,
+    // Set once the table has been replaced; its file is removed on drop
+    obsolete: AtomicBool,
+    // Times a reader was opened over the data section
+    passes: AtomicU64,
+    // Data section bytes read by point lookups
+    lookup_bytes: AtomicU64,
+    // Where point lookups keep the blocks they read
+    cache: Option<Arc<BlockCache>>,
+    // Codec for the next write; reads follow the table's properties
+    compression: Compression,
+    // Version from the header; `None` for a table written before headers
+    format_version: Option<u16>,
+    // Numbers of the tables the next write replaces, for its properties
+    replaces: Vec<u64>,
+    // Where the table is read from instead of its path, once uploaded
+    #[cfg(feature = "object-store")]
+    objects: Option<Arc<ObjectFs>>,
+}
+
+/// A table's bytes: its local file, or its object in a store
+enum TableFile {
+    Local(File),
+    #[cfg(feature = "object-store")]
+    Remote(RemoteFile),
+}
+
+impl Read for TableFile {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        match self {
+            TableFile::Local(file) => file.read(out),
+            #[cfg(feature = "object-store")]
+            TableFile::Remote(file) => file.read(out),
+        }
+    }
+}
+
+impl Seek for TableFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            TableFile::Local(file) => file.seek(pos),
+            #[cfg(feature = "object-store")]
+            TableFile::Remote(file) => file.seek(pos),
+        }
+    }
 }
 
 impl SSTable {
-    pub fn new(path: PathBuf) -> io::Result<Self> {
-        let size = if path.exists() {
-            fs::metadata(&path)?.len() as usize
-        } else {
-            0
+    /// Open the table at `path`, or prepare to write one there if it
+    /// doesn't exist. A file without the table header is refused, as is
+    /// one written in a newer format than this build reads.
+    pub fn new(path: PathBuf) -> Result<Self, StorageError> {
+        Ok(Self::open(path, false)?)
+    }
+
+    /// [`new`](SSTable::new), also accepting a table written before tables
+    /// carried a header, for reading it or rewriting it in the current
+    /// format
+    pub fn open_legacy(path: PathBuf) -> Result<Self, StorageError> {
+        Ok(Self::open(path, true)?)
+    }
+
+    fn open(path: PathBuf, legacy: bool) -> io::Result<Self> {
+        let mut table = Self::unloaded(path);
+        if table.path.exists() {
+            let file = File::open(&table.path)?;
+            table.load(TableFile::Local(file), legacy)?;
+        }
+        Ok(table)
+    }
+
+    /// Open the table at `path` from its object in `objects`, where
+    /// [`upload`](SSTable::upload) put it, rather than from local disk
+    #[cfg(feature = "object-store")]
+    pub(crate) fn open_object(path: PathBuf, objects: Arc<ObjectFs>) -> io::Result<Self> {
+        let file = objects.open(objectfs::object_name(&path)?)?;
+        let mut table = Self::unloaded(path);
+        table.objects = Some(objects);
+        table.load(TableFile::Remote(file), false)?;
+        Ok(table)
+    }
+
+    /// Move the finished table into `objects`, deleting the local file, and
+    /// read it from there on
+    #[cfg(feature = "object-store")]
+    pub(crate) fn upload(self, objects: &Arc<ObjectFs>) -> io::Result<Self> {
+        objects.upload(&self.path)?;
+        Self::open_object(self.path.clone(), objects.clone())
+    }
+
+    /// A table at `path` with nothing read or written yet
+    fn unloaded(path: PathBuf) -> Self {
+        SSTable {
+            path,
+            size: 0,
+            bloom_filter: None,
+            bloom_config: Some(BloomConfig::default()),
+            properties: TableProperties::new(),
+            index: None,
+            obsolete: AtomicBool::new(false),
+            passes: AtomicU64::new(0),
+            lookup_bytes: AtomicU64::new(0),
+            cache: None,
+            compression: Compression::None,
+            format_version: Some(TABLE_FORMAT_VERSION),
+            replaces: Vec::new(),
+            #[cfg(feature = "object-store")]
+            objects: None,
+        }
+    }
+
+    /// Read the header, metadata and index of an existing table out of
+    /// `file`. Metadata or an index that can't be read is left out.
+    fn load(&mut self, mut file: TableFile, legacy: bool) -> io::Result<()> {
+        self.size = file.seek(SeekFrom::End(0))? as usize;
+        let version = Self::read_header(&mut file, &self.path)?;
+        if version.is_none() && !legacy {
+            return Err(error::corruption(
+                &self.path,
+                0,
+                "missing table header; tables written before format versions \
+                 can be rewritten with Storage::migrate_format",
+            ));
+        }
+        self.format_version = version;
+        (self.bloom_filter, self.properties) = Self::read_metadata(&mut file, &self.path)
+            .unwrap_or_else(|_| (None, TableProperties::new()));
+        self.index = SparseIndex::read(&mut file).unwrap_or(None);
+        Ok(())
+    }
+
+    /// The table's bytes, wherever they're kept
+    fn open_file(&self) -> io::Result<TableFile> {
+        #[cfg(feature = "object-store")]
+        if let Some(objects) = &self.objects {
+            return objects
+                .open(objectfs::object_name(&self.path)?)
+                .map(TableFile::Remote);
+        }
+        File::open(&self.path).map(TableFile::Local)
+    }
+
+    /// Format version from the table's header, or `None` for a table
+    /// written before headers, opened with
+    /// [`open_legacy`](SSTable::open_legacy)
+    pub fn format_version(&self) -> Option<u16> {
+        self.format_version
+    }
+
+    /// Pairs of a table in the first release's layout, which has no
+    /// header, properties or sequence numbers: a filter block followed by
+    /// `[key len u32][key][value len u32][value]` to the end of the file.
+    /// `None` if the file at `path` isn't laid out that way, ascending keys
+    /// included.
+    pub fn read_first_format(path: &Path) -> Result<Option<Vec<(Key, Value)>>, StorageError> {
+        let bytes = fs::read(path)?;
+        let read_u32 = |pos: usize| {
+            bytes
+                .get(pos..pos + 4)
+                .map(|b| u32::from_le_bytes(b.try_into().unwrap()) as usize)
+        };
+        // The filter is [bits u32][hashes u32][bits, 8 to a byte]
+        let (Some(filter_len), Some(bits)) = (read_u32(0), read_u32(4)) else {
+            return Ok(None);
         };
+        if filter_len != 8 + bits.div_ceil(8) || filter_len > bytes.len() - 4 {
+            return Ok(None);
+        }
 
-        let bloom_filter = if path.exists() {
-            // Try to load bloom filter from file
-            Self::read_bloom_filter(&path).ok()
-        } else {
-            None
+        let mut pos = 4 + filter_len;
+        let mut pairs: Vec<(Key, Value)> = Vec::new();
+        let read_field = |pos: &mut usize| {
+            let len = read_u32(*pos)?;
+            let field = bytes.get(*pos + 4..*pos + 4 + len)?;
+            *pos += 4 + len;
+            Some(field.to_vec())
         };
+        while pos < bytes.len() {
+            let (Some(key), Some(value)) = (read_field(&mut pos), read_field(&mut pos)) else {
+                return Ok(None);
+            };
+            if pairs.last().is_some_and(|(last, _)| *last >= key) {
+                return Ok(None);
+            }
+            pairs.push((key, value));
+        }
+        Ok(Some(pairs))
+    }
 
-        Ok(SSTable {
-            path,
-            size,
-            bloom_filter,
-        })
+    /// Use `config` for the filter of tables written from now on, or write
+    /// none at all
+    pub fn with_bloom(mut self, config: Option<BloomConfig>) -> Self {
+        self.bloom_config = config;
+        self
     }
 
-    pub fn write(&mut self, data: &[(Key, Value)]) -> io::Result<()> {
-        let mut file = File::create(&self.path)?;
-        let mut size = 0;
+    /// Serve point lookups from `cache` where it holds their block, and
+    /// keep the blocks they read from disk there
+    pub fn with_cache(mut self, cache: Option<Arc<BlockCache>>) -> Self {
+        self.cache = cache;
+        self
+    }
 
-        // Create a new bloom filter for this SSTable
-        let mut bloom = BloomFilter::new(
-            data.len().max(EXPECTED_ENTRIES_PER_SSTABLE),
-            BLOOM_FALSE_POSITIVE_RATE,
-        );
+    /// Compress the data blocks of tables written from now on with
+    /// `compression`
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Record in the properties of tables written from now on that they
+    /// replace the tables numbered `numbers`
+    pub fn with_replaces(mut self, numbers: Vec<u64>) -> Self {
+        self.replaces = numbers;
+        self
+    }
+
+    /// Write plain values, all stamped with sequence number zero
+    pub fn write(&mut self, data: &[(Key, Value)]) -> Result<(), StorageError> {
+        let mut file = File::create(self.tmp_path())?;
+        self.write_records(
+            &mut file,
+            data.iter()
+                .map(|(key, value)| (key.as_slice(), 0, EntryRef::Value(value))),
+            data.len(),
+        )?;
+        Ok(self.commit(file)?)
+    }
+
+    /// Write versions, including tombstones. Multiple versions of a key must
+    /// be adjacent and ordered newest first.
+    pub fn write_entries(&mut self, data: &[(Key, Version)]) -> Result<(), StorageError> {
+        self.write_entries_with(data, IoMode::Buffered)
+    }
+
+    /// [`write_entries`](SSTable::write_entries) using the given I/O mode
+    pub fn write_entries_with(
+        &mut self,
+        data: &[(Key, Version)],
+        mode: IoMode,
+    ) -> Result<(), StorageError> {
+        let records = data
+            .iter()
+            .map(|(key, version)| (key.as_slice(), version.seq, version.entry.as_entry_ref()));
+        let file = match mode {
+            IoMode::Buffered => {
+                let mut file = File::create(self.tmp_path())?;
+                self.write_records(&mut file, records, data.len())?;
+                file
+            }
+            IoMode::Direct => {
+                let mut writer = DirectWriter::create(&self.tmp_path())?;
+                self.write_records(&mut writer, records, data.len())?;
+                writer.finish()?
+            }
+        };
+        Ok(self.commit(file)?)
+    }
+
+    /// Where the table is written before being renamed into place, so a
+    /// crash never leaves a partial file under the table's own name
+    fn tmp_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".tmp");
+        PathBuf::from(path)
+    }
 
-        // Add all keys to the bloom filter
-        for (key, _) in data {
-            bloom.insert(key.as_slice());
+    /// Durably move the finished file at [`tmp_path`](SSTable::tmp_path)
+    /// to the table's path
+    fn commit(&self, file: File) -> io::Result<()> {
+        file.sync_all()?;
+        drop(file);
+        fs::rename(self.tmp_path(), &self.path)?;
+        if let Some(cache) = &self.cache {
+            cache.invalidate(&self.path);
+        }
+        match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => sync_dir(dir),
+            _ => sync_dir(Path::new(".")),
         }
+    }
 
-        // Write bloom filter to the start of the file
-        let bloom_bytes = bloom.to_bytes();
-        file.write_all(&(bloom_bytes.len() as u32).to_le_bytes())?;
-        file.write_all(&bloom_bytes)?;
-        size += bloom_bytes.len() + 4; // 4 bytes for size
+    /// Write versions as they arrive from `records`, in key order, without
+    /// holding them in memory. The data section goes to a scratch file
+    /// while the bloom filter and properties are built, then follows them
+    /// into the table. `expected` sizes the bloom filter and may be an
+    /// overestimate. Nothing is left behind if writing fails.
+    pub fn write_stream_with<I>(
+        &mut self,
+        records: I,
+        expected: usize,
+        mode: IoMode,
+    ) -> Result<(), StorageError>
+    where
+        I: IntoIterator<Item = io::Result<(Key, Version)>>,
+    {
+        let data_path = self.path.with_extension("data");
+        let result = self.write_stream_via(&data_path, records, expected, mode);
+        let _ = fs::remove_file(&data_path);
+        if result.is_err() {
+            let _ = fs::remove_file(self.tmp_path());
+        }
+        Ok(result?)
+    }
 
-        // Write format: [key_size][key][value_size][value]
-        for (key, value) in data {
-            // Write key size and key
-            file.write_all(&(key.len() as u32).to_le_bytes())?;
-            file.write_all(key)?;
+    fn write_stream_via<I>(
+        &mut self,
+        data_path: &PathBuf,
+        records: I,
+        expected: usize,
+        mode: IoMode,
+    ) -> io::Result<()>
+    where
+        I: IntoIterator<Item = io::Result<(Key, Version)>>,
+    {
+        let mut bloom = self.new_bloom(expected);
+        let mut properties = TableProperties::new();
+        properties.compression = self.compression;
+        properties.replaces = self.replaces.clone();
+        let data = BufWriter::new(File::create(data_path)?);
+        let mut blocks = BlockWriter::new(data, self.compression);
+        for record in records {
+            let (key, version) = record?;
+            let entry = version.entry.as_entry_ref();
+            if let Some(bloom) = &mut bloom {
+                bloom.insert(&key);
+            }
+            properties.record(&key, version.seq, entry);
+            blocks.write(&key, version.seq, entry)?;
+        }
+        let (mut data, index) = blocks.finish()?;
+        data.flush()?;
+        drop(data);
+
+        let mut data = File::open(data_path)?;
+        let (index, index_bytes, file) = match mode {
+            IoMode::Buffered => {
+                let mut file = File::create(self.tmp_path())?;
+                let index = index.finish(write_metadata(&mut file, &bloom, &properties)? as u64);
+                io::copy(&mut data, &mut file)?;
+                let index_bytes = write_index(&mut file, &index)?;
+                (index, index_bytes, file)
+            }
+            IoMode::Direct => {
+                let mut writer = DirectWriter::create(&self.tmp_path())?;
+                let index = index.finish(write_metadata(&mut writer, &bloom, &properties)? as u64);
+                io::copy(&mut data, &mut writer)?;
+                let index_bytes = write_index(&mut writer, &index)?;
+                (index, index_bytes, writer.finish()?)
+            }
+        };
+        self.commit(file)?;
 
-            // Write value size and value
-            file.write_all(&(value.len() as u32).to_le_bytes())?;
-            file.write_all(value)?;
+        self.size = index.data_end() as usize + index_bytes;
+        self.bloom_filter = bloom;
+        self.properties = properties;
+        self.index = Some(index);
+        self.format_version = Some(TABLE_FORMAT_VERSION);
+        Ok(())
+    }
 
-            size += key.len() + value.len() + 8; // 8 bytes for sizes
+    fn write_records<'a, W, I>(&mut self, file: &mut W, records: I, count: usize) -> io::Result<()>
+    where
+        W: Write,
+        I: Iterator<Item = (&'a [u8], u64, EntryRef<'a>)> + Clone,
+    {
+        // Create a new bloom filter for this SSTable, unless disabled
+        let mut bloom = self.new_bloom(count);
+
+        // Add all keys to the bloom filter and collect size statistics
+        let mut properties = TableProperties::new();
+        properties.compression = self.compression;
+        properties.replaces = self.replaces.clone();
+        for (key, seq, entry) in records.clone() {
+            if let Some(bloom) = &mut bloom {
+                bloom.insert(key);
+            }
+            properties.record(key, seq, entry);
         }
 
-        self.size = size;
-        self.bloom_filter = Some(bloom);
+        let start = write_metadata(file, &bloom, &properties)?;
+        let mut blocks = BlockWriter::new(&mut *file, self.compression);
+        for (key, seq, entry) in records {
+            blocks.write(key, seq, entry)?;
+        }
+        let (_, index) = blocks.finish()?;
+        let index = index.finish(start as u64);
+
+        self.size = index.data_end() as usize + write_index(file, &index)?;
+        self.bloom_filter = bloom;
+        self.properties = properties;
+        self.index = Some(index);
+        self.format_version = Some(TABLE_FORMAT_VERSION);
         Ok(())
     }
 
-    fn read_bloom_filter(path: &PathBuf) -> io::Result<BloomFilter> {
-        let mut file = File::open(path)?;
+    /// A filter sized for `count` keys, so small tables get small filters
+    fn new_bloom(&self, count: usize) -> Option<BloomFilter> {
+        self.bloom_config
+            .map(|config| BloomFilter::new(count.max(1), config.false_positive_rate))
+    }
 
-        // Read bloom filter size
-        let mut size_bytes = [0u8; 4];
-        file.read_exact(&mut size_bytes)?;
-        let bloom_size = u32::from_le_bytes(size_bytes) as usize;
+    /// Read the header at the front of `file`, the table at `path`,
+    /// leaving it positioned after the header, or at the front for a table
+    /// written before headers, which yields `None`. Versions newer than
+    /// this build reads are refused.
+    fn read_header<R: Read + Seek>(file: &mut R, path: &Path) -> io::Result<Option<u16>> {
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        file.seek(SeekFrom::Start(0))?;
+        Read::by_ref(file)
+            .take(HEADER_SIZE as u64)
+            .read_to_end(&mut header)?;
+        if !header.starts_with(TABLE_MAGIC) {
+            if TABLE_MAGIC.starts_with(&header) {
+                return Err(error::corruption(path, 0, "table header cut short"));
+            }
+            // Older tables start with the filter block's length instead
+            file.seek(SeekFrom::Start(0))?;
+            return Ok(None);
+        }
+        let version = match header[TABLE_MAGIC.len()..].try_into() {
+            Ok(version) => u16::from_le_bytes(version),
+            Err(_) => return Err(error::corruption(path, 0, "table header cut short")),
+        };
+        if version > TABLE_FORMAT_VERSION {
+            return Err(StorageError::UnsupportedVersion {
+                file: path.to_path_buf(),
+                version: version.into(),
+                supported: TABLE_FORMAT_VERSION.into(),
+            }
+            .into());
+        }
+        Ok(Some(version))
+    }
 
-        // Read bloom filter data
-        let mut bloom_bytes = vec![0u8; bloom_size];
-        file.read_exact(&mut bloom_bytes)?;
+    fn read_metadata<R: Read + Seek>(
+        file: &mut R,
+        path: &Path,
+    ) -> io::Result<(Option<BloomFilter>, TableProperties)> {
+        let damaged = |offset| move |e| error::locate(e, path, offset);
+
+        let version = Self::read_header(file, path)?;
+        let offset = file.stream_position()?;
+        let bloom = Self::read_block(file)
+            .map_err(damaged(offset))?
+            .map(|bytes| decode_bloom(&bytes, version))
+            .transpose()
+            .map_err(damaged(offset + 4))?
+            .flatten();
+        let offset = file.stream_position()?;
+        let properties_bytes = Self::read_block(file)
+            .map_err(damaged(offset))?
+            .ok_or_else(|| error::corruption(path, offset, "missing properties block"))?;
+        let properties =
+            TableProperties::from_bytes(&properties_bytes).map_err(damaged(offset + 4))?;
+
+        Ok((bloom, properties))
+    }
 
-        BloomFilter::from_bytes(&bloom_bytes)
+    /// Read a length-prefixed metadata block, `None` if marked absent
+    fn read_block<R: Read + Seek>(file: &mut R) -> io::Result<Option<Vec<u8>>> {
+        let Some(block_size) = Self::block_size(file)? else {
+            return Ok(None);
+        };
+        let mut block = vec![0u8; block_size as usize];
+        file.read_exact(&mut block)?;
+        Ok(Some(block))
     }
 
-    pub fn read(&self) -> io::Result<Vec<(Key, Value)>> {
-        let mut file = File::open(&self.path)?;
-        let mut data = Vec::new();
+    /// Position the file at the start of the data section, past the
+    /// header, bloom filter and properties blocks
+    fn skip_metadata<R: Read + Seek>(file: &mut R, path: &Path) -> io::Result<()> {
+        Self::read_header(file, path)?;
+        for _ in 0..2 {
+            if let Some(block_size) = Self::block_size(file)? {
+                file.seek(SeekFrom::Current(block_size as i64))?;
+            }
+        }
+        Ok(())
+    }
 
-        // Skip the bloom filter
+    /// Read a metadata block's length prefix, `None` if marked absent,
+    /// rejecting lengths that run past the end of the file
+    fn block_size<R: Read + Seek>(file: &mut R) -> io::Result<Option<u64>> {
+        let offset = file.stream_position()?;
         let mut size_bytes = [0u8; 4];
-        file.read_exact(&mut size_bytes)?;
-        let bloom_size = u32::from_le_bytes(size_bytes) as usize;
-        file.seek(SeekFrom::Current(bloom_size as i64))?;
+        file.read_exact(&mut size_bytes)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Metadata block length at offset {} is cut short", offset),
+                ),
+                _ => e,
+            })?;
+        let block_size = u32::from_le_bytes(size_bytes);
+        if block_size == NO_FILTER {
+            return Ok(None);
+        }
+        if block_size as u64 > stream_len(file)?.saturating_sub(offset + 4) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Metadata block length {} at offset {} exceeds file size",
+                    block_size, offset
+                ),
+            ));
+        }
+        Ok(Some(block_size as u64))
+    }
+
+    /// Live key/value pairs: the newest version of each key, skipping
+    /// deleted keys. Values with an expiry are included whatever the time;
+    /// keys whose newest version is a merge operand are left out.
+    pub fn read(&self) -> Result<Vec<(Key, Value)>, StorageError> {
+        Ok(self.iter()?.collect::<io::Result<_>>()?)
+    }
 
-        // Read the rest of the file
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)?;
+    /// Stream the pairs [`read`](SSTable::read) would collect, one entry
+    /// at a time
+    pub fn iter(&self) -> Result<SSTableIterator<'_>, StorageError> {
+        Ok(SSTableIterator::new(self)?)
+    }
 
-        let mut pos = 0;
-        while pos < buffer.len() {
-            // Read key
-            let key_size = u32::from_le_bytes(buffer[pos..pos + 4].try_into().unwrap()) as usize;
-            pos += 4;
-            let key = buffer[pos..pos + key_size].to_vec();
-            pos += key_size;
+    /// Every stored version, including tombstones and shadowed values
+    pub fn read_entries(&self) -> Result<Vec<(Key, Version)>, StorageError> {
+        self.read_entries_with(IoMode::Buffered)
+    }
 
-            // Read value
-            let value_size = u32::from_le_bytes(buffer[pos..pos + 4].try_into().unwrap()) as usize;
-            pos += 4;
-            let value = buffer[pos..pos + value_size].to_vec();
-            pos += value_size;
+    /// [`read_entries`](SSTable::read_entries) using the given I/O mode
+    pub fn read_entries_with(&self, mode: IoMode) -> Result<Vec<(Key, Version)>, StorageError> {
+        let mut reader = self.entries_with(mode)?;
+        let mut data = Vec::new();
 
-            data.push((key, value));
+        while let Some((key, seq, entry)) = reader.next_entry()? {
+            data.push((key.to_vec(), Version::new(seq, entry.to_entry())));
         }
 
         Ok(data)
     }
 
+    /// Open a streaming reader over the data section
+    pub fn entries(&self) -> Result<EntryReader, StorageError> {
+        self.entries_with(IoMode::Buffered)
+    }
+
+    /// A streaming reader that starts at the index point at or before
+    /// `key`, or at the front if there is none, so the keys before `key`
+    /// it yields are at most one block's worth
+    pub fn entries_from(&self, key: &[u8]) -> Result<EntryReader, StorageError> {
+        let block = match &self.index {
+            Some(index) => index.seek_block(key).map(|(start, _)| start),
+            None => None,
+        };
+        Ok(self.entries_on(&ReadPath::Std, block)?)
+    }
+
+    /// A reader for point lookups through `path`, starting at the index
+    /// point at or before `key`. `None` means the key sorts before every
+    /// key in the table.
+    fn entries_near(&self, key: &[u8], path: &ReadPath) -> io::Result<Option<EntryReader>> {
+        let block = match &self.index {
+            Some(index) => match index.seek_block(key) {
+                Some(block) => Some(block),
+                None => return Ok(None),
+            },
+            None => None,
+        };
+        match (block, &self.cache) {
+            (Some((start, end)), Some(cache)) => self.entries_cached(cache, path, start, end),
+            (block, _) => self.entries_on(path, block.map(|(start, _)| start)),
+        }
+        .map(Some)
+    }
+
+    /// A reader over the data block at `[start, end)`, taken from `cache`
+    /// or else read through `path` and added to it
+    fn entries_cached(
+        &self,
+        cache: &BlockCache,
+        path: &ReadPath,
+        start: u64,
+        end: u64,
+    ) -> io::Result<EntryReader> {
+        let block = match cache.get(&self.path, start) {
+            Some(block) => block,
+            None => {
+                let block = self.load_block(path, start, end)?;
+                cache.insert(&self.path, start, block.clone());
+                block
+            }
+        };
+        Ok(EntryReader::cached(block, start).in_file(self.path.clone()))
+    }
+
+    /// The data block at `[start, end)` read through `path`, decompressed
+    /// if the table was written with a codec
+    fn load_block(&self, path: &ReadPath, start: u64, end: u64) -> io::Result<Arc<[u8]>> {
+        let stored = self.open_data(path, Some(start))?.read_to(end)?;
+        self.restore_block(stored, start)
+    }
+
+    /// A data block at `start` as stored, decompressed if the table was
+    /// written with a codec
+    fn restore_block(&self, stored: Vec<u8>, start: u64) -> io::Result<Arc<[u8]>> {
+        Ok(match self.properties.compression {
+            Compression::None => stored.into(),
+            codec => codec
+                .decompress_frame(&stored, self.properties.data_size)
+                .map_err(|e| error::locate(e, &self.path, start))?
+                .into(),
+        })
+    }
+
+    /// A reader over the data section through `path`, from file offset
+    /// `start` or else from the front
+    fn entries_on(&self, path: &ReadPath, start: Option<u64>) -> io::Result<EntryReader> {
+        Ok(self.decoded(self.open_data(path, start)?))
+    }
+
+    /// Entries out of `reader`'s data section as stored on disk,
+    /// decompressing its blocks if the table was written with a codec
+    fn decoded(&self, reader: EntryReader) -> EntryReader {
+        match self.properties.compression {
+            Compression::None => reader,
+            codec => reader.decompressed(codec, self.properties.data_size),
+        }
+    }
+
+    /// A reader over the bytes of the data section as stored, from file
+    /// offset `start` or else from the front
+    fn open_data(&self, path: &ReadPath, start: Option<u64>) -> io::Result<EntryReader> {
+        self.passes.fetch_add(1, AtomicOrdering::Relaxed);
+        let mut file = self.open_file()?;
+        let start = match start {
+            Some(offset) => file.seek(SeekFrom::Start(offset))?,
+            None => {
+                Self::skip_metadata(&mut file, &self.path)
+                    .map_err(|e| error::locate(e, &self.path, 0))?;
+                file.stream_position()?
+            }
+        };
+        let end = self.data_end(&mut file)?;
+
+        let reader = match (path, file) {
+            // Objects are read through their chunk cache whatever the path
+            #[cfg(feature = "object-store")]
+            (_, TableFile::Remote(file)) => EntryReader::remote(file, start, end),
+            (ReadPath::Std, TableFile::Local(file)) => EntryReader::new(file, start, end),
+            #[cfg(all(feature = "iouring", target_os = "linux"))]
+            (ReadPath::Uring(ring), TableFile::Local(file)) => {
+                let reader = uring::UringReader::new(ring.clone(), file, start);
+                EntryReader::uring(reader, start, end)
+            }
+        };
+        Ok(reader.in_file(self.path.clone()))
+    }
+
+    /// [`entries`](SSTable::entries) using the given I/O mode
+    pub fn entries_with(&self, mode: IoMode) -> Result<EntryReader, StorageError> {
+        match mode {
+            IoMode::Buffered => Ok(self.entries_on(&ReadPath::Std, None)?),
+            // Only local files can bypass the page cache
+            #[cfg(feature = "object-store")]
+            IoMode::Direct if self.objects.is_some() => Ok(self.entries_on(&ReadPath::Std, None)?),
+            IoMode::Direct => {
+                self.passes.fetch_add(1, AtomicOrdering::Relaxed);
+                let mut file = File::open(&self.path)?;
+                Self::skip_metadata(&mut file, &self.path)
+                    .map_err(|e| error::locate(e, &self.path, 0))?;
+                let start = file.stream_position()?;
+                let end = self.data_end(&mut file)?;
+                let reader = DirectReader::open(&self.path, start)?;
+                let reader = EntryReader::direct(reader, start, end).in_file(self.path.clone());
+                Ok(self.decoded(reader))
+            }
+        }
+    }
+
+    /// Offset where the data section ends: the start of the index, or the
+    /// end of the file for tables without one. A file cut short ends early.
+    fn data_end<R: Seek>(&self, file: &mut R) -> io::Result<u64> {
+        let len = stream_len(file)?;
+        Ok(self
+            .index
+            .as_ref()
+            .map_or(len, |index| index.data_end().min(len)))
+    }
+
+    /// Flush the table to disk and drop its pages from the page cache, for
+    /// output that won't be read again soon
+    pub fn release_cache(&self) -> Result<(), StorageError> {
+        #[cfg(feature = "object-store")]
+        if self.objects.is_some() {
+            return Ok(());
+        }
+        let file = File::open(&self.path)?;
+        file.sync_data()?;
+        advise(&file, 0, 0, Advice::DontNeed);
+        Ok(())
+    }
+
+    /// Whether the table carries a bloom filter
+    pub fn has_bloom_filter(&self) -> bool {
+        self.bloom_filter.is_some()
+    }
+
+    /// Memory held by the table's bloom filter
+    pub fn bloom_bytes(&self) -> usize {
+        self.bloom_filter
+            .as_ref()
+            .map_or(0, BloomFilter::memory_bytes)
+    }
+
+    /// Whether `key` falls within the table's smallest and largest key.
+    /// Tables without a recorded range, such as those written before
+    /// properties existed, are assumed to cover every key.
+    pub fn covers_key(&self, key: &[u8]) -> bool {
+        self.key_range()
+            .is_none_or(|(smallest, largest)| smallest <= key && key <= largest)
+    }
+
     pub fn might_contain_key(&self, key: &[u8]) -> bool {
         if let Some(filter) = &self.bloom_filter {
             filter.might_contain(key)
@@ -135,50 +840,354 @@ impl SSTable {
         }
     }
 
-    pub fn get(&self, key: &[u8]) -> io::Result<Option<Value>> {
-        // First check the bloom filter
-        if let Some(filter) = &self.bloom_filter {
-            if !filter.might_contain(key) {
-                // Definitely not in this SSTable
-                return Ok(None);
+    pub fn get(&self, key: &[u8]) -> Result<Option<Value>, StorageError> {
+        let mut value = Vec::new();
+        Ok(self.get_into(key, &mut value)?.then_some(value))
+    }
+
+    /// Look up `key`, copying its value into `out` and reusing its allocation.
+    /// Returns whether a value was found, expired or not; `out` is
+    /// unspecified when it wasn't.
+    pub fn get_into(&self, key: &[u8], out: &mut Vec<u8>) -> Result<bool, StorageError> {
+        Ok(matches!(
+            self.lookup_into(key, u64::MAX, out)?,
+            Lookup::Found | Lookup::Expiring(_)
+        ))
+    }
+
+    /// Resolve `key` as of sequence number `max_seq`, distinguishing a
+    /// tombstone from a key the table knows nothing about
+    pub fn lookup_into(
+        &self,
+        key: &[u8],
+        max_seq: u64,
+        out: &mut Vec<u8>,
+    ) -> Result<Lookup, StorageError> {
+        self.lookup_into_with(key, max_seq, out, &ReadPath::Std)
+    }
+
+    /// [`lookup_into`](SSTable::lookup_into) reading through `path`
+    pub fn lookup_into_with(
+        &self,
+        key: &[u8],
+        max_seq: u64,
+        out: &mut Vec<u8>,
+        path: &ReadPath,
+    ) -> Result<Lookup, StorageError> {
+        // First check the key range and bloom filter
+        if !self.covers_key(key) || !self.might_contain_key(key) {
+            // Definitely not in this SSTable
+            return Ok(Lookup::Missing);
+        }
+
+        // Key might be present: seek to the nearest index point and only
+        // read the matching entry
+        let Some(mut reader) = self.entries_near(key, path)? else {
+            return Ok(Lookup::Missing);
+        };
+        let result = Self::seek_version(&mut reader, key, max_seq, out);
+        self.lookup_bytes
+            .fetch_add(reader.bytes_read(), AtomicOrdering::Relaxed);
+        Ok(result?)
+    }
+
+    fn seek_version(
+        reader: &mut EntryReader,
+        key: &[u8],
+        max_seq: u64,
+        out: &mut Vec<u8>,
+    ) -> io::Result<Lookup> {
+        while let Some((current_key, seq)) = reader.next_key()? {
+            match current_key.cmp(key) {
+                Ordering::Less => reader.skip_entry()?,
+                // Versions are newest first, so skip those written after max_seq
+                Ordering::Equal if seq > max_seq => reader.skip_entry()?,
+                Ordering::Equal => return reader.read_entry_into(out),
+                Ordering::Greater => break, // keys are sorted, so it isn't here
             }
         }
+        Ok(Lookup::Missing)
+    }
 
-        // Key might be present, search through file
-        let mut file = File::open(&self.path)?;
+    /// Resolve many keys in one forward pass over the data section. `keys`
+    /// must be sorted and free of duplicates. Each result is the newest
+    /// version of its key at or before `max_seq`, or `None` if the table
+    /// holds none.
+    pub fn multi_get(
+        &self,
+        keys: &[&[u8]],
+        max_seq: u64,
+    ) -> Result<Vec<Option<Entry>>, StorageError> {
+        self.multi_get_with(keys, max_seq, &ReadPath::Std)
+    }
 
-        // Skip bloom filter
-        let mut size_bytes = [0u8; 4];
-        file.read_exact(&mut size_bytes)?;
-        let bloom_size = u32::from_le_bytes(size_bytes) as usize;
-        file.seek(SeekFrom::Current(bloom_size as i64))?;
+    /// [`multi_get`](SSTable::multi_get) reading through `path`
+    pub fn multi_get_with(
+        &self,
+        keys: &[&[u8]],
+        max_seq: u64,
+        path: &ReadPath,
+    ) -> Result<Vec<Option<Entry>>, StorageError> {
+        let mut results = vec![None; keys.len()];
+        let wanted: Vec<usize> = (0..keys.len())
+            .filter(|&i| self.covers_key(keys[i]) && self.might_contain_key(keys[i]))
+            .collect();
+        if wanted.is_empty() {
+            return Ok(results);
+        }
+
+        #[cfg(all(feature = "iouring", target_os = "linux"))]
+        if let (ReadPath::Uring(ring), Some(index)) = (path, &self.index) {
+            // Objects are read through their chunk cache instead
+            #[allow(irrefutable_let_patterns)]
+            if let TableFile::Local(file) = self.open_file()? {
+                return Ok(self.multi_get_batched(ring, &file, index, keys, &wanted, max_seq)?);
+            }
+        }
+
+        // Seek past keys before the first wanted one
+        let start = self
+            .index
+            .as_ref()
+            .and_then(|index| index.seek(keys[wanted[0]]));
+        let mut reader = self.entries_on(path, start)?;
+        let result = Self::resolve_sorted(&mut reader, keys, &wanted, max_seq, &mut results);
+        self.lookup_bytes
+            .fetch_add(reader.bytes_read(), AtomicOrdering::Relaxed);
+        result?;
+        Ok(results)
+    }
+
+    /// [`multi_get_with`](SSTable::multi_get_with) for a local table with
+    /// an index, reading every block the wanted keys fall in with one
+    /// batch submitted to `ring` instead of a pass over the data section.
+    /// A key's versions never span blocks, so each block resolves its own
+    /// keys.
+    #[cfg(all(feature = "iouring", target_os = "linux"))]
+    fn multi_get_batched(
+        &self,
+        ring: &Ring,
+        file: &File,
+        index: &SparseIndex,
+        keys: &[&[u8]],
+        wanted: &[usize],
+        max_seq: u64,
+    ) -> io::Result<Vec<Option<Entry>>> {
+        self.passes.fetch_add(1, AtomicOrdering::Relaxed);
+        let mut results = vec![None; keys.len()];
+        // Keys sorting before every block aren't in the table
+        let located: Vec<(usize, (u64, u64))> = wanted
+            .iter()
+            .filter_map(|&i| index.seek_block(keys[i]).map(|block| (i, block)))
+            .collect();
+
+        let mut blocks: HashMap<u64, Arc<[u8]>> = HashMap::new();
+        let mut missing: Vec<(u64, u64)> = Vec::new();
+        for &(_, (start, end)) in &located {
+            if blocks.contains_key(&start) || missing.last() == Some(&(start, end)) {
+                continue;
+            }
+            match self
+                .cache
+                .as_ref()
+                .and_then(|cache| cache.get(&self.path, start))
+            {
+                Some(block) => {
+                    blocks.insert(start, block);
+                }
+                None => missing.push((start, end)),
+            }
+        }
+
+        let mut stored: Vec<Vec<u8>> = missing
+            .iter()
+            .map(|&(start, end)| vec![0; (end - start) as usize])
+            .collect();
+        let mut reads: Vec<uring::ReadAt> = missing
+            .iter()
+            .zip(&mut stored)
+            .map(|(&(start, _), buf)| uring::ReadAt {
+                file,
+                offset: start,
+                buf,
+            })
+            .collect();
+        for ((start, end), read) in missing.iter().zip(ring.read_batch(&mut reads)) {
+            if read? < (end - start) as usize {
+                let cut = io::Error::from(io::ErrorKind::UnexpectedEof);
+                return Err(error::locate(cut, &self.path, *start));
+            }
+        }
+        for ((start, _), stored) in missing.into_iter().zip(stored) {
+            let block = self.restore_block(stored, start)?;
+            if let Some(cache) = &self.cache {
+                cache.insert(&self.path, start, block.clone());
+            }
+            blocks.insert(start, block);
+        }
+
+        let mut bytes_read = 0;
+        for group in located.chunk_by(|(_, a), (_, b)| a == b) {
+            let start = group[0].1 .0;
+            let mut reader =
+                EntryReader::cached(blocks[&start].clone(), start).in_file(self.path.clone());
+            let group: Vec<usize> = group.iter().map(|&(i, _)| i).collect();
+            let result = Self::resolve_sorted(&mut reader, keys, &group, max_seq, &mut results);
+            bytes_read += reader.bytes_read();
+            result?;
+        }
+        self.lookup_bytes
+            .fetch_add(bytes_read, AtomicOrdering::Relaxed);
+        Ok(results)
+    }
+
+    fn resolve_sorted(
+        reader: &mut EntryReader,
+        keys: &[&[u8]],
+        wanted: &[usize],
+        max_seq: u64,
+        results: &mut [Option<Entry>],
+    ) -> io::Result<()> {
+        let mut next = 0;
+        while next < wanted.len() {
+            let Some((current, seq)) = reader.next_key()? else {
+                break;
+            };
+            // Requested keys sorting before this one aren't in the table
+            while next < wanted.len() && keys[wanted[next]] < current {
+                next += 1;
+            }
+            // Versions are newest first, so skip those written after max_seq
+            if next < wanted.len() && keys[wanted[next]] == current && seq <= max_seq {
+                results[wanted[next]] = Some(reader.read_entry()?.to_entry());
+                next += 1;
+            } else {
+                reader.skip_entry()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of passes made over the data section by scans and lookups
+    pub fn data_passes(&self) -> u64 {
+        self.passes.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Bytes of the data section read by point lookups, single or batched
+    pub fn lookup_bytes(&self) -> u64 {
+        self.lookup_bytes.load(AtomicOrdering::Relaxed)
+    }
+
+    /// All versions of `key` stored in this table, newest first
+    pub fn versions(&self, key: &[u8]) -> Result<Vec<Version>, StorageError> {
+        let mut versions = Vec::new();
+        if !self.covers_key(key) || !self.might_contain_key(key) {
+            return Ok(versions);
+        }
 
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)?;
+        let Some(mut reader) = self.entries_near(key, &ReadPath::Std)? else {
+            return Ok(versions);
+        };
+        while let Some((current_key, seq, entry)) = reader.next_entry()? {
+            match current_key.cmp(key) {
+                Ordering::Less => continue,
+                Ordering::Equal => versions.push(Version::new(seq, entry.to_entry())),
+                Ordering::Greater => break,
+            }
+        }
 
-        let mut pos = 0;
-        while pos < buffer.len() {
-            // Read key
-            let key_size = u32::from_le_bytes(buffer[pos..pos + 4].try_into().unwrap()) as usize;
-            pos += 4;
-            let current_key = &buffer[pos..pos + key_size];
-            pos += key_size;
+        Ok(versions)
+    }
 
-            // Read value size
-            let value_size = u32::from_le_bytes(buffer[pos..pos + 4].try_into().unwrap()) as usize;
-            pos += 4;
+    /// Re-read the whole table, checking that its metadata and index parse, that
+    /// its entries decode in key order (newest first within a key) and that
+    /// they agree with the stored properties. Returns the entries read.
+    pub fn verify(&self) -> Result<u64, StorageError> {
+        let corrupt = |offset, msg: String| error::corruption(&self.path, offset, msg);
+        let mut file = self.open_file()?;
+        let (_, properties) = Self::read_metadata(&mut file, &self.path)?;
+        let index = SparseIndex::read(&mut file).map_err(|e| error::locate(e, &self.path, 0))?;
+        if index != self.index {
+            let offset = self.index.as_ref().map_or(0, |index| index.data_end());
+            return Err(corrupt(offset, "sparse index is missing or damaged".to_string()).into());
+        }
+        // Every table with a header was written with an index, so one
+        // without has lost its end
+        if index.is_none() && self.format_version.is_some() {
+            let len = file.seek(SeekFrom::End(0))?;
+            return Err(corrupt(len, "sparse index footer is missing".to_string()).into());
+        }
 
-            // Check if key matches
-            if current_key == key {
-                // Found the key, return the value
-                return Ok(Some(buffer[pos..pos + value_size].to_vec()));
+        let mut reader = self.entries()?;
+        let mut actual = TableProperties::new();
+        let mut last: Option<(Vec<u8>, u64)> = None;
+        loop {
+            let offset = reader.offset();
+            let Some((key, seq, entry)) = reader.next_entry()? else {
+                break;
+            };
+            if let Some((last_key, last_seq)) = &last {
+                let ordered = match key.cmp(last_key) {
+                    Ordering::Greater => true,
+                    Ordering::Equal => seq < *last_seq,
+                    Ordering::Less => false,
+                };
+                if !ordered {
+                    return Err(corrupt(
+                        offset,
+                        format!(
+                            "entry {} ({}, seq {}) is out of order",
+                            actual.entry_count,
+                            key.escape_ascii(),
+                            seq
+                        ),
+                    )
+                    .into());
+                }
             }
+            actual.record(key, seq, entry);
+            last = Some((key.to_vec(), seq));
+        }
 
-            // Skip this value
-            pos += value_size;
+        if actual.entry_count != properties.entry_count
+            || actual.tombstone_count != properties.tombstone_count
+            || actual.max_seq != properties.max_seq
+            || actual.key_range() != properties.key_range()
+        {
+            return Err(corrupt(
+                0,
+                format!(
+                    "entries disagree with the stored properties ({} read, {} recorded)",
+                    actual.entry_count, properties.entry_count
+                ),
+            )
+            .into());
         }
+        Ok(actual.entry_count)
+    }
 
-        Ok(None)
+    /// Every version that decodes cleanly and in order up to the first
+    /// damage, for rebuilding a table [`verify`](SSTable::verify) rejects.
+    /// Fails if the metadata can't be read, as the data section can't be
+    /// found without it.
+    pub fn salvage(&self) -> Result<Vec<(Key, Version)>, StorageError> {
+        Self::read_metadata(&mut self.open_file()?, &self.path)?;
+        let mut reader = self.entries()?;
+        let mut salvaged: Vec<(Key, Version)> = Vec::new();
+        while let Ok(Some((key, seq, entry))) = reader.next_entry() {
+            if let Some((last_key, last)) = salvaged.last() {
+                let ordered = match key.cmp(last_key) {
+                    Ordering::Greater => true,
+                    Ordering::Equal => seq < last.seq,
+                    Ordering::Less => false,
+                };
+                if !ordered {
+                    break;
+                }
+            }
+            salvaged.push((key.to_vec(), Version::new(seq, entry.to_entry())));
+        }
+        Ok(salvaged)
     }
 
     pub fn size(&self) -> usize {
@@ -191,19 +1200,212 @@ impl SSTable {
         self.size
     }
 
+    /// Size statistics recorded when the table was written
+    pub fn properties(&self) -> &TableProperties {
+        &self.properties
+    }
+
+    /// The smallest and largest key in the table, or `None` if it's empty
+    pub fn key_range(&self) -> Option<(&[u8], &[u8])> {
+        self.properties.key_range()
+    }
+
+    /// Estimated bytes held by keys in `[start, end)`, interpolated between
+    /// the table's smallest and largest key without reading any data. Tables
+    /// with no recorded key range count as empty.
+    pub fn approximate_size_of_range(&self, start: &[u8], end: Option<&[u8]>) -> u64 {
+        let Some((smallest, largest)) = self.key_range() else {
+            return 0;
+        };
+        if largest < start || end.is_some_and(|end| end <= smallest) {
+            return 0;
+        }
+
+        let from = if start <= smallest {
+            0.0
+        } else {
+            key_position(start, smallest, largest)
+        };
+        let to = match end {
+            Some(end) if end <= largest => key_position(end, smallest, largest),
+            _ => 1.0,
+        };
+        (self.size() as f64 * (to - from).max(0.0)) as u64
+    }
+
     pub fn get_path(&self) -> &PathBuf {
         &self.path
     }
 
-    #[allow(dead_code)]
-    pub fn delete(self) -> io::Result<()> {
-        fs::remove_file(self.path)
+    pub fn delete(self) -> Result<(), StorageError> {
+        Ok(self.remove_file()?)
+    }
+
+    /// Remove the table's file, or its object if it was uploaded
+    fn remove_file(&self) -> io::Result<()> {
+        #[cfg(feature = "object-store")]
+        if let Some(objects) = &self.objects {
+            return objects.delete(objectfs::object_name(&self.path)?);
+        }
+        fs::remove_file(&self.path)
+    }
+
+    /// Schedule the file for removal once the last reference to this table
+    /// is dropped, so readers that still hold it can finish
+    pub fn mark_obsolete(&self) {
+        self.obsolete.store(true, AtomicOrdering::Release);
+    }
+}
+
+/// Length of `file`, leaving its position where it was
+fn stream_len<R: Seek>(file: &mut R) -> io::Result<u64> {
+    let pos = file.stream_position()?;
+    let len = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(pos))?;
+    Ok(len)
+}
+
+/// The filter in `bytes`, from a table in format `version`. One hashed in a
+/// way this build doesn't know is left out, so lookups read the table
+/// rather than trust it.
+fn decode_bloom(bytes: &[u8], version: Option<u16>) -> io::Result<Option<BloomFilter>> {
+    let filter = match version {
+        Some(version) if version >= 3 => BloomFilter::from_bytes(bytes).map_err(io::Error::from),
+        Some(2) => BloomFilter::from_legacy_bytes(bytes, BloomHashing::Double),
+        _ => BloomFilter::from_legacy_bytes(bytes, BloomHashing::Seeded),
+    };
+    match filter {
+        Ok(filter) => Ok(Some(filter)),
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Where `key` falls between `smallest` and `largest`, from 0 to 1, reading
+/// the eight bytes after their common prefix as a number
+/// Write the header, then the bloom filter or a mark of its absence,
+/// followed by the properties block, returning the bytes written
+fn write_metadata<W: Write>(
+    file: &mut W,
+    bloom: &Option<BloomFilter>,
+    properties: &TableProperties,
+) -> io::Result<usize> {
+    file.write_all(TABLE_MAGIC)?;
+    file.write_all(&TABLE_FORMAT_VERSION.to_le_bytes())?;
+    let mut size = HEADER_SIZE;
+    match bloom {
+        Some(bloom) => {
+            let bloom_bytes = bloom.to_bytes();
+            file.write_all(&(bloom_bytes.len() as u32).to_le_bytes())?;
+            file.write_all(&bloom_bytes)?;
+            size += bloom_bytes.len() + 4; // 4 bytes for size
+        }
+        None => {
+            file.write_all(&NO_FILTER.to_le_bytes())?;
+            size += 4;
+        }
+    }
+
+    let properties_bytes = properties.to_bytes();
+    file.write_all(&(properties_bytes.len() as u32).to_le_bytes())?;
+    file.write_all(&properties_bytes)?;
+    Ok(size + properties_bytes.len() + 4)
+}
+
+/// Write the sparse index and the footer locating it after the data
+/// section, returning the bytes written
+fn write_index<W: Write>(file: &mut W, index: &SparseIndex) -> io::Result<usize> {
+    let bytes = index.to_bytes();
+    file.write_all(&bytes)?;
+    Ok(bytes.len())
+}
+
+/// Bytes [`write_record`] takes for an entry
+fn record_size(key: &[u8], entry: EntryRef) -> usize {
+    match entry {
+        EntryRef::Value(value) | EntryRef::Merge(value) => key.len() + value.len() + 17, // sizes, seq and kind
+        EntryRef::Tombstone { .. } => key.len() + 21, // key size, seq, kind and timestamp
+        EntryRef::Expiring { value, .. } => key.len() + value.len() + 25, // and the expiry
+    }
+}
+
+/// Write one entry of the data section, returning the bytes written.
+///
+/// Write format: [key_size][key][seq][kind] followed by [value_size][value]
+/// for values or [deleted_at] for tombstones
+fn write_record<W: Write>(
+    file: &mut W,
+    key: &[u8],
+    seq: u64,
+    entry: EntryRef,
+) -> io::Result<usize> {
+    file.write_all(&(key.len() as u32).to_le_bytes())?;
+    file.write_all(key)?;
+    file.write_all(&seq.to_le_bytes())?;
+
+    match entry {
+        EntryRef::Value(value) => {
+            file.write_all(&[ENTRY_VALUE])?;
+            file.write_all(&(value.len() as u32).to_le_bytes())?;
+            file.write_all(value)?;
+        }
+        EntryRef::Tombstone { deleted_at } => {
+            file.write_all(&[ENTRY_TOMBSTONE])?;
+            file.write_all(&deleted_at.to_le_bytes())?;
+        }
+        EntryRef::Merge(operand) => {
+            file.write_all(&[ENTRY_MERGE])?;
+            file.write_all(&(operand.len() as u32).to_le_bytes())?;
+            file.write_all(operand)?;
+        }
+        EntryRef::Expiring { value, expires_at } => {
+            file.write_all(&[ENTRY_EXPIRING])?;
+            file.write_all(&expires_at.to_le_bytes())?;
+            file.write_all(&(value.len() as u32).to_le_bytes())?;
+            file.write_all(value)?;
+        }
+    }
+    Ok(record_size(key, entry))
+}
+
+fn key_position(key: &[u8], smallest: &[u8], largest: &[u8]) -> f64 {
+    let prefix = smallest
+        .iter()
+        .zip(largest)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let number = |key: &[u8]| {
+        let mut bytes = [0u8; 8];
+        for (byte, &key_byte) in bytes.iter_mut().zip(key.iter().skip(prefix)) {
+            *byte = key_byte;
+        }
+        u64::from_be_bytes(bytes) as f64
+    };
+
+    let (low, high) = (number(smallest), number(largest));
+    if high <= low {
+        // The bounds only differ past the bytes compared
+        return 0.5;
+    }
+    ((number(key) - low) / (high - low)).clamp(0.0, 1.0)
+}
+
+impl Drop for SSTable {
+    fn drop(&mut self) {
+        if self.obsolete.load(AtomicOrdering::Acquire) {
+            let _ = self.remove_file();
+        }
+        // Nothing reaches these blocks any more, and the path may be reused
+        if let Some(cache) = &self.cache {
+            cache.invalidate(&self.path);
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::error::StorageError;
     use tempfile::TempDir;
 
     fn create_test_data() -> Vec<(Key, Value)> {
@@ -253,6 +1455,30 @@ mod tests {
         assert_eq!(table.size(), expected_size);
     }
 
+    #[test]
+    fn test_writes_go_through_tmp_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("test.sst");
+        let mut table = SSTable::new(path.clone()).unwrap();
+        let entries: Vec<_> = create_test_data()
+            .into_iter()
+            .map(|(key, value)| (key, Version::new(1, Entry::Value(value))))
+            .collect();
+
+        for mode in [IoMode::Buffered, IoMode::Direct] {
+            table.write_entries_with(&entries, mode).unwrap();
+            assert!(!temp_dir.path().join("test.sst.tmp").exists());
+            assert_eq!(table.read_entries().unwrap(), entries);
+
+            table
+                .write_stream_with(entries.iter().cloned().map(Ok), entries.len(), mode)
+                .unwrap();
+            assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+            assert_eq!(table.read_entries().unwrap(), entries);
+        }
+        assert_eq!(fs::metadata(&path).unwrap().len() as usize, table.size());
+    }
+
     #[test]
     fn test_empty_sstable() {
         let temp_dir = TempDir::new().unwrap();
@@ -262,6 +1488,33 @@ mod tests {
         table.write(&[]).unwrap();
         let read_data = table.read().unwrap();
         assert!(read_data.is_empty());
+        assert_eq!(table.key_range(), None);
+        assert!(table.covers_key(b"any"));
+        assert_eq!(table.get(b"any").unwrap(), None);
+    }
+
+    #[test]
+    fn test_key_range_prunes_lookups() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("single.sst");
+        let mut table = SSTable::new(path.clone()).unwrap().with_bloom(None);
+        table.write(&[(b"m".to_vec(), b"value".to_vec())]).unwrap();
+
+        let table = SSTable::new(path).unwrap();
+        assert_eq!(table.key_range(), Some((&b"m"[..], &b"m"[..])));
+        assert!(table.covers_key(b"m"));
+        assert!(!table.covers_key(b"a"));
+        assert!(!table.covers_key(b"z"));
+
+        // Keys outside the range are answered without reading the file
+        assert_eq!(table.get(b"z").unwrap(), None);
+        assert_eq!(
+            table.multi_get(&[b"a", b"z"], u64::MAX).unwrap(),
+            vec![None, None]
+        );
+        assert_eq!(table.data_passes(), 0);
+        assert_eq!(table.get(b"m").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(table.data_passes(), 1);
     }
 
     #[test]
@@ -330,4 +1583,647 @@ mod tests {
         assert_eq!(table.get(b"key2").unwrap(), Some(b"value2".to_vec()));
         assert_eq!(table.get(b"nonexistent").unwrap(), None);
     }
+
+    #[test]
+    fn test_older_filters_keep_their_hashing() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("older.sst");
+        let data: Vec<_> = (0..500)
+            .map(|i| (format!("key{:03}", i).into_bytes(), b"value".to_vec()))
+            .collect();
+        let mut table = SSTable::new(path.clone()).unwrap();
+        table.write(&data).unwrap();
+        assert_eq!(
+            table.bloom_filter.as_ref().unwrap().hashing(),
+            BloomHashing::Double
+        );
+        let mut table = SSTable::new(path.clone()).unwrap().with_bloom(None);
+        table.write(&data).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        let data_end = table.index.as_ref().unwrap().data_end() as usize;
+
+        // Rebuilt as older versions wrote them: filters without the
+        // hashing's id, which version 1 built by rehashing. The index
+        // can't follow the shifted data, so it's left off.
+        for (version, hashing) in [(1u16, BloomHashing::Seeded), (2, BloomHashing::Double)] {
+            let mut filter = BloomFilter::with_hashing(
+                data.len(),
+                BloomConfig::default().false_positive_rate,
+                hashing,
+            );
+            for (key, _) in &data {
+                filter.insert(key);
+            }
+            let mut filter = filter.to_bytes();
+            filter.remove(8);
+            let mut older = TABLE_MAGIC.to_vec();
+            older.extend_from_slice(&version.to_le_bytes());
+            older.extend_from_slice(&(filter.len() as u32).to_le_bytes());
+            older.extend_from_slice(&filter);
+            older.extend_from_slice(&bytes[HEADER_SIZE + 4..data_end]);
+            fs::write(&path, &older).unwrap();
+
+            let table = SSTable::new(path.clone()).unwrap();
+            assert_eq!(table.format_version(), Some(version));
+            assert_eq!(table.bloom_filter.as_ref().unwrap().hashing(), hashing);
+            for (key, value) in &data {
+                assert!(table.might_contain_key(key));
+                assert_eq!(table.get(key).unwrap().as_ref(), Some(value));
+            }
+        }
+    }
+
+    #[test]
+    fn test_filter_with_unknown_hashing_is_ignored() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("unknown.sst");
+        let data = create_test_data();
+        SSTable::new(path.clone()).unwrap().write(&data).unwrap();
+        // The hashing's id follows the filter's size and hash count
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[HEADER_SIZE + 4 + 8] = 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        // Rather than trust a filter it can't probe, it reads the table
+        let table = SSTable::new(path).unwrap();
+        assert!(!table.has_bloom_filter());
+        assert_eq!(table.properties().entry_count, 3);
+        for (key, value) in &data {
+            assert_eq!(table.get(key).unwrap().as_ref(), Some(value));
+        }
+        assert!(table.verify().is_ok());
+    }
+
+    #[test]
+    fn test_without_bloom_filter() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("no_bloom.sst");
+        let mut table = SSTable::new(path.clone()).unwrap().with_bloom(None);
+        table
+            .write(&[(b"key1".to_vec(), b"value1".to_vec())])
+            .unwrap();
+        assert!(!table.has_bloom_filter());
+
+        // The absence is recorded in the file, not inferred from a failure
+        let reopened = SSTable::new(path).unwrap();
+        assert!(!reopened.has_bloom_filter());
+        assert_eq!(reopened.properties().entry_count, 1);
+        assert!(reopened.might_contain_key(b"nonexistent"));
+        assert_eq!(reopened.get(b"key1").unwrap(), Some(b"value1".to_vec()));
+        assert_eq!(reopened.get(b"nonexistent").unwrap(), None);
+        reopened.verify().unwrap();
+    }
+
+    #[test]
+    fn test_approximate_size_of_range() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut table = SSTable::new(temp_dir.path().join("approx.sst")).unwrap();
+        let data: Vec<_> = (0..=255u8)
+            .map(|i| (vec![b'k', i], vec![b'v'; 100]))
+            .collect();
+        table.write(&data).unwrap();
+        let size = table.size() as u64;
+
+        assert_eq!(table.approximate_size_of_range(b"", None), size);
+        assert_eq!(table.approximate_size_of_range(b"a", Some(b"k")), 0);
+        assert_eq!(table.approximate_size_of_range(b"l", None), 0);
+        let half = table.approximate_size_of_range(&[b'k', 128], None);
+        assert!(half.abs_diff(size / 2) < size / 100, "{} of {}", half, size);
+        let quarter = table.approximate_size_of_range(&[b'k', 64], Some(&[b'k', 128]));
+        assert!(
+            quarter.abs_diff(size / 4) < size / 100,
+            "{} of {}",
+            quarter,
+            size
+        );
+    }
+
+    #[test]
+    fn test_get_into_reuses_buffer() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("get_into.sst");
+        let mut table = SSTable::new(path).unwrap();
+        table.write(&create_test_data()).unwrap();
+
+        let mut out = Vec::with_capacity(64);
+        let ptr = out.as_ptr();
+        for (key, value) in create_test_data() {
+            assert!(table.get_into(&key, &mut out).unwrap());
+            assert_eq!(out, value);
+        }
+        assert!(!table.get_into(b"nonexistent", &mut out).unwrap());
+
+        // Every lookup wrote into the caller's original allocation
+        assert_eq!(out.as_ptr(), ptr);
+        assert_eq!(out.capacity(), 64);
+    }
+
+    #[test]
+    fn test_entry_reader_borrows_scratch() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("reader.sst");
+        let mut table = SSTable::new(path).unwrap();
+        let test_data = create_test_data();
+        table.write(&test_data).unwrap();
+
+        let mut reader = table.entries().unwrap();
+        let mut seen = Vec::new();
+        let mut key_ptrs = Vec::new();
+        while let Some((key, _, entry)) = reader.next_entry().unwrap() {
+            key_ptrs.push(key.as_ptr());
+            if let EntryRef::Value(value) = entry {
+                seen.push((key.to_vec(), value.to_vec()));
+            }
+        }
+        assert_eq!(seen, test_data);
+
+        // Equal-length keys are decoded into the same scratch allocation
+        assert!(key_ptrs.windows(2).all(|w| w[0] == w[1]));
+    }
+
+    #[test]
+    fn test_direct_io_round_trip() {
+        let temp_dir = TempDir::new().unwrap();
+        let entries: Vec<_> = (0..5000u64)
+            .map(|i| {
+                let entry = if i % 7 == 0 {
+                    Entry::Tombstone { deleted_at: i }
+                } else {
+                    Entry::Value(vec![b'v'; (i % 300) as usize])
+                };
+                (format!("key{:05}", i).into_bytes(), Version::new(i, entry))
+            })
+            .collect();
+
+        let buffered_path = temp_dir.path().join("buffered.sst");
+        let direct_path = temp_dir.path().join("direct.sst");
+        let mut buffered = SSTable::new(buffered_path.clone()).unwrap();
+        buffered.write_entries(&entries).unwrap();
+        let mut direct = SSTable::new(direct_path.clone()).unwrap();
+        direct.write_entries_with(&entries, IoMode::Direct).unwrap();
+
+        // Padding written for alignment must not survive in the file
+        assert_eq!(
+            fs::read(&direct_path).unwrap(),
+            fs::read(&buffered_path).unwrap()
+        );
+        assert_eq!(direct.size(), buffered.size());
+
+        let direct = SSTable::new(direct_path).unwrap();
+        for mode in [IoMode::Buffered, IoMode::Direct] {
+            assert_eq!(direct.read_entries_with(mode).unwrap(), entries);
+        }
+        assert_eq!(direct.get(b"key00001").unwrap(), Some(vec![b'v'; 1]));
+    }
+
+    #[test]
+    fn test_compressed_tables_round_trip() {
+        let temp_dir = TempDir::new().unwrap();
+        let entries: Vec<_> = (0..3000u64)
+            .map(|i| {
+                let entry = if i % 7 == 0 {
+                    Entry::Tombstone { deleted_at: i }
+                } else {
+                    Entry::Value(format!("value-{}", i % 10).repeat(20).into_bytes())
+                };
+                (
+                    format!("key{:05}", i / 2).into_bytes(),
+                    Version::new(3000 - i, entry),
+                )
+            })
+            .collect();
+        let mut plain = SSTable::new(temp_dir.path().join("plain.sst")).unwrap();
+        plain.write_entries(&entries).unwrap();
+
+        let codecs = [Compression::Snappy, Compression::Lz4];
+        for codec in codecs.into_iter().filter(|codec| codec.is_supported()) {
+            for mode in [IoMode::Buffered, IoMode::Direct] {
+                let path = temp_dir.path().join(format!("{}.sst", codec));
+                let mut table = SSTable::new(path.clone()).unwrap().with_compression(codec);
+                table.write_entries_with(&entries, mode).unwrap();
+                assert!(table.size() < plain.size() / 2, "{}", codec);
+
+                let cache = Arc::new(BlockCache::new(1024 * 1024));
+                let table = SSTable::new(path).unwrap().with_cache(Some(cache.clone()));
+                assert_eq!(table.properties().compression, codec);
+                assert_eq!(table.properties().data_size, plain.properties().data_size);
+                assert_eq!(table.read_entries_with(mode).unwrap(), entries);
+                assert_eq!(table.verify().unwrap(), entries.len() as u64);
+
+                // Point reads land inside blocks, cached or not
+                for _ in 0..2 {
+                    for (key, _) in entries.iter().step_by(97) {
+                        assert_eq!(table.get(key).unwrap(), plain.get(key).unwrap());
+                        assert_eq!(table.versions(key).unwrap(), plain.versions(key).unwrap());
+                    }
+                }
+                assert!(cache.stats().hits > 0);
+                let keys: Vec<&[u8]> = vec![b"key00001", b"key00700", b"key01499", b"z"];
+                assert_eq!(
+                    table.multi_get(&keys, u64::MAX).unwrap(),
+                    plain.multi_get(&keys, u64::MAX).unwrap()
+                );
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(not(all(feature = "snappy", feature = "lz4")))]
+    fn test_missing_codec_is_an_error() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("missing.sst");
+        let codec = if cfg!(feature = "snappy") {
+            Compression::Lz4
+        } else {
+            Compression::Snappy
+        };
+        let mut table = SSTable::new(path.clone()).unwrap().with_compression(codec);
+        let err = table.write(&create_test_data()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert!(!path.exists());
+
+        // A table from a build that had the codec opens, but reads fail
+        let mut properties = TableProperties::new();
+        let mut block = Vec::new();
+        for (key, value) in create_test_data() {
+            properties.record(&key, 0, EntryRef::Value(&value));
+            write_record(&mut block, &key, 0, EntryRef::Value(&value)).unwrap();
+        }
+        properties.compression = codec;
+        let mut file = File::create(&path).unwrap();
+        write_metadata(&mut file, &None, &properties).unwrap();
+        file.write_all(&Compression::None.compress_block(&block).unwrap())
+            .unwrap();
+        drop(file);
+
+        let table = SSTable::new(path).unwrap();
+        assert_eq!(table.properties().compression, codec);
+        let err = table.get(b"key1").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert!(table.read().is_err());
+        assert!(table.verify().is_err());
+    }
+
+    #[test]
+    fn test_tombstones_and_versions() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("tombstones.sst");
+        let mut table = SSTable::new(path.clone()).unwrap();
+
+        let entries = vec![
+            (
+                b"a".to_vec(),
+                Version::new(1, Entry::Value(b"live".to_vec())),
+            ),
+            (
+                b"b".to_vec(),
+                Version::new(4, Entry::Tombstone { deleted_at: 7 }),
+            ),
+            (
+                b"b".to_vec(),
+                Version::new(2, Entry::Value(b"retained".to_vec())),
+            ),
+            (
+                b"c".to_vec(),
+                Version::new(3, Entry::Tombstone { deleted_at: 9 }),
+            ),
+        ];
+        table.write_entries(&entries).unwrap();
+
+        let table = SSTable::new(path).unwrap();
+        assert_eq!(table.read_entries().unwrap(), entries);
+        assert_eq!(table.properties().tombstone_count, 2);
+        assert_eq!(table.properties().max_seq, 4);
+
+        // Only the newest version of each key counts for reads
+        assert_eq!(
+            table.read().unwrap(),
+            vec![(b"a".to_vec(), b"live".to_vec())]
+        );
+        let mut out = Vec::new();
+        let lookup =
+            |key: &[u8], max_seq, out: &mut Vec<u8>| table.lookup_into(key, max_seq, out).unwrap();
+        assert_eq!(lookup(b"a", u64::MAX, &mut out), Lookup::Found);
+        assert_eq!(lookup(b"b", u64::MAX, &mut out), Lookup::Deleted);
+        assert_eq!(lookup(b"bb", u64::MAX, &mut out), Lookup::Missing);
+        assert_eq!(table.get(b"b").unwrap(), None);
+
+        // Older sequence numbers see the table as it was at the time
+        assert_eq!(lookup(b"b", 3, &mut out), Lookup::Found);
+        assert_eq!(out, b"retained");
+        assert_eq!(lookup(b"b", 1, &mut out), Lookup::Missing);
+        assert_eq!(lookup(b"a", 0, &mut out), Lookup::Missing);
+
+        assert_eq!(
+            table.versions(b"b").unwrap(),
+            vec![entries[1].1.clone(), entries[2].1.clone()]
+        );
+    }
+
+    #[test]
+    fn test_properties_match_written_data() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("props.sst");
+        let mut table = SSTable::new(path.clone()).unwrap();
+
+        let test_data = vec![
+            (b"a".to_vec(), vec![b'x'; 10]),
+            (b"bb".to_vec(), vec![b'y'; 5000]),
+            (b"ccc".to_vec(), Vec::new()),
+        ];
+        table.write(&test_data).unwrap();
+
+        // Properties must survive a reopen and still describe the data
+        let reopened = SSTable::new(path).unwrap();
+        let props = reopened.properties();
+        assert_eq!(props, table.properties());
+        assert_eq!(props.entry_count, 3);
+        assert_eq!(props.key_sizes.min(), 1);
+        assert_eq!(props.key_sizes.max(), 3);
+        assert_eq!(props.key_sizes.total(), 6);
+        assert_eq!(props.value_sizes.min(), 0);
+        assert_eq!(props.value_sizes.max(), 5000);
+        assert_eq!(props.value_sizes.mean(), 5010.0 / 3.0);
+        assert_eq!(
+            reopened.key_range(),
+            Some((b"a".as_slice(), b"ccc".as_slice()))
+        );
+
+        // The data section is unaffected by the extra block
+        assert_eq!(reopened.read().unwrap(), test_data);
+    }
+
+    #[test]
+    fn test_sparse_index_lookups() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("indexed.sst");
+        // Without a filter every lookup has to go through the index
+        let mut table = SSTable::new(path.clone()).unwrap().with_bloom(None);
+        let data: Vec<(Key, Value)> = (0..1000)
+            .map(|i| (format!("key{:04}", i * 2).into_bytes(), vec![b'v'; 100]))
+            .collect();
+        table.write(&data).unwrap();
+
+        let table = SSTable::new(path).unwrap();
+        assert!(!table.has_bloom_filter());
+        // [key_size][key][seq][kind][value_size][value]
+        let entry_size = 4 + 7 + 8 + 1 + 4 + 100;
+        let block = (index::INDEX_INTERVAL * entry_size) as u64;
+        let lookup = |key: &[u8]| {
+            let before = table.lookup_bytes();
+            let value = table.get(key).unwrap();
+            let read = table.lookup_bytes() - before;
+            assert!(read <= block, "{} read {} bytes", key.escape_ascii(), read);
+            value
+        };
+
+        assert_eq!(lookup(b"key0000"), Some(vec![b'v'; 100]));
+        assert_eq!(lookup(b"key1998"), Some(vec![b'v'; 100]));
+        // Between the indexed key0032 and key0064, and within that block
+        assert_eq!(lookup(b"key0033"), None);
+        assert_eq!(lookup(b"key9999"), None);
+        // Keys before the first need no read at all
+        let passes = table.data_passes();
+        assert_eq!(lookup(b"a"), None);
+        assert_eq!(table.data_passes(), passes);
+
+        let keys: Vec<&[u8]> = vec![b"a", b"key0100", b"key1998", b"z"];
+        let found = table.multi_get(&keys, u64::MAX).unwrap();
+        assert_eq!(
+            found.iter().map(Option::is_some).collect::<Vec<_>>(),
+            vec![false, true, true, false]
+        );
+        assert_eq!(table.verify().unwrap(), 1000);
+    }
+
+    #[test]
+    #[cfg(all(feature = "iouring", target_os = "linux"))]
+    fn test_batched_multi_get_matches_pass() {
+        let temp_dir = TempDir::new().unwrap();
+        let data: Vec<(Key, Value)> = (0..1000)
+            .map(|i| (format!("key{:04}", i * 2).into_bytes(), vec![b'v'; 100]))
+            .collect();
+        // Some present, some between keys, some before and after the table,
+        // several to a block and more blocks than the ring holds at once
+        let wanted: Vec<Key> = (0..2100)
+            .step_by(7)
+            .map(|i| format!("key{:04}", i).into_bytes())
+            .chain([b"a".to_vec(), b"z".to_vec()])
+            .collect();
+        let mut keys: Vec<&[u8]> = wanted.iter().map(Vec::as_slice).collect();
+        keys.sort();
+        let ring = ReadPath::Uring(Arc::new(Ring::new(4).unwrap()));
+
+        let codecs = [Compression::None, Compression::Snappy, Compression::Lz4];
+        for codec in codecs.into_iter().filter(|codec| codec.is_supported()) {
+            let path = temp_dir.path().join(format!("{}.sst", codec));
+            let mut table = SSTable::new(path.clone()).unwrap().with_compression(codec);
+            table.write(&data).unwrap();
+
+            let cache = Arc::new(BlockCache::new(1024 * 1024));
+            let table = SSTable::new(path).unwrap().with_cache(Some(cache.clone()));
+            let expected = table.multi_get(&keys, u64::MAX).unwrap();
+            assert_eq!(expected.iter().flatten().count(), 143);
+            // Once reading every block, then from the cache
+            for _ in 0..2 {
+                let passes = table.data_passes();
+                assert_eq!(
+                    table.multi_get_with(&keys, u64::MAX, &ring).unwrap(),
+                    expected
+                );
+                assert_eq!(table.data_passes(), passes + 1);
+            }
+            assert!(cache.stats().hits > 0);
+
+            let uncached = SSTable::new(table.get_path().clone()).unwrap();
+            assert_eq!(
+                uncached.multi_get_with(&keys, u64::MAX, &ring).unwrap(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_header_checked_on_open() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("current.sst");
+        let mut table = SSTable::new(path.clone()).unwrap();
+        assert_eq!(table.format_version(), Some(TABLE_FORMAT_VERSION));
+        table.write(&create_test_data()).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        assert!(bytes.starts_with(TABLE_MAGIC));
+        let table = SSTable::new(path.clone()).unwrap();
+        assert_eq!(table.format_version(), Some(TABLE_FORMAT_VERSION));
+        assert!(table.verify().is_ok());
+
+        // A newer format is refused, even as a legacy table
+        let mut newer = bytes.clone();
+        newer[TABLE_MAGIC.len()..HEADER_SIZE]
+            .copy_from_slice(&(TABLE_FORMAT_VERSION + 1).to_le_bytes());
+        fs::write(&path, &newer).unwrap();
+        for open in [SSTable::new, SSTable::open_legacy] {
+            let err = open(path.clone()).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+            match err {
+                StorageError::UnsupportedVersion {
+                    file,
+                    version,
+                    supported,
+                } => {
+                    assert_eq!(file, path);
+                    assert_eq!(version, TABLE_FORMAT_VERSION as u32 + 1);
+                    assert_eq!(supported, TABLE_FORMAT_VERSION as u32);
+                }
+                e => panic!("unexpected error: {}", e),
+            }
+        }
+
+        // Anything else at the front isn't a table with a header
+        let mut bogus = bytes.clone();
+        bogus[..TABLE_MAGIC.len()].copy_from_slice(b"NOTATABL");
+        fs::write(&path, &bogus).unwrap();
+        let err = SSTable::new(path.clone()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        match err {
+            StorageError::Corruption { file, offset, .. } => {
+                assert_eq!(file, path);
+                assert_eq!(offset, 0);
+            }
+            e => panic!("unexpected error: {}", e),
+        }
+        // and read as a legacy table, it makes no sense
+        let table = SSTable::open_legacy(path.clone()).unwrap();
+        assert!(table.verify().is_err());
+    }
+
+    #[test]
+    fn test_table_without_index_still_readable() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("unindexed.sst");
+        let data = create_test_data();
+
+        // Lay the table out as it was before indexes and compression:
+        // metadata without the codec, then plain entries
+        let mut properties = TableProperties::new();
+        for (key, value) in &data {
+            properties.record(key, 0, EntryRef::Value(value));
+        }
+        let properties_bytes = properties.to_bytes();
+        let legacy = &properties_bytes[..properties_bytes.len() - 9];
+        let mut file = File::create(&path).unwrap();
+        file.write_all(&NO_FILTER.to_le_bytes()).unwrap();
+        file.write_all(&(legacy.len() as u32).to_le_bytes())
+            .unwrap();
+        file.write_all(legacy).unwrap();
+        for (key, value) in &data {
+            write_record(&mut file, key, 0, EntryRef::Value(value)).unwrap();
+        }
+        drop(file);
+
+        let err = SSTable::new(path.clone()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("migrate_format"), "{}", err);
+        let table = SSTable::open_legacy(path).unwrap();
+        assert_eq!(table.format_version(), None);
+        assert!(table.index.is_none());
+        assert_eq!(table.properties().compression, Compression::None);
+        assert_eq!(table.properties().data_size, 0);
+        for (key, value) in &data {
+            assert_eq!(table.get(key).unwrap().as_ref(), Some(value));
+        }
+        assert_eq!(table.get(b"nonexistent").unwrap(), None);
+        assert_eq!(table.read().unwrap(), data);
+        assert_eq!(table.verify().unwrap(), data.len() as u64);
+    }
+
+    #[test]
+    fn test_truncated_tables_return_errors() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("whole.sst");
+        let mut table = SSTable::new(path.clone()).unwrap();
+        table.write(&create_test_data()).unwrap();
+        let bytes = fs::read(&path).unwrap();
+
+        // Whatever survives a cut, reads fail cleanly rather than panic
+        let cut = temp_dir.path().join("cut.sst");
+        for len in 0..bytes.len() {
+            fs::write(&cut, &bytes[..len]).unwrap();
+            let table = match SSTable::new(cut.clone()) {
+                Ok(table) => table,
+                Err(e) => {
+                    // Only a cut into the header stops it opening
+                    assert!(len < HEADER_SIZE, "cut at {}: {}", len, e);
+                    assert!(e.to_string().contains("header cut short"), "{}", e);
+                    continue;
+                }
+            };
+            let results = create_test_data()
+                .iter()
+                .map(|(key, _)| table.get(key).map(|_| ()))
+                .chain([table.read().map(|_| ()), table.verify().map(|_| ())])
+                .collect::<Vec<_>>();
+            for result in results {
+                if let Err(e) = result {
+                    assert_eq!(
+                        e.kind(),
+                        io::ErrorKind::InvalidData,
+                        "cut at {}: {}",
+                        len,
+                        e
+                    );
+                    match e {
+                        StorageError::Corruption { file, .. } => assert_eq!(file, cut),
+                        other => panic!("cut at {}: {:?}", len, other),
+                    }
+                }
+            }
+            // Even cut at the index, the header says one was written
+            assert!(
+                table.verify().is_err(),
+                "cut at {} passed verification",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn test_absurd_length_prefixes_return_errors() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("lengths.sst");
+        let mut table = SSTable::new(path.clone()).unwrap();
+        table
+            .write(&[(b"key".to_vec(), b"value".to_vec())])
+            .unwrap();
+        let bytes = fs::read(&path).unwrap();
+        let bloom_at = HEADER_SIZE;
+        let bloom_size =
+            u32::from_le_bytes(bytes[bloom_at..bloom_at + 4].try_into().unwrap()) as usize;
+        let props_at = bloom_at + 4 + bloom_size;
+        let props_size = u32::from_le_bytes(bytes[props_at..props_at + 4].try_into().unwrap());
+        let data_at = props_at + 4 + props_size as usize;
+
+        // The bloom filter, properties, key and value length prefixes
+        for offset in [bloom_at, props_at, data_at, data_at + 4 + 3 + 8 + 1] {
+            for length in [u32::MAX - 1, 1 << 30] {
+                let mut damaged = bytes.clone();
+                damaged[offset..offset + 4].copy_from_slice(&length.to_le_bytes());
+                fs::write(&path, &damaged).unwrap();
+
+                let table = SSTable::new(path.clone()).unwrap();
+                let err = table.read().unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+                assert!(err.to_string().contains("offset"), "{}", err);
+                assert_eq!(
+                    table.verify().unwrap_err().kind(),
+                    io::ErrorKind::InvalidData
+                );
+                // Point reads seek straight to the data through the index,
+                // so only a damaged entry gets in their way
+                if offset >= data_at {
+                    let err = table.get(b"key").unwrap_err();
+                    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,159 @@
+use super::Compression;
+use crate::entry::EntryRef;
+use crate::stats::SizeHistogram;
+use std::io;
+
+/// Summary statistics stored alongside each SSTable so that callers can
+/// reason about a table without reading its entries
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TableProperties {
+    pub entry_count: u64,
+    pub tombstone_count: u64,
+    /// Highest sequence number of any entry in the table
+    pub max_seq: u64,
+    pub key_sizes: SizeHistogram,
+    pub value_sizes: SizeHistogram,
+    /// Smallest and largest key in the table; meaningless when it's empty
+    pub smallest_key: Vec<u8>,
+    pub largest_key: Vec<u8>,
+    /// How the data blocks are compressed
+    pub compression: Compression,
+    /// Bytes of the data section before compression; zero for tables
+    /// written before it was recorded
+    pub data_size: u64,
+    /// Numbers of the tables a compaction merged into this one, which are
+    /// stale wherever this table is found; empty for other tables
+    pub replaces: Vec<u64>,
+}
+
+impl TableProperties {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Account for one entry written to the table
+    pub fn record(&mut self, key: &[u8], seq: u64, entry: EntryRef) {
+        if self.entry_count == 0 || key < self.smallest_key.as_slice() {
+            self.smallest_key = key.to_vec();
+        }
+        if self.entry_count == 0 || key > self.largest_key.as_slice() {
+            self.largest_key = key.to_vec();
+        }
+        self.entry_count += 1;
+        self.max_seq = self.max_seq.max(seq);
+        self.key_sizes.record(key.len());
+        match entry {
+            EntryRef::Value(value) | EntryRef::Expiring { value, .. } | EntryRef::Merge(value) => {
+                self.value_sizes.record(value.len())
+            }
+            EntryRef::Tombstone { .. } => self.tombstone_count += 1,
+        }
+        self.data_size += super::record_size(key, entry) as u64;
+    }
+
+    /// Fold another table's properties into this one
+    pub fn merge(&mut self, other: &TableProperties) {
+        if other.entry_count > 0 {
+            if self.entry_count == 0 || other.smallest_key < self.smallest_key {
+                self.smallest_key = other.smallest_key.clone();
+            }
+            if self.entry_count == 0 || other.largest_key > self.largest_key {
+                self.largest_key = other.largest_key.clone();
+            }
+        }
+        self.entry_count += other.entry_count;
+        self.tombstone_count += other.tombstone_count;
+        self.max_seq = self.max_seq.max(other.max_seq);
+        self.key_sizes.merge(&other.key_sizes);
+        self.value_sizes.merge(&other.value_sizes);
+        self.data_size += other.data_size;
+    }
+
+    /// The smallest and largest key, or `None` for an empty table
+    pub fn key_range(&self) -> Option<(&[u8], &[u8])> {
+        (self.entry_count > 0).then_some((&self.smallest_key, &self.largest_key))
+    }
+
+    /// Serialize as `[entry_count][tombstone_count][max_seq][key_histogram][value_histogram]`
+    /// followed by the length-prefixed smallest and largest keys, then
+    /// `[codec][data_size]`, which older tables lack, and for compaction
+    /// outputs `[count u32]` replaced table numbers
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.entry_count.to_le_bytes());
+        bytes.extend_from_slice(&self.tombstone_count.to_le_bytes());
+        bytes.extend_from_slice(&self.max_seq.to_le_bytes());
+        bytes.extend_from_slice(&self.key_sizes.to_bytes());
+        bytes.extend_from_slice(&self.value_sizes.to_bytes());
+        for key in [&self.smallest_key, &self.largest_key] {
+            bytes.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(key);
+        }
+        bytes.push(self.compression.to_byte());
+        bytes.extend_from_slice(&self.data_size.to_le_bytes());
+        if !self.replaces.is_empty() {
+            bytes.extend_from_slice(&(self.replaces.len() as u32).to_le_bytes());
+            for number in &self.replaces {
+                bytes.extend_from_slice(&number.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid table properties");
+        let read_u64 = |pos: usize| {
+            bytes
+                .get(pos..pos + 8)
+                .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+                .ok_or_else(invalid)
+        };
+        let entry_count = read_u64(0)?;
+        let tombstone_count = read_u64(8)?;
+        let max_seq = read_u64(16)?;
+        let mut pos = 24;
+        let (key_sizes, consumed) = SizeHistogram::from_bytes(&bytes[pos..])?;
+        pos += consumed;
+        let (value_sizes, consumed) = SizeHistogram::from_bytes(&bytes[pos..])?;
+        pos += consumed;
+
+        let mut read_key = || {
+            let len = bytes
+                .get(pos..pos + 4)
+                .map(|b| u32::from_le_bytes(b.try_into().unwrap()) as usize)
+                .ok_or_else(invalid)?;
+            let key = bytes.get(pos + 4..pos + 4 + len).ok_or_else(invalid)?;
+            pos += 4 + len;
+            Ok::<_, io::Error>(key.to_vec())
+        };
+        let smallest_key = read_key()?;
+        let largest_key = read_key()?;
+        // Tables written before compression existed stop here
+        let (compression, data_size) = match bytes.get(pos) {
+            Some(&codec) => (Compression::from_byte(codec)?, read_u64(pos + 1)?),
+            None => (Compression::None, 0),
+        };
+        // As do tables that replace none
+        pos += 9;
+        let mut replaces = Vec::new();
+        if let Some(count) = bytes.get(pos..pos + 4) {
+            let count = u32::from_le_bytes(count.try_into().unwrap()) as usize;
+            for i in 0..count {
+                replaces.push(read_u64(pos + 4 + i * 8)?);
+            }
+        }
+
+        Ok(TableProperties {
+            entry_count,
+            tombstone_count,
+            max_seq,
+            key_sizes,
+            value_sizes,
+            smallest_key,
+            largest_key,
+            compression,
+            data_size,
+            replaces,
+        })
+    }
+}
@@ -0,0 +1,452 @@
+use super::advise::{advise, Advice};
+use super::blocks::BlockDecoder;
+use super::direct::DirectReader;
+#[cfg(all(feature = "iouring", target_os = "linux"))]
+use super::uring::UringReader;
+use super::{Compression, Lookup, ENTRY_EXPIRING, ENTRY_MERGE, ENTRY_TOMBSTONE, ENTRY_VALUE};
+use crate::entry::EntryRef;
+use crate::error;
+#[cfg(feature = "object-store")]
+use crate::objectfs::RemoteFile;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Cursor, Read, Seek};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// Consumed bytes dropped from the page cache at a time under sequential advice
+const RELEASE_CHUNK: u64 = 1024 * 1024;
+// First window prefetched by a read-ahead reader; it doubles from there
+const INITIAL_READ_AHEAD: u64 = 64 * 1024;
+
+/// Where an [`EntryReader`] pulls its bytes from
+enum Source {
+    Buffered(BufReader<File>),
+    Direct(DirectReader),
+    // A block held by the block cache
+    Cached(Cursor<Arc<[u8]>>),
+    // Compressed blocks read from another source
+    Decoded(Box<BlockDecoder<Source>>),
+    #[cfg(all(feature = "iouring", target_os = "linux"))]
+    Uring(UringReader),
+    // An object fetched through the chunk cache
+    #[cfg(feature = "object-store")]
+    Remote(BufReader<RemoteFile>),
+}
+
+impl Source {
+    fn skip(&mut self, n: usize) -> io::Result<()> {
+        match self {
+            Source::Buffered(reader) => reader.seek_relative(n as i64),
+            Source::Direct(reader) => reader.skip(n),
+            Source::Cached(reader) => reader.seek_relative(n as i64),
+            Source::Decoded(reader) => reader.skip(n),
+            #[cfg(all(feature = "iouring", target_os = "linux"))]
+            Source::Uring(reader) => {
+                reader.skip(n);
+                Ok(())
+            }
+            #[cfg(feature = "object-store")]
+            Source::Remote(reader) => reader.seek_relative(n as i64),
+        }
+    }
+}
+
+impl Read for Source {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Source::Buffered(reader) => reader.read(out),
+            Source::Direct(reader) => reader.read(out),
+            Source::Cached(reader) => reader.read(out),
+            Source::Decoded(reader) => reader.read(out),
+            #[cfg(all(feature = "iouring", target_os = "linux"))]
+            Source::Uring(reader) => reader.read(out),
+            #[cfg(feature = "object-store")]
+            Source::Remote(reader) => reader.read(out),
+        }
+    }
+}
+
+impl BufRead for Source {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        match self {
+            Source::Buffered(reader) => reader.fill_buf(),
+            Source::Direct(reader) => reader.fill_buf(),
+            Source::Cached(reader) => reader.fill_buf(),
+            Source::Decoded(reader) => reader.fill_buf(),
+            #[cfg(all(feature = "iouring", target_os = "linux"))]
+            Source::Uring(reader) => reader.fill_buf(),
+            #[cfg(feature = "object-store")]
+            Source::Remote(reader) => reader.fill_buf(),
+        }
+    }
+
+    fn consume(&mut self, n: usize) {
+        match self {
+            Source::Buffered(reader) => reader.consume(n),
+            Source::Direct(reader) => reader.consume(n),
+            Source::Cached(reader) => reader.consume(n),
+            Source::Decoded(reader) => reader.consume(n),
+            #[cfg(all(feature = "iouring", target_os = "linux"))]
+            Source::Uring(reader) => reader.consume(n),
+            #[cfg(feature = "object-store")]
+            Source::Remote(reader) => reader.consume(n),
+        }
+    }
+}
+
+/// Streams entries out of an SSTable's data section one at a time.
+///
+/// The key and value of the current entry are decoded into scratch buffers
+/// owned by the reader and handed out as borrowed slices, so walking a table
+/// allocates nothing per entry once the buffers have grown to fit.
+pub struct EntryReader {
+    reader: Source,
+    // Named in errors about damaged entries
+    file: PathBuf,
+    // Where the reader started, and where it is now
+    start: u64,
+    pos: u64,
+    len: u64,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    // Offset up to which cached pages were released, when advising
+    released: Option<u64>,
+    read_ahead: Option<ReadAhead>,
+}
+
+/// Prefetch state of a reader moving through a table front to back
+struct ReadAhead {
+    max: u64,
+    window: u64,
+    // Offset up to which reads were requested
+    until: u64,
+}
+
+impl EntryReader {
+    /// Wrap a file already positioned at `pos`, within a data section that
+    /// ends at offset `len`
+    pub(super) fn new(file: File, pos: u64, len: u64) -> Self {
+        Self::with_source(Source::Buffered(BufReader::new(file)), pos, len)
+    }
+
+    /// Wrap a direct reader positioned at `pos`, the start of the data
+    /// section, which ends at offset `len`
+    pub(super) fn direct(reader: DirectReader, pos: u64, len: u64) -> Self {
+        Self::with_source(Source::Direct(reader), pos, len)
+    }
+
+    /// Wrap an io_uring reader positioned at `pos` within a data section
+    /// that ends at offset `len`
+    #[cfg(all(feature = "iouring", target_os = "linux"))]
+    pub(super) fn uring(reader: UringReader, pos: u64, len: u64) -> Self {
+        Self::with_source(Source::Uring(reader), pos, len)
+    }
+
+    /// Wrap an object positioned at `pos`, within a data section that ends
+    /// at offset `len`
+    #[cfg(feature = "object-store")]
+    pub(super) fn remote(file: RemoteFile, pos: u64, len: u64) -> Self {
+        Self::with_source(Source::Remote(BufReader::new(file)), pos, len)
+    }
+
+    /// Read entries out of a cached block that starts at offset `pos`
+    pub(super) fn cached(block: Arc<[u8]>, pos: u64) -> Self {
+        let len = pos + block.len() as u64;
+        Self::with_source(Source::Cached(Cursor::new(block)), pos, len)
+    }
+
+    /// Read entries out of the compressed blocks this reader is positioned
+    /// at. Positions from here on count restored bytes from zero, up to
+    /// `max_raw`, the size of the whole data section before compression.
+    pub(super) fn decompressed(self, compression: Compression, max_raw: u64) -> Self {
+        let stored = self.len.saturating_sub(self.pos);
+        let decoder = BlockDecoder::new(self.reader, compression, stored, max_raw);
+        Self::with_source(Source::Decoded(Box::new(decoder)), 0, max_raw).in_file(self.file)
+    }
+
+    /// Name `file` as the table in errors about damaged entries
+    pub(super) fn in_file(mut self, file: PathBuf) -> Self {
+        self.file = file;
+        self
+    }
+
+    /// The raw bytes from the reader's position up to offset `end`, or the
+    /// end of the data section if that comes first
+    pub(super) fn read_to(mut self, end: u64) -> io::Result<Vec<u8>> {
+        let mut bytes = vec![0; (end.min(self.len).saturating_sub(self.pos)) as usize];
+        self.reader
+            .read_exact(&mut bytes)
+            .map_err(damaged(&self.file, self.pos))?;
+        Ok(bytes)
+    }
+
+    fn with_source(reader: Source, pos: u64, len: u64) -> Self {
+        EntryReader {
+            reader,
+            file: PathBuf::new(),
+            start: pos,
+            pos,
+            len,
+            key: Vec::new(),
+            value: Vec::new(),
+            released: None,
+            read_ahead: None,
+        }
+    }
+
+    /// Prefetch ahead of the reader as it moves through the table. The
+    /// window starts small and doubles up to `max` bytes, so short scans
+    /// don't pay for reads they never use. Only buffered readers on Linux
+    /// prefetch; zero turns it off.
+    pub fn read_ahead(&mut self, max: u64) {
+        self.read_ahead =
+            (max > 0 && matches!(self.reader, Source::Buffered(_))).then(|| ReadAhead {
+                max,
+                window: INITIAL_READ_AHEAD.min(max),
+                until: self.pos,
+            });
+    }
+
+    /// Request the next window once the reader is halfway into the last one
+    fn prefetch(&mut self) {
+        if let (Some(ahead), Source::Buffered(reader)) = (&mut self.read_ahead, &self.reader) {
+            if ahead.until < self.len && self.pos + ahead.window / 2 >= ahead.until {
+                advise(
+                    reader.get_ref(),
+                    ahead.until,
+                    ahead.window,
+                    Advice::WillNeed,
+                );
+                ahead.until += ahead.window;
+                ahead.window = (ahead.window * 2).min(ahead.max);
+            }
+        }
+    }
+
+    /// Hint that the table will be read once, front to back: the kernel
+    /// reads ahead, and pages already consumed are dropped from the cache as
+    /// the reader moves on. Direct readers bypass the cache, so this only
+    /// affects buffered ones.
+    pub fn advise_sequential(&mut self) {
+        if let Source::Buffered(reader) = &self.reader {
+            advise(reader.get_ref(), 0, 0, Advice::Sequential);
+            self.released = Some(0);
+        }
+    }
+
+    /// Drop cached pages behind the reader once a chunk has been consumed,
+    /// or all of them once `finished`
+    fn release_consumed(&mut self, finished: bool) {
+        if let (Some(released), Source::Buffered(reader)) = (self.released, &self.reader) {
+            if finished {
+                advise(reader.get_ref(), released, 0, Advice::DontNeed);
+                self.released = None;
+            } else if self.pos - released >= RELEASE_CHUNK {
+                advise(
+                    reader.get_ref(),
+                    released,
+                    self.pos - released,
+                    Advice::DontNeed,
+                );
+                self.released = Some(self.pos);
+            }
+        }
+    }
+
+    /// Bytes of the data section consumed so far
+    pub fn bytes_read(&self) -> u64 {
+        self.pos - self.start
+    }
+
+    /// Offset of the next entry: in the file, or in the restored data
+    /// section of a compressed table
+    pub fn offset(&self) -> u64 {
+        self.pos
+    }
+
+    /// Decode the next entry and its sequence number into the scratch buffers
+    pub fn next_entry(&mut self) -> io::Result<Option<(&[u8], u64, EntryRef<'_>)>> {
+        if self.at_end()? {
+            self.release_consumed(true);
+            return Ok(None);
+        }
+        self.release_consumed(false);
+        self.prefetch();
+        let key_size = self.read_length()?;
+        Self::read_exact_into(&mut self.reader, &mut self.key, key_size)
+            .map_err(damaged(&self.file, self.pos))?;
+        self.pos += key_size as u64;
+        let seq = self.read_u64()?;
+
+        let (kind, stamp) = self.decode_entry()?;
+        Ok(Some((&self.key, seq, self.entry_ref(kind, stamp))))
+    }
+
+    /// Decode only the next key and its sequence number, leaving the reader
+    /// positioned at its entry, which must then be consumed with
+    /// `read_entry_into` or `skip_entry`
+    pub fn next_key(&mut self) -> io::Result<Option<(&[u8], u64)>> {
+        if self.at_end()? {
+            self.release_consumed(true);
+            return Ok(None);
+        }
+        self.release_consumed(false);
+        self.prefetch();
+        let key_size = self.read_length()?;
+        Self::read_exact_into(&mut self.reader, &mut self.key, key_size)
+            .map_err(damaged(&self.file, self.pos))?;
+        self.pos += key_size as u64;
+        let seq = self.read_u64()?;
+        Ok(Some((&self.key, seq)))
+    }
+
+    /// Decode the current entry into the value scratch buffer
+    pub fn read_entry(&mut self) -> io::Result<EntryRef<'_>> {
+        let (kind, stamp) = self.decode_entry()?;
+        Ok(self.entry_ref(kind, stamp))
+    }
+
+    /// Read the current entry's kind and timestamp, the deletion time of a
+    /// tombstone or the expiry of an expiring value, and any value into the
+    /// scratch buffer
+    fn decode_entry(&mut self) -> io::Result<(u8, u64)> {
+        let kind = self.read_kind()?;
+        if kind == ENTRY_TOMBSTONE {
+            return Ok((kind, self.read_u64()?));
+        }
+        let stamp = match kind {
+            ENTRY_EXPIRING => self.read_u64()?,
+            _ => 0,
+        };
+        let size = self.read_length()?;
+        Self::read_exact_into(&mut self.reader, &mut self.value, size)
+            .map_err(damaged(&self.file, self.pos))?;
+        self.pos += size as u64;
+        Ok((kind, stamp))
+    }
+
+    fn entry_ref(&self, kind: u8, stamp: u64) -> EntryRef<'_> {
+        match kind {
+            ENTRY_TOMBSTONE => EntryRef::Tombstone { deleted_at: stamp },
+            ENTRY_EXPIRING => EntryRef::Expiring {
+                value: &self.value,
+                expires_at: stamp,
+            },
+            ENTRY_MERGE => EntryRef::Merge(&self.value),
+            _ => EntryRef::Value(&self.value),
+        }
+    }
+
+    /// Copy the current entry's value into `out`, reusing its allocation,
+    /// or report that the entry is a tombstone
+    pub fn read_entry_into(&mut self, out: &mut Vec<u8>) -> io::Result<Lookup> {
+        let lookup = match self.read_kind()? {
+            ENTRY_TOMBSTONE => {
+                self.read_u64()?;
+                return Ok(Lookup::Deleted);
+            }
+            ENTRY_EXPIRING => Lookup::Expiring(self.read_u64()?),
+            ENTRY_MERGE => Lookup::Merge,
+            _ => Lookup::Found,
+        };
+        let size = self.read_length()?;
+        Self::read_exact_into(&mut self.reader, out, size)
+            .map_err(damaged(&self.file, self.pos))?;
+        self.pos += size as u64;
+        Ok(lookup)
+    }
+
+    /// Skip over the current entry without reading its value
+    pub fn skip_entry(&mut self) -> io::Result<()> {
+        self.skip_value(0)?;
+        Ok(())
+    }
+
+    /// Skip over the current entry, reporting whether it holds a value
+    /// that hasn't expired by `now` or a merge operand, rather than a
+    /// tombstone
+    pub fn skip_value(&mut self, now: u64) -> io::Result<bool> {
+        let (size, live) = match self.read_kind()? {
+            ENTRY_VALUE | ENTRY_MERGE => (self.read_length()?, true),
+            ENTRY_EXPIRING => {
+                let live = self.read_u64()? > now;
+                (self.read_length()?, live)
+            }
+            _ => (8, false),
+        };
+        self.reader
+            .skip(size)
+            .map_err(damaged(&self.file, self.pos))?;
+        self.pos += size as u64;
+        Ok(live)
+    }
+
+    fn read_kind(&mut self) -> io::Result<u8> {
+        let mut kind = [0u8; 1];
+        self.reader
+            .read_exact(&mut kind)
+            .map_err(damaged(&self.file, self.pos))?;
+        self.pos += 1;
+        match kind[0] {
+            ENTRY_VALUE | ENTRY_TOMBSTONE | ENTRY_EXPIRING | ENTRY_MERGE => Ok(kind[0]),
+            other => Err(error::corruption(
+                &self.file,
+                self.pos - 1,
+                format!("invalid entry kind {}", other),
+            )),
+        }
+    }
+
+    fn read_u64(&mut self) -> io::Result<u64> {
+        let mut bytes = [0u8; 8];
+        self.reader
+            .read_exact(&mut bytes)
+            .map_err(damaged(&self.file, self.pos))?;
+        self.pos += 8;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn at_end(&mut self) -> io::Result<bool> {
+        let pos = self.pos;
+        if pos >= self.len {
+            return Ok(true);
+        }
+        let buffered = self.reader.fill_buf().map_err(damaged(&self.file, pos))?;
+        Ok(buffered.is_empty())
+    }
+
+    /// Read a length prefix, rejecting lengths that run past the end of the
+    /// data section
+    fn read_length(&mut self) -> io::Result<usize> {
+        let mut size_bytes = [0u8; 4];
+        self.reader
+            .read_exact(&mut size_bytes)
+            .map_err(damaged(&self.file, self.pos))?;
+        self.pos += 4;
+        let size = u32::from_le_bytes(size_bytes) as u64;
+        if size > self.len.saturating_sub(self.pos) {
+            return Err(error::corruption(
+                &self.file,
+                self.pos - 4,
+                format!("entry length {} exceeds data section", size),
+            ));
+        }
+        Ok(size as usize)
+    }
+
+    fn read_exact_into(reader: &mut Source, buf: &mut Vec<u8>, size: usize) -> io::Result<()> {
+        buf.clear();
+        buf.resize(size, 0);
+        reader.read_exact(buf)
+    }
+}
+
+/// Report a read at offset `pos` that ran off the end of the file or hit a
+/// block that can't be restored as damage to `file` rather than a plain
+/// I/O error
+fn damaged(file: &Path, pos: u64) -> impl FnOnce(io::Error) -> io::Error + '_ {
+    move |e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => error::corruption(file, pos, "entry cut short"),
+        _ => error::locate(e, file, pos),
+    }
+}
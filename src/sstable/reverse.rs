@@ -0,0 +1,200 @@
+use super::{EntryReader, ReadPath, SSTable};
+use crate::entry::Version;
+use crate::error::StorageError;
+use crate::Key;
+use std::io;
+use std::sync::Arc;
+
+/// Reads a table's versions in a key range back to front: keys in
+/// descending order, each key's versions still newest first.
+///
+/// Index points never split a key's versions, so a table with an index is
+/// read one data block at a time, from the last block overlapping the range
+/// towards the first. Tables written before the index are read whole when
+/// the reader is opened.
+pub struct ReverseReader {
+    table: Arc<SSTable>,
+    start: Key,
+    end: Option<Key>,
+    // Blocks left to read as `[start, end)` offsets, the next one last
+    blocks: Vec<(u64, u64)>,
+    // Versions of the block being read, the next one last
+    pending: Vec<(Key, Version)>,
+    bytes_read: u64,
+}
+
+impl ReverseReader {
+    pub(super) fn new(table: Arc<SSTable>, start: &[u8], end: Option<&[u8]>) -> io::Result<Self> {
+        let blocks = match &table.index {
+            Some(index) => {
+                let blocks: Vec<_> = index.blocks().collect();
+                // A block holds keys from its first key up to the next block's
+                (0..blocks.len())
+                    .filter(|&i| {
+                        let (first, _, _) = blocks[i];
+                        let below_end = end.is_none_or(|end| first < end);
+                        let reaches_start = blocks.get(i + 1).is_none_or(|next| next.0 > start);
+                        below_end && reaches_start
+                    })
+                    .map(|i| (blocks[i].1, blocks[i].2))
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+        let mut reader = ReverseReader {
+            start: start.to_vec(),
+            end: end.map(<[u8]>::to_vec),
+            blocks,
+            pending: Vec::new(),
+            bytes_read: 0,
+            table,
+        };
+        if reader.table.index.is_none() {
+            let mut entries = reader.table.entries()?;
+            reader.stage(&mut entries)?;
+            reader.bytes_read = entries.bytes_read();
+        }
+        Ok(reader)
+    }
+
+    /// Bytes of the data section read so far, as stored on disk
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// The next version, by descending key and then newest first
+    pub fn next_version(&mut self) -> io::Result<Option<(Key, Version)>> {
+        loop {
+            if let Some(version) = self.pending.pop() {
+                return Ok(Some(version));
+            }
+            let Some((start, end)) = self.blocks.pop() else {
+                return Ok(None);
+            };
+            let block = self.table.load_block(&ReadPath::Std, start, end)?;
+            let mut entries = EntryReader::cached(block, start).in_file(self.table.path.clone());
+            self.stage(&mut entries)?;
+            self.bytes_read += end - start;
+        }
+    }
+
+    /// Queue the versions `entries` hold within the range, ordered so that
+    /// popping them goes down the keys and, within a key, from the newest
+    fn stage(&mut self, entries: &mut EntryReader) -> io::Result<()> {
+        while let Some((key, seq, entry)) = entries.next_entry()? {
+            let in_range =
+                key >= self.start.as_slice() && self.end.as_deref().is_none_or(|end| key < end);
+            if in_range {
+                self.pending
+                    .push((key.to_vec(), Version::new(seq, entry.to_entry())));
+            }
+        }
+        self.pending
+            .sort_by(|(a, x), (b, y)| a.cmp(b).then(x.seq.cmp(&y.seq)));
+        Ok(())
+    }
+}
+
+impl SSTable {
+    /// Read the versions in `[start, end)` back to front; a missing `end`
+    /// runs to the last key
+    pub fn entries_rev(
+        self: &Arc<Self>,
+        start: &[u8],
+        end: Option<&[u8]>,
+    ) -> Result<ReverseReader, StorageError> {
+        Ok(ReverseReader::new(self.clone(), start, end)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::Entry;
+    use crate::sstable::Compression;
+    use tempfile::TempDir;
+
+    fn key(i: usize) -> Key {
+        format!("key{:04}", i).into_bytes()
+    }
+
+    /// Three versions of every even key below 200, newest first
+    fn versions() -> Vec<(Key, Version)> {
+        (0..200)
+            .step_by(2)
+            .flat_map(|i| {
+                (0..3).rev().map(move |seq| {
+                    let entry = match seq {
+                        1 if i % 10 == 0 => Entry::Tombstone { deleted_at: 0 },
+                        _ => Entry::Value(format!("{}-{}", i, seq).into_bytes()),
+                    };
+                    (key(i), Version::new(seq, entry))
+                })
+            })
+            .collect()
+    }
+
+    fn read_all(reader: &mut ReverseReader) -> Vec<(Key, Version)> {
+        let mut read = Vec::new();
+        while let Some(version) = reader.next_version().unwrap() {
+            read.push(version);
+        }
+        read
+    }
+
+    /// `versions` within `[start, end)`, descending by key with each key's
+    /// versions still newest first
+    fn expected(start: usize, end: usize) -> Vec<(Key, Version)> {
+        let mut expected: Vec<_> = versions()
+            .into_iter()
+            .filter(|(k, _)| *k >= key(start) && *k < key(end))
+            .collect();
+        expected.sort_by(|(a, x), (b, y)| b.cmp(a).then(y.seq.cmp(&x.seq)));
+        expected
+    }
+
+    #[test]
+    fn test_reads_blocks_back_to_front() {
+        let temp_dir = TempDir::new().unwrap();
+        let codecs = [Compression::None, Compression::Snappy, Compression::Lz4];
+        for compression in codecs.into_iter().filter(|codec| codec.is_supported()) {
+            let path = temp_dir.path().join(format!("{:?}.sst", compression));
+            let mut table = SSTable::new(path).unwrap().with_compression(compression);
+            table.write_entries(&versions()).unwrap();
+            let table = Arc::new(table);
+
+            let mut reader = table.entries_rev(b"", None).unwrap();
+            assert_eq!(read_all(&mut reader), expected(0, 200));
+
+            // Bounds between keys and on them; only overlapping blocks are read
+            let mut reader = table.entries_rev(&key(51), Some(&key(60))).unwrap();
+            assert_eq!(read_all(&mut reader), expected(51, 60));
+            assert!(reader.bytes_read() < table.size() as u64 / 4);
+            let mut reader = table.entries_rev(&key(150), Some(&key(150))).unwrap();
+            assert!(reader.next_version().unwrap().is_none());
+            let mut reader = table.entries_rev(&key(300), None).unwrap();
+            assert!(reader.next_version().unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_tables_without_an_index_are_read_whole() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("legacy.sst");
+        let mut table = SSTable::new(path.clone()).unwrap();
+        table.write_entries(&versions()).unwrap();
+        // Cut the index and footer off, leaving the layout of older tables
+        let data_end = table.index.as_ref().unwrap().data_end();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(data_end)
+            .unwrap();
+        let table = Arc::new(SSTable::new(path).unwrap());
+        assert!(table.index.is_none());
+
+        let mut reader = table.entries_rev(&key(10), Some(&key(20))).unwrap();
+        assert_eq!(read_all(&mut reader), expected(10, 20));
+    }
+}
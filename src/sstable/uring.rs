@@ -0,0 +1,294 @@
+use io_uring::{opcode, types, IoUring};
+use log::warn;
+use std::fs::File;
+use std::io::{self, BufRead, Read};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+// Bytes fetched per positional read by a `UringReader`
+const READ_SIZE: usize = 64 * 1024;
+
+/// A positional read to submit to a [`Ring`]
+pub struct ReadAt<'a> {
+    pub file: &'a File,
+    pub offset: u64,
+    pub buf: &'a mut [u8],
+}
+
+/// An io_uring shared by every reader of a `Storage`.
+///
+/// One thread submits through it at a time. A batch waits its turn, but a
+/// single read that finds the ring busy is served by a plain `pread`
+/// instead, so concurrent point reads never queue behind one another.
+pub struct Ring {
+    ring: Mutex<IoUring>,
+    entries: usize,
+    // Set once a wait fails with reads possibly still in flight; the ring
+    // is left alone from then on and everything goes through `pread`
+    broken: AtomicBool,
+}
+
+impl Ring {
+    /// Set up a ring with room for `entries` reads in flight
+    pub fn new(entries: u32) -> io::Result<Self> {
+        Ok(Ring {
+            ring: Mutex::new(IoUring::new(entries)?),
+            entries: entries as usize,
+            broken: AtomicBool::new(false),
+        })
+    }
+
+    pub fn read_at(&self, file: &File, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let mut ring = match self.ring.try_lock() {
+            Ok(ring) if !self.broken.load(Ordering::Relaxed) => ring,
+            _ => return file.read_at(buf, offset),
+        };
+        self.submit(&mut ring, &mut [ReadAt { file, offset, buf }])
+            .pop()
+            .unwrap()
+    }
+
+    /// Submit every read at once, as many as fit in the ring per round, and
+    /// return their results in order
+    pub fn read_batch(&self, reads: &mut [ReadAt<'_>]) -> Vec<io::Result<usize>> {
+        let mut results: Vec<io::Result<usize>> = Vec::with_capacity(reads.len());
+        // A panic can't leave the ring itself inconsistent: a round either
+        // completes or marks it broken
+        let mut ring = self.ring.lock().unwrap_or_else(PoisonError::into_inner);
+        for batch in reads.chunks_mut(self.entries) {
+            if self.broken.load(Ordering::Relaxed) {
+                results.extend(batch.iter_mut().map(pread));
+            } else {
+                results.extend(self.submit(&mut ring, batch));
+            }
+        }
+        results
+    }
+
+    /// Read `batch`, no larger than the ring, through it. The kernel reads
+    /// into buffers owned here and copied out on completion, so that if
+    /// waiting fails with reads still in flight, leaking those buffers
+    /// keeps the callers' memory safe.
+    fn submit(&self, ring: &mut IoUring, batch: &mut [ReadAt<'_>]) -> Vec<io::Result<usize>> {
+        let mut bufs: Vec<Vec<u8>> = batch.iter().map(|read| vec![0; read.buf.len()]).collect();
+        for (i, (read, buf)) in batch.iter().zip(&mut bufs).enumerate() {
+            let entry = opcode::Read::new(
+                types::Fd(read.file.as_raw_fd()),
+                buf.as_mut_ptr(),
+                buf.len() as u32,
+            )
+            .offset(read.offset)
+            .build()
+            .user_data(i as u64);
+            // SAFETY: `bufs` is only dropped once every read pushed here
+            // has completed, and leaked otherwise; the kernel holds its own
+            // reference to each file
+            if unsafe { ring.submission().push(&entry) }.is_err() {
+                // Every round drains the ring, so it can only be full if
+                // something went wrong with it
+                return self.abandon(bufs, batch, io::Error::other("submission queue full"));
+            }
+        }
+
+        let mut results: Vec<Option<io::Result<usize>>> = (0..batch.len()).map(|_| None).collect();
+        let mut pending = batch.len();
+        while pending > 0 {
+            match ring.submit_and_wait(pending) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return self.abandon(bufs, batch, e),
+            }
+            for cqe in ring.completion() {
+                let result = cqe.result();
+                results[cqe.user_data() as usize] = Some(if result < 0 {
+                    Err(io::Error::from_raw_os_error(-result))
+                } else {
+                    Ok(result as usize)
+                });
+                pending -= 1;
+            }
+        }
+
+        batch
+            .iter_mut()
+            .zip(bufs)
+            .zip(results)
+            .map(|((read, buf), result)| {
+                let n = result.unwrap()?;
+                read.buf[..n].copy_from_slice(&buf[..n]);
+                Ok(n)
+            })
+            .collect()
+    }
+
+    /// Give up on the ring after `e`, leaking `bufs` since reads may still
+    /// land in them, and serve `batch` with `pread` instead
+    fn abandon(
+        &self,
+        bufs: Vec<Vec<u8>>,
+        batch: &mut [ReadAt<'_>],
+        e: io::Error,
+    ) -> Vec<io::Result<usize>> {
+        warn!("io_uring failed, reading with pread from now on: {}", e);
+        self.broken.store(true, Ordering::Relaxed);
+        std::mem::forget(bufs);
+        batch.iter_mut().map(pread).collect()
+    }
+}
+
+fn pread(read: &mut ReadAt<'_>) -> io::Result<usize> {
+    read.file.read_at(read.buf, read.offset)
+}
+
+/// Streams a file from a starting offset using positional reads on a [`Ring`]
+pub struct UringReader {
+    ring: Arc<Ring>,
+    file: File,
+    offset: u64,
+    buf: Vec<u8>,
+    pos: usize,
+    filled: usize,
+}
+
+impl UringReader {
+    pub fn new(ring: Arc<Ring>, file: File, offset: u64) -> Self {
+        UringReader {
+            ring,
+            file,
+            offset,
+            buf: vec![0; READ_SIZE],
+            pos: 0,
+            filled: 0,
+        }
+    }
+
+    /// Advance past `n` bytes; whatever isn't buffered is never read
+    pub fn skip(&mut self, n: usize) {
+        let buffered = self.filled - self.pos;
+        if n <= buffered {
+            self.pos += n;
+        } else {
+            self.offset += (n - buffered) as u64;
+            self.pos = self.filled;
+        }
+    }
+}
+
+impl Read for UringReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(out.len());
+        out[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for UringReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.filled {
+            self.filled = self.ring.read_at(&self.file, self.offset, &mut self.buf)?;
+            self.offset += self.filled as u64;
+            self.pos = 0;
+        }
+        Ok(&self.buf[self.pos..self.filled])
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.pos = (self.pos + n).min(self.filled);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_read_batch_larger_than_ring() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("data");
+        let data: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        let file = File::open(&path).unwrap();
+
+        let ring = Ring::new(4).unwrap();
+        let mut bufs = vec![[0u8; 100]; 10];
+        let mut reads: Vec<_> = bufs
+            .iter_mut()
+            .enumerate()
+            .map(|(i, buf)| ReadAt {
+                file: &file,
+                offset: i as u64 * 997,
+                buf,
+            })
+            .collect();
+        for result in ring.read_batch(&mut reads) {
+            assert_eq!(result.unwrap(), 100);
+        }
+        for (i, buf) in bufs.iter().enumerate() {
+            let offset = i * 997;
+            assert_eq!(&buf[..], &data[offset..offset + 100]);
+        }
+
+        // Reads at the end of the file come back short
+        let mut buf = [0u8; 100];
+        assert_eq!(ring.read_at(&file, 9_950, &mut buf).unwrap(), 50);
+    }
+
+    #[test]
+    fn test_busy_ring_reads_with_pread() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("data");
+        std::fs::write(&path, b"hello world").unwrap();
+        let file = File::open(&path).unwrap();
+
+        let ring = Ring::new(4).unwrap();
+        let held = ring.ring.lock().unwrap();
+        let mut buf = [0u8; 5];
+        assert_eq!(ring.read_at(&file, 6, &mut buf).unwrap(), 5);
+        assert_eq!(&buf, b"world");
+        drop(held);
+
+        ring.broken.store(true, Ordering::Relaxed);
+        let mut bufs = [[0u8; 5]; 2];
+        let [first, second] = &mut bufs;
+        let mut reads = [
+            ReadAt {
+                file: &file,
+                offset: 0,
+                buf: first,
+            },
+            ReadAt {
+                file: &file,
+                offset: 6,
+                buf: second,
+            },
+        ];
+        for result in ring.read_batch(&mut reads) {
+            assert_eq!(result.unwrap(), 5);
+        }
+        assert_eq!(&bufs, &[*b"hello", *b"world"]);
+    }
+
+    #[test]
+    fn test_reader_skips_without_reading() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("data");
+        let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+
+        let ring = Arc::new(Ring::new(8).unwrap());
+        let mut reader = UringReader::new(ring, File::open(&path).unwrap(), 10);
+        let mut head = [0u8; 5];
+        reader.read_exact(&mut head).unwrap();
+        assert_eq!(&head, &data[10..15]);
+
+        reader.skip(100_000);
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, &data[100_015..]);
+    }
+}
@@ -0,0 +1,421 @@
+use crate::sstable::TableProperties;
+use crate::Key;
+use std::io;
+use std::path::PathBuf;
+
+/// Number of log2 buckets: bucket 0 holds empty items and bucket `i` holds
+/// sizes in `[2^(i-1), 2^i)`, which covers every length that fits in a u32.
+pub const HISTOGRAM_BUCKETS: usize = 33;
+
+/// A cheap, log-bucketed histogram of byte sizes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SizeHistogram {
+    buckets: [u64; HISTOGRAM_BUCKETS],
+    count: u64,
+    total: u64,
+    min: u64,
+    max: u64,
+}
+
+impl SizeHistogram {
+    pub fn new() -> Self {
+        SizeHistogram {
+            buckets: [0; HISTOGRAM_BUCKETS],
+            count: 0,
+            total: 0,
+            min: 0,
+            max: 0,
+        }
+    }
+
+    /// Index of the bucket a size falls into
+    pub fn bucket_for(size: u64) -> usize {
+        let bits = (u64::BITS - size.leading_zeros()) as usize;
+        bits.min(HISTOGRAM_BUCKETS - 1)
+    }
+
+    /// Record a single observed size
+    pub fn record(&mut self, size: usize) {
+        let size = size as u64;
+        self.buckets[Self::bucket_for(size)] += 1;
+        self.min = if self.count == 0 {
+            size
+        } else {
+            self.min.min(size)
+        };
+        self.max = self.max.max(size);
+        self.count += 1;
+        self.total += size;
+    }
+
+    /// Fold another histogram into this one
+    pub fn merge(&mut self, other: &SizeHistogram) {
+        if other.count == 0 {
+            return;
+        }
+        for (bucket, count) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *bucket += count;
+        }
+        self.min = if self.count == 0 {
+            other.min
+        } else {
+            self.min.min(other.min)
+        };
+        self.max = self.max.max(other.max);
+        self.count += other.count;
+        self.total += other.total;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn min(&self) -> u64 {
+        self.min
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.total as f64 / self.count as f64
+        }
+    }
+
+    pub fn buckets(&self) -> &[u64; HISTOGRAM_BUCKETS] {
+        &self.buckets
+    }
+
+    /// Serialize as `[count][total][min][max][bucket_count][buckets...]`,
+    /// trimming trailing empty buckets
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let used = self
+            .buckets
+            .iter()
+            .rposition(|&b| b != 0)
+            .map_or(0, |i| i + 1);
+
+        let mut bytes = Vec::with_capacity(33 + used * 8);
+        bytes.extend_from_slice(&self.count.to_le_bytes());
+        bytes.extend_from_slice(&self.total.to_le_bytes());
+        bytes.extend_from_slice(&self.min.to_le_bytes());
+        bytes.extend_from_slice(&self.max.to_le_bytes());
+        bytes.push(used as u8);
+        for bucket in &self.buckets[..used] {
+            bytes.extend_from_slice(&bucket.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Deserialize a histogram, returning it along with the number of bytes consumed
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<(Self, usize)> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid size histogram");
+        let read_u64 = |pos: usize| -> io::Result<u64> {
+            bytes
+                .get(pos..pos + 8)
+                .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+                .ok_or_else(invalid)
+        };
+
+        let mut histogram = SizeHistogram::new();
+        histogram.count = read_u64(0)?;
+        histogram.total = read_u64(8)?;
+        histogram.min = read_u64(16)?;
+        histogram.max = read_u64(24)?;
+
+        let used = *bytes.get(32).ok_or_else(invalid)? as usize;
+        if used > HISTOGRAM_BUCKETS {
+            return Err(invalid());
+        }
+        let mut pos = 33;
+        for bucket in histogram.buckets.iter_mut().take(used) {
+            *bucket = read_u64(pos)?;
+            pos += 8;
+        }
+
+        Ok((histogram, pos))
+    }
+}
+
+impl Default for SizeHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Per-level summary built from SSTable metadata
+#[derive(Debug, Clone)]
+pub struct LevelStats {
+    pub level: usize,
+    pub file_count: usize,
+    pub total_bytes: usize,
+    /// Memory held by the level's bloom filters
+    pub filter_bytes: usize,
+    pub properties: TableProperties,
+}
+
+/// Metadata for a single SSTable, read from its properties
+#[derive(Debug, Clone)]
+pub struct SstFileInfo {
+    pub path: PathBuf,
+    pub file_number: u64,
+    pub size: usize,
+    /// Smallest and largest key, or `None` for an empty table
+    pub key_range: Option<(Key, Key)>,
+    pub entry_count: u64,
+}
+
+/// Cumulative activity counters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counters {
+    /// Values written by `put`, batches and direct writes
+    pub puts: u64,
+    /// Tombstones written by `delete`, batches and direct writes
+    pub deletes: u64,
+    pub flushes: u64,
+    /// Bytes of level 0 tables written by flushes
+    pub bytes_flushed: u64,
+    pub compactions: u64,
+    /// Bytes of tables written by compactions
+    pub bytes_compacted: u64,
+    /// Time compactions spent merging, summed over jobs that ran side by
+    /// side
+    pub compaction_ms: u64,
+    /// Keys looked up through `get`, `get_at` and `multi_get`
+    pub gets: u64,
+    /// Tables a lookup skipped because their bloom filter ruled the key out
+    pub bloom_negatives: u64,
+    /// Tables whose bloom filter passed a key they didn't hold
+    pub bloom_false_positives: u64,
+    /// Writes delayed because L0 reached `l0_slowdown_files`
+    pub write_slowdowns: u64,
+    /// Writes held until compaction brought L0 under `l0_stop_files`
+    pub write_stops: u64,
+    /// Time writes spent delayed or held
+    pub write_stall_ms: u64,
+}
+
+impl Counters {
+    /// Serialize as `name=value` lines
+    pub fn to_text(self) -> String {
+        self.fields()
+            .iter()
+            .map(|(name, value)| format!("{}={}\n", name, value))
+            .collect()
+    }
+
+    /// Parse what `to_text` wrote. Unknown names are ignored and missing
+    /// ones left at zero, so files from other versions still load.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut counters = Counters::default();
+        for line in text.lines().filter(|line| !line.is_empty()) {
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid stats line {:?}", line),
+                )
+            };
+            let (name, value) = line.split_once('=').ok_or_else(invalid)?;
+            let value: u64 = value.parse().map_err(|_| invalid())?;
+            let field = match name {
+                "puts" => &mut counters.puts,
+                "deletes" => &mut counters.deletes,
+                "flushes" => &mut counters.flushes,
+                "bytes_flushed" => &mut counters.bytes_flushed,
+                "compactions" => &mut counters.compactions,
+                "bytes_compacted" => &mut counters.bytes_compacted,
+                "compaction_ms" => &mut counters.compaction_ms,
+                "gets" => &mut counters.gets,
+                "bloom_negatives" => &mut counters.bloom_negatives,
+                "bloom_false_positives" => &mut counters.bloom_false_positives,
+                "write_slowdowns" => &mut counters.write_slowdowns,
+                "write_stops" => &mut counters.write_stops,
+                "write_stall_ms" => &mut counters.write_stall_ms,
+                _ => continue,
+            };
+            *field = value;
+        }
+        Ok(counters)
+    }
+
+    /// Bytes compactions wrote per second of merging
+    pub fn compaction_throughput(self) -> u64 {
+        self.bytes_compacted * 1000 / self.compaction_ms.max(1)
+    }
+
+    fn fields(self) -> [(&'static str, u64); 13] {
+        [
+            ("puts", self.puts),
+            ("deletes", self.deletes),
+            ("flushes", self.flushes),
+            ("bytes_flushed", self.bytes_flushed),
+            ("compactions", self.compactions),
+            ("bytes_compacted", self.bytes_compacted),
+            ("compaction_ms", self.compaction_ms),
+            ("gets", self.gets),
+            ("bloom_negatives", self.bloom_negatives),
+            ("bloom_false_positives", self.bloom_false_positives),
+            ("write_slowdowns", self.write_slowdowns),
+            ("write_stops", self.write_stops),
+            ("write_stall_ms", self.write_stall_ms),
+        ]
+    }
+}
+
+impl std::ops::Add for Counters {
+    type Output = Counters;
+
+    fn add(self, other: Counters) -> Counters {
+        Counters {
+            puts: self.puts + other.puts,
+            deletes: self.deletes + other.deletes,
+            flushes: self.flushes + other.flushes,
+            bytes_flushed: self.bytes_flushed + other.bytes_flushed,
+            compactions: self.compactions + other.compactions,
+            bytes_compacted: self.bytes_compacted + other.bytes_compacted,
+            compaction_ms: self.compaction_ms + other.compaction_ms,
+            gets: self.gets + other.gets,
+            bloom_negatives: self.bloom_negatives + other.bloom_negatives,
+            bloom_false_positives: self.bloom_false_positives + other.bloom_false_positives,
+            write_slowdowns: self.write_slowdowns + other.write_slowdowns,
+            write_stops: self.write_stops + other.write_stops,
+            write_stall_ms: self.write_stall_ms + other.write_stall_ms,
+        }
+    }
+}
+
+/// Activity of the block cache serving point lookups
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Lookups whose block was already cached
+    pub hits: u64,
+    /// Lookups that read their block from disk
+    pub misses: u64,
+    /// Bytes of blocks held
+    pub bytes: u64,
+    pub capacity: u64,
+}
+
+/// A point-in-time snapshot of storage metrics
+#[derive(Debug, Clone)]
+pub struct StorageStats {
+    /// Key sizes accepted by `put` since this instance was opened
+    pub written_key_sizes: SizeHistogram,
+    /// Value sizes accepted by `put` since this instance was opened
+    pub written_value_sizes: SizeHistogram,
+    /// Bytes and entries buffered in memory, including a memtable still
+    /// being flushed
+    pub memtable_bytes: u64,
+    pub memtable_entries: u64,
+    /// Bytes of every WAL segment still on disk
+    pub wal_bytes: u64,
+    /// Properties of every live SSTable combined
+    pub stored: TableProperties,
+    /// Per-level breakdown, ordered by level
+    pub levels: Vec<LevelStats>,
+    /// SSTable reads that failed and were skipped under `best_effort_reads`
+    pub skipped_reads: u64,
+    /// `posix_fadvise` hints issued, counted across the whole process
+    pub fadvise_calls: u64,
+    /// Event journal writes that failed since this instance was opened
+    pub event_log_errors: u64,
+    /// Activity over the data directory's whole life, carried across
+    /// restarts in its `STATS` file
+    pub lifetime: Counters,
+    /// Activity since this instance was opened, for computing rates
+    pub since_open: Counters,
+    /// Block cache activity since this instance was opened; all zero when
+    /// the cache is off
+    pub block_cache: CacheStats,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_boundaries() {
+        assert_eq!(SizeHistogram::bucket_for(0), 0);
+        assert_eq!(SizeHistogram::bucket_for(1), 1);
+        assert_eq!(SizeHistogram::bucket_for(2), 2);
+        assert_eq!(SizeHistogram::bucket_for(3), 2);
+        assert_eq!(SizeHistogram::bucket_for(4), 3);
+        assert_eq!(SizeHistogram::bucket_for(1024), 11);
+        assert_eq!(SizeHistogram::bucket_for(u64::MAX), HISTOGRAM_BUCKETS - 1);
+    }
+
+    #[test]
+    fn test_record_and_merge() {
+        let mut a = SizeHistogram::new();
+        a.record(10);
+        a.record(20);
+
+        let mut b = SizeHistogram::new();
+        b.record(5);
+        b.record(4096);
+
+        a.merge(&b);
+        assert_eq!(a.count(), 4);
+        assert_eq!(a.total(), 4131);
+        assert_eq!(a.min(), 5);
+        assert_eq!(a.max(), 4096);
+        assert_eq!(a.buckets()[SizeHistogram::bucket_for(4096)], 1);
+
+        // Merging an empty histogram must not reset the minimum
+        a.merge(&SizeHistogram::new());
+        assert_eq!(a.min(), 5);
+    }
+
+    #[test]
+    fn test_counters_round_trip() {
+        let counters = Counters {
+            puts: 20,
+            deletes: 5,
+            flushes: 3,
+            bytes_flushed: 4096,
+            compactions: 1,
+            bytes_compacted: 8192,
+            compaction_ms: 4,
+            gets: 100,
+            bloom_negatives: 7,
+            bloom_false_positives: 2,
+            write_slowdowns: 6,
+            write_stops: 1,
+            write_stall_ms: 30,
+        };
+        assert_eq!(Counters::parse(&counters.to_text()).unwrap(), counters);
+        assert_eq!(
+            Counters::parse("gets=5\nfuture=1\n").unwrap(),
+            Counters {
+                gets: 5,
+                ..Default::default()
+            }
+        );
+        assert!(Counters::parse("gets=many\n").is_err());
+        assert_eq!((counters + counters).gets, 200);
+        assert_eq!(counters.compaction_throughput(), 2_048_000);
+    }
+
+    #[test]
+    fn test_serialization_round_trip() {
+        let mut histogram = SizeHistogram::new();
+        for size in [0, 1, 7, 100, 65536] {
+            histogram.record(size);
+        }
+
+        let bytes = histogram.to_bytes();
+        let (restored, consumed) = SizeHistogram::from_bytes(&bytes).unwrap();
+        assert_eq!(restored, histogram);
+        assert_eq!(consumed, bytes.len());
+
+        assert!(SizeHistogram::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,276 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::identity::IDENTITY_FILE;
+use super::{sync_dir, Storage};
+use crate::error::StorageError;
+
+/// Marks the start of a backup archive
+const ARCHIVE_MAGIC: &[u8; 8] = b"LSMARCHV";
+/// Archive layout this build writes; older ones are still read
+const ARCHIVE_VERSION: u32 = 1;
+// Bytes copied per read while packing or unpacking a file
+const COPY_BUFFER: usize = 64 * 1024;
+
+// Layout: [magic][version], then per file [name_len u32][name][len u64]
+// [crc32 u32][bytes], then a trailer of [0u32][file_count u64]. A missing
+// trailer means the archive was cut short.
+
+impl Storage {
+    /// Stream a consistent checkpoint of the database into `w` as a single
+    /// archive; see [`restore_from_archive`](Storage::restore_from_archive).
+    /// The memtable is flushed first, and the tables being packed are held
+    /// open so a compaction can't remove them midway. Returns the files
+    /// written.
+    pub fn backup_to_archive<W: Write>(&self, mut w: W) -> Result<usize, StorageError> {
+        self.options.require_local_tables("An archive backup")?;
+        self.flush_memtable(&mut self.writer())?;
+        let tables: Vec<_> = self.levels().sstables.values().flatten().cloned().collect();
+
+        w.write_all(ARCHIVE_MAGIC)?;
+        w.write_all(&ARCHIVE_VERSION.to_le_bytes())?;
+        write_file(&mut w, IDENTITY_FILE, &self.data_dir.join(IDENTITY_FILE))?;
+        for table in &tables {
+            let path = table.get_path();
+            let name = path.file_name().and_then(|n| n.to_str()).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Table path {:?} has no usable file name", path),
+                )
+            })?;
+            write_file(&mut w, name, path)?;
+        }
+
+        let count = tables.len() + 1;
+        w.write_all(&0u32.to_le_bytes())?;
+        w.write_all(&(count as u64).to_le_bytes())?;
+        w.flush()?;
+        Ok(count)
+    }
+
+    /// Unpack an archive from [`backup_to_archive`](Storage::backup_to_archive)
+    /// into `dest`, which must be missing or empty, checking every file
+    /// against its checksum. On any error, including a truncated archive,
+    /// the files unpacked so far are removed again. Returns the files
+    /// restored.
+    pub fn restore_from_archive<R: Read, P: AsRef<Path>>(
+        r: R,
+        dest: P,
+    ) -> Result<usize, StorageError> {
+        let dest = dest.as_ref();
+        if dest.exists() && fs::read_dir(dest)?.next().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Restore directory {:?} is not empty", dest),
+            )
+            .into());
+        }
+        fs::create_dir_all(dest)?;
+
+        let mut restored = Vec::new();
+        let result = unpack(r, dest, &mut restored);
+        if result.is_err() {
+            for path in &restored {
+                let _ = fs::remove_file(path);
+            }
+        }
+        Ok(result?)
+    }
+}
+
+fn write_file<W: Write>(w: &mut W, name: &str, path: &Path) -> io::Result<()> {
+    // Checksum first so the header can precede the bytes without buffering
+    // the whole file
+    let mut hasher = crc32fast::Hasher::new();
+    let mut file = File::open(path)?;
+    let mut buf = vec![0u8; COPY_BUFFER];
+    let mut len = 0u64;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        len += n as u64;
+    }
+
+    w.write_all(&(name.len() as u32).to_le_bytes())?;
+    w.write_all(name.as_bytes())?;
+    w.write_all(&len.to_le_bytes())?;
+    w.write_all(&hasher.finalize().to_le_bytes())?;
+    // Tables are immutable, so a second pass sees the same bytes
+    let copied = io::copy(&mut File::open(path)?.take(len), w)?;
+    if copied != len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("{:?} shrank while being archived", path),
+        ));
+    }
+    Ok(())
+}
+
+fn unpack<R: Read>(mut r: R, dest: &Path, restored: &mut Vec<PathBuf>) -> io::Result<usize> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let truncated = |e: io::Error| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            invalid("Archive is truncated".to_string())
+        } else {
+            e
+        }
+    };
+
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic).map_err(truncated)?;
+    if &magic != ARCHIVE_MAGIC {
+        return Err(invalid("Not a backup archive".to_string()));
+    }
+    let version = read_u32(&mut r).map_err(truncated)?;
+    if version > ARCHIVE_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "Archive version {} is newer than this build supports ({})",
+                version, ARCHIVE_VERSION
+            ),
+        ));
+    }
+
+    loop {
+        let name_len = read_u32(&mut r).map_err(truncated)? as usize;
+        if name_len == 0 {
+            let count = read_u64(&mut r).map_err(truncated)?;
+            if count != restored.len() as u64 {
+                return Err(invalid(format!(
+                    "Archive lists {} files but holds {}",
+                    count,
+                    restored.len()
+                )));
+            }
+            break;
+        }
+
+        let mut name = vec![0u8; name_len];
+        r.read_exact(&mut name).map_err(truncated)?;
+        let name = String::from_utf8(name)
+            .ok()
+            .filter(|name| Path::new(name).file_name() == Some(name.as_ref()))
+            .ok_or_else(|| invalid("Archive holds an invalid file name".to_string()))?;
+        let len = read_u64(&mut r).map_err(truncated)?;
+        let expected_crc = read_u32(&mut r).map_err(truncated)?;
+
+        let path = dest.join(&name);
+        let mut file = File::create(&path)?;
+        restored.push(path);
+        let mut hasher = crc32fast::Hasher::new();
+        let mut buf = vec![0u8; COPY_BUFFER];
+        let mut remaining = len;
+        while remaining > 0 {
+            let n = remaining.min(buf.len() as u64) as usize;
+            r.read_exact(&mut buf[..n]).map_err(truncated)?;
+            hasher.update(&buf[..n]);
+            file.write_all(&buf[..n])?;
+            remaining -= n as u64;
+        }
+        if hasher.finalize() != expected_crc {
+            return Err(invalid(format!("Checksum mismatch for {}", name)));
+        }
+        file.sync_all()?;
+    }
+
+    sync_dir(dest)?;
+    Ok(restored.len())
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    r.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Key, Value};
+    use tempfile::TempDir;
+
+    fn contents(storage: &Storage) -> Vec<(Key, Value)> {
+        storage
+            .scan(b"", b"")
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap()
+    }
+
+    fn populated() -> (TempDir, Storage) {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        for i in 0..500 {
+            let key = format!("key{:03}", i).into_bytes();
+            storage
+                .put(key, format!("value{}", i).into_bytes())
+                .unwrap();
+            if i % 100 == 99 {
+                storage.flush().unwrap();
+            }
+        }
+        storage.delete(b"key007").unwrap();
+        (temp_dir, storage)
+    }
+
+    #[test]
+    fn test_archive_round_trip() {
+        let (_temp_dir, storage) = populated();
+        let mut archive = Vec::new();
+        storage.backup_to_archive(&mut archive).unwrap();
+        let expected = contents(&storage);
+
+        // Writes after the backup stay out of it
+        storage.put(b"key007", b"later").unwrap();
+        storage.delete(b"key100").unwrap();
+
+        let restore_dir = TempDir::new().unwrap();
+        let dest = restore_dir.path().join("restored");
+        let files = Storage::restore_from_archive(&archive[..], &dest).unwrap();
+        assert_eq!(files, fs::read_dir(&dest).unwrap().count());
+
+        let restored = Storage::new(&dest, false).unwrap();
+        assert_eq!(restored.identity(), storage.identity());
+        assert_eq!(contents(&restored), expected);
+        assert_eq!(restored.get(b"key007").unwrap(), None);
+    }
+
+    #[test]
+    fn test_damaged_archive_rejected() {
+        let (_temp_dir, storage) = populated();
+        let mut archive = Vec::new();
+        storage.backup_to_archive(&mut archive).unwrap();
+        let restore_dir = TempDir::new().unwrap();
+
+        for cut in [4, 20, archive.len() / 2, archive.len() - 1] {
+            let dest = restore_dir.path().join(format!("cut{}", cut));
+            let err = Storage::restore_from_archive(&archive[..cut], &dest).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "cut at {}", cut);
+            assert_eq!(fs::read_dir(&dest).unwrap().count(), 0);
+        }
+
+        let mut flipped = archive.clone();
+        let last = flipped.len() - 20;
+        flipped[last] ^= 0xff;
+        let dest = restore_dir.path().join("flipped");
+        let err = Storage::restore_from_archive(&flipped[..], &dest).unwrap_err();
+        assert!(err.to_string().contains("Checksum mismatch"), "{}", err);
+
+        let mut future = archive.clone();
+        future[8..12].copy_from_slice(&(ARCHIVE_VERSION + 1).to_le_bytes());
+        let dest = restore_dir.path().join("future");
+        let err = Storage::restore_from_archive(&future[..], &dest).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+}
@@ -0,0 +1,104 @@
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+
+/// Which background threads are still running, so callers can wait for
+/// them without polling. A thread holds a [`Running`] for as long as it
+/// runs, and dropping it, a panic included, wakes every waiter.
+#[derive(Default)]
+pub(super) struct Background {
+    state: Mutex<Jobs>,
+    finished: Condvar,
+}
+
+#[derive(Default)]
+pub(super) struct Jobs {
+    pub(super) flush: bool,
+    pub(super) compaction: bool,
+}
+
+#[derive(Clone, Copy)]
+pub(super) enum Job {
+    Flush,
+    Compaction,
+}
+
+impl Jobs {
+    fn slot(&mut self, job: Job) -> &mut bool {
+        match job {
+            Job::Flush => &mut self.flush,
+            Job::Compaction => &mut self.compaction,
+        }
+    }
+}
+
+/// Marks a job as running until dropped
+pub(super) struct Running {
+    background: Arc<Background>,
+    job: Job,
+}
+
+impl Background {
+    // A waiter that panicked can't have left the flags half set
+    fn jobs(&self) -> MutexGuard<'_, Jobs> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Mark `job` as running, for the thread about to run it to hold
+    pub(super) fn start(self: &Arc<Self>, job: Job) -> Running {
+        *self.jobs().slot(job) = true;
+        Running {
+            background: self.clone(),
+            job,
+        }
+    }
+
+    #[cfg(test)]
+    pub(super) fn is_running(&self, job: Job) -> bool {
+        *self.jobs().slot(job)
+    }
+
+    /// Block while `busy` holds, checking it again each time a job finishes
+    pub(super) fn wait_while(&self, mut busy: impl FnMut(&Jobs) -> bool) {
+        let jobs = self.jobs();
+        let _jobs = self
+            .finished
+            .wait_while(jobs, |jobs| busy(jobs))
+            .unwrap_or_else(PoisonError::into_inner);
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        *self.background.jobs().slot(self.job) = false;
+        self.background.finished.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_waiters_wake_when_a_job_ends() {
+        let background = Arc::new(Background::default());
+        let running = background.start(Job::Flush);
+        assert!(background.is_running(Job::Flush));
+        assert!(!background.is_running(Job::Compaction));
+
+        let waiter = {
+            let background = background.clone();
+            thread::spawn(move || background.wait_while(|jobs| jobs.flush))
+        };
+        thread::sleep(Duration::from_millis(20));
+        assert!(!waiter.is_finished());
+        // Ended by a panic all the same
+        let _ = thread::spawn(move || {
+            let _running = running;
+            panic!("flush failed");
+        })
+        .join();
+        waiter.join().unwrap();
+        assert!(!background.is_running(Job::Flush));
+    }
+}
@@ -0,0 +1,51 @@
+use crate::{Key, Value};
+
+/// A single write within a [`WriteBatch`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    Put(Key, Value),
+    Delete(Key),
+}
+
+/// A group of puts and deletes applied together by `Storage::write`
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, key: impl Into<Key>, value: impl Into<Value>) {
+        self.ops.push(BatchOp::Put(key.into(), value.into()));
+    }
+
+    pub fn delete(&mut self, key: impl Into<Key>) {
+        self.ops.push(BatchOp::Delete(key.into()));
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Bytes of keys and values in the batch
+    pub fn size(&self) -> usize {
+        self.ops
+            .iter()
+            .map(|op| match op {
+                BatchOp::Put(key, value) => key.len() + value.len(),
+                BatchOp::Delete(key) => key.len(),
+            })
+            .sum()
+    }
+
+    pub(super) fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
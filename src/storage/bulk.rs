@@ -0,0 +1,846 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use log::info;
+
+use super::manifest::ManifestEdit;
+use super::{parse_table_name, sync_dir, BatchOp, ChangeEvent, Storage, StorageOptions, Writer};
+use crate::entry::{Entry, Version};
+use crate::memtable::MemTable;
+use crate::sstable::SSTable;
+use crate::wal::{Operation, WalRecord};
+use crate::{Key, Value};
+
+// Staged tables live here until the load is committed
+const BULK_DIR: &str = "bulk";
+// Lists staged -> final table names once a load is committed
+const COMMIT_MARKER: &str = "COMMIT";
+
+/// A bulk-load session that writes SSTables directly, bypassing the WAL.
+///
+/// Loaded data is staged outside the live table set and becomes visible all
+/// at once in [`finish`](BulkLoader::finish). If the process dies before then,
+/// the staged files are discarded on the next open, so a crashed load can
+/// simply be rerun. Dropping the loader without finishing aborts the load.
+pub struct BulkLoader<'a> {
+    storage: &'a mut Storage,
+    memtable: MemTable,
+    seq: u64, // shared by every entry in the load
+    staged: Vec<PathBuf>,
+    finished: bool,
+}
+
+impl<'a> BulkLoader<'a> {
+    pub(super) fn new(storage: &'a mut Storage) -> io::Result<Self> {
+        // Anything written before the load must be older than the loaded tables
+        storage.flush_memtable(&mut storage.writer())?;
+        prepare_staging(&storage.data_dir)?;
+
+        let seq = storage.mem().seq() + 1;
+        Ok(BulkLoader {
+            seq,
+            storage,
+            memtable: MemTable::new(),
+            staged: Vec::new(),
+            finished: false,
+        })
+    }
+
+    pub fn put(&mut self, key: impl Into<Key>, value: impl Into<Value>) -> io::Result<()> {
+        self.memtable
+            .insert(key.into(), self.seq, value.into(), &[]);
+        if self.memtable.size() >= self.storage.options.memtable_size {
+            self.stage_memtable()?;
+        }
+        Ok(())
+    }
+
+    /// Make everything loaded so far visible and durable in one step
+    pub fn finish(mut self) -> io::Result<()> {
+        self.stage_memtable()?;
+        // Staging left behind by a failed commit is cleaned up on the next open
+        self.finished = true;
+        let mut writer = self.storage.writer();
+        commit(self.storage, &mut writer, &self.staged, 0, self.seq)
+    }
+
+    /// Discard everything loaded so far
+    pub fn abort(mut self) -> io::Result<()> {
+        self.finished = true;
+        remove_staging(&self.storage.data_dir)
+    }
+
+    fn stage_memtable(&mut self) -> io::Result<()> {
+        if self.memtable.is_empty() {
+            return Ok(());
+        }
+
+        let entries: Vec<(Key, Version)> = self
+            .memtable
+            .iter_versions()
+            .map(|(key, versions)| (key.clone(), versions[0].clone()))
+            .collect();
+        let path = stage_table(
+            &self.storage.data_dir,
+            self.staged.len(),
+            &self.storage.options,
+            &entries,
+        )?;
+        self.staged.push(path);
+        self.memtable = MemTable::new();
+        Ok(())
+    }
+}
+
+impl Drop for BulkLoader<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = remove_staging(&self.storage.data_dir);
+        }
+    }
+}
+
+/// Write strictly ascending `pairs` into tables at the deepest level up to
+/// `bottom_level` whose data, and that of every level above it, doesn't
+/// overlap the input. Input that overlaps L0 or L1 is placed in L0 when
+/// `allow_l0_fallback` is set and rejected otherwise. A pair that fails to
+/// be read aborts the load with nothing committed.
+pub(super) fn load_sorted<I>(
+    storage: &mut Storage,
+    pairs: I,
+    allow_l0_fallback: bool,
+) -> io::Result<()>
+where
+    I: IntoIterator<Item = io::Result<(Key, Value)>>,
+{
+    // Buffered writes are older than the load and must not shadow it
+    let mut writer = storage.writer();
+    storage.flush_memtable(&mut writer)?;
+    prepare_staging(&storage.data_dir)?;
+
+    let seq = storage.mem().seq() + 1;
+    let staged = match stage_sorted(&storage.data_dir, seq, &storage.options, pairs) {
+        Ok(staged) => staged,
+        Err(e) => {
+            remove_staging(&storage.data_dir)?;
+            return Err(e);
+        }
+    };
+    let Some((first, last)) = staged.range else {
+        return remove_staging(&storage.data_dir);
+    };
+
+    let current = storage.levels();
+    let overlaps = |level: usize| {
+        current.sstables.get(&level).is_some_and(|tables| {
+            tables.iter().any(|table| {
+                table
+                    .key_range()
+                    .is_some_and(|(min, max)| min <= last.as_slice() && first.as_slice() <= max)
+            })
+        })
+    };
+    let bottom = storage.options.bottom_level;
+    let clear_levels = (0..=bottom).take_while(|&level| !overlaps(level)).count();
+    let level = match clear_levels.checked_sub(1) {
+        Some(level) if level > 0 || bottom == 0 => level,
+        _ if allow_l0_fallback => 0,
+        _ => {
+            remove_staging(&storage.data_dir)?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Sorted input overlaps existing keys",
+            ));
+        }
+    };
+
+    commit(storage, &mut writer, &staged.tables, level, seq)
+}
+
+/// Write a large batch straight to new L0 tables, keeping the last write
+/// to each key. The WAL gets only a marker with the batch's sequence
+/// numbers once its tables are committed, so replaying the log never
+/// applies the batch twice and writes after it stay numbered after it.
+pub(super) fn write_direct(
+    storage: &Storage,
+    writer: &mut Writer,
+    ops: Vec<BatchOp>,
+) -> io::Result<()> {
+    storage.check_quota(writer)?;
+    // Buffered writes are older than the batch and must not shadow it
+    storage.flush_memtable(writer)?;
+    prepare_staging(&storage.data_dir)?;
+
+    let first_seq = storage.mem().seq() + 1;
+    let last_seq = first_seq + ops.len() as u64 - 1;
+    let now = storage.options.now();
+    let mut latest: BTreeMap<Key, Version> = BTreeMap::new();
+    let mut changes = Vec::new();
+    for (seq, op) in (first_seq..).zip(ops) {
+        let (key, entry) = match op {
+            BatchOp::Put(key, value) => {
+                storage.lifetime.add_writes(1, 0);
+                writer.written_key_sizes.record(key.len());
+                writer.written_value_sizes.record(value.len());
+                (key, Entry::Value(value))
+            }
+            BatchOp::Delete(key) => {
+                storage.lifetime.add_writes(0, 1);
+                (key, Entry::Tombstone { deleted_at: now })
+            }
+        };
+        if writer.watchers.wants(&key) {
+            changes.push(ChangeEvent {
+                key: key.clone(),
+                op: match entry {
+                    Entry::Value(_) | Entry::Expiring { .. } => Operation::Put,
+                    Entry::Tombstone { .. } => Operation::Delete,
+                    Entry::Merge(_) => Operation::Merge,
+                },
+                value: entry.value().cloned(),
+                seq,
+            });
+        }
+        latest.insert(key, Version::new(seq, entry));
+    }
+
+    let staged = match stage_batch(storage, latest) {
+        Ok(staged) => staged,
+        Err(e) => {
+            remove_staging(&storage.data_dir)?;
+            return Err(e);
+        }
+    };
+    install(storage, writer, &staged, 0, last_seq)?;
+    let marker = WalRecord::direct_batch(first_seq, last_seq);
+    writer.wal.append(last_seq, marker.op, &marker.key, None)?;
+    info!(
+        "Wrote batch of sequence numbers {}..={} directly to {} SSTables",
+        first_seq,
+        last_seq,
+        staged.len()
+    );
+    for change in changes {
+        writer.watchers.publish(change);
+    }
+
+    storage
+        .maybe_compact(writer, 0)
+        .and_then(|()| storage.maybe_reclaim_space(writer))
+}
+
+/// Stage `entries` as tables of about `memtable_size` bytes each
+fn stage_batch(storage: &Storage, entries: BTreeMap<Key, Version>) -> io::Result<Vec<PathBuf>> {
+    let mut tables = Vec::new();
+    let mut chunk = Vec::new();
+    let mut chunk_size = 0;
+    for (key, version) in entries {
+        chunk_size += key.len() + version.entry.value().map_or(0, Vec::len);
+        chunk.push((key, version));
+        if chunk_size >= storage.options.memtable_size {
+            let options = &storage.options;
+            tables.push(stage_table(
+                &storage.data_dir,
+                tables.len(),
+                options,
+                &chunk,
+            )?);
+            chunk.clear();
+            chunk_size = 0;
+        }
+    }
+    if !chunk.is_empty() {
+        let options = &storage.options;
+        tables.push(stage_table(
+            &storage.data_dir,
+            tables.len(),
+            options,
+            &chunk,
+        )?);
+    }
+    Ok(tables)
+}
+
+/// Tables staged from sorted input, along with the key range they cover
+struct StagedInput {
+    tables: Vec<PathBuf>,
+    range: Option<(Key, Key)>,
+}
+
+fn stage_sorted<I>(
+    data_dir: &Path,
+    seq: u64,
+    options: &StorageOptions,
+    pairs: I,
+) -> io::Result<StagedInput>
+where
+    I: IntoIterator<Item = io::Result<(Key, Value)>>,
+{
+    let mut tables = Vec::new();
+    let mut first_key = None;
+    let mut last_key: Option<Key> = None;
+    let mut chunk: Vec<(Key, Version)> = Vec::new();
+    let mut chunk_size = 0;
+
+    for pair in pairs {
+        let (key, value) = pair?;
+        let previous = chunk.last().map(|(k, _)| k).or(last_key.as_ref());
+        if previous.is_some_and(|previous| key <= *previous) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Key {:?} is not in ascending order",
+                    String::from_utf8_lossy(&key)
+                ),
+            ));
+        }
+        if first_key.is_none() {
+            first_key = Some(key.clone());
+        }
+
+        chunk_size += key.len() + value.len();
+        chunk.push((key, Version::new(seq, Entry::Value(value))));
+        if chunk_size >= options.memtable_size {
+            last_key = chunk.last().map(|(k, _)| k.clone());
+            tables.push(stage_table(data_dir, tables.len(), options, &chunk)?);
+            chunk.clear();
+            chunk_size = 0;
+        }
+    }
+    if !chunk.is_empty() {
+        last_key = chunk.last().map(|(k, _)| k.clone());
+        tables.push(stage_table(data_dir, tables.len(), options, &chunk)?);
+    }
+
+    Ok(StagedInput {
+        tables,
+        range: first_key.zip(last_key),
+    })
+}
+
+/// Called on open: complete a committed load, then discard any staging left
+/// behind by a load that never committed. Returns the tables a committed
+/// load moved into place.
+pub(super) fn recover(data_dir: &Path, options: &StorageOptions) -> io::Result<Vec<PathBuf>> {
+    complete_commit(data_dir, options)
+}
+
+fn prepare_staging(data_dir: &Path) -> io::Result<()> {
+    remove_staging(data_dir)?;
+    fs::create_dir_all(data_dir.join(BULK_DIR))
+}
+
+fn stage_table(
+    data_dir: &Path,
+    index: usize,
+    options: &StorageOptions,
+    entries: &[(Key, Version)],
+) -> io::Result<PathBuf> {
+    let path = data_dir.join(BULK_DIR).join(format!("{}.sst", index));
+    SSTable::new(path.clone())?
+        .with_bloom(options.bloom)
+        .with_compression(options.compression)
+        .write_entries(entries)?;
+    Ok(path)
+}
+
+/// Durably move `staged` tables into `level` as a single step, register
+/// them and compact as needed
+fn commit(
+    storage: &Storage,
+    writer: &mut Writer,
+    staged: &[PathBuf],
+    level: usize,
+    last_seq: u64,
+) -> io::Result<()> {
+    install(storage, writer, staged, level, last_seq)?;
+    storage.maybe_compact(writer, level)
+}
+
+/// Durably move `staged` tables into `level` as a single step and register
+/// them, numbering writes from after `last_seq`, the newest they hold
+fn install(
+    storage: &Storage,
+    writer: &mut Writer,
+    staged: &[PathBuf],
+    level: usize,
+    last_seq: u64,
+) -> io::Result<()> {
+    // Tables bound for a level kept in another directory are first moved
+    // into staging there, so the committing renames never cross devices
+    let table_dir = storage
+        .options
+        .table_dir(&storage.data_dir, level)
+        .to_path_buf();
+    let staged = if table_dir != storage.data_dir {
+        relocate(staged, &table_dir.join(BULK_DIR))?
+    } else {
+        staged.to_vec()
+    };
+
+    // Staged tables must be on disk before the commit marker refers to them
+    for path in &staged {
+        File::open(path)?.sync_all()?;
+    }
+    sync_dir(&table_dir.join(BULK_DIR))?;
+
+    let mut renames = Vec::with_capacity(staged.len());
+    for path in staged {
+        let final_path = storage.table_path(level, writer.sstable_counter);
+        writer.sstable_counter += 1;
+        renames.push((path, final_path));
+    }
+    write_commit_marker(&storage.data_dir.join(BULK_DIR), &renames)?;
+
+    // From here on the load is committed; recovery completes the renames
+    // and lists the tables in the manifest if this doesn't get to
+    let mut edits = Vec::with_capacity(renames.len() + 1);
+    for (staged, final_path) in &renames {
+        edits.push(ManifestEdit::AddFile {
+            level,
+            seq: parse_table_name(final_path).map_or(0, |(_, seq)| seq),
+            path: final_path.file_name().unwrap().into(),
+            size: fs::metadata(staged)?.len(),
+        });
+    }
+    edits.push(ManifestEdit::SetCounter(writer.sstable_counter));
+    writer.manifest.record(&edits)?;
+    complete_commit(&storage.data_dir, &storage.options)?;
+
+    info!(
+        level,
+        files = renames.len();
+        "Bulk load committed {} SSTables to level {}",
+        renames.len(),
+        level
+    );
+    let mut tables = Vec::with_capacity(renames.len());
+    for (_, final_path) in renames {
+        let table = storage.options.publish(SSTable::new(final_path)?)?;
+        tables.push(Arc::new(table.with_cache(storage.block_cache.clone())));
+    }
+    // Numbered under the memtables' lock, so no snapshot covers the load
+    // before reads can see it
+    let mem = storage.mem_mut();
+    storage.edit_levels(|current| {
+        current.sstables.entry(level).or_default().extend(tables);
+        current.arrange_level(level);
+    });
+    mem.seq.fetch_max(last_seq, Ordering::Release);
+    Ok(())
+}
+
+fn write_commit_marker(bulk_dir: &Path, renames: &[(PathBuf, PathBuf)]) -> io::Result<()> {
+    let tmp_path = bulk_dir.join(format!("{}.tmp", COMMIT_MARKER));
+    let mut file = File::create(&tmp_path)?;
+    for (staged, final_path) in renames {
+        let staged = staged.file_name().unwrap().to_string_lossy();
+        let final_name = final_path.file_name().unwrap().to_string_lossy();
+        writeln!(file, "{} {}", staged, final_name)?;
+    }
+    file.sync_all()?;
+    fs::rename(&tmp_path, bulk_dir.join(COMMIT_MARKER))?;
+    sync_dir(bulk_dir)
+}
+
+/// Move staged tables into `dir`, copying when it is on another device
+fn relocate(staged: &[PathBuf], dir: &Path) -> io::Result<Vec<PathBuf>> {
+    prepare_staging(dir.parent().unwrap())?;
+    let mut moved = Vec::with_capacity(staged.len());
+    for path in staged {
+        let target = dir.join(path.file_name().unwrap());
+        if let Err(e) = fs::rename(path, &target) {
+            if e.kind() != io::ErrorKind::CrossesDevices {
+                return Err(e);
+            }
+            fs::copy(path, &target)?;
+            fs::remove_file(path)?;
+        }
+        moved.push(target);
+    }
+    Ok(moved)
+}
+
+/// Move every table named in the commit marker into place, if there is one,
+/// then remove the staging directories. Each table is staged in, and renamed
+/// within, the directory its level is configured to live in. Returns the
+/// final paths of the tables the marker named.
+fn complete_commit(data_dir: &Path, options: &StorageOptions) -> io::Result<Vec<PathBuf>> {
+    let marker = data_dir.join(BULK_DIR).join(COMMIT_MARKER);
+    let mut committed = Vec::new();
+    if marker.exists() {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid bulk commit marker");
+        let mut touched = Vec::new();
+        for line in BufReader::new(File::open(&marker)?).lines() {
+            let line = line?;
+            let (staged, final_name) = line.split_once(' ').ok_or_else(invalid)?;
+            let (level, _) = parse_table_name(Path::new(final_name)).ok_or_else(invalid)?;
+            let table_dir = options.table_dir(data_dir, level);
+
+            // Renames that already happened before a crash are skipped
+            let staged = table_dir.join(BULK_DIR).join(staged);
+            if staged.exists() {
+                fs::rename(staged, table_dir.join(final_name))?;
+            }
+            committed.push(table_dir.join(final_name));
+            if !touched.contains(&table_dir) {
+                touched.push(table_dir);
+            }
+        }
+        for dir in touched {
+            sync_dir(dir)?;
+        }
+    }
+    // The data directory's staging holds the marker, so it goes last
+    for dir in options.table_dirs(data_dir).iter().rev() {
+        remove_staging(dir)?;
+    }
+    Ok(committed)
+}
+
+fn remove_staging(data_dir: &Path) -> io::Result<()> {
+    let bulk_dir = data_dir.join(BULK_DIR);
+    if bulk_dir.exists() {
+        fs::remove_dir_all(bulk_dir)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{EventKind, WriteBatch};
+    use tempfile::TempDir;
+
+    fn key(i: usize) -> Key {
+        format!("bulk{:05}", i).into_bytes()
+    }
+
+    fn load(loader: &mut BulkLoader, count: usize) {
+        // Large enough values to stage several tables
+        for i in 0..count {
+            loader.put(key(i), vec![b'v'; 1024]).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_bulk_load_finish() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut storage = Storage::new(temp_dir.path(), false).unwrap();
+        storage.put(b"existing", b"old").unwrap();
+        storage.put(key(0), b"old".to_vec()).unwrap();
+
+        let mut loader = storage.bulk_loader().unwrap();
+        load(&mut loader, 1500);
+        assert!(loader.staged.len() > 1);
+        loader.finish().unwrap();
+
+        assert_eq!(storage.get(key(0)).unwrap(), Some(vec![b'v'; 1024]));
+        assert_eq!(storage.get(key(1499)).unwrap(), Some(vec![b'v'; 1024]));
+        assert!(!temp_dir.path().join(BULK_DIR).exists());
+        // Nothing went through the WAL
+        assert!(storage.writer().wal.replay().unwrap().is_empty());
+
+        drop(storage);
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        assert_eq!(storage.get(key(750)).unwrap(), Some(vec![b'v'; 1024]));
+        assert_eq!(storage.get(b"existing").unwrap(), Some(b"old".to_vec()));
+    }
+
+    #[test]
+    fn test_bulk_load_abort() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut storage = Storage::new(temp_dir.path(), false).unwrap();
+
+        let mut loader = storage.bulk_loader().unwrap();
+        load(&mut loader, 1000);
+        loader.abort().unwrap();
+
+        assert_eq!(storage.get(key(0)).unwrap(), None);
+        assert!(!temp_dir.path().join(BULK_DIR).exists());
+    }
+
+    #[test]
+    fn test_bulk_load_crash_before_finish() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut storage = Storage::new(temp_dir.path(), false).unwrap();
+        storage.put(b"existing", b"old").unwrap();
+
+        // Crash midway: the loader never gets to clean up after itself
+        let mut loader = storage.bulk_loader().unwrap();
+        load(&mut loader, 1500);
+        std::mem::forget(loader);
+        assert!(temp_dir.path().join(BULK_DIR).exists());
+        drop(storage);
+
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        for i in (0..1500).step_by(100) {
+            assert_eq!(storage.get(key(i)).unwrap(), None);
+        }
+        assert_eq!(storage.get(b"existing").unwrap(), Some(b"old".to_vec()));
+        assert!(!temp_dir.path().join(BULK_DIR).exists());
+    }
+
+    #[test]
+    fn test_bulk_load_crash_after_commit() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut storage = Storage::new(temp_dir.path(), false).unwrap();
+
+        // Crash once the commit marker is durable, with one table moved
+        let mut loader = storage.bulk_loader().unwrap();
+        load(&mut loader, 1500);
+        loader.stage_memtable().unwrap();
+        let renames: Vec<_> = loader
+            .staged
+            .iter()
+            .enumerate()
+            .map(|(i, staged)| {
+                let final_name = format!("L0_{}.sst", 100 + i);
+                (staged.clone(), temp_dir.path().join(final_name))
+            })
+            .collect();
+        let bulk_dir = temp_dir.path().join(BULK_DIR);
+        write_commit_marker(&bulk_dir, &renames).unwrap();
+        fs::rename(&renames[0].0, &renames[0].1).unwrap();
+        std::mem::forget(loader);
+        drop(storage);
+
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        for i in (0..1500).step_by(100) {
+            assert_eq!(storage.get(key(i)).unwrap(), Some(vec![b'v'; 1024]));
+        }
+        assert!(!bulk_dir.exists());
+    }
+
+    fn sorted_key(i: usize) -> Key {
+        format!("k{:08}", i).into_bytes()
+    }
+
+    fn sorted_pairs(range: std::ops::Range<usize>) -> impl Iterator<Item = (Key, Value)> {
+        range.map(|i| (sorted_key(i), format!("v{:07}", i).into_bytes()))
+    }
+
+    #[test]
+    fn test_bulk_load_sorted_into_bottom_level() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut storage = Storage::new(temp_dir.path(), false).unwrap();
+
+        let count = 1_000_000;
+        storage
+            .bulk_load_sorted(sorted_pairs(0..count), false)
+            .unwrap();
+
+        // Each pair is a 9 byte key and an 8 byte value
+        let per_table = storage.options.memtable_size.div_ceil(17);
+        let bottom = storage.options.bottom_level;
+        assert!(storage
+            .levels()
+            .sstables
+            .get(&0)
+            .is_none_or(|t| t.is_empty()));
+        assert_eq!(
+            storage.levels().sstables[&bottom].len(),
+            count.div_ceil(per_table)
+        );
+        assert!(storage.writer().wal.replay().unwrap().is_empty());
+
+        for i in [0, 1, 4242, 123_456, 500_000, 777_777, 999_999] {
+            assert_eq!(
+                storage.get(sorted_key(i)).unwrap(),
+                Some(format!("v{:07}", i).into_bytes())
+            );
+        }
+        assert_eq!(storage.get(sorted_key(count)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_bulk_load_sorted_into_level_dir() {
+        let temp_dir = TempDir::new().unwrap();
+        let cold_dir = TempDir::new().unwrap();
+        let options = StorageOptions::default()
+            .bottom_level(3)
+            .level_dir(2, cold_dir.path());
+        let mut storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+
+        storage
+            .bulk_load_sorted(sorted_pairs(0..1000), false)
+            .unwrap();
+        let files = storage.level_files(3);
+        assert_eq!(files.len(), 1);
+        assert!(files[0].path.starts_with(cold_dir.path()));
+        assert!(!cold_dir.path().join(BULK_DIR).exists());
+        assert!(!temp_dir.path().join(BULK_DIR).exists());
+        assert_eq!(
+            storage.get(sorted_key(999)).unwrap(),
+            Some(b"v0000999".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_bulk_load_sorted_rejects_unsorted_input() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut storage = Storage::new(temp_dir.path(), false).unwrap();
+
+        let pairs = sorted_pairs(0..10).chain(sorted_pairs(5..6));
+        let err = storage.bulk_load_sorted(pairs, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("k00000005"));
+
+        assert_eq!(storage.get(sorted_key(0)).unwrap(), None);
+        assert!(storage.levels().sstables.is_empty());
+        assert!(!temp_dir.path().join(BULK_DIR).exists());
+    }
+
+    #[test]
+    fn test_bulk_load_sorted_overlap() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut storage = Storage::new(temp_dir.path(), false).unwrap();
+        storage.put(sorted_key(50), b"old".to_vec()).unwrap();
+
+        // The buffered write is flushed to L0, which the input overlaps
+        let err = storage
+            .bulk_load_sorted(sorted_pairs(0..100), false)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(storage.get(sorted_key(0)).unwrap(), None);
+        assert_eq!(storage.get(sorted_key(50)).unwrap(), Some(b"old".to_vec()));
+
+        storage
+            .bulk_load_sorted(sorted_pairs(0..100), true)
+            .unwrap();
+        assert_eq!(storage.levels().sstables[&0].len(), 2);
+        assert_eq!(
+            storage.get(sorted_key(50)).unwrap(),
+            Some(b"v0000050".to_vec())
+        );
+
+        // A disjoint range still goes all the way down
+        storage
+            .bulk_load_sorted(sorted_pairs(100..200), false)
+            .unwrap();
+        let bottom = storage.options.bottom_level;
+        assert_eq!(storage.levels().sstables[&bottom].len(), 1);
+        assert_eq!(
+            storage.get(sorted_key(150)).unwrap(),
+            Some(b"v0000150".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_ingest_sorted() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut storage = Storage::new(temp_dir.path(), false).unwrap();
+        storage.put(sorted_key(10), b"old".to_vec()).unwrap();
+        storage.flush().unwrap();
+        let wal_bytes = storage.stats().wal_bytes;
+
+        // A large import goes around the WAL entirely
+        let count = 200_000;
+        storage.ingest_sorted(sorted_pairs(0..count)).unwrap();
+        assert_eq!(storage.stats().wal_bytes, wal_bytes);
+        assert_eq!(
+            storage.get(sorted_key(count - 1)).unwrap(),
+            Some(format!("v{:07}", count - 1).into_bytes())
+        );
+
+        // The import overlaps the flushed write, so it landed above it in L0
+        // and its value wins
+        let per_table = storage.options.memtable_size.div_ceil(17);
+        let l0_tables = 1 + count.div_ceil(per_table);
+        assert_eq!(storage.levels().sstables[&0].len(), l0_tables);
+        assert_eq!(
+            storage.get(sorted_key(10)).unwrap(),
+            Some(b"v0000010".to_vec())
+        );
+
+        let pairs = sorted_pairs(count..count + 10).chain(sorted_pairs(count + 3..count + 4));
+        let err = storage.ingest_sorted(pairs).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains(&format!("k{:08}", count + 3)));
+        assert_eq!(storage.get(sorted_key(count)).unwrap(), None);
+        assert_eq!(
+            storage.get(sorted_key(10)).unwrap(),
+            Some(b"v0000010".to_vec())
+        );
+    }
+
+    /// A batch of 1000 1KB puts and a delete, with the memtable and the
+    /// direct-write threshold at 64KB
+    fn open_small(dir: &TempDir) -> Storage {
+        let options = StorageOptions::default().memtable_size(64 * 1024);
+        Storage::open_with_options(dir.path(), options).unwrap()
+    }
+
+    fn large_batch() -> WriteBatch {
+        let mut batch = WriteBatch::new();
+        for i in 0..1000 {
+            batch.put(key(i), vec![b'b'; 1024]);
+        }
+        batch.put(key(0), b"last".to_vec());
+        batch.delete(b"existing".to_vec());
+        batch
+    }
+
+    #[test]
+    fn test_large_batch_written_to_tables() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = open_small(&temp_dir);
+        storage.put(b"existing", b"old").unwrap();
+        storage.put(key(1), b"old".to_vec()).unwrap();
+
+        storage.write(large_batch()).unwrap();
+        assert!(storage.mem().memtable.is_empty());
+        assert!(storage.writer().wal.size().unwrap() < 64);
+        // Only the buffered writes were flushed; the batch's tables were
+        // merged straight into L1 in the background
+        storage.finish_compaction(&mut storage.writer()).unwrap();
+        let events = storage.recent_events(10).unwrap();
+        let flushes = events.iter().filter(|e| e.kind == EventKind::Flush).count();
+        assert_eq!(flushes, 1);
+        let merged = events.iter().find(|e| e.kind == EventKind::Compaction);
+        assert!(merged.unwrap().inputs.len() > 10);
+
+        assert_eq!(storage.get(key(0)).unwrap(), Some(b"last".to_vec()));
+        assert_eq!(storage.get(key(1)).unwrap(), Some(vec![b'b'; 1024]));
+        assert_eq!(storage.get(b"existing").unwrap(), None);
+        assert_eq!(storage.scan(b"", b"").unwrap().count(), 1000);
+
+        // Small batches still go through the memtable
+        let mut batch = WriteBatch::new();
+        batch.put(key(2), b"small".to_vec());
+        storage.write(batch).unwrap();
+        assert_eq!(storage.mem().memtable.len(), 1);
+        assert_eq!(storage.get(key(2)).unwrap(), Some(b"small".to_vec()));
+    }
+
+    #[test]
+    fn test_large_batch_survives_crash() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = open_small(&temp_dir);
+        storage.write(large_batch()).unwrap();
+        let batch_seq = storage.mem().seq();
+        storage.put(key(5), b"after".to_vec()).unwrap();
+
+        // Crash right after: nothing is flushed or saved on the way out
+        storage.crash();
+
+        let storage = open_small(&temp_dir);
+        assert_eq!(storage.get(key(0)).unwrap(), Some(b"last".to_vec()));
+        assert_eq!(storage.get(key(5)).unwrap(), Some(b"after".to_vec()));
+        assert_eq!(storage.get(key(999)).unwrap(), Some(vec![b'b'; 1024]));
+        assert_eq!(storage.scan(b"", b"").unwrap().count(), 1000);
+        assert!(!temp_dir.path().join(BULK_DIR).exists());
+        assert_eq!(storage.mem().seq(), batch_seq + 1);
+
+        // Reopening again replays the marker without applying anything twice
+        storage.put(key(2000), b"later".to_vec()).unwrap();
+        drop(storage);
+        let storage = open_small(&temp_dir);
+        assert_eq!(storage.mem().seq(), batch_seq + 2);
+        assert_eq!(storage.scan(b"", b"").unwrap().count(), 1001);
+    }
+}
@@ -0,0 +1,698 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::identity::{self, IDENTITY_FILE};
+use super::lock::DirLock;
+use super::{parse_table_name, sync_dir, Storage, VerifyReport};
+use crate::error::StorageError;
+use crate::sstable::SSTable;
+use crate::wal::WAL;
+
+/// Lists the tables a backup directory stands for; see [`BackupManifest`]
+pub const BACKUP_MANIFEST: &str = "BACKUP";
+
+/// What a checkpoint or incremental backup contains, stored as text lines
+/// (`parent <dir>`, `live <table> <length>`, `deleted <table>`) in its
+/// directory.
+///
+/// An incremental backup holds only tables its parent chain lacks; the
+/// rest are found by walking `parent` links. Tables listed as deleted were
+/// live in the parent but compacted away since, and must not be restored.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct BackupManifest {
+    pub parent: Option<PathBuf>,
+    pub live: BTreeSet<String>,
+    pub deleted: BTreeSet<String>,
+    /// Length of each live table when it was backed up; manifests written
+    /// before lengths were recorded have none
+    pub lengths: BTreeMap<String, u64>,
+}
+
+impl BackupManifest {
+    pub fn load(backup_dir: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(backup_dir.join(BACKUP_MANIFEST))?;
+        let mut manifest = BackupManifest::default();
+        for line in text.lines().filter(|line| !line.is_empty()) {
+            match line.split_once(' ') {
+                Some(("parent", dir)) => manifest.parent = Some(PathBuf::from(dir)),
+                Some(("live", rest)) => {
+                    let name = match rest.split_once(' ') {
+                        Some((name, length)) => {
+                            let length = length.parse().map_err(|_| {
+                                io::Error::new(
+                                    io::ErrorKind::InvalidData,
+                                    format!("Invalid backup manifest line {:?}", line),
+                                )
+                            })?;
+                            manifest.lengths.insert(name.to_string(), length);
+                            name
+                        }
+                        None => rest,
+                    };
+                    manifest.live.insert(name.to_string());
+                }
+                Some(("deleted", name)) => {
+                    manifest.deleted.insert(name.to_string());
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Invalid backup manifest line {:?}", line),
+                    ))
+                }
+            }
+        }
+        Ok(manifest)
+    }
+
+    fn to_text(&self) -> io::Result<String> {
+        let mut text = String::new();
+        if let Some(parent) = &self.parent {
+            let parent = parent.to_str().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Backup path {:?} is not valid UTF-8", parent),
+                )
+            })?;
+            text.push_str(&format!("parent {}\n", parent));
+        }
+        for name in &self.live {
+            match self.lengths.get(name) {
+                Some(length) => text.push_str(&format!("live {} {}\n", name, length)),
+                None => text.push_str(&format!("live {}\n", name)),
+            }
+        }
+        for name in &self.deleted {
+            text.push_str(&format!("deleted {}\n", name));
+        }
+        Ok(text)
+    }
+}
+
+impl Storage {
+    /// Write a consistent copy of the database to `dest`, which must be
+    /// missing or empty. The memtable is flushed first so the copy needs no
+    /// WAL. Tables are hard-linked where possible, since they're never
+    /// modified, and copied otherwise; all of them land in `dest` itself,
+    /// whatever level directory they came from. Returns the tables copied.
+    ///
+    /// The copy opens as a database directly and can be the base of an
+    /// [`incremental_backup`](Storage::incremental_backup).
+    pub fn checkpoint<P: AsRef<Path>>(&self, dest: P) -> Result<usize, StorageError> {
+        Ok(self.backup_into(dest.as_ref(), None)?)
+    }
+
+    /// Like [`checkpoint`](Storage::checkpoint), but copy only the tables
+    /// missing from the backup at `previous` (a checkpoint or another
+    /// incremental backup), which must stay where it is. The result can't
+    /// be opened directly; use [`restore_backup`](Storage::restore_backup).
+    /// Returns the tables copied.
+    pub fn incremental_backup<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        dest: P,
+        previous: Q,
+    ) -> Result<usize, StorageError> {
+        Ok(self.backup_into(dest.as_ref(), Some(previous.as_ref()))?)
+    }
+
+    /// Materialize the backup at `backup_dir` into `dest`, which must be
+    /// missing or empty, gathering tables from its chain of parents.
+    /// Returns the tables restored.
+    pub fn restore_backup<P: AsRef<Path>, Q: AsRef<Path>>(
+        backup_dir: P,
+        dest: Q,
+    ) -> Result<usize, StorageError> {
+        let (backup_dir, dest) = (backup_dir.as_ref(), dest.as_ref());
+        let manifest = BackupManifest::load(backup_dir)?;
+        prepare_dest(dest)?;
+
+        for name in &manifest.live {
+            let source = find_in_chain(backup_dir, name)?;
+            link_or_copy(&source, &dest.join(name))?;
+        }
+        link_or_copy(&backup_dir.join(IDENTITY_FILE), &dest.join(IDENTITY_FILE))?;
+        sync_dir(dest)?;
+        Ok(manifest.live.len())
+    }
+
+    /// Restore the backup at `backup_dir` into `data_dir` and open it.
+    ///
+    /// The backup must pass [`verify_checkpoint`](Storage::verify_checkpoint)
+    /// first. A `data_dir` that holds anything is refused unless `force` is
+    /// set, in which case it's replaced, though never while another instance
+    /// has it open. The files are gathered in a staging directory beside
+    /// `data_dir` and renamed into place once complete, so a restore cut
+    /// short leaves `data_dir` as it was rather than half populated.
+    pub fn restore<P: AsRef<Path>, Q: AsRef<Path>>(
+        backup_dir: P,
+        data_dir: Q,
+        force: bool,
+    ) -> Result<Storage, StorageError> {
+        let (backup_dir, data_dir) = (backup_dir.as_ref(), data_dir.as_ref());
+        let report = Self::verify_checkpoint(backup_dir);
+        if let Some((path, reason)) = report.corrupt.first() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Backup {:?} failed verification at {:?}: {}",
+                    backup_dir, path, reason
+                ),
+            )
+            .into());
+        }
+        let occupied = data_dir.exists() && fs::read_dir(data_dir)?.next().is_some();
+        if occupied && !force {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Restore directory {:?} is not empty", data_dir),
+            )
+            .into());
+        }
+        // Held until the old contents are gone, so an open instance keeps them
+        let lock = occupied.then(|| DirLock::acquire(data_dir)).transpose()?;
+
+        let name = data_dir.file_name().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Restore directory {:?} has no name", data_dir),
+            )
+        })?;
+        let mut staging_name = name.to_os_string();
+        staging_name.push(".restoring");
+        let staging = data_dir.with_file_name(staging_name);
+        // Left by a restore that was cut short
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+        Self::restore_backup(backup_dir, &staging)?;
+
+        if data_dir.exists() {
+            fs::remove_dir_all(data_dir)?;
+        }
+        fs::rename(&staging, data_dir)?;
+        drop(lock);
+        if let Some(parent) = data_dir.parent().filter(|p| !p.as_os_str().is_empty()) {
+            sync_dir(parent)?;
+        }
+        Storage::new(data_dir, false)
+    }
+
+    /// Check the backup at `backup_dir` without opening it as a database:
+    /// its identity and manifest parse, every live table is found in its
+    /// chain with the recorded length and passes
+    /// [`SSTable::verify`](crate::sstable::SSTable::verify), any WAL reads
+    /// back, and no table shadows a table holding newer data for keys they
+    /// share. A manifest that can't be read fails the backup outright.
+    pub fn verify_checkpoint<P: AsRef<Path>>(backup_dir: P) -> VerifyReport {
+        let backup_dir = backup_dir.as_ref();
+        let mut report = VerifyReport::default();
+        let identity = identity::load_any(backup_dir).map(|_| ());
+        report.record(backup_dir.join(IDENTITY_FILE), identity);
+        let manifest = match BackupManifest::load(backup_dir) {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                report.record(backup_dir.join(BACKUP_MANIFEST), Err(e));
+                return report;
+            }
+        };
+        report.record(backup_dir.join(BACKUP_MANIFEST), Ok(()));
+
+        let mut tables = Vec::new();
+        for name in &manifest.live {
+            report.tables += 1;
+            let path = match find_in_chain(backup_dir, name) {
+                Ok(path) => path,
+                Err(e) => {
+                    report.record(backup_dir.join(name), Err(e));
+                    continue;
+                }
+            };
+            match verify_table(&path, manifest.lengths.get(name).copied()) {
+                Ok(table) => {
+                    report.entries += table.properties().entry_count;
+                    tables.push(table);
+                    report.record(path, Ok(()));
+                }
+                Err(e) => report.record(path, Err(e)),
+            }
+        }
+
+        let wal_dir = backup_dir.join("wal");
+        if wal_dir.is_dir() {
+            let replayed = WAL::logs(&wal_dir).and_then(|logs| {
+                logs.into_iter()
+                    .try_for_each(|number| WAL::read_log(&wal_dir, number, 0).map(|_| ()))
+            });
+            report.record(wal_dir, replayed.map_err(io::Error::from));
+        }
+
+        for (path, e) in shadowing_errors(&tables) {
+            report.corrupt.push((path, e));
+        }
+        report
+    }
+
+    fn backup_into(&self, dest: &Path, previous: Option<&Path>) -> io::Result<usize> {
+        let parent = match previous {
+            Some(dir) => Some((fs::canonicalize(dir)?, BackupManifest::load(dir)?)),
+            None => None,
+        };
+        prepare_dest(dest)?;
+        self.flush()?;
+        self.capture()?.write(dest, parent)
+    }
+
+    /// Flush the memtable and hold on to the tables that then make up the
+    /// database, so they can be copied while it moves on. Compactions are
+    /// left running; their inputs are what's held.
+    pub(super) fn capture(&self) -> io::Result<Capture> {
+        self.options.require_local_tables("A checkpoint")?;
+        self.flush_buffered()?;
+        Ok(Capture {
+            tables: self.levels().sstables.values().flatten().cloned().collect(),
+            identity: self.data_dir.join(IDENTITY_FILE),
+        })
+    }
+}
+
+/// The tables of a database at one moment. Holding them keeps a
+/// compaction that replaces them from removing their files until the
+/// capture is dropped, so they can be copied without the database.
+pub(super) struct Capture {
+    tables: Vec<Arc<SSTable>>,
+    identity: PathBuf,
+}
+
+impl Capture {
+    /// Write the captured tables to the empty `dest` as a backup of
+    /// `parent`, a backup directory and its manifest, or as a checkpoint
+    /// without one. Returns the tables copied.
+    pub(super) fn write(
+        self,
+        dest: &Path,
+        parent: Option<(PathBuf, BackupManifest)>,
+    ) -> io::Result<usize> {
+        let mut manifest = BackupManifest {
+            parent: parent.as_ref().map(|(dir, _)| dir.clone()),
+            ..Default::default()
+        };
+        let mut copied = 0;
+        for table in &self.tables {
+            let source = table.get_path();
+            let name = source
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Table path {:?} has no usable file name", source),
+                    )
+                })?;
+            manifest.live.insert(name.to_string());
+            manifest
+                .lengths
+                .insert(name.to_string(), fs::metadata(source)?.len());
+            if parent
+                .as_ref()
+                .is_some_and(|(_, previous)| previous.live.contains(name))
+            {
+                continue;
+            }
+            link_or_copy(source, &dest.join(name))?;
+            copied += 1;
+        }
+        if let Some((_, previous)) = &parent {
+            manifest.deleted = previous.live.difference(&manifest.live).cloned().collect();
+        }
+
+        link_or_copy(&self.identity, &dest.join(IDENTITY_FILE))?;
+        fs::write(dest.join(BACKUP_MANIFEST), manifest.to_text()?)?;
+        fs::File::open(dest.join(BACKUP_MANIFEST))?.sync_all()?;
+        sync_dir(dest)?;
+        Ok(copied)
+    }
+}
+
+/// Create `dest` unless it exists, refusing one that isn't empty
+pub(super) fn prepare_dest(dest: &Path) -> io::Result<()> {
+    if dest.exists() && fs::read_dir(dest)?.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("Backup directory {:?} is not empty", dest),
+        ));
+    }
+    fs::create_dir_all(dest)
+}
+
+/// The nearest copy of table `name` in `backup_dir` or its parents
+pub(super) fn find_in_chain(backup_dir: &Path, name: &str) -> io::Result<PathBuf> {
+    let mut dir = backup_dir.to_path_buf();
+    loop {
+        let path = dir.join(name);
+        if path.exists() {
+            return Ok(path);
+        }
+        dir = BackupManifest::load(&dir)?.parent.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "{} is missing from the backup chain of {:?}",
+                    name, backup_dir
+                ),
+            )
+        })?;
+    }
+}
+
+/// Open the backed-up table at `path` and read it end to end, checking its
+/// length against the manifest's when one was recorded
+fn verify_table(path: &Path, length: Option<u64>) -> io::Result<SSTable> {
+    let actual = fs::metadata(path)?.len();
+    if let Some(length) = length.filter(|&length| length != actual) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("length is {}, manifest records {}", actual, length),
+        ));
+    }
+    let table = SSTable::new(path.to_path_buf())?;
+    table.verify()?;
+    Ok(table)
+}
+
+/// Tables that reads consult before another table sharing some of its keys
+/// (a shallower level, or a newer table in the same level) must not hold
+/// only older data than it, or the older versions would win
+fn shadowing_errors(tables: &[SSTable]) -> Vec<(PathBuf, String)> {
+    let mut ordered: Vec<_> = tables
+        .iter()
+        .filter_map(|table| {
+            let (level, number) = parse_table_name(table.get_path())?;
+            Some((level, std::cmp::Reverse(number), table))
+        })
+        .collect();
+    ordered.sort_by_key(|(level, number, _)| (*level, *number));
+
+    let mut errors = Vec::new();
+    for (i, (_, _, upper)) in ordered.iter().enumerate() {
+        let Some((upper_smallest, upper_largest)) = upper.key_range() else {
+            continue;
+        };
+        for (_, _, lower) in &ordered[i + 1..] {
+            let Some((smallest, largest)) = lower.key_range() else {
+                continue;
+            };
+            let overlaps = smallest <= upper_largest && upper_smallest <= largest;
+            if overlaps && upper.properties().max_seq < lower.properties().max_seq {
+                errors.push((
+                    upper.get_path().clone(),
+                    format!(
+                        "shadows {:?}, which holds newer data",
+                        lower.get_path().file_name().unwrap_or_default()
+                    ),
+                ));
+            }
+        }
+    }
+    errors
+}
+
+fn link_or_copy(source: &Path, target: &Path) -> io::Result<()> {
+    if fs::hard_link(source, target).is_ok() {
+        return Ok(());
+    }
+    fs::copy(source, target)?;
+    fs::File::open(target)?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::StorageOptions;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_checkpoint_opens_as_copy() {
+        let temp_dir = TempDir::new().unwrap();
+        let cold_dir = TempDir::new().unwrap();
+        let options = StorageOptions::default().level_dir(1, cold_dir.path());
+        let storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+        for i in 0..250 {
+            let key = format!("key{:03}", i).into_bytes();
+            storage.put(key, vec![b'v'; 100]).unwrap();
+            if i % 50 == 49 {
+                storage.flush().unwrap();
+            }
+        }
+        storage.put(b"unflushed", b"v").unwrap();
+
+        let dest = TempDir::new().unwrap();
+        let backup = dest.path().join("backup");
+        assert_eq!(storage.checkpoint(&backup).unwrap(), 3);
+        let err = storage.checkpoint(&backup).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        // Later writes don't reach the copy
+        storage.put(b"later", b"v").unwrap();
+        let copy = Storage::new(&backup, false).unwrap();
+        assert_eq!(copy.identity(), storage.identity());
+        assert_eq!(copy.get(b"key123").unwrap(), Some(vec![b'v'; 100]));
+        assert_eq!(copy.get(b"unflushed").unwrap(), Some(b"v".to_vec()));
+        assert_eq!(copy.get(b"later").unwrap(), None);
+    }
+
+    #[test]
+    fn test_captured_tables_outlive_compaction() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut storage = Storage::new(temp_dir.path(), false).unwrap();
+        for i in 0..200 {
+            storage.put(format!("key{:03}", i), "old").unwrap();
+            if i % 50 == 49 {
+                storage.flush().unwrap();
+            }
+        }
+        storage.put("unflushed", "v").unwrap();
+        let capture = storage.capture().unwrap();
+        let captured = storage.levels().sstables.values().flatten().count();
+
+        // Replace every captured table before copying them
+        for i in 0..200 {
+            storage.put(format!("key{:03}", i), "new").unwrap();
+        }
+        storage.delete("unflushed").unwrap();
+        storage.compact_all().unwrap();
+        let dest = TempDir::new().unwrap();
+        assert_eq!(capture.write(dest.path(), None).unwrap(), captured);
+
+        let copy = Storage::new(dest.path(), false).unwrap();
+        assert_eq!(copy.get("key123").unwrap(), Some(b"old".to_vec()));
+        assert_eq!(copy.get("unflushed").unwrap(), Some(b"v".to_vec()));
+        assert_eq!(storage.get("key123").unwrap(), Some(b"new".to_vec()));
+    }
+
+    #[test]
+    fn test_restore() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        for i in 0..200 {
+            storage.put(format!("key{:03}", i), "v").unwrap();
+            if i % 50 == 49 {
+                storage.flush().unwrap();
+            }
+        }
+        let dest = TempDir::new().unwrap();
+        let backup = dest.path().join("backup");
+        storage.checkpoint(&backup).unwrap();
+        storage.put("later", "v").unwrap();
+
+        // Into a directory that doesn't exist yet
+        let data_dir = dest.path().join("restored");
+        let restored = Storage::restore(&backup, &data_dir, false).unwrap();
+        assert_eq!(restored.get("key123").unwrap(), Some(b"v".to_vec()));
+        assert_eq!(restored.get("later").unwrap(), None);
+
+        // Not over a dirty one without force, nor over an open one with it
+        let err = Storage::restore(&backup, &data_dir, false).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        let err = Storage::restore(&backup, &data_dir, true).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(restored.get("key123").unwrap(), Some(b"v".to_vec()));
+        drop(restored);
+        fs::write(data_dir.join("stray"), b"x").unwrap();
+        let restored = Storage::restore(&backup, &data_dir, true).unwrap();
+        assert!(!data_dir.join("stray").exists());
+        assert!(!dest.path().join("restored.restoring").exists());
+        assert_eq!(restored.scan(b"", b"").unwrap().count(), 200);
+    }
+
+    #[test]
+    fn test_restore_refuses_a_backup_missing_a_table() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        storage.put("key", "v").unwrap();
+        let dest = TempDir::new().unwrap();
+        let backup = dest.path().join("backup");
+        storage.checkpoint(&backup).unwrap();
+        let manifest = BackupManifest::load(&backup).unwrap();
+        fs::remove_file(backup.join(manifest.live.iter().next().unwrap())).unwrap();
+
+        let data_dir = dest.path().join("restored");
+        let err = Storage::restore(&backup, &data_dir, false).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(!data_dir.exists());
+    }
+
+    #[test]
+    fn test_verify_checkpoint() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        for i in 0..300 {
+            let key = format!("key{:03}", i).into_bytes();
+            storage.put(key, vec![b'v'; 100]).unwrap();
+            if i % 50 == 49 {
+                storage.flush().unwrap();
+            }
+        }
+
+        let dest = TempDir::new().unwrap();
+        let backup = dest.path().join("backup");
+        assert_eq!(storage.checkpoint(&backup).unwrap(), 3);
+        let report = Storage::verify_checkpoint(&backup);
+        assert!(report.is_ok(), "{:?}", report.corrupt);
+        assert_eq!(report.tables, 3);
+        assert_eq!(report.entries, 300);
+        // IDENTITY, BACKUP and the tables
+        assert_eq!(report.checked.len(), 5);
+
+        let manifest = BackupManifest::load(&backup).unwrap();
+        let missing = backup.join(manifest.live.iter().next().unwrap());
+        fs::remove_file(&missing).unwrap();
+        let report = Storage::verify_checkpoint(&backup);
+        assert_eq!(report.tables, 3);
+        assert_eq!(report.corrupt.len(), 1, "{:?}", report.corrupt);
+        assert_eq!(report.corrupt[0].0, missing);
+
+        fs::remove_file(backup.join(BACKUP_MANIFEST)).unwrap();
+        let report = Storage::verify_checkpoint(&backup);
+        assert_eq!(report.corrupt.len(), 1);
+        assert_eq!(report.corrupt[0].0, backup.join(BACKUP_MANIFEST));
+    }
+
+    #[test]
+    fn test_verify_checkpoint_length_and_order() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        for round in 0..2 {
+            storage.put(b"key".to_vec(), vec![round; 10]).unwrap();
+            storage.flush().unwrap();
+        }
+        let dest = TempDir::new().unwrap();
+        let backup = dest.path().join("backup");
+        storage.checkpoint(&backup).unwrap();
+
+        // Swapping the tables' names makes the older value win reads
+        let (older, newer) = (backup.join("L0_0.sst"), backup.join("L0_1.sst"));
+        let swap = backup.join("swap");
+        fs::rename(&older, &swap).unwrap();
+        fs::rename(&newer, &older).unwrap();
+        fs::rename(&swap, &newer).unwrap();
+        let report = Storage::verify_checkpoint(&backup);
+        assert_eq!(report.corrupt.len(), 1, "{:?}", report.corrupt);
+        assert_eq!(report.corrupt[0].0, newer);
+        assert!(report.corrupt[0].1.contains("L0_0.sst"));
+
+        // Lengths are checked against the manifest; a table failing that
+        // is left out of the ordering check
+        let manifest = fs::read_to_string(backup.join(BACKUP_MANIFEST)).unwrap();
+        fs::write(
+            backup.join(BACKUP_MANIFEST),
+            manifest.replacen("live L0_0.sst ", "live L0_0.sst 1", 1),
+        )
+        .unwrap();
+        let report = Storage::verify_checkpoint(&backup);
+        assert_eq!(report.corrupt.len(), 1, "{:?}", report.corrupt);
+        assert_eq!(report.corrupt[0].0, older);
+        assert!(report.corrupt[0].1.contains("manifest records"));
+    }
+
+    #[test]
+    fn test_incremental_backup_chain() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut storage = Storage::new(temp_dir.path(), false).unwrap();
+        let write = |storage: &mut Storage, range: std::ops::Range<usize>, value: &[u8]| {
+            for i in range {
+                let key = format!("key{:03}", i).into_bytes();
+                storage.put(key, value.to_vec()).unwrap();
+            }
+            storage.flush().unwrap();
+        };
+        write(&mut storage, 0..100, b"a");
+        write(&mut storage, 100..200, b"a");
+
+        let backups = TempDir::new().unwrap();
+        let full = backups.path().join("full");
+        assert_eq!(storage.checkpoint(&full).unwrap(), 2);
+
+        // Two more flushes fill L0 and compact every table seen so far away
+        write(&mut storage, 50..150, b"b");
+        write(&mut storage, 300..400, b"b");
+        write(&mut storage, 400..410, b"c");
+        storage.delete(b"key000").unwrap();
+
+        // The delete is flushed into a table of its own
+        let incremental = backups.path().join("incremental");
+        assert_eq!(storage.incremental_backup(&incremental, &full).unwrap(), 3);
+        let manifest = BackupManifest::load(&incremental).unwrap();
+        assert_eq!(manifest.parent, Some(fs::canonicalize(&full).unwrap()));
+        assert_eq!(manifest.live.len(), 3);
+        assert_eq!(manifest.deleted, BackupManifest::load(&full).unwrap().live);
+
+        // A further increment with nothing new copies nothing
+        let unchanged = backups.path().join("unchanged");
+        assert_eq!(
+            storage
+                .incremental_backup(&unchanged, &incremental)
+                .unwrap(),
+            0
+        );
+
+        let restore_dir = TempDir::new().unwrap();
+        let dest = restore_dir.path().join("restored");
+        assert_eq!(Storage::restore_backup(&unchanged, &dest).unwrap(), 3);
+        let restored_tables = fs::read_dir(&dest)
+            .unwrap()
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .unwrap()
+                    .path()
+                    .extension()
+                    .is_some_and(|ext| ext == "sst")
+            })
+            .count();
+        assert_eq!(restored_tables, 3);
+
+        let restored = Storage::new(&dest, false).unwrap();
+        let expected: Vec<_> = storage
+            .scan(b"", b"")
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        let actual: Vec<_> = restored
+            .scan(b"", b"")
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(actual, expected);
+        assert_eq!(restored.get(b"key000").unwrap(), None);
+        assert_eq!(restored.get(b"key120").unwrap(), Some(b"b".to_vec()));
+
+        // The chain is useless without the links holding its tables
+        fs::remove_dir_all(&incremental).unwrap();
+        let err =
+            Storage::restore_backup(&unchanged, restore_dir.path().join("again")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
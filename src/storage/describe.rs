@@ -0,0 +1,173 @@
+use std::fmt::{self, Write};
+use std::fs;
+use std::time::Duration;
+
+use super::Storage;
+
+impl Storage {
+    /// A compact, human-readable picture of the tree for debugging and bug
+    /// reports; see [`describe_to`](Storage::describe_to)
+    pub fn describe(&self) -> String {
+        let mut out = String::new();
+        self.describe_to(&mut out)
+            .expect("writing to a String cannot fail");
+        out
+    }
+
+    /// Write the memtable sizes (including one being flushed), the WAL size,
+    /// block cache usage and the options that can be changed while open, then per level the
+    /// file count, total bytes, key range, age of the oldest file and
+    /// compaction score.
+    /// Only table metadata is consulted, never data blocks.
+    pub fn describe_to(&self, out: &mut impl Write) -> fmt::Result {
+        let wal_size = self.writer().wal.size();
+        let (mem, current) = self.current();
+        writeln!(
+            out,
+            "memtable: {} entries, {} bytes",
+            mem.memtable.len(),
+            mem.memtable.size()
+        )?;
+        if let Some(immutable) = &mem.immutable {
+            writeln!(
+                out,
+                "immutable memtable: {} entries, {} bytes, flushing",
+                immutable.memtable.len(),
+                immutable.memtable.size()
+            )?;
+        }
+        drop(mem);
+        match wal_size {
+            Ok(size) => writeln!(out, "wal: {} bytes", size)?,
+            Err(e) => writeln!(out, "wal: size unavailable ({})", e)?,
+        }
+        if let Some(cache) = &self.block_cache {
+            let stats = cache.stats();
+            writeln!(
+                out,
+                "block cache: {} of {} bytes, {} hits, {} misses",
+                stats.bytes, stats.capacity, stats.hits, stats.misses
+            )?;
+        }
+        writeln!(out, "options: {}", self.options)?;
+
+        let mut levels: Vec<_> = current
+            .sstables
+            .iter()
+            .filter(|(_, tables)| !tables.is_empty())
+            .collect();
+        levels.sort_by_key(|(level, _)| **level);
+        if levels.is_empty() {
+            return writeln!(out, "no sstables");
+        }
+
+        for (level, tables) in levels {
+            let bytes: usize = tables.iter().map(|t| t.size()).sum();
+            write!(out, "L{}: {} files, {} bytes", level, tables.len(), bytes)?;
+
+            let ranges = tables.iter().filter_map(|t| t.key_range());
+            let smallest = ranges.clone().map(|(smallest, _)| smallest).min();
+            let largest = ranges.map(|(_, largest)| largest).max();
+            if let (Some(smallest), Some(largest)) = (smallest, largest) {
+                write!(
+                    out,
+                    ", keys [{}, {}]",
+                    smallest.escape_ascii(),
+                    largest.escape_ascii()
+                )?;
+            }
+
+            let oldest = tables
+                .iter()
+                .filter_map(|t| {
+                    fs::metadata(t.get_path())
+                        .ok()?
+                        .modified()
+                        .ok()?
+                        .elapsed()
+                        .ok()
+                })
+                .max();
+            if let Some(age) = oldest {
+                write!(out, ", oldest {}", format_age(age))?;
+            }
+
+            writeln!(
+                out,
+                ", score {:.2}",
+                self.compaction_manager.score(*level, tables)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_describe_empty() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        let description = storage.describe();
+        assert!(description.contains("memtable: 0 entries"));
+        // Just the log's header
+        assert!(description.contains("wal: 8 bytes"));
+        assert!(description.contains("no sstables"));
+        assert!(description.contains("block cache: 0 of 8388608 bytes, 0 hits, 0 misses"));
+    }
+
+    #[test]
+    fn test_describe_levels() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+
+        // Four flushes compact into L1, then one more table lands in L0
+        for round in 0..5 {
+            for i in 0..50 {
+                let key = format!("key{:03}", round * 50 + i).into_bytes();
+                storage.put(key, vec![b'v'; 100]).unwrap();
+            }
+            storage.flush().unwrap();
+        }
+        storage.put(b"pending", b"v").unwrap();
+
+        let description = storage.describe();
+        assert!(description.contains("memtable: 1 entries"));
+        assert!(!description.contains("wal: 0 bytes"));
+
+        let line = |level: &str| {
+            description
+                .lines()
+                .find(|line| line.starts_with(level))
+                .unwrap_or_else(|| panic!("{} missing from:\n{}", level, description))
+                .to_string()
+        };
+        let l0 = line("L0:");
+        assert!(l0.contains("1 files"));
+        assert!(l0.contains(&format!(
+            "{} bytes",
+            storage.levels().sstables[&0][0].size()
+        )));
+        assert!(l0.contains("keys [key200, key249]"));
+        assert!(l0.contains("score 0.25"));
+
+        let l1 = line("L1:");
+        assert!(l1.contains("1 files"));
+        assert!(l1.contains("keys [key000, key199]"));
+        assert!(l1.contains("oldest "));
+        assert!(!description.contains("L2:"));
+    }
+}
@@ -0,0 +1,243 @@
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+use super::{bulk, Storage};
+use crate::error::StorageError;
+use crate::{Key, Value};
+
+/// Marks the start of a dump
+const DUMP_MAGIC: &[u8; 8] = b"LSMDUMP\0";
+/// Dump layout this build writes and reads
+const DUMP_VERSION: u32 = 1;
+// A key length no pair can have, marking the trailer
+const END_OF_PAIRS: u32 = u32::MAX;
+
+// Layout: [magic][version u32], then per pair [key_len u32][key]
+// [value_len u32][value] in ascending key order, then a trailer of
+// [END_OF_PAIRS][pair_count u64][crc32 of the pairs u32]. A missing trailer
+// means the dump was cut short.
+
+impl Storage {
+    /// Stream every live pair into `w` in ascending key order, as a dump
+    /// [`import_from`](Storage::import_from) can load into another
+    /// database. Values are written as reads see them: merge operands
+    /// applied, deleted and expired keys left out, and expiry times not
+    /// kept. Returns the pairs written.
+    pub fn export_to<W: Write>(&self, w: W) -> Result<u64, StorageError> {
+        let mut w = BufWriter::new(w);
+        w.write_all(DUMP_MAGIC)?;
+        w.write_all(&DUMP_VERSION.to_le_bytes())?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        let mut count = 0u64;
+        for pair in self.scan(b"", b"")? {
+            let (key, value) = pair?;
+            for field in [&key, &value] {
+                let len = u32::try_from(field.len())
+                    .ok()
+                    .filter(|&len| len != END_OF_PAIRS);
+                let len = len.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "Pair {:?} is too large to dump",
+                            String::from_utf8_lossy(&key)
+                        ),
+                    )
+                })?;
+                hasher.update(&len.to_le_bytes());
+                hasher.update(field);
+                w.write_all(&len.to_le_bytes())?;
+                w.write_all(field)?;
+            }
+            count += 1;
+        }
+
+        w.write_all(&END_OF_PAIRS.to_le_bytes())?;
+        w.write_all(&count.to_le_bytes())?;
+        w.write_all(&hasher.finalize().to_le_bytes())?;
+        w.flush()?;
+        Ok(count)
+    }
+
+    /// Load a dump from [`export_to`](Storage::export_to) straight into
+    /// sorted tables, as `bulk_load_sorted` does, rather than through the
+    /// WAL and memtable. Keys already present are overwritten. A dump of
+    /// another version, or one that's damaged or cut short, is refused
+    /// with nothing loaded. Returns the pairs loaded.
+    pub fn import_from<R: Read>(&mut self, r: R) -> Result<u64, StorageError> {
+        let mut pairs = Pairs::open(BufReader::new(r))?;
+        bulk::load_sorted(self, &mut pairs, true)?;
+        Ok(pairs.count)
+    }
+}
+
+/// The pairs of a dump in order, checking the trailer once they run out
+struct Pairs<R> {
+    r: R,
+    hasher: crc32fast::Hasher,
+    count: u64,
+    done: bool,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+impl<R: Read> Pairs<R> {
+    /// Check the header of the dump in `r`
+    fn open(mut r: R) -> io::Result<Self> {
+        let mut header = [0u8; 12];
+        read_exact(&mut r, &mut header)?;
+        if &header[..8] != DUMP_MAGIC {
+            return Err(invalid("Not a dump"));
+        }
+        let version = u32::from_le_bytes(header[8..].try_into().unwrap());
+        if version != DUMP_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unsupported dump version {}", version),
+            ));
+        }
+        Ok(Pairs {
+            r,
+            hasher: crc32fast::Hasher::new(),
+            count: 0,
+            done: false,
+        })
+    }
+
+    fn next_pair(&mut self) -> io::Result<Option<(Key, Value)>> {
+        let key_len = self.read_u32()?;
+        if key_len == END_OF_PAIRS {
+            let mut trailer = [0u8; 12];
+            read_exact(&mut self.r, &mut trailer)?;
+            let count = u64::from_le_bytes(trailer[..8].try_into().unwrap());
+            let crc = u32::from_le_bytes(trailer[8..].try_into().unwrap());
+            if count != self.count || crc != self.hasher.clone().finalize() {
+                return Err(invalid("Dump is damaged"));
+            }
+            return Ok(None);
+        }
+        let key = self.read_field(key_len)?;
+        let value_len = self.read_u32()?;
+        let value = self.read_field(value_len)?;
+        self.count += 1;
+        Ok(Some((key, value)))
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        let mut buf = [0u8; 4];
+        read_exact(&mut self.r, &mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn read_field(&mut self, len: u32) -> io::Result<Vec<u8>> {
+        let mut field = Vec::new();
+        let read = (&mut self.r).take(len as u64).read_to_end(&mut field)?;
+        if read != len as usize {
+            return Err(invalid("Dump is truncated"));
+        }
+        self.hasher.update(&len.to_le_bytes());
+        self.hasher.update(&field);
+        Ok(field)
+    }
+}
+
+impl<R: Read> Iterator for Pairs<R> {
+    type Item = io::Result<(Key, Value)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let pair = self.next_pair().transpose();
+        self.done = !matches!(pair, Some(Ok(_)));
+        pair
+    }
+}
+
+/// `read_exact`, reporting running out of input as a truncated dump
+fn read_exact<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<()> {
+    r.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => invalid("Dump is truncated"),
+        _ => e,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::StorageOptions;
+    use tempfile::TempDir;
+
+    fn scan_all(storage: &Storage) -> Vec<(Key, Value)> {
+        storage
+            .scan(b"", b"")
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    fn populated() -> (TempDir, Storage) {
+        let temp_dir = TempDir::new().unwrap();
+        let options = StorageOptions::default().memtable_size(16 * 1024);
+        let storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+        for i in 0..2000 {
+            storage
+                .put(format!("key{:05}", i), vec![i as u8; 50])
+                .unwrap();
+        }
+        for i in (0..2000).step_by(3) {
+            storage.delete(format!("key{:05}", i)).unwrap();
+        }
+        storage.put("key00001", "overwritten").unwrap();
+        storage.put("", "empty key").unwrap();
+        (temp_dir, storage)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (_temp_dir, storage) = populated();
+        let mut dump = Vec::new();
+        let exported = storage.export_to(&mut dump).unwrap();
+        let expected = scan_all(&storage);
+        assert_eq!(exported, expected.len() as u64);
+
+        let options = StorageOptions::default().memtable_size(16 * 1024);
+        let temp_dir = TempDir::new().unwrap();
+        let mut copy = Storage::open_with_options(temp_dir.path(), options).unwrap();
+        copy.put("key00001", "older").unwrap();
+        assert_eq!(copy.import_from(dump.as_slice()).unwrap(), exported);
+        assert_eq!(scan_all(&copy), expected);
+        // Straight into tables, not through the memtable
+        assert!(copy.mem().memtable.is_empty());
+        assert!(copy.levels().sstables.values().flatten().count() > 1);
+    }
+
+    #[test]
+    fn test_bad_dumps_load_nothing() {
+        let (_temp_dir, storage) = populated();
+        let mut dump = Vec::new();
+        storage.export_to(&mut dump).unwrap();
+
+        let mut newer = dump.clone();
+        newer[8] = 2;
+        let mut flipped = dump.clone();
+        let middle = flipped.len() / 2;
+        flipped[middle] ^= 0xff;
+        for (bad, message) in [
+            (newer, "Unsupported dump version 2"),
+            (dump[..dump.len() / 2].to_vec(), "truncated"),
+            (dump[..dump.len() - 1].to_vec(), "truncated"),
+            (flipped, ""),
+            (b"not a dump at all".to_vec(), "Not a dump"),
+        ] {
+            let temp_dir = TempDir::new().unwrap();
+            let mut copy = Storage::new(temp_dir.path(), false).unwrap();
+            let err = copy.import_from(bad.as_slice()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains(message), "{}", err);
+            assert!(scan_all(&copy).is_empty());
+        }
+    }
+}
@@ -0,0 +1,425 @@
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::Storage;
+use crate::error::StorageError;
+
+/// Journal of flushes and compactions, one JSON object per line
+pub const EVENTS_FILE: &str = "EVENTS";
+// Where the journal is moved once it outgrows `MAX_EVENTS_BYTES`
+const ROTATED_EVENTS_FILE: &str = "EVENTS.old";
+const MAX_EVENTS_BYTES: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Flush,
+    Compaction,
+}
+
+impl EventKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            EventKind::Flush => "flush",
+            EventKind::Compaction => "compaction",
+        }
+    }
+}
+
+/// A flush or compaction as recorded in the journal
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub kind: EventKind,
+    /// When the operation started, in milliseconds since the Unix epoch
+    pub started_ms: u64,
+    pub duration_ms: u64,
+    /// Level the output table was written to
+    pub level: usize,
+    /// Numbers of the tables consumed; empty for a flush
+    pub inputs: Vec<u64>,
+    /// Number of the table written
+    pub output: u64,
+    pub bytes_in: u64,
+    /// Size of the output table, or 0 if the operation failed
+    pub bytes_out: u64,
+    pub error: Option<String>,
+}
+
+impl Event {
+    /// Input bytes processed per second of this operation
+    pub fn throughput(&self) -> u64 {
+        self.bytes_in * 1000 / self.duration_ms.max(1)
+    }
+
+    fn to_json(&self) -> String {
+        let inputs: Vec<String> = self.inputs.iter().map(u64::to_string).collect();
+        let error = match &self.error {
+            Some(error) => json_string(error),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"kind\":\"{}\",\"started_ms\":{},\"duration_ms\":{},\"level\":{},\
+             \"inputs\":[{}],\"output\":{},\"bytes_in\":{},\"bytes_out\":{},\"error\":{}}}",
+            self.kind.as_str(),
+            self.started_ms,
+            self.duration_ms,
+            self.level,
+            inputs.join(","),
+            self.output,
+            self.bytes_in,
+            self.bytes_out,
+            error
+        )
+    }
+
+    /// Parse a line written by `to_json`, or `None` for anything else
+    /// (such as a line torn by a crash)
+    fn from_json(line: &str) -> Option<Self> {
+        let mut parser = Parser { rest: line.trim() };
+        parser.eat('{')?;
+        let (mut kind, mut started_ms, mut duration_ms, mut level) = (None, None, None, None);
+        let (mut inputs, mut output, mut bytes_in, mut bytes_out) = (None, None, None, None);
+        let mut error = None;
+        loop {
+            let field = parser.string()?;
+            parser.eat(':')?;
+            match field.as_str() {
+                "kind" => {
+                    kind = match parser.string()?.as_str() {
+                        "flush" => Some(EventKind::Flush),
+                        "compaction" => Some(EventKind::Compaction),
+                        _ => return None,
+                    }
+                }
+                "started_ms" => started_ms = Some(parser.number()?),
+                "duration_ms" => duration_ms = Some(parser.number()?),
+                "level" => level = Some(parser.number()? as usize),
+                "inputs" => inputs = Some(parser.numbers()?),
+                "output" => output = Some(parser.number()?),
+                "bytes_in" => bytes_in = Some(parser.number()?),
+                "bytes_out" => bytes_out = Some(parser.number()?),
+                "error" => {
+                    error = if parser.rest.starts_with("null") {
+                        parser.rest = &parser.rest[4..];
+                        None
+                    } else {
+                        Some(parser.string()?)
+                    }
+                }
+                _ => return None,
+            }
+            if parser.eat('}').is_some() {
+                break;
+            }
+            parser.eat(',')?;
+        }
+        if !parser.rest.is_empty() {
+            return None;
+        }
+        Some(Event {
+            kind: kind?,
+            started_ms: started_ms?,
+            duration_ms: duration_ms?,
+            level: level?,
+            inputs: inputs?,
+            output: output?,
+            bytes_in: bytes_in?,
+            bytes_out: bytes_out?,
+            error,
+        })
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}", self.started_ms, self.kind.as_str())?;
+        if !self.inputs.is_empty() {
+            let inputs: Vec<String> = self.inputs.iter().map(u64::to_string).collect();
+            write!(f, " {}", inputs.join(","))?;
+        }
+        write!(
+            f,
+            " -> L{}_{} ({} -> {} bytes, {} ms)",
+            self.level, self.output, self.bytes_in, self.bytes_out, self.duration_ms
+        )?;
+        if let Some(error) = &self.error {
+            write!(f, " failed: {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Quote `s` as a JSON string
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Just enough JSON to read back what `Event::to_json` writes
+struct Parser<'a> {
+    rest: &'a str,
+}
+
+impl Parser<'_> {
+    fn eat(&mut self, c: char) -> Option<()> {
+        self.rest = self.rest.strip_prefix(c)?;
+        Some(())
+    }
+
+    fn number(&mut self) -> Option<u64> {
+        let end = self
+            .rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(self.rest.len());
+        let number = self.rest[..end].parse().ok()?;
+        self.rest = &self.rest[end..];
+        Some(number)
+    }
+
+    fn numbers(&mut self) -> Option<Vec<u64>> {
+        self.eat('[')?;
+        let mut numbers = Vec::new();
+        if self.eat(']').is_some() {
+            return Some(numbers);
+        }
+        loop {
+            numbers.push(self.number()?);
+            if self.eat(']').is_some() {
+                return Some(numbers);
+            }
+            self.eat(',')?;
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.eat('"')?;
+        let mut out = String::new();
+        let mut chars = self.rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.rest = &self.rest[i + 1..];
+                    return Some(out);
+                }
+                '\\' => match chars.next()?.1 {
+                    'n' => out.push('\n'),
+                    'u' => {
+                        let hex: String = (0..4)
+                            .map(|_| chars.next().map(|(_, c)| c))
+                            .collect::<Option<_>>()?;
+                        out.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                    }
+                    c => out.push(c),
+                },
+                c => out.push(c),
+            }
+        }
+        None
+    }
+}
+
+/// Appends events to the journal in a data directory. Writing is best
+/// effort: failures are counted, never returned.
+pub(super) struct EventLog {
+    dir: PathBuf,
+    errors: AtomicU64,
+}
+
+impl EventLog {
+    pub(super) fn new(dir: &Path) -> Self {
+        EventLog {
+            dir: dir.to_path_buf(),
+            errors: AtomicU64::new(0),
+        }
+    }
+
+    pub(super) fn record(&self, event: &Event) {
+        self.record_line(event.to_json());
+    }
+
+    /// Append any other JSON object; readers skip lines that aren't events
+    pub(super) fn record_line(&self, line: String) {
+        if self.append(line).is_err() {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Journal writes that failed since this instance was opened
+    pub(super) fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    fn append(&self, mut line: String) -> io::Result<()> {
+        let path = self.dir.join(EVENTS_FILE);
+        match fs::metadata(&path) {
+            Ok(metadata) if metadata.len() >= MAX_EVENTS_BYTES => {
+                fs::rename(&path, self.dir.join(ROTATED_EVENTS_FILE))?;
+            }
+            _ => {}
+        }
+        line.push('\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?
+            .write_all(line.as_bytes())
+    }
+
+    /// The last `n` events, oldest first, including the rotated file
+    fn recent(&self, n: usize) -> io::Result<Vec<Event>> {
+        let mut events = Vec::new();
+        for name in [ROTATED_EVENTS_FILE, EVENTS_FILE] {
+            let text = match fs::read_to_string(self.dir.join(name)) {
+                Ok(text) => text,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            events.extend(text.lines().filter_map(Event::from_json));
+        }
+        let skip = events.len().saturating_sub(n);
+        Ok(events.split_off(skip))
+    }
+}
+
+impl Storage {
+    /// The last `n` flushes and compactions recorded in the data
+    /// directory's journal, oldest first
+    pub fn recent_events(&self, n: usize) -> Result<Vec<Event>, StorageError> {
+        Ok(self.events.recent(n)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+    use tempfile::TempDir;
+
+    fn table_number(path: &Path) -> u64 {
+        super::super::parse_table_name(path).unwrap().1
+    }
+
+    #[test]
+    fn test_flush_and_compaction_recorded() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+
+        let mut flushed = Vec::new();
+        for round in 0..4 {
+            for i in 0..50 {
+                let key = format!("key{:03}", round * 50 + i).into_bytes();
+                storage.put(key, vec![b'v'; 100]).unwrap();
+            }
+            storage.flush().unwrap();
+            if round < 3 {
+                flushed.push(table_number(
+                    storage.levels().sstables[&0].last().unwrap().get_path(),
+                ));
+            }
+        }
+        // The fourth flush triggered a compaction of all four L0 tables
+        assert!(storage.levels().sstables[&0].is_empty());
+        let compacted = &storage.levels().sstables[&1][0];
+
+        let events = storage.recent_events(10).unwrap();
+        assert_eq!(events.len(), 5);
+        let flushes: Vec<_> = events
+            .iter()
+            .filter(|e| e.kind == EventKind::Flush)
+            .collect();
+        assert_eq!(flushes.len(), 4);
+        assert_eq!(
+            flushes[..3].iter().map(|e| e.output).collect::<Vec<_>>(),
+            flushed
+        );
+        assert!(flushes.iter().all(|e| e.level == 0 && e.bytes_out > 0));
+
+        let compaction = events.last().unwrap();
+        assert_eq!(compaction.kind, EventKind::Compaction);
+        assert_eq!(compaction.level, 1);
+        assert_eq!(compaction.output, table_number(compacted.get_path()));
+        assert_eq!(compaction.bytes_out, compacted.size() as u64);
+        assert_eq!(
+            compaction.inputs,
+            flushes.iter().map(|e| e.output).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            compaction.bytes_in,
+            flushes.iter().map(|e| e.bytes_out).sum::<u64>()
+        );
+        assert_eq!(compaction.error, None);
+
+        // The journal outlives the instance
+        drop(storage);
+        let reopened = Storage::new(temp_dir.path(), false).unwrap();
+        assert_eq!(reopened.recent_events(2).unwrap(), events[3..]);
+    }
+
+    #[test]
+    fn test_journal_failures_are_counted() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        // A directory in the journal's place makes every append fail
+        fs::create_dir(temp_dir.path().join(EVENTS_FILE)).unwrap();
+
+        storage.put(b"key", b"value").unwrap();
+        storage.flush().unwrap();
+        assert_eq!(storage.stats().event_log_errors, 1);
+        assert_eq!(storage.get(b"key").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_rotation_and_round_trip() {
+        let temp_dir = TempDir::new().unwrap();
+        let log = EventLog::new(temp_dir.path());
+        let event = |output| Event {
+            kind: EventKind::Compaction,
+            started_ms: 1_700_000_000_000,
+            duration_ms: 12,
+            level: 2,
+            inputs: vec![3, 4],
+            output,
+            bytes_in: 4096,
+            bytes_out: 0,
+            error: Some("disk \"full\"\n\u{1}".to_string()),
+        };
+
+        // Equal-width table numbers keep every line the same length
+        let first = 1_000_000;
+        let line_len = event(first).to_json().len() as u64 + 1;
+        let end = first + MAX_EVENTS_BYTES / line_len + 2;
+        for output in first..end {
+            log.record(&event(output));
+        }
+        assert_eq!(log.errors(), 0);
+        assert!(temp_dir.path().join(ROTATED_EVENTS_FILE).exists());
+        assert!(
+            fs::metadata(temp_dir.path().join(EVENTS_FILE))
+                .unwrap()
+                .len()
+                < line_len * 2
+        );
+
+        // Reads span the rotated file and skip a torn final line
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(temp_dir.path().join(EVENTS_FILE))
+            .unwrap();
+        file.write_all(b"{\"kind\":\"flu").unwrap();
+        let recent = log.recent(3).unwrap();
+        assert_eq!(recent, [event(end - 3), event(end - 2), event(end - 1)]);
+    }
+}
@@ -0,0 +1,258 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use arrow_array::builder::BinaryBuilder;
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{ArrowError, DataType, Field, Schema, SchemaRef};
+use parquet::arrow::ArrowWriter;
+use parquet::file::properties::WriterProperties;
+
+use super::{Scan, Storage};
+use crate::error::StorageError;
+use crate::{Key, Value};
+
+// Rows per record batch unless the hint says otherwise
+const DEFAULT_BATCH_ROWS: usize = 8192;
+
+/// Turns one batch of values into typed columns, one per hinted field
+type Decoder = Box<dyn Fn(&[Value]) -> Result<Vec<ArrayRef>, ArrowError> + Send>;
+
+/// How [`Storage::export_arrow`] lays out its batches: a Binary `key`
+/// column, followed by either a Binary `value` column or the columns a
+/// caller-supplied decoder makes of the values
+pub struct SchemaHint {
+    batch_rows: usize,
+    decoder: Option<(Vec<Field>, Decoder)>,
+}
+
+impl Default for SchemaHint {
+    fn default() -> Self {
+        SchemaHint {
+            batch_rows: DEFAULT_BATCH_ROWS,
+            decoder: None,
+        }
+    }
+}
+
+impl SchemaHint {
+    /// Rows per batch, which bounds how many pairs are held at once
+    pub fn batch_rows(mut self, rows: usize) -> Self {
+        self.batch_rows = rows.max(1);
+        self
+    }
+
+    /// Replace the `value` column with `fields`, filled by `decode` from
+    /// each batch's values; it must return one array per field, each as
+    /// long as the batch
+    pub fn decoded<F>(mut self, fields: Vec<Field>, decode: F) -> Self
+    where
+        F: Fn(&[Value]) -> Result<Vec<ArrayRef>, ArrowError> + Send + 'static,
+    {
+        self.decoder = Some((fields, Box::new(decode)));
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        let mut fields = vec![Field::new("key", DataType::Binary, false)];
+        match &self.decoder {
+            Some((decoded, _)) => fields.extend(decoded.iter().cloned()),
+            None => fields.push(Field::new("value", DataType::Binary, false)),
+        }
+        Arc::new(Schema::new(fields))
+    }
+}
+
+/// Record batches of a range, read as they're asked for
+pub struct ArrowExport {
+    scan: Scan,
+    schema: SchemaRef,
+    hint: SchemaHint,
+}
+
+impl ArrowExport {
+    pub fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn next_batch(&mut self) -> io::Result<Option<RecordBatch>> {
+        let mut keys: Vec<Key> = Vec::with_capacity(self.hint.batch_rows);
+        let mut values: Vec<Value> = Vec::with_capacity(self.hint.batch_rows);
+        for pair in self.scan.by_ref().take(self.hint.batch_rows) {
+            let (key, value) = pair?;
+            keys.push(key);
+            values.push(value);
+        }
+        if keys.is_empty() {
+            return Ok(None);
+        }
+
+        let mut columns = vec![binary_array(&keys)];
+        match &self.hint.decoder {
+            Some((_, decode)) => columns.extend(decode(&values).map_err(invalid)?),
+            None => columns.push(binary_array(&values)),
+        }
+        RecordBatch::try_new(self.schema.clone(), columns)
+            .map(Some)
+            .map_err(invalid)
+    }
+}
+
+impl Iterator for ArrowExport {
+    type Item = io::Result<RecordBatch>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_batch().transpose()
+    }
+}
+
+fn binary_array(items: &[Vec<u8>]) -> ArrayRef {
+    let bytes = items.iter().map(Vec::len).sum();
+    let mut builder = BinaryBuilder::with_capacity(items.len(), bytes);
+    for item in items {
+        builder.append_value(item);
+    }
+    Arc::new(builder.finish())
+}
+
+fn invalid(e: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+impl Storage {
+    /// Live pairs with keys in `[start, end)` (an empty `end` runs to the
+    /// last key) as Arrow record batches laid out per `hint`. The range is
+    /// streamed, so at most one batch is held in memory.
+    pub fn export_arrow(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        hint: SchemaHint,
+    ) -> Result<ArrowExport, StorageError> {
+        Ok(ArrowExport {
+            scan: self.scan(start, end)?,
+            schema: hint.schema(),
+            hint,
+        })
+    }
+
+    /// Write `[start, end)` to a new Parquet file at `path` with Binary
+    /// `key` and `value` columns. Row groups are capped at one batch so the
+    /// writer buffers no more than the export does. Returns the rows written.
+    pub fn export_parquet<P: AsRef<Path>>(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        path: P,
+    ) -> Result<u64, StorageError> {
+        let export = self.export_arrow(start, end, SchemaHint::default())?;
+        let file = File::create(path)?;
+        let properties = WriterProperties::builder()
+            .set_max_row_group_size(DEFAULT_BATCH_ROWS)
+            .build();
+        let mut writer =
+            ArrowWriter::try_new(file, export.schema(), Some(properties)).map_err(invalid)?;
+        let mut rows = 0;
+        for batch in export {
+            let batch = batch?;
+            rows += batch.num_rows() as u64;
+            writer.write(&batch).map_err(invalid)?;
+        }
+        writer.close().map_err(invalid)?;
+        Ok(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{Array, BinaryArray, UInt64Array};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use tempfile::TempDir;
+
+    fn populated() -> (TempDir, Storage) {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        for i in 0..3000u64 {
+            let key = format!("key{:05}", i).into_bytes();
+            storage.put(key, i.to_le_bytes().to_vec()).unwrap();
+            if i % 1000 == 999 {
+                storage.flush().unwrap();
+            }
+        }
+        storage.delete(b"key01500").unwrap();
+        (temp_dir, storage)
+    }
+
+    #[test]
+    fn test_export_parquet_round_trip() {
+        let (temp_dir, storage) = populated();
+        let path = temp_dir.path().join("export.parquet");
+        let rows = storage
+            .export_parquet(b"key00500", b"key02500", &path)
+            .unwrap();
+        assert_eq!(rows, 1999);
+
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut pairs = Vec::new();
+        for batch in reader {
+            let batch = batch.unwrap();
+            let keys = batch
+                .column(0)
+                .as_any()
+                .downcast_ref::<BinaryArray>()
+                .unwrap();
+            let values = batch
+                .column(1)
+                .as_any()
+                .downcast_ref::<BinaryArray>()
+                .unwrap();
+            for row in 0..batch.num_rows() {
+                pairs.push((keys.value(row).to_vec(), values.value(row).to_vec()));
+            }
+        }
+        assert_eq!(pairs.len(), 1999);
+        for (i, index) in [(0, 500u64), (999, 1499), (1000, 1501), (1998, 2499)] {
+            assert_eq!(pairs[i].0, format!("key{:05}", index).into_bytes());
+            assert_eq!(pairs[i].1, index.to_le_bytes().to_vec());
+        }
+    }
+
+    #[test]
+    fn test_export_arrow_decoded() {
+        let (_temp_dir, storage) = populated();
+        let hint = SchemaHint::default().batch_rows(1000).decoded(
+            vec![Field::new("n", DataType::UInt64, false)],
+            |values| {
+                let numbers = values
+                    .iter()
+                    .map(|v| u64::from_le_bytes(v.as_slice().try_into().unwrap()));
+                Ok(vec![
+                    Arc::new(UInt64Array::from_iter_values(numbers)) as ArrayRef
+                ])
+            },
+        );
+        let export = storage.export_arrow(b"", b"", hint).unwrap();
+        assert_eq!(export.schema().field(1).name(), "n");
+
+        let batches: Vec<RecordBatch> = export.map(Result::unwrap).collect();
+        assert_eq!(
+            batches
+                .iter()
+                .map(RecordBatch::num_rows)
+                .collect::<Vec<_>>(),
+            vec![1000, 1000, 999]
+        );
+        let numbers = batches[2]
+            .column(1)
+            .as_any()
+            .downcast_ref::<UInt64Array>()
+            .unwrap();
+        assert_eq!(numbers.value(998), 2999);
+        assert_eq!(numbers.len(), 999);
+    }
+}
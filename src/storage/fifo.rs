@@ -0,0 +1,160 @@
+use std::io;
+
+use log::info;
+
+use super::manifest::ManifestEdit;
+use super::{parse_table_name, Storage, Writer};
+
+impl Storage {
+    /// Delete the oldest tables until the rest fit in `max_bytes`. Scans
+    /// still reading an evicted table keep it until they finish.
+    pub(super) fn evict_fifo(&self, writer: &mut Writer, max_bytes: u64) -> io::Result<()> {
+        let mut tables: Vec<(u64, usize, u64)> = self
+            .levels()
+            .sstables
+            .iter()
+            .flat_map(|(&level, tables)| {
+                tables.iter().map(move |table| {
+                    let number = parse_table_name(table.get_path()).map_or(0, |(_, n)| n);
+                    (number, level, table.size() as u64)
+                })
+            })
+            .collect();
+        tables.sort_unstable();
+
+        let mut total: u64 = tables.iter().map(|(_, _, size)| size).sum();
+        let mut evicted = Vec::new();
+        for (number, level, size) in tables {
+            if total <= max_bytes {
+                break;
+            }
+            total -= size;
+            evicted.push((number, level));
+        }
+        if evicted.is_empty() {
+            return Ok(());
+        }
+
+        info!(
+            files = evicted.len(),
+            bytes_kept = total;
+            "FIFO eviction of {} tables",
+            evicted.len()
+        );
+        let edits: Vec<_> = evicted
+            .iter()
+            .map(|&(seq, level)| ManifestEdit::RemoveFile { level, seq })
+            .collect();
+        writer.manifest.record(&edits)?;
+        self.edit_levels(|current| {
+            for &(number, level) in &evicted {
+                let tables = current.sstables.get_mut(&level).unwrap();
+                tables.retain(|table| {
+                    let oldest = parse_table_name(table.get_path()) == Some((level, number));
+                    if oldest {
+                        table.mark_obsolete();
+                    }
+                    !oldest
+                });
+            }
+            let mut levels: Vec<usize> = evicted.iter().map(|&(_, level)| level).collect();
+            levels.sort_unstable();
+            levels.dedup();
+            for level in levels {
+                current.arrange_level(level);
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{CompactionStrategy, StorageOptions};
+    use tempfile::TempDir;
+
+    const CAP: u64 = 50_000;
+
+    fn open(dir: &TempDir) -> Storage {
+        let options = StorageOptions::default().compaction_strategy(CompactionStrategy::Fifo {
+            max_fifo_bytes: CAP,
+        });
+        Storage::open_with_options(dir.path(), options).unwrap()
+    }
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("key{:05}", i).into_bytes()
+    }
+
+    fn table_bytes(storage: &Storage) -> u64 {
+        storage
+            .levels()
+            .sstables
+            .values()
+            .flatten()
+            .map(|table| table.size() as u64)
+            .sum()
+    }
+
+    #[test]
+    fn test_oldest_tables_evicted_past_cap() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = open(&temp_dir);
+
+        // Twenty flushes of ten 1KB values, about 10KB each
+        for i in 0..200 {
+            storage.put(key(i), vec![b'v'; 1000]).unwrap();
+            if i % 10 == 9 {
+                storage.flush().unwrap();
+                assert!(table_bytes(&storage) <= CAP);
+            }
+        }
+
+        // Nothing was merged: every table is a flush output in L0
+        assert!(storage.levels().sstables.keys().all(|&level| level == 0));
+        assert!(storage
+            .recent_events(100)
+            .unwrap()
+            .iter()
+            .all(|e| e.inputs.is_empty()));
+        let kept = storage.level_files(0).len();
+        assert!((3..=5).contains(&kept), "{} tables kept", kept);
+
+        assert_eq!(storage.get(key(0)).unwrap(), None);
+        assert_eq!(storage.get(key(199)).unwrap(), Some(vec![b'v'; 1000]));
+        let oldest_kept = 200 - kept * 10;
+        assert_eq!(storage.get(key(oldest_kept - 1)).unwrap(), None);
+        assert!(storage.get(key(oldest_kept)).unwrap().is_some());
+
+        // Deleting evicted data is a harmless no-op, and eviction survives a
+        // reopen
+        storage.delete(key(3)).unwrap();
+        storage.flush().unwrap();
+        drop(storage);
+        let storage = open(&temp_dir);
+        assert_eq!(storage.get(key(3)).unwrap(), None);
+        assert_eq!(storage.get(key(199)).unwrap(), Some(vec![b'v'; 1000]));
+        assert!(table_bytes(&storage) <= CAP);
+    }
+
+    #[test]
+    fn test_fifo_refuses_merging() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = StorageOptions::default()
+            .compaction_strategy(CompactionStrategy::Fifo {
+                max_fifo_bytes: CAP,
+            })
+            .max_total_bytes(Some(CAP));
+        let err = Storage::open_with_options(temp_dir.path(), options)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let mut storage = open(&temp_dir);
+        storage.put(key(0), b"v".to_vec()).unwrap();
+        storage.flush().unwrap();
+        let err = storage.compact_level(0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+}
@@ -0,0 +1,452 @@
+use std::io;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use log::{debug, info, warn};
+
+use super::background::Job;
+use super::manifest::ManifestEdit;
+use super::{lifetime, Event, EventKind, Storage, Writer};
+use crate::memtable::MemTable;
+use crate::sstable::SSTable;
+
+/// A full memtable that no longer takes writes. Reads still see it until
+/// its table is installed in L0, and its WAL segments stay on disk until
+/// then.
+pub(super) struct Immutable {
+    pub(super) memtable: Arc<MemTable>,
+    log: u64,
+    // Sequence number of the last write its segments hold
+    last_seq: u64,
+}
+
+/// Points in a flush where a test can stop it, as a crash would
+#[cfg(test)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum FlushStep {
+    /// The table is synced under its name, but not yet in the manifest,
+    /// and the WAL still holds its writes
+    TableWritten,
+    /// The table is installed and its WAL segments removed
+    WalCleared,
+}
+
+/// A background thread writing the immutable memtable to an L0 table.
+/// `None` in the writer once a flush has failed, until the next attempt
+/// starts.
+pub(super) struct Flush {
+    handle: JoinHandle<io::Result<SSTable>>,
+    number: u64,
+    started: Instant,
+    started_ms: u64,
+}
+
+impl Storage {
+    /// Start flushing once the memtable has reached the configured size.
+    /// Writes carry on into a fresh memtable meanwhile, unless the previous
+    /// flush is still running, in which case this waits for it. Background
+    /// work that has finished is installed along the way.
+    pub(super) fn maybe_flush(&self, writer: &mut Writer) -> io::Result<()> {
+        let finished = writer
+            .flush
+            .as_ref()
+            .is_some_and(|flush| flush.handle.is_finished());
+        if finished {
+            self.wait_for_flush(writer)?;
+        }
+        let compacted = writer
+            .compacting
+            .as_ref()
+            .is_some_and(|compacting| compacting.is_finished());
+        if compacted {
+            // Install it and start on whatever became due meanwhile
+            self.maybe_compact(writer, 0)?;
+        }
+
+        let memtable_size = self.mem().memtable.size();
+        if memtable_size >= self.options.memtable_size {
+            debug!(
+                bytes = memtable_size,
+                threshold = self.options.memtable_size;
+                "Memtable full"
+            );
+            self.freeze(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Wait, without holding up other writers, while a write of `incoming`
+    /// bytes would fill the memtable before the previous one has been
+    /// flushed, or while L0 is at its stop limit with a flush or compaction
+    /// still running, since that write would otherwise wait for them
+    /// holding the writer lock
+    pub(super) fn wait_for_room(&self, incoming: usize) {
+        self.background.wait_while(|jobs| {
+            let full = self.mem().memtable.size() + incoming >= self.options.memtable_size;
+            (jobs.flush && full) || ((jobs.flush || jobs.compaction) && self.writes_stopped())
+        });
+    }
+
+    /// Write the memtable out as a level 0 table and clear the WAL,
+    /// returning once it, any flush already running and the compactions
+    /// they make due are all installed
+    pub(super) fn flush_memtable(&self, writer: &mut Writer) -> io::Result<()> {
+        self.freeze(writer)?;
+        self.wait_for_flush(writer)?;
+        self.compact_until_settled(writer, 0)
+    }
+
+    /// Write the memtable out as a level 0 table, waiting for the flush
+    /// thread without the writer lock, so other writes carry on meanwhile
+    pub(super) fn flush_buffered(&self) -> io::Result<()> {
+        self.background.wait_while(|jobs| jobs.flush);
+        self.freeze(&mut self.writer())?;
+        self.background.wait_while(|jobs| jobs.flush);
+        self.wait_for_flush(&mut self.writer())
+    }
+
+    /// Swap the memtable into the immutable slot, starting a new WAL
+    /// segment, and flush it in the background
+    pub(super) fn freeze(&self, writer: &mut Writer) -> io::Result<()> {
+        if self.mem().memtable.is_empty() {
+            return Ok(());
+        }
+        // Only one memtable can wait for disk; further writes wait for it
+        self.wait_for_flush(writer)?;
+
+        let log = writer.wal.rotate()?;
+        let mut mem = self.mem_mut();
+        debug!(
+            entries = mem.memtable.len(),
+            bytes = mem.memtable.size();
+            "Freezing memtable"
+        );
+        let memtable = std::mem::take(&mut mem.memtable);
+        #[cfg(feature = "skiplist")]
+        let memtable = memtable.freeze(&super::snapshot::live_seqs(&self.snapshots));
+        mem.immutable = Some(Immutable {
+            memtable: Arc::new(memtable),
+            log,
+            last_seq: mem.seq(),
+        });
+        drop(mem);
+        self.start_flush(writer);
+        Ok(())
+    }
+
+    /// Spawn the thread writing the immutable memtable to a new L0 table
+    fn start_flush(&self, writer: &mut Writer) {
+        let Some(memtable) = self
+            .mem()
+            .immutable
+            .as_ref()
+            .map(|immutable| immutable.memtable.clone())
+        else {
+            return;
+        };
+        let number = writer.sstable_counter;
+        writer.sstable_counter += 1;
+        let path = self.table_path(0, number);
+        let options = self.options.clone();
+        // Tombstones already past the retention window are discarded
+        let policy = self.gc_policy(0);
+        let running = self.background.start(Job::Flush);
+
+        let handle = thread::spawn(move || {
+            let _running = running;
+            let mut sstable = SSTable::new(path)?
+                .with_bloom(options.bloom)
+                .with_compression(options.compression);
+            let entries: Vec<_> = memtable
+                .iter_versions()
+                .flat_map(|(k, versions)| {
+                    policy
+                        .retain(k, versions.to_vec())
+                        .into_iter()
+                        .map(move |version| (k.clone(), version))
+                })
+                .collect();
+            sstable.write_entries_with(&entries, options.io_mode())?;
+            options.publish(sstable)
+        });
+        writer.flush = Some(Flush {
+            handle,
+            number,
+            started: Instant::now(),
+            started_ms: self.options.now(),
+        });
+    }
+
+    /// Wait for the running flush, if any, install its table, and compact
+    /// L0 if that filled it. A failed flush leaves the immutable memtable in
+    /// place for the next call to retry.
+    pub(super) fn wait_for_flush(&self, writer: &mut Writer) -> io::Result<()> {
+        if !self.finish_flush(writer)? {
+            return Ok(());
+        }
+
+        // Check if compaction is needed at level 0, or to stay under quota
+        let compacted = self
+            .maybe_compact(writer, 0)
+            .and_then(|()| self.maybe_reclaim_space(writer));
+        self.persist_stats();
+        compacted
+    }
+
+    /// Join the flush and put its table in L0, releasing the WAL segments
+    /// it replaces. Returns whether there was a flush to finish.
+    pub(super) fn finish_flush(&self, writer: &mut Writer) -> io::Result<bool> {
+        let Some((log, last_seq, bytes_in)) = self.mem().immutable.as_ref().map(|immutable| {
+            let bytes_in = immutable.memtable.size() as u64;
+            (immutable.log, immutable.last_seq, bytes_in)
+        }) else {
+            return Ok(false);
+        };
+        if writer.flush.is_none() {
+            self.start_flush(writer);
+        }
+        let Flush {
+            handle,
+            number,
+            started,
+            started_ms,
+        } = writer.flush.take().expect("flush was just started");
+
+        let result = handle
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("Flush thread panicked")));
+        self.events.record(&Event {
+            kind: EventKind::Flush,
+            started_ms,
+            duration_ms: started.elapsed().as_millis() as u64,
+            level: 0,
+            inputs: Vec::new(),
+            output: number,
+            bytes_in,
+            bytes_out: result.as_ref().map_or(0, |table| table.size() as u64),
+            error: result.as_ref().err().map(ToString::to_string),
+        });
+        let sstable = result?;
+        #[cfg(test)]
+        self.flush_step(FlushStep::TableWritten)?;
+        self.lifetime.add_flush(sstable.size());
+        info!(
+            level = 0,
+            bytes_in,
+            bytes_out = sstable.size(),
+            duration_ms = started.elapsed().as_millis() as u64;
+            "Flushed memtable to L0_{}.sst",
+            number
+        );
+
+        // Add new SSTable to level 0, then drop the writes it now holds.
+        // The table and its directory entry were synced as it was written,
+        // so it survives a power loss from here on. Once the manifest says
+        // so, recovery skips their segments even if removing them below is
+        // cut short, and ignores their records if they turn up again.
+        writer.manifest.record(&[
+            ManifestEdit::AddFile {
+                level: 0,
+                seq: number,
+                path: sstable.get_path().file_name().unwrap().into(),
+                size: sstable.size() as u64,
+            },
+            ManifestEdit::SetCounter(writer.sstable_counter),
+            ManifestEdit::SetLogNumber(log + 1),
+            ManifestEdit::SetLastSeq(last_seq),
+        ])?;
+        let sstable = Arc::new(sstable.with_cache(self.block_cache.clone()));
+        // Swapped under the memtables' lock, so reads find the writes in
+        // one place or the other
+        let mut mem = self.mem_mut();
+        self.edit_levels(|levels| {
+            levels.sstables.entry(0).or_default().push(sstable);
+            levels.arrange_level(0);
+        });
+        mem.immutable = None;
+        drop(mem);
+        writer.wal.release(log)?;
+        #[cfg(test)]
+        self.flush_step(FlushStep::WalCleared)?;
+        Ok(true)
+    }
+
+    #[cfg(test)]
+    fn flush_step(&self, step: FlushStep) -> io::Result<()> {
+        match &self.options.flush_hook {
+            Some(hook) => hook(step),
+            None => Ok(()),
+        }
+    }
+
+    /// Wait out a running flush whose table is about to be discarded
+    pub(super) fn abandon_flush(&self, writer: &mut Writer) {
+        if let Some(flush) = writer.flush.take() {
+            let _ = flush.handle.join();
+        }
+        self.mem_mut().immutable = None;
+    }
+
+    /// Save the lifetime counters; losing an update only costs accuracy
+    pub(super) fn persist_stats(&self) {
+        if let Err(e) = self.lifetime.persist() {
+            warn!("Failed to save {}: {}", lifetime::STATS_FILE, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::StorageOptions;
+    use crate::wal::{SyncPolicy, WAL};
+    use tempfile::TempDir;
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("key{:05}", i).into_bytes()
+    }
+
+    #[test]
+    fn test_keys_readable_during_and_after_background_flushes() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = StorageOptions::default().memtable_size(4 * 1024);
+        let storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+
+        let mut saw_immutable = false;
+        for i in 0..3000 {
+            storage.put(key(i), vec![b'v'; 64]).unwrap();
+            saw_immutable |= storage.mem().immutable.is_some();
+            // Spot-check old and new keys wherever they currently live
+            for j in [0, i / 2, i] {
+                assert_eq!(storage.get(key(j)).unwrap(), Some(vec![b'v'; 64]));
+            }
+        }
+        assert!(saw_immutable);
+        assert_eq!(storage.scan(b"", b"").unwrap().count(), 3000);
+        assert_eq!(storage.count_range(b"", b"").unwrap(), 3000);
+
+        storage.flush().unwrap();
+        assert!(storage.mem().immutable.is_none());
+        assert!(storage.mem().memtable.is_empty());
+        let multi: Vec<_> = (0..3000).step_by(7).map(key).collect();
+        assert!(storage
+            .multi_get(&multi)
+            .unwrap()
+            .iter()
+            .all(|value| value.is_some()));
+
+        drop(storage);
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        for i in 0..3000 {
+            assert_eq!(storage.get(key(i)).unwrap(), Some(vec![b'v'; 64]));
+        }
+    }
+
+    #[test]
+    fn test_immutable_memtable_shadows_tables_and_is_shadowed() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        storage.put(b"a", b"table").unwrap();
+        storage.put(b"b", b"table").unwrap();
+        storage.flush().unwrap();
+
+        storage.put(b"a", b"frozen").unwrap();
+        storage.delete(b"b").unwrap();
+        storage.put(b"c", b"frozen").unwrap();
+        storage.freeze(&mut storage.writer()).unwrap();
+        storage.put(b"c", b"active").unwrap();
+
+        assert_eq!(storage.get(b"a").unwrap(), Some(b"frozen".to_vec()));
+        assert_eq!(storage.get(b"b").unwrap(), None);
+        assert_eq!(storage.get(b"c").unwrap(), Some(b"active".to_vec()));
+        let pairs: Vec<_> = storage
+            .scan(b"", b"")
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            pairs,
+            vec![
+                (b"a".to_vec(), b"frozen".to_vec()),
+                (b"c".to_vec(), b"active".to_vec()),
+            ]
+        );
+
+        storage.wait_for_flush(&mut storage.writer()).unwrap();
+        assert_eq!(storage.get(b"c").unwrap(), Some(b"active".to_vec()));
+        assert_eq!(storage.levels().sstables[&0].len(), 2);
+    }
+
+    #[test]
+    fn test_unflushed_segment_replayed_after_crash() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        storage.put(b"frozen", b"1").unwrap();
+        storage.freeze(&mut storage.writer()).unwrap();
+        storage.put(b"active", b"2").unwrap();
+        // Crash before the flush is installed: no table, two WAL segments
+        storage.abandon_flush(&mut storage.writer());
+        for entry in std::fs::read_dir(temp_dir.path()).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|ext| ext == "sst") {
+                std::fs::remove_file(path).unwrap();
+            }
+        }
+        drop(storage);
+
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        assert_eq!(storage.get(b"frozen").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(storage.get(b"active").unwrap(), Some(b"2".to_vec()));
+    }
+
+    /// Stop a flush at `step` as a crash would, with every write synced to
+    /// the WAL as it's acknowledged, and reopen what it left behind
+    fn crash_during_flush(step: FlushStep) -> (TempDir, Storage) {
+        let temp_dir = TempDir::new().unwrap();
+        let mut options = StorageOptions::default().wal_sync(SyncPolicy::Always);
+        options.flush_hook = Some(Arc::new(move |at| {
+            if at == step {
+                Err(io::Error::other("crash"))
+            } else {
+                Ok(())
+            }
+        }));
+        let storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+        for i in 0..100 {
+            storage.put(key(i), b"flushed").unwrap();
+        }
+        storage.delete(key(7)).unwrap();
+        storage.freeze(&mut storage.writer()).unwrap();
+        // Acknowledged while the flush runs, so logged in the next segment
+        storage.put(key(100), b"logged").unwrap();
+        assert!(storage.wait_for_flush(&mut storage.writer()).is_err());
+        storage.crash();
+
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        for i in 0..100 {
+            let expected = (i != 7).then(|| b"flushed".to_vec());
+            assert_eq!(storage.get(key(i)).unwrap(), expected, "key {}", i);
+        }
+        assert_eq!(storage.get(key(100)).unwrap(), Some(b"logged".to_vec()));
+        (temp_dir, storage)
+    }
+
+    #[test]
+    fn test_crash_before_wal_cleared_replays_it() {
+        let (temp_dir, storage) = crash_during_flush(FlushStep::TableWritten);
+        // The table never reached the manifest, so it was removed and the
+        // writes came back from both segments
+        assert!(storage.level_files(0).is_empty());
+        assert_eq!(WAL::logs(&temp_dir.path().join("wal")).unwrap(), vec![1, 2]);
+        assert_eq!(storage.mem().memtable.len(), 101);
+    }
+
+    #[test]
+    fn test_crash_after_wal_cleared_reads_the_table() {
+        let (temp_dir, storage) = crash_during_flush(FlushStep::WalCleared);
+        assert_eq!(storage.level_files(0).len(), 1);
+        assert_eq!(WAL::logs(&temp_dir.path().join("wal")).unwrap(), vec![2]);
+        assert_eq!(storage.mem().memtable.len(), 1);
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::hash_map::RandomState;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Write};
+use std::path::Path;
+
+use super::sync_dir;
+
+/// Records which format a data directory was created with
+pub const IDENTITY_FILE: &str = "IDENTITY";
+/// On-disk format this build reads and writes
+pub const FORMAT_VERSION: u32 = 1;
+// Optional on-disk features this build understands; none exist yet
+const SUPPORTED_FEATURES: &[&str] = &[];
+/// Comparator recorded when none is configured
+pub const DEFAULT_COMPARATOR: &str = "bytewise";
+
+/// Contents of a data directory's `IDENTITY` file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    /// Random id assigned when the directory was created
+    pub id: String,
+    pub format_version: u32,
+    /// Features the directory's files depend on
+    pub features: Vec<String>,
+    pub comparator: String,
+}
+
+impl Identity {
+    fn new(comparator: &str) -> Self {
+        Identity {
+            id: random_uuid(),
+            format_version: FORMAT_VERSION,
+            features: Vec::new(),
+            comparator: comparator.to_string(),
+        }
+    }
+
+    /// Serialize as `key=value` lines
+    fn to_text(&self) -> String {
+        format!(
+            "id={}\nformat_version={}\nfeatures={}\ncomparator={}\n",
+            self.id,
+            self.format_version,
+            self.features.join(","),
+            self.comparator
+        )
+    }
+
+    fn parse(text: &str) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let (mut id, mut version, mut features, mut comparator) = (None, None, None, None);
+        for line in text.lines().filter(|line| !line.is_empty()) {
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("Invalid IDENTITY line {:?}", line)))?;
+            match key {
+                "id" => id = Some(value.to_string()),
+                "format_version" => {
+                    version = Some(value.parse().map_err(|_| {
+                        invalid(format!("Invalid format version {:?} in IDENTITY", value))
+                    })?)
+                }
+                "features" => {
+                    features = Some(
+                        value
+                            .split(',')
+                            .filter(|f| !f.is_empty())
+                            .map(str::to_string)
+                            .collect(),
+                    )
+                }
+                "comparator" => comparator = Some(value.to_string()),
+                // Unknown keys are informational; anything that matters
+                // for compatibility is expressed as a feature
+                _ => {}
+            }
+        }
+        let missing = |field: &str| invalid(format!("IDENTITY is missing {}", field));
+        Ok(Identity {
+            id: id.ok_or_else(|| missing("id"))?,
+            format_version: version.ok_or_else(|| missing("format_version"))?,
+            features: features.unwrap_or_default(),
+            comparator: comparator.ok_or_else(|| missing("comparator"))?,
+        })
+    }
+
+    /// Refuse directories written by a newer format, relying on features
+    /// this build lacks, or ordered by a different comparator
+    fn check(&self, comparator: &str) -> io::Result<()> {
+        if self.format_version > FORMAT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "Data directory uses format version {}, but this build only supports up to {}",
+                    self.format_version, FORMAT_VERSION
+                ),
+            ));
+        }
+        if let Some(feature) = self
+            .features
+            .iter()
+            .find(|f| !SUPPORTED_FEATURES.contains(&f.as_str()))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Data directory requires unsupported feature {:?}", feature),
+            ));
+        }
+        if self.comparator != comparator {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Data directory was created with comparator {:?}, not {:?}",
+                    self.comparator, comparator
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Read and validate the identity of `data_dir`, creating it for a new
+/// directory (or one from before identities were recorded)
+pub fn load_or_create(data_dir: &Path, comparator: &str) -> io::Result<Identity> {
+    let path = data_dir.join(IDENTITY_FILE);
+    if path.exists() {
+        return load(data_dir, comparator);
+    }
+
+    let identity = Identity::new(comparator);
+    let tmp_path = data_dir.join(format!("{}.tmp", IDENTITY_FILE));
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(identity.to_text().as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+    sync_dir(data_dir)?;
+    Ok(identity)
+}
+
+/// Read and validate the identity of an existing `data_dir` without
+/// writing anything
+pub fn load(data_dir: &Path, comparator: &str) -> io::Result<Identity> {
+    let identity = Identity::parse(&fs::read_to_string(data_dir.join(IDENTITY_FILE))?)?;
+    identity.check(comparator)?;
+    Ok(identity)
+}
+
+/// Read and validate the identity of `data_dir` under whichever comparator
+/// it records, for tools that don't open it as a database
+pub fn load_any(data_dir: &Path) -> io::Result<Identity> {
+    let identity = Identity::parse(&fs::read_to_string(data_dir.join(IDENTITY_FILE))?)?;
+    identity.check(&identity.comparator)?;
+    Ok(identity)
+}
+
+/// A random version 4 UUID, seeded from the standard library's per-process
+/// hash keys
+fn random_uuid() -> String {
+    let state = RandomState::new();
+    let half = |salt: u64| {
+        let mut hasher = state.build_hasher();
+        hasher.write_u64(salt);
+        hasher.write_u128(
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+        );
+        hasher.finish()
+    };
+    // Set the version nibble to 4 and the variant bits to 10
+    let bits = (u128::from(half(0)) << 64 | u128::from(half(1)))
+        & !(0xf_u128 << 76 | 0xc_u128 << 60)
+        | (0x4_u128 << 76 | 0x8_u128 << 60);
+    let hex = format!("{:032x}", bits);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Storage, StorageOptions};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_identity_created_and_kept() {
+        let temp_dir = TempDir::new().unwrap();
+        let id = Storage::new(temp_dir.path(), false)
+            .unwrap()
+            .identity()
+            .id
+            .clone();
+        assert_eq!(id.len(), 36);
+        assert_eq!(&id[14..15], "4");
+
+        let reopened = Storage::new(temp_dir.path(), false).unwrap();
+        assert_eq!(reopened.identity().id, id);
+        assert_eq!(reopened.identity().comparator, DEFAULT_COMPARATOR);
+
+        let other = TempDir::new().unwrap();
+        assert_ne!(Storage::new(other.path(), false).unwrap().identity().id, id);
+    }
+
+    #[test]
+    fn test_comparator_mismatch_refused() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = StorageOptions::default().comparator_name("reverse");
+        drop(Storage::open_with_options(temp_dir.path(), options).unwrap());
+
+        let err = Storage::new(temp_dir.path(), false).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("reverse"));
+    }
+
+    #[test]
+    fn test_newer_format_refused() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        storage.put(b"key", b"value").unwrap();
+        drop(storage);
+
+        let path = temp_dir.path().join(IDENTITY_FILE);
+        let text = fs::read_to_string(&path).unwrap();
+        let future = format!("format_version={}", FORMAT_VERSION + 1);
+        fs::write(
+            &path,
+            text.replace(&format!("format_version={}", FORMAT_VERSION), &future),
+        )
+        .unwrap();
+
+        let err = Storage::new(temp_dir.path(), false).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        // Refusing must leave the directory untouched
+        assert!(temp_dir.path().join("wal").exists());
+        assert!(fs::read_to_string(&path).unwrap().contains(&future));
+
+        fs::write(&path, text.replace("features=", "features=encryption")).unwrap();
+        let err = Storage::new(temp_dir.path(), false).err().unwrap();
+        assert!(err.to_string().contains("encryption"));
+    }
+}
@@ -0,0 +1,345 @@
+use std::io;
+use std::sync::Arc;
+
+use super::scan::Scan;
+use crate::entry::{MergeOperator, Version};
+use crate::sstable::SSTable;
+use crate::{Key, Value};
+
+/// A cursor over live keys that can be positioned and walked both ways.
+///
+/// The view is fixed when the cursor is created. It keeps a copy of the
+/// buffered writes and holds on to the SSTables that existed then, so later
+/// writes, flushes and compactions neither change what it reads nor remove
+/// its files. Each move streams the sources from the new position the way a
+/// scan does; stepping on in the same direction reads on from where the last
+/// move stopped, while turning around reopens them at the current key.
+pub struct DbIterator {
+    memtable: Vec<(Key, Version)>,
+    tables: Vec<Arc<SSTable>>,
+    max_seq: u64,
+    now: u64,
+    merge: Option<MergeOperator>,
+    read_ahead: u64,
+    // The scan the last move read from, and whether it runs backwards
+    scan: Option<(Scan, bool)>,
+    current: Option<(Key, Value)>,
+}
+
+impl DbIterator {
+    /// A cursor over `memtable`, sorted by key with each key's versions
+    /// newest first, and `tables`, as of sequence number `max_seq`
+    pub(super) fn new(
+        memtable: Vec<(Key, Version)>,
+        tables: Vec<Arc<SSTable>>,
+        max_seq: u64,
+        now: u64,
+        merge: Option<MergeOperator>,
+        read_ahead: u64,
+    ) -> Self {
+        DbIterator {
+            memtable,
+            tables,
+            max_seq,
+            now,
+            merge,
+            read_ahead,
+            scan: None,
+            current: None,
+        }
+    }
+
+    /// Whether the iterator is positioned on a key
+    pub fn valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// The current key. Panics unless `valid`.
+    pub fn key(&self) -> &[u8] {
+        &self.current.as_ref().expect("iterator is not valid").0
+    }
+
+    /// The current value. Panics unless `valid`.
+    pub fn value(&self) -> &[u8] {
+        &self.current.as_ref().expect("iterator is not valid").1
+    }
+
+    /// Move to the first key at or after `key`
+    pub fn seek(&mut self, key: &[u8]) -> io::Result<()> {
+        self.open(key, None, false)
+    }
+
+    /// Move to the last key at or before `key`
+    pub fn seek_for_prev(&mut self, key: &[u8]) -> io::Result<()> {
+        self.open(b"", Some(successor(key)), true)
+    }
+
+    pub fn seek_to_first(&mut self) -> io::Result<()> {
+        self.open(b"", None, false)
+    }
+
+    pub fn seek_to_last(&mut self) -> io::Result<()> {
+        self.open(b"", None, true)
+    }
+
+    /// Move to the following key, or become invalid past the last one
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> io::Result<()> {
+        let Some((key, _)) = &self.current else {
+            return Ok(());
+        };
+        match self.scan {
+            Some((_, false)) => self.step(),
+            _ => self.open(&successor(key), None, false),
+        }
+    }
+
+    /// Move to the preceding key, or become invalid before the first one
+    pub fn prev(&mut self) -> io::Result<()> {
+        let Some((key, _)) = &self.current else {
+            return Ok(());
+        };
+        match self.scan {
+            Some((_, true)) => self.step(),
+            _ => self.open(b"", Some(key.clone()), true),
+        }
+    }
+
+    /// Start a scan over `[start, end)` and land on its first live key
+    fn open(&mut self, start: &[u8], end: Option<Key>, descending: bool) -> io::Result<()> {
+        self.scan = None;
+        self.current = None;
+        let from = self.memtable.partition_point(|(k, _)| k.as_slice() < start);
+        let to = match &end {
+            Some(end) => self.memtable.partition_point(|(k, _)| k < end),
+            None => self.memtable.len(),
+        };
+        let memtable = self.memtable[from..to.max(from)].to_vec();
+        // Tables wholly outside the range would only be opened to find nothing
+        let tables = self
+            .tables
+            .iter()
+            .filter(|table| {
+                table.key_range().is_none_or(|(first, last)| {
+                    last >= start && end.as_deref().is_none_or(|end| first < end)
+                })
+            })
+            .cloned()
+            .collect();
+        let scan = Scan::new(
+            memtable,
+            tables,
+            start,
+            end,
+            self.max_seq,
+            self.now,
+            self.merge.clone(),
+            self.read_ahead,
+            None,
+            descending,
+        )?;
+        self.scan = Some((scan, descending));
+        self.step()
+    }
+
+    /// Land on the next key of the open scan. A failed read leaves the
+    /// iterator invalid.
+    fn step(&mut self) -> io::Result<()> {
+        let Some((scan, _)) = &mut self.scan else {
+            return Ok(());
+        };
+        match scan.next().transpose() {
+            Ok(current) => {
+                self.current = current;
+                Ok(())
+            }
+            Err(e) => {
+                self.scan = None;
+                self.current = None;
+                Err(e)
+            }
+        }
+    }
+}
+
+/// The smallest key after `key`
+fn successor(key: &[u8]) -> Key {
+    let mut next = key.to_vec();
+    next.push(0);
+    next
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Storage, StorageOptions};
+    use tempfile::TempDir;
+
+    fn populated() -> (TempDir, Storage) {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        // Every other letter from b to n in one table; overwrites and deletes in the memtable
+        for key in [b"b", b"d", b"f", b"h", b"j", b"l", b"n"] {
+            storage.put(key.to_vec(), b"old".to_vec()).unwrap();
+        }
+        storage.flush().unwrap();
+        storage.put(b"f", b"new").unwrap();
+        storage.put(b"g", b"new").unwrap();
+        storage.delete(b"h").unwrap();
+        storage.delete(b"j").unwrap();
+        (temp_dir, storage)
+    }
+
+    fn walk_forward(iter: &mut DbIterator) -> Vec<Vec<u8>> {
+        let mut keys = Vec::new();
+        while iter.valid() {
+            keys.push(iter.key().to_vec());
+            iter.next().unwrap();
+        }
+        keys
+    }
+
+    #[test]
+    fn test_walk_both_ways() {
+        let (_temp_dir, storage) = populated();
+        let mut iter = storage.iter().unwrap();
+        assert!(!iter.valid());
+
+        iter.seek_to_first().unwrap();
+        assert_eq!(
+            walk_forward(&mut iter),
+            [&b"b"[..], b"d", b"f", b"g", b"l", b"n"]
+        );
+
+        iter.seek_to_last().unwrap();
+        let mut keys = Vec::new();
+        while iter.valid() {
+            keys.push(iter.key().to_vec());
+            iter.prev().unwrap();
+        }
+        assert_eq!(keys, [&b"n"[..], b"l", b"g", b"f", b"d", b"b"]);
+    }
+
+    #[test]
+    fn test_seek_absent_keys() {
+        let (_temp_dir, storage) = populated();
+        let mut iter = storage.iter().unwrap();
+
+        iter.seek(b"e").unwrap();
+        assert_eq!(iter.key(), b"f");
+        assert_eq!(iter.value(), b"new");
+
+        // Deleted keys are skipped in either direction
+        iter.seek(b"h").unwrap();
+        assert_eq!(iter.key(), b"l");
+        iter.seek_for_prev(b"k").unwrap();
+        assert_eq!(iter.key(), b"g");
+        iter.seek_for_prev(b"d").unwrap();
+        assert_eq!(iter.key(), b"d");
+
+        // Before the first key and after the last
+        iter.seek(b"a").unwrap();
+        assert_eq!(iter.key(), b"b");
+        iter.seek_for_prev(b"z").unwrap();
+        assert_eq!(iter.key(), b"n");
+
+        // Past either end
+        iter.seek(b"z").unwrap();
+        assert!(!iter.valid());
+        iter.seek_for_prev(b"a").unwrap();
+        assert!(!iter.valid());
+    }
+
+    #[test]
+    fn test_switch_direction() {
+        let (_temp_dir, storage) = populated();
+        let mut iter = storage.iter().unwrap();
+
+        iter.seek(b"g").unwrap();
+        iter.next().unwrap();
+        assert_eq!(iter.key(), b"l");
+        iter.prev().unwrap();
+        assert_eq!(iter.key(), b"g");
+        iter.prev().unwrap();
+        assert_eq!(iter.key(), b"f");
+        iter.next().unwrap();
+        iter.next().unwrap();
+        assert_eq!(iter.key(), b"l");
+
+        // Running off the end leaves it invalid until re-seeked
+        iter.seek_to_last().unwrap();
+        iter.next().unwrap();
+        assert!(!iter.valid());
+        iter.prev().unwrap();
+        assert!(!iter.valid());
+        iter.seek_to_first().unwrap();
+        assert_eq!(iter.key(), b"b");
+    }
+
+    #[test]
+    fn test_view_is_fixed_at_creation() {
+        let (_temp_dir, storage) = populated();
+        let mut iter = storage.iter().unwrap();
+        storage.put(b"c", b"later").unwrap();
+        storage.delete(b"d").unwrap();
+
+        iter.seek(b"c").unwrap();
+        assert_eq!(iter.key(), b"d");
+    }
+
+    #[test]
+    fn test_position_survives_flush_and_compaction() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = StorageOptions::default().memtable_size(4 * 1024);
+        let mut storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+        let key = |i: usize| format!("key{:04}", i).into_bytes();
+        for i in 0..1000 {
+            storage.put(key(i), vec![1; 32]).unwrap();
+        }
+        let tables: Vec<_> = storage
+            .levels()
+            .sstables
+            .values()
+            .flatten()
+            .map(|table| table.get_path().clone())
+            .collect();
+        assert!(tables.len() > 1);
+
+        let mut cursor = storage.cursor().unwrap();
+        cursor.seek(&key(500)).unwrap();
+        assert_eq!(cursor.key(), key(500));
+
+        // Rewrite everything beneath the cursor
+        for i in (0..1000).step_by(2) {
+            storage.delete(key(i)).unwrap();
+        }
+        storage.put(key(501), b"later").unwrap();
+        storage.compact_all().unwrap();
+        assert!(tables.iter().any(|path| path.exists()));
+
+        cursor.next().unwrap();
+        assert_eq!(
+            (cursor.key(), cursor.value()),
+            (&key(501)[..], &[1; 32][..])
+        );
+        cursor.prev().unwrap();
+        cursor.prev().unwrap();
+        assert_eq!(cursor.key(), key(499));
+        let mut seen = 0;
+        cursor.seek_to_first().unwrap();
+        while cursor.valid() {
+            assert_eq!(cursor.key(), key(seen));
+            seen += 1;
+            cursor.next().unwrap();
+        }
+        assert_eq!(seen, 1000);
+
+        // The replaced tables go once the cursor lets go of them
+        drop(cursor);
+        assert!(tables.iter().all(|path| !path.exists()));
+        let mut cursor = storage.cursor().unwrap();
+        cursor.seek(&key(500)).unwrap();
+        assert_eq!(cursor.key(), key(501));
+        assert_eq!(cursor.value(), b"later");
+    }
+}
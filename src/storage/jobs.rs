@@ -0,0 +1,681 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use log::{debug, info};
+
+use super::background::Job;
+use super::manifest::ManifestEdit;
+use super::{
+    parse_table_name, CompactionStrategy, Event, EventKind, Storage, StorageOptions, Writer,
+};
+use crate::sstable::{CompactionManager, GcPolicy, SSTable};
+
+/// A merge of tables into a single new table: normally every table in one
+/// level, written to the next. Its inputs are fixed when it's planned and
+/// only leave their levels when it's installed, so jobs planned together
+/// never share a table.
+struct CompactionJob {
+    output_level: usize,
+    inputs: Vec<Arc<SSTable>>,
+    policy: GcPolicy,
+    number: u64,
+    output: PathBuf,
+    started_ms: u64,
+}
+
+impl CompactionJob {
+    /// Merge the inputs and write the output table. Nothing shared is
+    /// touched, so jobs on different levels can run side by side.
+    fn run(&self, manager: &CompactionManager, options: &StorageOptions) -> io::Result<SSTable> {
+        let mode = options.io_mode();
+        let output = self.output.clone();
+        let bloom = options
+            .bloom
+            .filter(|_| options.bottommost_bloom || !self.policy.bottommost);
+        // The inputs' numbers let a directory read without a manifest
+        // tell them apart from their replacement if a crash leaves both
+        let replaces = self
+            .inputs
+            .iter()
+            .filter_map(|t| parse_table_name(t.get_path()).map(|(_, number)| number))
+            .collect();
+        let output = SSTable::new(output)?
+            .with_bloom(bloom)
+            .with_compression(options.compression)
+            .with_replaces(replaces);
+        let table = manager.compact(&self.inputs, &self.policy, mode, output)?;
+        let count = table.properties().entry_count;
+
+        #[cfg(test)]
+        if let Some(hook) = &options.compaction_output_hook {
+            hook(table.get_path());
+        }
+        if options.verify_compaction_output {
+            if let Err(e) = verify_output(&table, count) {
+                let _ = fs::remove_file(table.get_path());
+                return Err(e);
+            }
+        }
+        if options.fadvise {
+            table.release_cache()?;
+        }
+        options.publish(table)
+    }
+}
+
+/// Outcome of each job with how long it took, in milliseconds
+type JobResults = Vec<(io::Result<SSTable>, u64)>;
+
+/// Compaction jobs merging on a background thread. Their inputs stay in
+/// place, and readable, until the jobs are installed.
+pub(super) struct Compacting {
+    handle: JoinHandle<(Vec<CompactionJob>, JobResults)>,
+}
+
+/// Run `jobs` in parallel, each on its own thread when there are several
+fn run_all(
+    jobs: &[CompactionJob],
+    manager: &CompactionManager,
+    options: &StorageOptions,
+) -> JobResults {
+    let timed = |job: &CompactionJob| {
+        let started = Instant::now();
+        let result = job.run(manager, options);
+        (result, started.elapsed().as_millis() as u64)
+    };
+    if jobs.len() == 1 {
+        return vec![timed(&jobs[0])];
+    }
+    thread::scope(|scope| {
+        let handles: Vec<_> = jobs
+            .iter()
+            .map(|job| scope.spawn(move || timed(job)))
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|_| (Err(io::Error::other("Compaction job panicked")), 0))
+            })
+            .collect()
+    })
+}
+
+impl Compacting {
+    pub(super) fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+}
+
+impl Storage {
+    /// Start compacting the levels from `level` down that have reached
+    /// their trigger, on a background thread, so the write that filled
+    /// them doesn't wait for the merge. Up to `max_background_compactions`
+    /// levels are merged at once, the shallowest first. Jobs that have
+    /// finished since the last call are installed first; while some are
+    /// still running this does nothing, and a later call picks up whatever
+    /// has become due by then.
+    pub(super) fn maybe_compact(&self, writer: &mut Writer, level: usize) -> io::Result<()> {
+        if let CompactionStrategy::Fifo { max_fifo_bytes } = self.options.compaction_strategy {
+            return self.evict_fifo(writer, max_fifo_bytes);
+        }
+        if writer
+            .compacting
+            .as_ref()
+            .is_some_and(|compacting| !compacting.is_finished())
+        {
+            return Ok(());
+        }
+        let installed = self.finish_compaction(writer);
+        self.start_compaction(writer, level);
+        installed.map(|_| ())
+    }
+
+    /// Compact every level from `level` down until none has reached its
+    /// trigger, waiting for each round to finish
+    pub(super) fn compact_until_settled(
+        &self,
+        writer: &mut Writer,
+        level: usize,
+    ) -> io::Result<()> {
+        if let CompactionStrategy::Fifo { max_fifo_bytes } = self.options.compaction_strategy {
+            return self.evict_fifo(writer, max_fifo_bytes);
+        }
+        loop {
+            self.finish_compaction(writer)?;
+            if !self.start_compaction(writer, level) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Merge `level` into the next one, whatever its size, once any
+    /// compaction already running has been installed
+    pub(super) fn run_compaction(&self, writer: &mut Writer, level: usize) -> io::Result<()> {
+        self.finish_compaction(writer)?;
+        let jobs = vec![self.plan_job(writer, level)];
+        let results = run_all(&jobs, &self.compaction_manager, &self.options);
+        self.install_all(writer, jobs, results)
+    }
+
+    /// Plan jobs for the due levels from `level` down and hand them to a
+    /// background thread, returning whether there were any
+    fn start_compaction(&self, writer: &mut Writer, level: usize) -> bool {
+        let due: Vec<usize> = self
+            .due_levels(level)
+            .into_iter()
+            .take(self.options.max_background_compactions)
+            .collect();
+        if due.is_empty() {
+            return false;
+        }
+        let jobs: Vec<_> = due
+            .into_iter()
+            .map(|level| self.plan_job(writer, level))
+            .collect();
+        let manager = self.compaction_manager.clone();
+        let options = self.options.clone();
+        let running = self.background.start(Job::Compaction);
+        let handle = thread::spawn(move || {
+            let _running = running;
+            let results = run_all(&jobs, &manager, &options);
+            (jobs, results)
+        });
+        writer.compacting = Some(Compacting { handle });
+        true
+    }
+
+    /// Wait for the running compaction, if any, and swap its outputs in
+    /// for its inputs. Returns whether there was one to finish.
+    pub(super) fn finish_compaction(&self, writer: &mut Writer) -> io::Result<bool> {
+        let Some(compacting) = writer.compacting.take() else {
+            return Ok(false);
+        };
+        let (jobs, results) = compacting
+            .handle
+            .join()
+            .map_err(|_| io::Error::other("Compaction thread panicked"))?;
+        self.install_all(writer, jobs, results)?;
+        Ok(true)
+    }
+
+    /// Wait out a running compaction whose outputs are about to be
+    /// discarded
+    pub(super) fn abandon_compaction(writer: &mut Writer) {
+        if let Some(compacting) = writer.compacting.take() {
+            let _ = compacting.handle.join();
+        }
+    }
+
+    /// Levels from `level` down whose compaction is due, shallowest first
+    fn due_levels(&self, level: usize) -> Vec<usize> {
+        let current = self.levels();
+        let mut levels: Vec<usize> = current
+            .sstables
+            .iter()
+            .filter(|(&l, tables)| l >= level && !tables.is_empty())
+            .map(|(&l, _)| l)
+            .collect();
+        levels.sort_unstable();
+        levels.retain(|&level| {
+            let tables = &current.sstables[&level];
+            self.compaction_manager.should_compact(level, tables)
+        });
+        levels
+    }
+
+    /// Reserve `level`'s tables and an output number for a job
+    fn plan_job(&self, writer: &mut Writer, level: usize) -> CompactionJob {
+        let inputs = self.levels().sstables[&level].clone();
+        debug!(
+            level,
+            files = inputs.len();
+            "Starting compaction of L{} into L{}",
+            level,
+            level + 1
+        );
+        let number = writer.sstable_counter;
+        writer.sstable_counter += 1;
+        CompactionJob {
+            inputs,
+            policy: self.gc_policy(level + 1),
+            number,
+            output: self.table_path(level + 1, number),
+            output_level: level + 1,
+            started_ms: self.options.now(),
+        }
+    }
+
+    /// Merge `inputs`, which may come from several levels, into one table
+    /// at `output_level` and wait for it to be installed. Tombstones are
+    /// dropped when every table at or below the output level is an input.
+    pub(super) fn run_merge(
+        &self,
+        writer: &mut Writer,
+        inputs: Vec<Arc<SSTable>>,
+        output_level: usize,
+    ) -> io::Result<()> {
+        debug!(
+            level = output_level,
+            files = inputs.len();
+            "Starting manual merge into L{}",
+            output_level
+        );
+        let mut policy = self.gc_policy(output_level);
+        policy.bottommost = self
+            .levels()
+            .sstables
+            .iter()
+            .filter(|(&level, _)| level >= output_level)
+            .flat_map(|(_, tables)| tables)
+            .all(|table| inputs.iter().any(|input| Arc::ptr_eq(input, table)));
+        let number = writer.sstable_counter;
+        writer.sstable_counter += 1;
+        let jobs = vec![CompactionJob {
+            inputs,
+            policy,
+            number,
+            output: self.table_path(output_level, number),
+            output_level,
+            started_ms: self.options.now(),
+        }];
+        let results = run_all(&jobs, &self.compaction_manager, &self.options);
+        self.install_all(writer, jobs, results)
+    }
+
+    /// Install finished jobs one at a time. Every job is installed or
+    /// discarded before the first error is returned.
+    fn install_all(
+        &self,
+        writer: &mut Writer,
+        jobs: Vec<CompactionJob>,
+        results: JobResults,
+    ) -> io::Result<()> {
+        let mut first_error = None;
+        for (job, (result, elapsed_ms)) in jobs.into_iter().zip(results) {
+            if let Err(e) = self.install(writer, job, result, elapsed_ms) {
+                first_error.get_or_insert(e);
+            }
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+
+    /// Swap a finished job's inputs for its output and record the outcome
+    /// in the event journal
+    fn install(
+        &self,
+        writer: &mut Writer,
+        job: CompactionJob,
+        result: io::Result<SSTable>,
+        elapsed_ms: u64,
+    ) -> io::Result<()> {
+        let total_size: usize = job.inputs.iter().map(|t| t.size()).sum();
+        self.events.record(&Event {
+            kind: EventKind::Compaction,
+            started_ms: job.started_ms,
+            duration_ms: elapsed_ms,
+            level: job.output_level,
+            inputs: job
+                .inputs
+                .iter()
+                .filter_map(|t| parse_table_name(t.get_path()).map(|(_, number)| number))
+                .collect(),
+            output: job.number,
+            bytes_in: total_size as u64,
+            bytes_out: result.as_ref().map_or(0, |table| table.size() as u64),
+            error: result.as_ref().err().map(ToString::to_string),
+        });
+        let new_table = result?;
+        let new_table_size = new_table.size();
+
+        // Record the swap, then update sstables collection; the old files
+        // are removed once no scan is still reading them
+        let mut edits = vec![ManifestEdit::AddFile {
+            level: job.output_level,
+            seq: job.number,
+            path: new_table.get_path().file_name().unwrap().into(),
+            size: new_table_size as u64,
+        }];
+        for input in &job.inputs {
+            if let Some((level, seq)) = parse_table_name(input.get_path()) {
+                edits.push(ManifestEdit::RemoveFile { level, seq });
+            }
+        }
+        edits.push(ManifestEdit::SetCounter(writer.sstable_counter));
+        writer.manifest.record(&edits)?;
+        let new_table = Arc::new(new_table.with_cache(self.block_cache.clone()));
+        self.edit_levels(|current| {
+            let mut levels = vec![job.output_level];
+            for (&level, tables) in current.sstables.iter_mut() {
+                tables.retain(|table| {
+                    let input = job.inputs.iter().any(|input| Arc::ptr_eq(input, table));
+                    if input {
+                        table.mark_obsolete();
+                        levels.push(level);
+                    }
+                    !input
+                });
+            }
+            current
+                .sstables
+                .entry(job.output_level)
+                .or_default()
+                .push(new_table);
+            levels.sort_unstable();
+            levels.dedup();
+            for level in levels {
+                current.arrange_level(level);
+            }
+        });
+        self.lifetime.add_compaction(new_table_size, elapsed_ms);
+        info!(
+            level = job.output_level,
+            files = job.inputs.len(),
+            bytes_in = total_size,
+            bytes_out = new_table_size,
+            duration_ms = elapsed_ms;
+            "Compacted {} tables into L{}_{}.sst",
+            job.inputs.len(),
+            job.output_level,
+            job.number
+        );
+        Ok(())
+    }
+}
+
+/// Reopen a freshly written table and check that it reads back as the
+/// `count` entries written, in order
+fn verify_output(table: &SSTable, count: u64) -> io::Result<()> {
+    let path = table.get_path();
+    let failed = |e: io::Error| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Compaction output {:?} failed verification: {}", path, e),
+        )
+    };
+    let entries = SSTable::new(path.clone())?
+        .verify()
+        .map_err(|e| failed(e.into()))?;
+    if entries != count {
+        return Err(failed(io::Error::other(format!(
+            "read {} entries, wrote {}",
+            entries, count
+        ))));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::{Entry, Version};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("key{:05}", i).into_bytes()
+    }
+
+    fn write_table(dir: &TempDir, level: usize, number: u64, keys: std::ops::Range<usize>) {
+        let path = dir.path().join(format!("L{}_{}.sst", level, number));
+        let entries: Vec<_> = keys
+            .map(|i| {
+                let version = Version::new(number * 1000 + i as u64, Entry::Value(vec![b'v'; 100]));
+                (key(i), version)
+            })
+            .collect();
+        SSTable::new(path).unwrap().write_entries(&entries).unwrap();
+    }
+
+    #[test]
+    fn test_replaced_tables_ignored_without_manifest() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut storage = Storage::new(temp_dir.path(), false).unwrap();
+        for i in 0..100 {
+            storage.put(key(i), b"v".to_vec()).unwrap();
+        }
+        storage.flush().unwrap();
+        storage.delete(key(5)).unwrap();
+        storage.flush().unwrap();
+        let inputs: Vec<_> = storage
+            .level_files(0)
+            .into_iter()
+            .map(|file| (fs::read(&file.path).unwrap(), file.path))
+            .collect();
+        storage.compact_level(0).unwrap();
+        storage.crash();
+
+        // As if the inputs outlived a crash, in a directory read by name
+        for (bytes, path) in &inputs {
+            fs::write(path, bytes).unwrap();
+        }
+        fs::remove_file(temp_dir.path().join(super::super::MANIFEST_FILE)).unwrap();
+
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        assert!(storage.level_files(0).is_empty());
+        assert_eq!(storage.level_files(1).len(), 1);
+        assert!(inputs.iter().all(|(_, path)| !path.exists()));
+        assert_eq!(storage.get(key(5)).unwrap(), None);
+        assert_eq!(storage.get(key(6)).unwrap(), Some(b"v".to_vec()));
+    }
+
+    #[test]
+    fn test_levels_compact_concurrently() {
+        let temp_dir = TempDir::new().unwrap();
+        // An over-threshold L2, older than four L0 tables
+        write_table(&temp_dir, 2, 0, 0..200);
+        for number in 1..4 {
+            let start = 1000 + number as usize * 10;
+            write_table(&temp_dir, 0, number, start..start + 10);
+        }
+
+        // Each output waits for the other job, so running them one after
+        // the other would only ever see one in flight
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let mut options = StorageOptions::default().max_background_compactions(2);
+        let (counter, peak) = (in_flight.clone(), most.clone());
+        options.compaction_output_hook = Some(Arc::new(move |_: &std::path::Path| {
+            let now = counter.fetch_add(1, Ordering::SeqCst) + 1;
+            peak.fetch_max(now, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(200));
+            counter.fetch_sub(1, Ordering::SeqCst);
+        }));
+        let mut storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+        // Level thresholds of 1KB, 4KB, 16KB, ...
+        storage.compaction_manager = CompactionManager::new(4, 1024);
+
+        storage.put(key(5000), b"flushed".to_vec()).unwrap();
+        storage.flush().unwrap();
+
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+        let events = storage.recent_events(10).unwrap();
+        let compacted: Vec<usize> = events
+            .iter()
+            .filter(|e| e.kind == EventKind::Compaction)
+            .map(|e| e.level)
+            .collect();
+        assert!(
+            compacted.contains(&1) && compacted.contains(&3),
+            "{:?}",
+            compacted
+        );
+        assert!(events.iter().all(|e| e.error.is_none()));
+
+        // Nothing lost or duplicated, and no scratch files left behind
+        for i in (0..200).chain(1010..1040) {
+            assert_eq!(storage.get(key(i)).unwrap(), Some(vec![b'v'; 100]));
+        }
+        assert_eq!(storage.get(key(5000)).unwrap(), Some(b"flushed".to_vec()));
+        assert_eq!(storage.scan(b"", b"").unwrap().count(), 231);
+        let stats = storage.stats();
+        assert_eq!(stats.stored.entry_count, 231);
+        assert!(stats.since_open.compactions >= 2);
+        assert!(stats.since_open.compaction_ms >= 400);
+        assert!(stats.since_open.compaction_throughput() > 0);
+        let names: Vec<String> = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        assert!(
+            !names.iter().any(|name| name.starts_with("compact_")),
+            "{:?}",
+            names
+        );
+    }
+
+    #[test]
+    fn test_compaction_writes_output_once_under_its_table_name() {
+        let temp_dir = TempDir::new().unwrap();
+        for number in 0..4 {
+            let start = number as usize * 50;
+            write_table(&temp_dir, 0, number, start..start + 50);
+        }
+        let mut storage = Storage::new(temp_dir.path(), false).unwrap();
+        storage.compact_level(0).unwrap();
+
+        let output = storage.level_files(1);
+        assert_eq!(output.len(), 1);
+        let output_size = fs::metadata(&output[0].path).unwrap().len();
+        assert_eq!(storage.stats().since_open.bytes_compacted, output_size);
+        let event = storage.recent_events(1).unwrap().remove(0);
+        assert_eq!(event.bytes_out, output_size);
+
+        // Only tables under their own names; no scratch or temporary files
+        let mut tables: Vec<String> = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.contains('.'))
+            .collect();
+        tables.sort();
+        assert_eq!(tables, vec![format!("L1_{}.sst", output[0].file_number)]);
+    }
+
+    #[test]
+    fn test_one_job_at_a_time_by_default() {
+        let temp_dir = TempDir::new().unwrap();
+        write_table(&temp_dir, 2, 0, 0..200);
+        for number in 1..4 {
+            let start = 1000 + number as usize * 10;
+            write_table(&temp_dir, 0, number, start..start + 10);
+        }
+        let mut storage = Storage::new(temp_dir.path(), false).unwrap();
+        storage.compaction_manager = CompactionManager::new(4, 1024);
+        storage.put(key(5000), b"flushed".to_vec()).unwrap();
+        storage.flush().unwrap();
+
+        // L0 went first; L2 followed once L0 was done
+        let events = storage.recent_events(10).unwrap();
+        let compacted: Vec<&Event> = events
+            .iter()
+            .filter(|e| e.kind == EventKind::Compaction)
+            .collect();
+        assert_eq!(compacted[0].level, 1);
+        assert!(compacted.iter().any(|e| e.level == 3));
+        assert_eq!(storage.scan(b"", b"").unwrap().count(), 231);
+    }
+
+    #[test]
+    fn test_puts_never_wait_for_compaction() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut options = StorageOptions::default().memtable_size(4 * 1024);
+        // Every merge takes at least half a second
+        options.compaction_output_hook = Some(Arc::new(|_: &std::path::Path| {
+            std::thread::sleep(Duration::from_millis(500));
+        }));
+        let storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+
+        let mut expected = std::collections::BTreeMap::new();
+        let mut slowest = Duration::ZERO;
+        let mut i = 0;
+        while storage.stats().since_open.compactions < 3 {
+            let value = format!("value{}", i).into_bytes();
+            let started = Instant::now();
+            storage.put(key(i % 700), value.clone()).unwrap();
+            slowest = slowest.max(started.elapsed());
+            expected.insert(key(i % 700), value);
+            i += 1;
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(slowest < Duration::from_millis(250), "{:?}", slowest);
+
+        // Settled, the tree holds exactly what was written and no level is
+        // left over its trigger
+        storage.flush().unwrap();
+        assert!(storage.writer().compacting.is_none());
+        assert!(storage.due_levels(0).is_empty());
+        let pairs: Vec<_> = storage
+            .scan(b"", b"")
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(pairs, expected.clone().into_iter().collect::<Vec<_>>());
+
+        // Dropping mid-compaction waits for the merge and installs it
+        for j in 0..2000 {
+            storage.put(key(j % 700), b"last".to_vec()).unwrap();
+            expected.insert(key(j % 700), b"last".to_vec());
+            if storage.writer().compacting.is_some() {
+                break;
+            }
+        }
+        assert!(storage.writer().compacting.is_some());
+        drop(storage);
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        let pairs: Vec<_> = storage
+            .scan(b"", b"")
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(pairs, expected.into_iter().collect::<Vec<_>>());
+        assert!(storage.level_files(0).len() < 4);
+    }
+
+    #[test]
+    fn test_compaction_prints_nothing_without_a_logger() {
+        // The test harness captures stdout, so the work runs in a child
+        // test process that writes straight to its stdout
+        const CHILD: &str = "LSM_RUST_QUIET_CHILD";
+        const BEGIN: &str = "-- begin --";
+        const END: &str = "-- end --";
+        if std::env::var_os(CHILD).is_some() {
+            let temp_dir = TempDir::new().unwrap();
+            println!("{}", BEGIN);
+            let options = StorageOptions::default()
+                .memtable_size(4 * 1024)
+                .verbose(true);
+            let mut storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+            for i in 0..2000 {
+                storage.put(key(i), vec![b'v'; 64]).unwrap();
+                storage.get(key(i / 2)).unwrap();
+            }
+            storage.compact_all().unwrap();
+            assert!(storage.stats().since_open.compactions > 0);
+            drop(storage);
+            println!("{}", END);
+            return;
+        }
+
+        let name = module_path!().split_once("::").unwrap().1;
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args([
+                &format!("{}::test_compaction_prints_nothing_without_a_logger", name),
+                "--exact",
+                "--nocapture",
+            ])
+            .env(CHILD, "1")
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{}", stdout);
+        let (_, rest) = stdout.split_once(BEGIN).unwrap();
+        let (between, _) = rest.split_once(END).unwrap();
+        assert_eq!(between.trim(), "");
+    }
+}
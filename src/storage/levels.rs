@@ -0,0 +1,278 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use super::parse_table_name;
+use crate::sstable::SSTable;
+
+/// Put `level`'s tables back in read order after they change: oldest to
+/// newest by file number, or, for a level below L0 whose tables don't
+/// overlap, by smallest key. Returns whether the level was sorted by key.
+pub(super) fn arrange(level: usize, tables: &mut [Arc<SSTable>]) -> bool {
+    tables.sort_by_key(|table| parse_table_name(table.get_path()).map_or(0, |(_, n)| n));
+    if level == 0 || tables.iter().any(|table| table.key_range().is_none()) {
+        return false;
+    }
+
+    tables.sort_by(|a, b| a.key_range().unwrap().0.cmp(b.key_range().unwrap().0));
+    let disjoint = tables
+        .windows(2)
+        .all(|pair| pair[0].key_range().unwrap().1 < pair[1].key_range().unwrap().0);
+    if !disjoint {
+        tables.sort_by_key(|table| parse_table_name(table.get_path()).map_or(0, |(_, n)| n));
+    }
+    disjoint
+}
+
+/// The table of a key-sorted level whose range covers `key`, if any
+fn covering<'a>(tables: &'a [Arc<SSTable>], key: &[u8]) -> &'a [Arc<SSTable>] {
+    let i = tables.partition_point(|table| table.key_range().unwrap().1 < key);
+    match tables.get(i) {
+        Some(table) if table.key_range().unwrap().0 <= key => &tables[i..=i],
+        _ => &[],
+    }
+}
+
+/// The run of a key-sorted level's tables overlapping `[start, end)`
+fn overlapping<'a>(
+    tables: &'a [Arc<SSTable>],
+    start: &[u8],
+    end: Option<&[u8]>,
+) -> &'a [Arc<SSTable>] {
+    let from = tables.partition_point(|table| table.key_range().unwrap().1 < start);
+    let to =
+        tables.partition_point(|table| end.is_none_or(|end| table.key_range().unwrap().0 < end));
+    &tables[from..to.max(from)]
+}
+
+/// The live tables by level. Reads take the current set as a whole, and
+/// flushes and compactions replace it rather than change it in place, so
+/// a read never sees a table swap half done.
+#[derive(Clone, Default)]
+pub(super) struct Levels {
+    pub(super) sstables: HashMap<usize, Vec<Arc<SSTable>>>, // level -> SSTables
+    pub(super) sorted_levels: HashSet<usize>,               // levels ordered by key, not age
+}
+
+impl Levels {
+    /// Re-sort `level` after adding or removing tables
+    pub(super) fn arrange_level(&mut self, level: usize) {
+        let tables = self.sstables.entry(level).or_default();
+        if arrange(level, tables) {
+            self.sorted_levels.insert(level);
+        } else {
+            self.sorted_levels.remove(&level);
+        }
+    }
+
+    /// The deepest level holding a table list, empty or not
+    pub(super) fn max_level(&self) -> usize {
+        self.sstables.keys().max().copied().unwrap_or(0)
+    }
+
+    /// The tables of `level` that may hold `key`, to be probed from the
+    /// back: at most one when the level is sorted by key, else all of them
+    pub(super) fn tables_for_key(&self, level: usize, key: &[u8]) -> &[Arc<SSTable>] {
+        let tables = self.sstables.get(&level).map_or(&[][..], |t| t.as_slice());
+        if self.sorted_levels.contains(&level) {
+            covering(tables, key)
+        } else {
+            tables
+        }
+    }
+
+    /// Every table that may hold keys in `[start, end)`, skipping the
+    /// tables of key-sorted levels that lie outside it
+    pub(super) fn tables_for_range(&self, start: &[u8], end: Option<&[u8]>) -> Vec<Arc<SSTable>> {
+        let mut selected = Vec::new();
+        for (level, tables) in &self.sstables {
+            if self.sorted_levels.contains(level) {
+                selected.extend_from_slice(overlapping(tables, start, end));
+            } else {
+                selected.extend_from_slice(tables);
+            }
+        }
+        selected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::{Entry, Version};
+    use crate::storage::{Storage, StorageOptions};
+    use tempfile::TempDir;
+
+    const FILES: usize = 100;
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("key{:05}", i).into_bytes()
+    }
+
+    /// A storage with `FILES` tables of ten keys each at L2, written in
+    /// shuffled order and without bloom filters so only key ranges can
+    /// rule a table out. The block cache is off so every lookup that
+    /// reaches a table opens it.
+    fn deep_tree() -> (TempDir, Storage) {
+        let temp_dir = TempDir::new().unwrap();
+        for n in 0..FILES {
+            let file = (n * 37) % FILES;
+            let path = temp_dir.path().join(format!("L2_{}.sst", n));
+            let mut table = SSTable::new(path).unwrap().with_bloom(None);
+            let entries: Vec<_> = (file * 10..file * 10 + 10)
+                .map(|i| {
+                    (
+                        key(i),
+                        Version::new(i as u64 + 1, Entry::Value(b"v".to_vec())),
+                    )
+                })
+                .collect();
+            table.write_entries(&entries).unwrap();
+        }
+        let options = StorageOptions::default().block_cache_size(0);
+        let storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+        (temp_dir, storage)
+    }
+
+    #[test]
+    fn test_l0_read_in_number_order_whatever_the_listing_order() {
+        let temp_dir = TempDir::new().unwrap();
+        // Newest first, so directory listings tend to return them reversed
+        for n in (0..10u64).rev() {
+            let path = temp_dir.path().join(format!("L0_{}.sst", n));
+            let mut table = SSTable::new(path).unwrap();
+            let value = format!("value{}", n).into_bytes();
+            table
+                .write_entries(&[(b"key".to_vec(), Version::new(n + 1, Entry::Value(value)))])
+                .unwrap();
+        }
+
+        for _ in 0..2 {
+            // First from the listing, then from the manifest
+            let storage = Storage::new(temp_dir.path(), false).unwrap();
+            let numbers: Vec<_> = storage.levels().sstables[&0]
+                .iter()
+                .map(|table| parse_table_name(table.get_path()).unwrap().1)
+                .collect();
+            assert_eq!(numbers, (0..10).collect::<Vec<_>>());
+            assert_eq!(storage.get(b"key").unwrap(), Some(b"value9".to_vec()));
+        }
+    }
+
+    fn passes(storage: &Storage) -> Vec<u64> {
+        storage.levels().sstables[&2]
+            .iter()
+            .map(|table| table.data_passes())
+            .collect()
+    }
+
+    #[test]
+    fn test_l0_lookup_skips_tables_outside_key_range() {
+        let temp_dir = TempDir::new().unwrap();
+        for n in 0..10 {
+            let path = temp_dir.path().join(format!("L0_{}.sst", n));
+            let mut table = SSTable::new(path).unwrap().with_bloom(None);
+            let entries: Vec<_> = (n * 10..n * 10 + 10)
+                .map(|i| {
+                    (
+                        key(i),
+                        Version::new(i as u64 + 1, Entry::Value(b"v".to_vec())),
+                    )
+                })
+                .collect();
+            table.write_entries(&entries).unwrap();
+        }
+        let options = StorageOptions::default()
+            .block_cache_size(0)
+            .l0_compaction_files(100);
+        let storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+        assert!(!storage.levels().sorted_levels.contains(&0));
+
+        let opened = |storage: &Storage| -> u64 {
+            storage.levels().sstables[&0]
+                .iter()
+                .map(|table| table.data_passes())
+                .sum()
+        };
+        for i in [0, 35, 99] {
+            let before = opened(&storage);
+            assert_eq!(storage.get(key(i)).unwrap(), Some(b"v".to_vec()));
+            assert_eq!(opened(&storage) - before, 1, "lookup of key {}", i);
+        }
+        assert_eq!(storage.get(key(100)).unwrap(), None);
+        assert_eq!(opened(&storage), 3);
+    }
+
+    #[test]
+    fn test_point_lookup_opens_one_file() {
+        let (_temp_dir, storage) = deep_tree();
+        assert!(storage.levels().sorted_levels.contains(&2));
+
+        let mut before = passes(&storage).iter().sum::<u64>();
+        for i in [0, 9, 10, 555, FILES * 10 - 1] {
+            assert_eq!(storage.get(key(i)).unwrap(), Some(b"v".to_vec()));
+            let after = passes(&storage).iter().sum::<u64>();
+            assert_eq!(after - before, 1, "lookup of key {}", i);
+            before = after;
+        }
+
+        // Keys outside every table's range open nothing
+        for missing in [b"a".to_vec(), b"key00005x".to_vec(), b"z".to_vec()] {
+            let expected = u64::from(missing == b"key00005x");
+            assert_eq!(storage.get(&missing).unwrap(), None);
+            let after = passes(&storage).iter().sum::<u64>();
+            assert_eq!(after - before, expected);
+            before = after;
+        }
+    }
+
+    #[test]
+    fn test_scan_opens_overlapping_files() {
+        let (_temp_dir, storage) = deep_tree();
+        let keys: Vec<_> = storage
+            .scan(&key(105), &key(135))
+            .unwrap()
+            .map(|pair| pair.unwrap().0)
+            .collect();
+        assert_eq!(keys, (105..135).map(key).collect::<Vec<_>>());
+
+        let opened: Vec<usize> = passes(&storage)
+            .iter()
+            .enumerate()
+            .filter(|(_, &n)| n > 0)
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(opened, vec![10, 11, 12, 13]);
+        assert_eq!(storage.count_range(&key(105), &key(135)).unwrap(), 30);
+    }
+
+    #[test]
+    fn test_overlap_restores_age_order() {
+        let (_temp_dir, storage) = deep_tree();
+        // A newer table spanning the whole level leaves it unsorted, so
+        // lookups fall back to probing newest first
+        let path = storage.table_path(2, FILES as u64);
+        let mut table = SSTable::new(path).unwrap();
+        let entries = vec![
+            (key(0), Version::new(10_000, Entry::Value(b"new".to_vec()))),
+            (
+                key(999),
+                Version::new(10_001, Entry::Value(b"new".to_vec())),
+            ),
+        ];
+        table.write_entries(&entries).unwrap();
+        storage.edit_levels(|levels| {
+            levels.sstables.get_mut(&2).unwrap().push(Arc::new(table));
+            levels.arrange_level(2);
+        });
+
+        assert!(!storage.levels().sorted_levels.contains(&2));
+        assert_eq!(storage.get(key(999)).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(storage.get(key(500)).unwrap(), Some(b"v".to_vec()));
+        let numbers: Vec<_> = storage
+            .level_files(2)
+            .iter()
+            .map(|file| file.file_number)
+            .collect();
+        assert!(numbers.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+}
@@ -0,0 +1,224 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use log::warn;
+
+use crate::stats::Counters;
+
+/// Cumulative counters carried across restarts
+pub const STATS_FILE: &str = "STATS";
+
+/// Counters for this instance on top of those loaded from `STATS`. The
+/// loaded values are kept apart so rates can be taken from the
+/// in-process deltas alone.
+pub(super) struct LifetimeStats {
+    dir: PathBuf,
+    loaded: Counters,
+    puts: AtomicU64,
+    deletes: AtomicU64,
+    flushes: AtomicU64,
+    bytes_flushed: AtomicU64,
+    compactions: AtomicU64,
+    bytes_compacted: AtomicU64,
+    compaction_ms: AtomicU64,
+    gets: AtomicU64,
+    bloom_negatives: AtomicU64,
+    bloom_false_positives: AtomicU64,
+    write_slowdowns: AtomicU64,
+    write_stops: AtomicU64,
+    write_stall_ms: AtomicU64,
+}
+
+impl LifetimeStats {
+    /// Seed from `dir`'s `STATS` file. A missing or unreadable file
+    /// starts over from zero rather than keeping the directory from opening.
+    pub(super) fn new(dir: &Path) -> Self {
+        let loaded = match fs::read_to_string(dir.join(STATS_FILE)) {
+            Ok(text) => Counters::parse(&text).unwrap_or_else(|e| {
+                warn!("Ignoring unreadable {}: {}", STATS_FILE, e);
+                Counters::default()
+            }),
+            Err(_) => Counters::default(),
+        };
+        LifetimeStats {
+            dir: dir.to_path_buf(),
+            loaded,
+            puts: AtomicU64::new(0),
+            deletes: AtomicU64::new(0),
+            flushes: AtomicU64::new(0),
+            bytes_flushed: AtomicU64::new(0),
+            compactions: AtomicU64::new(0),
+            bytes_compacted: AtomicU64::new(0),
+            compaction_ms: AtomicU64::new(0),
+            gets: AtomicU64::new(0),
+            bloom_negatives: AtomicU64::new(0),
+            bloom_false_positives: AtomicU64::new(0),
+            write_slowdowns: AtomicU64::new(0),
+            write_stops: AtomicU64::new(0),
+            write_stall_ms: AtomicU64::new(0),
+        }
+    }
+
+    pub(super) fn add_writes(&self, puts: usize, deletes: usize) {
+        self.puts.fetch_add(puts as u64, Ordering::Relaxed);
+        self.deletes.fetch_add(deletes as u64, Ordering::Relaxed);
+    }
+
+    pub(super) fn add_flush(&self, bytes: usize) {
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        self.bytes_flushed
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(super) fn add_compaction(&self, bytes: usize, ms: u64) {
+        self.compactions.fetch_add(1, Ordering::Relaxed);
+        self.bytes_compacted
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.compaction_ms.fetch_add(ms, Ordering::Relaxed);
+    }
+
+    pub(super) fn add_gets(
+        &self,
+        keys: usize,
+        bloom_negatives: usize,
+        bloom_false_positives: usize,
+    ) {
+        self.gets.fetch_add(keys as u64, Ordering::Relaxed);
+        self.bloom_negatives
+            .fetch_add(bloom_negatives as u64, Ordering::Relaxed);
+        self.bloom_false_positives
+            .fetch_add(bloom_false_positives as u64, Ordering::Relaxed);
+    }
+
+    /// A write delayed at the slowdown limit, or held at the stop limit
+    pub(super) fn add_stall(&self, stopped: bool, ms: u64) {
+        let counter = if stopped {
+            &self.write_stops
+        } else {
+            &self.write_slowdowns
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.write_stall_ms.fetch_add(ms, Ordering::Relaxed);
+    }
+
+    pub(super) fn since_open(&self) -> Counters {
+        Counters {
+            puts: self.puts.load(Ordering::Relaxed),
+            deletes: self.deletes.load(Ordering::Relaxed),
+            flushes: self.flushes.load(Ordering::Relaxed),
+            bytes_flushed: self.bytes_flushed.load(Ordering::Relaxed),
+            compactions: self.compactions.load(Ordering::Relaxed),
+            bytes_compacted: self.bytes_compacted.load(Ordering::Relaxed),
+            compaction_ms: self.compaction_ms.load(Ordering::Relaxed),
+            gets: self.gets.load(Ordering::Relaxed),
+            bloom_negatives: self.bloom_negatives.load(Ordering::Relaxed),
+            bloom_false_positives: self.bloom_false_positives.load(Ordering::Relaxed),
+            write_slowdowns: self.write_slowdowns.load(Ordering::Relaxed),
+            write_stops: self.write_stops.load(Ordering::Relaxed),
+            write_stall_ms: self.write_stall_ms.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(super) fn lifetime(&self) -> Counters {
+        self.loaded + self.since_open()
+    }
+
+    /// Write the lifetime counters to `STATS`, replacing it atomically
+    pub(super) fn persist(&self) -> io::Result<()> {
+        let tmp_path = self.dir.join(format!("{}.tmp", STATS_FILE));
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(self.lifetime().to_text().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, self.dir.join(STATS_FILE))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_stats_survive_reopen() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        storage.put(b"key", b"value").unwrap();
+        storage.flush().unwrap();
+        storage.get(b"key").unwrap();
+        let before = storage.stats();
+        assert_eq!(before.since_open.flushes, 1);
+        assert!(before.lifetime.bytes_flushed > 0);
+        assert_eq!(before.lifetime, before.since_open);
+        drop(storage);
+
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        let reopened = storage.stats();
+        assert_eq!(reopened.lifetime, before.lifetime);
+        assert_eq!(reopened.since_open, Counters::default());
+
+        storage.put(b"other", b"value").unwrap();
+        storage.flush().unwrap();
+        let stats = storage.stats();
+        assert_eq!(stats.since_open.flushes, 1);
+        assert_eq!(stats.lifetime.flushes, 2);
+        assert_eq!(stats.lifetime.gets, 1);
+    }
+
+    #[test]
+    fn test_counters_follow_operations() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        for key in [b"a", b"b", b"c"] {
+            storage.put(key.to_vec(), b"value".to_vec()).unwrap();
+        }
+        storage.delete(b"b").unwrap();
+        let mut batch = crate::storage::WriteBatch::new();
+        batch.put(b"d", b"value");
+        batch.delete(b"a".to_vec());
+        storage.write(batch).unwrap();
+
+        let stats = storage.stats();
+        assert_eq!((stats.since_open.puts, stats.since_open.deletes), (4, 2));
+        assert_eq!(stats.memtable_entries, 4);
+        assert!(stats.memtable_bytes > 0);
+        assert!(stats.wal_bytes > 0);
+        assert_eq!(stats.since_open.flushes, 0);
+
+        storage.flush().unwrap();
+        // The flush drops the tombstones, leaving a table of c and d: z is
+        // outside it and c is found, so neither counts against the filter
+        storage.get(b"z").unwrap();
+        storage.get(b"c").unwrap();
+        // Absent keys inside its range are ruled out or false positives
+        for i in 0..20 {
+            storage.get(format!("c{}", i).into_bytes()).unwrap();
+        }
+        let stats = storage.stats();
+        assert_eq!(stats.memtable_entries, 0);
+        assert_eq!(stats.since_open.flushes, 1);
+        assert_eq!(stats.since_open.gets, 22);
+        let counters = stats.since_open;
+        assert_eq!(
+            counters.bloom_negatives + counters.bloom_false_positives,
+            20
+        );
+        assert!(counters.bloom_negatives >= 15);
+        assert_eq!(stats.levels.iter().map(|l| l.file_count).sum::<usize>(), 1);
+
+        // Another instance's counters are its own
+        let other_dir = TempDir::new().unwrap();
+        let other = Storage::new(other_dir.path(), false).unwrap();
+        assert_eq!(other.stats().since_open, Counters::default());
+    }
+
+    #[test]
+    fn test_corrupt_stats_reset() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(temp_dir.path().join(STATS_FILE), "flushes=\u{0}garbage").unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        assert_eq!(storage.stats().lifetime, Counters::default());
+    }
+}
@@ -0,0 +1,79 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io;
+use std::path::Path;
+
+/// Locked by whichever instance has the data directory open
+pub const LOCK_FILE: &str = "LOCK";
+
+/// An exclusive advisory lock on a data directory, held for as long as the
+/// value lives.
+///
+/// The lock belongs to the open file rather than the process, so a second
+/// instance in the same process is refused just like one in another. It's
+/// released when the file closes: on drop, while unwinding from a panic, or
+/// when the process dies, so a crash never leaves the directory locked.
+pub(super) struct DirLock {
+    _file: File,
+}
+
+impl DirLock {
+    /// Lock `dir`, failing at once with `WouldBlock` if another instance
+    /// holds it
+    pub(super) fn acquire(dir: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(dir.join(LOCK_FILE))?;
+        match file.try_lock() {
+            Ok(()) => Ok(DirLock { _file: file }),
+            Err(TryLockError::WouldBlock) => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("{:?} is already open in another Storage instance", dir),
+            )),
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+
+    /// Give the lock up while keeping the file open, as a process dying
+    /// would
+    #[cfg(test)]
+    pub(super) fn release(&self) {
+        self._file.unlock().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::Storage;
+    use std::io;
+    use std::panic;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_second_open_fails_until_the_first_is_dropped() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        storage.put("key", "value").unwrap();
+
+        let err = Storage::new(temp_dir.path(), false).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert!(err.to_string().contains(&format!("{:?}", temp_dir.path())));
+
+        drop(storage);
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        assert_eq!(storage.get("key").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_lock_is_released_by_a_panic() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        let result = panic::catch_unwind(move || {
+            let _storage = Storage::new(&path, false).unwrap();
+            panic!("while open");
+        });
+        assert!(result.is_err());
+        Storage::new(temp_dir.path(), false).unwrap();
+    }
+}
@@ -0,0 +1,389 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+#[cfg(feature = "object-store")]
+use std::sync::Arc;
+
+#[cfg(feature = "object-store")]
+use log::warn;
+
+use super::sync_dir;
+#[cfg(feature = "object-store")]
+use crate::objectfs::ObjectFs;
+
+/// Records which tables are live, as a log of changes
+pub const MANIFEST_FILE: &str = "MANIFEST";
+
+/// One change to the set of live tables
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManifestEdit {
+    /// Table number `seq` joined `level`; `path` is relative to the
+    /// level's table directory
+    AddFile {
+        level: usize,
+        seq: u64,
+        path: PathBuf,
+        size: u64,
+    },
+    RemoveFile {
+        level: usize,
+        seq: u64,
+    },
+    /// Table numbers below this may have been handed out
+    SetCounter(u64),
+    /// WAL segments numbered below this only hold writes already in tables
+    SetLogNumber(u64),
+    /// Every write numbered up to this is in a table, or was compacted away
+    SetLastSeq(u64),
+}
+
+impl ManifestEdit {
+    /// Write format: `add <level> <seq> <path> <size>`, `remove <level>
+    /// <seq>`, `counter <n>`, `log <n>` or `last_seq <n>`
+    fn to_text(&self) -> String {
+        match self {
+            ManifestEdit::AddFile {
+                level,
+                seq,
+                path,
+                size,
+            } => format!("add {} {} {} {}", level, seq, path.display(), size),
+            ManifestEdit::RemoveFile { level, seq } => format!("remove {} {}", level, seq),
+            ManifestEdit::SetCounter(n) => format!("counter {}", n),
+            ManifestEdit::SetLogNumber(n) => format!("log {}", n),
+            ManifestEdit::SetLastSeq(n) => format!("last_seq {}", n),
+        }
+    }
+
+    fn parse(text: &str) -> io::Result<Self> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid MANIFEST edit {:?}", text),
+            )
+        };
+        let fields: Vec<&str> = text.split(' ').collect();
+        let number = |i: usize| -> io::Result<u64> {
+            fields
+                .get(i)
+                .and_then(|field| field.parse().ok())
+                .ok_or_else(invalid)
+        };
+        match (fields[0], fields.len()) {
+            ("add", 5) => Ok(ManifestEdit::AddFile {
+                level: number(1)? as usize,
+                seq: number(2)?,
+                path: PathBuf::from(fields[3]),
+                size: number(4)?,
+            }),
+            ("remove", 3) => Ok(ManifestEdit::RemoveFile {
+                level: number(1)? as usize,
+                seq: number(2)?,
+            }),
+            ("counter", 2) => Ok(ManifestEdit::SetCounter(number(1)?)),
+            ("log", 2) => Ok(ManifestEdit::SetLogNumber(number(1)?)),
+            ("last_seq", 2) => Ok(ManifestEdit::SetLastSeq(number(1)?)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// The live tables, keyed by level and table number, the next number to
+/// hand out, the first WAL segment recovery replays and the last sequence
+/// number flushed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ManifestState {
+    /// `(level, seq)` to the table's path and size
+    pub tables: BTreeMap<(usize, u64), (PathBuf, u64)>,
+    pub counter: u64,
+    pub log_number: u64,
+    pub last_seq: u64,
+}
+
+impl ManifestState {
+    pub fn apply(&mut self, edit: &ManifestEdit) {
+        match edit {
+            ManifestEdit::AddFile {
+                level,
+                seq,
+                path,
+                size,
+            } => {
+                self.tables.insert((*level, *seq), (path.clone(), *size));
+                self.counter = self.counter.max(seq + 1);
+            }
+            ManifestEdit::RemoveFile { level, seq } => {
+                self.tables.remove(&(*level, *seq));
+            }
+            ManifestEdit::SetCounter(n) => self.counter = self.counter.max(*n),
+            ManifestEdit::SetLogNumber(n) => self.log_number = self.log_number.max(*n),
+            ManifestEdit::SetLastSeq(n) => self.last_seq = self.last_seq.max(*n),
+        }
+    }
+
+    /// Edits that rebuild this state from nothing
+    fn edits(&self) -> Vec<ManifestEdit> {
+        let mut edits: Vec<_> = self
+            .tables
+            .iter()
+            .map(|(&(level, seq), (path, size))| ManifestEdit::AddFile {
+                level,
+                seq,
+                path: path.clone(),
+                size: *size,
+            })
+            .collect();
+        edits.push(ManifestEdit::SetCounter(self.counter));
+        if self.log_number > 0 {
+            edits.push(ManifestEdit::SetLogNumber(self.log_number));
+        }
+        if self.last_seq > 0 {
+            edits.push(ManifestEdit::SetLastSeq(self.last_seq));
+        }
+        edits
+    }
+}
+
+/// The `MANIFEST` file of a data directory, open for appending.
+///
+/// Each line holds the edits of one change separated by `; `, so a change
+/// applies whole or, if a crash tore its line, not at all. Opening rewrites
+/// the file as a single line describing the current state.
+pub struct Manifest {
+    file: File,
+    // The store a copy is uploaded to after every change, and the file
+    // copied
+    #[cfg(feature = "object-store")]
+    mirror: Option<(Arc<ObjectFs>, PathBuf)>,
+}
+
+impl Manifest {
+    /// Replay the manifest in `data_dir`, or `None` for a directory that
+    /// doesn't have one yet. A torn last line is ignored.
+    pub fn load(data_dir: &Path) -> io::Result<Option<ManifestState>> {
+        let text = match fs::read_to_string(data_dir.join(MANIFEST_FILE)) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        Self::replay(&text).map(Some)
+    }
+
+    /// The state the copy of the manifest in `objects` describes, or
+    /// `None` if the store has none
+    #[cfg(feature = "object-store")]
+    pub fn load_mirror(objects: &ObjectFs) -> io::Result<Option<ManifestState>> {
+        match objects.fetch(MANIFEST_FILE)? {
+            Some(bytes) => Self::replay(&String::from_utf8_lossy(&bytes)).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// The state `text`'s complete lines add up to
+    fn replay(text: &str) -> io::Result<ManifestState> {
+        let mut state = ManifestState::default();
+        // Only lines ended by a newline were completely written
+        let complete = text.rfind('\n').map_or("", |end| &text[..end]);
+        for line in complete.lines().filter(|line| !line.is_empty()) {
+            let edits = line
+                .split("; ")
+                .map(ManifestEdit::parse)
+                .collect::<io::Result<Vec<_>>>()?;
+            for edit in &edits {
+                state.apply(edit);
+            }
+        }
+        Ok(state)
+    }
+
+    /// Durably replace the manifest in `data_dir` with one describing
+    /// `state`, and open it for further changes
+    pub fn create(data_dir: &Path, state: &ManifestState) -> io::Result<Self> {
+        let tmp_path = data_dir.join(format!("{}.tmp", MANIFEST_FILE));
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(Self::line(&state.edits()).as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, data_dir.join(MANIFEST_FILE))?;
+        sync_dir(data_dir)?;
+
+        let file = OpenOptions::new()
+            .append(true)
+            .open(data_dir.join(MANIFEST_FILE))?;
+        Ok(Manifest {
+            file,
+            #[cfg(feature = "object-store")]
+            mirror: None,
+        })
+    }
+
+    /// Upload a copy of the manifest in `data_dir` to `objects`, now and
+    /// after every change from here on
+    #[cfg(feature = "object-store")]
+    pub fn mirror_to(mut self, objects: Arc<ObjectFs>, data_dir: &Path) -> io::Result<Self> {
+        let path = data_dir.join(MANIFEST_FILE);
+        objects.put(MANIFEST_FILE, fs::read(&path)?)?;
+        self.mirror = Some((objects, path));
+        Ok(self)
+    }
+
+    /// Durably append one change made of `edits`
+    pub fn record(&mut self, edits: &[ManifestEdit]) -> io::Result<()> {
+        self.file.write_all(Self::line(edits).as_bytes())?;
+        self.file.sync_data()?;
+        // The local file is the one recovery reads; a copy that failed to
+        // upload is replaced along with the next change
+        #[cfg(feature = "object-store")]
+        if let Some((objects, path)) = &self.mirror {
+            if let Err(e) = fs::read(path).and_then(|bytes| objects.put(MANIFEST_FILE, bytes)) {
+                warn!(
+                    "Failed to copy {} to the object store: {}",
+                    MANIFEST_FILE, e
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn line(edits: &[ManifestEdit]) -> String {
+        let edits: Vec<String> = edits.iter().map(ManifestEdit::to_text).collect();
+        format!("{}\n", edits.join("; "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Storage, StorageOptions};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    fn add(level: usize, seq: u64) -> ManifestEdit {
+        ManifestEdit::AddFile {
+            level,
+            seq,
+            path: PathBuf::from(format!("L{}_{}.sst", level, seq)),
+            size: 100,
+        }
+    }
+
+    #[test]
+    fn test_edits_replay_and_torn_line_ignored() {
+        let temp_dir = TempDir::new().unwrap();
+        assert_eq!(Manifest::load(temp_dir.path()).unwrap(), None);
+
+        let mut manifest = Manifest::create(temp_dir.path(), &ManifestState::default()).unwrap();
+        manifest.record(&[add(0, 0), add(0, 1)]).unwrap();
+        manifest
+            .record(&[
+                add(1, 2),
+                ManifestEdit::RemoveFile { level: 0, seq: 0 },
+                ManifestEdit::RemoveFile { level: 0, seq: 1 },
+                ManifestEdit::SetCounter(5),
+                ManifestEdit::SetLogNumber(3),
+                ManifestEdit::SetLastSeq(42),
+            ])
+            .unwrap();
+        let state = Manifest::load(temp_dir.path()).unwrap().unwrap();
+        assert_eq!(state.tables.keys().collect::<Vec<_>>(), vec![&(1, 2)]);
+        assert_eq!(state.counter, 5);
+        assert_eq!(state.log_number, 3);
+        assert_eq!(state.last_seq, 42);
+
+        // Half of a change never happened
+        manifest
+            .file
+            .write_all(b"add 0 6 L0_6.sst 100; remove 1 ")
+            .unwrap();
+        assert_eq!(Manifest::load(temp_dir.path()).unwrap().unwrap(), state);
+
+        // Recreating keeps the state but drops the history
+        Manifest::create(temp_dir.path(), &state).unwrap();
+        let text = fs::read_to_string(temp_dir.path().join(MANIFEST_FILE)).unwrap();
+        assert_eq!(
+            text,
+            "add 1 2 L1_2.sst 100; counter 5; log 3; last_seq 42\n"
+        );
+    }
+
+    #[test]
+    fn test_garbage_tables_removed_on_open() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        storage.put(b"key", b"value").unwrap();
+        storage.flush().unwrap();
+        drop(storage);
+
+        // Leftovers no manifest edit ever mentioned
+        let leftovers = ["L0_7.sst", "compact_3.sst", "L1_8.data"];
+        for name in leftovers {
+            fs::write(temp_dir.path().join(name), b"partial").unwrap();
+        }
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        for name in leftovers {
+            assert!(!temp_dir.path().join(name).exists());
+        }
+        assert_eq!(storage.level_files(0).len(), 1);
+        assert_eq!(storage.get(b"key").unwrap(), Some(b"value".to_vec()));
+    }
+
+    fn sst_files(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.ends_with(".sst"))
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn fill_l0(storage: &mut Storage) {
+        for i in 0..4u8 {
+            storage.put(vec![i], vec![i; 10]).unwrap();
+            storage.put(b"shared".to_vec(), vec![i; 10]).unwrap();
+            storage.freeze(&mut storage.writer()).unwrap();
+            storage.finish_flush(&mut storage.writer()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_compaction_crash_leaves_no_visible_duplicates() {
+        // Crash after writing the output, before installing it
+        let temp_dir = TempDir::new().unwrap();
+        let options = StorageOptions {
+            compaction_output_hook: Some(Arc::new(|_: &Path| panic!("crash"))),
+            ..Default::default()
+        };
+        let mut storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+        fill_l0(&mut storage);
+        assert!(storage
+            .compact_until_settled(&mut storage.writer(), 0)
+            .is_err());
+        let inputs = sst_files(temp_dir.path());
+        assert_eq!(inputs.len(), 5);
+        storage.crash();
+
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        assert_eq!(storage.level_files(0).len(), 4);
+        assert!(storage.level_files(1).is_empty());
+        assert_eq!(sst_files(temp_dir.path()).len(), 4);
+        assert_eq!(storage.scan(b"", b"").unwrap().count(), 5);
+        assert_eq!(storage.get(b"shared").unwrap(), Some(vec![3; 10]));
+        drop(storage);
+
+        // Crash after installing the output, before the inputs were removed
+        let mut storage = Storage::new(temp_dir.path(), false).unwrap();
+        let scan = storage.scan(b"", b"").unwrap();
+        storage.compact_level(0).unwrap();
+        std::mem::forget(scan);
+        assert_eq!(sst_files(temp_dir.path()).len(), 5);
+        storage.crash();
+
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        assert!(storage.level_files(0).is_empty());
+        assert_eq!(storage.level_files(1).len(), 1);
+        assert_eq!(sst_files(temp_dir.path()).len(), 1);
+        assert_eq!(storage.scan(b"", b"").unwrap().count(), 5);
+        assert_eq!(storage.get(b"shared").unwrap(), Some(vec![3; 10]));
+    }
+}
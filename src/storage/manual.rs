@@ -0,0 +1,261 @@
+use std::cmp::Reverse;
+use std::io;
+use std::sync::Arc;
+
+use log::debug;
+
+use super::{parse_table_name, CompactionStrategy, Storage, Writer};
+use crate::error::StorageError;
+use crate::sstable::SSTable;
+
+/// Whether `table` may hold keys in `[start, end]`, or in `[start, ..)`
+/// without an end. Tables with no recorded key range always may.
+fn overlaps(table: &SSTable, start: &[u8], end: Option<&[u8]>) -> bool {
+    table
+        .key_range()
+        .is_none_or(|(smallest, largest)| largest >= start && end.is_none_or(|end| smallest <= end))
+}
+
+impl Storage {
+    /// Merge every table into one at the bottom level, dropping overwritten
+    /// versions and, past their retention window, tombstones. The memtable
+    /// is flushed first, and levels left over their trigger afterwards are
+    /// compacted as usual. FIFO compaction never merges, so it fails with
+    /// `Unsupported` there.
+    pub fn compact_all(&mut self) -> Result<(), StorageError> {
+        self.refuse_fifo()?;
+        let mut writer = self.writer();
+        self.flush_memtable(&mut writer)?;
+        self.merge_range(&mut writer, b"", None)?;
+        Ok(self.compact_until_settled(&mut writer, 0)?)
+    }
+
+    /// Rewrite the tables holding keys in `[start, end)` (an empty `end`
+    /// runs to the last key) as one table, leaving tables outside the range
+    /// alone. The memtable isn't flushed. Returns `false` if no table
+    /// overlaps the range.
+    ///
+    /// Tables in the same or shallower levels that overlap the merged
+    /// tables are merged too, even outside the range, so no older version
+    /// ends up shadowing the output.
+    pub fn compact_range(&mut self, start: &[u8], end: &[u8]) -> Result<bool, StorageError> {
+        self.refuse_fifo()?;
+        debug!("COMPACT_RANGE {:?}..{:?}", start, end);
+        if !end.is_empty() && start >= end {
+            return Ok(false);
+        }
+        let mut writer = self.writer();
+        let merged = self.merge_range(&mut writer, start, (!end.is_empty()).then_some(end))?;
+        if merged {
+            self.compact_until_settled(&mut writer, 0)?;
+        }
+        Ok(merged)
+    }
+
+    fn refuse_fifo(&self) -> io::Result<()> {
+        if self.options.compaction_strategy != CompactionStrategy::Leveled {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Tables are never merged under FIFO compaction",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Merge the tables covering `[start, end)` into the deepest level
+    /// among them, below Level 0
+    fn merge_range(
+        &self,
+        writer: &mut Writer,
+        start: &[u8],
+        end: Option<&[u8]>,
+    ) -> io::Result<bool> {
+        self.finish_compaction(writer)?;
+        let tables: Vec<(usize, Arc<SSTable>)> = self
+            .levels()
+            .sstables
+            .iter()
+            .flat_map(|(&level, tables)| tables.iter().map(move |table| (level, table.clone())))
+            .collect();
+        let mut selected: Vec<bool> = tables
+            .iter()
+            .map(|(_, table)| {
+                overlaps(table, start, None)
+                    && end.is_none_or(|end| {
+                        table.key_range().is_none_or(|(smallest, _)| smallest < end)
+                    })
+            })
+            .collect();
+        if !selected.contains(&true) {
+            return Ok(false);
+        }
+
+        // Widen the selection until nothing in a level at or above the
+        // deepest selected one overlaps it: reads stop at the shallowest
+        // version, so an unselected newer table must stay above the output
+        // and an unselected older one can't be left above it
+        let deepest = (0..tables.len())
+            .filter(|&i| selected[i])
+            .map(|i| tables[i].0)
+            .max()
+            .unwrap();
+        loop {
+            let ranges: Vec<_> = (0..tables.len())
+                .filter(|&i| selected[i])
+                .map(|i| tables[i].1.key_range())
+                .collect();
+            let (low, high) = if ranges.contains(&None) {
+                (&b""[..], None)
+            } else {
+                let low = ranges.iter().map(|range| range.unwrap().0).min().unwrap();
+                let high = ranges.iter().map(|range| range.unwrap().1).max();
+                (low, high)
+            };
+            let widened: Vec<usize> = (0..tables.len())
+                .filter(|&i| !selected[i] && tables[i].0 <= deepest)
+                .filter(|&i| overlaps(&tables[i].1, low, high))
+                .collect();
+            if widened.is_empty() {
+                break;
+            }
+            for i in widened {
+                selected[i] = true;
+            }
+        }
+
+        // Oldest first, as the merge expects: deepest level first, then by
+        // file number within a level
+        let mut inputs: Vec<(usize, u64, Arc<SSTable>)> = tables
+            .into_iter()
+            .zip(selected)
+            .filter(|(_, selected)| *selected)
+            .map(|((level, table), _)| {
+                let number = parse_table_name(table.get_path()).map_or(0, |(_, n)| n);
+                (level, number, table)
+            })
+            .collect();
+        inputs.sort_by_key(|&(level, number, _)| (Reverse(level), number));
+        let inputs = inputs.into_iter().map(|(_, _, table)| table).collect();
+        self.run_merge(writer, inputs, deepest.max(1))?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::{Entry, Version};
+    use crate::storage::StorageOptions;
+    use tempfile::TempDir;
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("key{:05}", i).into_bytes()
+    }
+
+    fn write_table(dir: &TempDir, level: usize, number: u64, keys: std::ops::Range<usize>) {
+        let path = dir.path().join(format!("L{}_{}.sst", level, number));
+        let entries: Vec<_> = keys
+            .map(|i| {
+                let value = format!("v{}", number).into_bytes();
+                (
+                    key(i),
+                    Version::new(number * 1000 + i as u64, Entry::Value(value)),
+                )
+            })
+            .collect();
+        SSTable::new(path).unwrap().write_entries(&entries).unwrap();
+    }
+
+    fn table_names(storage: &Storage) -> Vec<String> {
+        let mut names: Vec<String> = storage
+            .levels()
+            .sstables
+            .values()
+            .flatten()
+            .map(|table| {
+                let name = table.get_path().file_name().unwrap();
+                name.to_string_lossy().into_owned()
+            })
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_compact_all_leaves_one_bottom_table_of_live_keys() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = StorageOptions::default().memtable_size(4 * 1024);
+        let mut storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+        for round in 0..4 {
+            for i in 0..300 {
+                storage.put(key(i), vec![round as u8; 64]).unwrap();
+                if i % 3 == round % 3 {
+                    storage.delete(key(i)).unwrap();
+                }
+            }
+            storage.flush().unwrap();
+        }
+        let live: Vec<_> = storage
+            .scan(b"", b"")
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert!(storage.levels().sstables.values().flatten().count() > 1);
+
+        storage.compact_all().unwrap();
+        let tables: Vec<_> = storage
+            .levels()
+            .sstables
+            .values()
+            .flatten()
+            .cloned()
+            .collect();
+        assert_eq!(tables.len(), 1);
+        let bottom = *storage.levels().sstables.keys().max().unwrap();
+        assert_eq!(storage.levels().sstables[&bottom].len(), 1);
+        let properties = tables[0].properties();
+        assert_eq!(properties.entry_count, live.len() as u64);
+        assert_eq!(properties.tombstone_count, 0);
+
+        drop(storage);
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        let reopened: Vec<_> = storage
+            .scan(b"", b"")
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(reopened, live);
+    }
+
+    #[test]
+    fn test_compact_range_rewrites_only_overlapping_tables() {
+        let temp_dir = TempDir::new().unwrap();
+        write_table(&temp_dir, 1, 0, 0..100);
+        write_table(&temp_dir, 1, 1, 100..200);
+        write_table(&temp_dir, 1, 2, 200..300);
+        // Newer versions of some keys of the middle table
+        write_table(&temp_dir, 0, 3, 150..160);
+        // Outside the middle table, so left in place
+        write_table(&temp_dir, 0, 4, 400..410);
+        let mut storage = Storage::new(temp_dir.path(), false).unwrap();
+
+        assert!(!storage.compact_range(&key(500), &key(600)).unwrap());
+        assert!(storage.compact_range(&key(120), &key(130)).unwrap());
+        assert_eq!(
+            table_names(&storage),
+            ["L0_4.sst", "L1_0.sst", "L1_2.sst", "L1_5.sst"]
+        );
+        assert!(storage.levels().sorted_levels.contains(&1));
+        assert_eq!(storage.get(key(155)).unwrap(), Some(b"v3".to_vec()));
+        assert_eq!(storage.get(key(165)).unwrap(), Some(b"v1".to_vec()));
+        assert_eq!(storage.get(key(405)).unwrap(), Some(b"v4".to_vec()));
+        assert_eq!(storage.scan(b"", b"").unwrap().count(), 310);
+
+        storage.delete(key(50)).unwrap();
+        storage.compact_all().unwrap();
+        assert_eq!(table_names(&storage), ["L1_7.sst"]);
+        assert_eq!(storage.get(key(50)).unwrap(), None);
+        assert_eq!(storage.get(key(155)).unwrap(), Some(b"v3".to_vec()));
+        assert_eq!(storage.scan(b"", b"").unwrap().count(), 309);
+    }
+}
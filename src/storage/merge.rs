@@ -0,0 +1,249 @@
+use std::io;
+
+use log::debug;
+
+use super::{ChangeEvent, MemWrite, Storage, Writer};
+use crate::entry::{self, Entry, Version};
+use crate::error::StorageError;
+use crate::wal::Operation;
+use crate::{Key, Value};
+
+impl Storage {
+    /// Log `operand` for `key` without reading it first. Reads apply the
+    /// configured merge operator to the operands on top of the value
+    /// beneath them, or to none past a delete, and compaction folds them
+    /// into a plain value. Fails with `InvalidInput` unless
+    /// `StorageOptions::merge_operator` is set.
+    ///
+    /// ```
+    /// # use std::sync::Arc;
+    /// # use lsm_rust::{Storage, StorageOptions};
+    /// # fn main() -> std::io::Result<()> {
+    /// # let dir = tempfile::tempdir()?;
+    /// // Concatenates, so the operands make up a list
+    /// let options = StorageOptions::default().merge_operator(Arc::new(|_key, base, operands| {
+    ///     let mut value = base.unwrap_or_default().to_vec();
+    ///     operands.iter().for_each(|operand| value.extend_from_slice(operand));
+    ///     value
+    /// }));
+    /// let db = Storage::open_with_options(dir.path(), options)?;
+    /// db.put(b"list", b"a")?;
+    /// db.merge(b"list", b"b")?;
+    /// db.merge(b"list", b"c")?;
+    /// assert_eq!(db.get(b"list")?, Some(b"abc".to_vec()));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn merge(
+        &self,
+        key: impl Into<Key>,
+        operand: impl Into<Value>,
+    ) -> Result<(), StorageError> {
+        let (key, operand) = (key.into(), operand.into());
+        if self.options.merge_operator.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "No merge operator configured",
+            )
+            .into());
+        }
+        if self.verbose {
+            debug!("MERGE {:?}", String::from_utf8_lossy(&key));
+        }
+
+        self.wait_for_room(key.len() + operand.len());
+        let mut writer = self.writer();
+        self.check_quota(&writer)?;
+        self.throttle_writes(&mut writer)?;
+
+        let seq = self.mem().seq() + 1;
+        writer
+            .wal
+            .append(seq, Operation::Merge, &key, Some(&operand))?;
+        self.apply_merge(&mut writer, &mut self.mem_for_write(), key, operand);
+        Ok(self.maybe_flush(&mut writer)?)
+    }
+
+    /// Add a logged merge operand to the memtable and tell watchers
+    fn apply_merge(&self, writer: &mut Writer, mem: &mut impl MemWrite, key: Key, operand: Value) {
+        self.lifetime.add_writes(1, 0);
+        writer.written_key_sizes.record(key.len());
+        writer.written_value_sizes.record(operand.len());
+        let seq = mem.seq() + 1;
+        let change = writer
+            .watchers
+            .wants(&key)
+            .then(|| (key.clone(), operand.clone()));
+        self.buffer(mem, key, Version::new(seq, Entry::Merge(operand)));
+        if let Some((key, operand)) = change {
+            writer.watchers.publish(ChangeEvent {
+                key,
+                op: Operation::Merge,
+                value: Some(operand),
+                seq,
+            });
+        }
+    }
+
+    /// Read `key` as of `max_seq` into `out` when its newest version is a
+    /// merge operand, returning whether it holds a value
+    pub(super) fn read_merged(
+        &self,
+        key: &[u8],
+        max_seq: u64,
+        out: &mut Vec<u8>,
+    ) -> io::Result<bool> {
+        let versions = self.merge_chain(key, max_seq)?;
+        let merge = self.options.merge_operator.as_ref();
+        let entries = versions.iter().map(|version| &version.entry);
+        match entry::resolve(key, entries, self.options.now(), merge)? {
+            Some(value) => {
+                *out = value;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Versions of `key` written at or before `max_seq`, newest first, down
+    /// to the first that isn't a merge operand
+    fn merge_chain(&self, key: &[u8], max_seq: u64) -> io::Result<Vec<Version>> {
+        let visible = |version: &Version| version.seq <= max_seq;
+        let (mem, levels) = self.current();
+        let mut versions = mem.versions(key);
+        versions.retain(visible);
+        drop(mem);
+
+        for level in 0..=levels.max_level() {
+            for sstable in levels.tables_for_key(level, key).iter().rev() {
+                if versions.iter().any(|version| !version.entry.is_merge()) {
+                    return Ok(versions);
+                }
+                versions.extend(sstable.versions(key)?.into_iter().filter(visible));
+            }
+        }
+        Ok(versions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::StorageOptions;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    /// Adds up operands and the base as little-endian u64 counters
+    fn counter() -> StorageOptions {
+        let add = |_: &[u8], base: Option<&[u8]>, operands: &[&[u8]]| {
+            let read = |bytes: &[u8]| u64::from_le_bytes(bytes.try_into().unwrap());
+            let sum: u64 = base.map_or(0, read) + operands.iter().map(|o| read(o)).sum::<u64>();
+            sum.to_le_bytes().to_vec()
+        };
+        StorageOptions::default()
+            .memtable_size(4 * 1024)
+            .merge_operator(Arc::new(add))
+    }
+
+    fn count(storage: &Storage, key: &[u8]) -> Option<u64> {
+        let value = storage.get(key).unwrap()?;
+        Some(u64::from_le_bytes(value.try_into().unwrap()))
+    }
+
+    #[test]
+    fn test_counter_survives_flush_and_compaction() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut storage = Storage::open_with_options(temp_dir.path(), counter()).unwrap();
+        for i in 0..3000u64 {
+            storage
+                .merge(b"hits".to_vec(), 1u64.to_le_bytes().to_vec())
+                .unwrap();
+            // Enough other keys to flush the memtable every few hundred hits
+            storage
+                .put(format!("key{:05}", i).into_bytes(), vec![0; 32])
+                .unwrap();
+            if i % 1000 == 999 {
+                assert_eq!(count(&storage, b"hits"), Some(i + 1));
+            }
+        }
+        assert!(storage.levels().sstables.values().flatten().count() > 1);
+        assert_eq!(count(&storage, b"hits"), Some(3000));
+        let hits = storage.multi_get(&[b"hits".to_vec()]).unwrap();
+        assert_eq!(hits, vec![Some(3000u64.to_le_bytes().to_vec())]);
+        let scanned: Vec<_> = storage
+            .scan(b"hits", b"hitt")
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            scanned,
+            vec![(b"hits".to_vec(), 3000u64.to_le_bytes().to_vec())]
+        );
+
+        // Compaction folds every operand into one value
+        storage.compact_all().unwrap();
+        assert_eq!(count(&storage, b"hits"), Some(3000));
+        let versions = storage.merge_chain(b"hits", u64::MAX).unwrap();
+        assert_eq!(versions.len(), 1);
+        assert!(!versions[0].entry.is_merge());
+
+        // Operands still in the WAL are replayed
+        storage
+            .merge(b"hits".to_vec(), 5u64.to_le_bytes().to_vec())
+            .unwrap();
+        drop(storage);
+        let storage = Storage::open_with_options(temp_dir.path(), counter()).unwrap();
+        assert_eq!(count(&storage, b"hits"), Some(3005));
+    }
+
+    #[test]
+    fn test_merge_without_base_or_past_delete() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut storage = Storage::open_with_options(temp_dir.path(), counter()).unwrap();
+        let one = 1u64.to_le_bytes().to_vec();
+
+        // No base value at all: the operands start from nothing
+        storage.merge(b"new".to_vec(), one.clone()).unwrap();
+        storage.merge(b"new".to_vec(), one.clone()).unwrap();
+        assert_eq!(count(&storage, b"new"), Some(2));
+
+        // A delete between operands hides the ones beneath it, in the
+        // memtable and once flushed apart into separate tables
+        storage
+            .put(b"reset".to_vec(), 10u64.to_le_bytes().to_vec())
+            .unwrap();
+        storage.merge(b"reset".to_vec(), one.clone()).unwrap();
+        storage.flush().unwrap();
+        storage.delete(b"reset").unwrap();
+        assert_eq!(count(&storage, b"reset"), None);
+        storage.flush().unwrap();
+        storage.merge(b"reset".to_vec(), one.clone()).unwrap();
+        assert_eq!(count(&storage, b"reset"), Some(1));
+        storage.flush().unwrap();
+        assert_eq!(count(&storage, b"reset"), Some(1));
+        assert_eq!(storage.count_range(b"", b"").unwrap(), 2);
+
+        storage.compact_all().unwrap();
+        assert_eq!(count(&storage, b"new"), Some(2));
+        assert_eq!(count(&storage, b"reset"), Some(1));
+    }
+
+    #[test]
+    fn test_merge_needs_an_operator() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        let err = storage.merge(b"key", b"1").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // Operands written with an operator can't be read without one
+        drop(storage);
+        let storage = Storage::open_with_options(temp_dir.path(), counter()).unwrap();
+        storage
+            .merge(b"key".to_vec(), 1u64.to_le_bytes().to_vec())
+            .unwrap();
+        drop(storage);
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        let err = storage.get(b"key").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+}
@@ -0,0 +1,249 @@
+use std::io;
+use std::iter;
+use std::path::Path;
+
+use super::lock::DirLock;
+use super::manifest::Manifest;
+use super::{sync_dir, Storage, StorageOptions};
+use crate::entry::{Entry, Version};
+use crate::error::StorageError;
+use crate::sstable::{IoMode, SSTable};
+
+impl Storage {
+    /// [`migrate_format_with_options`](Storage::migrate_format_with_options)
+    /// with default options
+    pub fn migrate_format<P: AsRef<Path>>(data_dir: P) -> Result<usize, StorageError> {
+        Self::migrate_format_with_options(data_dir, StorageOptions::default())
+    }
+
+    /// Rewrite every live table written before tables carried a format
+    /// header in the current format, so a directory that
+    /// [`open`](Storage::open_with_options) refuses for them opens again.
+    /// That includes the first release's tables, which had no properties
+    /// or sequence numbers; their writes are numbered by table instead.
+    /// Pass the options it's opened with, so tables in level directories
+    /// are found and rewritten with its filter and codec.
+    ///
+    /// Tables keep their level, number and contents, and each is rewritten
+    /// beside the original and renamed over it, so an interrupted migration
+    /// can simply be run again. The directory must not be open elsewhere.
+    /// Returns the tables rewritten.
+    pub fn migrate_format_with_options<P: AsRef<Path>>(
+        data_dir: P,
+        options: StorageOptions,
+    ) -> Result<usize, StorageError> {
+        let data_dir = data_dir.as_ref();
+        options.validate()?;
+        if !data_dir.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no database at {:?}", data_dir),
+            )
+            .into());
+        }
+        let _lock = DirLock::acquire(data_dir)?;
+
+        let manifest = Manifest::load(data_dir)?;
+        let mut state = match &manifest {
+            Some(state) => state.clone(),
+            None => Self::list_tables(&options.table_dirs(data_dir))?,
+        };
+        let mut migrated = 0;
+        for ((level, number), (name, size)) in state.tables.iter_mut() {
+            let dir = options.table_dir(data_dir, *level);
+            let path = dir.join(&*name);
+            let first_format = SSTable::read_first_format(&path)?;
+            let mut table = SSTable::open_legacy(path)?
+                .with_bloom(options.bloom)
+                .with_compression(options.compression);
+            if let Some(pairs) = first_format {
+                // Writes weren't numbered then, but tables were, in the
+                // order they were written, so a newer table still shadows
+                // an older one
+                let seq = *number + 1;
+                let entries: Vec<_> = pairs
+                    .into_iter()
+                    .map(|(key, value)| (key, Version::new(seq, Entry::Value(value))))
+                    .collect();
+                table.write_entries(&entries)?;
+                sync_dir(dir)?;
+                *size = table.size() as u64;
+                migrated += 1;
+                continue;
+            }
+            if table.format_version().is_some() {
+                continue;
+            }
+            let mut reader = table.entries()?;
+            let records =
+                iter::from_fn(|| {
+                    let entry = reader.next_entry().transpose()?;
+                    Some(entry.map(|(key, seq, entry)| {
+                        (key.to_vec(), Version::new(seq, entry.to_entry()))
+                    }))
+                });
+            let expected = table.properties().entry_count as usize;
+            table.write_stream_with(records, expected, IoMode::Buffered)?;
+            sync_dir(dir)?;
+            *size = table.size() as u64;
+            migrated += 1;
+        }
+
+        // A directory without a manifest finds its tables by name again
+        if manifest.is_some() && migrated > 0 {
+            Manifest::create(data_dir, &state)?;
+        }
+        Ok(migrated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sstable::HEADER_SIZE;
+    use std::fs;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("key{:04}", i).into_bytes()
+    }
+
+    fn tables(dir: &Path) -> Vec<PathBuf> {
+        let mut tables: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "sst"))
+            .collect();
+        tables.sort();
+        tables
+    }
+
+    /// Rewrite the table at `path`, written without a filter, as tables
+    /// were written before headers and sparse indexes: the index's offsets
+    /// count the header, so both go
+    fn make_legacy(path: &Path) {
+        let bytes = fs::read(path).unwrap();
+        // The footer is [data_end u64][magic u64]
+        let footer = bytes.len() - 16;
+        let data_end = u64::from_le_bytes(bytes[footer..footer + 8].try_into().unwrap());
+        fs::write(path, &bytes[HEADER_SIZE..data_end as usize]).unwrap();
+
+        let legacy = SSTable::open_legacy(path.to_path_buf()).unwrap();
+        assert_eq!(legacy.format_version(), None);
+        assert!(legacy.verify().is_ok());
+    }
+
+    #[test]
+    fn test_migrate_rewrites_legacy_tables() {
+        let temp_dir = TempDir::new().unwrap();
+        // Filters from then can't be rebuilt, so there are none
+        let options = StorageOptions::default().bloom(None);
+        let storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+        for batch in 0..3 {
+            for i in batch * 100..(batch + 1) * 100 {
+                storage.put(key(i), vec![b'v'; 32]).unwrap();
+            }
+            if batch == 2 {
+                storage.delete(key(150)).unwrap();
+            }
+            storage.flush().unwrap();
+        }
+        storage.crash();
+
+        let legacy = tables(temp_dir.path());
+        for path in &legacy[..2] {
+            make_legacy(path);
+        }
+        let err = Storage::new(temp_dir.path(), false).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("migrate_format"), "{}", err);
+
+        assert_eq!(Storage::migrate_format(temp_dir.path()).unwrap(), 2);
+        for path in &legacy {
+            let table = SSTable::new(path.clone()).unwrap();
+            assert_eq!(table.format_version(), Some(crate::TABLE_FORMAT_VERSION));
+        }
+        // Rewritten with the options given
+        assert!(SSTable::new(legacy[0].clone()).unwrap().has_bloom_filter());
+        assert!(!SSTable::new(legacy[2].clone()).unwrap().has_bloom_filter());
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        assert!(storage.verify().is_ok());
+        for i in 0..300 {
+            assert_eq!(
+                storage.get(key(i)).unwrap().is_some(),
+                i != 150,
+                "key {}",
+                i
+            );
+        }
+        drop(storage);
+
+        // Nothing left to migrate
+        assert_eq!(Storage::migrate_format(temp_dir.path()).unwrap(), 0);
+    }
+
+    /// Write `pairs` at `path` as the first release wrote tables, behind a
+    /// filter with every bit set
+    fn write_first_format(path: &Path, pairs: &[(Vec<u8>, &[u8])]) {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        bytes.extend_from_slice(&64u32.to_le_bytes());
+        bytes.extend_from_slice(&7u32.to_le_bytes());
+        bytes.extend_from_slice(&[0xff; 8]);
+        for (key, value) in pairs {
+            bytes.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(key);
+            bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(value);
+        }
+        fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_rewrites_first_release_directory() {
+        let temp_dir = TempDir::new().unwrap();
+        let old: Vec<_> = (0..100).map(|i| (key(i), &b"old"[..])).collect();
+        write_first_format(&temp_dir.path().join("L1_0.sst"), &old);
+        let new: Vec<_> = (50..60).map(|i| (key(i), &b"new"[..])).collect();
+        write_first_format(&temp_dir.path().join("L0_1.sst"), &new);
+        // And a single-file log holding a put of key 0
+        let mut log = vec![0u8];
+        log.extend_from_slice(&7u32.to_le_bytes());
+        log.extend_from_slice(&key(0));
+        log.extend_from_slice(&6u32.to_le_bytes());
+        log.extend_from_slice(b"logged");
+        fs::write(temp_dir.path().join("wal"), log).unwrap();
+
+        let err = Storage::new(temp_dir.path(), false).err().unwrap();
+        assert!(err.to_string().contains("migrate_format"), "{}", err);
+        assert_eq!(Storage::migrate_format(temp_dir.path()).unwrap(), 2);
+
+        let mut storage = Storage::new(temp_dir.path(), false).unwrap();
+        assert!(storage.verify().is_ok());
+        assert_eq!(storage.get(key(0)).unwrap(), Some(b"logged".to_vec()));
+        assert_eq!(storage.get(key(10)).unwrap(), Some(b"old".to_vec()));
+        assert_eq!(storage.get(key(55)).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(storage.get(key(100)).unwrap(), None);
+
+        // New writes are numbered above the migrated ones
+        storage.put(key(55), b"newest".to_vec()).unwrap();
+        storage.compact_all().unwrap();
+        assert_eq!(storage.get(key(55)).unwrap(), Some(b"newest".to_vec()));
+        assert_eq!(storage.get(key(60)).unwrap(), Some(b"old".to_vec()));
+    }
+
+    #[test]
+    fn test_migrate_needs_the_directory_to_itself() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        let err = Storage::migrate_format(temp_dir.path()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        drop(storage);
+        assert_eq!(Storage::migrate_format(temp_dir.path()).unwrap(), 0);
+
+        let missing = temp_dir.path().join("missing");
+        let err = Storage::migrate_format(&missing).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -1,143 +1,1016 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicUsize, Ordering};
-
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+
+mod archive;
+mod background;
+mod batch;
+mod bulk;
+mod checkpoint;
+mod describe;
+mod dump;
+mod events;
+#[cfg(feature = "arrow")]
+mod export;
+mod fifo;
+mod flush;
+mod identity;
+mod iter;
+mod jobs;
+mod levels;
+mod lifetime;
+mod lock;
+mod manifest;
+mod manual;
+mod merge;
+mod migrate;
+#[cfg(feature = "object-store")]
+mod objects;
+mod options;
+mod quota;
+mod reader;
+mod reconfigure;
+mod repair;
+mod scan;
+mod secondary;
+mod shared;
+mod slow;
+mod snapshot;
+mod stall;
+mod txn;
+mod verify;
+mod watch;
+pub use crate::bloom::BloomConfig;
+pub use crate::entry::MergeOperator;
+pub use crate::sstable::Compression;
+pub use crate::wal::SyncPolicy;
+pub use batch::{BatchOp, WriteBatch};
+pub use bulk::BulkLoader;
+pub use checkpoint::BackupManifest;
+pub(crate) use events::json_string;
+pub use events::{Event, EventKind};
+#[cfg(feature = "arrow")]
+pub use export::{ArrowExport, SchemaHint};
+pub use identity::Identity;
+pub use iter::DbIterator;
+pub use options::{Clock, CompactionStrategy, StorageOptions};
+pub use quota::QuotaExceeded;
+pub use reader::CheckpointReader;
+pub use reconfigure::OptionsDelta;
+pub use repair::{RepairReport, LOST_DIR};
+pub use scan::{LevelIter, Page, Scan};
+pub use secondary::Secondary;
+pub use shared::SharedStorage;
+pub use slow::{SlowRead, SlowReadTarget};
+pub use snapshot::Snapshot;
+pub use txn::{CommitError, Txn};
+pub use verify::VerifyReport;
+pub use watch::{ChangeEvent, WatchHandle};
+
+use crate::entry::{Entry, Version};
+use crate::error::StorageError;
+#[cfg(feature = "skiplist")]
+use crate::memtable::ConcurrentMemTable;
 use crate::memtable::MemTable;
-use crate::sstable::{CompactionManager, SSTable};
+use crate::sstable::{
+    advise_calls, BlockCache, CompactionManager, GcPolicy, Lookup, ReadPath, SSTable,
+    TableProperties,
+};
+use crate::stats::{LevelStats, SizeHistogram, SstFileInfo, StorageStats};
 use crate::wal::{Operation, WAL};
 use crate::{Key, Value};
+use background::Background;
+use events::EventLog;
+use levels::Levels;
+use lifetime::LifetimeStats;
+use manifest::{Manifest, ManifestEdit, ManifestState, MANIFEST_FILE};
+use secondary::Replayed;
+use slow::{ReadTrace, SlowReadLog};
+use watch::WatchList;
 
 const MEMTABLE_SIZE_THRESHOLD: usize = 512 * 1024; // 512KB (smaller for more frequent flushes)
 const COMPACTION_SIZE_THRESHOLD: usize = 1024 * 1024; // 1MB
 const LEVEL_MULTIPLIER: u32 = 4; // More aggressive compaction
 
-static PUT_COUNT: AtomicUsize = AtomicUsize::new(0);
-static TOTAL_BYTES: AtomicUsize = AtomicUsize::new(0);
+// Present while a `clear` is in progress; recovery completes the clear
+const CLEAR_MARKER: &str = "CLEAR";
 
 pub struct Storage {
-    memtable: MemTable,
-    wal: WAL,
-    sstables: HashMap<usize, Vec<SSTable>>, // level -> SSTables
+    // Taken by every write, and by flushes and compactions as they start
+    // and install, but never by reads
+    writer: Mutex<Writer>,
+    // Writers hold it exclusively only to apply a logged write or swap a
+    // memtable out
+    mem: RwLock<Memtables>,
+    // Replaced whole when a flush or compaction installs a table
+    levels: RwLock<Arc<Levels>>,
+    background: Arc<Background>,
     data_dir: PathBuf,
-    sstable_counter: u64,
     compaction_manager: CompactionManager,
+    options: StorageOptions,
+    snapshots: snapshot::SnapshotList,
+    skipped_reads: AtomicU64,
+    read_path: ReadPath,
+    block_cache: Option<Arc<BlockCache>>,
+    identity: Identity,
+    events: Arc<EventLog>,
+    slow_reads: Arc<SlowReadLog>,
+    lifetime: LifetimeStats,
     verbose: bool,
+    // Dropped last, so the directory stays locked until everything else is done
+    _lock: lock::DirLock,
 }
 
-impl Storage {
-    pub fn new<P: AsRef<Path>>(data_dir: P, verbose: bool) -> io::Result<Self> {
-        if verbose {
-            println!("Initializing storage at {:?}", data_dir.as_ref());
+/// What only writers touch, one at a time
+struct Writer {
+    manifest: Manifest,
+    wal: WAL,
+    sstable_counter: u64,
+    flush: Option<flush::Flush>,
+    compacting: Option<jobs::Compacting>,
+    written_key_sizes: SizeHistogram,
+    written_value_sizes: SizeHistogram,
+    watchers: WatchList,
+}
+
+/// The memtable taking writes. With the `skiplist` feature it's a skip
+/// list written through a shared reference, so reads don't wait for
+/// single writes; it prunes shadowed versions when frozen instead of as
+/// they're written.
+#[cfg(feature = "skiplist")]
+type ActiveMemTable = ConcurrentMemTable;
+#[cfg(not(feature = "skiplist"))]
+type ActiveMemTable = MemTable;
+
+/// Memtables locked so a write can apply to them: shared for a skip list,
+/// exclusive otherwise
+#[cfg(feature = "skiplist")]
+trait MemWrite: std::ops::Deref<Target = Memtables> {}
+#[cfg(feature = "skiplist")]
+impl<T: std::ops::Deref<Target = Memtables>> MemWrite for T {}
+#[cfg(not(feature = "skiplist"))]
+trait MemWrite: std::ops::DerefMut<Target = Memtables> {}
+#[cfg(not(feature = "skiplist"))]
+impl<T: std::ops::DerefMut<Target = Memtables>> MemWrite for T {}
+
+/// Writes not yet in a table
+struct Memtables {
+    memtable: ActiveMemTable,
+    immutable: Option<flush::Immutable>, // full memtable being flushed
+    // Sequence number of the last write, published once it's in the memtable
+    seq: AtomicU64,
+}
+
+impl Memtables {
+    fn seq(&self) -> u64 {
+        self.seq.load(Ordering::Acquire)
+    }
+
+    fn immutable(&self) -> Option<&MemTable> {
+        self.immutable
+            .as_ref()
+            .map(|immutable| immutable.memtable.as_ref())
+    }
+
+    /// Distinct keys buffered, counted once per memtable
+    fn len(&self) -> usize {
+        self.memtable.len() + self.immutable().map_or(0, MemTable::len)
+    }
+
+    /// Bytes buffered across both memtables
+    fn size(&self) -> usize {
+        self.memtable.size() + self.immutable().map_or(0, MemTable::size)
+    }
+
+    /// Newest buffered version of `key` written at or before `max_seq`
+    fn get_at(&self, key: &[u8], max_seq: u64) -> Option<Cow<'_, Entry>> {
+        #[cfg(feature = "skiplist")]
+        let active = self.memtable.get_at(key, max_seq).map(Cow::Owned);
+        #[cfg(not(feature = "skiplist"))]
+        let active = self.memtable.get_at(key, max_seq).map(Cow::Borrowed);
+        active.or_else(|| self.immutable()?.get_at(key, max_seq).map(Cow::Borrowed))
+    }
+
+    /// Every buffered version of `key`, newest first
+    fn versions(&self, key: &[u8]) -> Vec<Version> {
+        let mut versions = Cow::from(self.memtable.versions(key)).into_owned();
+        if let Some(immutable) = self.immutable() {
+            versions.extend_from_slice(immutable.versions(key));
         }
-        fs::create_dir_all(&data_dir)?;
+        versions
+    }
 
-        let wal_path = data_dir.as_ref().join("wal");
-        let mut wal = WAL::new(wal_path)?;
-        let mut memtable = MemTable::new();
+    /// Call `f` with each key in `[start, end)` (a missing `end` runs to the
+    /// last key) and its versions, the newer memtable first
+    // Only a skip list yields owned entries to borrow
+    #[cfg_attr(not(feature = "skiplist"), allow(clippy::needless_borrow))]
+    fn range_versions(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        mut f: impl FnMut(&Key, &[Version]),
+    ) {
+        for (key, versions) in self.memtable.range_versions(start, end) {
+            f(&key, &versions);
+        }
+        let immutable = self.immutable().into_iter();
+        for (key, versions) in immutable.flat_map(|m| m.range_versions(start, end)) {
+            f(key, versions);
+        }
+    }
+}
 
-        // Replay WAL if it exists
-        let mut replay_count = 0;
-        for (op, key, value) in wal.replay()? {
-            match op {
-                Operation::Put => {
-                    if let Some(value) = value {
-                        memtable.insert(key, value);
-                        replay_count += 1;
-                    }
-                }
-                Operation::Delete => {
-                    memtable.remove(&key);
-                    replay_count += 1;
+impl Storage {
+    /// Open the database in `data_dir` with default options, creating it if
+    /// needed and replaying any writes left in the WAL. Nothing is printed:
+    /// flushes and compactions are logged through the `log` crate at info
+    /// level, and `verbose` adds a debug-level trace of each operation.
+    ///
+    /// ```
+    /// use lsm_rust::Storage;
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// # let dir = tempfile::tempdir()?;
+    /// let db = Storage::new(dir.path(), false)?;
+    /// db.put(b"key", b"value")?;
+    /// drop(db);
+    ///
+    /// // Reopening recovers what was written
+    /// let db = Storage::new(dir.path(), false)?;
+    /// assert_eq!(db.get(b"key")?, Some(b"value".to_vec()));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn new<P: AsRef<Path>>(data_dir: P, verbose: bool) -> Result<Self, StorageError> {
+        Self::open_with_options(data_dir, StorageOptions::default().verbose(verbose))
+    }
+
+    pub fn open_with_options<P: AsRef<Path>>(
+        data_dir: P,
+        options: StorageOptions,
+    ) -> Result<Self, StorageError> {
+        options.validate()?;
+        let verbose = options.verbose;
+        debug!("Opening storage at {:?}", data_dir.as_ref());
+        let table_dirs = options.table_dirs(data_dir.as_ref());
+        for dir in &table_dirs {
+            fs::create_dir_all(dir)?;
+        }
+        let lock = lock::DirLock::acquire(data_dir.as_ref())?;
+        // Refuse directories this build can't safely touch before changing
+        // anything in them
+        let identity = identity::load_or_create(data_dir.as_ref(), &options.comparator_name)?;
+
+        // Finish a clear interrupted by a crash, and drop a marker that
+        // never became durable
+        let _ = fs::remove_file(data_dir.as_ref().join(format!("{}.tmp", CLEAR_MARKER)));
+        if data_dir.as_ref().join(CLEAR_MARKER).exists() {
+            info!("Completing interrupted clear");
+            let wal_dir = data_dir.as_ref().join("wal");
+            if wal_dir.exists() {
+                fs::remove_dir_all(wal_dir)?;
+            }
+            Self::finish_clear(data_dir.as_ref(), &table_dirs)?;
+        }
+        let committed = bulk::recover(data_dir.as_ref(), &options)?;
+
+        // The manifest says which tables are live; a directory without one
+        // predates it, so its tables are found by name instead. Tables in an
+        // object store outlive the directory, and so does the copy of the
+        // manifest kept with them.
+        let stored = Manifest::load(data_dir.as_ref())?;
+        #[cfg(feature = "object-store")]
+        let stored = match (stored, &options.object_fs) {
+            (None, Some(objects)) => Manifest::load_mirror(objects)?,
+            (stored, _) => stored,
+        };
+        let mut state = match stored {
+            Some(state) => state,
+            None => Self::list_tables(&table_dirs)?,
+        };
+        // A bulk load that committed just before a crash may not be listed
+        for path in committed {
+            if let Some((level, seq)) = parse_table_name(&path) {
+                if !state.tables.contains_key(&(level, seq)) {
+                    state.apply(&ManifestEdit::AddFile {
+                        level,
+                        seq,
+                        path: path.file_name().unwrap().into(),
+                        size: fs::metadata(&path)?.len(),
+                    });
                 }
             }
         }
-        if verbose && replay_count > 0 {
-            println!("Replayed {} operations from WAL", replay_count);
-        }
-
-        // Load existing SSTables
-        let mut sstables: HashMap<usize, Vec<SSTable>> = HashMap::new();
-        let mut counter = 0;
-        let mut total_sstables = 0;
-
-        for entry in fs::read_dir(&data_dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("sst") {
-                if let Some(filename) = path.file_stem().and_then(|s| s.to_str()) {
-                    // Parse level and sequence number from filename (L{level}_{seq}.sst)
-                    if let Some(level_str) = filename.strip_prefix('L') {
-                        if let Some((level, seq_str)) = level_str.split_once('_') {
-                            if let (Ok(level), Ok(seq)) =
-                                (level.parse::<usize>(), seq_str.parse::<u64>())
-                            {
-                                counter = counter.max(seq + 1);
-                                sstables.entry(level).or_default().push(SSTable::new(path)?);
-                                total_sstables += 1;
-                            }
-                        }
-                    }
+        let manifest = Manifest::create(data_dir.as_ref(), &state)?;
+
+        // Load the live tables from the data directory and any level dirs
+        let block_cache = (options.block_cache_size > 0)
+            .then(|| Arc::new(BlockCache::new(options.block_cache_size)));
+        let mut sstables: HashMap<usize, Vec<Arc<SSTable>>> = HashMap::new();
+        let mut live = HashSet::new();
+        for (&(level, _), (name, _)) in &state.tables {
+            let path = options.table_dir(data_dir.as_ref(), level).join(name);
+            let table = Self::open_live(&options, path.clone())?;
+            sstables
+                .entry(level)
+                .or_default()
+                .push(Arc::new(table.with_cache(block_cache.clone())));
+            live.insert(path);
+        }
+        #[cfg(feature = "object-store")]
+        let manifest = match &options.object_fs {
+            Some(objects) => objects::attach(objects, manifest, &state, data_dir.as_ref())?,
+            None => manifest,
+        };
+        let counter = state.counter;
+        let total_sstables = live.len();
+
+        // Any other table is garbage: the output of a flush or compaction
+        // that never finished, or an input whose removal a crash cut short.
+        // So is a `.tmp` table or `.data` scratch file a crash caught
+        // mid-write.
+        for dir in &table_dirs {
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                let garbage = match path.extension().and_then(|s| s.to_str()) {
+                    Some("sst") => !live.contains(&path),
+                    Some("tmp" | "data") => true,
+                    _ => false,
+                };
+                if garbage && path.is_file() {
+                    info!("Removing garbage file {:?}", path);
+                    fs::remove_file(path)?;
                 }
             }
         }
 
-        if verbose {
-            println!(
-                "Loaded {} SSTables across {} levels",
-                total_sstables,
-                sstables.len()
-            );
-            for (level, tables) in &sstables {
-                let total_size: usize = tables.iter().map(|t| t.size()).sum();
-                println!(
-                    "  Level {}: {} files, {} bytes total",
-                    level,
-                    tables.len(),
-                    total_size
-                );
-            }
+        let sorted_levels = sstables
+            .iter_mut()
+            .filter_map(|(&level, tables)| levels::arrange(level, tables).then_some(level))
+            .collect();
+
+        info!(
+            "Loaded {} SSTables across {} levels",
+            total_sstables,
+            sstables.len()
+        );
+        for (level, tables) in &sstables {
+            let bytes: usize = tables.iter().map(|t| t.size()).sum();
+            debug!(level, files = tables.len(), bytes; "Loaded level {}", level);
+        }
+
+        // Segments the manifest marks as flushed are removed, not replayed
+        let wal_path = data_dir.as_ref().join("wal");
+        let mut wal = WAL::open(wal_path, state.log_number)?;
+        wal.set_sync_policy(options.wal_sync);
+        wal.set_segment_size(options.wal_segment_size);
+        let mut memtable = MemTable::new();
+
+        // Sequence numbers continue from the newest write already in a
+        // table, or flushed before compaction dropped it
+        let seq = sstables
+            .values()
+            .flatten()
+            .map(|table| table.properties().max_seq)
+            .max()
+            .unwrap_or(0)
+            .max(state.last_seq);
+
+        // Replay WAL if it exists, skipping records already flushed
+        let mut replayed = Replayed {
+            seq,
+            applied: state.last_seq,
+        };
+        let replay_count =
+            secondary::replay(&mut memtable, &mut replayed, wal.replay()?, options.now());
+        let seq = replayed.seq;
+        if replay_count > 0 {
+            info!("Replayed {} operations from WAL", replay_count);
         }
+        #[cfg(feature = "skiplist")]
+        let memtable = ConcurrentMemTable::from(memtable);
 
-        let compaction_manager =
-            CompactionManager::new(LEVEL_MULTIPLIER, COMPACTION_SIZE_THRESHOLD);
+        let read_path = Self::read_path(&options);
+        let events = Arc::new(EventLog::new(data_dir.as_ref()));
+        let slow_reads = Arc::new(SlowReadLog::new(&options, &events));
+        let compaction_manager = Self::compaction_manager(&options);
 
         Ok(Storage {
-            memtable,
-            wal,
-            sstables,
+            writer: Mutex::new(Writer {
+                manifest,
+                wal,
+                sstable_counter: counter,
+                flush: None,
+                compacting: None,
+                written_key_sizes: SizeHistogram::new(),
+                written_value_sizes: SizeHistogram::new(),
+                watchers: WatchList::default(),
+            }),
+            mem: RwLock::new(Memtables {
+                memtable,
+                immutable: None,
+                seq: AtomicU64::new(seq),
+            }),
+            levels: RwLock::new(Arc::new(Levels {
+                sstables,
+                sorted_levels,
+            })),
+            background: Default::default(),
             data_dir: data_dir.as_ref().to_path_buf(),
-            sstable_counter: counter,
             compaction_manager,
+            options,
+            snapshots: Default::default(),
+            skipped_reads: AtomicU64::new(0),
+            read_path,
+            block_cache,
+            identity,
+            events,
+            slow_reads,
+            lifetime: LifetimeStats::new(data_dir.as_ref()),
             verbose,
+            _lock: lock,
         })
     }
 
-    pub fn get(&self, key: &Key) -> io::Result<Option<Value>> {
+    /// Tables found by name in `table_dirs`, for a directory written before
+    /// manifests existed, less any a compaction output there replaced
+    fn list_tables(table_dirs: &[PathBuf]) -> io::Result<ManifestState> {
+        let mut state = ManifestState::default();
+        let mut replaced: HashSet<u64> = HashSet::new();
+        for dir in table_dirs {
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("sst") {
+                    if let Some((level, seq)) = parse_table_name(&path) {
+                        // A table that can't be read is left for open or
+                        // repair to report
+                        if let Ok(table) = SSTable::open_legacy(path.clone()) {
+                            replaced.extend(&table.properties().replaces);
+                        }
+                        state.apply(&ManifestEdit::AddFile {
+                            level,
+                            seq,
+                            path: path.file_name().unwrap().into(),
+                            size: fs::metadata(&path)?.len(),
+                        });
+                    }
+                }
+            }
+        }
+        // Compaction inputs are removed only after the output is written,
+        // so a crash can leave both; the output names the tables it replaced
+        state.tables.retain(|&(_, seq), _| !replaced.contains(&seq));
+        Ok(state)
+    }
+
+    /// Open `path`, a table the manifest lists as live
+    #[cfg_attr(not(feature = "object-store"), allow(unused_variables))]
+    fn open_live(options: &StorageOptions, path: PathBuf) -> io::Result<SSTable> {
+        #[cfg(feature = "object-store")]
+        if let Some(objects) = &options.object_fs {
+            return objects::open_table(objects, path);
+        }
+        if !path.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Table {:?} listed in {} is missing", path, MANIFEST_FILE),
+            ));
+        }
+        Ok(SSTable::new(path)?)
+    }
+
+    fn compaction_manager(options: &StorageOptions) -> CompactionManager {
+        CompactionManager::new(options.level_multiplier, options.level_size_base)
+            .fadvise(options.fadvise)
+            .l0_files(options.l0_compaction_files)
+    }
+
+    /// Set up the io_uring for point reads, falling back to standard reads
+    /// if the kernel refuses one
+    #[cfg(all(feature = "iouring", target_os = "linux"))]
+    fn read_path(options: &StorageOptions) -> ReadPath {
+        if options.io_uring_entries == 0 {
+            return ReadPath::Std;
+        }
+        match crate::sstable::Ring::new(options.io_uring_entries) {
+            Ok(ring) => ReadPath::Uring(Arc::new(ring)),
+            Err(e) => {
+                warn!("io_uring unavailable, using standard reads: {}", e);
+                ReadPath::Std
+            }
+        }
+    }
+
+    #[cfg(not(all(feature = "iouring", target_os = "linux")))]
+    fn read_path(_options: &StorageOptions) -> ReadPath {
+        ReadPath::Std
+    }
+
+    /// The newest value of `key`, or `None` if it was never written or its
+    /// latest write is a delete
+    ///
+    /// ```
+    /// # use lsm_rust::Storage;
+    /// # fn main() -> std::io::Result<()> {
+    /// # let dir = tempfile::tempdir()?;
+    /// let db = Storage::new(dir.path(), false)?;
+    /// assert_eq!(db.get(b"missing")?, None);
+    ///
+    /// db.put(b"key", b"v1")?;
+    /// db.put(b"key", b"v2")?;
+    /// db.flush()?;
+    /// assert_eq!(db.get(b"key")?, Some(b"v2".to_vec()));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Value>, StorageError> {
+        let mut value = Vec::new();
+        Ok(self.get_into(key.as_ref(), &mut value)?.then_some(value))
+    }
+
+    /// Look up `key`, copying its value into `out` so that callers issuing
+    /// many reads can reuse a single buffer. Returns whether the key was found.
+    pub fn get_into(&self, key: &[u8], out: &mut Vec<u8>) -> Result<bool, StorageError> {
+        Ok(self.read_into(key, u64::MAX, out)?)
+    }
+
+    /// What the data directory recorded about itself when it was created
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+
+    /// Take a point-in-time view of the database; see [`Snapshot`]
+    pub fn snapshot(&self) -> Snapshot {
+        // Registered under the memtables' lock, so no write lands after
+        // the snapshot's sequence number without seeing it
+        let mem = self.mem();
+        Snapshot::new(mem.seq(), &self.snapshots)
+    }
+
+    /// Look up `key` as it was when `snapshot`, taken from this storage,
+    /// was taken
+    pub fn get_at(&self, key: &[u8], snapshot: &Snapshot) -> Result<Option<Value>, StorageError> {
+        let seq = snapshot.seq_in(&self.snapshots)?;
+        let mut value = Vec::new();
+        Ok(self.read_into(key, seq, &mut value)?.then_some(value))
+    }
+
+    /// Live key/value pairs with keys in `[start, end)`, in ascending order.
+    /// An empty `end` scans to the last key.
+    pub fn scan(&self, start: &[u8], end: &[u8]) -> Result<Scan, StorageError> {
+        Ok(self.scan_to(start, end, u64::MAX, false)?)
+    }
+
+    /// [`scan`](Storage::scan) in descending key order, such as for pages
+    /// of the latest items first
+    pub fn scan_rev(&self, start: &[u8], end: &[u8]) -> Result<Scan, StorageError> {
+        Ok(self.scan_to(start, end, u64::MAX, true)?)
+    }
+
+    /// Scan `[start, end)` as it was when `snapshot`, taken from this
+    /// storage, was taken
+    pub fn scan_at(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        snapshot: &Snapshot,
+    ) -> Result<Scan, StorageError> {
+        let seq = snapshot.seq_in(&self.snapshots)?;
+        Ok(self.scan_to(start, end, seq, false)?)
+    }
+
+    fn scan_to(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        max_seq: u64,
+        descending: bool,
+    ) -> io::Result<Scan> {
+        let end = (!end.is_empty()).then(|| end.to_vec());
+        let (memtable, tables) = self.range_sources(start, end.as_deref());
+        let slow = self
+            .slow_reads
+            .start()
+            .map(|started| (self.slow_reads.clone(), started));
+        let read_ahead = self.options.read_ahead;
+        let now = self.options.now();
+        let merge = self.options.merge_operator.clone();
+        Scan::new(
+            memtable, tables, start, end, max_seq, now, merge, read_ahead, slow, descending,
+        )
+    }
+
+    /// Number of live keys in `[start, end)`, as `scan` would yield them
+    /// (an empty `end` runs to the last key), without reading any values
+    pub fn count_range(&self, start: &[u8], end: &[u8]) -> Result<u64, StorageError> {
+        let end = (!end.is_empty()).then_some(end);
+        let (memtable, tables) = self.range_sources(start, end);
+        let now = self.options.now();
+        Ok(scan::count_live(
+            live_kinds(memtable, now),
+            tables,
+            start,
+            end,
+            now,
+        )?)
+    }
+
+    /// The buffered versions and the tables that may hold keys in
+    /// `[start, end)`, as of one moment
+    fn range_sources(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+    ) -> (Vec<(Key, Version)>, Vec<Arc<SSTable>>) {
+        let (mem, levels) = self.current();
+        let memtable = buffered_range(&mem, start, end);
+        (memtable, levels.tables_for_range(start, end))
+    }
+
+    /// The memtables and the tables beneath them as of now. The tables are
+    /// taken under the memtables' lock, so no flush moves writes from one
+    /// to the other in between.
+    fn current(&self) -> (RwLockReadGuard<'_, Memtables>, Arc<Levels>) {
+        let mem = self.mem();
+        let levels = self.levels();
+        (mem, levels)
+    }
+
+    fn mem(&self) -> RwLockReadGuard<'_, Memtables> {
+        self.mem.read().unwrap()
+    }
+
+    fn mem_mut(&self) -> RwLockWriteGuard<'_, Memtables> {
+        self.mem.write().unwrap()
+    }
+
+    /// The memtables, locked for a single write to apply to them
+    #[cfg(feature = "skiplist")]
+    fn mem_for_write(&self) -> RwLockReadGuard<'_, Memtables> {
+        self.mem()
+    }
+
+    #[cfg(not(feature = "skiplist"))]
+    fn mem_for_write(&self) -> RwLockWriteGuard<'_, Memtables> {
+        self.mem_mut()
+    }
+
+    /// Add `version` of `key` to the memtable taking writes and publish its
+    /// sequence number
+    fn buffer(&self, mem: &mut impl MemWrite, key: Key, version: Version) {
+        let seq = version.seq;
+        #[cfg(feature = "skiplist")]
+        mem.memtable.push(key, version);
+        #[cfg(not(feature = "skiplist"))]
+        mem.memtable
+            .push(key, version, &snapshot::live_seqs(&self.snapshots));
+        mem.seq.store(seq, Ordering::Release);
+    }
+
+    /// The live tables as of now
+    fn levels(&self) -> Arc<Levels> {
+        self.levels.read().unwrap().clone()
+    }
+
+    /// Change the live tables. Reads holding the current set keep it, and
+    /// it's copied first if any do.
+    fn edit_levels<T>(&self, edit: impl FnOnce(&mut Levels) -> T) -> T {
+        edit(Arc::make_mut(&mut self.levels.write().unwrap()))
+    }
+
+    fn writer(&self) -> MutexGuard<'_, Writer> {
+        self.writer.lock().unwrap()
+    }
+
+    /// Rough number of keys, for capacity planning: distinct keys in the
+    /// memtables plus the entries each table records in its properties, so
+    /// no data is read. Keys with versions in more than one place, deleted
+    /// ones included, are counted once per place until compaction merges
+    /// them.
+    pub fn approximate_len(&self) -> u64 {
+        let (mem, levels) = self.current();
+        let tables: u64 = levels
+            .sstables
+            .values()
+            .flatten()
+            .map(|table| table.properties().entry_count)
+            .sum();
+        tables + mem.len() as u64
+    }
+
+    /// Rough bytes occupied by keys in `[start, end)` (an empty `end` runs to
+    /// the last key), for planning splits. Tables are estimated from their
+    /// key ranges and sizes alone, so no data is read; buffered writes count
+    /// their key and value bytes.
+    pub fn approximate_size_of_range(&self, start: &[u8], end: &[u8]) -> u64 {
+        let end = (!end.is_empty()).then_some(end);
+        let (mem, levels) = self.current();
+        let tables: u64 = levels
+            .sstables
+            .values()
+            .flatten()
+            .map(|table| table.approximate_size_of_range(start, end))
+            .sum();
+        let mut memtable = 0;
+        mem.range_versions(start, end, |key, versions| {
+            memtable += versions
+                .iter()
+                .map(|version| key.len() + version.entry.value().map_or(0, Vec::len))
+                .sum::<usize>();
+        });
+        tables + memtable as u64
+    }
+
+    /// One page of at most `limit` live pairs from `[start, end)`, resuming
+    /// strictly after `start_after`. Returns the page along with the token to
+    /// pass as `start_after` for the next one: its last key, or `None` once
+    /// the range is exhausted.
+    ///
+    /// Each page reads the database as of the call; writes between calls
+    /// show up in later pages only if their keys sort after the token.
+    pub fn scan_page(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        limit: usize,
+        start_after: Option<Key>,
+    ) -> Result<Page, StorageError> {
+        if limit == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Page limit must be at least 1",
+            )
+            .into());
+        }
+        // The smallest key sorting after the token is the token plus a zero byte
+        let resume = start_after.map(|mut key| {
+            key.push(0);
+            key
+        });
+        let start = resume.as_deref().map_or(start, |resume| resume.max(start));
+
+        // Fetch one extra pair to tell whether anything follows this page
+        let mut page = self
+            .scan(start, end)?
+            .take(limit + 1)
+            .collect::<io::Result<Vec<_>>>()?;
+        let token = if page.len() > limit {
+            page.truncate(limit);
+            page.last().map(|(key, _)| key.clone())
+        } else {
+            None
+        };
+        Ok((page, token))
+    }
+
+    /// A seekable cursor over the current contents, which keeps reading
+    /// them as they are now through later writes, flushes and compactions;
+    /// see [`DbIterator`]
+    pub fn cursor(&self) -> Result<DbIterator, StorageError> {
+        let (mem, levels) = self.current();
+        Ok(DbIterator::new(
+            buffered_range(&mem, b"", None),
+            levels.tables_for_range(b"", None),
+            mem.seq(),
+            self.options.now(),
+            self.options.merge_operator.clone(),
+            self.options.read_ahead,
+        ))
+    }
+
+    /// Same as [`cursor`](Storage::cursor)
+    pub fn iter(&self) -> Result<DbIterator, StorageError> {
+        self.cursor()
+    }
+
+    /// Metadata for every SSTable at `level`, from oldest to newest, or by
+    /// smallest key once the level's tables no longer overlap
+    pub fn level_files(&self, level: usize) -> Vec<SstFileInfo> {
+        let levels = self.levels();
+        let tables = levels
+            .sstables
+            .get(&level)
+            .map_or(&[][..], |t| t.as_slice());
+        tables
+            .iter()
+            .map(|table| SstFileInfo {
+                path: table.get_path().clone(),
+                file_number: parse_table_name(table.get_path()).map_or(0, |(_, seq)| seq),
+                size: table.size(),
+                key_range: table
+                    .key_range()
+                    .map(|(min, max)| (min.to_vec(), max.to_vec())),
+                entry_count: table.properties().entry_count,
+            })
+            .collect()
+    }
+
+    /// Raw view of exactly what is stored at `level`: every version in its
+    /// tables, tombstones and stale values included, in key order and newest
+    /// first within a key. Nothing from other levels shadows or is merged in.
+    pub fn iter_level(&self, level: usize) -> Result<LevelIter, StorageError> {
+        let tables = self
+            .levels()
+            .sstables
+            .get(&level)
+            .cloned()
+            .unwrap_or_default();
+        Ok(LevelIter::new(tables, self.options.read_ahead)?)
+    }
+
+    /// Look up many keys at once, returning their values in the order asked.
+    ///
+    /// The keys are sorted and each table is read at most once, in a single
+    /// forward pass that resolves every key it holds. Keys stop being looked
+    /// for as soon as a newer table resolves them, including by a tombstone.
+    pub fn multi_get(&self, keys: &[Key]) -> Result<Vec<Option<Value>>, StorageError> {
+        let started = self.slow_reads.start();
+        let mut trace = ReadTrace::default();
+        let result = self.multi_get_traced(keys, &mut trace);
+        self.lifetime.add_gets(keys.len(), 0, 0);
+        if let Some(started) = started {
+            let target = || SlowReadTarget::MultiGet { keys: keys.len() };
+            self.slow_reads.finish(started, target, trace);
+        }
+        Ok(result?)
+    }
+
+    fn multi_get_traced(
+        &self,
+        keys: &[Key],
+        trace: &mut ReadTrace,
+    ) -> io::Result<Vec<Option<Value>>> {
+        let mut results = vec![None; keys.len()];
+
+        // Distinct keys in sorted order, each with the positions that asked for it
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        order.sort_by(|&a, &b| keys[a].cmp(&keys[b]));
+        let mut pending: Vec<(&[u8], Vec<usize>)> = Vec::new();
+        for i in order {
+            match pending.last_mut() {
+                Some((key, positions)) if *key == keys[i].as_slice() => positions.push(i),
+                _ => pending.push((&keys[i], vec![i])),
+            }
+        }
+
+        let now = self.options.now();
+        // Keys whose newest version is a merge operand, read on their own
+        // once the rest are resolved
+        let mut merged: Vec<(Key, Vec<usize>)> = Vec::new();
+        let mut resolve = |key: &[u8], positions: &[usize], entry: Option<&Entry>| match entry {
+            Some(Entry::Merge(_)) => {
+                merged.push((key.to_vec(), positions.to_vec()));
+                true
+            }
+            Some(entry) => {
+                if let Some(value) = entry.live_value(now) {
+                    for &i in positions {
+                        results[i] = Some(value.clone());
+                    }
+                }
+                true
+            }
+            None => false,
+        };
+
+        let (mem, levels) = self.current();
+        pending.retain(|(key, positions)| {
+            !resolve(key, positions, mem.get_at(key, u64::MAX).as_deref())
+        });
+        drop(mem);
+
+        let max_level = levels.max_level();
+        'levels: for tables in (0..=max_level).filter_map(|level| levels.sstables.get(&level)) {
+            for sstable in tables.iter().rev() {
+                if pending.is_empty() {
+                    break 'levels;
+                }
+                let lookup: Vec<&[u8]> = pending.iter().map(|(key, _)| *key).collect();
+                let (passes, bytes) = (sstable.data_passes(), sstable.lookup_bytes());
+                let found = sstable.multi_get_with(&lookup, u64::MAX, &self.read_path);
+                trace.tables_probed += (sstable.data_passes() - passes) as usize;
+                trace.bytes_read += sstable.lookup_bytes() - bytes;
+                let found = match found {
+                    Ok(found) => found,
+                    Err(e) if self.options.best_effort_reads => {
+                        self.skipped_reads.fetch_add(1, Ordering::Relaxed);
+                        warn!(
+                            "Skipping unreadable SSTable {:?}: {}",
+                            sstable.get_path(),
+                            e
+                        );
+                        continue;
+                    }
+                    Err(e) => return Err(table_read_error(sstable, e.into())),
+                };
+                let mut found = found.into_iter();
+                pending.retain(|(key, positions)| {
+                    !resolve(key, positions, found.next().unwrap().as_ref())
+                });
+            }
+        }
+
+        for (key, positions) in merged {
+            let mut value = Vec::new();
+            if self.read_merged(&key, u64::MAX, &mut value)? {
+                for i in positions {
+                    results[i] = Some(value.clone());
+                }
+            }
+        }
+        Ok(results)
+    }
+
+    /// Find the newest version of `key` written at or before `max_seq`
+    fn read_into(&self, key: &[u8], max_seq: u64, out: &mut Vec<u8>) -> io::Result<bool> {
+        let started = self.slow_reads.start();
+        let mut trace = ReadTrace::default();
+        let result = self.read_traced(key, max_seq, out, &mut trace);
+        self.lifetime
+            .add_gets(1, trace.bloom_negatives, trace.bloom_false_positives);
+        if let Some(started) = started {
+            let target = || SlowReadTarget::Get { key: key.to_vec() };
+            self.slow_reads.finish(started, target, trace);
+        }
+        result
+    }
+
+    fn read_traced(
+        &self,
+        key: &[u8],
+        max_seq: u64,
+        out: &mut Vec<u8>,
+        trace: &mut ReadTrace,
+    ) -> io::Result<bool> {
         if self.verbose {
-            println!("GET {:?}", String::from_utf8_lossy(key));
+            debug!("GET {:?}", String::from_utf8_lossy(key));
         }
 
-        // First check memtable
-        if let Some(value) = self.memtable.get(key) {
-            if self.verbose {
-                println!("  Found in memtable");
+        // Values past their expiry read as deleted
+        let now = self.options.now();
+
+        // First check the memtables, the one taking writes first
+        let (mem, levels) = self.current();
+        let merged = match mem.get_at(key, max_seq).as_deref() {
+            Some(entry @ (Entry::Value(_) | Entry::Expiring { .. })) => {
+                let Some(value) = entry.live_value(now) else {
+                    if self.verbose {
+                        debug!("  Expired in memtable");
+                    }
+                    return Ok(false);
+                };
+                if self.verbose {
+                    debug!("  Found in memtable");
+                }
+                out.clear();
+                out.extend_from_slice(value);
+                return Ok(true);
+            }
+            Some(Entry::Tombstone { .. }) => {
+                if self.verbose {
+                    debug!("  Deleted in memtable");
+                }
+                return Ok(false);
             }
-            return Ok(Some(value.clone()));
+            Some(Entry::Merge(_)) => true,
+            None => false,
+        };
+        // Tables are read without holding up writes
+        drop(mem);
+        if merged {
+            return self.read_merged(key, max_seq, out);
         }
 
         // Then check SSTables from newest to oldest, level by level
-        for level in 0..=self.sstables.keys().max().copied().unwrap_or(0) {
-            if let Some(tables) = self.sstables.get(&level) {
+        for level in 0..=levels.max_level() {
+            if levels.sstables.contains_key(&level) {
+                // A level sorted by key narrows to the one table covering it
+                let tables = levels.tables_for_key(level, key);
                 if self.verbose {
-                    println!("  Searching level {} ({} files)", level, tables.len());
+                    debug!("  Searching level {} ({} files)", level, tables.len());
                 }
                 for (idx, sstable) in tables.iter().rev().enumerate() {
+                    if !sstable.covers_key(key) {
+                        if self.verbose {
+                            debug!(
+                                "  Skipped SSTable {} at level {} (outside key range)",
+                                idx, level
+                            );
+                        }
+                        continue;
+                    }
                     // Use bloom filter to avoid unnecessary disk reads
                     if !sstable.might_contain_key(key) {
+                        trace.bloom_negatives += 1;
                         if self.verbose {
-                            println!(
+                            debug!(
                                 "  Skipped SSTable {} at level {} (Bloom filter negative)",
                                 idx, level
                             );
@@ -146,215 +1019,702 @@ impl Storage {
                     }
 
                     // Key might be in this SSTable, do a full check
-                    if let Ok(Some(value)) = sstable.get(key) {
-                        if self.verbose {
-                            println!("  Found in SSTable {} at level {}", idx, level);
+                    let bytes = sstable.lookup_bytes();
+                    let lookup = sstable.lookup_into_with(key, max_seq, out, &self.read_path);
+                    trace.tables_probed += 1;
+                    trace.bytes_read += sstable.lookup_bytes() - bytes;
+                    match lookup {
+                        Ok(Lookup::Found) => {
+                            if self.verbose {
+                                debug!("  Found in SSTable {} at level {}", idx, level);
+                            }
+                            return Ok(true);
+                        }
+                        Ok(Lookup::Expiring(expires_at)) => {
+                            if self.verbose {
+                                debug!("  Found in SSTable {} at level {}", idx, level);
+                            }
+                            return Ok(expires_at > now);
+                        }
+                        Ok(Lookup::Merge) => {
+                            if self.verbose {
+                                debug!("  Merge operand in SSTable {} at level {}", idx, level);
+                            }
+                            return self.read_merged(key, max_seq, out);
+                        }
+                        Ok(Lookup::Deleted) => {
+                            if self.verbose {
+                                debug!("  Deleted in SSTable {} at level {}", idx, level);
+                            }
+                            return Ok(false);
+                        }
+                        Ok(Lookup::Missing) => {
+                            if sstable.has_bloom_filter() {
+                                trace.bloom_false_positives += 1;
+                            }
                         }
-                        return Ok(Some(value));
+                        Err(e) if self.options.best_effort_reads => {
+                            self.skipped_reads.fetch_add(1, Ordering::Relaxed);
+                            warn!(
+                                "Skipping unreadable SSTable {:?}: {}",
+                                sstable.get_path(),
+                                e
+                            );
+                        }
+                        Err(e) => return Err(table_read_error(sstable, e.into())),
                     }
                 }
             }
         }
 
         if self.verbose {
-            println!("  Key not found");
+            debug!("  Key not found");
         }
-        Ok(None)
+        Ok(false)
     }
 
-    pub fn put(&mut self, key: Key, value: Value) -> io::Result<()> {
+    /// Write `value` under `key`, logging it to the WAL before it becomes
+    /// visible. The memtable is flushed to a new L0 table once it reaches
+    /// `memtable_size`.
+    ///
+    /// ```
+    /// # use lsm_rust::Storage;
+    /// # fn main() -> std::io::Result<()> {
+    /// # let dir = tempfile::tempdir()?;
+    /// let db = Storage::new(dir.path(), false)?;
+    /// db.put(b"city", b"Lisbon")?;
+    /// assert_eq!(db.get(b"city")?, Some(b"Lisbon".to_vec()));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn put(&self, key: impl Into<Key>, value: impl Into<Value>) -> Result<(), StorageError> {
+        let (key, value) = (key.into(), value.into());
+        self.wait_for_room(key.len() + value.len());
+        let mut writer = self.writer();
         if self.verbose {
-            let count = PUT_COUNT.fetch_add(1, Ordering::Relaxed) + 1;
-            let bytes = TOTAL_BYTES.fetch_add(key.len() + value.len(), Ordering::Relaxed)
-                + key.len()
-                + value.len();
-
-            if count % 1000 == 0 {
-                println!(
-                    "\nProgress: {} operations ({:.2} MB written)",
+            let count = writer.written_key_sizes.count() + 1;
+            let bytes = writer.written_key_sizes.total()
+                + writer.written_value_sizes.total()
+                + (key.len() + value.len()) as u64;
+
+            if count.is_multiple_of(1000) {
+                debug!(
+                    "Progress: {} operations ({:.2} MB written, {:.2} KB average)",
                     count,
-                    bytes as f64 / 1_048_576.0
-                );
-                println!(
-                    "Average value size: {:.2} KB",
+                    bytes as f64 / 1_048_576.0,
                     (bytes as f64 / count as f64) / 1024.0
                 );
             }
         }
 
-        // Write to WAL first
-        self.wal.append(Operation::Put, &key, Some(&value))?;
+        self.check_quota(&writer)?;
+        self.throttle_writes(&mut writer)?;
 
-        // Then update memtable
-        self.memtable.insert(key, value);
+        // Write to WAL first, then update memtable
+        let seq = self.mem().seq() + 1;
+        writer.wal.append(seq, Operation::Put, &key, Some(&value))?;
+        self.apply_put(&mut writer, &mut self.mem_for_write(), key, value, None);
 
         // Check if we need to flush memtable to SSTable
-        let memtable_size = self.memtable.size();
-        if memtable_size >= MEMTABLE_SIZE_THRESHOLD {
-            if self.verbose {
-                println!("\n=== Memtable Flush ===");
-                println!(
-                    "Size: {:.2} MB (threshold: {:.2} MB)",
-                    memtable_size as f64 / 1_048_576.0,
-                    MEMTABLE_SIZE_THRESHOLD as f64 / 1_048_576.0
-                );
-            }
-            self.flush_memtable()?;
-        }
-
-        Ok(())
+        Ok(self.maybe_flush(&mut writer)?)
     }
 
-    pub fn delete(&mut self, key: &Key) -> io::Result<()> {
-        if self.verbose {
-            println!("DELETE {:?}", String::from_utf8_lossy(key));
-        }
-
-        // Write to WAL first
-        self.wal.append(Operation::Delete, key, None)?;
-
-        // Then update memtable
-        self.memtable.remove(key);
-
-        Ok(())
+    /// Write `value` under `key` so that it reads as deleted once `ttl` has
+    /// passed by the configured clock. Compaction then drops it as it would
+    /// a tombstone. Batches can't carry a TTL.
+    pub fn put_with_ttl(
+        &self,
+        key: impl Into<Key>,
+        value: impl Into<Value>,
+        ttl: Duration,
+    ) -> Result<(), StorageError> {
+        let (key, value) = (key.into(), value.into());
+        self.wait_for_room(key.len() + value.len());
+        let mut writer = self.writer();
+        self.check_quota(&writer)?;
+        self.throttle_writes(&mut writer)?;
+
+        let expires_at = self.options.now().saturating_add(ttl.as_millis() as u64);
+        let seq = self.mem().seq() + 1;
+        writer.wal.append_expiring(seq, &key, &value, expires_at)?;
+        self.apply_put(
+            &mut writer,
+            &mut self.mem_for_write(),
+            key,
+            value,
+            Some(expires_at),
+        );
+        Ok(self.maybe_flush(&mut writer)?)
     }
 
-    fn flush_memtable(&mut self) -> io::Result<()> {
-        if self.memtable.is_empty() {
-            return Ok(());
+    /// Add a logged put to the memtable, expiring at `expires_at` if given,
+    /// and tell watchers
+    fn apply_put(
+        &self,
+        writer: &mut Writer,
+        mem: &mut impl MemWrite,
+        key: Key,
+        value: Value,
+        expires_at: Option<u64>,
+    ) {
+        self.lifetime.add_writes(1, 0);
+        writer.written_key_sizes.record(key.len());
+        writer.written_value_sizes.record(value.len());
+        let seq = mem.seq() + 1;
+        let change = writer
+            .watchers
+            .wants(&key)
+            .then(|| (key.clone(), value.clone()));
+        let entry = match expires_at {
+            Some(expires_at) => Entry::Expiring { value, expires_at },
+            None => Entry::Value(value),
+        };
+        self.buffer(mem, key, Version::new(seq, entry));
+        if let Some((key, value)) = change {
+            writer.watchers.publish(ChangeEvent {
+                key,
+                op: Operation::Put,
+                value: Some(value),
+                seq,
+            });
         }
+    }
 
+    /// Delete `key` by writing a tombstone, which hides every older value
+    /// in the memtable and the tables beneath it
+    ///
+    /// ```
+    /// # use lsm_rust::Storage;
+    /// # fn main() -> std::io::Result<()> {
+    /// # let dir = tempfile::tempdir()?;
+    /// let db = Storage::new(dir.path(), false)?;
+    /// db.put(b"age", b"30")?;
+    /// db.flush()?;
+    /// db.delete(b"age")?;
+    /// assert_eq!(db.get(b"age")?, None);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn delete(&self, key: impl AsRef<[u8]>) -> Result<(), StorageError> {
+        let key = key.as_ref();
         if self.verbose {
-            println!("Entries: {}", self.memtable.len());
-            println!(
-                "Average entry size: {:.2} KB",
-                (self.memtable.size() as f64 / self.memtable.len() as f64) / 1024.0
-            );
+            debug!("DELETE {:?}", String::from_utf8_lossy(key));
         }
 
-        // Create new SSTable at level 0
-        let sstable_path = self
-            .data_dir
-            .join(format!("L0_{}.sst", self.sstable_counter));
-        let mut sstable = SSTable::new(sstable_path)?;
-
-        // Write memtable data to SSTable
-        let entries: Vec<_> = self
-            .memtable
-            .iter()
-            .map(|(k, v)| (k.clone(), v.clone()))
-            .collect();
-
-        sstable.write(&entries)?;
+        // Write to WAL first, then record a tombstone in the memtable
+        let mut writer = self.writer();
+        let seq = self.mem().seq() + 1;
+        writer.wal.append(seq, Operation::Delete, key, None)?;
+        self.apply_delete(&mut writer, &mut self.mem_for_write(), key.to_vec());
+        Ok(())
+    }
 
+    /// Delete every key in `[start, end)`; an empty `end` runs to the last
+    /// key. The keys live right now are found without reading any values,
+    /// and each gets a tombstone, all written as one batch: the range is
+    /// gone at once or, after a crash, not at all. Reads, snapshots and
+    /// compaction then treat them like any other delete.
+    pub fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<(), StorageError> {
         if self.verbose {
-            println!(
-                "Created SSTable: L0_{}.sst ({:.2} MB)",
-                self.sstable_counter,
-                sstable.size() as f64 / 1_048_576.0
+            debug!(
+                "DELETE_RANGE {:?}..{:?}",
+                String::from_utf8_lossy(start),
+                String::from_utf8_lossy(end)
             );
         }
 
-        // Add new SSTable to level 0
-        self.sstables.entry(0).or_default().push(sstable);
-        self.sstable_counter += 1;
+        let end = (!end.is_empty()).then_some(end);
+        let (memtable, tables) = self.range_sources(start, end);
+        let now = self.options.now();
+        let keys = scan::live_keys(live_kinds(memtable, now), tables, start, end, now)?;
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let mut batch = WriteBatch::new();
+        for key in keys {
+            batch.delete(key);
+        }
+        self.write(batch)
+    }
 
-        // Clear memtable and WAL
-        self.memtable = MemTable::new();
-        self.wal.clear()?;
+    /// Add a logged delete to the memtable and tell watchers
+    fn apply_delete(&self, writer: &mut Writer, mem: &mut impl MemWrite, key: Key) {
+        self.lifetime.add_writes(0, 1);
+        let seq = mem.seq() + 1;
+        let watched = writer.watchers.wants(&key).then(|| key.clone());
+        let deleted_at = self.options.now();
+        self.buffer(mem, key, Version::new(seq, Entry::Tombstone { deleted_at }));
+        if let Some(key) = watched {
+            writer.watchers.publish(ChangeEvent {
+                key,
+                op: Operation::Delete,
+                value: None,
+                seq,
+            });
+        }
+    }
 
-        // Check if compaction is needed at level 0
-        self.maybe_compact(0)?;
+    /// Apply every write in `batch`, in order and atomically: the batch is
+    /// logged as a single WAL record, so after a crash either all of it is
+    /// replayed or none. A batch larger than `large_batch_bytes` skips the
+    /// memtable and is written straight to new L0 tables, logging only a
+    /// marker in the WAL.
+    pub fn write(&self, batch: WriteBatch) -> Result<(), StorageError> {
+        self.wait_for_room(batch.size());
+        Ok(self.write_locked(&mut self.writer(), batch)?)
+    }
 
-        Ok(())
+    /// [`write`](Storage::write) with the writer lock already held
+    fn write_locked(&self, writer: &mut Writer, batch: WriteBatch) -> io::Result<()> {
+        let threshold = self
+            .options
+            .large_batch_bytes
+            .unwrap_or(self.options.memtable_size);
+        if batch.size() > threshold {
+            return bulk::write_direct(self, writer, batch.into_ops());
+        }
+        let ops = batch.into_ops();
+        if ops.iter().any(|op| matches!(op, BatchOp::Put(..))) {
+            self.check_quota(writer)?;
+        }
+        self.throttle_writes(writer)?;
+
+        let seq = self.mem().seq() + 1;
+        writer.wal.append_batch(
+            seq,
+            ops.iter().map(|op| match op {
+                BatchOp::Put(key, value) => {
+                    (Operation::Put, key.as_slice(), Some(value.as_slice()))
+                }
+                BatchOp::Delete(key) => (Operation::Delete, key.as_slice(), None),
+            }),
+        )?;
+        // Applied under one lock, so reads see all of the batch or none
+        let mut mem = self.mem_mut();
+        for op in ops {
+            match op {
+                BatchOp::Put(key, value) => self.apply_put(writer, &mut mem, key, value, None),
+                BatchOp::Delete(key) => self.apply_delete(writer, &mut mem, key),
+            }
+        }
+        drop(mem);
+        self.maybe_flush(writer)
     }
 
-    fn maybe_compact(&mut self, level: usize) -> io::Result<()> {
-        if let Some(tables) = self.sstables.get(&level) {
-            let total_size: usize = tables.iter().map(|t| t.size()).sum();
+    /// Start an optimistic transaction reading from the current state; see [`Txn`]
+    pub fn transaction(&self) -> Txn {
+        Txn::new(self.snapshot())
+    }
 
-            if self.verbose {
-                println!("\n=== Compaction Check: Level {} ===", level);
-                println!("Files: {}", tables.len());
-                println!("Total size: {:.2} MB", total_size as f64 / 1_048_576.0);
+    /// Restore the value a key held before it was deleted, provided the
+    /// delete is still within the configured `tombstone_retention` window
+    pub fn undelete(&self, key: &Key) -> Result<(), StorageError> {
+        let versions = self.versions(key)?;
+        let not_recoverable =
+            || io::Error::new(io::ErrorKind::NotFound, "Deleted value is not recoverable");
+
+        let deleted_at = match versions.first().map(|version| &version.entry) {
+            Some(Entry::Tombstone { deleted_at }) => *deleted_at,
+            Some(Entry::Value(_) | Entry::Expiring { .. } | Entry::Merge(_)) => {
+                return Err(
+                    io::Error::new(io::ErrorKind::InvalidInput, "Key is not deleted").into(),
+                )
             }
+            // Nothing left at all: compaction already discarded the delete
+            None => return Err(not_recoverable().into()),
+        };
 
-            if self.compaction_manager.should_compact(level, tables) {
-                if self.verbose {
-                    println!("\n=== Starting Compaction ===");
-                    println!("Level: {} -> {}", level, level + 1);
-                    println!("Files to compact: {}", tables.len());
-                    for (idx, table) in tables.iter().enumerate() {
-                        println!("  {}: {:.2} MB", idx, table.size() as f64 / 1_048_576.0);
+        let retention = self.options.tombstone_retention.as_millis() as u64;
+        if self.options.now().saturating_sub(deleted_at) >= retention {
+            return Err(not_recoverable().into());
+        }
+        match versions[1..]
+            .iter()
+            .find_map(|version| version.entry.value())
+        {
+            Some(value) => self.put(key.clone(), value.clone()),
+            None => Err(not_recoverable().into()),
+        }
+    }
+
+    /// Versions of `key` from newest to oldest across the memtable and all
+    /// SSTables, stopping once a live value has been found
+    fn versions(&self, key: &[u8]) -> io::Result<Vec<Version>> {
+        let (mem, levels) = self.current();
+        let mut versions = mem.versions(key);
+        drop(mem);
+
+        for level in 0..=levels.max_level() {
+            if levels.sstables.contains_key(&level) {
+                for sstable in levels.tables_for_key(level, key).iter().rev() {
+                    if versions.iter().any(|version| !version.entry.is_tombstone()) {
+                        return Ok(versions);
                     }
+                    versions.extend(sstable.versions(key)?);
                 }
+            }
+        }
 
-                // Perform compaction
-                let compacted = self.compaction_manager.compact(tables)?;
-
-                // Get paths of tables to delete
-                let table_paths: Vec<_> = tables.iter().map(|t| t.get_path().clone()).collect();
-
-                // Move compacted SSTable to next level
-                let next_level = level + 1;
-                let new_path = self
-                    .data_dir
-                    .join(format!("L{}_{}.sst", next_level, self.sstable_counter));
+        Ok(versions)
+    }
 
-                let mut new_table = SSTable::new(new_path)?;
-                let entries = compacted.read()?;
+    /// Sequence number of the newest write to `key`, if any is still stored
+    fn latest_seq(&self, key: &[u8]) -> io::Result<Option<u64>> {
+        let (mem, levels) = self.current();
+        if let Some(version) = mem.versions(key).first() {
+            return Ok(Some(version.seq));
+        }
+        drop(mem);
 
-                if self.verbose {
-                    println!("\n=== Compaction Results ===");
-                    println!("Unique entries: {}", entries.len());
+        for level in 0..=levels.max_level() {
+            if levels.sstables.contains_key(&level) {
+                for sstable in levels.tables_for_key(level, key).iter().rev() {
+                    if let Some(version) = sstable.versions(key)?.first() {
+                        return Ok(Some(version.seq));
+                    }
                 }
+            }
+        }
 
-                new_table.write(&entries)?;
+        Ok(None)
+    }
 
-                let new_table_size = new_table.size();
-                if self.verbose {
-                    println!(
-                        "New SSTable size: {:.2} MB",
-                        new_table_size as f64 / 1_048_576.0
-                    );
-                }
+    /// Policy for discarding versions when writing a table at `level`
+    fn gc_policy(&self, output_level: usize) -> GcPolicy {
+        // Tombstones can only be dropped when no older data for the key could
+        // remain beneath the output, i.e. nothing lives at or below its level
+        let bottommost = self
+            .levels()
+            .sstables
+            .iter()
+            .all(|(&level, tables)| level < output_level || tables.is_empty());
+
+        GcPolicy {
+            now: self.options.now(),
+            tombstone_retention: self.options.tombstone_retention.as_millis() as u64,
+            bottommost,
+            snapshots: snapshot::live_seqs(&self.snapshots),
+            merge: self.options.merge_operator.clone(),
+        }
+    }
 
-                // Update sstables collection
-                self.sstables.get_mut(&level).unwrap().clear();
-                self.sstables.entry(next_level).or_default().push(new_table);
-                self.sstable_counter += 1;
+    /// Remove all data while keeping this instance open.
+    ///
+    /// A durable marker is written before anything is deleted, so a crash
+    /// midway recovers to either the complete old state (marker not yet
+    /// written) or an empty database (recovery finishes the clear).
+    pub fn clear(&mut self) -> Result<(), StorageError> {
+        info!("Clearing {:?}", self.data_dir);
+
+        Self::write_clear_marker(&self.data_dir)?;
+
+        let mut writer = self.writer();
+        self.abandon_flush(&mut writer);
+        Self::abandon_compaction(&mut writer);
+        self.mem_mut().memtable = ActiveMemTable::new();
+        writer.wal.clear()?;
+        self.edit_levels(|levels| *levels = Levels::default());
+        writer.sstable_counter = 0;
+        writer.written_key_sizes = SizeHistogram::new();
+        writer.written_value_sizes = SizeHistogram::new();
+
+        let table_dirs = self.options.table_dirs(&self.data_dir);
+        let manifest = Self::finish_clear(&self.data_dir, &table_dirs)?;
+        // The cleared tables' objects go too
+        #[cfg(feature = "object-store")]
+        let manifest = match &self.options.object_fs {
+            Some(objects) => {
+                objects::attach(objects, manifest, &ManifestState::default(), &self.data_dir)?
+            }
+            None => manifest,
+        };
+        writer.manifest = manifest;
+        Ok(())
+    }
 
-                // Now delete the old files
-                for path in table_paths {
-                    fs::remove_file(path)?;
-                }
+    fn write_clear_marker(data_dir: &Path) -> io::Result<()> {
+        let tmp_path = data_dir.join(format!("{}.tmp", CLEAR_MARKER));
+        fs::File::create(&tmp_path)?.sync_all()?;
+        fs::rename(&tmp_path, data_dir.join(CLEAR_MARKER))?;
+        sync_dir(data_dir)
+    }
 
-                if self.verbose {
-                    let space_saved = total_size.saturating_sub(new_table_size);
-                    println!(
-                        "Space reclaimed: {:.2} MB",
-                        space_saved as f64 / 1_048_576.0
-                    );
-                    println!(
-                        "Compression ratio: {:.2}%",
-                        (1.0 - (new_table_size as f64 / total_size as f64)) * 100.0
-                    );
+    /// Delete every SSTable in `table_dirs` and empty the manifest, then
+    /// remove the marker that made the clear durable
+    fn finish_clear(data_dir: &Path, table_dirs: &[PathBuf]) -> io::Result<Manifest> {
+        for dir in table_dirs {
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("sst") {
+                    fs::remove_file(path)?;
                 }
-
-                // Check if next level needs compaction
-                self.maybe_compact(next_level)?;
             }
+            sync_dir(dir)?;
         }
-        Ok(())
+
+        let manifest = Manifest::create(data_dir, &ManifestState::default())?;
+        fs::remove_file(data_dir.join(CLEAR_MARKER))?;
+        sync_dir(data_dir)?;
+        Ok(manifest)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Start a bulk load that writes SSTables directly instead of going
+    /// through the WAL and memtable; see [`BulkLoader`]
+    pub fn bulk_loader(&mut self) -> Result<BulkLoader<'_>, StorageError> {
+        Ok(BulkLoader::new(self)?)
+    }
+
+    /// Import strictly ascending pairs straight into SSTables at the bottom
+    /// level, skipping the memtable and WAL. Input overlapping existing keys
+    /// near the top of the tree is rejected unless `allow_l0_fallback` is set,
+    /// in which case it lands in L0 instead.
+    pub fn bulk_load_sorted<I>(
+        &mut self,
+        pairs: I,
+        allow_l0_fallback: bool,
+    ) -> Result<(), StorageError>
+    where
+        I: IntoIterator<Item = (Key, Value)>,
+    {
+        Ok(bulk::load_sorted(
+            self,
+            pairs.into_iter().map(Ok),
+            allow_l0_fallback,
+        )?)
+    }
+
+    /// [`bulk_load_sorted`](Storage::bulk_load_sorted) for input that may
+    /// overlap what's already stored, which then lands in L0 and shadows it
+    pub fn ingest_sorted<I>(&mut self, pairs: I) -> Result<(), StorageError>
+    where
+        I: IntoIterator<Item = (Key, Value)>,
+    {
+        self.bulk_load_sorted(pairs, true)
+    }
+
+    /// Snapshot of size statistics and activity counters, built from SSTable
+    /// properties without reading any table data
+    pub fn stats(&self) -> StorageStats {
+        let writer = self.writer();
+        let (mem, levels) = self.current();
+        let tables = levels
+            .sstables
+            .iter()
+            .map(|(&level, tables)| (level, tables.as_slice()));
+        let (stored, levels) = level_stats(tables);
+
+        StorageStats {
+            memtable_bytes: mem.size() as u64,
+            memtable_entries: mem.len() as u64,
+            // Only unreadable segment metadata fails this; count it as empty
+            wal_bytes: writer.wal.size().unwrap_or(0),
+            written_key_sizes: writer.written_key_sizes.clone(),
+            written_value_sizes: writer.written_value_sizes.clone(),
+            stored,
+            levels,
+            skipped_reads: self.skipped_reads.load(Ordering::Relaxed),
+            fadvise_calls: advise_calls(),
+            event_log_errors: self.events.errors(),
+            lifetime: self.lifetime.lifetime(),
+            since_open: self.lifetime.since_open(),
+            block_cache: self
+                .block_cache
+                .as_ref()
+                .map_or_else(Default::default, |cache| cache.stats()),
+        }
+    }
+
+    /// Make every acknowledged write durable by syncing the WAL, whatever
+    /// `StorageOptions::wal_sync` says
+    pub fn sync(&self) -> Result<(), StorageError> {
+        self.writer().wal.sync()
+    }
+
+    /// Write the memtable out as a level 0 table and clear the WAL,
+    /// returning once it and the compactions it makes due are installed;
+    /// with nothing buffered this does nothing. Other writes carry on while
+    /// it waits for the flush and compaction threads.
+    pub fn flush(&self) -> Result<(), StorageError> {
+        self.flush_buffered()?;
+        loop {
+            self.background.wait_while(|jobs| jobs.compaction);
+            let mut writer = self.writer();
+            // Installs the finished jobs and starts whatever is due next
+            self.maybe_compact(&mut writer, 0)?;
+            if writer.compacting.is_none() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Whether [`flush`](Storage::flush) has anything to write: buffered
+    /// writes, or a full memtable whose table isn't installed yet
+    pub fn flush_pending(&self) -> bool {
+        let mem = self.mem();
+        !mem.memtable.is_empty() || mem.immutable.is_some()
+    }
+
+    /// Flush the memtable, install any running compaction and sync the
+    /// WAL, so the next open has nothing to replay. Unlike dropping
+    /// `Storage`, failures are returned.
+    pub fn close(self) -> Result<(), StorageError> {
+        let mut writer = self.writer();
+        self.flush_memtable(&mut writer)?;
+        self.finish_compaction(&mut writer)?;
+        writer.wal.sync()?;
+        drop(writer);
+        self.persist_stats();
+        Ok(())
+    }
+
+    /// Merge every table of `level` into the next level now, whatever its
+    /// size, then compact deeper levels as usual. Returns `false` if the
+    /// level holds no tables. FIFO compaction never merges, so it fails
+    /// with `Unsupported` there.
+    pub fn compact_level(&mut self, level: usize) -> Result<bool, StorageError> {
+        if self.options.compaction_strategy != CompactionStrategy::Leveled {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Tables are never merged under FIFO compaction",
+            )
+            .into());
+        }
+        if self
+            .levels()
+            .sstables
+            .get(&level)
+            .is_none_or(|tables| tables.is_empty())
+        {
+            return Ok(false);
+        }
+        let mut writer = self.writer();
+        self.run_compaction(&mut writer, level)?;
+        self.compact_until_settled(&mut writer, level + 1)?;
+        Ok(true)
+    }
+
+    /// Where table number `number` of `level` lives, honoring any
+    /// per-level directory from the options
+    fn table_path(&self, level: usize, number: u64) -> PathBuf {
+        self.options
+            .table_dir(&self.data_dir, level)
+            .join(format!("L{}_{}.sst", level, number))
+    }
+}
+
+#[cfg(test)]
+impl Storage {
+    /// Stop as a crash would: nothing is flushed, installed or saved, and
+    /// only the directory lock is let go
+    pub(super) fn crash(self) {
+        self._lock.release();
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for Storage {
+    fn drop(&mut self) {
+        // Install background work still running, so its WAL segment isn't
+        // replayed and its output isn't left beside its inputs
+        let mut writer = self.writer();
+        let _ = self.finish_flush(&mut writer);
+        let _ = self.finish_compaction(&mut writer);
+        drop(writer);
+        self.persist_stats();
+    }
+}
+
+/// Buffered versions in `[start, end)` as (key, seq, holds a value not
+/// yet expired at `now`)
+fn live_kinds(versions: Vec<(Key, Version)>, now: u64) -> Vec<(Key, u64, bool)> {
+    versions
+        .into_iter()
+        .map(|(key, version)| {
+            let live = version.entry.is_live(now);
+            (key, version.seq, live)
+        })
+        .collect()
+}
+
+/// Versions held in `mem` for keys in `[start, end)` (a missing `end` runs
+/// to the last key), in key order and newest first within a key
+fn buffered_range(mem: &Memtables, start: &[u8], end: Option<&[u8]>) -> Vec<(Key, Version)> {
+    let mut versions: Vec<(Key, Version)> = Vec::new();
+    mem.range_versions(start, end, |key, held| {
+        versions.extend(held.iter().map(|version| (key.clone(), version.clone())));
+    });
+    // Stable, so the active memtable's newer versions stay in front
+    versions.sort_by(|(a, _), (b, _)| a.cmp(b));
+    versions
+}
+
+/// A failed read of `sstable`, naming the table unless the error already
+/// does. The kind is kept, so corruption (InvalidData) stays distinguishable
+/// from other I/O failures.
+fn table_read_error(sstable: &SSTable, e: io::Error) -> io::Error {
+    if e.get_ref().is_some_and(|inner| inner.is::<StorageError>()) {
+        return e;
+    }
+    io::Error::new(
+        e.kind(),
+        format!("Failed to read SSTable {:?}: {}", sstable.get_path(), e),
+    )
+}
+
+/// Properties of every table in `levels` combined, and per level in level
+/// order
+fn level_stats<'a>(
+    levels: impl Iterator<Item = (usize, &'a [Arc<SSTable>])>,
+) -> (TableProperties, Vec<LevelStats>) {
+    let mut stored = TableProperties::new();
+    let mut levels: Vec<LevelStats> = levels
+        .map(|(level, tables)| {
+            let mut properties = TableProperties::new();
+            for table in tables {
+                properties.merge(table.properties());
+            }
+            stored.merge(&properties);
+            LevelStats {
+                level,
+                file_count: tables.len(),
+                total_bytes: tables.iter().map(|t| t.size()).sum(),
+                filter_bytes: tables.iter().map(|t| t.bloom_bytes()).sum(),
+                properties,
+            }
+        })
+        .collect();
+    levels.sort_by_key(|l| l.level);
+    (stored, levels)
+}
+
+/// Parse level and sequence number from a table's filename (`L{level}_{seq}.sst`)
+fn parse_table_name(path: &Path) -> Option<(usize, u64)> {
+    let (level, seq) = path
+        .file_stem()?
+        .to_str()?
+        .strip_prefix('L')?
+        .split_once('_')?;
+    Some((level.parse().ok()?, seq.parse().ok()?))
+}
+
+/// Make renames and removals within `dir` durable
+pub(crate) fn sync_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    fs::File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::CacheStats;
     use std::fs;
+    use std::sync::atomic::AtomicU64;
+    use std::sync::Arc;
     use std::thread;
-    use std::time::Duration;
+    use std::time::Instant;
     use tempfile::TempDir;
 
     fn create_test_storage() -> (TempDir, Storage) {
@@ -365,7 +1725,7 @@ mod tests {
 
     #[test]
     fn test_basic_operations() {
-        let (_temp_dir, mut storage) = create_test_storage();
+        let (_temp_dir, storage) = create_test_storage();
 
         // Test put and get
         let key1 = b"key1".to_vec();
@@ -388,9 +1748,33 @@ mod tests {
         assert_eq!(storage.get(&nonexistent).unwrap(), None);
     }
 
+    #[test]
+    fn test_keys_and_values_of_any_byte_form() {
+        let (_temp_dir, storage) = create_test_storage();
+
+        storage.put("str", "value").unwrap();
+        storage
+            .put(String::from("string"), String::from("value"))
+            .unwrap();
+        storage.put(b"array", b"value").unwrap();
+        storage.put(&b"slice"[..], &b"value"[..]).unwrap();
+        storage.put(b"vec".to_vec(), b"value".to_vec()).unwrap();
+
+        let value = Some(b"value".to_vec());
+        assert_eq!(storage.get("str").unwrap(), value);
+        assert_eq!(storage.get(String::from("string")).unwrap(), value);
+        assert_eq!(storage.get(b"array").unwrap(), value);
+        assert_eq!(storage.get(&b"slice"[..]).unwrap(), value);
+
+        storage.delete("str").unwrap();
+        storage.delete(b"array").unwrap();
+        assert_eq!(storage.get(b"str").unwrap(), None);
+        assert_eq!(storage.get("array").unwrap(), None);
+    }
+
     #[test]
     fn test_memtable_flush() {
-        let (temp_dir, mut storage) = create_test_storage();
+        let (temp_dir, storage) = create_test_storage();
         let data_dir = temp_dir.path();
 
         // Write enough data to trigger a flush
@@ -425,7 +1809,7 @@ mod tests {
 
     #[test]
     fn test_concurrent_operations() {
-        let (_temp_dir, mut storage) = create_test_storage();
+        let (_temp_dir, storage) = create_test_storage();
 
         // Perform rapid operations
         for i in 0..100 {
@@ -453,12 +1837,81 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "skiplist")]
+    #[test]
+    fn test_skiplist_memtable() {
+        const WRITERS: usize = 4;
+        const WRITES: usize = 500;
+        let (_temp_dir, storage) = create_test_storage();
+        storage.put(b"hot", b"before").unwrap();
+        let snapshot = storage.snapshot();
+        let done = AtomicU64::new(0);
+
+        thread::scope(|scope| {
+            for w in 0..WRITERS {
+                let (storage, done) = (&storage, &done);
+                scope.spawn(move || {
+                    for i in 0..WRITES {
+                        let value = format!("{}-{}", w, i).into_bytes();
+                        storage
+                            .put(format!("key{}-{}", w, i), value.clone())
+                            .unwrap();
+                        storage.put(b"hot", value.clone()).unwrap();
+                        let mut batch = WriteBatch::new();
+                        batch.put(b"pair-a".to_vec(), value.clone());
+                        batch.put(b"pair-b".to_vec(), value);
+                        storage.write(batch).unwrap();
+                    }
+                    done.fetch_add(1, Ordering::Release);
+                });
+            }
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    while done.load(Ordering::Acquire) < WRITERS as u64 {
+                        // A batch is seen whole or not at all
+                        let pair: Vec<_> = storage
+                            .scan(b"pair-a", b"pair-c")
+                            .unwrap()
+                            .map(|pair| pair.unwrap().1)
+                            .collect();
+                        assert!(pair.is_empty() || (pair.len() == 2 && pair[0] == pair[1]));
+                        let before = storage.get_at(b"hot", &snapshot).unwrap();
+                        assert_eq!(before, Some(b"before".to_vec()));
+                    }
+                });
+            }
+        });
+
+        // Every overwrite is held until the memtable is frozen
+        let hot = storage.mem().memtable.versions(b"hot");
+        assert_eq!(hot.len(), WRITERS * WRITES + 1);
+        storage.flush().unwrap();
+        assert!(storage.mem().memtable.is_empty());
+        for w in 0..WRITERS {
+            for i in 0..WRITES {
+                let value = format!("{}-{}", w, i).into_bytes();
+                assert_eq!(storage.get(format!("key{}-{}", w, i)).unwrap(), Some(value));
+            }
+        }
+        assert_eq!(
+            storage.get(b"hot").unwrap(),
+            Some(hot[0].entry.value().unwrap().clone())
+        );
+        // The flush kept the newest write and what the snapshot reads
+        let levels = storage.levels();
+        assert_eq!(levels.sstables[&0][0].versions(b"hot").unwrap().len(), 2);
+        assert_eq!(
+            storage.get_at(b"hot", &snapshot).unwrap(),
+            Some(b"before".to_vec())
+        );
+    }
+
     #[test]
     fn test_recovery() {
-        let (temp_dir, mut storage) = create_test_storage();
+        let (temp_dir, storage) = create_test_storage();
 
         // Write some data
-        let test_data = vec![
+        let test_data = [
             (b"key1".to_vec(), b"value1".to_vec()),
             (b"key2".to_vec(), b"value2".to_vec()),
             (b"key3".to_vec(), b"value3".to_vec()),
@@ -479,8 +1932,166 @@ mod tests {
     }
 
     #[test]
-    fn test_compaction() {
+    fn test_recovery_skips_flushed_segments() {
+        let (temp_dir, storage) = create_test_storage();
+        let key = b"key".to_vec();
+        let log = |n: u64| temp_dir.path().join("wal").join(format!("{:06}.log", n));
+
+        storage.put(key.clone(), b"a".to_vec()).unwrap();
+        let first_log = fs::read(log(1)).unwrap();
+        storage.flush().unwrap();
+        storage.put(key.clone(), b"b".to_vec()).unwrap();
+        storage.flush().unwrap();
+        drop(storage);
+
+        // Resurrect the first segment as if removing it after the flush had
+        // crashed. Replaying it would renumber the old write above the new.
+        fs::write(log(1), first_log).unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        assert!(!log(1).exists());
+        assert_eq!(storage.get(&key).unwrap(), Some(b"b".to_vec()));
+    }
+
+    #[test]
+    fn test_open_with_single_file_wal() {
+        let temp_dir = TempDir::new().unwrap();
+        // The log as the first release wrote it: bare records in one file
+        let mut log = Vec::new();
+        for (op, key, value) in [(0u8, &b"a"[..], &b"1"[..]), (0, b"b", b"2"), (1, b"a", b"")] {
+            log.push(op);
+            log.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            log.extend_from_slice(key);
+            if op == 0 {
+                log.extend_from_slice(&(value.len() as u32).to_le_bytes());
+                log.extend_from_slice(value);
+            }
+        }
+        fs::write(temp_dir.path().join("wal"), log).unwrap();
+
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        assert!(temp_dir.path().join("wal").is_dir());
+        assert_eq!(storage.get(b"a").unwrap(), None);
+        assert_eq!(storage.get(b"b").unwrap(), Some(b"2".to_vec()));
+
+        storage.put(b"c", b"3").unwrap();
+        drop(storage);
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        assert_eq!(storage.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(storage.get(b"c").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn test_seqs_continue_across_restart() {
         let (temp_dir, mut storage) = create_test_storage();
+        storage.put(b"a", b"1").unwrap();
+        storage.put(b"b", b"2").unwrap();
+        storage.delete(b"b").unwrap();
+        storage.flush().unwrap();
+        // Compacting to the bottom drops the newest write, the delete
+        storage.compact_all().unwrap();
+        let tables: Vec<_> = storage
+            .levels()
+            .sstables
+            .values()
+            .flatten()
+            .cloned()
+            .collect();
+        assert!(tables.iter().all(|table| table.properties().max_seq < 3));
+        assert_eq!(storage.mem().seq(), 3);
+        drop(storage);
+
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        assert_eq!(storage.mem().seq(), 3);
+        storage.put(b"c", b"3").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(b"d", b"4");
+        batch.delete(b"a");
+        storage.write(batch).unwrap();
+        assert_eq!(storage.mem().seq(), 6);
+        storage.crash();
+
+        // Numbered as logged, and numbering carries on after them
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        assert_eq!(storage.mem().seq(), 6);
+        assert_eq!(storage.mem().memtable.versions(b"c")[0].seq, 4);
+        assert_eq!(storage.mem().memtable.versions(b"a")[0].seq, 6);
+        storage.put(b"e", b"5").unwrap();
+        assert_eq!(storage.mem().seq(), 7);
+    }
+
+    #[test]
+    fn test_replaying_a_segment_twice_changes_nothing() {
+        let (temp_dir, storage) = create_test_storage();
+        let log = |n: u64| temp_dir.path().join("wal").join(format!("{:06}.log", n));
+        storage.put(b"key", b"old").unwrap();
+        let flushed_log = fs::read(log(1)).unwrap();
+        storage.put(b"key", b"flushed").unwrap();
+        storage.flush().unwrap();
+
+        storage.put(b"other", b"1").unwrap();
+        storage.delete(b"other").unwrap();
+        storage.put(b"other", b"2").unwrap();
+        let live = WAL::newest_log(&temp_dir.path().join("wal"))
+            .unwrap()
+            .unwrap();
+        storage.crash();
+        let expected = {
+            let storage = Storage::new(temp_dir.path(), false).unwrap();
+            let mem = storage.mem();
+            (mem.seq(), mem.memtable.versions(b"other").to_vec())
+        };
+
+        // As if a retry logged the live segment again, then resurrected the
+        // flushed one after it
+        fs::copy(log(live), log(live + 1)).unwrap();
+        fs::write(log(live + 2), flushed_log).unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        assert_eq!(storage.mem().seq(), expected.0);
+        assert_eq!(storage.mem().memtable.versions(b"other"), &expected.1[..]);
+        assert!(storage.mem().memtable.versions(b"key").is_empty());
+        assert_eq!(storage.get(b"key").unwrap(), Some(b"flushed".to_vec()));
+        assert_eq!(storage.get(b"other").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn test_wal_segments_rotate_and_recover() {
+        let temp_dir = TempDir::new().unwrap();
+        let logs = || WAL::logs(&temp_dir.path().join("wal")).unwrap();
+        let options = StorageOptions::default().wal_segment_size(1024);
+        let storage = Storage::open_with_options(temp_dir.path(), options.clone()).unwrap();
+        for i in 0..30 {
+            storage
+                .put(format!("key{:02}", i).into_bytes(), vec![b'v'; 100])
+                .unwrap();
+        }
+        assert!(logs().len() >= 3);
+
+        // Flushing drops every segment the memtable spanned
+        storage.flush().unwrap();
+        assert_eq!(logs().len(), 1);
+        for i in 30..60 {
+            storage
+                .put(format!("key{:02}", i).into_bytes(), vec![b'w'; 100])
+                .unwrap();
+        }
+        assert!(logs().len() >= 3);
+        storage.crash();
+
+        // A crash replays the segments written since the flush, in order
+        let storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+        for i in 0..60 {
+            let value = if i < 30 { b'v' } else { b'w' };
+            assert_eq!(
+                storage.get(format!("key{:02}", i).into_bytes()).unwrap(),
+                Some(vec![value; 100])
+            );
+        }
+        assert_eq!(storage.level_files(0).len(), 1);
+    }
+
+    #[test]
+    fn test_compaction() {
+        let (temp_dir, storage) = create_test_storage();
         let data_dir = temp_dir.path();
 
         // Write enough data to trigger multiple flushes and compaction
@@ -490,8 +2101,10 @@ mod tests {
             storage.put(key, value.clone()).unwrap();
         }
 
-        // Give time for compaction to occur
-        thread::sleep(Duration::from_millis(200));
+        // Let compactions still running in the background finish
+        storage
+            .compact_until_settled(&mut storage.writer(), 0)
+            .unwrap();
 
         // Count SSTable files
         let sstable_files: Vec<_> = fs::read_dir(data_dir)
@@ -508,11 +2121,11 @@ mod tests {
             .collect();
 
         // Verify compaction occurred by checking file count and levels
-        let mut level_counts = vec![0; 4]; // Count files in levels 0-3
+        let mut level_counts = [0; 4]; // Count files in levels 0-3
         for entry in sstable_files {
             let filename = entry.unwrap().file_name();
             let name = filename.to_str().unwrap();
-            if let Some(level) = name.chars().find(|c| c.is_digit(10)) {
+            if let Some(level) = name.chars().find(|c| c.is_ascii_digit()) {
                 let level_num = level.to_digit(10).unwrap() as usize;
                 if level_num < level_counts.len() {
                     level_counts[level_num] += 1;
@@ -526,13 +2139,1423 @@ mod tests {
 
         // Verify all data is still accessible
         let test_keys = vec![
-            format!("key0").into_bytes(),
-            format!("key500").into_bytes(),
-            format!("key1999").into_bytes(),
+            "key0".to_string().into_bytes(),
+            "key500".to_string().into_bytes(),
+            "key1999".to_string().into_bytes(),
         ];
 
         for key in &test_keys {
             assert_eq!(storage.get(key).unwrap(), Some(value.clone()));
         }
     }
+
+    fn create_storage_with_clock(retention: Duration) -> (TempDir, Storage, Arc<AtomicU64>) {
+        let temp_dir = TempDir::new().unwrap();
+        let now = Arc::new(AtomicU64::new(1_000_000));
+        let clock_now = now.clone();
+        let options = StorageOptions::default()
+            .tombstone_retention(retention)
+            .clock(Arc::new(move || clock_now.load(Ordering::SeqCst)));
+        let storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+        (temp_dir, storage, now)
+    }
+
+    #[test]
+    fn test_delete_survives_flush_and_restart() {
+        let (temp_dir, storage) = create_test_storage();
+        let key = b"key".to_vec();
+
+        storage.put(key.clone(), b"value".to_vec()).unwrap();
+        storage.flush().unwrap();
+        storage.delete(&key).unwrap();
+        storage.flush().unwrap();
+        assert_eq!(storage.get(&key).unwrap(), None);
+
+        drop(storage);
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        assert_eq!(storage.get(&key).unwrap(), None);
+    }
+
+    #[test]
+    fn test_delete_range() {
+        let (temp_dir, storage) = create_test_storage();
+        let key = |user: u32, i: u32| format!("user{}:{:02}", user, i).into_bytes();
+        for user in 1..=3 {
+            for i in 0..10 {
+                storage.put(key(user, i), b"flushed".to_vec()).unwrap();
+            }
+        }
+        storage.flush().unwrap();
+        // Newer versions in the memtable, and a key only it holds
+        storage.put(key(2, 0), b"buffered".to_vec()).unwrap();
+        storage.put(key(2, 10), b"buffered".to_vec()).unwrap();
+        let before = storage.snapshot();
+
+        storage.delete_range(b"user2:", b"user2;").unwrap();
+        for i in 0..=10 {
+            assert_eq!(storage.get(key(2, i)).unwrap(), None);
+        }
+        assert_eq!(
+            storage.get_at(&key(2, 3), &before).unwrap(),
+            Some(b"flushed".to_vec())
+        );
+        assert_eq!(storage.scan(b"", b"").unwrap().count(), 20);
+        drop(before);
+
+        // Writes after the delete aren't covered by it
+        storage.put(key(2, 5), b"after".to_vec()).unwrap();
+        storage.flush().unwrap();
+        drop(storage);
+        let mut storage = Storage::new(temp_dir.path(), false).unwrap();
+        assert_eq!(storage.get(key(1, 9)).unwrap(), Some(b"flushed".to_vec()));
+        assert_eq!(storage.get(key(2, 4)).unwrap(), None);
+        assert_eq!(storage.get(key(2, 5)).unwrap(), Some(b"after".to_vec()));
+        assert_eq!(storage.get(key(3, 0)).unwrap(), Some(b"flushed".to_vec()));
+
+        // Compacting to the bottom purges the covered values and the
+        // tombstones along with them
+        storage.compact_level(0).unwrap();
+        assert!(storage.level_files(0).is_empty());
+        let entries: u64 = storage.levels().sstables[&1]
+            .iter()
+            .map(|table| table.properties().entry_count)
+            .sum();
+        assert_eq!(entries, 21);
+        assert_eq!(storage.count_range(b"user2:", b"user2;").unwrap(), 1);
+
+        // An empty end runs to the last key; nothing live is a no-op
+        storage.delete_range(b"user3:05", b"").unwrap();
+        assert_eq!(storage.scan(b"", b"").unwrap().count(), 16);
+        storage.delete_range(b"zzz", b"").unwrap();
+    }
+
+    #[test]
+    fn test_batch_all_or_nothing_after_crash() {
+        let (temp_dir, storage) = create_test_storage();
+        storage.put(b"a", b"old").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(b"a", b"new");
+        batch.put(b"b", b"new");
+        batch.delete(b"c".to_vec());
+        storage.write(batch.clone()).unwrap();
+        drop(storage);
+
+        // The whole batch survives a restart
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        assert_eq!(storage.get(b"a").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(storage.get(b"b").unwrap(), Some(b"new".to_vec()));
+
+        // A crash partway through writing the next batch loses all of it
+        storage.put(b"c", b"old").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(b"c", b"newer");
+        batch.put(b"d", b"newer");
+        storage.write(batch).unwrap();
+        drop(storage);
+        let log = fs::read_dir(temp_dir.path().join("wal"))
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let len = fs::metadata(&log).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&log)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        assert_eq!(storage.get(b"a").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(storage.get(b"c").unwrap(), Some(b"old".to_vec()));
+        assert_eq!(storage.get(b"d").unwrap(), None);
+    }
+
+    #[test]
+    fn test_undelete_within_retention() {
+        let (_temp_dir, storage, now) = create_storage_with_clock(Duration::from_secs(60));
+        let flushed = b"flushed".to_vec();
+        let buffered = b"buffered".to_vec();
+
+        // One value already on disk, one still in the memtable
+        storage.put(flushed.clone(), b"old".to_vec()).unwrap();
+        storage.flush().unwrap();
+        storage.put(buffered.clone(), b"recent".to_vec()).unwrap();
+
+        storage.delete(&flushed).unwrap();
+        storage.delete(&buffered).unwrap();
+        assert_eq!(storage.get(&flushed).unwrap(), None);
+        assert_eq!(storage.get(&buffered).unwrap(), None);
+
+        // Flushing the tombstones keeps the shadowed value alongside them
+        storage.flush().unwrap();
+        assert_eq!(storage.get(&buffered).unwrap(), None);
+
+        now.fetch_add(10_000, Ordering::SeqCst);
+        storage.undelete(&flushed).unwrap();
+        storage.undelete(&buffered).unwrap();
+        assert_eq!(storage.get(&flushed).unwrap(), Some(b"old".to_vec()));
+        assert_eq!(storage.get(&buffered).unwrap(), Some(b"recent".to_vec()));
+
+        // A live key can't be undeleted
+        let err = storage.undelete(&flushed).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_undelete_after_retention_expires() {
+        let (_temp_dir, storage, now) = create_storage_with_clock(Duration::from_secs(60));
+        let key = b"key".to_vec();
+
+        storage.put(key.clone(), b"value".to_vec()).unwrap();
+        storage.flush().unwrap();
+        storage.delete(&key).unwrap();
+        storage.flush().unwrap();
+
+        // Let the window pass, then push everything through a compaction
+        now.fetch_add(61_000, Ordering::SeqCst);
+        for i in 0..2 {
+            storage
+                .put(format!("filler{}", i).into_bytes(), b"x".to_vec())
+                .unwrap();
+            storage.flush().unwrap();
+        }
+        assert!(storage
+            .levels()
+            .sstables
+            .get(&0)
+            .is_none_or(|t| t.is_empty()));
+        assert!(!storage.levels().sstables[&1].is_empty());
+
+        // The compaction output is the bottom level, so both the tombstone
+        // and the old value are gone
+        let compacted = storage.levels().sstables[&1][0].read_entries().unwrap();
+        assert!(compacted.iter().all(|(k, _)| k != &key));
+
+        let err = storage.undelete(&key).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(storage.get(&key).unwrap(), None);
+    }
+
+    #[test]
+    fn test_put_with_ttl_expires_and_is_compacted_away() {
+        let (temp_dir, storage, now) = create_storage_with_clock(Duration::ZERO);
+        let second = Duration::from_secs(1);
+        storage.put_with_ttl(b"a", b"1", second).unwrap();
+        storage.put(b"b", b"2").unwrap();
+        storage
+            .put_with_ttl(b"c", b"3", Duration::from_secs(3600))
+            .unwrap();
+
+        // The expiry survives WAL replay
+        drop(storage);
+        let clock_now = now.clone();
+        let options = StorageOptions::default()
+            .tombstone_retention(Duration::ZERO)
+            .clock(Arc::new(move || clock_now.load(Ordering::SeqCst)));
+        let mut storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+        assert_eq!(storage.get(b"a").unwrap(), Some(b"1".to_vec()));
+        storage.flush().unwrap();
+        storage.put_with_ttl(b"d", b"4", second).unwrap();
+
+        now.fetch_add(2_000, Ordering::SeqCst);
+        // Expired in a table and in the memtable alike
+        assert_eq!(storage.get(b"a").unwrap(), None);
+        assert_eq!(storage.get(b"d").unwrap(), None);
+        assert_eq!(storage.get(b"c").unwrap(), Some(b"3".to_vec()));
+        let keys = [b"a".to_vec(), b"b".to_vec(), b"d".to_vec()];
+        assert_eq!(
+            storage.multi_get(&keys).unwrap(),
+            vec![None, Some(b"2".to_vec()), None]
+        );
+        let live: Vec<_> = storage
+            .scan(b"", b"")
+            .unwrap()
+            .map(|pair| pair.unwrap().0)
+            .collect();
+        assert_eq!(live, vec![b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(storage.count_range(b"", b"").unwrap(), 2);
+
+        storage.compact_all().unwrap();
+        let tables: Vec<_> = storage
+            .levels()
+            .sstables
+            .values()
+            .flatten()
+            .cloned()
+            .collect();
+        assert_eq!(tables.len(), 1);
+        assert_eq!(tables[0].properties().entry_count, 2);
+        assert_eq!(tables[0].properties().tombstone_count, 0);
+    }
+
+    #[test]
+    fn test_compaction_keeps_newest_version() {
+        let (_temp_dir, storage) = create_test_storage();
+        let key = b"key".to_vec();
+
+        // Four L0 tables, each overwriting the same key, trigger a compaction
+        for i in 0..4 {
+            storage
+                .put(key.clone(), format!("v{}", i).into_bytes())
+                .unwrap();
+            storage.flush().unwrap();
+        }
+        assert!(!storage.levels().sstables[&1].is_empty());
+        assert_eq!(storage.get(&key).unwrap(), Some(b"v3".to_vec()));
+    }
+
+    #[test]
+    fn test_compaction_keeps_snapshot_versions() {
+        let (temp_dir, storage) = create_test_storage();
+        let keys: Vec<Key> = (0..10).map(|i| format!("key{}", i).into_bytes()).collect();
+        for key in &keys {
+            storage.put(key.clone(), b"old".to_vec()).unwrap();
+        }
+        storage.flush().unwrap();
+
+        // Overwrite or delete every key, partly before and partly after a flush
+        let snapshot = storage.snapshot();
+        for (i, key) in keys.iter().enumerate() {
+            if i % 2 == 0 {
+                storage.put(key.clone(), b"new".to_vec()).unwrap();
+            } else {
+                storage.delete(key).unwrap();
+            }
+            if i == 4 {
+                storage.flush().unwrap();
+            }
+        }
+        storage.flush().unwrap();
+        storage.put(b"filler", b"x").unwrap();
+        storage.flush().unwrap();
+        assert!(storage.levels().sstables[&0].is_empty());
+        assert!(!storage.levels().sstables[&1].is_empty());
+
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(
+                storage.get_at(key, &snapshot).unwrap(),
+                Some(b"old".to_vec())
+            );
+            let expected = (i % 2 == 0).then(|| b"new".to_vec());
+            assert_eq!(storage.get(key).unwrap(), expected);
+        }
+        assert_eq!(storage.get_at(b"filler", &snapshot).unwrap(), None);
+
+        // Sequence numbers carry on from the tables after a restart
+        let last_seq = storage.mem().seq();
+        drop(snapshot);
+        drop(storage);
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        assert_eq!(storage.mem().seq(), last_seq);
+        assert_eq!(storage.snapshot().seq(), last_seq);
+    }
+
+    #[test]
+    fn test_scan_range() {
+        let (_temp_dir, storage) = create_test_storage();
+        for i in 0..50 {
+            storage
+                .put(format!("key{:02}", i).into_bytes(), b"old".to_vec())
+                .unwrap();
+            if i % 20 == 19 {
+                storage.flush().unwrap();
+            }
+        }
+        // Newer writes and deletes, some flushed and some still buffered
+        storage.put(b"key10", b"new").unwrap();
+        storage.delete(b"key11").unwrap();
+        storage.flush().unwrap();
+        storage.put(b"key12", b"new").unwrap();
+        storage.delete(b"key13").unwrap();
+
+        let scanned: Vec<_> = storage
+            .scan(b"key09", b"key15")
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        let expected: Vec<(Key, Value)> = vec![
+            (b"key09".to_vec(), b"old".to_vec()),
+            (b"key10".to_vec(), b"new".to_vec()),
+            (b"key12".to_vec(), b"new".to_vec()),
+            (b"key14".to_vec(), b"old".to_vec()),
+        ];
+        assert_eq!(scanned, expected);
+
+        // An empty end runs to the last key
+        let tail: Vec<_> = storage.scan(b"key45", b"").unwrap().collect();
+        assert_eq!(tail.len(), 5);
+        assert_eq!(storage.scan(b"", b"").unwrap().count(), 48);
+    }
+
+    #[test]
+    fn test_scan_interleaved_flushes() {
+        let (_temp_dir, storage) = create_test_storage();
+        let key = |i: usize| format!("key{:05}", i).into_bytes();
+
+        // Keys arrive out of order and spread over several tables
+        let mut expected = std::collections::BTreeMap::new();
+        for n in 0..10_000 {
+            let i = (n * 7919) % 10_000;
+            let value = format!("v{}", n).into_bytes();
+            storage.put(key(i), value.clone()).unwrap();
+            expected.insert(key(i), value);
+            if n % 2000 == 1999 {
+                storage.flush().unwrap();
+            }
+        }
+        // Buffered updates shadow the flushed versions
+        for i in (4000..6000).step_by(7) {
+            storage.put(key(i), b"new".to_vec()).unwrap();
+            expected.insert(key(i), b"new".to_vec());
+        }
+        assert!(storage.levels().sstables.values().flatten().count() > 1);
+
+        let scanned: Vec<(Key, Value)> = storage
+            .scan(&key(2500), &key(7500))
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        let wanted: Vec<(Key, Value)> = expected
+            .range(key(2500)..key(7500))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        assert_eq!(scanned.len(), 5000);
+        assert_eq!(scanned, wanted);
+    }
+
+    #[test]
+    fn test_scan_rev_across_flushes() {
+        let (_temp_dir, storage) = create_test_storage();
+        let key = |i: usize| format!("key{:04}", i).into_bytes();
+        let mut expected = std::collections::BTreeMap::new();
+        for i in 0..2000 {
+            storage.put(key(i), b"old").unwrap();
+            expected.insert(key(i), b"old".to_vec());
+        }
+        storage.flush().unwrap();
+        // Newer versions on both sides of the flush: overwrites and deletes
+        // in a second table, then more of each still in the memtable
+        for i in (0..2000).step_by(3) {
+            storage.put(key(i), b"flushed").unwrap();
+            expected.insert(key(i), b"flushed".to_vec());
+        }
+        for i in (0..2000).step_by(5) {
+            storage.delete(key(i)).unwrap();
+            expected.remove(&key(i));
+        }
+        storage.flush().unwrap();
+        for i in (1..2000).step_by(4) {
+            storage.put(key(i), b"buffered").unwrap();
+            expected.insert(key(i), b"buffered".to_vec());
+        }
+        for i in (2..2000).step_by(7) {
+            storage.delete(key(i)).unwrap();
+            expected.remove(&key(i));
+        }
+        assert!(storage.levels().sstables.values().flatten().count() > 1);
+
+        let reversed: Vec<(Key, Value)> = storage
+            .scan_rev(&key(150), &key(1850))
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        let wanted: Vec<(Key, Value)> = expected
+            .range(key(150)..key(1850))
+            .rev()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        assert_eq!(reversed, wanted);
+        let mut forward: Vec<_> = storage
+            .scan(&key(150), &key(1850))
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        forward.reverse();
+        assert_eq!(reversed, forward);
+        assert_eq!(storage.scan_rev(b"", b"").unwrap().count(), expected.len());
+        assert_eq!(storage.scan_rev(&key(9000), b"").unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_count_range_matches_scan() {
+        let (_temp_dir, storage) = create_test_storage();
+
+        // A fixed-seed generator keeps the test reproducible
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut random = move |bound: u64| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % bound
+        };
+        for round in 0..6 {
+            for _ in 0..400 {
+                let key = format!("key{:04}", random(1000)).into_bytes();
+                if random(4) == 0 {
+                    storage.delete(&key).unwrap();
+                } else {
+                    storage.put(key, vec![b'v'; random(64) as usize]).unwrap();
+                }
+            }
+            if round < 5 {
+                storage.flush().unwrap();
+            }
+        }
+
+        let ranges: [(&[u8], &[u8]); 5] = [
+            (b"", b""),
+            (b"key0100", b"key0200"),
+            (b"key0500", b""),
+            (b"key0333", b"key0334"),
+            (b"a", b"b"),
+        ];
+        for (start, end) in ranges {
+            let scanned = storage.scan(start, end).unwrap().count() as u64;
+            assert_eq!(
+                storage.count_range(start, end).unwrap(),
+                scanned,
+                "range {:?}..{:?}",
+                String::from_utf8_lossy(start),
+                String::from_utf8_lossy(end)
+            );
+        }
+        assert_eq!(storage.count_range(b"a", b"b").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_approximate_size_of_range() {
+        let (_temp_dir, storage) = create_test_storage();
+        // Keys spread evenly over the whole keyspace
+        let key = |i: u64| (i * (u64::MAX / 4000)).to_be_bytes().to_vec();
+        for i in 0..4000 {
+            storage.put(key(i), vec![b'v'; 100]).unwrap();
+            if i % 1000 == 999 {
+                storage.flush().unwrap();
+            }
+        }
+        let total: u64 = storage
+            .levels()
+            .sstables
+            .values()
+            .flatten()
+            .map(|table| table.size() as u64)
+            .sum();
+        assert_eq!(storage.approximate_size_of_range(b"", b""), total);
+
+        for (from, to) in [(0, 1000), (1000, 3000), (500, 700)] {
+            let expected = total * (to - from) / 4000;
+            let estimate = storage.approximate_size_of_range(&key(from), &key(to));
+            assert!(
+                estimate.abs_diff(expected) < expected / 10,
+                "{}..{}: estimated {}, expected {}",
+                from,
+                to,
+                estimate,
+                expected
+            );
+        }
+
+        // Disjoint ranges, and the memtable's contribution
+        assert_eq!(storage.approximate_size_of_range(b"", &key(0)), 0);
+        storage.put(vec![0xff; 9], vec![b'v'; 100]).unwrap();
+        let past_end = storage.approximate_size_of_range(&[0xff; 9], b"");
+        assert_eq!(past_end, 109);
+    }
+
+    #[test]
+    fn test_approximate_len() {
+        let (_temp_dir, mut storage) = create_test_storage();
+        let key = |i: usize| format!("key{:04}", i).into_bytes();
+        assert_eq!(storage.approximate_len(), 0);
+        for i in 0..1000 {
+            storage.put(key(i), b"first").unwrap();
+        }
+        assert_eq!(storage.approximate_len(), 1000);
+        storage.flush().unwrap();
+        assert_eq!(storage.approximate_len(), 1000);
+
+        // Overwrites in another table count twice until compaction merges them
+        for i in 0..500 {
+            storage.put(key(i), b"second").unwrap();
+        }
+        storage.flush().unwrap();
+        storage.put(key(2000), b"new").unwrap();
+        assert_eq!(storage.approximate_len(), 1501);
+        storage.compact_all().unwrap();
+        assert_eq!(storage.approximate_len(), 1001);
+        assert_eq!(storage.count_range(b"", b"").unwrap(), 1001);
+    }
+
+    #[test]
+    fn test_count_range_skips_disjoint_tables() {
+        let (_temp_dir, storage) = create_test_storage();
+        for prefix in ["a", "b", "c"] {
+            for i in 0..100 {
+                let key = format!("{}{:03}", prefix, i).into_bytes();
+                storage.put(key, b"value".to_vec()).unwrap();
+            }
+            storage.flush().unwrap();
+        }
+        let tables = storage.levels().sstables[&0].clone();
+        let before: Vec<u64> = tables.iter().map(|t| t.data_passes()).collect();
+
+        assert_eq!(storage.count_range(b"b", b"c").unwrap(), 100);
+        let after: Vec<u64> = tables.iter().map(|t| t.data_passes()).collect();
+        assert_eq!(after[0], before[0]);
+        assert_eq!(after[1], before[1] + 1);
+        assert_eq!(after[2], before[2]);
+    }
+
+    fn page_keys(page: &[(Key, Value)]) -> Vec<Key> {
+        page.iter().map(|(key, _)| key.clone()).collect()
+    }
+
+    #[test]
+    fn test_scan_page() {
+        let (_temp_dir, storage) = create_test_storage();
+        for i in 0..10 {
+            storage
+                .put(format!("key{}", i).into_bytes(), b"v".to_vec())
+                .unwrap();
+        }
+        storage.flush().unwrap();
+
+        // A limit larger than the range returns everything and no token
+        let (page, token) = storage.scan_page(b"key2", b"key5", 100, None).unwrap();
+        assert_eq!(page_keys(&page), [b"key2", b"key3", b"key4"]);
+        assert_eq!(token, None);
+
+        // A range that fills the last page exactly also ends without a token
+        let (page, token) = storage.scan_page(b"key2", b"key4", 2, None).unwrap();
+        assert_eq!(page.len(), 2);
+        assert_eq!(token, None);
+
+        let (page, token) = storage.scan_page(b"", b"", 3, None).unwrap();
+        assert_eq!(page_keys(&page), [b"key0", b"key1", b"key2"]);
+        assert_eq!(token, Some(b"key2".to_vec()));
+
+        // A deleted key at the page boundary is skipped, not returned
+        storage.delete(b"key3").unwrap();
+        let (page, token) = storage.scan_page(b"", b"", 3, token).unwrap();
+        assert_eq!(page_keys(&page), [b"key4", b"key5", b"key6"]);
+
+        // Resuming after a token whose key was since deleted still works
+        storage.delete(b"key6").unwrap();
+        let (page, token) = storage.scan_page(b"", b"", 3, token).unwrap();
+        assert_eq!(page_keys(&page), [b"key7", b"key8", b"key9"]);
+        assert_eq!(token, None);
+
+        let err = storage.scan_page(b"", b"", 0, None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_scan_page_sees_later_writes_after_cursor_only() {
+        let (_temp_dir, storage) = create_test_storage();
+        for key in [b"b", b"d", b"f"] {
+            storage.put(key.to_vec(), b"v".to_vec()).unwrap();
+        }
+        let (page, token) = storage.scan_page(b"", b"", 2, None).unwrap();
+        assert_eq!(page_keys(&page), [b"b", b"d"]);
+
+        // Inserted before the cursor: missed. After it: picked up.
+        storage.put(b"a", b"v").unwrap();
+        storage.put(b"e", b"v").unwrap();
+        let (page, token) = storage.scan_page(b"", b"", 2, token).unwrap();
+        assert_eq!(page_keys(&page), [b"e", b"f"]);
+        assert_eq!(token, None);
+    }
+
+    #[test]
+    fn test_scan_outlives_compaction() {
+        let (_temp_dir, storage) = create_test_storage();
+        for i in 0..30 {
+            storage
+                .put(format!("key{:02}", i).into_bytes(), b"v1".to_vec())
+                .unwrap();
+            if i % 10 == 9 {
+                storage.flush().unwrap();
+            }
+        }
+        let old_files: Vec<PathBuf> = storage.levels().sstables[&0]
+            .iter()
+            .map(|table| table.get_path().clone())
+            .collect();
+
+        let mut scan = storage.scan(b"", b"").unwrap();
+        let (first, _) = scan.next().unwrap().unwrap();
+        assert_eq!(first, b"key00");
+
+        // Overwrite everything and push L0 through a compaction mid-scan
+        for i in 0..30 {
+            storage
+                .put(format!("key{:02}", i).into_bytes(), b"v2".to_vec())
+                .unwrap();
+        }
+        storage.flush().unwrap();
+        assert!(storage.levels().sstables[&0].is_empty());
+        assert!(old_files.iter().all(|path| path.exists()));
+
+        // The scan finishes on the view it started with
+        let rest: Vec<_> = scan.by_ref().collect::<io::Result<_>>().unwrap();
+        assert_eq!(rest.len(), 29);
+        assert!(rest.iter().all(|(_, value)| value == b"v1"));
+
+        // Only once it's gone are the replaced files removed
+        drop(scan);
+        assert!(old_files.iter().all(|path| !path.exists()));
+        let mut scan = storage.scan(b"", b"").unwrap();
+        assert_eq!(scan.next().unwrap().unwrap().1, b"v2");
+    }
+
+    #[test]
+    fn test_get_reports_corrupt_table() {
+        let (temp_dir, storage) = create_test_storage();
+        storage.put(b"a", b"value").unwrap();
+        storage.put(b"b", b"value").unwrap();
+        storage.flush().unwrap();
+
+        // Cut off the end of the last entry's value, along with the index
+        // that follows it
+        let path = storage.levels().sstables[&0][0].get_path().clone();
+        let bytes = fs::read(&path).unwrap();
+        let last_value = bytes.windows(5).rposition(|w| w == b"value").unwrap();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(last_value as u64 + 2)
+            .unwrap();
+
+        let err = storage.get(b"b").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        match err {
+            StorageError::Corruption { file, offset, .. } => {
+                assert_eq!(file, path);
+                assert!(offset > 0 && offset <= last_value as u64);
+            }
+            other => panic!("expected corruption, got {:?}", other),
+        }
+        assert_eq!(storage.stats().skipped_reads, 0);
+        drop(storage);
+
+        // Best-effort reads skip the table, but count it
+        let options = StorageOptions::default().best_effort_reads(true);
+        let storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+        assert_eq!(storage.get(b"b").unwrap(), None);
+        assert_eq!(storage.stats().skipped_reads, 1);
+    }
+
+    #[test]
+    fn test_level_inspection() {
+        let (_temp_dir, storage) = create_test_storage();
+        let key = b"key".to_vec();
+
+        // Push an old value down to L1, then overwrite it in L0
+        for i in 0..4 {
+            storage
+                .put(format!("other{}", i).into_bytes(), b"x".to_vec())
+                .unwrap();
+            if i == 0 {
+                storage.put(key.clone(), b"old".to_vec()).unwrap();
+            }
+            storage.flush().unwrap();
+        }
+        storage.put(key.clone(), b"new".to_vec()).unwrap();
+        storage.delete(b"other0").unwrap();
+        storage.flush().unwrap();
+        assert_eq!(storage.get(&key).unwrap(), Some(b"new".to_vec()));
+
+        let files = storage.level_files(1);
+        assert_eq!(files.len(), 1);
+        assert!(files[0]
+            .path
+            .ends_with(format!("L1_{}.sst", files[0].file_number)));
+        assert_eq!(files[0].entry_count, 5);
+        assert_eq!(
+            files[0].key_range,
+            Some((b"key".to_vec(), b"other3".to_vec()))
+        );
+        assert_eq!(files[0].size, storage.levels().sstables[&1][0].size());
+
+        // The raw L1 view still holds the value that reads no longer see
+        let level1: Vec<_> = storage
+            .iter_level(1)
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(level1.len(), 5);
+        assert_eq!(level1[0].0, key);
+        assert_eq!(level1[0].1.entry, Entry::Value(b"old".to_vec()));
+
+        // ...while L0 shows the overwrite and the tombstone
+        let level0: Vec<_> = storage
+            .iter_level(0)
+            .unwrap()
+            .map(|entry| entry.unwrap().1.entry)
+            .collect();
+        assert_eq!(level0.len(), 2);
+        assert_eq!(level0[0], Entry::Value(b"new".to_vec()));
+        assert!(level0[1].is_tombstone());
+        assert!(storage.level_files(5).is_empty());
+    }
+
+    fn fill_for_clear(storage: &mut Storage) -> Vec<Key> {
+        let mut keys = Vec::new();
+        for i in 0..20 {
+            let key = format!("key{:02}", i).into_bytes();
+            storage.put(key.clone(), b"value".to_vec()).unwrap();
+            if i % 5 == 4 {
+                storage.flush().unwrap();
+            }
+            keys.push(key);
+        }
+        keys
+    }
+
+    fn count_sst_files(dir: &Path) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().path().extension() == Some("sst".as_ref()))
+            .count()
+    }
+
+    #[test]
+    fn test_clear() {
+        let (temp_dir, mut storage) = create_test_storage();
+        let keys = fill_for_clear(&mut storage);
+        storage.put(b"unflushed", b"v").unwrap();
+        assert!(count_sst_files(temp_dir.path()) > 0);
+
+        storage.clear().unwrap();
+        for key in &keys {
+            assert_eq!(storage.get(key).unwrap(), None);
+        }
+        assert_eq!(storage.get(b"unflushed").unwrap(), None);
+        assert_eq!(count_sst_files(temp_dir.path()), 0);
+        assert!(storage.stats().levels.is_empty());
+        assert!(!temp_dir.path().join(CLEAR_MARKER).exists());
+
+        // The store keeps working normally afterwards, including across a restart
+        storage.put(b"after", b"clear").unwrap();
+        storage.flush().unwrap();
+        storage.put(b"buffered", b"write").unwrap();
+        drop(storage);
+
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        assert_eq!(storage.get(b"after").unwrap(), Some(b"clear".to_vec()));
+        assert_eq!(storage.get(b"buffered").unwrap(), Some(b"write".to_vec()));
+        for key in &keys {
+            assert_eq!(storage.get(key).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_clear_crash_after_marker() {
+        let (temp_dir, mut storage) = create_test_storage();
+        let keys = fill_for_clear(&mut storage);
+
+        // Crash once the marker is durable but only part of the data is gone
+        Storage::write_clear_marker(temp_dir.path()).unwrap();
+        let first_table = storage
+            .levels()
+            .sstables
+            .values()
+            .flatten()
+            .next()
+            .unwrap()
+            .get_path()
+            .clone();
+        fs::remove_file(first_table).unwrap();
+        drop(storage);
+
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        for key in &keys {
+            assert_eq!(storage.get(key).unwrap(), None);
+        }
+        assert_eq!(count_sst_files(temp_dir.path()), 0);
+        assert!(!temp_dir.path().join(CLEAR_MARKER).exists());
+    }
+
+    #[test]
+    fn test_clear_crash_before_marker() {
+        let (temp_dir, mut storage) = create_test_storage();
+        let keys = fill_for_clear(&mut storage);
+
+        // A marker that never got renamed into place doesn't count
+        fs::write(temp_dir.path().join("CLEAR.tmp"), b"").unwrap();
+        drop(storage);
+
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        for key in &keys {
+            assert_eq!(storage.get(key).unwrap(), Some(b"value".to_vec()));
+        }
+        assert!(!temp_dir.path().join("CLEAR.tmp").exists());
+    }
+
+    #[test]
+    fn test_half_written_table_removed_on_open() {
+        let (temp_dir, storage) = create_test_storage();
+        storage.put(b"key", b"value").unwrap();
+        storage.flush().unwrap();
+        let table = fs::read(storage.levels().sstables[&0][0].get_path()).unwrap();
+        drop(storage);
+
+        // A crash mid-write leaves the table under its temporary name
+        let tmp_path = temp_dir.path().join("L0_1.sst.tmp");
+        fs::write(&tmp_path, &table[..table.len() / 2]).unwrap();
+
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        assert!(!tmp_path.exists());
+        assert_eq!(storage.level_files(0).len(), 1);
+        assert_eq!(storage.get(b"key").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_cold_levels_in_level_dir() {
+        let temp_dir = TempDir::new().unwrap();
+        let cold_dir = TempDir::new().unwrap();
+        let options = StorageOptions::default().level_dir(2, cold_dir.path());
+        let storage = Storage::open_with_options(temp_dir.path(), options.clone()).unwrap();
+
+        // Two L0 compactions fill L1 past its threshold, pushing data to L2
+        let value = vec![b'x'; 1024];
+        for i in 0..5000 {
+            let key = format!("key{:05}", i).into_bytes();
+            storage.put(key, value.clone()).unwrap();
+        }
+        storage
+            .compact_until_settled(&mut storage.writer(), 0)
+            .unwrap();
+        assert!(!storage.level_files(2).is_empty());
+        for level in 0..2 {
+            for file in storage.level_files(level) {
+                assert!(file.path.starts_with(temp_dir.path()));
+            }
+        }
+        for file in storage.level_files(2) {
+            assert!(file.path.starts_with(cold_dir.path()));
+        }
+        assert_eq!(
+            count_sst_files(cold_dir.path()),
+            storage.level_files(2).len()
+        );
+
+        // Reads, including after a restart, see both directories
+        drop(storage);
+        let mut storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+        for i in (0..5000).step_by(250) {
+            let key = format!("key{:05}", i).into_bytes();
+            assert_eq!(storage.get(&key).unwrap(), Some(value.clone()));
+        }
+
+        storage.clear().unwrap();
+        assert_eq!(count_sst_files(temp_dir.path()), 0);
+        assert_eq!(count_sst_files(cold_dir.path()), 0);
+    }
+
+    #[test]
+    fn test_fadvise_leaves_compaction_output_unchanged() {
+        let mut outputs = Vec::new();
+        for fadvise in [false, true] {
+            let temp_dir = TempDir::new().unwrap();
+            let options = StorageOptions::default()
+                .fadvise(fadvise)
+                .clock(Arc::new(|| 0));
+            let storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+            let before = storage.stats().fadvise_calls;
+
+            for i in 0..4 {
+                for j in 0..100 {
+                    let key = format!("key{:03}", j).into_bytes();
+                    storage.put(key, format!("v{}", i).into_bytes()).unwrap();
+                }
+                storage.flush().unwrap();
+            }
+            let level1 = storage.level_files(1);
+            assert_eq!(level1.len(), 1);
+            outputs.push(fs::read(&level1[0].path).unwrap());
+
+            if fadvise && cfg!(target_os = "linux") {
+                assert!(storage.stats().fadvise_calls > before);
+            }
+        }
+        assert_eq!(outputs[0], outputs[1]);
+    }
+
+    #[test]
+    fn test_filters_sized_by_rate_and_table() {
+        let filter_bytes = |options: StorageOptions, keys: usize| {
+            let temp_dir = TempDir::new().unwrap();
+            let storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+            for i in 0..keys {
+                storage.put(format!("key{:05}", i), "value").unwrap();
+            }
+            storage.flush().unwrap();
+            assert_eq!(storage.get("key00000").unwrap(), Some(b"value".to_vec()));
+            storage.stats().levels[0].filter_bytes
+        };
+
+        // A tighter rate takes measurably more bits per key
+        let loose = filter_bytes(StorageOptions::default().bloom_fpr(0.05), 1000);
+        let default = filter_bytes(StorageOptions::default(), 1000);
+        let tight = filter_bytes(StorageOptions::default().bloom_fpr(0.0001), 1000);
+        assert!(
+            loose < default && default * 3 / 2 < tight,
+            "{} {} {}",
+            loose,
+            default,
+            tight
+        );
+        // and a small table gets a filter for the keys it holds
+        let small = filter_bytes(StorageOptions::default(), 10);
+        assert!(small * 50 < default, "{} {}", small, default);
+    }
+
+    #[test]
+    fn test_bottommost_tables_without_filters() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = StorageOptions::default().bottommost_bloom(false);
+        let mut storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+        for i in 0..300 {
+            storage.put(format!("key{:03}", i), "old").unwrap();
+        }
+        storage.compact_all().unwrap();
+        for i in 0..100 {
+            storage.put(format!("key{:03}", i), "new").unwrap();
+        }
+        storage.flush().unwrap();
+
+        let bottom = *storage.levels().sstables.keys().max().unwrap();
+        assert!(bottom > 0);
+        assert!(!storage.levels().sstables[&bottom][0].has_bloom_filter());
+        assert!(storage.levels().sstables[&0][0].has_bloom_filter());
+        let levels = storage.stats().levels;
+        assert!(levels
+            .iter()
+            .filter(|l| l.file_count > 0)
+            .all(|l| (l.filter_bytes == 0) == (l.level == bottom)));
+        for i in 0..300 {
+            let expected = if i < 100 { "new" } else { "old" };
+            let value = storage.get(format!("key{:03}", i)).unwrap();
+            assert_eq!(value, Some(expected.as_bytes().to_vec()));
+        }
+        assert_eq!(storage.get("missing").unwrap(), None);
+
+        // Turned back on, the next compaction to the bottom writes one
+        storage
+            .set_options(OptionsDelta::new().bottommost_bloom(true))
+            .unwrap();
+        storage.compact_all().unwrap();
+        let bottom = *storage.levels().sstables.keys().max().unwrap();
+        assert!(storage.levels().sstables[&bottom][0].has_bloom_filter());
+    }
+
+    #[test]
+    fn test_bloom_filters_disabled() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = StorageOptions::default().bloom(None);
+        let storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+        for round in 0..3 {
+            for i in 0..100 {
+                let key = format!("key{:03}", round * 100 + i).into_bytes();
+                storage.put(key, format!("v{}", i).into_bytes()).unwrap();
+            }
+            storage.flush().unwrap();
+        }
+        assert!(storage.levels().sstables[&0]
+            .iter()
+            .all(|table| !table.has_bloom_filter()));
+        assert!(storage.stats().levels.iter().all(|l| l.filter_bytes == 0));
+        assert_eq!(storage.get(b"key150").unwrap(), Some(b"v50".to_vec()));
+        assert_eq!(storage.get(b"missing").unwrap(), None);
+
+        // Tables written without filters stay readable once they're back on
+        drop(storage);
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        assert!(storage.levels().sstables[&0]
+            .iter()
+            .all(|table| !table.has_bloom_filter()));
+        assert_eq!(storage.get(b"key250").unwrap(), Some(b"v50".to_vec()));
+
+        // Mixed with a filtered table, compaction output regains a filter
+        storage.put(b"key999", b"last").unwrap();
+        storage.flush().unwrap();
+        assert!(storage.levels().sstables[&0].is_empty());
+        assert!(storage.levels().sstables[&1][0].has_bloom_filter());
+        for i in (0..300).step_by(30) {
+            let key = format!("key{:03}", i).into_bytes();
+            assert_eq!(
+                storage.get(&key).unwrap(),
+                Some(format!("v{}", i % 100).into_bytes())
+            );
+        }
+        assert_eq!(storage.get(b"key999").unwrap(), Some(b"last".to_vec()));
+    }
+
+    /// About 6MB of interleaved tables, evicted from the page cache
+    fn scan_storage(read_ahead: u64) -> (TempDir, Storage) {
+        let temp_dir = TempDir::new().unwrap();
+        let options = StorageOptions::default().read_ahead(read_ahead);
+        let storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+        for round in 0..3 {
+            for i in 0..2000 {
+                let key = format!("key{:05}", i * 3 + round).into_bytes();
+                storage.put(key, vec![b'a' + round as u8; 1000]).unwrap();
+            }
+        }
+        storage.flush().unwrap();
+        for table in storage.levels().sstables.values().flatten() {
+            table.release_cache().unwrap();
+        }
+        (temp_dir, storage)
+    }
+
+    #[test]
+    fn test_read_ahead_leaves_scans_unchanged() {
+        let (_plain_dir, plain) = scan_storage(0);
+        let (_ahead_dir, ahead) = scan_storage(256 * 1024);
+
+        let before = advise_calls();
+        let expected: Vec<_> = plain.scan(b"", b"").unwrap().map(Result::unwrap).collect();
+        let scanned: Vec<_> = ahead.scan(b"", b"").unwrap().map(Result::unwrap).collect();
+        assert_eq!(expected.len(), 6000);
+        assert_eq!(scanned, expected);
+        if cfg!(target_os = "linux") {
+            // At least the 64KB, 128KB and 256KB windows of the largest table
+            assert!(advise_calls() - before >= 3);
+        }
+
+        let from: Vec<_> = ahead.scan(b"key03000", b"key03100").unwrap().collect();
+        assert_eq!(from.len(), 100);
+
+        // Point reads don't prefetch
+        let before = ahead.stats().fadvise_calls;
+        for i in (0..6000).step_by(500) {
+            let key = format!("key{:05}", i).into_bytes();
+            assert!(ahead.get(&key).unwrap().is_some());
+        }
+        assert_eq!(ahead.stats().fadvise_calls, before);
+    }
+
+    /// Full-scan throughput from a cold page cache with and without
+    /// read-ahead. Run with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_scan_read_ahead() {
+        for read_ahead in [0, 2 * 1024 * 1024] {
+            let (_temp_dir, storage) = scan_storage(read_ahead);
+            let start = Instant::now();
+            let bytes: usize = storage
+                .scan(b"", b"")
+                .unwrap()
+                .map(|pair| pair.unwrap().1.len())
+                .sum();
+            let elapsed = start.elapsed();
+            println!(
+                "read_ahead {:>8}: {:.1} MB/s",
+                read_ahead,
+                bytes as f64 / 1_048_576.0 / elapsed.as_secs_f64()
+            );
+        }
+    }
+
+    #[test]
+    fn test_bad_compaction_output_keeps_inputs() {
+        let temp_dir = TempDir::new().unwrap();
+        let truncate: options::TableHook = Arc::new(|path: &Path| {
+            let file = fs::OpenOptions::new().write(true).open(path).unwrap();
+            let len = file.metadata().unwrap().len();
+            file.set_len(len - 3).unwrap();
+        });
+        let options = StorageOptions {
+            compaction_output_hook: Some(truncate),
+            ..Default::default()
+        };
+        let storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+
+        let mut result = Ok(());
+        for round in 0..4 {
+            for i in 0..50 {
+                let key = format!("key{:03}", round * 50 + i).into_bytes();
+                storage
+                    .put(key, format!("v{}", round).into_bytes())
+                    .unwrap();
+            }
+            result = storage.flush();
+        }
+        let err = result.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("failed verification"));
+
+        // The inputs are still live and the output is gone
+        assert_eq!(storage.level_files(0).len(), 4);
+        assert!(storage.level_files(1).is_empty());
+        let leftovers: Vec<_> = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with("L1_"))
+            .collect();
+        assert!(leftovers.is_empty(), "{:?}", leftovers);
+        for round in 0..4 {
+            let key = format!("key{:03}", round * 50 + 7).into_bytes();
+            assert_eq!(
+                storage.get(&key).unwrap(),
+                Some(format!("v{}", round).into_bytes())
+            );
+        }
+        let event = storage.recent_events(1).unwrap().pop().unwrap();
+        assert!(event.error.unwrap().contains("failed verification"));
+
+        // A good write compacts them as usual
+        drop(storage);
+        let mut storage = Storage::new(temp_dir.path(), false).unwrap();
+        storage.compact_level(0).unwrap();
+        assert!(storage.level_files(0).is_empty());
+        assert_eq!(storage.get(b"key157").unwrap(), Some(b"v3".to_vec()));
+    }
+
+    #[test]
+    fn test_flush_and_close_persist_memtable() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        assert!(!storage.flush_pending());
+        storage.flush().unwrap();
+        assert!(storage
+            .levels()
+            .sstables
+            .values()
+            .all(|tables| tables.is_empty()));
+        for key in [b"a", b"b", b"c"] {
+            storage.put(key.to_vec(), key.to_vec()).unwrap();
+        }
+        assert!(storage.flush_pending());
+        storage.flush().unwrap();
+        assert!(!storage.flush_pending());
+        assert!(storage.mem().memtable.is_empty());
+        assert!(temp_dir.path().join("L0_0.sst").exists());
+        assert!(storage.writer().wal.replay().unwrap().is_empty());
+
+        storage.put(b"d", b"d").unwrap();
+        storage.close().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        assert!(storage.mem().memtable.is_empty());
+        assert_eq!(storage.levels().sstables[&0].len(), 2);
+        for key in [b"a", b"b", b"c", b"d"] {
+            assert_eq!(storage.get(key).unwrap(), Some(key.to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_multi_get_matches_get() {
+        let (_temp_dir, storage) = create_test_storage();
+
+        // Spread overwrites and deletes across several tables and the memtable
+        for round in 0..3 {
+            for i in (round..1000).step_by(round + 1) {
+                let key = format!("key{:04}", i).into_bytes();
+                if i % 11 == round {
+                    storage.delete(&key).unwrap();
+                } else {
+                    storage
+                        .put(key, format!("v{}_{}", round, i).into_bytes())
+                        .unwrap();
+                }
+            }
+            storage.flush().unwrap();
+        }
+        storage.put(b"key0500", b"fresh").unwrap();
+        storage.delete(b"key0501").unwrap();
+
+        // Unsorted, with duplicates and keys that were never written
+        let mut keys: Vec<Key> = (0..1000)
+            .rev()
+            .map(|i| format!("key{:04}", (i * 7) % 1000).into_bytes())
+            .collect();
+        keys.extend([
+            b"key0500".to_vec(),
+            b"missing".to_vec(),
+            b"key0003".to_vec(),
+        ]);
+
+        let passes = |storage: &Storage| -> Vec<u64> {
+            storage
+                .levels()
+                .sstables
+                .values()
+                .flatten()
+                .map(|table| table.data_passes())
+                .collect()
+        };
+        let before = passes(&storage);
+        let results = storage.multi_get(&keys).unwrap();
+        for (pass_before, pass_after) in before.iter().zip(passes(&storage)) {
+            assert!(pass_after - pass_before <= 1);
+        }
+
+        assert_eq!(results.len(), keys.len());
+        for (key, result) in keys.iter().zip(&results) {
+            assert_eq!(result, &storage.get(key).unwrap());
+        }
+        assert_eq!(results[keys.len() - 3], Some(b"fresh".to_vec()));
+        assert_eq!(results[keys.len() - 2], None);
+    }
+
+    /// Multi-get throughput over tables too large for the block cache,
+    /// reading through a pass over each table against one io_uring batch of
+    /// blocks. Run with `cargo test --release --features iouring --
+    /// --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_multi_get_read_paths() {
+        for entries in [0, 256] {
+            let temp_dir = TempDir::new().unwrap();
+            let options = StorageOptions::default()
+                .io_uring_entries(entries)
+                .block_cache_size(0);
+            let storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+            for i in 0..200_000 {
+                let key = format!("key{:07}", i).into_bytes();
+                storage.put(key, vec![b'v'; 100]).unwrap();
+            }
+            storage.flush().unwrap();
+
+            let batches: Vec<Vec<Key>> = (0..200)
+                .map(|batch| {
+                    (0..100)
+                        .map(|i| format!("key{:07}", (batch * 7919 + i * 1999) % 200_000))
+                        .map(String::into_bytes)
+                        .collect()
+                })
+                .collect();
+            let start = Instant::now();
+            for keys in &batches {
+                storage.multi_get(keys).unwrap();
+            }
+            let elapsed = start.elapsed();
+            println!(
+                "io_uring entries {:>3}: {:.0} keys/s",
+                entries,
+                (batches.len() * 100) as f64 / elapsed.as_secs_f64()
+            );
+        }
+    }
+
+    #[test]
+    fn test_get_into_reuses_buffer() {
+        let (_temp_dir, storage) = create_test_storage();
+
+        // Enough data to push most keys out of the memtable
+        let value = vec![b'v'; 1024];
+        for i in 0..600 {
+            storage
+                .put(format!("key{:04}", i).into_bytes(), value.clone())
+                .unwrap();
+        }
+        storage.wait_for_flush(&mut storage.writer()).unwrap();
+        assert!(!storage.levels().sstables.is_empty());
+
+        let mut out = Vec::with_capacity(value.len());
+        let ptr = out.as_ptr();
+        for i in (0..600).step_by(50) {
+            let key = format!("key{:04}", i).into_bytes();
+            assert!(storage.get_into(&key, &mut out).unwrap());
+            assert_eq!(out, value);
+        }
+        assert!(!storage.get_into(b"missing", &mut out).unwrap());
+
+        // Reads from both the memtable and SSTables reused the same buffer
+        assert_eq!(out.as_ptr(), ptr);
+        assert_eq!(out.capacity(), value.len());
+    }
+
+    #[test]
+    fn test_block_cache_serves_repeated_gets() {
+        for cache_size in [0, 1024 * 1024] {
+            let temp_dir = TempDir::new().unwrap();
+            let options = StorageOptions::default().block_cache_size(cache_size);
+            let mut storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+            for i in 0..100 {
+                let key = format!("key{:03}", i).into_bytes();
+                storage.put(key, b"value".to_vec()).unwrap();
+            }
+            storage.flush().unwrap();
+            let table = storage.levels().sstables[&0][0].clone();
+
+            for _ in 0..5 {
+                assert_eq!(storage.get(b"key042").unwrap(), Some(b"value".to_vec()));
+            }
+            let stats = storage.stats().block_cache;
+            if cache_size == 0 {
+                assert_eq!(table.data_passes(), 5);
+                assert_eq!(stats, CacheStats::default());
+                continue;
+            }
+            assert_eq!(table.data_passes(), 1);
+            assert_eq!((stats.hits, stats.misses), (4, 1));
+            assert!(stats.bytes > 0);
+
+            // Compaction drops the blocks of the tables it replaced
+            storage.compact_level(0).unwrap();
+            drop(table);
+            assert_eq!(storage.stats().block_cache.bytes, 0);
+            assert_eq!(storage.get(b"key042").unwrap(), Some(b"value".to_vec()));
+            assert_eq!(storage.stats().block_cache.misses, 2);
+        }
+    }
+
+    #[test]
+    fn test_stats_size_distribution() {
+        let (_temp_dir, storage) = create_test_storage();
+
+        // Bimodal workload: many tiny values and a few huge ones
+        let tiny = vec![b't'; 8];
+        let huge = vec![b'h'; 64 * 1024];
+        for i in 0..400 {
+            let key = format!("key{:04}", i).into_bytes();
+            let value = if i % 40 == 0 { &huge } else { &tiny };
+            storage.put(key, value.clone()).unwrap();
+        }
+        storage.wait_for_flush(&mut storage.writer()).unwrap();
+
+        let stats = storage.stats();
+        let written = &stats.written_value_sizes;
+        assert_eq!(written.count(), 400);
+        assert_eq!(written.min(), 8);
+        assert_eq!(written.max(), 64 * 1024);
+        assert_eq!(written.buckets()[SizeHistogram::bucket_for(8)], 390);
+        assert_eq!(written.buckets()[SizeHistogram::bucket_for(64 * 1024)], 10);
+        // Nothing in between the two modes
+        let between = SizeHistogram::bucket_for(8) + 1..SizeHistogram::bucket_for(64 * 1024);
+        assert!(written.buckets()[between].iter().all(|&b| b == 0));
+        assert_eq!(stats.written_key_sizes.min(), 7);
+
+        // The flushed tables must describe exactly what they hold
+        assert!(!stats.levels.is_empty());
+        for tables in storage.levels().sstables.values() {
+            for table in tables {
+                let entries = table.read().unwrap();
+                let props = table.properties();
+                assert_eq!(props.entry_count, entries.len() as u64);
+                let value_bytes: usize = entries.iter().map(|(_, v)| v.len()).sum();
+                assert_eq!(props.value_sizes.total(), value_bytes as u64);
+                let max_value = entries.iter().map(|(_, v)| v.len()).max().unwrap();
+                assert_eq!(props.value_sizes.max(), max_value as u64);
+            }
+        }
+
+        for level_stats in &stats.levels {
+            let tables = &storage.levels().sstables[&level_stats.level];
+            assert_eq!(level_stats.file_count, tables.len());
+            let bytes: usize = tables.iter().map(|t| t.size()).sum();
+            assert_eq!(level_stats.total_bytes, bytes);
+        }
+
+        let stored_entries: u64 = stats.levels.iter().map(|l| l.properties.entry_count).sum();
+        assert_eq!(stats.stored.entry_count, stored_entries);
+        assert!(stats.stored.value_sizes.buckets()[SizeHistogram::bucket_for(8)] > 0);
+        assert!(stats.stored.value_sizes.buckets()[SizeHistogram::bucket_for(64 * 1024)] > 0);
+    }
 }
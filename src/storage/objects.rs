@@ -0,0 +1,268 @@
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use log::info;
+
+use super::manifest::{Manifest, ManifestState, MANIFEST_FILE};
+use crate::objectfs::ObjectFs;
+use crate::sstable::SSTable;
+
+/// Open live table `path` from `objects`, uploading it first if it's still
+/// on local disk: written before the store was configured, or committed
+/// by a bulk load that a crash stopped short of uploading
+pub(super) fn open_table(objects: &Arc<ObjectFs>, path: PathBuf) -> io::Result<SSTable> {
+    if path.is_file() {
+        objects.upload(&path)?;
+    }
+    SSTable::open_object(path.clone(), objects.clone()).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "Table {:?} listed in {} is missing from the object store",
+                path, MANIFEST_FILE
+            ),
+        ),
+        _ => e,
+    })
+}
+
+/// Keep a copy of `manifest` in `objects`, then delete every object
+/// `state` doesn't list: output uploaded just before a crash, or a replaced
+/// table whose deletion one cut short
+pub(super) fn attach(
+    objects: &Arc<ObjectFs>,
+    manifest: Manifest,
+    state: &ManifestState,
+    data_dir: &Path,
+) -> io::Result<Manifest> {
+    let manifest = manifest.mirror_to(objects.clone(), data_dir)?;
+    let live: HashSet<&Path> = state
+        .tables
+        .values()
+        .map(|(path, _)| path.as_path())
+        .collect();
+    for name in objects.names()? {
+        if name != MANIFEST_FILE && !live.contains(Path::new(&name)) {
+            info!("Removing garbage object {}", name);
+            objects.delete(&name)?;
+        }
+    }
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objectfs::CHUNK_SIZE;
+    use crate::storage::{Storage, StorageOptions};
+    use object_store::memory::InMemory;
+    use object_store::ObjectStore;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("key{:05}", i).into_bytes()
+    }
+
+    fn open(dir: &Path, objects: &Arc<ObjectFs>) -> Storage {
+        let options = StorageOptions::default()
+            .memtable_size(4 * 1024)
+            .object_fs(objects.clone());
+        Storage::open_with_options(dir, options).unwrap()
+    }
+
+    /// A cache over `store` in a directory of its own, as on another machine
+    fn objects_over(store: &Arc<InMemory>, cache_size: u64) -> (TempDir, Arc<ObjectFs>) {
+        let cache_dir = TempDir::new().unwrap();
+        let store: Arc<dyn ObjectStore> = store.clone();
+        let objects = ObjectFs::new(store, cache_dir.path(), cache_size).unwrap();
+        (cache_dir, Arc::new(objects))
+    }
+
+    fn sorted_names(objects: &ObjectFs) -> Vec<String> {
+        let mut names = objects.names().unwrap();
+        names.sort();
+        names
+    }
+
+    /// The manifest and the objects of every table `storage` reads
+    fn live_names(storage: &Storage) -> Vec<String> {
+        let mut names: Vec<String> = storage
+            .levels()
+            .sstables
+            .values()
+            .flatten()
+            .map(|table| {
+                table
+                    .get_path()
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into()
+            })
+            .collect();
+        names.push(MANIFEST_FILE.to_string());
+        names.sort();
+        names
+    }
+
+    fn local_tables(dir: &Path) -> Vec<PathBuf> {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "sst"))
+            .collect()
+    }
+
+    #[test]
+    fn test_flush_uploads_tables() {
+        let temp_dir = TempDir::new().unwrap();
+        let objects = Arc::new(ObjectFs::in_memory(temp_dir.path().join("cache")).unwrap());
+        let storage = open(&temp_dir.path().join("db"), &objects);
+        for i in 0..40 {
+            storage.put(key(i), vec![b'v'; 64]).unwrap();
+        }
+        storage.flush().unwrap();
+
+        assert_eq!(storage.level_files(0).len(), 1);
+        assert!(local_tables(&temp_dir.path().join("db")).is_empty());
+        assert_eq!(sorted_names(&objects), live_names(&storage));
+        for i in 0..40 {
+            assert_eq!(storage.get(key(i)).unwrap(), Some(vec![b'v'; 64]));
+        }
+        assert!(storage.verify().is_ok());
+        // The upload left the table's chunks in the cache
+        assert_eq!(objects.range_reads(), 0);
+    }
+
+    #[test]
+    fn test_reads_go_through_the_cache() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = Arc::new(InMemory::new());
+        // Room for a single chunk, so the tables don't fit
+        let (_cache, objects) = objects_over(&store, CHUNK_SIZE);
+        let options = StorageOptions::default()
+            .memtable_size(64 * 1024)
+            .block_cache_size(0)
+            .object_fs(objects.clone());
+        let storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+        for i in 0..5000 {
+            storage.put(key(i), vec![b'v'; 100]).unwrap();
+        }
+        storage.flush().unwrap();
+        let size: usize = storage
+            .levels()
+            .sstables
+            .values()
+            .flatten()
+            .map(|t| t.size())
+            .sum();
+        assert!(size as u64 > 2 * CHUNK_SIZE);
+
+        let fetched = objects.range_reads();
+        assert_eq!(storage.get(key(0)).unwrap(), Some(vec![b'v'; 100]));
+        let after_first = objects.range_reads();
+        assert!(after_first > fetched);
+        assert_eq!(storage.get(key(0)).unwrap(), Some(vec![b'v'; 100]));
+        assert_eq!(objects.range_reads(), after_first);
+    }
+
+    #[test]
+    fn test_compaction_deletes_replaced_objects() {
+        let temp_dir = TempDir::new().unwrap();
+        let objects = Arc::new(ObjectFs::in_memory(temp_dir.path().join("cache")).unwrap());
+        let storage = open(&temp_dir.path().join("db"), &objects);
+        for round in 0..3 {
+            for i in 0..1000 {
+                storage
+                    .put(key(i), format!("v{}-{}", round, i).into_bytes())
+                    .unwrap();
+            }
+        }
+        storage.flush().unwrap();
+        storage
+            .compact_until_settled(&mut storage.writer(), 0)
+            .unwrap();
+
+        assert!(storage.level_files(1).len() + storage.level_files(2).len() > 0);
+        assert!(local_tables(&temp_dir.path().join("db")).is_empty());
+        // Replaced tables were deleted once nothing read them
+        assert_eq!(sorted_names(&objects), live_names(&storage));
+        for i in 0..1000 {
+            let value = format!("v2-{}", i).into_bytes();
+            assert_eq!(storage.get(key(i)).unwrap(), Some(value));
+        }
+        let scanned = storage.scan(b"", b"").unwrap().count();
+        assert_eq!(scanned, 1000);
+    }
+
+    #[test]
+    fn test_open_from_scratch() {
+        let store = Arc::new(InMemory::new());
+        let first = TempDir::new().unwrap();
+        let (_cache, objects) = objects_over(&store, 64 * CHUNK_SIZE);
+        let storage = open(first.path(), &objects);
+        for i in 0..2000 {
+            storage.put(key(i), vec![b'a'; 50]).unwrap();
+        }
+        storage.flush().unwrap();
+        let live = live_names(&storage);
+        drop(storage);
+
+        // Output a crash left unlisted is garbage
+        fs::write(first.path().join("L0_999.sst"), b"unlisted").unwrap();
+        objects.upload(&first.path().join("L0_999.sst")).unwrap();
+
+        // A new machine has nothing but the store
+        let second = TempDir::new().unwrap();
+        let (_cache, objects) = objects_over(&store, 64 * CHUNK_SIZE);
+        let storage = open(second.path(), &objects);
+        assert_eq!(live_names(&storage), live);
+        assert_eq!(sorted_names(&objects), live);
+        for i in 0..2000 {
+            assert_eq!(storage.get(key(i)).unwrap(), Some(vec![b'a'; 50]));
+        }
+
+        // It carries on from where the first left off
+        for i in 0..2000 {
+            storage.put(key(i), vec![b'b'; 50]).unwrap();
+        }
+        storage.flush().unwrap();
+        storage
+            .compact_until_settled(&mut storage.writer(), 0)
+            .unwrap();
+        let live = live_names(&storage);
+        drop(storage);
+        let third = TempDir::new().unwrap();
+        let (_cache, objects) = objects_over(&store, 64 * CHUNK_SIZE);
+        let storage = open(third.path(), &objects);
+        assert_eq!(live_names(&storage), live);
+        for i in 0..2000 {
+            assert_eq!(storage.get(key(i)).unwrap(), Some(vec![b'b'; 50]));
+        }
+    }
+
+    #[test]
+    fn test_local_tables_move_to_the_store() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        storage.put(b"key", b"local").unwrap();
+        storage.flush().unwrap();
+        drop(storage);
+
+        let objects = Arc::new(ObjectFs::in_memory(temp_dir.path().join("cache")).unwrap());
+        let mut storage = open(temp_dir.path(), &objects);
+        assert!(local_tables(temp_dir.path()).is_empty());
+        assert_eq!(sorted_names(&objects), live_names(&storage));
+        assert_eq!(storage.get(b"key").unwrap(), Some(b"local".to_vec()));
+
+        let dest = TempDir::new().unwrap();
+        let refused = storage.checkpoint(dest.path().join("copy")).unwrap_err();
+        assert_eq!(refused.kind(), io::ErrorKind::Unsupported);
+        storage.clear().unwrap();
+        assert_eq!(sorted_names(&objects), [MANIFEST_FILE]);
+        assert_eq!(storage.get(b"key").unwrap(), None);
+    }
+}
@@ -0,0 +1,690 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::identity::DEFAULT_COMPARATOR;
+use super::{COMPACTION_SIZE_THRESHOLD, LEVEL_MULTIPLIER, MEMTABLE_SIZE_THRESHOLD};
+use crate::bloom::BloomConfig;
+use crate::entry::MergeOperator;
+#[cfg(feature = "object-store")]
+use crate::objectfs::ObjectFs;
+use crate::sstable::{Compression, IoMode, SSTable, L0_COMPACTION_FILES};
+use crate::wal::SyncPolicy;
+
+/// Source of wall-clock time in milliseconds since the Unix epoch.
+/// Injectable so tests can control time-dependent behavior.
+pub type Clock = Arc<dyn Fn() -> u64 + Send + Sync>;
+
+/// Called with the path of a table just written
+#[cfg(test)]
+pub(super) type TableHook = Arc<dyn Fn(&Path) + Send + Sync>;
+
+/// Called as a flush passes each step; an error stops it there, as a crash
+/// would
+#[cfg(test)]
+pub(super) type FlushHook = Arc<dyn Fn(super::flush::FlushStep) -> io::Result<()> + Send + Sync>;
+
+// Deepest level that sorted bulk loads write into by default
+const DEFAULT_BOTTOM_LEVEL: usize = 6;
+// Point reads the io_uring keeps in flight at once by default
+const DEFAULT_IO_URING_ENTRIES: u32 = 64;
+// Bytes of data blocks point reads keep cached by default
+const DEFAULT_BLOCK_CACHE_SIZE: usize = 8 * 1024 * 1024;
+// Size past which the WAL moves on to a new segment by default
+const DEFAULT_WAL_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+// L0 tables at which writes are slowed, and stopped, by default
+const DEFAULT_L0_SLOWDOWN_FILES: usize = 20;
+const DEFAULT_L0_STOP_FILES: usize = 36;
+
+/// Milliseconds since the Unix epoch according to the system clock
+pub fn system_clock() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// How tables are kept in check as flushes add them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CompactionStrategy {
+    /// Merge each full level into the next (the default)
+    #[default]
+    Leveled,
+    /// Never merge: flushes stay in L0 and the oldest tables, by file
+    /// number, are deleted whenever all tables together exceed
+    /// `max_fifo_bytes`. Meant for logs and caches that only need the
+    /// newest data. A tombstone is dropped only with its table, by which
+    /// time every older table it could shadow is already gone.
+    Fifo { max_fifo_bytes: u64 },
+}
+
+/// Tunables for a [`Storage`](super::Storage) instance
+#[derive(Clone)]
+pub struct StorageOptions {
+    pub(super) verbose: bool,
+    pub(super) tombstone_retention: Duration,
+    pub(super) clock: Clock,
+    pub(super) merge_operator: Option<MergeOperator>,
+    pub(super) bottom_level: usize,
+    pub(super) best_effort_reads: bool,
+    pub(super) level_dirs: Vec<(usize, PathBuf)>, // (min level, dir), ascending
+    pub(super) direct_io: bool,
+    pub(super) fadvise: bool,
+    #[cfg_attr(not(all(feature = "iouring", target_os = "linux")), allow(dead_code))]
+    pub(super) io_uring_entries: u32,
+    pub(super) block_cache_size: usize,
+    pub(super) comparator_name: String,
+    pub(super) bloom: Option<BloomConfig>,
+    pub(super) bottommost_bloom: bool,
+    pub(super) compression: Compression,
+    pub(super) slow_read_threshold: Option<Duration>,
+    pub(super) journal_slow_reads: bool,
+    pub(super) read_ahead: u64,
+    pub(super) verify_compaction_output: bool,
+    pub(super) max_total_bytes: Option<u64>,
+    pub(super) compaction_strategy: CompactionStrategy,
+    pub(super) memtable_size: usize,
+    pub(super) l0_compaction_files: usize,
+    pub(super) l0_slowdown_files: usize,
+    pub(super) l0_stop_files: usize,
+    pub(super) level_size_base: usize,
+    pub(super) level_multiplier: u32,
+    pub(super) max_background_compactions: usize,
+    pub(super) large_batch_bytes: Option<usize>,
+    pub(super) wal_sync: SyncPolicy,
+    pub(super) wal_segment_size: u64,
+    #[cfg(feature = "object-store")]
+    pub(super) object_fs: Option<Arc<ObjectFs>>,
+    // Runs on each compaction output before it's verified, to simulate
+    // a bad write
+    #[cfg(test)]
+    pub(super) compaction_output_hook: Option<TableHook>,
+    #[cfg(test)]
+    pub(super) flush_hook: Option<FlushHook>,
+}
+
+impl Default for StorageOptions {
+    fn default() -> Self {
+        StorageOptions {
+            verbose: false,
+            tombstone_retention: Duration::ZERO,
+            clock: Arc::new(system_clock),
+            merge_operator: None,
+            bottom_level: DEFAULT_BOTTOM_LEVEL,
+            best_effort_reads: false,
+            level_dirs: Vec::new(),
+            direct_io: false,
+            fadvise: cfg!(target_os = "linux"),
+            io_uring_entries: DEFAULT_IO_URING_ENTRIES,
+            block_cache_size: DEFAULT_BLOCK_CACHE_SIZE,
+            comparator_name: DEFAULT_COMPARATOR.to_string(),
+            bloom: Some(BloomConfig::default()),
+            bottommost_bloom: true,
+            compression: Compression::None,
+            slow_read_threshold: None,
+            journal_slow_reads: false,
+            read_ahead: 0,
+            verify_compaction_output: true,
+            max_total_bytes: None,
+            compaction_strategy: CompactionStrategy::Leveled,
+            memtable_size: MEMTABLE_SIZE_THRESHOLD,
+            l0_compaction_files: L0_COMPACTION_FILES,
+            l0_slowdown_files: DEFAULT_L0_SLOWDOWN_FILES,
+            l0_stop_files: DEFAULT_L0_STOP_FILES,
+            level_size_base: COMPACTION_SIZE_THRESHOLD,
+            level_multiplier: LEVEL_MULTIPLIER,
+            max_background_compactions: 1,
+            large_batch_bytes: None,
+            wal_sync: SyncPolicy::Never,
+            wal_segment_size: DEFAULT_WAL_SEGMENT_SIZE,
+            #[cfg(feature = "object-store")]
+            object_fs: None,
+            #[cfg(test)]
+            compaction_output_hook: None,
+            #[cfg(test)]
+            flush_hook: None,
+        }
+    }
+}
+
+impl StorageOptions {
+    /// Trace each read and write at debug level through the `log` crate.
+    /// Nothing reaches the log without a logger installed.
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// How long the value shadowed by a delete stays recoverable through
+    /// `Storage::undelete`. Zero (the default) discards it at the next flush.
+    pub fn tombstone_retention(mut self, retention: Duration) -> Self {
+        self.tombstone_retention = retention;
+        self
+    }
+
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// How `Storage::merge` operands combine with the value beneath them.
+    /// Reads of a key with operands fail without one.
+    pub fn merge_operator(mut self, merge: MergeOperator) -> Self {
+        self.merge_operator = Some(merge);
+        self
+    }
+
+    /// Deepest level `Storage::bulk_load_sorted` places data in
+    pub fn bottom_level(mut self, level: usize) -> Self {
+        self.bottom_level = level;
+        self
+    }
+
+    /// Treat an SSTable that fails to read as not holding the key, instead of
+    /// failing the read. Every skip is logged and counted in `Storage::stats`.
+    pub fn best_effort_reads(mut self, best_effort: bool) -> Self {
+        self.best_effort_reads = best_effort;
+        self
+    }
+
+    /// Store tables at `min_level` and deeper in `dir` instead of the data
+    /// directory, e.g. to keep cold levels on a cheaper device. May be given
+    /// several times; each level uses the entry with the highest `min_level`
+    /// not above it. `dir` must be configured on every open, since it is
+    /// scanned for tables alongside the data directory.
+    pub fn level_dir(mut self, min_level: usize, dir: impl Into<PathBuf>) -> Self {
+        self.level_dirs.retain(|(level, _)| *level != min_level);
+        self.level_dirs.push((min_level, dir.into()));
+        self.level_dirs.sort_by_key(|(level, _)| *level);
+        self
+    }
+
+    /// Write flush and compaction output, and read compaction input, with
+    /// O_DIRECT so they bypass the page cache. Only takes effect on Linux
+    /// filesystems that support it; point reads are always cached.
+    pub fn direct_io(mut self, direct: bool) -> Self {
+        self.direct_io = direct;
+        self
+    }
+
+    /// Issue `posix_fadvise` hints so compaction's one-pass reads and
+    /// writes don't evict hot pages. On by default where supported and a
+    /// no-op elsewhere.
+    pub fn fadvise(mut self, fadvise: bool) -> Self {
+        self.fadvise = fadvise;
+        self
+    }
+
+    /// Size of the io_uring serving point reads when built with the
+    /// `iouring` feature on Linux. Zero reads through the standard library
+    /// instead, as do builds without the feature.
+    pub fn io_uring_entries(mut self, entries: u32) -> Self {
+        self.io_uring_entries = entries;
+        self
+    }
+
+    /// Bytes of recently read data blocks kept in memory for point reads,
+    /// shared by every table. 8MB by default; zero turns the cache off.
+    /// Scans and compactions read around it.
+    pub fn block_cache_size(mut self, bytes: usize) -> Self {
+        self.block_cache_size = bytes;
+        self
+    }
+
+    /// Name of the key ordering the data directory is tied to. It is
+    /// recorded when the directory is created and opening it under another
+    /// name fails. Keys are always ordered bytewise for now.
+    pub fn comparator_name(mut self, name: impl Into<String>) -> Self {
+        self.comparator_name = name.into();
+        self
+    }
+
+    /// Bloom filter settings for new tables; `None` writes tables without
+    /// filters, so every read consults them. Tables already on disk keep
+    /// whatever they were written with until compaction rewrites them.
+    pub fn bloom(mut self, bloom: Option<BloomConfig>) -> Self {
+        self.bloom = bloom;
+        self
+    }
+
+    /// Whether compaction gives a filter to tables it writes with nothing
+    /// beneath them, `true` by default. Most reads that get as far as the
+    /// bottommost level find their key there, so the largest filters are
+    /// the ones least often worth their memory.
+    pub fn bottommost_bloom(mut self, enabled: bool) -> Self {
+        self.bottommost_bloom = enabled;
+        self
+    }
+
+    /// Codec for the data blocks of new tables, `None` by default. Snappy
+    /// and LZ4 need their cargo features. Tables already on disk keep
+    /// their codec until compaction rewrites them.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Record gets, multi-gets and scans taking at least `threshold` for
+    /// `Storage::slow_reads`. A scan is timed from when it's opened until
+    /// it's dropped. Off by default, in which case reads never read the clock.
+    pub fn slow_read_threshold(mut self, threshold: Option<Duration>) -> Self {
+        self.slow_read_threshold = threshold;
+        self
+    }
+
+    /// Also append slow reads to the `EVENTS` journal
+    pub fn journal_slow_reads(mut self, journal: bool) -> Self {
+        self.journal_slow_reads = journal;
+        self
+    }
+
+    /// Most bytes a scan asks the kernel to prefetch ahead of each table
+    /// it reads, growing from 64KB as the scan continues. Point reads never
+    /// prefetch. Off (zero) by default, since the kernel's own read-ahead
+    /// already keeps local disks busy; it pays off on high-latency devices
+    /// such as network block storage. Has no effect off Linux.
+    pub fn read_ahead(mut self, bytes: u64) -> Self {
+        self.read_ahead = bytes;
+        self
+    }
+
+    /// Re-read each compaction output and check it against what the merge
+    /// wrote before its inputs are dropped. On by default; a failed check
+    /// discards the output and keeps the inputs.
+    pub fn verify_compaction_output(mut self, verify: bool) -> Self {
+        self.verify_compaction_output = verify;
+        self
+    }
+
+    /// Cap on the bytes held by tables and the WAL. Past 80% of it every
+    /// flush compacts all levels down to reclaim overwritten and deleted
+    /// space, and at the cap `put` fails with
+    /// [`QuotaExceeded`](super::QuotaExceeded) while reads and deletes keep
+    /// working. The check runs before each put, so usage can overshoot by
+    /// that put and the flush it triggers.
+    pub fn max_total_bytes(mut self, limit: Option<u64>) -> Self {
+        self.max_total_bytes = limit;
+        self
+    }
+
+    /// Leveled merging (the default) or FIFO eviction; see
+    /// [`CompactionStrategy`]. FIFO can't be combined with
+    /// `max_total_bytes`, which reclaims space by merging.
+    pub fn compaction_strategy(mut self, strategy: CompactionStrategy) -> Self {
+        self.compaction_strategy = strategy;
+        self
+    }
+
+    /// Bytes of buffered writes that trigger a flush to L0
+    pub fn memtable_size(mut self, bytes: usize) -> Self {
+        self.memtable_size = bytes;
+        self
+    }
+
+    /// Number of L0 tables that triggers merging them into L1
+    pub fn l0_compaction_files(mut self, files: usize) -> Self {
+        self.l0_compaction_files = files;
+        self
+    }
+
+    /// Number of L0 tables, counting a memtable being flushed, at which
+    /// each put and batch is delayed by a millisecond so compaction can
+    /// catch up. 20 by default.
+    pub fn l0_slowdown_files(mut self, files: usize) -> Self {
+        self.l0_slowdown_files = files;
+        self
+    }
+
+    /// Number of L0 tables, counting a memtable being flushed, at which
+    /// puts and batches wait until compaction has brought L0 back under
+    /// it. 36 by default; never below `l0_slowdown_files`.
+    pub fn l0_stop_files(mut self, files: usize) -> Self {
+        self.l0_stop_files = files;
+        self
+    }
+
+    /// Level `n` compacts once its tables exceed `level_size_base *
+    /// level_multiplier^n` bytes
+    pub fn level_size_base(mut self, bytes: usize) -> Self {
+        self.level_size_base = bytes;
+        self
+    }
+
+    /// How many times larger each level may grow than the one above it
+    pub fn level_multiplier(mut self, multiplier: u32) -> Self {
+        self.level_multiplier = multiplier;
+        self
+    }
+
+    /// Write bloom filters with this false-positive rate; shorthand for
+    /// [`bloom`](StorageOptions::bloom)
+    pub fn bloom_fpr(self, false_positive_rate: f64) -> Self {
+        self.bloom(Some(BloomConfig {
+            false_positive_rate,
+        }))
+    }
+
+    /// Most compactions of different levels run at the same time when
+    /// several are due at once
+    pub fn max_background_compactions(mut self, jobs: usize) -> Self {
+        self.max_background_compactions = jobs;
+        self
+    }
+
+    /// Size past which a `WriteBatch` is written straight to L0 tables
+    /// rather than through the WAL and memtable; `None` uses `memtable_size`
+    pub fn large_batch_bytes(mut self, bytes: Option<usize>) -> Self {
+        self.large_batch_bytes = bytes;
+        self
+    }
+
+    /// When WAL appends are synced to disk; see [`SyncPolicy`]. By default
+    /// they never are, so acknowledged writes survive a crash of the
+    /// process but not of the machine.
+    pub fn wal_sync(mut self, policy: SyncPolicy) -> Self {
+        self.wal_sync = policy;
+        self
+    }
+
+    /// Size at which the WAL starts a new segment file, 64MB by default.
+    /// Segments are removed once every write in them is in a table.
+    pub fn wal_segment_size(mut self, bytes: u64) -> Self {
+        self.wal_segment_size = bytes;
+        self
+    }
+
+    /// Keep tables in an object store rather than the table directories,
+    /// reading them through its local chunk cache. Flushes and compactions
+    /// still write each table locally first, then upload it. The WAL and
+    /// manifest stay in the data directory, with a copy of the manifest in
+    /// the store, so a data directory that lost everything opens from the
+    /// store alone, less the writes its WAL held. Checkpoints and backups
+    /// need tables on local disk and are refused.
+    #[cfg(feature = "object-store")]
+    pub fn object_fs(mut self, fs: Arc<ObjectFs>) -> Self {
+        self.object_fs = Some(fs);
+        self
+    }
+
+    /// Reject combinations of options that contradict each other
+    pub(super) fn validate(&self) -> io::Result<()> {
+        if self.memtable_size == 0
+            || self.l0_compaction_files == 0
+            || self.level_size_base == 0
+            || self.level_multiplier == 0
+            || self.max_background_compactions == 0
+            || self.wal_segment_size == 0
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "memtable_size, l0_compaction_files, level_size_base, level_multiplier, max_background_compactions and wal_segment_size must be at least 1",
+            ));
+        }
+        if self.l0_slowdown_files == 0 || self.l0_stop_files < self.l0_slowdown_files {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "l0_slowdown_files ({}) must be at least 1 and at most l0_stop_files ({})",
+                    self.l0_slowdown_files, self.l0_stop_files
+                ),
+            ));
+        }
+        if self.wal_sync == SyncPolicy::EveryN(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "wal_sync every_n must be at least 1",
+            ));
+        }
+        if let Some(bloom) = self.bloom {
+            if !(bloom.false_positive_rate > 0.0 && bloom.false_positive_rate < 1.0) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "bloom false positive rate {} must be between 0 and 1",
+                        bloom.false_positive_rate
+                    ),
+                ));
+            }
+        }
+        if !self.compression.is_supported() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} compression needs the `{}` cargo feature",
+                    self.compression, self.compression
+                ),
+            ));
+        }
+        if matches!(self.compaction_strategy, CompactionStrategy::Fifo { .. })
+            && self.max_total_bytes.is_some()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "FIFO compaction can't be combined with max_total_bytes; max_fifo_bytes already caps the tables",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Move a finished table into the object store, if tables live in one
+    #[cfg(feature = "object-store")]
+    pub(super) fn publish(&self, table: SSTable) -> io::Result<SSTable> {
+        match &self.object_fs {
+            Some(fs) => table.upload(fs),
+            None => Ok(table),
+        }
+    }
+
+    #[cfg(not(feature = "object-store"))]
+    pub(super) fn publish(&self, table: SSTable) -> io::Result<SSTable> {
+        Ok(table)
+    }
+
+    /// Refuse `what` when tables live in an object store, for operations
+    /// that copy table files
+    #[cfg(feature = "object-store")]
+    pub(super) fn require_local_tables(&self, what: &str) -> io::Result<()> {
+        if self.object_fs.is_none() {
+            return Ok(());
+        }
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "{} needs tables on local disk, not in an object store",
+                what
+            ),
+        ))
+    }
+
+    #[cfg(not(feature = "object-store"))]
+    pub(super) fn require_local_tables(&self, _what: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub(super) fn io_mode(&self) -> IoMode {
+        if self.direct_io {
+            IoMode::Direct
+        } else {
+            IoMode::Buffered
+        }
+    }
+
+    /// Directory holding the tables of `level`
+    pub(super) fn table_dir<'a>(&'a self, data_dir: &'a Path, level: usize) -> &'a Path {
+        self.level_dirs
+            .iter()
+            .rev()
+            .find(|(min_level, _)| level >= *min_level)
+            .map_or(data_dir, |(_, dir)| dir.as_path())
+    }
+
+    /// Every directory that may hold tables, starting with `data_dir`
+    pub(super) fn table_dirs(&self, data_dir: &Path) -> Vec<PathBuf> {
+        let mut dirs = vec![data_dir.to_path_buf()];
+        for (_, dir) in &self.level_dirs {
+            if !dirs.contains(dir) {
+                dirs.push(dir.clone());
+            }
+        }
+        dirs
+    }
+
+    pub(super) fn now(&self) -> u64 {
+        (self.clock)()
+    }
+}
+
+/// The options that can be changed while open, as `name=value` pairs
+/// separated by spaces, in the form [`OptionsDelta::set`](super::OptionsDelta::set)
+/// accepts. Unset values print as `none`.
+impl fmt::Display for StorageOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let none = || "none".to_string();
+        write!(
+            f,
+            "memtable_size={} l0_compaction_files={} l0_slowdown_files={} l0_stop_files={} level_size_base={} level_multiplier={} max_background_compactions={} \
+             large_batch_bytes={} wal_sync={} bloom_false_positive_rate={} bottommost_bloom={} read_ahead={} verify_compaction_output={} max_total_bytes={} \
+             tombstone_retention_ms={} best_effort_reads={}",
+            self.memtable_size,
+            self.l0_compaction_files,
+            self.l0_slowdown_files,
+            self.l0_stop_files,
+            self.level_size_base,
+            self.level_multiplier,
+            self.max_background_compactions,
+            self.large_batch_bytes.map_or_else(none, |n| n.to_string()),
+            self.wal_sync,
+            self.bloom
+                .map_or_else(none, |bloom| bloom.false_positive_rate.to_string()),
+            self.bottommost_bloom,
+            self.read_ahead,
+            self.verify_compaction_output,
+            self.max_total_bytes.map_or_else(none, |n| n.to_string()),
+            self.tombstone_retention.as_millis(),
+            self.best_effort_reads,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Storage;
+    use super::*;
+    use tempfile::TempDir;
+
+    fn deepest_level(storage: &Storage) -> Option<usize> {
+        (0..=DEFAULT_BOTTOM_LEVEL).rfind(|&level| !storage.level_files(level).is_empty())
+    }
+
+    #[test]
+    fn test_memtable_size_controls_flushes() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = StorageOptions::default().memtable_size(64);
+        let storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+        storage.put(b"key1".to_vec(), vec![b'v'; 40]).unwrap();
+        assert!(storage.level_files(0).is_empty());
+        storage.put(b"key2".to_vec(), vec![b'v'; 40]).unwrap();
+        storage.wait_for_flush(&mut storage.writer()).unwrap();
+        assert_eq!(storage.level_files(0).len(), 1);
+
+        let temp_dir = TempDir::new().unwrap();
+        let options = StorageOptions::default().memtable_size(1 << 30);
+        let storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+        for i in 0..1000 {
+            storage
+                .put(format!("key{:04}", i).into_bytes(), vec![b'v'; 1024])
+                .unwrap();
+        }
+        assert_eq!(deepest_level(&storage), None);
+    }
+
+    #[test]
+    fn test_level_sizes_follow_options() {
+        let load = |options: StorageOptions| {
+            let temp_dir = TempDir::new().unwrap();
+            let options = options.memtable_size(4 * 1024);
+            let storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+            for i in 0..2000 {
+                storage
+                    .put(format!("key{:05}", i).into_bytes(), vec![b'v'; 100])
+                    .unwrap();
+            }
+            storage
+                .compact_until_settled(&mut storage.writer(), 0)
+                .unwrap();
+            deepest_level(&storage)
+        };
+
+        // About 200KB fits in L1 by default, but spills much deeper when
+        // each level may only hold twice the one above, from 4KB
+        assert_eq!(load(StorageOptions::default()), Some(1));
+        let small_levels = StorageOptions::default()
+            .level_size_base(4 * 1024)
+            .level_multiplier(2)
+            .bloom_fpr(0.001);
+        assert!(small_levels.to_string().contains("level_multiplier=2"));
+        assert!(load(small_levels).unwrap() >= 3);
+    }
+
+    #[test]
+    fn test_invalid_options_rejected() {
+        for options in [
+            StorageOptions::default().level_multiplier(0),
+            StorageOptions::default().level_size_base(0),
+            StorageOptions::default().bloom_fpr(0.0),
+            StorageOptions::default().bloom_fpr(1.5),
+        ] {
+            let temp_dir = TempDir::new().unwrap();
+            let err = Storage::open_with_options(temp_dir.path(), options)
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn test_compression_applies_to_flushes_and_compactions() {
+        for codec in [Compression::Snappy, Compression::Lz4] {
+            let temp_dir = TempDir::new().unwrap();
+            let options = StorageOptions::default()
+                .memtable_size(4 * 1024)
+                .compression(codec);
+            if !codec.is_supported() {
+                let err = Storage::open_with_options(temp_dir.path(), options)
+                    .err()
+                    .unwrap();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+                continue;
+            }
+
+            let storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+            for i in 0..2000 {
+                storage
+                    .put(format!("key{:05}", i).into_bytes(), vec![b'v'; 100])
+                    .unwrap();
+            }
+            storage.wait_for_flush(&mut storage.writer()).unwrap();
+            storage
+                .compact_until_settled(&mut storage.writer(), 0)
+                .unwrap();
+            assert!(!storage.level_files(1).is_empty());
+            for level in 0..=1 {
+                for table in storage.levels().sstables.get(&level).into_iter().flatten() {
+                    assert_eq!(table.properties().compression, codec);
+                }
+            }
+            drop(storage);
+
+            // Read back under the default codec
+            let storage = Storage::new(temp_dir.path(), false).unwrap();
+            for i in (0..2000).step_by(37) {
+                assert_eq!(
+                    storage.get(format!("key{:05}", i).into_bytes()).unwrap(),
+                    Some(vec![b'v'; 100])
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use log::info;
+
+use super::{Storage, Writer};
+use crate::error::StorageError;
+
+// Share of `max_total_bytes` past which flushes compact to reclaim space
+const HIGH_WATER_PERCENT: u64 = 80;
+
+/// The error inside the `StorageFull` I/O error a `put` fails with once
+/// usage reaches `StorageOptions::max_total_bytes`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaExceeded {
+    pub used: u64,
+    pub limit: u64,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Disk quota exceeded: {} bytes used of {}",
+            self.used, self.limit
+        )
+    }
+}
+
+impl Error for QuotaExceeded {}
+
+impl Storage {
+    /// Bytes held by live tables and the WAL
+    pub fn disk_usage(&self) -> Result<u64, StorageError> {
+        Ok(self.usage(&self.writer())?)
+    }
+
+    fn usage(&self, writer: &Writer) -> io::Result<u64> {
+        let levels = self.levels();
+        let tables: usize = levels.sstables.values().flatten().map(|t| t.size()).sum();
+        Ok(tables as u64 + writer.wal.size()?)
+    }
+
+    /// Refuse a new write once usage has reached the configured cap
+    pub(super) fn check_quota(&self, writer: &Writer) -> io::Result<()> {
+        let Some(limit) = self.options.max_total_bytes else {
+            return Ok(());
+        };
+        let used = self.usage(writer)?;
+        if used >= limit {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                QuotaExceeded { used, limit },
+            ));
+        }
+        Ok(())
+    }
+
+    /// Past the high-water mark, merge every level down into one table so
+    /// overwritten values and expired tombstones are dropped
+    pub(super) fn maybe_reclaim_space(&self, writer: &mut Writer) -> io::Result<()> {
+        let Some(limit) = self.options.max_total_bytes else {
+            return Ok(());
+        };
+        if self.usage(writer)? < limit / 100 * HIGH_WATER_PERCENT {
+            return Ok(());
+        }
+
+        let Some(deepest) = self
+            .levels()
+            .sstables
+            .iter()
+            .filter(|(_, tables)| !tables.is_empty())
+            .map(|(&level, _)| level)
+            .max()
+        else {
+            return Ok(());
+        };
+        info!("Reclaiming space by compacting L0 to L{}", deepest);
+        for level in 0..=deepest {
+            let files = self.levels().sstables.get(&level).map_or(0, Vec::len);
+            if (files > 0 && level < deepest) || files > 1 {
+                self.run_compaction(writer, level)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{EventKind, StorageOptions};
+    use tempfile::TempDir;
+
+    const LIMIT: u64 = 100_000;
+
+    fn open(dir: &TempDir) -> Storage {
+        let options = StorageOptions::default().max_total_bytes(Some(LIMIT));
+        Storage::open_with_options(dir.path(), options).unwrap()
+    }
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("key{:04}", i).into_bytes()
+    }
+
+    #[test]
+    fn test_overwrites_reclaimed_past_high_water() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = open(&temp_dir);
+
+        // One round of 30KB stays under the high-water mark
+        for round in 0..3 {
+            for i in 0..30 {
+                storage.put(key(i), vec![round; 1000]).unwrap();
+            }
+            storage.flush().unwrap();
+            if round == 0 {
+                assert_eq!(storage.recent_events(10).unwrap().len(), 1);
+            }
+        }
+
+        // The third flush crossed it and merged everything into one table
+        // holding only the newest round
+        let events = storage.recent_events(10).unwrap();
+        assert_eq!(events.last().unwrap().kind, EventKind::Compaction);
+        assert!(storage.level_files(0).is_empty());
+        assert_eq!(storage.level_files(1).len(), 1);
+        let usage = storage.disk_usage().unwrap();
+        assert!(usage < 40_000, "{} bytes still in use", usage);
+        assert_eq!(storage.get(key(7)).unwrap(), Some(vec![2; 1000]));
+    }
+
+    #[test]
+    fn test_puts_refused_at_cap() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = open(&temp_dir);
+
+        let mut written = 0;
+        let err = loop {
+            if let Err(e) = storage.put(key(written), vec![b'v'; 1000]) {
+                break e;
+            }
+            written += 1;
+            if written % 20 == 0 {
+                storage.flush().unwrap();
+            }
+        };
+        assert!(written > 50, "only {} keys fit", written);
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        let StorageError::Io(err) = err else {
+            panic!("expected an I/O error, got {:?}", err);
+        };
+        let quota = err.get_ref().unwrap().downcast_ref::<QuotaExceeded>();
+        assert_eq!(quota.unwrap().limit, LIMIT);
+        assert!(storage.disk_usage().unwrap() >= LIMIT);
+
+        // Reads and deletes still work
+        assert_eq!(storage.get(key(3)).unwrap(), Some(vec![b'v'; 1000]));
+        for i in 0..written {
+            storage.delete(key(i)).unwrap();
+        }
+        assert!(storage.put(key(0), b"v".to_vec()).is_err());
+
+        // Compacting the deletes away frees the space again
+        storage.flush().unwrap();
+        assert!(storage.disk_usage().unwrap() < LIMIT / 10);
+        storage.put(key(0), b"v".to_vec()).unwrap();
+        assert_eq!(storage.get(key(3)).unwrap(), None);
+    }
+}
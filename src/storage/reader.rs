@@ -0,0 +1,248 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::checkpoint::{find_in_chain, BackupManifest, BACKUP_MANIFEST};
+use super::secondary::{self, Tables};
+use super::{identity, level_stats, options, parse_table_name, Identity, Scan};
+use crate::memtable::MemTable;
+use crate::sstable::SSTable;
+use crate::stats::{CacheStats, Counters, SizeHistogram, StorageStats};
+use crate::wal::WAL;
+use crate::Value;
+
+/// Reads a checkpoint, or any consistent copy of a data directory, without
+/// opening it as a database.
+///
+/// Nothing in the directory is created, changed or removed: there is no
+/// lock file, the WAL is only read into memory and never replayed into
+/// tables, and no compaction or other background work runs. The tables
+/// read are those a `BACKUP` manifest lists, found through its chain of
+/// parents, or every table in the directory when there is no manifest.
+pub struct CheckpointReader {
+    identity: Identity,
+    tables: Tables,
+    memtable: MemTable,
+}
+
+impl CheckpointReader {
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        let identity = identity::load_any(dir)?;
+
+        let mut tables = BTreeMap::new();
+        for path in table_paths(dir)? {
+            let (level, number) = parse_table_name(&path).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{:?} is not a table name", path),
+                )
+            })?;
+            tables.insert((level, Reverse(number)), Arc::new(SSTable::new(path)?));
+        }
+
+        let seq = tables
+            .values()
+            .map(|table| table.properties().max_seq)
+            .max()
+            .unwrap_or(0);
+        let mut memtable = MemTable::new();
+        let wal_dir = dir.join("wal");
+        if wal_dir.is_dir() {
+            let mut records = Vec::new();
+            for number in WAL::logs(&wal_dir)? {
+                records.extend(WAL::read_log(&wal_dir, number, 0)?.0);
+            }
+            let mut replayed = secondary::Replayed { seq, applied: 0 };
+            let now = options::system_clock();
+            secondary::replay(&mut memtable, &mut replayed, records, now);
+        }
+
+        Ok(CheckpointReader {
+            identity,
+            tables,
+            memtable,
+        })
+    }
+
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+
+    pub fn get(&self, key: &[u8]) -> io::Result<Option<Value>> {
+        secondary::lookup(&self.memtable, &self.tables, key, options::system_clock())
+    }
+
+    /// Live pairs with keys in `[start, end)`, in ascending order. An empty
+    /// `end` scans to the last key.
+    pub fn scan(&self, start: &[u8], end: &[u8]) -> io::Result<Scan> {
+        let end = (!end.is_empty()).then(|| end.to_vec());
+        let memtable = self
+            .memtable
+            .range_versions(start, end.as_deref())
+            .flat_map(|(key, versions)| {
+                versions
+                    .iter()
+                    .map(move |version| (key.clone(), version.clone()))
+            })
+            .collect();
+        let tables = self.tables.values().cloned().collect();
+        let now = options::system_clock();
+        Scan::new(
+            memtable,
+            tables,
+            start,
+            end,
+            u64::MAX,
+            now,
+            None,
+            0,
+            None,
+            false,
+        )
+    }
+
+    /// Table properties per level, as [`Storage::stats`](super::Storage::stats)
+    /// reports them. Nothing is written through a reader, so the write and
+    /// activity counters are all zero.
+    pub fn stats(&self) -> StorageStats {
+        let mut levels: HashMap<usize, Vec<Arc<SSTable>>> = HashMap::new();
+        for (&(level, _), table) in &self.tables {
+            levels.entry(level).or_default().push(table.clone());
+        }
+        let (stored, levels) = level_stats(
+            levels
+                .iter()
+                .map(|(&level, tables)| (level, tables.as_slice())),
+        );
+        StorageStats {
+            memtable_bytes: self.memtable.size() as u64,
+            memtable_entries: self.memtable.len() as u64,
+            wal_bytes: 0,
+            written_key_sizes: SizeHistogram::new(),
+            written_value_sizes: SizeHistogram::new(),
+            stored,
+            levels,
+            skipped_reads: 0,
+            fadvise_calls: 0,
+            event_log_errors: 0,
+            lifetime: Counters::default(),
+            since_open: Counters::default(),
+            block_cache: CacheStats::default(),
+        }
+    }
+}
+
+/// The tables `dir` stands for: its manifest's live tables if it has one,
+/// else every table file in it
+fn table_paths(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    if dir.join(BACKUP_MANIFEST).exists() {
+        let manifest = BackupManifest::load(dir)?;
+        return manifest
+            .live
+            .iter()
+            .map(|name| find_in_chain(dir, name))
+            .collect();
+    }
+
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("sst") {
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+    use tempfile::TempDir;
+
+    /// Every file under `dir` with its contents, in path order
+    fn contents(dir: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                files.extend(contents(&path));
+            } else {
+                files.push((path.clone(), fs::read(&path).unwrap()));
+            }
+        }
+        files.sort();
+        files
+    }
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("key{:04}", i).into_bytes()
+    }
+
+    #[test]
+    fn test_reader_serves_checkpoint_contents() {
+        let primary_dir = TempDir::new().unwrap();
+        let backup_dir = TempDir::new().unwrap();
+        let primary = Storage::new(primary_dir.path(), false).unwrap();
+        for i in 0..300 {
+            primary.put(key(i), b"old".to_vec()).unwrap();
+            if i % 100 == 99 {
+                primary.flush().unwrap();
+            }
+        }
+        primary.delete(key(7)).unwrap();
+        primary.checkpoint(backup_dir.path()).unwrap();
+        let before = contents(backup_dir.path());
+
+        // The primary keeps writing while the reader is open
+        let reader = CheckpointReader::open(backup_dir.path()).unwrap();
+        for i in 0..300 {
+            primary.put(key(i), b"new".to_vec()).unwrap();
+        }
+        primary.put(key(1000), b"new".to_vec()).unwrap();
+        primary.flush().unwrap();
+
+        assert_eq!(reader.identity(), primary.identity());
+        assert_eq!(reader.get(&key(5)).unwrap(), Some(b"old".to_vec()));
+        assert_eq!(reader.get(&key(7)).unwrap(), None);
+        assert_eq!(reader.get(&key(1000)).unwrap(), None);
+        let pairs: Vec<_> = reader.scan(b"", b"").unwrap().map(Result::unwrap).collect();
+        assert_eq!(pairs.len(), 299);
+        assert!(pairs.iter().all(|(_, value)| value == b"old"));
+
+        let stats = reader.stats();
+        assert_eq!(stats.stored.entry_count, 299);
+        let manifest = BackupManifest::load(backup_dir.path()).unwrap();
+        assert_eq!(
+            stats.levels.iter().map(|l| l.file_count).sum::<usize>(),
+            manifest.live.len()
+        );
+
+        drop(reader);
+        assert_eq!(contents(backup_dir.path()), before);
+    }
+
+    #[test]
+    fn test_reader_reads_wal_of_plain_copy() {
+        let data_dir = TempDir::new().unwrap();
+        let storage = Storage::new(data_dir.path(), false).unwrap();
+        storage.put(key(1), b"flushed".to_vec()).unwrap();
+        storage.flush().unwrap();
+        storage.put(key(1), b"buffered".to_vec()).unwrap();
+        storage.put(key(2), b"buffered".to_vec()).unwrap();
+        storage.delete(key(2)).unwrap();
+        drop(storage);
+        let before = contents(data_dir.path());
+
+        let reader = CheckpointReader::open(data_dir.path()).unwrap();
+        assert_eq!(reader.get(&key(1)).unwrap(), Some(b"buffered".to_vec()));
+        assert_eq!(reader.get(&key(2)).unwrap(), None);
+        assert_eq!(reader.stats().stored.entry_count, 1);
+        drop(reader);
+        assert_eq!(contents(data_dir.path()), before);
+    }
+}
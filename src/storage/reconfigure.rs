@@ -0,0 +1,401 @@
+use std::io;
+use std::str::FromStr;
+use std::time::Duration;
+
+use log::info;
+
+use super::{Storage, StorageOptions, SyncPolicy};
+use crate::bloom::BloomConfig;
+use crate::error::StorageError;
+
+// Options fixed for as long as the storage is open
+const IMMUTABLE: &[&str] = &[
+    "comparator_name",
+    "data_dir",
+    "level_dir",
+    "bottom_level",
+    "compaction_strategy",
+    "io_uring_entries",
+    "direct_io",
+    "fadvise",
+    "slow_read_threshold_ms",
+    "journal_slow_reads",
+];
+
+/// Changes for [`Storage::set_options`]; options left unset keep their
+/// current values
+#[derive(Debug, Clone, Default)]
+pub struct OptionsDelta {
+    memtable_size: Option<usize>,
+    l0_compaction_files: Option<usize>,
+    l0_slowdown_files: Option<usize>,
+    l0_stop_files: Option<usize>,
+    level_size_base: Option<usize>,
+    level_multiplier: Option<u32>,
+    max_background_compactions: Option<usize>,
+    large_batch_bytes: Option<Option<usize>>,
+    wal_sync: Option<SyncPolicy>,
+    bloom: Option<Option<BloomConfig>>,
+    bottommost_bloom: Option<bool>,
+    read_ahead: Option<u64>,
+    verify_compaction_output: Option<bool>,
+    max_total_bytes: Option<Option<u64>>,
+    tombstone_retention: Option<Duration>,
+    best_effort_reads: Option<bool>,
+}
+
+impl OptionsDelta {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn memtable_size(mut self, bytes: usize) -> Self {
+        self.memtable_size = Some(bytes);
+        self
+    }
+
+    pub fn l0_compaction_files(mut self, files: usize) -> Self {
+        self.l0_compaction_files = Some(files);
+        self
+    }
+
+    pub fn l0_slowdown_files(mut self, files: usize) -> Self {
+        self.l0_slowdown_files = Some(files);
+        self
+    }
+
+    pub fn l0_stop_files(mut self, files: usize) -> Self {
+        self.l0_stop_files = Some(files);
+        self
+    }
+
+    pub fn level_size_base(mut self, bytes: usize) -> Self {
+        self.level_size_base = Some(bytes);
+        self
+    }
+
+    pub fn level_multiplier(mut self, multiplier: u32) -> Self {
+        self.level_multiplier = Some(multiplier);
+        self
+    }
+
+    /// Applies from the next compaction
+    pub fn max_background_compactions(mut self, jobs: usize) -> Self {
+        self.max_background_compactions = Some(jobs);
+        self
+    }
+
+    pub fn large_batch_bytes(mut self, bytes: Option<usize>) -> Self {
+        self.large_batch_bytes = Some(bytes);
+        self
+    }
+
+    /// Applies from the next write
+    pub fn wal_sync(mut self, policy: SyncPolicy) -> Self {
+        self.wal_sync = Some(policy);
+        self
+    }
+
+    /// Applies to tables written from now on
+    pub fn bloom(mut self, bloom: Option<BloomConfig>) -> Self {
+        self.bloom = Some(bloom);
+        self
+    }
+
+    /// Applies from the next compaction
+    pub fn bottommost_bloom(mut self, enabled: bool) -> Self {
+        self.bottommost_bloom = Some(enabled);
+        self
+    }
+
+    /// Applies to scans opened from now on
+    pub fn read_ahead(mut self, bytes: u64) -> Self {
+        self.read_ahead = Some(bytes);
+        self
+    }
+
+    pub fn verify_compaction_output(mut self, verify: bool) -> Self {
+        self.verify_compaction_output = Some(verify);
+        self
+    }
+
+    pub fn max_total_bytes(mut self, limit: Option<u64>) -> Self {
+        self.max_total_bytes = Some(limit);
+        self
+    }
+
+    pub fn tombstone_retention(mut self, retention: Duration) -> Self {
+        self.tombstone_retention = Some(retention);
+        self
+    }
+
+    pub fn best_effort_reads(mut self, best_effort: bool) -> Self {
+        self.best_effort_reads = Some(best_effort);
+        self
+    }
+
+    /// Change one option by the name and value format `StorageOptions`
+    /// displays, e.g. `memtable_size=65536`. Options that can only be set
+    /// when opening, and unknown names, are refused with `InvalidInput`.
+    pub fn set(self, name: &str, value: &str) -> io::Result<Self> {
+        Ok(match name {
+            "memtable_size" => self.memtable_size(parse(name, value)?),
+            "l0_compaction_files" => self.l0_compaction_files(parse(name, value)?),
+            "l0_slowdown_files" => self.l0_slowdown_files(parse(name, value)?),
+            "l0_stop_files" => self.l0_stop_files(parse(name, value)?),
+            "level_size_base" => self.level_size_base(parse(name, value)?),
+            "level_multiplier" => self.level_multiplier(parse(name, value)?),
+            "max_background_compactions" => self.max_background_compactions(parse(name, value)?),
+            "large_batch_bytes" => self.large_batch_bytes(parse_optional(name, value)?),
+            "wal_sync" => self.wal_sync(parse(name, value)?),
+            "bloom_false_positive_rate" => self.bloom(parse_optional(name, value)?.map(
+                |false_positive_rate| BloomConfig {
+                    false_positive_rate,
+                },
+            )),
+            "bottommost_bloom" => self.bottommost_bloom(parse(name, value)?),
+            "read_ahead" => self.read_ahead(parse(name, value)?),
+            "verify_compaction_output" => self.verify_compaction_output(parse(name, value)?),
+            "max_total_bytes" => self.max_total_bytes(parse_optional(name, value)?),
+            "tombstone_retention_ms" => {
+                self.tombstone_retention(Duration::from_millis(parse(name, value)?))
+            }
+            "best_effort_reads" => self.best_effort_reads(parse(name, value)?),
+            _ if IMMUTABLE.contains(&name) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} can't be changed while open; reopen with it instead",
+                        name
+                    ),
+                ))
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Unknown option {}", name),
+                ))
+            }
+        })
+    }
+
+    fn apply(self, options: &mut StorageOptions) {
+        if let Some(bytes) = self.memtable_size {
+            options.memtable_size = bytes;
+        }
+        if let Some(files) = self.l0_compaction_files {
+            options.l0_compaction_files = files;
+        }
+        if let Some(files) = self.l0_slowdown_files {
+            options.l0_slowdown_files = files;
+        }
+        if let Some(files) = self.l0_stop_files {
+            options.l0_stop_files = files;
+        }
+        if let Some(bytes) = self.level_size_base {
+            options.level_size_base = bytes;
+        }
+        if let Some(multiplier) = self.level_multiplier {
+            options.level_multiplier = multiplier;
+        }
+        if let Some(jobs) = self.max_background_compactions {
+            options.max_background_compactions = jobs;
+        }
+        if let Some(bytes) = self.large_batch_bytes {
+            options.large_batch_bytes = bytes;
+        }
+        if let Some(policy) = self.wal_sync {
+            options.wal_sync = policy;
+        }
+        if let Some(bloom) = self.bloom {
+            options.bloom = bloom;
+        }
+        if let Some(enabled) = self.bottommost_bloom {
+            options.bottommost_bloom = enabled;
+        }
+        if let Some(bytes) = self.read_ahead {
+            options.read_ahead = bytes;
+        }
+        if let Some(verify) = self.verify_compaction_output {
+            options.verify_compaction_output = verify;
+        }
+        if let Some(limit) = self.max_total_bytes {
+            options.max_total_bytes = limit;
+        }
+        if let Some(retention) = self.tombstone_retention {
+            options.tombstone_retention = retention;
+        }
+        if let Some(best_effort) = self.best_effort_reads {
+            options.best_effort_reads = best_effort;
+        }
+    }
+}
+
+fn parse<T: FromStr>(name: &str, value: &str) -> io::Result<T> {
+    value.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid value {:?} for {}", value, name),
+        )
+    })
+}
+
+/// A value that may also be `none`
+fn parse_optional<T: FromStr>(name: &str, value: &str) -> io::Result<Option<T>> {
+    match value {
+        "none" => Ok(None),
+        _ => parse(name, value).map(Some),
+    }
+}
+
+impl Storage {
+    /// The options in effect, including changes made since opening
+    pub fn options(&self) -> &StorageOptions {
+        &self.options
+    }
+
+    /// Change tunables without reopening. The whole delta is checked
+    /// before anything is applied. A smaller memtable or L0 trigger takes
+    /// effect at once: a memtable already past the new size is flushed and
+    /// a full L0 compacted before this returns.
+    pub fn set_options(&mut self, changes: OptionsDelta) -> Result<(), StorageError> {
+        let mut options = self.options.clone();
+        changes.apply(&mut options);
+        options.validate()?;
+
+        self.options = options;
+        self.compaction_manager = Self::compaction_manager(&self.options);
+        let mut writer = self.writer();
+        writer.wal.set_sync_policy(self.options.wal_sync);
+        info!("Options now {}", self.options);
+        self.maybe_flush(&mut writer)?;
+        self.wait_for_flush(&mut writer)?;
+        Ok(self.compact_until_settled(&mut writer, 0)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_lower_flush_threshold_at_runtime() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut storage = Storage::new(temp_dir.path(), false).unwrap();
+        for i in 0..20 {
+            storage
+                .put(format!("key{:02}", i).into_bytes(), vec![b'v'; 100])
+                .unwrap();
+        }
+        assert!(storage.level_files(0).is_empty());
+
+        storage
+            .set_options(OptionsDelta::new().memtable_size(8 * 1024))
+            .unwrap();
+        assert!(storage.level_files(0).is_empty());
+        let mut puts = 0;
+        // Counted up to the flush starting, not its table landing
+        while storage.mem().immutable.is_none() && storage.level_files(0).is_empty() {
+            storage
+                .put(format!("more{:03}", puts).into_bytes(), vec![b'v'; 100])
+                .unwrap();
+            puts += 1;
+        }
+        assert!(puts < 80, "flushed after {} more puts", puts);
+        storage.wait_for_flush(&mut storage.writer()).unwrap();
+        assert!(storage.describe().contains("memtable_size=8192"));
+
+        // Shrinking below what's buffered flushes straight away
+        storage.put(b"last".to_vec(), vec![b'v'; 100]).unwrap();
+        storage
+            .set_options(OptionsDelta::new().memtable_size(64))
+            .unwrap();
+        assert_eq!(storage.level_files(0).len(), 2);
+        assert_eq!(storage.options().memtable_size, 64);
+    }
+
+    #[test]
+    fn test_lower_l0_trigger_compacts() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut storage = Storage::new(temp_dir.path(), false).unwrap();
+        for i in 0..2 {
+            storage.put(vec![i], b"v".to_vec()).unwrap();
+            storage.flush().unwrap();
+        }
+        assert_eq!(storage.level_files(0).len(), 2);
+
+        let changes = OptionsDelta::new().set("l0_compaction_files", "2").unwrap();
+        storage.set_options(changes).unwrap();
+        assert!(storage.level_files(0).is_empty());
+        assert_eq!(storage.level_files(1).len(), 1);
+        assert_eq!(storage.get(vec![1]).unwrap(), Some(b"v".to_vec()));
+    }
+
+    #[test]
+    fn test_invalid_changes_rejected() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut storage = Storage::new(temp_dir.path(), false).unwrap();
+
+        let err = OptionsDelta::new()
+            .set("comparator_name", "reverse")
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("can't be changed while open"));
+        assert!(OptionsDelta::new().set("no_such_option", "1").is_err());
+        assert!(OptionsDelta::new().set("memtable_size", "big").is_err());
+
+        // Nothing from a rejected delta is applied
+        let before = storage.options().to_string();
+        let changes = OptionsDelta::new()
+            .read_ahead(1 << 20)
+            .l0_compaction_files(0);
+        assert!(storage.set_options(changes).is_err());
+        assert_eq!(storage.options().to_string(), before);
+
+        let changes = OptionsDelta::new()
+            .set("max_total_bytes", "1000000")
+            .and_then(|delta| delta.set("bloom_false_positive_rate", "none"))
+            .unwrap();
+        storage.set_options(changes).unwrap();
+        let options = storage.options().to_string();
+        assert!(options.contains("max_total_bytes=1000000"));
+        assert!(options.contains("bloom_false_positive_rate=none"));
+    }
+
+    #[test]
+    fn test_wal_sync_policy() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = StorageOptions::default().wal_sync(SyncPolicy::Always);
+        let mut storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+        storage.put(b"a", b"v").unwrap();
+        storage.delete(b"a").unwrap();
+        assert_eq!(storage.writer().wal.syncs(), 2);
+
+        let changes = OptionsDelta::new().set("wal_sync", "every_n:100").unwrap();
+        storage.set_options(changes).unwrap();
+        assert!(storage.describe().contains("wal_sync=every_n:100"));
+        for i in 0..99u32 {
+            storage
+                .put(i.to_be_bytes().to_vec(), b"v".to_vec())
+                .unwrap();
+        }
+        assert_eq!(storage.writer().wal.syncs(), 2);
+        storage.put(b"b", b"v").unwrap();
+        assert_eq!(storage.writer().wal.syncs(), 3);
+
+        // An explicit sync happens regardless of the policy
+        storage
+            .set_options(OptionsDelta::new().wal_sync(SyncPolicy::Never))
+            .unwrap();
+        storage.put(b"c", b"v").unwrap();
+        assert_eq!(storage.writer().wal.syncs(), 3);
+        storage.sync().unwrap();
+        assert_eq!(storage.writer().wal.syncs(), 4);
+
+        let err = storage
+            .set_options(OptionsDelta::new().wal_sync(SyncPolicy::EveryN(0)))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
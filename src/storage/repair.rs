@@ -0,0 +1,333 @@
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::lock::DirLock;
+use super::manifest::{Manifest, ManifestState, MANIFEST_FILE};
+use super::{sync_dir, Storage, StorageOptions};
+use crate::error::StorageError;
+use crate::sstable::SSTable;
+use crate::wal::WAL;
+
+/// Where [`Storage::repair`] moves the originals of files it changes
+pub const LOST_DIR: &str = "lost";
+
+/// Outcome of [`Storage::repair`]
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    /// Damaged tables rebuilt from what could be read, with the entries
+    /// kept and why the table was damaged. A table kept nothing from is
+    /// dropped.
+    pub tables: Vec<(PathBuf, usize, String)>,
+    /// Tables the manifest listed that no longer exist
+    pub missing: Vec<PathBuf>,
+    /// WAL segments cut back to their last readable record, with the
+    /// records kept
+    pub logs: Vec<(PathBuf, usize)>,
+    /// Whether the manifest couldn't be read and was rebuilt from the
+    /// tables' names
+    pub manifest_rebuilt: bool,
+}
+
+impl RepairReport {
+    /// Whether nothing needed repairing
+    pub fn is_clean(&self) -> bool {
+        self.tables.is_empty()
+            && self.missing.is_empty()
+            && self.logs.is_empty()
+            && !self.manifest_rebuilt
+    }
+}
+
+impl Storage {
+    /// [`repair_with_options`](Storage::repair_with_options) with default
+    /// options
+    pub fn repair<P: AsRef<Path>>(data_dir: P) -> Result<RepairReport, StorageError> {
+        Self::repair_with_options(data_dir, StorageOptions::default())
+    }
+
+    /// Salvage what can be read from a data directory that no longer opens
+    /// or reads cleanly, such as after a crash on failing hardware. Pass
+    /// the options it's opened with, so tables in level directories are
+    /// found.
+    ///
+    /// Each live table that fails [`verify`](SSTable::verify) is rebuilt
+    /// in place from the entries read before its damage, and each WAL
+    /// segment is cut back to its last readable record. Tables keep their
+    /// level and number, so newer versions still shadow older ones as they
+    /// did. A manifest that can't be read is rebuilt from the tables'
+    /// names, as for a directory written before manifests, and one listing
+    /// missing tables is rewritten without them. The original of every
+    /// file changed goes to [`LOST_DIR`] rather than being deleted.
+    ///
+    /// A healthy directory is left exactly as it was. The directory must
+    /// not be open elsewhere.
+    pub fn repair_with_options<P: AsRef<Path>>(
+        data_dir: P,
+        options: StorageOptions,
+    ) -> Result<RepairReport, StorageError> {
+        let data_dir = data_dir.as_ref();
+        options.validate()?;
+        if !data_dir.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no database at {:?}", data_dir),
+            )
+            .into());
+        }
+        let _lock = DirLock::acquire(data_dir)?;
+        let lost = data_dir.join(LOST_DIR);
+        let mut report = RepairReport::default();
+
+        let mut state = match Manifest::load(data_dir) {
+            Ok(Some(state)) => state,
+            Ok(None) => Self::list_tables(&options.table_dirs(data_dir))?,
+            Err(_) => {
+                keep_original(&lost, &data_dir.join(MANIFEST_FILE))?;
+                report.manifest_rebuilt = true;
+                Self::list_tables(&options.table_dirs(data_dir))?
+            }
+        };
+        repair_tables(data_dir, &options, &lost, &mut state, &mut report)?;
+        repair_logs(&data_dir.join("wal"), state.log_number, &lost, &mut report)?;
+
+        if !report.tables.is_empty() || !report.missing.is_empty() || report.manifest_rebuilt {
+            Manifest::create(data_dir, &state)?;
+        }
+        if lost.is_dir() {
+            sync_dir(&lost)?;
+        }
+        Ok(report)
+    }
+}
+
+/// Rebuild the tables in `state` that fail verification and forget the
+/// missing ones, keeping `state` in step
+fn repair_tables(
+    data_dir: &Path,
+    options: &StorageOptions,
+    lost: &Path,
+    state: &mut ManifestState,
+    report: &mut RepairReport,
+) -> io::Result<()> {
+    let listed: Vec<_> = state
+        .tables
+        .iter()
+        .map(|(&key, (name, _))| (key, name.clone()))
+        .collect();
+    for ((level, seq), name) in listed {
+        let dir = options.table_dir(data_dir, level);
+        let path = dir.join(&name);
+        if !path.is_file() {
+            state.tables.remove(&(level, seq));
+            report.missing.push(path);
+            continue;
+        }
+        // A table whose header is unreadable has nothing to salvage
+        let (e, salvaged) = match SSTable::open_legacy(path.clone()) {
+            Ok(table) => match table.verify() {
+                Ok(_) => continue,
+                Err(e) => (e, table.salvage().unwrap_or_default()),
+            },
+            Err(e) => (e, Vec::new()),
+        };
+
+        keep_original(lost, &path)?;
+        fs::remove_file(&path)?;
+        if salvaged.is_empty() {
+            state.tables.remove(&(level, seq));
+        } else {
+            // Rewritten afresh, in the current format, as the damaged
+            // header may not open
+            let mut table = SSTable::new(path.clone())?
+                .with_bloom(options.bloom)
+                .with_compression(options.compression);
+            table.write_entries(&salvaged)?;
+            state
+                .tables
+                .insert((level, seq), (name, table.size() as u64));
+        }
+        sync_dir(dir)?;
+        report.tables.push((path, salvaged.len(), e.to_string()));
+    }
+    Ok(())
+}
+
+/// Cut each WAL segment in `wal_dir` from `first` on back to its last
+/// readable record
+fn repair_logs(
+    wal_dir: &Path,
+    first: u64,
+    lost: &Path,
+    report: &mut RepairReport,
+) -> io::Result<()> {
+    if !wal_dir.is_dir() {
+        return Ok(());
+    }
+    for number in WAL::logs(wal_dir)? {
+        if number < first {
+            continue; // already in tables; removed on open
+        }
+        let path = WAL::log_path(wal_dir, number);
+        let (records, readable) = WAL::salvage_log(wal_dir, number)?;
+        if readable >= fs::metadata(&path)?.len() {
+            continue;
+        }
+        keep_original(lost, &path)?;
+        let file = OpenOptions::new().write(true).open(&path)?;
+        file.set_len(readable)?;
+        file.sync_all()?;
+        report.logs.push((path, records.len()));
+    }
+    Ok(())
+}
+
+/// Copy `path` into `lost`, leaving the original in place until it's
+/// replaced
+fn keep_original(lost: &Path, path: &Path) -> io::Result<()> {
+    fs::create_dir_all(lost)?;
+    fs::copy(path, lost.join(path.file_name().unwrap()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use tempfile::TempDir;
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("key{:04}", i).into_bytes()
+    }
+
+    /// Three tables of 100 keys each, then 10 more keys only in the WAL
+    fn populated(dir: &Path) {
+        let storage = Storage::new(dir, false).unwrap();
+        for batch in 0..3 {
+            for i in batch * 100..(batch + 1) * 100 {
+                storage.put(key(i), vec![b'v'; 64]).unwrap();
+            }
+            storage.flush().unwrap();
+        }
+        for i in 300..310 {
+            storage.put(key(i), b"logged").unwrap();
+        }
+        storage.crash();
+    }
+
+    fn tables(dir: &Path) -> Vec<PathBuf> {
+        let mut tables: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "sst"))
+            .collect();
+        tables.sort();
+        tables
+    }
+
+    fn log(dir: &Path) -> PathBuf {
+        let wal_dir = dir.join("wal");
+        WAL::log_path(&wal_dir, WAL::newest_log(&wal_dir).unwrap().unwrap())
+    }
+
+    #[test]
+    fn test_repair_salvages_damaged_table_and_log() {
+        let temp_dir = TempDir::new().unwrap();
+        populated(temp_dir.path());
+
+        // Cut one table partway through its data, and flip a byte in the
+        // last WAL record
+        let damaged = tables(temp_dir.path())[1].clone();
+        let len = fs::metadata(&damaged).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&damaged).unwrap();
+        file.set_len(len / 2).unwrap();
+        let mut wal = fs::read(log(temp_dir.path())).unwrap();
+        let last = wal.len() - 3;
+        wal[last] ^= 0xff;
+        fs::write(log(temp_dir.path()), &wal).unwrap();
+
+        let report = Storage::repair(temp_dir.path()).unwrap();
+        assert!(!report.is_clean());
+        assert_eq!(report.tables.len(), 1, "{:?}", report);
+        let (path, kept, _) = &report.tables[0];
+        assert_eq!(path, &damaged);
+        assert!(*kept > 0 && *kept < 100, "{}", kept);
+        assert_eq!(report.logs, vec![(log(temp_dir.path()), 9)]);
+        assert!(!report.manifest_rebuilt);
+        // The originals are kept
+        let lost = temp_dir.path().join(LOST_DIR);
+        assert_eq!(
+            fs::metadata(lost.join(damaged.file_name().unwrap()))
+                .unwrap()
+                .len(),
+            len / 2
+        );
+        let log_name = log(temp_dir.path()).file_name().unwrap().to_owned();
+        assert_eq!(fs::read(lost.join(log_name)).unwrap(), wal);
+
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        assert!(storage.verify().is_ok());
+        let readable = (0..310)
+            .filter(|&i| storage.get(key(i)).unwrap().is_some())
+            .count();
+        // Everything but the end of the cut table and the last record
+        assert_eq!(readable, 200 + kept + 9);
+        for i in (0..100).chain(200..309) {
+            assert!(storage.get(key(i)).unwrap().is_some(), "key {}", i);
+        }
+        drop(storage);
+
+        // Once repaired there's nothing left to do
+        assert!(Storage::repair(temp_dir.path()).unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_repair_leaves_a_healthy_directory_alone() {
+        let temp_dir = TempDir::new().unwrap();
+        populated(temp_dir.path());
+        let contents = |dir: &Path| -> BTreeMap<PathBuf, Vec<u8>> {
+            let mut files = BTreeMap::new();
+            for dir in [dir.to_path_buf(), dir.join("wal")] {
+                for entry in fs::read_dir(dir).unwrap() {
+                    let path = entry.unwrap().path();
+                    if path.is_file() {
+                        files.insert(path.clone(), fs::read(&path).unwrap());
+                    }
+                }
+            }
+            files
+        };
+        let before = contents(temp_dir.path());
+
+        assert!(Storage::repair(temp_dir.path()).unwrap().is_clean());
+        assert_eq!(contents(temp_dir.path()), before);
+        assert!(!temp_dir.path().join(LOST_DIR).exists());
+
+        // Not while the directory is open
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        let err = Storage::repair(temp_dir.path()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        drop(storage);
+    }
+
+    #[test]
+    fn test_repair_rebuilds_an_unreadable_manifest() {
+        let temp_dir = TempDir::new().unwrap();
+        populated(temp_dir.path());
+        let manifest = temp_dir.path().join(MANIFEST_FILE);
+        fs::write(&manifest, "add 0 nonsense\n").unwrap();
+        assert!(Storage::new(temp_dir.path(), false).is_err());
+
+        let report = Storage::repair(temp_dir.path()).unwrap();
+        assert!(report.manifest_rebuilt);
+        assert!(report.tables.is_empty());
+        assert_eq!(
+            fs::read_to_string(temp_dir.path().join(LOST_DIR).join(MANIFEST_FILE)).unwrap(),
+            "add 0 nonsense\n"
+        );
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        for i in 0..310 {
+            assert!(storage.get(key(i)).unwrap().is_some(), "key {}", i);
+        }
+    }
+}
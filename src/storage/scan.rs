@@ -0,0 +1,412 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::io;
+use std::sync::Arc;
+use std::time::Instant;
+use std::vec;
+
+use super::slow::{ReadTrace, SlowReadLog, SlowReadTarget};
+use crate::entry::{self, Entry, MergeOperator, Version};
+use crate::sstable::{EntryReader, ReverseReader, SSTable};
+use crate::{Key, Value};
+
+/// A page of live pairs and the token resuming after it, as returned by
+/// `Storage::scan_page`
+pub type Page = (Vec<(Key, Value)>, Option<Key>);
+
+/// Iterator over live key/value pairs in a key range, in ascending order,
+/// or descending for `Storage::scan_rev`.
+///
+/// A scan merges a copy of the memtable's range with streaming readers over
+/// every SSTable that existed when it started. It holds references to those
+/// tables, so a compaction that replaces them mid-scan leaves the scan's view
+/// intact and only removes the old files once the scan is dropped.
+pub struct Scan {
+    merge: Merge,
+    max_seq: u64,
+    // Values expiring at or before this time read as deleted
+    now: u64,
+    merge_operator: Option<MergeOperator>,
+    failed: bool,
+    // Where to report the scan if it's slow, when it was opened and from
+    // which key
+    slow: Option<(Arc<SlowReadLog>, Instant, Key)>,
+}
+
+/// Every version stored at a single level, as returned by `Storage::iter_level`
+pub struct LevelIter {
+    merge: Merge,
+    failed: bool,
+}
+
+/// Merges sorted sources into one stream ordered by key, newest first
+struct Merge {
+    sources: Vec<Source>,
+    heap: BinaryHeap<Reverse<Head>>,
+    end: Option<Key>,
+    descending: bool,
+}
+
+/// One sorted input to the merge
+enum Source {
+    Memtable(vec::IntoIter<(Key, Version)>),
+    Table {
+        reader: EntryReader,
+        // Keeps the file around for as long as it's being read
+        _table: Arc<SSTable>,
+    },
+    // Read back to front, keeping its table around itself
+    TableRev(ReverseReader),
+}
+
+/// The next entry from a source, ordered by key, ascending or descending,
+/// and then newest first
+struct Head {
+    key: Key,
+    version: Version,
+    source: usize,
+    descending: bool,
+}
+
+impl Ord for Head {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let by_key = self.key.cmp(&other.key);
+        if self.descending {
+            by_key.reverse()
+        } else {
+            by_key
+        }
+        .then_with(|| other.version.seq.cmp(&self.version.seq))
+        .then_with(|| self.source.cmp(&other.source))
+    }
+}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Head {}
+
+impl Source {
+    fn next(&mut self, start: &[u8]) -> io::Result<Option<(Key, Version)>> {
+        match self {
+            Source::Memtable(entries) => Ok(entries.next()),
+            Source::Table { reader, .. } => {
+                while let Some((key, seq)) = reader.next_key()? {
+                    if key < start {
+                        reader.skip_entry()?;
+                        continue;
+                    }
+                    let key = key.to_vec();
+                    let entry = reader.read_entry()?.to_entry();
+                    return Ok(Some((key, Version::new(seq, entry))));
+                }
+                Ok(None)
+            }
+            Source::TableRev(reader) => reader.next_version(),
+        }
+    }
+}
+
+impl Merge {
+    /// Merge `memtable`, in key order with each key's versions newest
+    /// first, with `tables`, going down the keys if `descending`
+    fn new(
+        mut memtable: Vec<(Key, Version)>,
+        tables: Vec<Arc<SSTable>>,
+        start: &[u8],
+        end: Option<Key>,
+        read_ahead: u64,
+        descending: bool,
+    ) -> io::Result<Self> {
+        if descending {
+            // Stable, so each key's versions stay newest first
+            memtable.sort_by(|(a, _), (b, _)| b.cmp(a));
+        }
+        let mut sources = vec![Source::Memtable(memtable.into_iter())];
+        for table in tables {
+            if descending {
+                sources.push(Source::TableRev(table.entries_rev(start, end.as_deref())?));
+                continue;
+            }
+            let mut reader = table.entries_from(start)?;
+            reader.read_ahead(read_ahead);
+            sources.push(Source::Table {
+                reader,
+                _table: table,
+            });
+        }
+
+        let mut merge = Merge {
+            sources,
+            heap: BinaryHeap::new(),
+            end,
+            descending,
+        };
+        for source in 0..merge.sources.len() {
+            merge.advance(source, start)?;
+        }
+        Ok(merge)
+    }
+
+    /// Pull the next entry from `source` into the heap, unless it's past the end
+    fn advance(&mut self, source: usize, start: &[u8]) -> io::Result<()> {
+        if let Some((key, version)) = self.sources[source].next(start)? {
+            if self.end.as_ref().is_none_or(|end| &key < end) {
+                self.heap.push(Reverse(Head {
+                    key,
+                    version,
+                    source,
+                    descending: self.descending,
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    fn next_version(&mut self) -> io::Result<Option<(Key, Version)>> {
+        match self.heap.pop() {
+            Some(Reverse(head)) => {
+                self.advance(head.source, &head.key)?;
+                Ok(Some((head.key, head.version)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn peek_key(&self) -> Option<&[u8]> {
+        self.heap.peek().map(|Reverse(head)| head.key.as_slice())
+    }
+}
+
+impl Scan {
+    /// Merge `memtable` entries with `tables`, yielding keys in `[start, end)`
+    /// as of sequence number `max_seq` and time `now`, applying merge
+    /// operands with `merge`, in descending order if `descending`
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn new(
+        memtable: Vec<(Key, Version)>,
+        tables: Vec<Arc<SSTable>>,
+        start: &[u8],
+        end: Option<Key>,
+        max_seq: u64,
+        now: u64,
+        merge: Option<MergeOperator>,
+        read_ahead: u64,
+        slow: Option<(Arc<SlowReadLog>, Instant)>,
+        descending: bool,
+    ) -> io::Result<Self> {
+        Ok(Scan {
+            merge: Merge::new(memtable, tables, start, end, read_ahead, descending)?,
+            max_seq,
+            now,
+            merge_operator: merge,
+            failed: false,
+            slow: slow.map(|(log, started)| (log, started, start.to_vec())),
+        })
+    }
+
+    fn next_live(&mut self) -> io::Result<Option<(Key, Value)>> {
+        while let Some((key, version)) = self.merge.next_version()? {
+            // The first version of a key at or below max_seq decides it;
+            // everything after it for the same key is shadowed
+            if version.seq > self.max_seq {
+                continue;
+            }
+            // Merge operands need the versions beneath them
+            let mut entries = vec![version.entry];
+            while self.merge.peek_key() == Some(key.as_slice()) {
+                let older = self.merge.next_version()?;
+                if entries.last().is_some_and(Entry::is_merge) {
+                    entries.extend(older.map(|(_, older)| older.entry));
+                }
+            }
+
+            if entries[0].is_merge() {
+                let merge = self.merge_operator.as_ref();
+                if let Some(value) = entry::resolve(&key, &entries, self.now, merge)? {
+                    return Ok(Some((key, value)));
+                }
+            } else if let Some(value) = entries.swap_remove(0).into_live_value(self.now) {
+                return Ok(Some((key, value)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl LevelIter {
+    pub(super) fn new(tables: Vec<Arc<SSTable>>, read_ahead: u64) -> io::Result<Self> {
+        Ok(LevelIter {
+            merge: Merge::new(Vec::new(), tables, &[], None, read_ahead, false)?,
+            failed: false,
+        })
+    }
+}
+
+/// Number of live keys in `[start, end)` across `memtable` entries, given
+/// as (key, seq, holds a value), and `tables`. Only keys and entry kinds are
+/// decoded; values are skipped on disk, and tables whose key range misses
+/// the range aren't opened at all.
+pub(super) fn count_live(
+    memtable: Vec<(Key, u64, bool)>,
+    tables: Vec<Arc<SSTable>>,
+    start: &[u8],
+    end: Option<&[u8]>,
+    now: u64,
+) -> io::Result<u64> {
+    let mut count = 0;
+    visit_newest(memtable, tables, start, end, now, |_, live| {
+        count += u64::from(live)
+    })?;
+    Ok(count)
+}
+
+/// The live keys in `[start, end)`, in ascending order, read the way
+/// [`count_live`] reads them
+pub(super) fn live_keys(
+    memtable: Vec<(Key, u64, bool)>,
+    tables: Vec<Arc<SSTable>>,
+    start: &[u8],
+    end: Option<&[u8]>,
+    now: u64,
+) -> io::Result<Vec<Key>> {
+    let mut keys = Vec::new();
+    visit_newest(memtable, tables, start, end, now, |key, live| {
+        if live {
+            keys.push(key);
+        }
+    })?;
+    Ok(keys)
+}
+
+/// Call `visit` with each key in `[start, end)`, in order, and whether its
+/// newest version holds a value not yet expired at `now`
+fn visit_newest(
+    memtable: Vec<(Key, u64, bool)>,
+    tables: Vec<Arc<SSTable>>,
+    start: &[u8],
+    end: Option<&[u8]>,
+    now: u64,
+    mut visit: impl FnMut(Key, bool),
+) -> io::Result<()> {
+    let mut memtable = memtable.into_iter();
+    let mut readers = Vec::new();
+    for table in tables {
+        let overlaps = table.key_range().is_none_or(|(smallest, largest)| {
+            largest >= start && end.is_none_or(|end| smallest < end)
+        });
+        if overlaps {
+            readers.push(table.entries()?);
+        }
+    }
+
+    let sources = readers.len() + 1;
+    // Next key from a source, by index: the memtable first, then each reader
+    let mut next = |source: usize| -> io::Result<Option<(Key, u64, bool)>> {
+        let entry = match source {
+            0 => memtable.next(),
+            _ => {
+                let reader = &mut readers[source - 1];
+                let mut found = None;
+                while let Some((key, seq)) = reader.next_key()? {
+                    if key < start {
+                        reader.skip_entry()?;
+                        continue;
+                    }
+                    let key = key.to_vec();
+                    found = Some((key, seq, reader.skip_value(now)?));
+                    break;
+                }
+                found
+            }
+        };
+        Ok(entry.filter(|(key, _, _)| end.is_none_or(|end| key.as_slice() < end)))
+    };
+
+    // Ordered by key, then newest first
+    let mut heap = BinaryHeap::new();
+    for source in 0..sources {
+        if let Some((key, seq, live)) = next(source)? {
+            heap.push(Reverse((key, Reverse(seq), source, live)));
+        }
+    }
+
+    let mut last: Option<Key> = None;
+    while let Some(Reverse((key, _, source, live))) = heap.pop() {
+        if let Some((key, seq, live)) = next(source)? {
+            heap.push(Reverse((key, Reverse(seq), source, live)));
+        }
+        // The newest version of each key decides whether it's live
+        if last.as_ref() != Some(&key) {
+            last = Some(key.clone());
+            visit(key, live);
+        }
+    }
+    Ok(())
+}
+
+impl Iterator for LevelIter {
+    type Item = io::Result<(Key, Version)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        match self.merge.next_version() {
+            Ok(entry) => entry.map(Ok),
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl Drop for Scan {
+    fn drop(&mut self) {
+        let Some((log, started, start)) = self.slow.take() else {
+            return;
+        };
+        let mut trace = ReadTrace::default();
+        for source in &self.merge.sources {
+            let bytes_read = match source {
+                Source::Memtable(_) => continue,
+                Source::Table { reader, .. } => reader.bytes_read(),
+                Source::TableRev(reader) => reader.bytes_read(),
+            };
+            trace.tables_probed += 1;
+            trace.bytes_read += bytes_read;
+        }
+        let target = || SlowReadTarget::Scan {
+            start,
+            end: self.merge.end.take().unwrap_or_default(),
+        };
+        log.finish(started, target, trace);
+    }
+}
+
+impl Iterator for Scan {
+    type Item = io::Result<(Key, Value)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        match self.next_live() {
+            Ok(entry) => entry.map(Ok),
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
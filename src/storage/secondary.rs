@@ -0,0 +1,383 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::{identity, parse_table_name, Identity, Storage, StorageOptions};
+use crate::entry;
+use crate::error::StorageError;
+use crate::memtable::MemTable;
+use crate::sstable::{Lookup, SSTable};
+use crate::wal::{Operation, WalRecord, WAL};
+use crate::Value;
+
+// Times a catch-up starts over when the primary removes a file under it
+const CATCH_UP_ATTEMPTS: usize = 10;
+
+/// Tables in read order: by level, then newest first
+pub(super) type Tables = BTreeMap<(usize, Reverse<u64>), Arc<SSTable>>;
+
+/// A read-only view of another instance's data directory, kept current by
+/// [`try_catch_up`](Secondary::try_catch_up).
+///
+/// Nothing is ever written to the primary's directory, and its lock is left
+/// to the primary. Tables are opened
+/// through hard links in the scratch directory, so they stay readable after
+/// the primary compacts them away; where links aren't possible (another
+/// filesystem) the primary's files are read directly until the next
+/// catch-up. A table the primary is still writing may fail reads until it's
+/// finished.
+pub struct Secondary {
+    primary_dir: PathBuf,
+    scratch_dir: PathBuf,
+    options: StorageOptions,
+    identity: Identity,
+    tables: Tables,
+    memtable: MemTable,
+    replayed: Replayed,
+    log: Option<(u64, u64)>, // (log number, offset replayed up to)
+}
+
+impl Storage {
+    /// Open `primary_dir` read-only alongside the instance writing to it,
+    /// using `scratch_dir` for this reader's own files; see [`Secondary`]
+    pub fn open_secondary<P: AsRef<Path>, Q: AsRef<Path>>(
+        primary_dir: P,
+        scratch_dir: Q,
+    ) -> Result<Secondary, StorageError> {
+        let options = StorageOptions::default();
+        let primary_dir = primary_dir.as_ref().to_path_buf();
+        let identity = identity::load(&primary_dir, &options.comparator_name)?;
+
+        // Links left by an earlier secondary are stale
+        let scratch_dir = scratch_dir.as_ref().to_path_buf();
+        fs::create_dir_all(&scratch_dir)?;
+        for entry in fs::read_dir(&scratch_dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|s| s.to_str()) == Some("sst") {
+                fs::remove_file(path)?;
+            }
+        }
+
+        let mut secondary = Secondary {
+            primary_dir,
+            scratch_dir,
+            options,
+            identity,
+            tables: BTreeMap::new(),
+            memtable: MemTable::new(),
+            replayed: Replayed::default(),
+            log: None,
+        };
+        secondary.try_catch_up()?;
+        Ok(secondary)
+    }
+}
+
+impl Secondary {
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+
+    /// The newest value of `key` as of the last catch-up
+    pub fn get(&self, key: &[u8]) -> io::Result<Option<Value>> {
+        lookup(&self.memtable, &self.tables, key, self.options.now())
+    }
+
+    /// Pick up the primary's flushes, compactions and new WAL records.
+    /// If the primary removes a file while this runs, start over from a
+    /// fresh listing.
+    pub fn try_catch_up(&mut self) -> io::Result<()> {
+        for _ in 0..CATCH_UP_ATTEMPTS {
+            match self.catch_up_once() {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                result => return result,
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            format!(
+                "Primary at {:?} kept changing during {} catch-up attempts",
+                self.primary_dir, CATCH_UP_ATTEMPTS
+            ),
+        ))
+    }
+
+    fn catch_up_once(&mut self) -> io::Result<()> {
+        let wal_dir = self.primary_dir.join("wal");
+        // List the logs before the tables: a flush finishing in between
+        // leaves its data in both, never in neither
+        let logs = WAL::logs(&wal_dir)?;
+        let tables = self.load_tables()?;
+
+        let (records, position) = match (logs.last().copied(), self.log) {
+            (None, _) => (Vec::new(), None),
+            (Some(number), Some((current, offset))) if number == current => {
+                let (records, end) = WAL::read_log(&wal_dir, number, offset)?;
+                (records, Some((number, end)))
+            }
+            (Some(number), _) => {
+                // Older segments may not be flushed yet, so their writes
+                // may not be in any table
+                let mut records = Vec::new();
+                let mut end = 0;
+                for &log in &logs {
+                    let (log_records, log_end) = WAL::read_log(&wal_dir, log, 0)?;
+                    records.extend(log_records);
+                    end = log_end;
+                }
+                (records, Some((number, end)))
+            }
+        };
+
+        // Nothing can fail from here on, so a retry always starts from the
+        // previous consistent state
+        if position.map(|(number, _)| number) != self.log.map(|(number, _)| number) {
+            // The primary flushed or started a new segment: start over
+            // from its logs
+            self.memtable = MemTable::new();
+            let seq = tables
+                .values()
+                .map(|table| table.properties().max_seq)
+                .max()
+                .unwrap_or(0);
+            self.replayed = Replayed { seq, applied: 0 };
+        }
+        self.apply(records);
+        self.tables = tables;
+        self.log = position;
+        Ok(())
+    }
+
+    fn apply(&mut self, records: Vec<WalRecord>) {
+        replay(
+            &mut self.memtable,
+            &mut self.replayed,
+            records,
+            self.options.now(),
+        );
+    }
+
+    /// The primary's live tables, reusing those already open
+    fn load_tables(&self) -> io::Result<Tables> {
+        let mut tables = BTreeMap::new();
+        for dir in self.options.table_dirs(&self.primary_dir) {
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.extension().and_then(|s| s.to_str()) != Some("sst") {
+                    continue;
+                }
+                if let Some((level, number)) = parse_table_name(&path) {
+                    let key = (level, Reverse(number));
+                    let table = match self.tables.get(&key) {
+                        Some(table) => table.clone(),
+                        None => Arc::new(self.pin(&path)?),
+                    };
+                    tables.insert(key, table);
+                }
+            }
+        }
+        Ok(tables)
+    }
+
+    /// Open a primary table through a hard link in the scratch directory,
+    /// removed again once the table is no longer needed
+    fn pin(&self, path: &Path) -> io::Result<SSTable> {
+        let link = self.scratch_dir.join(path.file_name().unwrap_or_default());
+        let _ = fs::remove_file(&link);
+        match fs::hard_link(path, &link) {
+            Ok(()) => {
+                let table = SSTable::new(link)?;
+                table.mark_obsolete();
+                Ok(table)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(e),
+            Err(_) => Ok(SSTable::new(path.to_path_buf())?),
+        }
+    }
+}
+
+/// The newest value of `key` in `memtable`, then `tables` in read order.
+/// With no merge operator to apply them, merge operands can't be read.
+pub(super) fn lookup(
+    memtable: &MemTable,
+    tables: &Tables,
+    key: &[u8],
+    now: u64,
+) -> io::Result<Option<Value>> {
+    if let Some(entry) = memtable.get(key) {
+        if entry.is_merge() {
+            return Err(entry::no_merge_operator());
+        }
+        return Ok(entry.live_value(now).cloned());
+    }
+
+    let mut value = Vec::new();
+    for table in tables.values() {
+        if !table.might_contain_key(key) {
+            continue;
+        }
+        match table.lookup_into(key, u64::MAX, &mut value)? {
+            Lookup::Found => return Ok(Some(value)),
+            Lookup::Expiring(expires_at) => return Ok((expires_at > now).then_some(value)),
+            Lookup::Deleted => return Ok(None),
+            Lookup::Merge => return Err(entry::no_merge_operator()),
+            Lookup::Missing => {}
+        }
+    }
+    Ok(None)
+}
+
+/// How far WAL replay has got
+#[derive(Debug, Clone, Copy, Default)]
+pub(super) struct Replayed {
+    /// The last sequence number used; records logged without one are
+    /// numbered after it
+    pub(super) seq: u64,
+    /// Records logged at or below this are already applied or in tables
+    pub(super) applied: u64,
+}
+
+/// Apply WAL `records` to `memtable` at the sequence numbers they were
+/// logged at, skipping stale ones, and move `replayed` past them. Returns
+/// how many were applied.
+pub(super) fn replay(
+    memtable: &mut MemTable,
+    replayed: &mut Replayed,
+    records: Vec<WalRecord>,
+    now: u64,
+) -> usize {
+    let mut applied = 0;
+    for record in records {
+        if let Some((_, last)) = record.seq_range() {
+            // Its data is already in tables
+            replayed.seq = replayed.seq.max(last);
+            replayed.applied = replayed.applied.max(last);
+            continue;
+        }
+        let seq = match record.seq {
+            // Logged twice, as by a retried segment, or already flushed
+            Some(seq) if seq <= replayed.applied => continue,
+            Some(seq) => seq,
+            None => replayed.seq + 1,
+        };
+        replayed.seq = replayed.seq.max(seq);
+        replayed.applied = seq;
+        match (record.op, record.value) {
+            (Operation::Put, Some(value)) => {
+                memtable.insert(record.key, seq, value, &[]);
+            }
+            (Operation::PutExpiring, Some(value)) => {
+                let expires_at = record.expires_at.unwrap_or(0);
+                memtable.insert_expiring(record.key, seq, value, expires_at, &[]);
+            }
+            (Operation::Merge, Some(operand)) => {
+                memtable.merge(record.key, seq, operand, &[]);
+            }
+            (Operation::Put | Operation::PutExpiring | Operation::Merge, None)
+            | (Operation::DirectBatch, _) => continue,
+            (Operation::Delete, _) => {
+                // The WAL doesn't record when the delete happened, so the
+                // retention window restarts from recovery time; it can only
+                // grow, never shrink, across a restart
+                memtable.delete(record.key, seq, now, &[]);
+            }
+        }
+        applied += 1;
+    }
+    applied
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Every file in a data directory and its WAL, with its length
+    fn listing(dir: &Path) -> Vec<(PathBuf, u64)> {
+        let mut files: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .chain(fs::read_dir(dir.join("wal")).unwrap())
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                let len = fs::metadata(&path).unwrap().len();
+                (path, len)
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn test_catch_up_sees_new_writes() {
+        let primary_dir = TempDir::new().unwrap();
+        let scratch_dir = TempDir::new().unwrap();
+        let primary = Storage::new(primary_dir.path(), false).unwrap();
+        primary.put(b"a", b"1").unwrap();
+        primary.flush().unwrap();
+        primary.put(b"b", b"2").unwrap();
+
+        let before = listing(primary_dir.path());
+        let mut secondary =
+            Storage::open_secondary(primary_dir.path(), scratch_dir.path()).unwrap();
+        assert_eq!(listing(primary_dir.path()), before);
+        assert_eq!(secondary.identity(), primary.identity());
+        assert_eq!(secondary.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(secondary.get(b"b").unwrap(), Some(b"2".to_vec()));
+
+        primary.put(b"c", b"3").unwrap();
+        primary.delete(b"a").unwrap();
+        assert_eq!(secondary.get(b"c").unwrap(), None);
+        secondary.try_catch_up().unwrap();
+        assert_eq!(secondary.get(b"c").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(secondary.get(b"a").unwrap(), None);
+
+        // A flush moves the memtable into a table and starts a new log
+        primary.flush().unwrap();
+        primary.put(b"d", b"4").unwrap();
+        let before = listing(primary_dir.path());
+        secondary.try_catch_up().unwrap();
+        assert_eq!(listing(primary_dir.path()), before);
+        for (key, value) in [(b"b", &b"2"[..]), (b"c", b"3"), (b"d", b"4")] {
+            assert_eq!(secondary.get(key).unwrap(), Some(value.to_vec()));
+        }
+        assert_eq!(secondary.get(b"a").unwrap(), None);
+    }
+
+    #[test]
+    fn test_survives_compaction() {
+        let primary_dir = TempDir::new().unwrap();
+        let scratch_dir = TempDir::new().unwrap();
+        let primary = Storage::new(primary_dir.path(), false).unwrap();
+        for round in 0..3 {
+            for i in 0..20 {
+                let key = format!("key{:02}", round * 20 + i).into_bytes();
+                primary.put(key, vec![b'v'; 10]).unwrap();
+            }
+            primary.flush().unwrap();
+        }
+        let mut secondary =
+            Storage::open_secondary(primary_dir.path(), scratch_dir.path()).unwrap();
+
+        // The fourth flush compacts L0 away underneath the secondary
+        primary.put(b"late", b"v").unwrap();
+        primary.flush().unwrap();
+        assert!(!primary_dir.path().join("L0_0.sst").exists());
+        assert_eq!(secondary.get(b"key05").unwrap(), Some(vec![b'v'; 10]));
+
+        secondary.try_catch_up().unwrap();
+        assert_eq!(secondary.get(b"key45").unwrap(), Some(vec![b'v'; 10]));
+        assert_eq!(secondary.get(b"late").unwrap(), Some(b"v".to_vec()));
+
+        // Links to compacted tables go once they're no longer read
+        let links: Vec<_> = fs::read_dir(scratch_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(links.len(), 1, "{:?}", links);
+        drop(secondary);
+        assert_eq!(fs::read_dir(scratch_dir.path()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,321 @@
+use std::io;
+use std::path::Path;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use super::{Scan, Storage, StorageOptions, WriteBatch};
+use crate::error::StorageError;
+use crate::stats::StorageStats;
+use crate::{Key, Value};
+
+/// A `Storage` that many threads can use at once through an
+/// `Arc<SharedStorage>`. Reads and writes go through a shared lock, since
+/// `Storage` already lets them run side by side: writers queue on its own
+/// writer lock, and reads never wait for one.
+///
+/// Flushes and compactions write their tables on background threads, and
+/// writers wait for them without holding anything, so reads carry on
+/// meanwhile and only pause while a finished table is swapped in. Only
+/// maintenance that needs `&mut Storage`, through [`lock`](Self::lock),
+/// holds everything else up.
+///
+/// ```
+/// use std::sync::Arc;
+/// use lsm_rust::storage::SharedStorage;
+///
+/// # let dir = tempfile::tempdir().unwrap();
+/// let db = Arc::new(SharedStorage::open(dir.path()).unwrap());
+/// let writer = {
+///     let db = db.clone();
+///     std::thread::spawn(move || db.put(b"key", b"value"))
+/// };
+/// writer.join().unwrap().unwrap();
+/// assert_eq!(db.get(b"key").unwrap(), Some(b"value".to_vec()));
+/// ```
+pub struct SharedStorage {
+    storage: RwLock<Storage>,
+}
+
+fn poisoned() -> io::Error {
+    io::Error::other("Storage lock poisoned by a panicked thread")
+}
+
+impl SharedStorage {
+    pub fn new(storage: Storage) -> Self {
+        SharedStorage {
+            storage: RwLock::new(storage),
+        }
+    }
+
+    /// Open the database in `data_dir` with default options
+    pub fn open<P: AsRef<Path>>(data_dir: P) -> Result<Self, StorageError> {
+        Self::open_with_options(data_dir, StorageOptions::default())
+    }
+
+    pub fn open_with_options<P: AsRef<Path>>(
+        data_dir: P,
+        options: StorageOptions,
+    ) -> Result<Self, StorageError> {
+        Storage::open_with_options(data_dir, options).map(Self::new)
+    }
+
+    /// Shared access for anything `Storage` does through `&self`, reads
+    /// and writes alike
+    pub fn read(&self) -> io::Result<RwLockReadGuard<'_, Storage>> {
+        self.storage.read().map_err(|_| poisoned())
+    }
+
+    /// Exclusive access for anything else. Reads and writes wait for the
+    /// guard, so long operations such as `compact_all` stall them
+    /// throughout.
+    pub fn lock(&self) -> io::Result<RwLockWriteGuard<'_, Storage>> {
+        self.storage.write().map_err(|_| poisoned())
+    }
+
+    pub fn into_inner(self) -> io::Result<Storage> {
+        self.storage.into_inner().map_err(|_| poisoned())
+    }
+
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Value>, StorageError> {
+        let mut value = Vec::new();
+        Ok(self
+            .read()?
+            .get_into(key.as_ref(), &mut value)?
+            .then_some(value))
+    }
+
+    pub fn multi_get(&self, keys: &[Key]) -> Result<Vec<Option<Value>>, StorageError> {
+        self.read()?.multi_get(keys)
+    }
+
+    /// A scan of `[start, end)`. It holds its own references to the tables,
+    /// so the lock is released once it's open.
+    pub fn scan(&self, start: &[u8], end: &[u8]) -> Result<Scan, StorageError> {
+        self.read()?.scan(start, end)
+    }
+
+    /// [`scan`](SharedStorage::scan) in descending key order
+    pub fn scan_rev(&self, start: &[u8], end: &[u8]) -> Result<Scan, StorageError> {
+        self.read()?.scan_rev(start, end)
+    }
+
+    pub fn stats(&self) -> Result<StorageStats, StorageError> {
+        Ok(self.read()?.stats())
+    }
+
+    pub fn put(&self, key: impl Into<Key>, value: impl Into<Value>) -> Result<(), StorageError> {
+        self.read()?.put(key, value)
+    }
+
+    /// Log a merge operand; see [`Storage::merge`]. Concurrent merges to
+    /// the same key all count, with no read in between to race on.
+    pub fn merge(
+        &self,
+        key: impl Into<Key>,
+        operand: impl Into<Value>,
+    ) -> Result<(), StorageError> {
+        self.read()?.merge(key, operand)
+    }
+
+    pub fn delete(&self, key: impl AsRef<[u8]>) -> Result<(), StorageError> {
+        self.read()?.delete(key)
+    }
+
+    pub fn write(&self, batch: WriteBatch) -> Result<(), StorageError> {
+        self.read()?.write(batch)
+    }
+
+    /// Write the memtable out as a level 0 table and settle the compactions
+    /// that makes due; see [`Storage::flush`]
+    pub fn flush(&self) -> Result<(), StorageError> {
+        self.read()?.flush()
+    }
+
+    /// Write a consistent copy of the database to `dest`, as
+    /// `Storage::checkpoint` does. Writes carry on while it's made, and the
+    /// copy holds everything written before its memtable was frozen.
+    pub fn checkpoint<P: AsRef<Path>>(&self, dest: P) -> Result<usize, StorageError> {
+        self.read()?.checkpoint(dest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use tempfile::TempDir;
+
+    // How often the tests check on the threads they run
+    const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+    fn key(writer: usize, i: usize) -> Vec<u8> {
+        format!("w{}-{:05}", writer, i).into_bytes()
+    }
+
+    #[test]
+    fn test_storage_is_send_and_sync() {
+        fn shareable<T: Send + Sync>() {}
+        shareable::<Storage>();
+        shareable::<SharedStorage>();
+    }
+
+    #[test]
+    fn test_concurrent_writers_and_readers() {
+        const WRITERS: usize = 4;
+        const KEYS: usize = 1500;
+        let temp_dir = TempDir::new().unwrap();
+        let options = StorageOptions::default().memtable_size(16 * 1024);
+        let db = Arc::new(SharedStorage::open_with_options(temp_dir.path(), options).unwrap());
+        // Keys below each writer's mark have been acknowledged
+        let acknowledged: Arc<Vec<AtomicUsize>> =
+            Arc::new((0..WRITERS).map(|_| AtomicUsize::new(0)).collect());
+        let done = Arc::new(AtomicBool::new(false));
+
+        let writers: Vec<_> = (0..WRITERS)
+            .map(|w| {
+                let (db, acknowledged) = (db.clone(), acknowledged.clone());
+                thread::spawn(move || {
+                    for i in 0..KEYS {
+                        db.put(key(w, i), vec![w as u8; 100]).unwrap();
+                        if i % 10 == 9 {
+                            db.delete(key(w, i)).unwrap();
+                        }
+                        acknowledged[w].store(i + 1, Ordering::Release);
+                        if i % 500 == 499 {
+                            db.flush().unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        let readers: Vec<_> = (0..4)
+            .map(|r| {
+                let (db, acknowledged, done) = (db.clone(), acknowledged.clone(), done.clone());
+                thread::spawn(move || {
+                    let mut reads = 0;
+                    while !done.load(Ordering::Acquire) {
+                        let w = (reads + r) % WRITERS;
+                        let mark = acknowledged[w].load(Ordering::Acquire);
+                        if mark > 0 {
+                            let i = (reads * 7919) % mark;
+                            let expected = (i % 10 != 9).then(|| vec![w as u8; 100]);
+                            assert_eq!(db.get(key(w, i)).unwrap(), expected);
+                        }
+                        reads += 1;
+                    }
+                    reads
+                })
+            })
+            .collect();
+
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        done.store(true, Ordering::Release);
+        for reader in readers {
+            assert!(reader.join().unwrap() > 0);
+        }
+
+        let live = WRITERS * (KEYS - KEYS / 10);
+        assert_eq!(db.scan(b"", b"").unwrap().count(), live);
+        let storage = Arc::into_inner(db).unwrap().into_inner().unwrap();
+        storage.close().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        assert_eq!(storage.scan(b"", b"").unwrap().count(), live);
+    }
+
+    #[test]
+    fn test_checkpoint_during_writes() {
+        const WRITERS: usize = 2;
+        const KEYS: usize = 2000;
+        let temp_dir = TempDir::new().unwrap();
+        let options = StorageOptions::default().memtable_size(16 * 1024);
+        let db = Arc::new(SharedStorage::open_with_options(temp_dir.path(), options).unwrap());
+        let acknowledged: Arc<Vec<AtomicUsize>> =
+            Arc::new((0..WRITERS).map(|_| AtomicUsize::new(0)).collect());
+        let writers: Vec<_> = (0..WRITERS)
+            .map(|w| {
+                let (db, acknowledged) = (db.clone(), acknowledged.clone());
+                thread::spawn(move || {
+                    for i in 0..KEYS {
+                        db.put(key(w, i), vec![w as u8; 100]).unwrap();
+                        acknowledged[w].store(i + 1, Ordering::Release);
+                    }
+                })
+            })
+            .collect();
+
+        while acknowledged[0].load(Ordering::Acquire) < KEYS / 4 {
+            thread::sleep(POLL_INTERVAL);
+        }
+        let marks = || -> Vec<usize> {
+            acknowledged
+                .iter()
+                .map(|mark| mark.load(Ordering::Acquire))
+                .collect()
+        };
+        let before = marks();
+        let dest = TempDir::new().unwrap();
+        db.checkpoint(dest.path()).unwrap();
+        let after = marks();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        // Change everything the copy holds, compacting the tables away
+        for w in 0..WRITERS {
+            db.delete(key(w, 0)).unwrap();
+            db.put(key(w, 1), b"changed").unwrap();
+        }
+        db.lock().unwrap().compact_all().unwrap();
+
+        // Each writer's keys in the copy run up to some point during the
+        // checkpoint, with nothing after it
+        let copy = Storage::new(dest.path(), false).unwrap();
+        for w in 0..WRITERS {
+            let prefix = format!("w{}-", w).into_bytes();
+            let mut end = prefix.clone();
+            *end.last_mut().unwrap() += 1;
+            let keys: Vec<_> = copy
+                .scan(&prefix, &end)
+                .unwrap()
+                .map(Result::unwrap)
+                .collect();
+            assert!(before[w] <= keys.len() && keys.len() <= after[w]);
+            for (i, (k, value)) in keys.into_iter().enumerate() {
+                assert_eq!((k, value), (key(w, i), vec![w as u8; 100]));
+            }
+        }
+    }
+
+    #[test]
+    fn test_gets_run_during_flush_and_compaction() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut options = StorageOptions::default().memtable_size(4 * 1024);
+        // Every merge takes at least half a second
+        options.compaction_output_hook = Some(Arc::new(|_: &Path| {
+            thread::sleep(Duration::from_millis(500));
+        }));
+        let db = Arc::new(SharedStorage::open_with_options(temp_dir.path(), options).unwrap());
+        for i in 0..400 {
+            db.put(key(0, i), vec![1; 64]).unwrap();
+        }
+
+        let flusher = {
+            let db = db.clone();
+            thread::spawn(move || db.flush().unwrap())
+        };
+        let mut slowest = Duration::ZERO;
+        while !flusher.is_finished() {
+            let started = Instant::now();
+            assert_eq!(db.get(key(0, 7)).unwrap(), Some(vec![1; 64]));
+            slowest = slowest.max(started.elapsed());
+            thread::sleep(POLL_INTERVAL);
+        }
+        flusher.join().unwrap();
+        let stats = db.stats().unwrap();
+        assert!(stats.since_open.compactions > 0);
+        assert!(slowest < Duration::from_millis(250), "{:?}", slowest);
+    }
+}
@@ -0,0 +1,222 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::events::EventLog;
+use super::{json_string, Storage, StorageOptions};
+use crate::Key;
+
+// Slow reads kept in memory; older ones are dropped first
+const SLOW_READ_CAPACITY: usize = 128;
+
+/// The read a [`SlowRead`] describes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SlowReadTarget {
+    Get {
+        key: Key,
+    },
+    MultiGet {
+        keys: usize,
+    },
+    /// An empty `end` ran to the last key
+    Scan {
+        start: Key,
+        end: Key,
+    },
+}
+
+/// A read that took at least `StorageOptions::slow_read_threshold`. There's
+/// no block cache, so every table probed was read from its file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlowRead {
+    pub target: SlowReadTarget,
+    pub duration: Duration,
+    /// Tables whose data section was read
+    pub tables_probed: usize,
+    /// Tables whose bloom filter passed a key they turned out not to hold
+    pub bloom_false_positives: usize,
+    pub bytes_read: u64,
+}
+
+impl SlowRead {
+    fn to_json(&self) -> String {
+        let target = match &self.target {
+            SlowReadTarget::Get { key } => {
+                format!("\"op\":\"get\",\"key\":{}", escaped(key))
+            }
+            SlowReadTarget::MultiGet { keys } => format!("\"op\":\"multi_get\",\"keys\":{}", keys),
+            SlowReadTarget::Scan { start, end } => format!(
+                "\"op\":\"scan\",\"start\":{},\"end\":{}",
+                escaped(start),
+                escaped(end)
+            ),
+        };
+        format!(
+            "{{\"kind\":\"slow_read\",{},\"duration_us\":{},\"tables_probed\":{},\
+             \"bloom_false_positives\":{},\"bytes_read\":{}}}",
+            target,
+            self.duration.as_micros(),
+            self.tables_probed,
+            self.bloom_false_positives,
+            self.bytes_read
+        )
+    }
+}
+
+fn escaped(key: &[u8]) -> String {
+    json_string(&key.escape_ascii().to_string())
+}
+
+/// What a read touched, gathered as it runs
+#[derive(Debug, Default)]
+pub(super) struct ReadTrace {
+    pub(super) tables_probed: usize,
+    pub(super) bloom_negatives: usize,
+    pub(super) bloom_false_positives: usize,
+    pub(super) bytes_read: u64,
+}
+
+/// The most recent slow reads, shared with the scans that report into it
+pub(super) struct SlowReadLog {
+    threshold: Option<Duration>,
+    entries: Mutex<VecDeque<SlowRead>>,
+    journal: Option<Arc<EventLog>>,
+}
+
+impl SlowReadLog {
+    pub(super) fn new(options: &StorageOptions, events: &Arc<EventLog>) -> Self {
+        SlowReadLog {
+            threshold: options.slow_read_threshold,
+            entries: Mutex::new(VecDeque::new()),
+            journal: options.journal_slow_reads.then(|| events.clone()),
+        }
+    }
+
+    /// Start timing a read, or `None` without a threshold, so reads skip
+    /// the clock entirely
+    pub(super) fn start(&self) -> Option<Instant> {
+        self.threshold.map(|_| Instant::now())
+    }
+
+    /// Keep the read begun at `started` if it ran past the threshold
+    pub(super) fn finish(
+        &self,
+        started: Instant,
+        target: impl FnOnce() -> SlowReadTarget,
+        trace: ReadTrace,
+    ) {
+        let duration = started.elapsed();
+        if self.threshold.is_none_or(|threshold| duration < threshold) {
+            return;
+        }
+
+        let read = SlowRead {
+            target: target(),
+            duration,
+            tables_probed: trace.tables_probed,
+            bloom_false_positives: trace.bloom_false_positives,
+            bytes_read: trace.bytes_read,
+        };
+        if let Some(journal) = &self.journal {
+            journal.record_line(read.to_json());
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.len() == SLOW_READ_CAPACITY {
+            entries.pop_front();
+        }
+        entries.push_back(read);
+    }
+
+    fn recent(&self) -> Vec<SlowRead> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.iter().cloned().collect()
+    }
+}
+
+impl Storage {
+    /// The most recent reads slower than `StorageOptions::slow_read_threshold`,
+    /// oldest first
+    pub fn slow_reads(&self) -> Vec<SlowRead> {
+        self.slow_reads.recent()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::events::EVENTS_FILE;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_slow_get_recorded() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = StorageOptions::default()
+            .bloom(None)
+            .slow_read_threshold(Some(Duration::ZERO))
+            .journal_slow_reads(true);
+        let storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+
+        // Three L0 tables with interleaved keys, so neither key ranges nor
+        // (absent) filters rule any out. key010 is in the oldest and key060
+        // in the middle one.
+        for offset in [1, 0, 2] {
+            for i in 0..50 {
+                let key = format!("key{:03}", i * 3 + offset).into_bytes();
+                storage.put(key, vec![b'v'; 10]).unwrap();
+            }
+            storage.flush().unwrap();
+        }
+        assert_eq!(storage.get(b"key010").unwrap(), Some(vec![b'v'; 10]));
+
+        let reads = storage.slow_reads();
+        assert_eq!(reads.len(), 1);
+        let read = &reads[0];
+        assert_eq!(
+            read.target,
+            SlowReadTarget::Get {
+                key: b"key010".to_vec()
+            }
+        );
+        assert_eq!(read.tables_probed, 3);
+        assert_eq!(read.bloom_false_positives, 0);
+        assert!(read.bytes_read > 0);
+
+        storage.multi_get(&[b"key060".to_vec()]).unwrap();
+        assert_eq!(storage.scan(b"key100", b"").unwrap().count(), 50);
+        let reads = storage.slow_reads();
+        assert_eq!(reads.len(), 3);
+        assert_eq!(reads[1].target, SlowReadTarget::MultiGet { keys: 1 });
+        assert_eq!(reads[1].tables_probed, 2);
+        assert_eq!(
+            reads[2].target,
+            SlowReadTarget::Scan {
+                start: b"key100".to_vec(),
+                end: Vec::new()
+            }
+        );
+        assert_eq!(reads[2].tables_probed, 3);
+
+        // Slow reads share the journal without disturbing recent_events
+        let journal = fs::read_to_string(temp_dir.path().join(EVENTS_FILE)).unwrap();
+        assert_eq!(journal.matches("\"kind\":\"slow_read\"").count(), 3);
+        assert!(journal.contains("\"op\":\"get\",\"key\":\"key010\""));
+        assert_eq!(storage.recent_events(10).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_fast_reads_not_recorded() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = StorageOptions::default().slow_read_threshold(Some(Duration::from_secs(60)));
+        let storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+        storage.put(b"key", b"value").unwrap();
+        storage.flush().unwrap();
+        storage.get(b"key").unwrap();
+        assert!(storage.slow_reads().is_empty());
+
+        // Without a threshold reads don't even look at the clock
+        let other = TempDir::new().unwrap();
+        let storage = Storage::new(other.path(), false).unwrap();
+        assert!(storage.slow_reads.start().is_none());
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use super::{Scan, Storage};
+use crate::error::StorageError;
+use crate::Value;
+
+/// Sequence numbers of live snapshots, each with the number of handles
+/// sharing it
+pub(super) type SnapshotList = Arc<Mutex<BTreeMap<u64, usize>>>;
+
+/// A point-in-time view of the database.
+///
+/// Reads through it, or through [`Storage::get_at`] and [`Storage::scan_at`],
+/// only see writes made before the snapshot was taken, and flushes and
+/// compactions keep every version it can read until it is dropped.
+pub struct Snapshot {
+    seq: u64,
+    live: SnapshotList,
+}
+
+impl Snapshot {
+    pub(super) fn new(seq: u64, live: &SnapshotList) -> Self {
+        *live.lock().unwrap().entry(seq).or_insert(0) += 1;
+        Snapshot {
+            seq,
+            live: live.clone(),
+        }
+    }
+
+    /// Sequence number of the last write visible to this snapshot
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Look up `key` in `storage` as of this snapshot. `storage` must be
+    /// the one the snapshot was taken from, or this fails with
+    /// `StorageError::InvalidArgument`.
+    pub fn get(&self, storage: &Storage, key: &[u8]) -> Result<Option<Value>, StorageError> {
+        storage.get_at(key, self)
+    }
+
+    /// Scan `[start, end)` in `storage` as of this snapshot; an empty `end`
+    /// scans to the last key. `storage` must be the one the snapshot was
+    /// taken from, as for [`get`](Snapshot::get).
+    pub fn scan(&self, storage: &Storage, start: &[u8], end: &[u8]) -> Result<Scan, StorageError> {
+        storage.scan_at(start, end, self)
+    }
+
+    /// The sequence number to read at, provided the snapshot was taken from
+    /// the storage whose snapshots are `live`; another's numbers mean
+    /// nothing there
+    pub(super) fn seq_in(&self, live: &SnapshotList) -> io::Result<u64> {
+        if !Arc::ptr_eq(&self.live, live) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Snapshot was taken from another storage",
+            ));
+        }
+        Ok(self.seq)
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let mut live = self.live.lock().unwrap();
+        if let Some(count) = live.get_mut(&self.seq) {
+            *count -= 1;
+            if *count == 0 {
+                live.remove(&self.seq);
+            }
+        }
+    }
+}
+
+/// Sequence numbers of every live snapshot, ascending
+pub(super) fn live_seqs(live: &SnapshotList) -> Vec<u64> {
+    live.lock().unwrap().keys().copied().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn pairs(scan: Scan) -> Vec<(Vec<u8>, Vec<u8>)> {
+        scan.map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn test_snapshot_scan_ignores_later_writes() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut storage = Storage::new(temp_dir.path(), false).unwrap();
+        for key in [b"a", b"b", b"c"] {
+            storage.put(key.to_vec(), b"old".to_vec()).unwrap();
+        }
+        storage.flush().unwrap();
+        let snapshot = storage.snapshot();
+        let original = pairs(storage.scan(b"", b"").unwrap());
+
+        storage.put(b"a", b"new").unwrap();
+        storage.delete(b"b").unwrap();
+        storage.put(b"d", b"new").unwrap();
+        assert_eq!(pairs(snapshot.scan(&storage, b"", b"").unwrap()), original);
+
+        // Still there once the changes are flushed and merged
+        storage.compact_all().unwrap();
+        assert_eq!(pairs(snapshot.scan(&storage, b"", b"").unwrap()), original);
+        assert_eq!(
+            pairs(snapshot.scan(&storage, b"b", b"c").unwrap()),
+            [(b"b".to_vec(), b"old".to_vec())]
+        );
+        assert_eq!(snapshot.get(&storage, b"b").unwrap(), Some(b"old".to_vec()));
+        assert_eq!(snapshot.get(&storage, b"d").unwrap(), None);
+
+        let fresh: Vec<_> = pairs(storage.scan(b"", b"").unwrap())
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(fresh, [b"a".to_vec(), b"c".to_vec(), b"d".to_vec()]);
+        assert_eq!(storage.get(b"a").unwrap(), Some(b"new".to_vec()));
+    }
+
+    #[test]
+    fn test_snapshot_refuses_other_storage() {
+        let (first_dir, second_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let first = Storage::new(first_dir.path(), false).unwrap();
+        let second = Storage::new(second_dir.path(), false).unwrap();
+        first.put(b"key", b"first").unwrap();
+        let snapshot = first.snapshot();
+        for i in 0..10u8 {
+            second.put(b"key", vec![i]).unwrap();
+        }
+
+        let err = snapshot.get(&second, b"key").unwrap_err();
+        assert!(matches!(err, StorageError::InvalidArgument(_)));
+        assert!(snapshot.scan(&second, b"", b"").is_err());
+        assert!(second.get_at(b"key", &snapshot).is_err());
+        assert_eq!(
+            snapshot.get(&first, b"key").unwrap(),
+            Some(b"first".to_vec())
+        );
+    }
+}
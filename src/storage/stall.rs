@@ -0,0 +1,117 @@
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use super::{CompactionStrategy, Storage, Writer};
+
+// How long each write waits once L0 reaches the slowdown limit
+const SLOWDOWN_DELAY: Duration = Duration::from_millis(1);
+
+impl Storage {
+    /// L0 tables, counting a memtable being flushed into a new one
+    pub(super) fn l0_files(&self) -> usize {
+        let (mem, levels) = self.current();
+        levels.sstables.get(&0).map_or(0, Vec::len) + usize::from(mem.immutable.is_some())
+    }
+
+    /// Whether writes are held until compaction brings L0 back down
+    pub(super) fn writes_stopped(&self) -> bool {
+        self.options.compaction_strategy == CompactionStrategy::Leveled
+            && self.l0_files() >= self.options.l0_stop_files
+    }
+
+    /// Hold a write back while L0 has too many tables for reads to stay
+    /// fast: briefly from `l0_slowdown_files`, and from `l0_stop_files`
+    /// until compaction has merged L0 under it. FIFO compaction never
+    /// merges, so its writes are never held.
+    pub(super) fn throttle_writes(&self, writer: &mut Writer) -> io::Result<()> {
+        let files = self.l0_files();
+        if self.options.compaction_strategy != CompactionStrategy::Leveled
+            || files < self.options.l0_slowdown_files
+        {
+            return Ok(());
+        }
+        let started = Instant::now();
+        let stopped = self.writes_stopped();
+        if stopped {
+            warn!("Writes stopped at {} L0 tables", files);
+            while self.writes_stopped() {
+                if self.finish_compaction(writer)? {
+                    continue;
+                }
+                if self.mem().immutable.is_some() {
+                    self.wait_for_flush(writer)?;
+                    continue;
+                }
+                // Nothing running will help, e.g. below the L0 trigger
+                self.run_compaction(writer, 0)?;
+            }
+        } else {
+            thread::sleep(SLOWDOWN_DELAY);
+        }
+        self.lifetime
+            .add_stall(stopped, started.elapsed().as_millis() as u64);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{OptionsDelta, StorageOptions};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_l0_never_exceeds_stop_limit() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut options = StorageOptions::default()
+            .memtable_size(1024)
+            .l0_compaction_files(2)
+            .l0_slowdown_files(3)
+            .l0_stop_files(4);
+        // Merges are slow, so flushes pile up in L0 behind them
+        options.compaction_output_hook = Some(Arc::new(|_: &std::path::Path| {
+            thread::sleep(Duration::from_millis(20));
+        }));
+        let storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+
+        let mut most = 0;
+        for i in 0..1500 {
+            storage
+                .put(format!("key{:05}", i % 1000).into_bytes(), vec![b'v'; 64])
+                .unwrap();
+            most = most.max(storage.l0_files());
+        }
+        assert!(most <= 4, "{} L0 tables", most);
+        let counters = storage.stats().since_open;
+        assert!(counters.write_slowdowns > 0);
+        assert!(counters.write_stops > 0);
+        assert!(counters.write_stall_ms >= 20);
+        for i in (0..1000).step_by(97) {
+            let key = format!("key{:05}", i).into_bytes();
+            assert_eq!(storage.get(&key).unwrap(), Some(vec![b'v'; 64]));
+        }
+    }
+
+    #[test]
+    fn test_stop_limit_below_trigger_still_compacts() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = StorageOptions::default()
+            .l0_slowdown_files(2)
+            .l0_stop_files(2);
+        let mut storage = Storage::open_with_options(temp_dir.path(), options).unwrap();
+        for i in 0..5u8 {
+            storage.put(vec![i], b"v".to_vec()).unwrap();
+            storage.flush().unwrap();
+        }
+        assert!(storage.level_files(0).len() <= 2);
+        assert!(!storage.level_files(1).is_empty());
+        assert_eq!(storage.scan(b"", b"").unwrap().count(), 5);
+
+        let changes = OptionsDelta::new().l0_stop_files(1);
+        assert!(storage.set_options(changes).is_err());
+    }
+}
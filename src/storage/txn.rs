@@ -0,0 +1,231 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io;
+
+use super::{Snapshot, Storage, WriteBatch};
+use crate::error::StorageError;
+use crate::{Key, Value};
+
+/// Why a transaction failed to commit
+#[derive(Debug)]
+pub enum CommitError {
+    /// `key` was written by someone else after the transaction started;
+    /// nothing was applied and the transaction can be retried
+    Conflict {
+        key: Key,
+    },
+    Io(io::Error),
+}
+
+impl fmt::Display for CommitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommitError::Conflict { key } => write!(
+                f,
+                "Transaction conflict on key {:?}",
+                String::from_utf8_lossy(key)
+            ),
+            CommitError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for CommitError {}
+
+impl From<io::Error> for CommitError {
+    fn from(e: io::Error) -> Self {
+        CommitError::Io(e)
+    }
+}
+
+/// An optimistic transaction.
+///
+/// Reads see the database as of the transaction's start plus its own
+/// buffered writes. Nothing is locked; instead `commit` checks that no key
+/// the transaction read or wrote has been written since it started, and
+/// applies all of its writes together only if so.
+pub struct Txn {
+    snapshot: Snapshot,
+    writes: BTreeMap<Key, Option<Value>>, // None marks a delete
+    reads: BTreeSet<Key>,
+}
+
+impl Txn {
+    pub(super) fn new(snapshot: Snapshot) -> Self {
+        Txn {
+            snapshot,
+            writes: BTreeMap::new(),
+            reads: BTreeSet::new(),
+        }
+    }
+
+    pub fn get(&mut self, storage: &Storage, key: &[u8]) -> Result<Option<Value>, StorageError> {
+        if let Some(write) = self.writes.get(key) {
+            return Ok(write.clone());
+        }
+        self.reads.insert(key.to_vec());
+        storage.get_at(key, &self.snapshot)
+    }
+
+    pub fn put(&mut self, key: impl Into<Key>, value: impl Into<Value>) {
+        self.writes.insert(key.into(), Some(value.into()));
+    }
+
+    pub fn delete(&mut self, key: impl Into<Key>) {
+        self.writes.insert(key.into(), None);
+    }
+
+    /// Validate and apply the transaction's writes, or fail with
+    /// [`CommitError::Conflict`] without applying any of them. The writes
+    /// are logged as one batch record, so a crash part way through the
+    /// commit loses all of them rather than some.
+    pub fn commit(self, storage: &Storage) -> Result<(), CommitError> {
+        let started = self.snapshot.seq_in(&storage.snapshots)?;
+        // Checked and written under the writer lock, so no other write can
+        // land in between
+        let mut writer = storage.writer();
+        for key in self.reads.iter().chain(self.writes.keys()) {
+            if storage.latest_seq(key)?.is_some_and(|seq| seq > started) {
+                return Err(CommitError::Conflict { key: key.clone() });
+            }
+        }
+
+        let mut batch = WriteBatch::new();
+        for (key, write) in self.writes {
+            match write {
+                Some(value) => batch.put(key, value),
+                None => batch.delete(key),
+            }
+        }
+        storage.write_locked(&mut writer, batch)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn increment(storage: &Storage, txn: &mut Txn, key: &[u8]) {
+        let current = txn
+            .get(storage, key)
+            .unwrap()
+            .map_or(0, |v| u64::from_le_bytes(v.try_into().unwrap()));
+        txn.put(key.to_vec(), (current + 1).to_le_bytes().to_vec());
+    }
+
+    #[test]
+    fn test_concurrent_increments_conflict() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        let key = b"counter";
+
+        // Both transactions read the same base value
+        let mut first = storage.transaction();
+        let mut second = storage.transaction();
+        increment(&storage, &mut first, key);
+        increment(&storage, &mut second, key);
+
+        first.commit(&storage).unwrap();
+        match second.commit(&storage) {
+            Err(CommitError::Conflict { key: conflict }) => assert_eq!(conflict, key),
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+
+        // A retry sees the first transaction's write
+        let mut retry = storage.transaction();
+        increment(&storage, &mut retry, key);
+        retry.commit(&storage).unwrap();
+        assert_eq!(storage.get(key).unwrap(), Some(2u64.to_le_bytes().to_vec()));
+    }
+
+    #[test]
+    fn test_reads_own_writes_and_snapshot() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        storage.put(b"a", b"1").unwrap();
+
+        let mut txn = storage.transaction();
+        txn.put(b"b", b"2");
+        txn.delete(b"a".to_vec());
+        assert_eq!(txn.get(&storage, b"a").unwrap(), None);
+        assert_eq!(txn.get(&storage, b"b").unwrap(), Some(b"2".to_vec()));
+
+        // Writes to keys the transaction never touched don't conflict, and
+        // aren't visible to it either
+        storage.put(b"c", b"3").unwrap();
+        assert_eq!(txn.get(&storage, b"c").unwrap(), None);
+        assert_eq!(storage.get(b"b").unwrap(), None);
+
+        // ...unless the transaction read them, as it now has
+        assert!(matches!(
+            txn.commit(&storage),
+            Err(CommitError::Conflict { .. })
+        ));
+        assert_eq!(storage.get(b"a").unwrap(), Some(b"1".to_vec()));
+
+        let mut txn = storage.transaction();
+        txn.put(b"b", b"2");
+        txn.delete(b"a".to_vec());
+        txn.commit(&storage).unwrap();
+        assert_eq!(storage.get(b"a").unwrap(), None);
+        assert_eq!(storage.get(b"b").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn test_blind_writes_conflict() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+
+        let mut txn = storage.transaction();
+        txn.put(b"key", b"txn");
+        storage.put(b"key", b"direct").unwrap();
+        storage.flush().unwrap();
+
+        assert!(matches!(
+            txn.commit(&storage),
+            Err(CommitError::Conflict { .. })
+        ));
+        assert_eq!(storage.get(b"key").unwrap(), Some(b"direct".to_vec()));
+    }
+
+    #[test]
+    fn test_commit_is_atomic_across_a_crash() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        storage.put(b"a", b"old").unwrap();
+
+        let mut txn = storage.transaction();
+        txn.put(b"a", b"new");
+        txn.put(b"b", b"new");
+        txn.delete(b"c".to_vec());
+        txn.commit(&storage).unwrap();
+        let log = fs::read_dir(temp_dir.path().join("wal"))
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let committed = fs::metadata(&log).unwrap().len();
+
+        let mut txn = storage.transaction();
+        txn.put(b"b", b"newer");
+        txn.put(b"d", b"newer");
+        txn.commit(&storage).unwrap();
+        storage.crash();
+        let logged = fs::read(&log).unwrap();
+
+        // Cut the second commit short anywhere and none of it survives
+        let len = logged.len() as u64;
+        for cut in [committed + 1, (committed + len) / 2, len - 1] {
+            fs::write(&log, &logged[..cut as usize]).unwrap();
+            let storage = Storage::new(temp_dir.path(), false).unwrap();
+            assert_eq!(storage.get(b"a").unwrap(), Some(b"new".to_vec()));
+            assert_eq!(storage.get(b"b").unwrap(), Some(b"new".to_vec()));
+            assert_eq!(storage.get(b"d").unwrap(), None);
+            storage.crash();
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use std::io;
+use std::path::PathBuf;
+
+use super::Storage;
+
+/// Outcome of [`Storage::verify`]
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub tables: usize,
+    pub entries: u64,
+    /// Every file checked, in order, whether it passed or not
+    pub checked: Vec<PathBuf>,
+    /// Tables that failed their checks, with the reason
+    pub corrupt: Vec<(PathBuf, String)>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.corrupt.is_empty()
+    }
+
+    /// Note that `path` was checked, and why it failed if it did
+    pub(super) fn record(&mut self, path: PathBuf, result: io::Result<()>) {
+        if let Err(e) = result {
+            self.corrupt.push((path.clone(), e.to_string()));
+        }
+        self.checked.push(path);
+    }
+}
+
+impl Storage {
+    /// Read every live SSTable end to end and check it against its own
+    /// metadata; see [`SSTable::verify`](crate::sstable::SSTable::verify).
+    /// Failures are collected rather than returned so one bad table doesn't
+    /// hide another.
+    pub fn verify(&self) -> VerifyReport {
+        let current = self.levels();
+        let mut levels: Vec<_> = current.sstables.iter().collect();
+        levels.sort_by_key(|(level, _)| **level);
+
+        let mut report = VerifyReport::default();
+        for table in levels.into_iter().flat_map(|(_, tables)| tables) {
+            report.tables += 1;
+            report.checked.push(table.get_path().clone());
+            match table.verify() {
+                Ok(entries) => report.entries += entries,
+                Err(e) => report
+                    .corrupt
+                    .push((table.get_path().clone(), e.to_string())),
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_verify_finds_damaged_table() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        for batch in 0..2 {
+            for i in 0..100 {
+                let key = format!("key{:03}", batch * 100 + i).into_bytes();
+                storage.put(key, vec![b'v'; 50]).unwrap();
+            }
+            storage.flush().unwrap();
+        }
+        storage.delete(b"key000").unwrap();
+        storage.flush().unwrap();
+
+        let report = storage.verify();
+        assert!(report.is_ok(), "{:?}", report.corrupt);
+        assert_eq!(report.tables, 3);
+        assert_eq!(report.checked.len(), 3);
+        assert_eq!(report.entries, 201);
+
+        // Cut the second table short
+        let damaged = storage.levels().sstables[&0][1].get_path().clone();
+        let len = fs::metadata(&damaged).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&damaged)
+            .unwrap()
+            .set_len(len - 10)
+            .unwrap();
+
+        let report = storage.verify();
+        assert_eq!(report.tables, 3);
+        assert_eq!(report.corrupt.len(), 1);
+        assert_eq!(report.corrupt[0].0, damaged);
+    }
+}
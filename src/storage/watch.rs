@@ -0,0 +1,206 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::Storage;
+use crate::wal::Operation;
+use crate::{Key, Value};
+
+// Events a watcher can fall behind by before it's cut off
+const WATCH_CAPACITY: usize = 1024;
+
+/// A committed write seen by a [`WatchHandle`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEvent {
+    pub key: Key,
+    pub op: Operation,
+    /// The new value; `None` for a delete
+    pub value: Option<Value>,
+    pub seq: u64,
+}
+
+/// Receives the writes under one key prefix, in commit order. Dropping it
+/// unsubscribes.
+///
+/// Writes never wait for a watcher: one that falls more than
+/// `WATCH_CAPACITY` events behind is cut off, after which it yields what it
+/// already received and then ends with [`overflowed`](WatchHandle::overflowed)
+/// set.
+pub struct WatchHandle {
+    events: Receiver<ChangeEvent>,
+    overflowed: Arc<AtomicBool>,
+}
+
+impl WatchHandle {
+    /// The next event if one is waiting
+    pub fn try_recv(&self) -> Option<ChangeEvent> {
+        self.events.try_recv().ok()
+    }
+
+    /// Wait up to `timeout` for the next event, or `None` if none came or
+    /// the watch has ended
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<ChangeEvent> {
+        self.events.recv_timeout(timeout).ok()
+    }
+
+    /// Whether events were lost because this watcher fell too far behind
+    pub fn overflowed(&self) -> bool {
+        self.overflowed.load(Ordering::Relaxed)
+    }
+}
+
+/// Blocks for each event; ends once the storage is dropped or the watcher
+/// has been cut off
+impl Iterator for WatchHandle {
+    type Item = ChangeEvent;
+
+    fn next(&mut self) -> Option<ChangeEvent> {
+        self.events.recv().ok()
+    }
+}
+
+struct Watcher {
+    prefix: Vec<u8>,
+    events: SyncSender<ChangeEvent>,
+    overflowed: Arc<AtomicBool>,
+}
+
+/// The live watchers of a storage instance
+#[derive(Default)]
+pub(super) struct WatchList {
+    watchers: Vec<Watcher>,
+}
+
+impl WatchList {
+    /// Whether any watcher wants writes to `key`, so callers copy keys and
+    /// values only when needed
+    pub(super) fn wants(&self, key: &[u8]) -> bool {
+        self.watchers.iter().any(|w| key.starts_with(&w.prefix))
+    }
+
+    /// Hand `event` to every watcher of its key, forgetting those that have
+    /// been dropped or fallen behind
+    pub(super) fn publish(&mut self, event: ChangeEvent) {
+        self.watchers.retain(|watcher| {
+            if !event.key.starts_with(&watcher.prefix) {
+                return true;
+            }
+            match watcher.events.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    watcher.overflowed.store(true, Ordering::Relaxed);
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
+
+impl Storage {
+    /// Subscribe to every committed put and delete of a key starting with
+    /// `prefix`; an empty prefix watches everything. Events are sent once
+    /// the write is applied, so a `get` made on receiving one sees it. Bulk
+    /// loads and ingested tables aren't reported.
+    pub fn watch_prefix(&self, prefix: &[u8]) -> WatchHandle {
+        let (sender, receiver) = mpsc::sync_channel(WATCH_CAPACITY);
+        let overflowed = Arc::new(AtomicBool::new(false));
+        self.writer().watchers.watchers.push(Watcher {
+            prefix: prefix.to_vec(),
+            events: sender,
+            overflowed: overflowed.clone(),
+        });
+        WatchHandle {
+            events: receiver,
+            overflowed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn drain(handle: &WatchHandle) -> Vec<(Key, Option<Value>)> {
+        std::iter::from_fn(|| handle.try_recv())
+            .map(|event| (event.key, event.value))
+            .collect()
+    }
+
+    #[test]
+    fn test_watchers_see_their_prefix() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        storage.put(b"config/early", b"0").unwrap();
+        let config = storage.watch_prefix(b"config/");
+        let users = storage.watch_prefix(b"user/");
+
+        storage.put(b"config/a", b"1").unwrap();
+        storage.put(b"user/x", b"2").unwrap();
+        storage.put(b"other", b"3").unwrap();
+        storage.delete(b"config/a").unwrap();
+        let mut batch = crate::storage::WriteBatch::new();
+        batch.put(b"user/y", b"4");
+        batch.put(b"config/b", b"5");
+        storage.write(batch).unwrap();
+
+        assert_eq!(
+            drain(&config),
+            vec![
+                (b"config/a".to_vec(), Some(b"1".to_vec())),
+                (b"config/a".to_vec(), None),
+                (b"config/b".to_vec(), Some(b"5".to_vec())),
+            ]
+        );
+        assert_eq!(
+            drain(&users),
+            vec![
+                (b"user/x".to_vec(), Some(b"2".to_vec())),
+                (b"user/y".to_vec(), Some(b"4".to_vec())),
+            ]
+        );
+
+        // Sequence numbers follow commit order
+        storage.put(b"config/c", b"6").unwrap();
+        storage.put(b"config/d", b"7").unwrap();
+        let (first, second) = (config.try_recv().unwrap(), config.try_recv().unwrap());
+        assert_eq!(first.op, Operation::Put);
+        assert_eq!(second.seq, first.seq + 1);
+        assert_eq!(storage.get(&second.key).unwrap(), second.value);
+
+        drop(users);
+        storage.put(b"user/z", b"8").unwrap();
+        assert_eq!(storage.writer().watchers.watchers.len(), 1);
+    }
+
+    #[test]
+    fn test_watcher_across_threads() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        let handle = storage.watch_prefix(b"");
+        let reader = std::thread::spawn(move || handle.take(3).map(|e| e.key).collect::<Vec<_>>());
+        for key in [b"a", b"b", b"c"] {
+            storage.put(key.to_vec(), b"v".to_vec()).unwrap();
+        }
+        assert_eq!(
+            reader.join().unwrap(),
+            vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_slow_watcher_cut_off() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Storage::new(temp_dir.path(), false).unwrap();
+        let handle = storage.watch_prefix(b"k");
+        for i in 0..WATCH_CAPACITY + 1 {
+            storage
+                .put(format!("k{}", i).into_bytes(), b"v".to_vec())
+                .unwrap();
+        }
+        assert!(handle.overflowed());
+        assert_eq!(handle.count(), WATCH_CAPACITY);
+    }
+}
@@ -1,64 +1,710 @@
+use crate::error::StorageError;
+use crate::storage::sync_dir;
 use crate::{Key, Value};
-use std::fs::{File, OpenOptions};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
 use std::io::{self, Read, Seek, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum Operation {
     Put,
     Delete,
+    /// A batch written straight to tables, logged only by its range of
+    /// sequence numbers
+    DirectBatch,
+    /// A put whose value reads as deleted once its expiry has passed
+    PutExpiring,
+    /// An operand for the merge operator, carried as the value
+    Merge,
 }
 
+/// When appends are made durable with `fsync`. Without a sync, an
+/// acknowledged write survives a process crash but can be lost on power
+/// failure.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// After every append
+    Always,
+    /// After every `n`th append
+    EveryN(u32),
+    /// On the first append at least this many milliseconds after the last
+    /// sync
+    IntervalMillis(u64),
+    /// Only when asked to with [`WAL::sync`] (the default)
+    #[default]
+    Never,
+}
+
+/// Prints as `always`, `every_n:<n>`, `interval_ms:<ms>` or `never`, the
+/// form it parses from
+impl fmt::Display for SyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyncPolicy::Always => write!(f, "always"),
+            SyncPolicy::EveryN(n) => write!(f, "every_n:{}", n),
+            SyncPolicy::IntervalMillis(ms) => write!(f, "interval_ms:{}", ms),
+            SyncPolicy::Never => write!(f, "never"),
+        }
+    }
+}
+
+impl FromStr for SyncPolicy {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid sync policy {:?}", s),
+            )
+        };
+        match s.split_once(':') {
+            None if s == "always" => Ok(SyncPolicy::Always),
+            None if s == "never" => Ok(SyncPolicy::Never),
+            Some(("every_n", n)) => n.parse().map(SyncPolicy::EveryN).map_err(|_| invalid()),
+            Some(("interval_ms", ms)) => ms
+                .parse()
+                .map(SyncPolicy::IntervalMillis)
+                .map_err(|_| invalid()),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+// Marks a record holding several operations
+const BATCH_OP: u8 = 3;
+// Opens every log whose records carry sequence numbers; older logs start
+// with the previous header or, before checksums, a record, whose first byte
+// is never 'L'
+const LOG_MAGIC: &[u8; 8] = b"LSMWAL\x00\x02";
+// Opens a log whose records are checksummed but not numbered
+const FRAMED_LOG_MAGIC: &[u8; 8] = b"LSMWAL\x00\x01";
+// [crc][payload_size] ahead of each record in a checksummed log
+const FRAME_HEADER: usize = 8;
+// Reserved ahead of an encoded record for its frame and sequence number
+const RESERVED: usize = FRAME_HEADER + 8;
+
+/// How a log's records are laid out, as told by its header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LogFormat {
+    /// No header: bare records, written before checksums
+    Unframed,
+    /// Checksummed records without sequence numbers
+    Framed,
+    /// Checksummed records, each starting with the sequence number of its
+    /// first operation
+    Sequenced,
+}
+
+// Records decoded from a log and the bytes they span
+type Parsed = (Vec<WalRecord>, usize);
+
+/// A logged operation: a put or merge carries its value, a delete doesn't,
+/// and an expiring put carries its expiry too. A direct batch marker keeps its
+/// first and last sequence numbers in the key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct WalRecord {
+    pub op: Operation,
+    pub key: Key,
+    pub value: Option<Value>,
+    /// Milliseconds since the epoch, for an expiring put
+    pub expires_at: Option<u64>,
+    /// Sequence number the operation was written at, or `None` in a log
+    /// from before they were logged
+    pub seq: Option<u64>,
+}
+
+impl WalRecord {
+    pub fn put(key: Key, value: Value) -> Self {
+        WalRecord {
+            op: Operation::Put,
+            key,
+            value: Some(value),
+            expires_at: None,
+            seq: None,
+        }
+    }
+
+    pub fn put_expiring(key: Key, value: Value, expires_at: u64) -> Self {
+        WalRecord {
+            op: Operation::PutExpiring,
+            key,
+            value: Some(value),
+            expires_at: Some(expires_at),
+            seq: None,
+        }
+    }
+
+    pub fn delete(key: Key) -> Self {
+        WalRecord {
+            op: Operation::Delete,
+            key,
+            value: None,
+            expires_at: None,
+            seq: None,
+        }
+    }
+
+    pub fn direct_batch(first_seq: u64, last_seq: u64) -> Self {
+        let mut key = first_seq.to_le_bytes().to_vec();
+        key.extend_from_slice(&last_seq.to_le_bytes());
+        WalRecord {
+            op: Operation::DirectBatch,
+            key,
+            value: None,
+            expires_at: None,
+            seq: None,
+        }
+    }
+
+    /// This record as logged at sequence number `seq`
+    pub fn with_seq(mut self, seq: u64) -> Self {
+        self.seq = Some(seq);
+        self
+    }
+
+    /// First and last sequence numbers of a direct batch marker
+    pub fn seq_range(&self) -> Option<(u64, u64)> {
+        if self.op != Operation::DirectBatch || self.key.len() != 16 {
+            return None;
+        }
+        let (first, last) = self.key.split_at(8);
+        Some((
+            u64::from_le_bytes(first.try_into().unwrap()),
+            u64::from_le_bytes(last.try_into().unwrap()),
+        ))
+    }
+}
+
+/// Write-ahead log kept as numbered segment files (`000001.log`, ...) in a
+/// directory.
+///
+/// Appends go to the newest segment. `rotate` starts a new one while keeping
+/// the old until `release` is told its writes are safely in a table, so
+/// replay reads every segment left on disk, oldest first. A segment that
+/// grows past the segment size is rotated out by the append that crossed it,
+/// so no single file grows without bound between flushes.
+///
+/// Each file starts with a magic header, and each record is framed as
+/// `[crc32][payload_size][seq][payload]`, the checksum covering everything
+/// after it. `seq` is the sequence number the caller assigned the record's
+/// first operation; the rest of a batch follow it in order. A file with the
+/// previous header has no sequence numbers, and one without a header
+/// predates checksums; either is read and appended to in its own format
+/// until the next rotation.
 #[allow(clippy::upper_case_acronyms)]
 pub struct WAL {
-    path: PathBuf,
+    dir: PathBuf,
+    number: u64,
     file: File,
+    format: LogFormat,
+    // Bytes in the live segment, and the size that ends it
+    written: u64,
+    segment_size: u64,
+    policy: SyncPolicy,
+    // Appends since the last sync, and when it happened
+    unsynced: u32,
+    last_sync: Instant,
+    syncs: u64,
 }
 
 impl WAL {
-    pub fn new(path: PathBuf) -> io::Result<Self> {
-        let file = OpenOptions::new()
+    pub fn new(dir: PathBuf) -> Result<Self, StorageError> {
+        Self::open(dir, 0)
+    }
+
+    /// Open the log in `dir`, first removing segments numbered below
+    /// `first`, whose writes are already in tables. New segments are
+    /// numbered from `first` on.
+    pub fn open(dir: PathBuf, first: u64) -> Result<Self, StorageError> {
+        Self::upgrade_single_file(&dir)?;
+        fs::create_dir_all(&dir)?;
+
+        let mut numbers = Self::log_numbers(&dir)?;
+        let flushed = numbers.iter().take_while(|&&number| number < first).count();
+        for number in numbers.drain(..flushed) {
+            fs::remove_file(Self::log_path(&dir, number))?;
+        }
+        if flushed > 0 {
+            sync_dir(&dir)?;
+        }
+
+        let number = numbers.pop().unwrap_or(1).max(first);
+        let (file, format) = Self::open_log(&Self::log_path(&dir, number))?;
+        Ok(WAL {
+            dir,
+            number,
+            written: file.metadata()?.len(),
+            file,
+            format,
+            segment_size: u64::MAX,
+            policy: SyncPolicy::default(),
+            unsynced: 0,
+            last_sync: Instant::now(),
+            syncs: 0,
+        })
+    }
+
+    /// Sync appends according to `policy` from now on
+    pub fn set_sync_policy(&mut self, policy: SyncPolicy) {
+        self.policy = policy;
+    }
+
+    /// Rotate to a new segment once the live one reaches `bytes`
+    pub fn set_segment_size(&mut self, bytes: u64) {
+        self.segment_size = bytes;
+    }
+
+    /// Turn a log from before segments, a single file at `dir`, into the
+    /// first segment of a `dir` directory. It's renamed to `<dir>.old` on
+    /// the way, so a crash part way leaves it where the next open finds it.
+    fn upgrade_single_file(dir: &Path) -> io::Result<()> {
+        let moved = dir.with_extension("old");
+        if dir.is_file() {
+            fs::rename(dir, &moved)?;
+        } else if !moved.is_file() {
+            return Ok(());
+        }
+        fs::create_dir_all(dir)?;
+        fs::rename(&moved, Self::log_path(dir, 1))?;
+        sync_dir(dir)?;
+        if let Some(parent) = dir.parent() {
+            sync_dir(parent)?;
+        }
+        Ok(())
+    }
+
+    /// Make everything appended so far durable, whatever the policy
+    pub fn sync(&mut self) -> Result<(), StorageError> {
+        self.file.sync_data()?;
+        self.unsynced = 0;
+        self.last_sync = Instant::now();
+        self.syncs += 1;
+        Ok(())
+    }
+
+    /// Number of syncs made since the log was opened
+    pub fn syncs(&self) -> u64 {
+        self.syncs
+    }
+
+    /// Path of log `number` in `dir`
+    pub fn log_path(dir: &Path, number: u64) -> PathBuf {
+        dir.join(format!("{:06}.log", number))
+    }
+
+    /// Open a log for appending, starting it with the header if it's new.
+    /// Returns the format its records are in.
+    fn open_log(path: &Path) -> io::Result<(File, LogFormat)> {
+        let mut file = OpenOptions::new()
             .create(true)
             .append(true)
             .read(true)
-            .open(&path)?;
+            .open(path)?;
+        let header = Self::read_header(&mut file)?;
+        let format = Self::format_of(&header);
+        if header.len() == LOG_MAGIC.len() || format == LogFormat::Unframed {
+            return Ok((file, format));
+        }
+        // New, or its header was cut short before any record followed
+        file.set_len(0)?;
+        file.write_all(LOG_MAGIC)?;
+        Ok((file, LogFormat::Sequenced))
+    }
+
+    /// Format of a log starting with `header`. A header cut short can only
+    /// be the current one's, as nothing was appended after it.
+    fn format_of(header: &[u8]) -> LogFormat {
+        if header == FRAMED_LOG_MAGIC {
+            LogFormat::Framed
+        } else if LOG_MAGIC.starts_with(header) {
+            LogFormat::Sequenced
+        } else {
+            LogFormat::Unframed
+        }
+    }
+
+    /// Up to the first `LOG_MAGIC.len()` bytes of `file`
+    fn read_header(file: &mut File) -> io::Result<Vec<u8>> {
+        let mut header = Vec::with_capacity(LOG_MAGIC.len());
+        file.seek(io::SeekFrom::Start(0))?;
+        Read::by_ref(file)
+            .take(LOG_MAGIC.len() as u64)
+            .read_to_end(&mut header)?;
+        Ok(header)
+    }
+
+    /// Numbers of the log files in `dir`, ascending
+    fn log_numbers(dir: &Path) -> io::Result<Vec<u64>> {
+        let mut numbers = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|s| s.to_str()) == Some("log") {
+                if let Some(number) = path
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .and_then(|s| s.parse().ok())
+                {
+                    numbers.push(number);
+                }
+            }
+        }
+        numbers.sort_unstable();
+        Ok(numbers)
+    }
+
+    /// Log one operation at sequence number `seq`
+    pub fn append(
+        &mut self,
+        seq: u64,
+        op: Operation,
+        key: &[u8],
+        value: Option<&[u8]>,
+    ) -> Result<(), StorageError> {
+        let size = RESERVED + 9 + key.len() + value.map_or(0, <[u8]>::len);
+        let mut record = Vec::with_capacity(size);
+        record.resize(RESERVED, 0);
+        Self::encode(&mut record, op, key, value);
+        Ok(self.write_record(seq, record)?)
+    }
+
+    /// Log a put of `value` at sequence number `seq` that expires at
+    /// `expires_at`, which is kept ahead of the value:
+    /// `[4][key_size][key][value_size][expires_at][value]`
+    pub fn append_expiring(
+        &mut self,
+        seq: u64,
+        key: &[u8],
+        value: &[u8],
+        expires_at: u64,
+    ) -> Result<(), StorageError> {
+        let mut payload = Vec::with_capacity(8 + value.len());
+        payload.extend_from_slice(&expires_at.to_le_bytes());
+        payload.extend_from_slice(value);
+        self.append(seq, Operation::PutExpiring, key, Some(&payload))
+    }
+
+    /// Log several operations as one record, written with a single call:
+    /// `[3][count][op][key_size][key][value_size?][value?]...`. The first is
+    /// at sequence number `seq` and the rest follow it. Replay applies all
+    /// of them or, if the record was cut short, none.
+    pub fn append_batch<'a, I>(&mut self, seq: u64, ops: I) -> Result<(), StorageError>
+    where
+        I: IntoIterator<Item = (Operation, &'a [u8], Option<&'a [u8]>)>,
+    {
+        let mut record = vec![0; RESERVED];
+        record.extend_from_slice(&[BATCH_OP, 0, 0, 0, 0]);
+        let mut count: u32 = 0;
+        for (op, key, value) in ops {
+            Self::encode(&mut record, op, key, value);
+            count += 1;
+        }
+        record[RESERVED + 1..RESERVED + 5].copy_from_slice(&count.to_le_bytes());
+        Ok(self.write_record(seq, record)?)
+    }
+
+    /// Write an encoded record that follows `RESERVED` bytes, filling in
+    /// the frame and sequence number the live log's format has room for
+    fn write_record(&mut self, seq: u64, mut record: Vec<u8>) -> io::Result<()> {
+        let start = match self.format {
+            LogFormat::Sequenced => {
+                record[FRAME_HEADER..RESERVED].copy_from_slice(&seq.to_le_bytes());
+                0
+            }
+            LogFormat::Framed => RESERVED - FRAME_HEADER,
+            LogFormat::Unframed => RESERVED,
+        };
+        let bytes = &mut record[start..];
+        if self.format != LogFormat::Unframed {
+            let size = (bytes.len() - FRAME_HEADER) as u32;
+            bytes[4..FRAME_HEADER].copy_from_slice(&size.to_le_bytes());
+            let crc = crc32fast::hash(&bytes[4..]);
+            bytes[..4].copy_from_slice(&crc.to_le_bytes());
+        }
+        self.file.write_all(bytes)?;
+        self.file.flush()?;
+        self.written += bytes.len() as u64;
 
-        Ok(WAL { path, file })
+        self.unsynced = self.unsynced.saturating_add(1);
+        let due = match self.policy {
+            SyncPolicy::Always => true,
+            SyncPolicy::EveryN(n) => self.unsynced >= n,
+            SyncPolicy::IntervalMillis(ms) => self.last_sync.elapsed() >= Duration::from_millis(ms),
+            SyncPolicy::Never => false,
+        };
+        if due {
+            self.sync()?;
+        }
+        if self.written >= self.segment_size {
+            self.rotate()?;
+        }
+        Ok(())
     }
 
-    pub fn append(&mut self, op: Operation, key: &[u8], value: Option<&[u8]>) -> io::Result<()> {
+    fn encode(record: &mut Vec<u8>, op: Operation, key: &[u8], value: Option<&[u8]>) {
         // Write format: [op_type][key_size][key][value_size?][value?]
         let op_byte = match op {
             Operation::Put => 0u8,
             Operation::Delete => 1u8,
+            Operation::DirectBatch => 2u8,
+            Operation::PutExpiring => 4u8,
+            Operation::Merge => 5u8,
         };
 
-        self.file.write_all(&[op_byte])?;
-        self.file.write_all(&(key.len() as u32).to_le_bytes())?;
-        self.file.write_all(key)?;
+        record.push(op_byte);
+        record.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        record.extend_from_slice(key);
 
         if let Some(value) = value {
-            self.file.write_all(&(value.len() as u32).to_le_bytes())?;
-            self.file.write_all(value)?;
+            record.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            record.extend_from_slice(value);
         }
-
-        self.file.flush()?;
-        Ok(())
     }
 
-    pub fn replay(&mut self) -> io::Result<Vec<(Operation, Key, Option<Value>)>> {
+    /// Every record in the logs on disk, oldest file first. A torn tail is
+    /// cut off the live segment, the only one a crash can leave one in; a
+    /// bad record in a sealed segment fails the replay, as the writes after
+    /// it were acknowledged.
+    pub fn replay(&mut self) -> Result<Vec<WalRecord>, StorageError> {
         let mut entries = Vec::new();
+        for number in Self::log_numbers(&self.dir)? {
+            if number == self.number {
+                entries.extend(Self::replay_file(
+                    &mut self.file,
+                    self.format,
+                    number,
+                    false,
+                )?);
+            } else {
+                let mut file = File::open(Self::log_path(&self.dir, number))?;
+                let format = Self::format_of(&Self::read_header(&mut file)?);
+                entries.extend(Self::replay_file(&mut file, format, number, true)?);
+            }
+        }
+        Ok(entries)
+    }
+
+    fn replay_file(
+        file: &mut File,
+        format: LogFormat,
+        segment: u64,
+        sealed: bool,
+    ) -> io::Result<Vec<WalRecord>> {
         let mut buffer = Vec::new();
 
         // Reset file pointer to start
-        self.file.seek(io::SeekFrom::Start(0))?;
-        self.file.read_to_end(&mut buffer)?;
+        file.seek(io::SeekFrom::Start(0))?;
+        file.read_to_end(&mut buffer)?;
+
+        let start = Self::header_len(format, buffer.len());
+        let (entries, len) = Self::parse(&buffer[start..], format)
+            .map_err(|(offset, e)| replay_error(segment, (start + offset) as u64, e))?;
+        let len = start + len;
+        if len < buffer.len() {
+            if sealed {
+                let e = io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Record cut short or failing its checksum in a sealed segment",
+                );
+                return Err(replay_error(segment, len as u64, e));
+            }
+            // Unframed logs can only tell a torn tail from corruption when
+            // it starts a batch
+            if format == LogFormat::Unframed && buffer[len] != BATCH_OP {
+                let e = io::Error::new(io::ErrorKind::InvalidData, "Truncated WAL record");
+                return Err(replay_error(segment, len as u64, e));
+            }
+            // A record cut short by a crash was never acknowledged: drop it
+            // and whatever follows, so nothing appended later comes after a
+            // torn record
+            file.set_len(len as u64)?;
+            file.sync_all()?;
+        }
+        Ok(entries)
+    }
+
+    /// Numbers of the logs in `dir`, oldest first, without creating or
+    /// removing anything; for readers in another process
+    pub fn logs(dir: &Path) -> Result<Vec<u64>, StorageError> {
+        Ok(Self::log_numbers(dir)?)
+    }
+
+    /// Number of the newest log in `dir`, without creating or removing
+    /// anything; for readers in another process
+    pub fn newest_log(dir: &Path) -> Result<Option<u64>, StorageError> {
+        Ok(Self::log_numbers(dir)?.pop())
+    }
+
+    /// Complete records of log `number` in `dir` from byte `offset` on, and
+    /// the offset just past them. A record still being appended is left for
+    /// the next read.
+    pub fn read_log(
+        dir: &Path,
+        number: u64,
+        offset: u64,
+    ) -> Result<(Vec<WalRecord>, u64), StorageError> {
+        let mut file = File::open(Self::log_path(dir, number))?;
+        let format = Self::format_of(&Self::read_header(&mut file)?);
+        let offset = offset.max(Self::header_len(format, usize::MAX) as u64);
+        file.seek(io::SeekFrom::Start(offset))?;
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer)?;
+        let (entries, len) = Self::parse(&buffer, format)
+            .map_err(|(at, e)| replay_error(number, offset + at as u64, e))?;
+        Ok((entries, offset + len as u64))
+    }
+
+    /// Records of log `number` in `dir` up to the first that is cut short,
+    /// fails its checksum or can't be decoded, and the offset just past
+    /// them; anything after that offset is damaged. For salvaging a log
+    /// that won't replay.
+    pub fn salvage_log(dir: &Path, number: u64) -> Result<(Vec<WalRecord>, u64), StorageError> {
+        let mut file = File::open(Self::log_path(dir, number))?;
+        let format = Self::format_of(&Self::read_header(&mut file)?);
+        let mut buffer = Vec::new();
+        file.seek(io::SeekFrom::Start(0))?;
+        file.read_to_end(&mut buffer)?;
+        let start = Self::header_len(format, buffer.len());
+        let readable = match Self::parse(&buffer[start..], format) {
+            Ok(parsed) => parsed,
+            // Everything before the bad record decodes
+            Err((offset, _)) => Self::parse(&buffer[start..start + offset], format)
+                .map_err(|(at, e)| replay_error(number, (start + at) as u64, e))?,
+        };
+        let (records, len) = readable;
+        Ok((records, (start + len) as u64))
+    }
+
+    /// Bytes of header ahead of the records of a log in `format` that is
+    /// `len` bytes long
+    fn header_len(format: LogFormat, len: usize) -> usize {
+        match format {
+            LogFormat::Unframed => 0,
+            LogFormat::Framed | LogFormat::Sequenced => LOG_MAGIC.len().min(len),
+        }
+    }
+
+    /// Decode records from the start of `buffer`, stopping before one that
+    /// is cut short or, in a checksummed log, fails its checksum. Returns
+    /// them with the number of bytes they span, or the offset of a record
+    /// that can't be decoded with the reason.
+    fn parse(buffer: &[u8], format: LogFormat) -> Result<Parsed, (usize, io::Error)> {
+        if format == LogFormat::Unframed {
+            return Self::parse_unframed(buffer);
+        }
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while let Some(payload) = Self::frame_at(buffer, pos) {
+            entries.extend(Self::parse_payload(payload, format).map_err(|e| (pos, e))?);
+            pos += FRAME_HEADER + payload.len();
+        }
+        Ok((entries, pos))
+    }
+
+    /// Payload of the frame at `pos`, or `None` if the buffer ends inside it
+    /// or it fails its checksum
+    fn frame_at(buffer: &[u8], pos: usize) -> Option<&[u8]> {
+        let header = buffer.get(pos..pos + FRAME_HEADER)?;
+        let crc = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let size = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+        let payload = buffer.get(pos + FRAME_HEADER..pos + FRAME_HEADER + size)?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header[4..]);
+        hasher.update(payload);
+        (hasher.finalize() == crc).then_some(payload)
+    }
+
+    /// Decode a checksummed record, which must hold exactly one operation
+    /// or one batch, numbering its operations if the log has sequence
+    /// numbers
+    fn parse_payload(payload: &[u8], format: LogFormat) -> io::Result<Vec<WalRecord>> {
+        let malformed = || io::Error::new(io::ErrorKind::InvalidData, "Malformed WAL record");
+        let (seq, payload) = if format == LogFormat::Sequenced {
+            let seq = payload.get(..8).ok_or_else(malformed)?;
+            (
+                Some(u64::from_le_bytes(seq.try_into().unwrap())),
+                &payload[8..],
+            )
+        } else {
+            (None, payload)
+        };
+        let (start, count) = if payload.first() == Some(&BATCH_OP) {
+            let count = payload.get(1..5).ok_or_else(malformed)?;
+            (5, u32::from_le_bytes(count.try_into().unwrap()) as usize)
+        } else {
+            (0, 1)
+        };
+        match Self::parse_entries(payload, start, count)? {
+            Some((mut records, end)) if end == payload.len() => {
+                if let Some(seq) = seq {
+                    for (record, seq) in records.iter_mut().zip(seq..) {
+                        record.seq = Some(seq);
+                    }
+                }
+                Ok(records)
+            }
+            _ => Err(malformed()),
+        }
+    }
 
+    /// [`parse`](WAL::parse) for a log written before records were framed
+    fn parse_unframed(buffer: &[u8]) -> Result<Parsed, (usize, io::Error)> {
+        let mut entries = Vec::new();
         let mut pos = 0;
+        let length = |pos: usize| -> Option<usize> {
+            let bytes = buffer.get(pos..pos + 4)?;
+            Some(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+        };
+
         while pos < buffer.len() {
+            // A batch only counts once it has been read to its end
+            let (start, count) = if buffer[pos] == BATCH_OP {
+                let Some(count) = length(pos + 1) else { break };
+                (pos + 5, count)
+            } else {
+                (pos, 1)
+            };
+            let parsed = Self::parse_entries(buffer, start, count).map_err(|e| (pos, e))?;
+            let Some((records, next)) = parsed else {
+                break;
+            };
+            entries.extend(records);
+            pos = next;
+        }
+
+        Ok((entries, pos))
+    }
+
+    /// Decode `count` single records starting at `pos`, returning them and
+    /// the offset just past them, or `None` if the buffer ends first
+    fn parse_entries(
+        buffer: &[u8],
+        mut pos: usize,
+        count: usize,
+    ) -> io::Result<Option<(Vec<WalRecord>, usize)>> {
+        let mut entries = Vec::with_capacity(count.min(1024));
+        let length = |pos: usize| -> Option<usize> {
+            let bytes = buffer.get(pos..pos + 4)?;
+            Some(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+        };
+
+        for _ in 0..count {
+            let Some(&op_byte) = buffer.get(pos) else {
+                return Ok(None);
+            };
             // Read operation type
-            let op = match buffer[pos] {
+            let op = match op_byte {
                 0 => Operation::Put,
                 1 => Operation::Delete,
+                2 => Operation::DirectBatch,
+                4 => Operation::PutExpiring,
+                5 => Operation::Merge,
                 _ => {
                     return Err(io::Error::new(
                         io::ErrorKind::InvalidData,
@@ -66,41 +712,121 @@ impl WAL {
                     ))
                 }
             };
-            pos += 1;
+            let mut next = pos + 1;
 
             // Read key
-            let key_size = u32::from_le_bytes(buffer[pos..pos + 4].try_into().unwrap()) as usize;
-            pos += 4;
-            let key = buffer[pos..pos + key_size].to_vec();
-            pos += key_size;
+            let Some(key_size) = length(next) else {
+                return Ok(None);
+            };
+            next += 4;
+            let Some(key) = buffer.get(next..next + key_size) else {
+                return Ok(None);
+            };
+            next += key_size;
 
             // Read value if present
-            let value = if matches!(op, Operation::Put) {
-                let value_size =
-                    u32::from_le_bytes(buffer[pos..pos + 4].try_into().unwrap()) as usize;
-                pos += 4;
-                let value = buffer[pos..pos + value_size].to_vec();
-                pos += value_size;
-                Some(value)
+            let value = if matches!(
+                op,
+                Operation::Put | Operation::PutExpiring | Operation::Merge
+            ) {
+                let Some(value_size) = length(next) else {
+                    return Ok(None);
+                };
+                next += 4;
+                let Some(value) = buffer.get(next..next + value_size) else {
+                    return Ok(None);
+                };
+                next += value_size;
+                Some(value.to_vec())
             } else {
                 None
             };
 
-            entries.push((op, key, value));
+            let mut record = WalRecord {
+                op,
+                key: key.to_vec(),
+                value,
+                expires_at: None,
+                seq: None,
+            };
+            if op == Operation::PutExpiring {
+                let value = record.value.as_mut().unwrap();
+                if value.len() < 8 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Expiring put too short for its expiry",
+                    ));
+                }
+                let expires_at = value.drain(..8).collect::<Vec<_>>();
+                record.expires_at = Some(u64::from_le_bytes(expires_at.try_into().unwrap()));
+            }
+            entries.push(record);
+            pos = next;
         }
 
-        Ok(entries)
+        Ok(Some((entries, pos)))
     }
 
-    pub fn clear(&mut self) -> io::Result<()> {
-        self.file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .read(true)
-            .open(&self.path)?;
-        Ok(())
+    /// Bytes in every segment on disk
+    pub fn size(&self) -> Result<u64, StorageError> {
+        let mut size = 0;
+        for number in Self::log_numbers(&self.dir)? {
+            size += fs::metadata(Self::log_path(&self.dir, number))?.len();
+        }
+        Ok(size)
     }
+
+    /// Send appends to a new, empty file, keeping the current one until
+    /// [`release`](WAL::release) is called for the number returned. Pending
+    /// appends in the old file are synced first unless the policy is `Never`.
+    pub fn rotate(&mut self) -> Result<u64, StorageError> {
+        if self.unsynced > 0 && self.policy != SyncPolicy::Never {
+            self.sync()?;
+        }
+        let number = self.number + 1;
+        let (file, format) = Self::open_log(&Self::log_path(&self.dir, number))?;
+        file.sync_all()?;
+        sync_dir(&self.dir)?;
+
+        let sealed = self.number;
+        self.written = file.metadata()?.len();
+        self.file = file;
+        self.number = number;
+        self.format = format;
+        self.unsynced = 0;
+        Ok(sealed)
+    }
+
+    /// Remove the files rotated out up to and including `number`, once
+    /// their writes are in a table
+    pub fn release(&mut self, number: u64) -> Result<(), StorageError> {
+        for old in Self::log_numbers(&self.dir)? {
+            if old <= number && old < self.number {
+                fs::remove_file(Self::log_path(&self.dir, old))?;
+            }
+        }
+        Ok(sync_dir(&self.dir)?)
+    }
+
+    /// Discard everything logged so far by switching to a new, empty file.
+    ///
+    /// The new file is made durable before the old ones are removed, so a
+    /// crash in between only replays writes that are already in tables.
+    pub fn clear(&mut self) -> Result<(), StorageError> {
+        let sealed = self.rotate()?;
+        self.release(sealed)
+    }
+}
+
+/// `e`, raised decoding the record at `offset` of log `segment`, as a
+/// replay failure naming both
+fn replay_error(segment: u64, offset: u64, e: io::Error) -> io::Error {
+    StorageError::WalReplay {
+        segment,
+        offset,
+        detail: e.to_string(),
+    }
+    .into()
 }
 
 #[cfg(test)]
@@ -112,126 +838,550 @@ mod tests {
     #[test]
     fn test_new_wal() {
         let temp_dir = TempDir::new().unwrap();
-        let path = temp_dir.path().join("test.wal");
+        let path = temp_dir.path().join("wal");
         let wal = WAL::new(path).unwrap();
-        assert!(wal.path.exists());
+        assert!(WAL::log_path(&wal.dir, 1).exists());
     }
 
     #[test]
     fn test_append_and_replay_put() {
         let temp_dir = TempDir::new().unwrap();
-        let path = temp_dir.path().join("test.wal");
+        let path = temp_dir.path().join("wal");
         let mut wal = WAL::new(path).unwrap();
 
         let key = b"test_key".to_vec();
         let value = b"test_value".to_vec();
-        wal.append(Operation::Put, &key, Some(&value)).unwrap();
+        wal.append(1, Operation::Put, &key, Some(&value)).unwrap();
 
         let entries = wal.replay().unwrap();
-        assert_eq!(entries.len(), 1);
-
-        match &entries[0] {
-            (Operation::Put, k, Some(v)) => {
-                assert_eq!(k, &key);
-                assert_eq!(v, &value);
-            }
-            _ => panic!("Expected Put operation"),
-        }
+        assert_eq!(entries, vec![WalRecord::put(key, value).with_seq(1)]);
     }
 
     #[test]
     fn test_append_and_replay_delete() {
         let temp_dir = TempDir::new().unwrap();
-        let path = temp_dir.path().join("test.wal");
+        let path = temp_dir.path().join("wal");
         let mut wal = WAL::new(path).unwrap();
 
         let key = b"test_key".to_vec();
-        wal.append(Operation::Delete, &key, None).unwrap();
+        wal.append(1, Operation::Delete, &key, None).unwrap();
 
         let entries = wal.replay().unwrap();
-        assert_eq!(entries.len(), 1);
-
-        match &entries[0] {
-            (Operation::Delete, k, None) => {
-                assert_eq!(k, &key);
-            }
-            _ => panic!("Expected Delete operation"),
-        }
+        assert_eq!(entries, vec![WalRecord::delete(key).with_seq(1)]);
     }
 
     #[test]
     fn test_multiple_operations() {
         let temp_dir = TempDir::new().unwrap();
-        let path = temp_dir.path().join("test.wal");
+        let path = temp_dir.path().join("wal");
         let mut wal = WAL::new(path).unwrap();
 
         // Append multiple operations
         let operations = vec![
-            (Operation::Put, b"key1".to_vec(), Some(b"value1".to_vec())),
-            (Operation::Delete, b"key2".to_vec(), None),
-            (Operation::Put, b"key3".to_vec(), Some(b"value3".to_vec())),
+            WalRecord::put(b"key1".to_vec(), b"value1".to_vec()).with_seq(1),
+            WalRecord::delete(b"key2".to_vec()).with_seq(2),
+            WalRecord::put(b"key3".to_vec(), b"value3".to_vec()).with_seq(3),
         ];
 
-        for (op, key, value) in &operations {
-            match op {
-                Operation::Put => wal.append(Operation::Put, &key, value.as_deref()).unwrap(),
-                Operation::Delete => wal.append(Operation::Delete, &key, None).unwrap(),
-            }
+        for record in &operations {
+            let seq = record.seq.unwrap();
+            wal.append(seq, record.op, &record.key, record.value.as_deref())
+                .unwrap();
         }
 
         // Replay and verify
+        assert_eq!(wal.replay().unwrap(), operations);
+    }
+
+    #[test]
+    fn test_direct_batch_marker() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("wal");
+        let mut wal = WAL::new(path).unwrap();
+
+        let marker = WalRecord::direct_batch(7, 4096);
+        wal.append(4096, marker.op, &marker.key, None).unwrap();
+        wal.append(4097, Operation::Delete, b"key", None).unwrap();
+
         let entries = wal.replay().unwrap();
-        assert_eq!(entries.len(), operations.len());
-
-        for (i, (op, key, value)) in operations.iter().enumerate() {
-            match (&entries[i].0, &entries[i].1, &entries[i].2) {
-                (replay_op, replay_key, replay_value) => {
-                    assert!(matches!(op, Operation::Put) == matches!(replay_op, Operation::Put));
-                    assert_eq!(replay_key, key);
-                    assert_eq!(replay_value, value);
-                }
-            }
+        assert_eq!(entries[0].seq_range(), Some((7, 4096)));
+        assert_eq!(entries[1].seq_range(), None);
+        assert_eq!(entries[1].seq, Some(4097));
+    }
+
+    #[test]
+    fn test_batch_replayed_whole() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("wal");
+        let mut wal = WAL::new(path).unwrap();
+
+        wal.append(1, Operation::Put, b"key0", Some(b"value0"))
+            .unwrap();
+        // Numbered in order from the batch's sequence number
+        let batch = [
+            WalRecord::put(b"key1".to_vec(), b"value1".to_vec()).with_seq(2),
+            WalRecord::delete(b"key0".to_vec()).with_seq(3),
+            WalRecord::put(b"key2".to_vec(), Vec::new()).with_seq(4),
+        ];
+        wal.append_batch(
+            2,
+            batch
+                .iter()
+                .map(|r| (r.op, r.key.as_slice(), r.value.as_deref())),
+        )
+        .unwrap();
+
+        let entries = wal.replay().unwrap();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[1..], batch[..]);
+    }
+
+    #[test]
+    fn test_torn_batch_dropped() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("wal");
+        let mut wal = WAL::new(path.clone()).unwrap();
+        wal.append(1, Operation::Put, b"before", Some(b"value"))
+            .unwrap();
+        let intact = wal.size().unwrap();
+        let batch = [
+            (Operation::Put, &b"key1"[..], Some(&b"value1"[..])),
+            (Operation::Put, &b"key2"[..], Some(&b"value2"[..])),
+        ];
+        wal.append_batch(2, batch).unwrap();
+        let full = wal.size().unwrap();
+
+        // Every cut through the batch loses all of it and nothing before
+        for len in intact + 1..full {
+            wal.file.set_len(len).unwrap();
+            let entries = wal.replay().unwrap();
+            assert_eq!(
+                entries,
+                vec![WalRecord::put(b"before".to_vec(), b"value".to_vec()).with_seq(1)]
+            );
+            assert_eq!(wal.size().unwrap(), intact);
+            wal.append_batch(2, batch).unwrap();
         }
+
+        // Records appended after a dropped batch replay normally
+        wal.append(4, Operation::Delete, b"before", None).unwrap();
+        drop(wal);
+        let mut wal = WAL::new(path).unwrap();
+        assert_eq!(wal.replay().unwrap().len(), 4);
     }
 
     #[test]
     fn test_clear() {
         let temp_dir = TempDir::new().unwrap();
-        let path = temp_dir.path().join("test.wal");
+        let path = temp_dir.path().join("wal");
         let mut wal = WAL::new(path.clone()).unwrap();
 
         // Write some data
-        wal.append(Operation::Put, b"key", Some(b"value")).unwrap();
-        assert!(fs::metadata(&path).unwrap().len() > 0);
+        wal.append(1, Operation::Put, b"key", Some(b"value"))
+            .unwrap();
+        assert!(fs::metadata(WAL::log_path(&path, 1)).unwrap().len() > 0);
 
-        // Clear and verify
+        // Clear rotates to a new file and removes the old one
         wal.clear().unwrap();
-        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+        assert!(!WAL::log_path(&path, 1).exists());
+        assert_eq!(
+            fs::read(WAL::log_path(&path, 2)).unwrap(),
+            LOG_MAGIC.to_vec()
+        );
 
         // Verify replay returns empty
         let entries = wal.replay().unwrap();
         assert!(entries.is_empty());
+
+        // Appends go to the new file and survive a reopen
+        wal.append(2, Operation::Put, b"key2", Some(b"value2"))
+            .unwrap();
+        drop(wal);
+        let mut wal = WAL::new(path).unwrap();
+        assert_eq!(wal.number, 2);
+        assert_eq!(wal.replay().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_rotated_logs_replay_until_released() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("wal");
+        let mut wal = WAL::new(path.clone()).unwrap();
+        wal.append(1, Operation::Put, b"old", Some(b"value"))
+            .unwrap();
+        assert_eq!(wal.rotate().unwrap(), 1);
+        wal.append(2, Operation::Put, b"new", Some(b"value"))
+            .unwrap();
+        drop(wal);
+
+        // Both files survive a reopen and replay oldest first
+        let mut wal = WAL::new(path.clone()).unwrap();
+        assert_eq!(wal.number, 2);
+        let keys: Vec<_> = wal.replay().unwrap().into_iter().map(|r| r.key).collect();
+        assert_eq!(keys, vec![b"old".to_vec(), b"new".to_vec()]);
+
+        wal.release(1).unwrap();
+        assert!(!WAL::log_path(&path, 1).exists());
+        assert_eq!(WAL::logs(&path).unwrap(), vec![2]);
+        let entries = wal.replay().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].key, b"new");
+
+        // The live file is never released
+        wal.release(2).unwrap();
+        assert_eq!(WAL::logs(&path).unwrap(), vec![2]);
+    }
+
+    #[test]
+    fn test_segments_rotate_by_size() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("wal");
+        let mut wal = WAL::new(path.clone()).unwrap();
+        wal.set_segment_size(256);
+        for i in 0..20u8 {
+            wal.append(i as u64 + 1, Operation::Put, &[i], Some(&[i; 50]))
+                .unwrap();
+        }
+        // Records never straddle segments, so each holds whole ones
+        let logs = WAL::logs(&path).unwrap();
+        assert!(logs.len() >= 4, "{:?}", logs);
+        for &number in &logs[..logs.len() - 1] {
+            let (records, _) = WAL::read_log(&path, number, 0).unwrap();
+            assert!(!records.is_empty());
+        }
+        let keys: Vec<_> = wal.replay().unwrap().into_iter().map(|r| r.key).collect();
+        assert_eq!(keys, (0..20u8).map(|i| vec![i]).collect::<Vec<_>>());
+        drop(wal);
+
+        // Reopening past the first two segments removes them
+        let mut wal = WAL::open(path.clone(), logs[2]).unwrap();
+        assert_eq!(WAL::logs(&path).unwrap(), logs[2..]);
+        let replayed = wal.replay().unwrap();
+        assert!(replayed.len() < 20);
+        assert_eq!(replayed.last().unwrap().key, vec![19]);
+        drop(wal);
+
+        // New segments are numbered from `first` even if older ones are gone
+        fs::remove_dir_all(&path).unwrap();
+        let wal = WAL::open(path.clone(), 9).unwrap();
+        assert_eq!(wal.number, 9);
+        assert_eq!(WAL::logs(&path).unwrap(), vec![9]);
     }
 
     #[test]
     fn test_large_entries() {
         let temp_dir = TempDir::new().unwrap();
-        let path = temp_dir.path().join("test.wal");
+        let path = temp_dir.path().join("wal");
         let mut wal = WAL::new(path).unwrap();
 
         let large_value = vec![b'x'; 1024 * 1024]; // 1MB value
-        wal.append(Operation::Put, b"large_key", Some(&large_value))
+        wal.append(1, Operation::Put, b"large_key", Some(&large_value))
             .unwrap();
 
         let entries = wal.replay().unwrap();
+        assert_eq!(
+            entries,
+            vec![WalRecord::put(b"large_key".to_vec(), large_value).with_seq(1)]
+        );
+    }
+
+    #[test]
+    fn test_read_log_leaves_partial_record() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("wal");
+        let mut wal = WAL::new(path.clone()).unwrap();
+        wal.append(1, Operation::Put, b"key1", Some(b"value1"))
+            .unwrap();
+        let first = wal.size().unwrap();
+        wal.append(2, Operation::Put, b"key2", Some(b"value2"))
+            .unwrap();
+        let number = WAL::newest_log(&path).unwrap().unwrap();
+
+        // Half of the second record, as if its append were still running
+        let log = fs::read(WAL::log_path(&path, number)).unwrap();
+        let (written, rest) = log.split_at(first as usize + 6);
+        fs::write(WAL::log_path(&path, number), written).unwrap();
+        let (entries, offset) = WAL::read_log(&path, number, 0).unwrap();
         assert_eq!(entries.len(), 1);
+        assert_eq!(offset, first);
+
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(WAL::log_path(&path, number))
+            .unwrap();
+        file.write_all(rest).unwrap();
+        let (entries, _) = WAL::read_log(&path, number, offset).unwrap();
+        assert_eq!(
+            entries,
+            vec![WalRecord::put(b"key2".to_vec(), b"value2".to_vec()).with_seq(2)]
+        );
+    }
+
+    #[test]
+    fn test_torn_tail_recovered() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("wal");
+        let mut wal = WAL::new(path.clone()).unwrap();
+        let records = [
+            WalRecord::put(b"key1".to_vec(), b"value1".to_vec()).with_seq(1),
+            WalRecord::delete(b"key2".to_vec()).with_seq(2),
+            WalRecord::put(b"key3".to_vec(), b"value3".to_vec()).with_seq(3),
+        ];
+        let mut intact = 0;
+        for record in &records {
+            intact = wal.size().unwrap();
+            let seq = record.seq.unwrap();
+            wal.append(seq, record.op, &record.key, record.value.as_deref())
+                .unwrap();
+        }
+        let full = wal.size().unwrap();
+        let log = fs::read(WAL::log_path(&path, 1)).unwrap();
+
+        // Cut anywhere inside the third record, the first two replay and
+        // the log is trimmed back to them
+        for len in intact + 1..full {
+            fs::write(WAL::log_path(&path, 1), &log[..len as usize]).unwrap();
+            let mut wal = WAL::new(path.clone()).unwrap();
+            assert_eq!(wal.replay().unwrap(), records[..2]);
+            assert_eq!(wal.size().unwrap(), intact);
+        }
+
+        // So does a tail the filesystem filled with zeros, or whose bytes
+        // don't match their checksum
+        let mut damaged = log[..intact as usize].to_vec();
+        damaged.extend_from_slice(&[0; 32]);
+        fs::write(WAL::log_path(&path, 1), &damaged).unwrap();
+        assert_eq!(
+            WAL::new(path.clone()).unwrap().replay().unwrap(),
+            records[..2]
+        );
+        let mut flipped = log.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        fs::write(WAL::log_path(&path, 1), &flipped).unwrap();
+        let mut wal = WAL::new(path.clone()).unwrap();
+        assert_eq!(wal.replay().unwrap(), records[..2]);
+
+        // Appends after recovery follow the last good record
+        wal.append(3, Operation::Delete, b"key1", None).unwrap();
+        drop(wal);
+        assert_eq!(WAL::new(path).unwrap().replay().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_damaged_sealed_segment_fails_replay() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("wal");
+        let mut wal = WAL::new(path.clone()).unwrap();
+        wal.append(1, Operation::Put, b"key1", Some(b"value1"))
+            .unwrap();
+        let offset = wal.size().unwrap();
+        wal.append(2, Operation::Put, b"key2", Some(b"value2"))
+            .unwrap();
+        wal.rotate().unwrap();
+        wal.append(3, Operation::Put, b"key3", Some(b"value3"))
+            .unwrap();
+        drop(wal);
+
+        // A flipped bit in the middle of the log, with acknowledged writes
+        // after it, isn't a torn tail
+        let sealed = WAL::log_path(&path, 1);
+        let mut log = fs::read(&sealed).unwrap();
+        *log.last_mut().unwrap() ^= 1;
+        fs::write(&sealed, &log).unwrap();
+
+        let err = WAL::new(path.clone()).unwrap().replay().unwrap_err();
+        match err {
+            StorageError::WalReplay {
+                segment,
+                offset: at,
+                ..
+            } => assert_eq!((segment, at), (1, offset)),
+            other => panic!("expected a replay error, got {:?}", other),
+        }
+        // Nothing was cut off
+        assert_eq!(fs::read(&sealed).unwrap(), log);
+    }
 
-        match &entries[0] {
-            (Operation::Put, k, Some(v)) => {
-                assert_eq!(k, b"large_key");
-                assert_eq!(v, &large_value);
+    #[test]
+    fn test_undecodable_record_names_segment_and_offset() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("wal");
+        let mut wal = WAL::new(path.clone()).unwrap();
+        wal.append(1, Operation::Put, b"key1", Some(b"value1"))
+            .unwrap();
+        let offset = wal.size().unwrap();
+
+        // Checksummed correctly, but with an operation type that doesn't exist
+        let mut payload = 2u64.to_le_bytes().to_vec();
+        payload.extend_from_slice(&[9, 0, 0, 0, 0]);
+        let size = (payload.len() as u32).to_le_bytes();
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&size);
+        hasher.update(&payload);
+        let mut log = fs::read(WAL::log_path(&path, 1)).unwrap();
+        log.extend_from_slice(&hasher.finalize().to_le_bytes());
+        log.extend_from_slice(&size);
+        log.extend_from_slice(&payload);
+        fs::write(WAL::log_path(&path, 1), &log).unwrap();
+
+        let err = WAL::new(path.clone()).unwrap().replay().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        match err {
+            StorageError::WalReplay {
+                segment,
+                offset: at,
+                ..
+            } => assert_eq!((segment, at), (1, offset)),
+            other => panic!("expected a replay error, got {:?}", other),
+        }
+        let err = WAL::read_log(&path, 1, 0).unwrap_err();
+        assert!(matches!(err, StorageError::WalReplay { segment: 1, .. }));
+    }
+
+    #[test]
+    fn test_single_file_log_becomes_first_segment() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("wal");
+        let mut log = Vec::new();
+        WAL::encode(&mut log, Operation::Put, b"key1", Some(b"value1"));
+        fs::write(&path, &log).unwrap();
+
+        let mut wal = WAL::new(path.clone()).unwrap();
+        assert_eq!(fs::read(WAL::log_path(&path, 1)).unwrap(), log);
+        assert_eq!(
+            wal.replay().unwrap(),
+            vec![WalRecord::put(b"key1".to_vec(), b"value1".to_vec())]
+        );
+        drop(wal);
+
+        // A crash between the two renames leaves only the moved file
+        fs::remove_dir_all(&path).unwrap();
+        fs::write(path.with_extension("old"), &log).unwrap();
+        let mut wal = WAL::new(path.clone()).unwrap();
+        assert!(!path.with_extension("old").exists());
+        assert_eq!(wal.replay().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_unframed_log_still_replays() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("wal");
+        fs::create_dir_all(&path).unwrap();
+        // A log from before checksums: bare records, no header
+        let mut log = Vec::new();
+        WAL::encode(&mut log, Operation::Put, b"key1", Some(b"value1"));
+        WAL::encode(&mut log, Operation::Delete, b"key2", None);
+        fs::write(WAL::log_path(&path, 1), &log).unwrap();
+
+        let mut wal = WAL::new(path.clone()).unwrap();
+        assert_eq!(wal.format, LogFormat::Unframed);
+        wal.append(3, Operation::Put, b"key3", Some(b"value3"))
+            .unwrap();
+        let entries = wal.replay().unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1], WalRecord::delete(b"key2".to_vec()));
+        assert_eq!(entries[2].seq, None);
+        assert_eq!(WAL::read_log(&path, 1, 0).unwrap().0, entries);
+
+        // The next log is framed
+        wal.clear().unwrap();
+        assert_eq!(wal.format, LogFormat::Sequenced);
+        wal.append(4, Operation::Delete, b"key1", None).unwrap();
+        assert_eq!(
+            wal.replay().unwrap(),
+            vec![WalRecord::delete(b"key1".to_vec()).with_seq(4)]
+        );
+    }
+
+    #[test]
+    fn test_log_without_seqs_still_replays() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("wal");
+        fs::create_dir_all(&path).unwrap();
+        // A log from before sequence numbers: checksummed frames of records
+        let mut log = FRAMED_LOG_MAGIC.to_vec();
+        let mut payload = Vec::new();
+        WAL::encode(&mut payload, Operation::Put, b"key1", Some(b"value1"));
+        let size = (payload.len() as u32).to_le_bytes();
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&size);
+        hasher.update(&payload);
+        log.extend_from_slice(&hasher.finalize().to_le_bytes());
+        log.extend_from_slice(&size);
+        log.extend_from_slice(&payload);
+        fs::write(WAL::log_path(&path, 1), &log).unwrap();
+
+        // Appended to in its own format, so nothing in it is numbered
+        let mut wal = WAL::new(path.clone()).unwrap();
+        assert_eq!(wal.format, LogFormat::Framed);
+        wal.append(2, Operation::Delete, b"key2", None).unwrap();
+        let entries = wal.replay().unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                WalRecord::put(b"key1".to_vec(), b"value1".to_vec()),
+                WalRecord::delete(b"key2".to_vec()),
+            ]
+        );
+        assert_eq!(WAL::read_log(&path, 1, 0).unwrap().0, entries);
+
+        // The next log numbers its records
+        wal.rotate().unwrap();
+        assert_eq!(wal.format, LogFormat::Sequenced);
+        wal.append(3, Operation::Delete, b"key1", None).unwrap();
+        let entries = wal.replay().unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[2], WalRecord::delete(b"key1".to_vec()).with_seq(3));
+    }
+
+    #[test]
+    fn test_sync_policies() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("wal");
+        let mut wal = WAL::new(path).unwrap();
+        let append = |wal: &mut WAL, n: u32| {
+            for _ in 0..n {
+                wal.append(1, Operation::Put, b"key", Some(b"value"))
+                    .unwrap();
             }
-            _ => panic!("Expected Put operation with large value"),
+            wal.syncs()
+        };
+
+        assert_eq!(append(&mut wal, 10), 0);
+
+        wal.set_sync_policy(SyncPolicy::Always);
+        assert_eq!(append(&mut wal, 3), 3);
+        wal.append_batch(1, [(Operation::Delete, &b"key"[..], None)])
+            .unwrap();
+        assert_eq!(wal.syncs(), 4);
+
+        // The count restarts from the last sync, whatever made it
+        wal.set_sync_policy(SyncPolicy::EveryN(100));
+        assert_eq!(append(&mut wal, 99), 4);
+        assert_eq!(append(&mut wal, 1), 5);
+        assert_eq!(append(&mut wal, 50), 5);
+        wal.sync().unwrap();
+        assert_eq!(append(&mut wal, 99), 6);
+        assert_eq!(append(&mut wal, 1), 7);
+
+        wal.set_sync_policy(SyncPolicy::IntervalMillis(60_000));
+        assert_eq!(append(&mut wal, 10), 7);
+        wal.set_sync_policy(SyncPolicy::IntervalMillis(0));
+        assert_eq!(append(&mut wal, 2), 9);
+    }
+
+    #[test]
+    fn test_sync_policy_round_trips_as_text() {
+        for policy in [
+            SyncPolicy::Always,
+            SyncPolicy::EveryN(100),
+            SyncPolicy::IntervalMillis(250),
+            SyncPolicy::Never,
+        ] {
+            assert_eq!(policy.to_string().parse::<SyncPolicy>().unwrap(), policy);
+        }
+        for bad in ["sometimes", "every_n:", "every_n:-1", "interval_ms:soon"] {
+            assert!(bad.parse::<SyncPolicy>().is_err(), "{}", bad);
         }
     }
 }